@@ -0,0 +1,261 @@
+use bvh;
+
+use error::MocapError;
+use selector::{self, Selector};
+use {channel_type, ChannelType};
+
+// Static channel adjustments for rig fixes, from --adjust or a profile: adding a constant to, or
+// multiplying by a constant, one channel type of the joints matching a selector, for the whole
+// clip. On the command line an adjustment is
+//
+//   <joint>:<type>+=<value>   add, such as LeftShoulder:RotationX+=7
+//   <joint>:<type>*=<value>   multiply, such as RightHand:RotationZ*=-1
+//
+// and in a profile `add = <value>` or `multiply = <value>` under `[channel."<joint>".<type>]`.
+// Profile adjustments apply first, then --adjust in the order given, each to the values the
+// previous ones left. They run before quantization (after --root, before ground snapping, clamps
+// and everything storing channels relative to something), so channel ranges, clamps and the BVH
+// output all see the adjusted values, and they're recorded in the metadata as their
+// command-line forms separated by spaces.
+//
+// A rotation channel whose values all lie within [-180, 180] stays there: adjusted values are
+// wrapped back into [-180, 180) degrees, so rotating a joint past 180 doesn't widen its range.
+// One that was already outside it (continuous, like a spinning yaw) is left continuous.
+//
+// Each adjustment works on a single Euler channel, which is what a rig fix usually means for a
+// joint with one rotation channel, or a sign flip undoing a mirrored axis. For a joint rotating
+// about several axes, adding to the first channel in its rotation order turns it about that axis
+// of the parent's frame and adding to the last about that axis of its own, but adding to the
+// middle one is neither; composing an offset rotation in the joint's full 3D rotation space is
+// out of scope.
+
+// Metadata key holding the adjustments applied
+pub const KEY: &str = "adjustments";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Add(f64),
+    Multiply(f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Adjustment {
+    pub selector: String,
+    pub type_: ChannelType,
+    pub operation: Operation,
+}
+
+impl Adjustment {
+    // `<joint>:<type>+=<value>` or `<joint>:<type>*=<value>`. The joint selector may itself
+    // contain colons; the type is after the last one.
+    pub fn parse(spec: &str) -> Option<Adjustment> {
+        let (channel, operation) = match (spec.rfind("+="), spec.rfind("*=")) {
+            (Some(add), multiply) if multiply.is_none_or(|multiply| multiply < add) => (&spec[..add], Operation::Add(spec[add + 2..].trim().parse().ok()?)),
+            (_, Some(multiply)) => (&spec[..multiply], Operation::Multiply(spec[multiply + 2..].trim().parse().ok()?)),
+            _ => return None,
+        };
+        let (selector, type_name) = channel.rsplit_once(':')?;
+        if selector.is_empty() || !operation.value().is_finite() {
+            return None;
+        }
+        Some(Adjustment {
+            selector: selector.into(),
+            type_: ChannelType::from_name(type_name)?,
+            operation: operation,
+        })
+    }
+
+    // Whether it multiplies by 0, the one adjustment the metadata can't be used to undo (see the
+    // lossy operations of options.rs).
+    pub fn discards_values(&self) -> bool {
+        self.operation == Operation::Multiply(0.0)
+    }
+
+    pub fn spec(&self) -> String {
+        match self.operation {
+            Operation::Add(value) => format!("{}:{}+={}", self.selector, self.type_.name(), value),
+            Operation::Multiply(value) => format!("{}:{}*={}", self.selector, self.type_.name(), value),
+        }
+    }
+}
+
+impl Operation {
+    fn value(&self) -> f64 {
+        match *self {
+            Operation::Add(value) | Operation::Multiply(value) => value,
+        }
+    }
+
+    fn apply(&self, value: f64) -> f64 {
+        match *self {
+            Operation::Add(constant) => value + constant,
+            Operation::Multiply(constant) => value * constant,
+        }
+    }
+}
+
+// Applies `adjustments` in order, returning the metadata recording them. Fails if a selector
+// matches no joint with a channel of its type.
+pub fn apply(bvh: &mut bvh::Bvh, adjustments: &[Adjustment]) -> Result<Vec<(String, String)>, MocapError> {
+    if adjustments.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut types = Vec::new();
+    push_channel_types(&bvh.hierarchy.root, &mut types);
+
+    for adjustment in adjustments.iter() {
+        let matches = selector::find_joints(&bvh.hierarchy.root, &Selector::parse(&adjustment.selector)?);
+        let channels = matches.iter()
+            .flat_map(|joint_match| joint_match.channel_index..joint_match.channel_index + joint_match.num_channels)
+            .filter(|index| types[*index] == adjustment.type_)
+            .collect::<Vec<_>>();
+        if channels.is_empty() {
+            return Err(if matches.is_empty() {
+                MocapError::JointNotFound(adjustment.selector.clone())
+            } else {
+                MocapError::Usage(format!("adjustment {}: no joint matching {} has a {} channel", adjustment.spec(), adjustment.selector, adjustment.type_.name()))
+            });
+        }
+        for index in channels {
+            let frames = &mut bvh.motion.frames;
+            let wrap = !adjustment.type_.is_translation() && frames.iter().all(|frame| (-180.0..=180.0).contains(&frame[index]));
+            for frame in frames.iter_mut() {
+                let value = adjustment.operation.apply(frame[index]);
+                frame[index] = if wrap { value - 360.0 * ((value + 180.0) / 360.0).floor() } else { value };
+            }
+        }
+    }
+
+    Ok(vec![(KEY.into(), adjustments.iter().map(|adjustment| adjustment.spec()).collect::<Vec<_>>().join(" "))])
+}
+
+fn push_channel_types(joint: &bvh::Joint, types: &mut Vec<ChannelType>) {
+    types.extend(joint.channels.iter().map(channel_type));
+    if let bvh::JointChildren::Joints(ref joints) = joint.children {
+        for child in joints.iter() {
+            push_channel_types(child, types);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use depth::DEFAULT_MAX_DEPTH;
+    use test_util;
+    use {convert, ranges, raw};
+
+    fn adjustment(selector: &str, type_: ChannelType, operation: Operation) -> Adjustment {
+        Adjustment {
+            selector: selector.into(),
+            type_: type_,
+            operation: operation,
+        }
+    }
+
+    #[test]
+    fn parses_adjustments() {
+        assert_eq!(Adjustment::parse("LeftShoulder:RotationX+=7"), Some(adjustment("LeftShoulder", ChannelType::RotationX, Operation::Add(7.0))));
+        assert_eq!(Adjustment::parse("RightHand:RotationZ*=-1"), Some(adjustment("RightHand", ChannelType::RotationZ, Operation::Multiply(-1.0))));
+        assert_eq!(Adjustment::parse("Hips:TranslationY+= -2.5 "), Some(adjustment("Hips", ChannelType::TranslationY, Operation::Add(-2.5))));
+        assert_eq!(Adjustment::parse("mixamorig:Spine/**:RotationY*=0.5"), Some(adjustment("mixamorig:Spine/**", ChannelType::RotationY, Operation::Multiply(0.5))));
+
+        for spec in ["LeftShoulder:RotationX=7", "LeftShoulder:RotationX+=", "LeftShoulder:RotationX+=seven", "LeftShoulder:RotationX*=inf",
+                     "LeftShoulder:RotationX+=NaN", ":RotationX+=7", "LeftShoulder+=7", "LeftShoulder:Xrotation+=7", "LeftShoulder:RotationX-=7"].iter() {
+            assert_eq!(Adjustment::parse(spec), None, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn specs_parse_back() {
+        for spec in ["LeftShoulder:RotationX+=7", "RightHand:RotationZ*=-1", "*/Hand:TranslationX+=0.125"].iter() {
+            let adjustment = Adjustment::parse(spec).unwrap();
+            assert_eq!(adjustment.spec(), *spec);
+            assert_eq!(Adjustment::parse(&adjustment.spec()), Some(adjustment));
+        }
+    }
+
+    #[test]
+    fn applies_adjustments_in_order_to_the_channels_selected() {
+        let original = test_util::sine_clip(20);
+        let mut bvh = test_util::sine_clip(20);
+        let adjustments = ["Spine:RotationZ+=7", "Spine:RotationZ*=-1", "*:RotationX*=0.5", "Hips:TranslationY+=3"].iter().map(|spec| Adjustment::parse(spec).unwrap()).collect::<Vec<_>>();
+        let metadata = apply(&mut bvh, &adjustments).unwrap();
+        assert_eq!(metadata, vec![(KEY.to_string(), "Spine:RotationZ+=7 Spine:RotationZ*=-1 *:RotationX*=0.5 Hips:TranslationY+=3".to_string())]);
+
+        for (frame, (adjusted, original)) in bvh.motion.frames.iter().zip(original.motion.frames.iter()).enumerate() {
+            for channel in 0..test_util::NUM_CHANNELS {
+                let expected = match channel {
+                    1 => original[channel] + 3.0,
+                    4 | 7 | 10 | 13 => original[channel] * 0.5,
+                    6 => -(original[channel] + 7.0),
+                    _ => original[channel],
+                };
+                assert!((adjusted[channel] - expected).abs() < 1e-9, "frame {} channel {}: {} vs {}", frame, channel, adjusted[channel], expected);
+            }
+        }
+        assert_eq!(apply(&mut bvh, &[]).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn wraps_rotations_within_a_turn() {
+        let text = test_util::clip_text(4, |frame, channel| match channel {
+            6 => [170.0, 175.0, 179.0, -179.0][frame],
+            9 => [170.0, 250.0, 330.0, 410.0][frame], // Continuous
+            _ => 0.0,
+        });
+        let mut bvh = test_util::parse(&text);
+        apply(&mut bvh, &[Adjustment::parse("Spine:RotationZ+=15").unwrap(), Adjustment::parse("Head:RotationZ+=15").unwrap()]).unwrap();
+        let values = |channel: usize| bvh.motion.frames.iter().map(|frame| frame[channel]).collect::<Vec<_>>();
+        assert_eq!(values(6), vec![-175.0, -170.0, -166.0, -164.0]);
+        assert_eq!(values(9), vec![185.0, 265.0, 345.0, 425.0]);
+
+        // Translations aren't angles
+        let mut bvh = test_util::parse(&test_util::clip_text(2, |_, _| 170.0));
+        apply(&mut bvh, &[Adjustment::parse("Hips:TranslationX+=15").unwrap()]).unwrap();
+        assert_eq!(bvh.motion.frames[1][0], 185.0);
+    }
+
+    #[test]
+    fn refuses_selectors_matching_no_channel() {
+        let mut bvh = test_util::sine_clip(2);
+        match apply(&mut bvh, &[Adjustment::parse("Tail:RotationZ+=1").unwrap()]) {
+            Err(MocapError::JointNotFound(ref selector)) if selector == "Tail" => {}
+            result => panic!("{:?}", result),
+        }
+        match apply(&mut bvh, &[Adjustment::parse("Spine:TranslationX+=1").unwrap()]) {
+            Err(MocapError::Usage(ref message)) => assert_eq!(message, "adjustment Spine:TranslationX+=1: no joint matching Spine has a TranslationX channel"),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    // Conversions quantize the adjusted values, with the profile's adjustments before --adjust
+    #[test]
+    fn channel_ranges_follow_the_adjusted_values() {
+        let dir = test_util::temp_dir("adjust");
+        let path = |file_name: &str| dir.join(file_name);
+        fs::write(path("in.bvh"), test_util::clip_text(40, test_util::sine)).unwrap();
+        fs::write(path("profile.toml"), "[channel.\"Head\".RotationX]\nadd = 10\n").unwrap();
+        let profile_arg = path("profile.toml").to_string_lossy().into_owned();
+        let convert_ranges = |args: &[&str]| {
+            convert(&path("in.bvh"), &path("out.bvh"), &path("out.csv"), &path("out.raw"), &test_util::options(args), None).unwrap();
+            let mocap = raw::read(&fs::read(path("out.raw")).unwrap(), DEFAULT_MAX_DEPTH).unwrap();
+            (ranges::of(&mocap).into_iter().map(|range| (range.min, range.range)).collect::<Vec<_>>(), mocap.metadata)
+        };
+
+        let (plain, _) = convert_ranges(&[]);
+        let (adjusted, metadata) = convert_ranges(&["--profile", &profile_arg, "--adjust", "Spine:RotationZ+=7", "--adjust", "Head:RotationX*=2", "--adjust", "LeftLeg:RotationY*=-1"]);
+        assert!(metadata.contains(&(KEY.to_string(), "Head:RotationX+=10 Spine:RotationZ+=7 Head:RotationX*=2 LeftLeg:RotationY*=-1".to_string())), "{:?}", metadata);
+        for (channel, (plain, adjusted)) in plain.iter().zip(adjusted.iter()).enumerate() {
+            let expected = match channel {
+                6 => (plain.0 + 7.0, plain.1),
+                10 => ((plain.0 + 10.0) * 2.0, plain.1 * 2.0),
+                14 => (-(plain.0 + plain.1), plain.1),
+                _ => *plain,
+            };
+            assert!((adjusted.0 - expected.0).abs() < 1e-3 && (adjusted.1 - expected.1).abs() < 1e-3, "channel {}: {:?} vs {:?}", channel, adjusted, expected);
+        }
+    }
+}
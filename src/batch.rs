@@ -0,0 +1,330 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+use cache::Cache;
+use cancel;
+use depth;
+use error::MocapError;
+use log;
+use manifest;
+use options::Options;
+use report::{Conversion, FileReport, RunReport};
+use write_report;
+
+// Parsed BVH takes several times its file size in memory, so this bounds the working set to a few
+// GB however many jobs there are
+const MAX_INPUT_BYTES_IN_FLIGHT: u64 = 512 << 20;
+
+// Compresses every `.bvh` file in `input_dir` (and, with `--recursive`, its subdirectories) into
+// `output_dir`, mirroring the directory structure. Failures are reported as they happen and
+// summarized at the end rather than stopping the batch. Files are converted on `--jobs` threads
+// (by default one per logical CPU), each printing its file's messages (see log.rs) and result
+// together once it's done, so with more than one job files are reported in the order they
+// finish; the summary is in input order. A panic converting one file fails just that file. To
+// keep memory in check, a file only starts converting once the inputs in flight add up to less
+// than `MAX_INPUT_BYTES_IN_FLIGHT` (a larger file waits until it's alone). With `--cache-dir`,
+// unchanged files are copied from the cache (see cache.rs) instead of converted. With
+// `--manifest`, every file's outputs are recorded once the batch is done, failed files included.
+// A batch cancelled with `cancel` (see cancel.rs) stops starting files and fails, keeping the
+// outputs of the files already done; the summary counts the files it didn't finish as cancelled
+// rather than failed.
+pub fn run(input_dir: &Path, output_dir: &Path, options: &Options, cancel: Option<&AtomicBool>) -> Result<(), MocapError> {
+    if is_same_dir(input_dir, output_dir) {
+        return Err(MocapError::Usage("batch: the output directory must differ from the input directory".into()));
+    }
+
+    let mut input_file_names = Vec::new();
+    find_bvh_files(input_dir, output_dir, options.recursive, &mut input_file_names)?;
+    input_file_names.sort();
+
+    let cache = match options.cache_dir {
+        Some(ref cache_dir) => Some(Cache::open(Path::new(cache_dir), options)?),
+        None => None,
+    };
+    let cache = cache.as_ref();
+
+    let jobs = options.jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, |jobs| jobs.get()));
+    let next = Mutex::new(0);
+    let budget = InputBudget::new(MAX_INPUT_BYTES_IN_FLIGHT);
+    // Held while printing a file's messages and result, so they come out together
+    let printing = Mutex::new(());
+    let results = Mutex::new((0..input_file_names.len()).map(|_| None).collect::<Vec<_>>());
+    thread::scope(|scope| -> io::Result<()> {
+        for _ in 0..jobs.min(input_file_names.len()) {
+            // With room for the deepest hierarchy allowed, like the main thread (see depth.rs)
+            thread::Builder::new().stack_size(depth::stack_size(options.max_depth)).spawn_scoped(scope, || loop {
+                let index = {
+                    let mut next = next.lock().unwrap();
+                    *next += 1;
+                    *next - 1
+                };
+                if index >= input_file_names.len() {
+                    break;
+                }
+                let input_file_name = &input_file_names[index];
+
+                let size = fs::metadata(input_file_name).map_or(0, |metadata| metadata.len());
+                budget.acquire(size);
+                let ((result, outputs), messages) = log::capture(|| manifest::record(|| manifest::atomically(|| {
+                    cancel::check(cancel)?;
+                    panic::catch_unwind(AssertUnwindSafe(|| convert(input_dir, output_dir, input_file_name, options, cache, cancel)))
+                        .unwrap_or_else(|payload| Err(MocapError::Internal(panic_message(&*payload))))
+                })));
+                budget.release(size);
+
+                {
+                    let _printing = printing.lock().unwrap_or_else(|e| e.into_inner());
+                    for message in messages.iter() {
+                        message.print();
+                    }
+                    let relative = input_file_name.strip_prefix(input_dir).unwrap();
+                    match result {
+                        Ok(Outcome::Converted(_)) => println!("{}: ok", relative.display()),
+                        Ok(Outcome::Cached) => println!("{}: ok (cached)", relative.display()),
+                        // Summarized at the end
+                        Err(MocapError::Cancelled) => (),
+                        Err(ref e) => println!("{}: failed: {}", relative.display(), e),
+                    }
+                }
+                results.lock().unwrap_or_else(|e| e.into_inner())[index] = Some((result, outputs, messages));
+            })?;
+        }
+        Ok(())
+    })?;
+
+    let mut failures = Vec::new();
+    let mut cancelled = 0;
+    let mut hits = 0;
+    let mut manifest_entries = Vec::new();
+    let mut report = RunReport::new("batch", options);
+    for (input_file_name, result) in input_file_names.iter().zip(results.into_inner().unwrap_or_else(|e| e.into_inner())) {
+        let (result, outputs, messages) = result.unwrap();
+        let cached = matches!(result, Ok(Outcome::Cached));
+        manifest_entries.push(manifest::Entry {
+            sources: vec![input_file_name.to_path_buf()],
+            outputs: outputs.clone(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+            cached: cached,
+        });
+        if options.report_file_name.is_some() {
+            let conversion = match result {
+                Ok(Outcome::Converted(ref conversion)) => Ok(conversion.clone()),
+                Ok(Outcome::Cached) => Ok(Conversion::default()),
+                Err(ref e) => Err(e.to_string()),
+            };
+            report.files.push(FileReport::new(input_file_name, &outputs, conversion, cached, log::diagnostics(&messages)));
+        }
+        match result {
+            Ok(_) => hits += cached as usize,
+            Err(MocapError::Cancelled) => cancelled += 1,
+            Err(e) => failures.push((input_file_name.strip_prefix(input_dir).unwrap().to_path_buf(), e)),
+        }
+    }
+
+    println!();
+    println!("{} converted, {} failed{}", input_file_names.len() - failures.len() - cancelled, failures.len(),
+        if cancelled > 0 { format!(", {} cancelled", cancelled) } else { String::new() });
+    for (relative, e) in failures.iter() {
+        println!("    {}: {}", relative.display(), e);
+    }
+    if let Some(cache) = cache {
+        println!("cache: {} hits, {} misses", hits, input_file_names.len() - hits);
+        if let Some(max_size) = options.cache_max_size {
+            let evicted = cache.evict(max_size)?;
+            if evicted > 0 {
+                println!("cache: evicted {} entries to stay under {} bytes", evicted, max_size);
+            }
+        }
+    }
+
+    if let Some(ref manifest_file_name) = options.manifest_file_name {
+        manifest::write(Path::new(manifest_file_name), "batch", options, &manifest_entries)?;
+    }
+    if let Some(ref report_file_name) = options.report_file_name {
+        write_report(&report, report_file_name, options)?;
+    }
+
+    if cancelled > 0 {
+        Err(MocapError::Cancelled)
+    } else if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(MocapError::BatchFailed(failures.len(), input_file_names.len()))
+    }
+}
+
+// A counting semaphore over input bytes.
+struct InputBudget {
+    max: u64,
+    in_flight: Mutex<u64>,
+    released: Condvar,
+}
+
+impl InputBudget {
+    fn new(max: u64) -> InputBudget {
+        InputBudget {
+            max: max,
+            in_flight: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self, size: u64) {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        while *in_flight > 0 && *in_flight + size > self.max {
+            in_flight = self.released.wait(in_flight).unwrap_or_else(|e| e.into_inner());
+        }
+        *in_flight += size;
+    }
+
+    fn release(&self, size: u64) {
+        *self.in_flight.lock().unwrap_or_else(|e| e.into_inner()) -= size;
+        self.released.notify_all();
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "panicked".into(),
+    }
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Converted(Conversion),
+    Cached,
+}
+
+fn convert(input_dir: &Path, output_dir: &Path, input_file_name: &Path, options: &Options, cache: Option<&Cache>, cancel: Option<&AtomicBool>) -> Result<Outcome, MocapError> {
+    let relative = input_file_name.strip_prefix(input_dir).unwrap();
+    let output_file_names = output_file_names(input_dir, output_dir, input_file_name);
+    let output_file_names = output_file_names.iter().map(|file_name| file_name.as_path()).collect::<Vec<_>>();
+
+    output_file_names[0].parent().map_or(Ok(()), fs::create_dir_all)?;
+    let key = match cache {
+        Some(cache) => {
+            let key = cache.key(&fs::read(input_file_name)?);
+            if cache.fetch(&key, &output_file_names)? {
+                return Ok(Outcome::Cached);
+            }
+            Some(key)
+        }
+        None => None,
+    };
+
+    let conversion = ::convert(input_file_name, output_file_names[0], output_file_names[1], output_file_names[2], options, cancel)?;
+
+    if let (Some(cache), Some(key)) = (cache, key) {
+        if let Err(e) = cache.store(&key, &output_file_names) {
+            log::warning(format!("{}: couldn't store the outputs in the cache: {}", relative.display(), e));
+        }
+    }
+    Ok(Outcome::Converted(conversion))
+}
+
+// The .bvh, .csv and .raw outputs for an input, mirroring its place in the input tree.
+fn output_file_names(input_dir: &Path, output_dir: &Path, input_file_name: &Path) -> [PathBuf; 3] {
+    let output_base = output_dir.join(input_file_name.strip_prefix(input_dir).unwrap());
+    [output_base.with_extension("bvh"), output_base.with_extension("csv"), output_base.with_extension("raw")]
+}
+
+fn find_bvh_files(dir: &Path, output_dir: &Path, recursive: bool, file_names: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            // Don't descend into our own output if it lives inside the input tree.
+            if recursive && !is_same_dir(&path, output_dir) {
+                find_bvh_files(&path, output_dir, recursive, file_names)?;
+            }
+        } else if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("bvh")) {
+            file_names.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn is_same_dir(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util;
+
+    fn batch_options(args: &[&str]) -> Options {
+        Options::parse(["batch"].iter().chain(args.iter()).map(|arg| arg.to_string())).unwrap()
+    }
+
+    // Five clips of different lengths and a file that doesn't parse
+    fn inputs(input_dir: &Path) -> Vec<PathBuf> {
+        let mut ret = Vec::new();
+        for i in 0..5 {
+            let file_name = input_dir.join(format!("clip{}.bvh", i));
+            fs::write(&file_name, test_util::clip_text(10 + i * 7, test_util::sine)).unwrap();
+            ret.push(file_name);
+        }
+        fs::write(input_dir.join("broken.bvh"), "HIERARCHY\nROOT Hips\n{").unwrap();
+        ret
+    }
+
+    #[test]
+    fn converts_every_input_and_reports_the_failure() {
+        let dir = test_util::temp_dir("batch");
+        let (input_dir, output_dir) = (dir.join("in"), dir.join("out"));
+        fs::create_dir_all(&input_dir).unwrap();
+        let input_file_names = inputs(&input_dir);
+        let options = batch_options(&["--jobs", "4", "--skeleton-hash", input_dir.to_str().unwrap(), output_dir.to_str().unwrap()]);
+
+        match run(&input_dir, &output_dir, &options, None) {
+            Err(MocapError::BatchFailed(1, 6)) => (),
+            result => panic!("expected one failure of six, got {:?}", result),
+        }
+        for input_file_name in input_file_names.iter() {
+            for output_file_name in output_file_names(&input_dir, &output_dir, input_file_name).iter() {
+                assert!(output_file_name.exists(), "{} is missing", output_file_name.display());
+            }
+        }
+        for output_file_name in output_file_names(&input_dir, &output_dir, &input_dir.join("broken.bvh")).iter() {
+            assert!(!output_file_name.exists(), "{} was left behind", output_file_name.display());
+        }
+    }
+
+    #[test]
+    fn captures_each_files_informational_output() {
+        let dir = test_util::temp_dir("batch-messages");
+        let (input_dir, output_dir) = (dir.join("in"), dir.join("out"));
+        fs::create_dir_all(&input_dir).unwrap();
+        let input_file_names = inputs(&input_dir);
+        let options = batch_options(&["--skeleton-hash", input_dir.to_str().unwrap(), output_dir.to_str().unwrap()]);
+
+        // Converted on separate threads at once, as a batch with several jobs would
+        let (input_dir, output_dir, options) = (&input_dir, &output_dir, &options);
+        thread::scope(|scope| {
+            for input_file_name in input_file_names.iter() {
+                scope.spawn(move || {
+                    let (result, messages) = log::capture(|| convert(input_dir, output_dir, input_file_name, options, None, None));
+                    assert!(matches!(result, Ok(Outcome::Converted(_))));
+                    assert_eq!(messages.len(), 1);
+                    match messages[0] {
+                        log::Message::Info(ref line) => assert!(line.starts_with(&format!("{}: skeleton hash", input_file_name.display())), "{}", line),
+                        ref message => panic!("expected an informational line, got {:?}", message),
+                    }
+                    assert!(log::diagnostics(&messages).is_empty());
+                });
+            }
+        });
+    }
+}
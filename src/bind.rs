@@ -0,0 +1,186 @@
+use std::path::Path;
+
+use bvh;
+
+use diff;
+use error::MocapError;
+use input;
+use options::Options;
+use {count_bvh_channels, rotation_channels};
+
+// Bind poses. An engine that composes animation on top of a skeleton's bind pose wants channel
+// values relative to that pose rather than absolute. With --bind-pose every frame has the pose
+// subtracted before quantization, so the encoded values are the relative ones, and the pose is
+// recorded in the metadata (as its channel values in flat channel order, separated by spaces) for
+// the runtime, or decode --add-bind-pose, to add back.
+//
+// This is also how additive layers are stored: an additive clip is its offset from a reference
+// pose, usually one frame of the base clip it's layered on, so `--bind-pose base.bvh@<frame>`
+// takes the pose from that frame of the base file rather than its first. Subtle layered motion
+// then spans a small range and quantizes finely. The base's skeleton has to match the clip's.
+//
+// Rotations are subtracted wrap-aware, into [-180, 180) degrees, so a joint turning past 180
+// degrees from the pose doesn't take up the whole range; adding the pose back then gives the
+// original angles up to multiples of 360 degrees.
+
+// Metadata key holding the bind pose
+pub const KEY: &str = "bind_pose";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BindPose {
+    FirstFrame,
+    Zero, // Every channel 0, which just wraps rotations
+    File(String, usize), // A frame (the first by default) of a BVH file with the same skeleton
+}
+
+impl BindPose {
+    // `first-frame`, `zero` or a file, as --bind-pose takes.
+    pub fn parse(s: &str) -> BindPose {
+        match s {
+            "first-frame" => BindPose::FirstFrame,
+            "zero" => BindPose::Zero,
+            file_name => BindPose::file(file_name),
+        }
+    }
+
+    // `<file.bvh>[@<frame>]`; a suffix that isn't a frame number is part of the file name.
+    pub fn file(s: &str) -> BindPose {
+        match s.rsplit_once('@').and_then(|(file_name, frame)| Some((file_name, frame.parse::<usize>().ok()?))) {
+            Some((file_name, frame)) => BindPose::File(file_name.into(), frame),
+            None => BindPose::File(s.into(), 0),
+        }
+    }
+}
+
+// The pose `bind_pose` designates for `bvh` (after the input passes), in flat channel order.
+pub fn pose(bvh: &bvh::Bvh, bind_pose: &BindPose, options: &Options) -> Result<Vec<f64>, MocapError> {
+    match *bind_pose {
+        BindPose::FirstFrame => bvh.motion.frames.first().cloned().ok_or_else(|| MocapError::Usage("--bind-pose first-frame needs a clip with frames".into())),
+        BindPose::Zero => Ok(vec![0.0; count_bvh_channels(&bvh.hierarchy.root)]),
+        BindPose::File(ref file_name, frame) => {
+            let pose = input::read_bvh(Path::new(file_name), options)?;
+            if let Some(mismatch) = diff::skeleton_mismatch(&bvh.hierarchy.root, &pose.hierarchy.root, "") {
+                return Err(MocapError::SkeletonMismatch(format!("{}: the bind pose's skeleton differs: {}", file_name, mismatch)));
+            }
+            let num_frames = pose.motion.frames.len();
+            pose.motion.frames.into_iter().nth(frame).ok_or_else(|| MocapError::Usage(format!("{}: the bind pose file has {} frames, no frame {}", file_name, num_frames, frame)))
+        }
+    }
+}
+
+// Subtracts `pose` from every frame, returning the metadata recording it.
+pub fn subtract(bvh: &mut bvh::Bvh, pose: &[f64]) -> Vec<(String, String)> {
+    let rotations = rotation_channels(&bvh.hierarchy.root);
+    for frame in bvh.motion.frames.iter_mut() {
+        for ((value, pose_value), rotation) in frame.iter_mut().zip(pose.iter()).zip(rotations.iter()) {
+            *value -= pose_value;
+            if *rotation {
+                *value -= 360.0 * ((*value + 180.0) / 360.0).floor();
+            }
+        }
+    }
+
+    vec![(KEY.into(), pose.iter().map(|value| format!("{}", value)).collect::<Vec<_>>().join(" "))]
+}
+
+// Adds back the bind pose recorded in `metadata`, returning whether there was one.
+pub fn add(bvh: &mut bvh::Bvh, metadata: &[(String, String)]) -> Result<bool, MocapError> {
+    let pose = match metadata.iter().find(|entry| entry.0 == KEY) {
+        Some(entry) => entry.1.split_whitespace().map(|value| value.parse::<f64>()).collect::<Result<Vec<_>, _>>()
+            .map_err(|_| MocapError::InvalidRaw("invalid bind pose".into()))?,
+        None => return Ok(false),
+    };
+    let num_channels = count_bvh_channels(&bvh.hierarchy.root);
+    if pose.len() != num_channels {
+        return Err(MocapError::InvalidRaw(format!("the bind pose has {} channels, not {}", pose.len(), num_channels)));
+    }
+
+    for frame in bvh.motion.frames.iter_mut() {
+        for (value, pose_value) in frame.iter_mut().zip(pose.iter()) {
+            *value += pose_value;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use conversion::ConversionSettings;
+    use depth::DEFAULT_MAX_DEPTH;
+    use periodic::Limits;
+    use raw;
+    use test_util;
+    use {build_bvh, build_mocap};
+
+    const NUM_FRAMES: usize = 40;
+
+    // The difference between two angles, or translations, ignoring whole turns for rotations
+    fn difference(a: f64, b: f64, rotation: bool) -> f64 {
+        let difference = (a - b).abs();
+        if rotation {
+            let difference = difference % 360.0;
+            difference.min(360.0 - difference)
+        } else {
+            difference
+        }
+    }
+
+    #[test]
+    fn round_trip_with_the_pose_added_back_is_within_a_step() {
+        let original = test_util::sine_clip(NUM_FRAMES);
+        let options = test_util::options(&[]);
+        for bind_pose in [BindPose::FirstFrame, BindPose::Zero].iter() {
+            let mut bvh = test_util::sine_clip(NUM_FRAMES);
+            let pose = pose(&bvh, bind_pose, &options).unwrap();
+            let metadata = subtract(&mut bvh, &pose);
+            let mut mocap = build_mocap(&bvh, &ConversionSettings::default().settings());
+            mocap.metadata = metadata;
+
+            let mut data = Vec::new();
+            raw::write(&mocap, Limits::default(), &mut data).unwrap();
+            let read = raw::read(&data, DEFAULT_MAX_DEPTH).unwrap();
+            let mut decoded = build_bvh(&read);
+            assert!(add(&mut decoded, &read.metadata).unwrap());
+
+            let steps = read.channels().iter().map(|channel| channel.value_range as f64 / 255.0).collect::<Vec<_>>();
+            let rotations = rotation_channels(&original.hierarchy.root);
+            for (decoded, original) in decoded.motion.frames.iter().zip(original.motion.frames.iter()) {
+                for channel in 0..test_util::NUM_CHANNELS {
+                    assert!(difference(decoded[channel], original[channel], rotations[channel]) <= steps[channel] + 1e-4, "{:?} channel {}: {} against {}", bind_pose, channel, decoded[channel], original[channel]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rotations_are_subtracted_the_short_way() {
+        let mut bvh = test_util::parse(&test_util::clip_text(1, |_, channel| if channel == 3 { 170.0 } else { 0.0 }));
+        let mut pose = vec![0.0; test_util::NUM_CHANNELS];
+        pose[3] = -170.0;
+        // Translations aren't wrapped
+        pose[0] = 500.0;
+        subtract(&mut bvh, &pose);
+        assert_eq!(bvh.motion.frames[0][3], -20.0);
+        assert_eq!(bvh.motion.frames[0][0], -500.0);
+    }
+
+    #[test]
+    fn pose_from_a_frame_of_another_file() {
+        let dir = test_util::temp_dir("bind-pose");
+        let file_name = dir.join("base.bvh");
+        fs::write(&file_name, test_util::clip_text(5, test_util::sine)).unwrap();
+        let options = test_util::options(&[]);
+        let clip = test_util::sine_clip(NUM_FRAMES);
+
+        let file_name = file_name.to_str().unwrap();
+        assert_eq!(pose(&clip, &BindPose::file(&format!("{}@3", file_name)), &options).unwrap(), test_util::sine_clip(5).motion.frames[3]);
+        assert!(matches!(pose(&clip, &BindPose::file(&format!("{}@5", file_name)), &options), Err(MocapError::Usage(_))));
+
+        let other_skeleton = dir.join("other.bvh");
+        fs::write(&other_skeleton, test_util::clip_text(5, test_util::sine).replacen("JOINT Head", "JOINT Neck", 1)).unwrap();
+        assert!(matches!(pose(&clip, &BindPose::file(other_skeleton.to_str().unwrap()), &options), Err(MocapError::SkeletonMismatch(_))));
+    }
+}
@@ -0,0 +1,219 @@
+use max_level;
+
+// Bit packing for the delta blocks of the .raw format. At `bits` bits per level a delta only needs
+// `bits` bits: levels are in [0, 2^bits), so the delta modulo 2^bits loses nothing, the decoder
+// adding it to the previous level modulo 2^bits. A 4-bit clip thus takes half the bytes of an 8-bit
+// one, and at 8 bits a packed delta is the same byte it always was.
+//
+// Each channel's run of deltas in a block is packed least significant bit first and padded to a
+// whole byte, so every run starts on a byte and can be found without unpacking what precedes it.
+//
+// With --bit-planes a run is stored as bit planes instead: the most significant bit of every delta
+// in the run, then the next bit of every delta, and so on down to the least significant, each bit
+// still least significant bit first within a byte, and the whole run padded to a byte as before,
+// so it takes the same number of bytes. Small deltas, positive or negative, share their high bits,
+// so the high planes are long runs of equal bits and only the low ones look random, which can give
+// a general-purpose compressor (gzip, zstd) applied to the file more to work with. It's a different
+// order of the same bits, and doesn't make the file itself any smaller. Whether it helps depends on
+// the clip and the bit depth, so it's worth measuring: at 8 bits every packed delta is a byte of
+// its own, which compressors already model well, and splitting it up can make things worse.
+//
+// In memory, deltas stay the i8 differences between consecutive levels (see `Channel::deltas`);
+// only the file is packed.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    Packed,
+    BitPlanes,
+}
+
+// The bytes `count` deltas take at `bits` bits.
+pub fn packed_len(count: usize, bits: u8) -> usize {
+    (count * bits as usize).div_ceil(8)
+}
+
+// Appends `deltas`, packed at `bits` bits in `layout`, to `out`.
+pub fn pack(deltas: &[i8], bits: u8, layout: Layout, out: &mut Vec<u8>) {
+    if layout == Layout::BitPlanes {
+        return pack_planes(deltas, bits, out);
+    }
+    let mask = max_level(bits);
+    let mut accumulator = 0u32;
+    let mut num_bits = 0;
+    for delta in deltas.iter() {
+        accumulator |= (((*delta as u8) & mask) as u32) << num_bits;
+        num_bits += bits;
+        while num_bits >= 8 {
+            out.push(accumulator as u8);
+            accumulator >>= 8;
+            num_bits -= 8;
+        }
+    }
+    if num_bits > 0 {
+        out.push(accumulator as u8);
+    }
+}
+
+fn pack_planes(deltas: &[i8], bits: u8, out: &mut Vec<u8>) {
+    let start = out.len();
+    out.resize(start + packed_len(deltas.len(), bits), 0);
+    for plane in 0..bits as usize {
+        let bit = bits as usize - 1 - plane;
+        for (index, delta) in deltas.iter().enumerate() {
+            let position = plane * deltas.len() + index;
+            out[start + position / 8] |= (((*delta as u8) >> bit) & 1) << (position % 8);
+        }
+    }
+}
+
+// Calls `f` with each of the first `count` of the `len` deltas packed at `bits` bits in `layout`
+// in `data` (at least `packed_len(len, bits)` bytes), as the i8 difference between consecutive
+// levels. `level` is the level before the first delta, and is left at the level after the last,
+// so a channel's runs in consecutive blocks can be unpacked one after another.
+pub fn unpack<F: FnMut(i8)>(data: &[u8], len: usize, count: usize, bits: u8, layout: Layout, level: &mut u8, mut f: F) {
+    let mask = max_level(bits);
+    if layout == Layout::BitPlanes {
+        for index in 0..count {
+            let delta = (0..bits as usize).fold(0u8, |delta, plane| {
+                let position = plane * len + index;
+                (delta << 1) | ((data[position / 8] >> (position % 8)) & 1)
+            });
+            let next_level = level.wrapping_add(delta) & mask;
+            f((next_level as i8).wrapping_sub(*level as i8));
+            *level = next_level;
+        }
+        return;
+    }
+    let mut bytes = data.iter();
+    let mut accumulator = 0u32;
+    let mut num_bits = 0;
+    for _ in 0..count {
+        while num_bits < bits {
+            accumulator |= (*bytes.next().unwrap() as u32) << num_bits;
+            num_bits += 8;
+        }
+        let next_level = level.wrapping_add((accumulator as u8) & mask) & mask;
+        accumulator >>= bits;
+        num_bits -= bits;
+        f((next_level as i8).wrapping_sub(*level as i8));
+        *level = next_level;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conversion::ConversionSettingsBuilder;
+    use depth::DEFAULT_MAX_DEPTH;
+    use periodic::Limits;
+    use raw;
+    use test_util;
+    use {build_bvh, build_mocap};
+
+    // Levels covering every bit of the depth, with jumps between the extremes
+    fn levels(bits: u8, count: usize) -> Vec<u8> {
+        let mut state = 12345u32;
+        (0..count).map(|index| match index % 5 {
+            0 => 0,
+            1 => max_level(bits),
+            _ => {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                ((state >> 16) as u8) & max_level(bits)
+            }
+        }).collect()
+    }
+
+    fn deltas(first: u8, levels: &[u8]) -> Vec<i8> {
+        let mut previous = first;
+        levels.iter().map(|level| {
+            let ret = (*level as i8).wrapping_sub(previous as i8);
+            previous = *level;
+            ret
+        }).collect()
+    }
+
+    fn unpacked_levels(data: &[u8], len: usize, bits: u8, layout: Layout, first: u8) -> Vec<u8> {
+        let mut level = first;
+        let mut ret = Vec::new();
+        let mut previous = first;
+        unpack(data, len, len, bits, layout, &mut level, |delta| {
+            previous = (previous as i8).wrapping_add(delta) as u8;
+            ret.push(previous);
+        });
+        assert_eq!(Some(&level), ret.last());
+        ret
+    }
+
+    #[test]
+    fn round_trips_at_every_tested_depth() {
+        for bits in [1, 3, 4, 6, 8].iter().cloned() {
+            for layout in [Layout::Packed, Layout::BitPlanes].iter().cloned() {
+                for count in [1, 7, 8, 9, 100].iter().cloned() {
+                    let levels = levels(bits, count);
+                    let mut data = vec![0xaa]; // Packing appends
+                    pack(&deltas(0, &levels), bits, layout, &mut data);
+                    assert_eq!(data.len(), 1 + packed_len(count, bits), "{} bits, {:?}, {} deltas", bits, layout, count);
+                    assert_eq!(unpacked_levels(&data[1..], count, bits, layout, 0), levels, "{} bits, {:?}, {} deltas", bits, layout, count);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn packs_tightly_across_bytes() {
+        assert_eq!(packed_len(8, 1), 1);
+        assert_eq!(packed_len(8, 3), 3);
+        assert_eq!(packed_len(3, 3), 2);
+        assert_eq!(packed_len(8, 4), 4);
+        assert_eq!(packed_len(4, 6), 3);
+
+        // Least significant bit first: 0b101, 0b011 and 0b110 straddle the first byte
+        let mut data = Vec::new();
+        pack(&[0b101, 0b011, 0b110], 3, Layout::Packed, &mut data);
+        assert_eq!(data, vec![0b1001_1101, 0b0000_0001]);
+
+        // At 8 bits a packed delta is its own byte
+        let deltas = [-128, -1, 0, 1, 127];
+        let mut data = Vec::new();
+        pack(&deltas, 8, Layout::Packed, &mut data);
+        assert_eq!(data, deltas.iter().map(|delta| *delta as u8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn unpacks_a_prefix_and_continues_from_the_level() {
+        let levels = levels(4, 20);
+        let mut data = Vec::new();
+        pack(&deltas(3, &levels[..10]), 4, Layout::Packed, &mut data);
+        let split = data.len();
+        pack(&deltas(levels[9], &levels[10..]), 4, Layout::Packed, &mut data);
+
+        let mut level = 3;
+        let mut count = 0;
+        unpack(&data, 10, 6, 4, Layout::Packed, &mut level, |_| count += 1);
+        assert_eq!((count, level), (6, levels[5]));
+
+        let mut level = 3;
+        unpack(&data, 10, 10, 4, Layout::Packed, &mut level, |_| ());
+        unpack(&data[split..], 10, 10, 4, Layout::Packed, &mut level, |_| ());
+        assert_eq!(level, levels[19]);
+    }
+
+    #[test]
+    fn lower_depths_shrink_the_raw_file() {
+        let bvh = test_util::sine_clip(400);
+        let mut sizes = Vec::new();
+        for bits in [1, 3, 4, 6, 8].iter().cloned() {
+            let settings = ConversionSettingsBuilder::default().channel_quantization_bits(bits).build().unwrap().settings();
+            let mocap = build_mocap(&bvh, &settings);
+            let mut data = Vec::new();
+            raw::write(&mocap, Limits::default(), &mut data).unwrap();
+            assert_eq!(build_bvh(&raw::read(&data, DEFAULT_MAX_DEPTH).unwrap()).motion.frames, build_bvh(&mocap).motion.frames, "{} bits", bits);
+            sizes.push(data.len());
+        }
+        assert!(sizes.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", sizes);
+
+        // Each channel's 400 deltas (the first relative to its initial level) take half the bytes at
+        // 4 bits; the rest of the file is the same
+        assert_eq!(sizes[4] - sizes[2], test_util::NUM_CHANNELS * (400 - packed_len(400, 4)));
+    }
+}
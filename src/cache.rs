@@ -0,0 +1,370 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use bind::BindPose;
+use mask::Mask;
+use error::MocapError;
+use log;
+use manifest;
+use options::Options;
+use profile::Profile;
+use raw;
+
+// A cache of batch conversion outputs, passed with --cache-dir. Each entry is keyed by a hash of
+// an input file's bytes together with everything else that affects its outputs (the conversion
+// options and the contents of any files they name), so an unchanged input converted with the same
+// options is copied from the cache instead of converted again. The layout is
+//
+//   <cache dir>/<key>/output.bvh, output.csv, output.raw
+//   <cache dir>/<key>/entry        one "<file name> <size> <hash>" line per output, written last
+//
+// An entry whose outputs don't match its `entry` file (truncated, edited, half-deleted) is
+// removed and the input converted again. Entries are written to a temporary directory and
+// renamed into place, so concurrent jobs never see a partial entry. `evict` removes the least
+// recently used entries (going by the `entry` file's modification time, touched on every hit)
+// to keep the cache under a size limit.
+
+// Bumped whenever the cache layout changes, or outputs change without the raw format version
+// changing
+const CACHE_VERSION: u32 = 1;
+
+const ENTRY_FILE_NAME: &str = "entry";
+const TEMPORARY_PREFIX: &str = "tmp-";
+
+pub struct Cache {
+    dir: PathBuf,
+    settings_hash: u128,
+    next_temporary: AtomicUsize,
+}
+
+impl Cache {
+    pub fn open(dir: &Path, options: &Options) -> Result<Cache, MocapError> {
+        fs::create_dir_all(dir)?;
+        Ok(Cache {
+            dir: dir.to_path_buf(),
+            settings_hash: settings_hash(options)?,
+            next_temporary: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn key(&self, input: &[u8]) -> String {
+        let mut data = self.settings_hash.to_le_bytes().to_vec();
+        data.extend_from_slice(input);
+        format!("{:032x}", hash(&data))
+    }
+
+    // Copies the cached outputs for `key` to `output_file_names` (which must be named by
+    // extension as they were stored), returning whether there was a valid entry.
+    pub fn fetch(&self, key: &str, output_file_names: &[&Path]) -> Result<bool, MocapError> {
+        let entry_dir = self.dir.join(key);
+        let entry = match fs::read_to_string(entry_dir.join(ENTRY_FILE_NAME)) {
+            Ok(entry) => entry,
+            // Without an entry file a directory can only be left over from a removal cut short
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && !entry_dir.exists() => return Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let cached_file_names = output_file_names.iter().map(|file_name| cached_file_name(file_name)).collect::<Vec<_>>();
+        if !is_valid_entry(&entry_dir, &entry, &cached_file_names) {
+            log::warning(format!("cache entry {} is corrupt, converting again", key));
+            let _ = fs::remove_dir_all(&entry_dir);
+            return Ok(false);
+        }
+
+        for (output_file_name, cached_file_name) in output_file_names.iter().zip(cached_file_names.iter()) {
+            fs::copy(entry_dir.join(cached_file_name), manifest::stage(output_file_name))?;
+            manifest::register(output_file_name);
+        }
+        File::options().write(true).open(entry_dir.join(ENTRY_FILE_NAME))?.set_modified(SystemTime::now())?;
+        Ok(true)
+    }
+
+    pub fn store(&self, key: &str, output_file_names: &[&Path]) -> io::Result<()> {
+        let temporary_dir = self.dir.join(format!("{}{}-{}", TEMPORARY_PREFIX, process::id(), self.next_temporary.fetch_add(1, Ordering::SeqCst)));
+        fs::create_dir_all(&temporary_dir)?;
+        let result = (|| {
+            let mut entry = String::new();
+            for output_file_name in output_file_names.iter() {
+                let data = fs::read(manifest::written(output_file_name))?;
+                let cached_file_name = cached_file_name(output_file_name);
+                fs::write(temporary_dir.join(&cached_file_name), &data)?;
+                entry.push_str(&format!("{} {} {:032x}\n", cached_file_name, data.len(), hash(&data)));
+            }
+            File::create(temporary_dir.join(ENTRY_FILE_NAME))?.write_all(entry.as_bytes())?;
+            fs::rename(&temporary_dir, self.dir.join(key))
+        })();
+        if result.is_err() {
+            // Also covers another job having stored the same entry first
+            let _ = fs::remove_dir_all(&temporary_dir);
+        }
+        result
+    }
+
+    // Removes the least recently used entries until the cache takes at most `max_size` bytes,
+    // returning how many were removed.
+    pub fn evict(&self, max_size: u64) -> io::Result<usize> {
+        let mut entries = Vec::new();
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            if !path.is_dir() || path.file_name().is_some_and(|name| name.to_string_lossy().starts_with(TEMPORARY_PREFIX)) {
+                continue;
+            }
+            let used = fs::metadata(path.join(ENTRY_FILE_NAME)).and_then(|metadata| metadata.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+            entries.push((used, dir_size(&path)?, path));
+        }
+        entries.sort();
+
+        let mut total = entries.iter().map(|entry| entry.1).sum::<u64>();
+        let mut ret = 0;
+        for (_, size, path) in entries.iter() {
+            if total <= max_size {
+                break;
+            }
+            fs::remove_dir_all(path)?;
+            total -= size;
+            ret += 1;
+        }
+        Ok(ret)
+    }
+}
+
+fn cached_file_name(output_file_name: &Path) -> String {
+    format!("output.{}", output_file_name.extension().map(|extension| extension.to_string_lossy().into_owned()).unwrap_or_default())
+}
+
+fn is_valid_entry(entry_dir: &Path, entry: &str, cached_file_names: &[String]) -> bool {
+    let lines = entry.lines().collect::<Vec<_>>();
+    lines.len() == cached_file_names.len() && lines.iter().zip(cached_file_names.iter()).all(|(line, cached_file_name)| {
+        let fields = line.split(' ').collect::<Vec<_>>();
+        match fields.as_slice() {
+            [name, size, expected_hash] if name == cached_file_name => match fs::read(entry_dir.join(name)) {
+                Ok(data) => format!("{}", data.len()) == *size && format!("{:032x}", hash(&data)) == *expected_hash,
+                Err(_) => false,
+            },
+            _ => false,
+        }
+    })
+}
+
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut ret = 0;
+    for dir_entry in fs::read_dir(dir)? {
+        ret += dir_entry?.metadata()?.len();
+    }
+    Ok(ret)
+}
+
+// The hash of everything besides the input that a batch conversion's outputs depend on, as keys
+// combine it with the input's.
+pub fn settings_hash(options: &Options) -> Result<u128, MocapError> {
+    Ok(hash(&settings_fingerprint(options)?))
+}
+
+// Everything besides the input that a batch conversion's outputs depend on: the conversion
+// options (see `Options::conversion_args`) and the contents of the files they name, or the
+// profile does.
+fn settings_fingerprint(options: &Options) -> Result<Vec<u8>, MocapError> {
+    let mut ret = format!("{} {} {}", CACHE_VERSION, raw::FORMAT_VERSION, env!("CARGO_PKG_VERSION")).into_bytes();
+    for arg in options.conversion_args() {
+        ret.push(0);
+        ret.extend_from_slice(arg.as_bytes());
+    }
+    let conversion = match options.profile_file_name {
+        Some(ref profile_file_name) => Profile::read(Path::new(profile_file_name))?.conversion(&options.conversion_builder, profile_file_name)?,
+        None => options.conversion.clone(),
+    };
+    let bind_pose_file_name = match conversion.bind_pose() {
+        Some(BindPose::File(ref file_name, _)) => Some(file_name.clone()),
+        _ => None,
+    };
+    let mask_file_name = match conversion.mask() {
+        Some(Mask::File(ref file_name)) => Some(file_name.clone()),
+        _ => None,
+    };
+    for file_name in [&options.markers_file_name, &options.profile_file_name, &bind_pose_file_name, &mask_file_name, &options.ranges_in_file_name].iter() {
+        match **file_name {
+            Some(ref file_name) => {
+                let data = fs::read(file_name)?;
+                ret.extend_from_slice(&(data.len() as u64).to_le_bytes());
+                ret.extend_from_slice(&data);
+            }
+            None => ret.push(0),
+        }
+    }
+    Ok(ret)
+}
+
+// 128-bit FNV-1a. Not cryptographic, but with 128 bits accidental collisions aren't a concern.
+pub fn hash(data: &[u8]) -> u128 {
+    let mut ret: u128 = 0x6c62272e07bb014262b821756295c58d;
+    for byte in data.iter() {
+        ret ^= *byte as u128;
+        ret = ret.wrapping_mul(0x0000000001000000000000000000013b);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use batch;
+    use json::{self, Value};
+    use test_util;
+
+    const NUM_CLIPS: usize = 3;
+
+    // A batch directory with `NUM_CLIPS` clips in in/, converting to out/ with a cache in cache/
+    fn batch_dir(name: &str) -> test_util::TempDir {
+        let dir = test_util::temp_dir(name);
+        fs::create_dir_all(dir.join("in")).unwrap();
+        for i in 0..NUM_CLIPS {
+            fs::write(dir.join("in").join(format!("clip{}.bvh", i)), test_util::clip_text(10 + i * 7, test_util::sine)).unwrap();
+        }
+        dir
+    }
+
+    fn batch_options(dir: &Path, args: &[&str]) -> Options {
+        let dir_arg = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let mut all_args = vec!["batch".to_string(), "--cache-dir".into(), dir_arg("cache"), "--report".into(), dir_arg("report.json")];
+        all_args.extend(args.iter().map(|arg| arg.to_string()));
+        all_args.extend(vec![dir_arg("in"), dir_arg("out")]);
+        Options::parse(all_args.into_iter()).unwrap()
+    }
+
+    // Runs the batch in `dir`, returning its report's files
+    fn run_batch(dir: &Path, args: &[&str]) -> Vec<Value> {
+        batch::run(&dir.join("in"), &dir.join("out"), &batch_options(dir, args), None).unwrap();
+        let report = json::parse(&fs::read_to_string(dir.join("report.json")).unwrap()).unwrap();
+        report.get("files").and_then(Value::as_array).unwrap().to_vec()
+    }
+
+    fn cached(files: &[Value]) -> Vec<bool> {
+        files.iter().map(|file| file.get("cached").and_then(Value::as_bool).unwrap()).collect()
+    }
+
+    // The phases a file's conversion went through, as its report times them
+    fn phases(file: &Value) -> Vec<String> {
+        match file.get("timings") {
+            Some(Value::Object(ref timings)) => timings.iter().map(|timing| timing.0.clone()).collect(),
+            timings => panic!("{:?}", timings),
+        }
+    }
+
+    fn outputs(dir: &Path) -> Vec<Vec<u8>> {
+        let mut file_names = fs::read_dir(dir.join("out")).unwrap().map(|entry| entry.unwrap().path()).collect::<Vec<_>>();
+        file_names.sort();
+        file_names.iter().map(|file_name| fs::read(file_name).unwrap()).collect()
+    }
+
+    fn entry_dir(dir: &Path, clip: usize) -> PathBuf {
+        let cache = Cache::open(&dir.join("cache"), &batch_options(dir, &[])).unwrap();
+        let key = cache.key(&fs::read(dir.join("in").join(format!("clip{}.bvh", clip))).unwrap());
+        dir.join("cache").join(key)
+    }
+
+    fn num_entries(dir: &Path) -> usize {
+        fs::read_dir(dir.join("cache")).unwrap().count()
+    }
+
+    #[test]
+    fn a_second_run_copies_every_output_from_the_cache() {
+        let dir = batch_dir("cache-hits");
+        let first = run_batch(&dir, &[]);
+        assert_eq!(cached(&first), vec![false; NUM_CLIPS]);
+        assert!(first.iter().all(|file| phases(file) == vec!["load", "encode", "write"]));
+        let converted = outputs(&dir);
+        assert_eq!(converted.len(), NUM_CLIPS * 3);
+        assert_eq!(num_entries(&dir), NUM_CLIPS);
+
+        // No file is loaded, let alone quantized, the second time round
+        fs::remove_dir_all(dir.join("out")).unwrap();
+        let second = run_batch(&dir, &[]);
+        assert_eq!(cached(&second), vec![true; NUM_CLIPS]);
+        assert!(second.iter().all(|file| phases(file).is_empty()));
+        assert_eq!(outputs(&dir), converted);
+
+        // A setting changing the outputs misses
+        assert_eq!(cached(&run_batch(&dir, &["--bits", "6"])), vec![false; NUM_CLIPS]);
+        assert_eq!(num_entries(&dir), NUM_CLIPS * 2);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_entries() {
+        let dir = batch_dir("cache-evict");
+        run_batch(&dir, &[]);
+        let sizes = (0..NUM_CLIPS).map(|clip| dir_size(&entry_dir(&dir, clip)).unwrap()).collect::<Vec<_>>();
+        let total = sizes.iter().sum::<u64>();
+
+        // clip1 used last, clip2 before it and clip0 longest ago
+        for (clip, age) in [(0, 300), (1, 100), (2, 200)].iter() {
+            let used = SystemTime::now() - Duration::from_secs(*age);
+            File::options().write(true).open(entry_dir(&dir, *clip).join(ENTRY_FILE_NAME)).unwrap().set_modified(used).unwrap();
+        }
+        let cache = Cache::open(&dir.join("cache"), &batch_options(&dir, &[])).unwrap();
+        assert_eq!(cache.evict(total).unwrap(), 0);
+        assert_eq!(cache.evict(total - 1).unwrap(), 1);
+        assert!(!entry_dir(&dir, 0).exists());
+        assert_eq!(cache.evict(sizes[1]).unwrap(), 1);
+        assert!(!entry_dir(&dir, 2).exists() && entry_dir(&dir, 1).exists());
+
+        // A fetch counts as a use
+        let entry_file_name = entry_dir(&dir, 1).join(ENTRY_FILE_NAME);
+        let used = SystemTime::now() - Duration::from_secs(1000);
+        File::options().write(true).open(&entry_file_name).unwrap().set_modified(used).unwrap();
+        let output_file_names = ["bvh", "csv", "raw"].iter().map(|extension| dir.join("out").join("clip1").with_extension(extension)).collect::<Vec<_>>();
+        assert!(cache.fetch(&entry_dir(&dir, 1).file_name().unwrap().to_string_lossy(), &output_file_names.iter().map(|file_name| file_name.as_path()).collect::<Vec<_>>()).unwrap());
+        assert!(fs::metadata(&entry_file_name).unwrap().modified().unwrap() > used + Duration::from_secs(900));
+
+        // With --cache-max-size 0 a batch leaves no entry behind
+        run_batch(&dir, &["--cache-max-size", "0"]);
+        assert_eq!(num_entries(&dir), 0);
+    }
+
+    #[test]
+    fn a_corrupt_entry_is_converted_again() {
+        let dir = batch_dir("cache-corrupt");
+        run_batch(&dir, &[]);
+        let converted = outputs(&dir);
+
+        // A truncated output, and an entry whose entry file was removed
+        let raw_file_name = entry_dir(&dir, 0).join("output.raw");
+        let data = fs::read(&raw_file_name).unwrap();
+        fs::write(&raw_file_name, &data[..data.len() / 2]).unwrap();
+        fs::remove_file(entry_dir(&dir, 2).join(ENTRY_FILE_NAME)).unwrap();
+
+        fs::remove_dir_all(dir.join("out")).unwrap();
+        let files = run_batch(&dir, &[]);
+        assert_eq!(cached(&files), vec![false, true, false]);
+        for (clip, file) in files.iter().enumerate() {
+            let warnings = file.get("warnings").and_then(Value::as_array).unwrap().iter().map(|warning| warning.as_str().unwrap().to_string()).collect::<Vec<_>>();
+            let key = entry_dir(&dir, clip).file_name().unwrap().to_string_lossy().into_owned();
+            assert_eq!(warnings, if clip == 1 { vec![] } else { vec![format!("warning: cache entry {} is corrupt, converting again", key)] });
+        }
+        assert_eq!(outputs(&dir), converted);
+
+        // Both stored again
+        assert_eq!(cached(&run_batch(&dir, &[])), vec![true; NUM_CLIPS]);
+    }
+
+    #[test]
+    fn settings_hash_follows_the_ranges_file() {
+        let dir = test_util::temp_dir("cache-ranges");
+        let ranges_file_name = dir.join("ranges.txt");
+        let ranges_arg = ranges_file_name.to_string_lossy().into_owned();
+        let options = test_util::options(&["--ranges-in", &ranges_arg]);
+
+        fs::write(&ranges_file_name, "Hips Xrotation -90 90\n").unwrap();
+        let first = settings_hash(&options).unwrap();
+        assert_eq!(settings_hash(&options).unwrap(), first);
+        fs::write(&ranges_file_name, "Hips Xrotation -180 180\n").unwrap();
+        assert_ne!(settings_hash(&options).unwrap(), first);
+    }
+}
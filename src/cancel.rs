@@ -0,0 +1,153 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use error::MocapError;
+
+// Cancellation, so a Ctrl-C during a long batch or conversion stops it promptly and cleanly
+// rather than killing the process halfway through writing an output. Cancellable work takes an
+// optional token, an `AtomicBool` set to cancel it, and asks `check` whether it's been set
+// between stages: between the phases of a conversion and its main outputs, between a batch's
+// files, and between the clips or bit depths of the commands handling several. The periodic
+// search, the slowest part of writing a clip, also stops looking for periods once cancelled
+// (checked between channels), so the clip being written finishes quickly. A cancelled piece of
+// work fails with `MocapError::Cancelled`, and its outputs, which are written to temporary files
+// until it succeeds (see manifest.rs), are removed.
+//
+// `run_command_line` passes the token that the first Ctrl-C (SIGINT, or a console Ctrl-C or
+// Ctrl-Break on Windows) sets; a second one kills the process as usual. Without a token nothing is
+// ever cancelled.
+
+// The token Ctrl-C sets, with `handle_interrupts`
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_cancelled(token: Option<&AtomicBool>) -> bool {
+    token.is_some_and(|token| token.load(Ordering::Relaxed))
+}
+
+pub fn check(token: Option<&AtomicBool>) -> Result<(), MocapError> {
+    if is_cancelled(token) {
+        return Err(MocapError::Cancelled);
+    }
+    Ok(())
+}
+
+// Whether Ctrl-C has set the token `handle_interrupts` returns.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+// Installs the Ctrl-C handler, returning the token it sets.
+pub fn handle_interrupts() -> &'static AtomicBool {
+    install_handler();
+    &INTERRUPTED
+}
+
+#[cfg(unix)]
+fn install_handler() {
+    use std::os::raw::c_int;
+
+    const SIGINT: c_int = 2;
+    const SIG_DFL: usize = 0;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    // Only async-signal-safe calls: an atomic store, and putting back the default handler so a
+    // second Ctrl-C kills the process
+    extern "C" fn handler(_: c_int) {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        unsafe {
+            signal(SIGINT, SIG_DFL);
+        }
+    }
+
+    unsafe {
+        signal(SIGINT, handler as extern "C" fn(c_int) as usize);
+    }
+}
+
+#[cfg(windows)]
+fn install_handler() {
+    extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<extern "system" fn(u32) -> i32>, add: i32) -> i32;
+    }
+
+    // Runs on a thread of its own. Returning 0 passes the event on to the default handler, which
+    // ends the process
+    extern "system" fn handler(_: u32) -> i32 {
+        !INTERRUPTED.swap(true, Ordering::Relaxed) as i32
+    }
+
+    unsafe {
+        SetConsoleCtrlHandler(Some(handler), 1);
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn install_handler() {
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use batch;
+    use manifest;
+    use options::Options;
+    use test_util;
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut ret = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect::<Vec<_>>();
+        ret.sort();
+        ret
+    }
+
+    #[test]
+    fn only_a_set_token_cancels() {
+        let token = AtomicBool::new(false);
+        assert!(!is_cancelled(None) && check(None).is_ok());
+        assert!(!is_cancelled(Some(&token)) && check(Some(&token)).is_ok());
+        token.store(true, Ordering::Relaxed);
+        assert!(is_cancelled(Some(&token)));
+        assert!(matches!(check(Some(&token)), Err(MocapError::Cancelled)));
+    }
+
+    #[test]
+    fn a_cancelled_conversion_leaves_no_outputs() {
+        let dir = test_util::temp_dir("cancel");
+        let paths = ["in.bvh", "out.bvh", "out.csv", "out.raw"].iter().map(|name| dir.join(name)).collect::<Vec<_>>();
+        fs::write(&paths[0], test_util::clip_text(20, test_util::sine)).unwrap();
+        let options = test_util::options(&[]);
+        let convert = |cancel: Option<&AtomicBool>| ::convert(&paths[0], &paths[1], &paths[2], &paths[3], &options, cancel);
+
+        let token = AtomicBool::new(true);
+        assert!(matches!(manifest::atomically(|| convert(Some(&token))), Err(MocapError::Cancelled)));
+        assert_eq!(file_names(&dir), vec!["in.bvh".to_string()]);
+
+        // Cancelled once every output is written, they're all removed rather than renamed
+        let result = manifest::atomically(|| {
+            convert(None)?;
+            assert_eq!(file_names(&dir).len(), 4);
+            Err::<(), _>(MocapError::Cancelled)
+        });
+        assert!(matches!(result, Err(MocapError::Cancelled)));
+        assert_eq!(file_names(&dir), vec!["in.bvh".to_string()]);
+    }
+
+    #[test]
+    fn a_cancelled_batch_starts_no_more_files() {
+        let dir = test_util::temp_dir("cancel-batch");
+        let (input_dir, output_dir) = (dir.join("in"), dir.join("out"));
+        fs::create_dir_all(&input_dir).unwrap();
+        for i in 0..3 {
+            fs::write(input_dir.join(format!("clip{}.bvh", i)), test_util::clip_text(10, test_util::sine)).unwrap();
+        }
+        let options = Options::parse(["batch", "--jobs", "2", input_dir.to_str().unwrap(), output_dir.to_str().unwrap()].iter().map(|arg| arg.to_string())).unwrap();
+
+        let token = AtomicBool::new(true);
+        assert!(matches!(batch::run(&input_dir, &output_dir, &options, Some(&token)), Err(MocapError::Cancelled)));
+        assert!(!output_dir.exists() || file_names(&output_dir).is_empty());
+    }
+}
@@ -0,0 +1,173 @@
+use std::io::{self, Write};
+
+use json;
+use {ChannelType, Joint, JointChildren, Mocap};
+
+// Where a channel's data lives in every frame layout. `flat_index` is the channel's position in the
+// order channels are written to the raw and CSV dumps and its column in `build_bvh`'s frames;
+// `joint_index` counts joints in the same pre-order walk.
+#[derive(Debug)]
+pub struct ChannelDescriptor {
+    pub joint_index: usize,
+    pub joint_name: String,
+    pub channel_type: ChannelType,
+    pub flat_index: usize,
+}
+
+impl Mocap {
+    pub fn channel_map(&self) -> Vec<ChannelDescriptor> {
+        let mut ret = Vec::new();
+        let mut joint_index = 0;
+        push_descriptors(&self.root, &mut joint_index, &mut ret);
+        ret
+    }
+}
+
+fn push_descriptors(joint: &Joint, joint_index: &mut usize, descriptors: &mut Vec<ChannelDescriptor>) {
+    for channel in joint.channels.iter() {
+        let flat_index = descriptors.len();
+        descriptors.push(ChannelDescriptor {
+            joint_index: *joint_index,
+            joint_name: joint.name.clone(),
+            channel_type: channel.type_,
+            flat_index: flat_index,
+        });
+    }
+    *joint_index += 1;
+
+    if let JointChildren::Joints(ref joints) = joint.children {
+        for joint in joints.iter() {
+            push_descriptors(joint, joint_index, descriptors);
+        }
+    }
+}
+
+pub fn write_json<W: Write>(channel_map: &[ChannelDescriptor], w: &mut W) -> io::Result<()> {
+    writeln!(w, "[")?;
+    for (index, descriptor) in channel_map.iter().enumerate() {
+        let separator = if index + 1 < channel_map.len() { "," } else { "" };
+        writeln!(w, "  {{ \"flat_index\": {}, \"joint_index\": {}, \"joint_name\": \"{}\", \"channel_type\": \"{}\" }}{}",
+            descriptor.flat_index,
+            descriptor.joint_index,
+            json::escape(&descriptor.joint_name),
+            descriptor.channel_type.name(),
+            separator)?;
+    }
+    writeln!(w, "]")
+}
+
+// The map as a C table, for the C header exports (see fixed_point.rs) to include. A runtime finds a
+// flat index's joint and channel type there without walking the hierarchy.
+pub fn write_c<W: Write>(channel_map: &[ChannelDescriptor], w: &mut W) -> io::Result<()> {
+    writeln!(w, "typedef struct {{")?;
+    writeln!(w, "    uint32_t flat_index;")?;
+    writeln!(w, "    uint32_t joint_index;")?;
+    writeln!(w, "    const char *joint_name;")?;
+    writeln!(w, "    const char *channel_type;")?;
+    writeln!(w, "}} mocap_channel_descriptor;")?;
+    writeln!(w)?;
+    writeln!(w, "static const mocap_channel_descriptor mocap_channel_map[{}] = {{", channel_map.len().max(1))?;
+    for descriptor in channel_map.iter() {
+        writeln!(w, "    {{ {}, {}, \"{}\", \"{}\" }},",
+            descriptor.flat_index,
+            descriptor.joint_index,
+            c_escape(&descriptor.joint_name),
+            descriptor.channel_type.name())?;
+    }
+    if channel_map.is_empty() {
+        writeln!(w, "    {{ 0, 0, \"\", \"\" }},")?;
+    }
+    writeln!(w, "}};")
+}
+
+// `s` as the inside of a C string literal. Anything but printable ASCII is an octal escape, which
+// unlike a hex one can't run into the characters after it.
+fn c_escape(s: &str) -> String {
+    s.bytes().map(|byte| match byte {
+        b'"' | b'\\' => format!("\\{}", byte as char),
+        0x20..=0x7e => (byte as char).to_string(),
+        _ => format!("\\{:03o}", byte),
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitpack;
+    use conversion::ConversionSettings;
+    use depth::DEFAULT_MAX_DEPTH;
+    use periodic;
+    use raw;
+    use test_util;
+    use {build_bvh, build_mocap, dump_channels_csv};
+
+    const NUM_FRAMES: usize = 30;
+
+    fn summary(mocap: &Mocap) -> Vec<(usize, String, ChannelType, usize)> {
+        mocap.channel_map().into_iter().map(|descriptor| (descriptor.joint_index, descriptor.joint_name, descriptor.channel_type, descriptor.flat_index)).collect()
+    }
+
+    #[test]
+    fn map_matches_the_csv_dump_and_the_decoded_columns() {
+        let mocap = build_mocap(&test_util::sine_clip(NUM_FRAMES), &ConversionSettings::default().settings());
+        let map = mocap.channel_map();
+        assert_eq!(map.len(), test_util::NUM_CHANNELS);
+        let joints = map.iter().map(|descriptor| (descriptor.joint_index, descriptor.joint_name.as_str())).collect::<Vec<_>>();
+        assert_eq!(joints[0], (0, "Hips"));
+        assert_eq!(joints[6], (1, "Spine"));
+        assert_eq!(joints[9], (2, "Head"));
+        assert_eq!(joints[12], (3, "LeftLeg"));
+
+        let mut csv = Vec::new();
+        dump_channels_csv(&mocap.root, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let rows = csv.lines().collect::<Vec<_>>();
+        let frames = build_bvh(&mocap).motion.frames;
+        let channels = mocap.channels();
+        for descriptor in map.iter() {
+            let channel = channels[descriptor.flat_index];
+            assert_eq!(channel.type_, descriptor.channel_type);
+            // The CSV dump holds each channel's deltas in turn
+            let dumped = &rows[descriptor.flat_index * NUM_FRAMES..(descriptor.flat_index + 1) * NUM_FRAMES];
+            assert_eq!(dumped, &channel.deltas.iter().enumerate().map(|(frame, delta)| format!("{};{}", frame, delta)).collect::<Vec<_>>()[..]);
+            // And `build_bvh` decodes it to its column
+            let column = frames.iter().map(|frame| frame[descriptor.flat_index]).collect::<Vec<_>>();
+            assert_eq!(column, periodic::levels(channel).iter().map(|level| channel.value_of(*level, mocap.channel_quantization_bits)).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn c_table_escapes_names() {
+        let mut bvh = test_util::sine_clip(NUM_FRAMES);
+        bvh.hierarchy.root.name = "Hips \"main\"\\é".into();
+        let mocap = build_mocap(&bvh, &ConversionSettings::default().settings());
+        let mut table = Vec::new();
+        write_c(&mocap.channel_map(), &mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        let rows = table.lines().filter(|line| line.starts_with("    { ")).collect::<Vec<_>>();
+        assert_eq!(rows.len(), test_util::NUM_CHANNELS);
+        assert_eq!(rows[0], "    { 0, 0, \"Hips \\\"main\\\"\\\\\\303\\251\", \"TranslationX\" },");
+        assert_eq!(rows[14], "    { 14, 3, \"LeftLeg\", \"RotationY\" },");
+        assert!(table.contains(&format!("mocap_channel_map[{}] = {{\n", test_util::NUM_CHANNELS)));
+    }
+
+    #[test]
+    fn map_survives_every_layout() {
+        let mocap = build_mocap(&test_util::sine_clip(NUM_FRAMES), &ConversionSettings::default().settings());
+        let expected = summary(&mocap);
+        type WriteRaw = fn(&Mocap, &mut Vec<u8>) -> io::Result<()>;
+        let writes: [WriteRaw; 4] = [
+            |mocap, w| raw::write(mocap, periodic::Limits::default(), w),
+            |mocap, w| raw::write_indexed(mocap, 8, bitpack::Layout::Packed, periodic::Limits::default(), w),
+            |mocap, w| raw::write_sparse(mocap, periodic::Limits::default(), w),
+            |mocap, w| raw::write_bit_planes(mocap, periodic::Limits::default(), w),
+        ];
+        for write in writes.iter() {
+            let mut data = Vec::new();
+            write(&mocap, &mut data).unwrap();
+            let read = raw::read(&data, DEFAULT_MAX_DEPTH).unwrap();
+            assert_eq!(summary(&read), expected);
+            assert_eq!(build_bvh(&read).motion.frames, build_bvh(&mocap).motion.frames);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use bvh;
+
+use error::MocapError;
+use log;
+use profile::Clamp;
+use quality::{Event, Quality};
+use selector::{self, Selector};
+use {channel_type, ChannelType};
+
+// Clips every channel the profile declares bounds for to those bounds, warning about how many
+// source values were out of range and recording them in `quality`. Returns the bounds per flat
+// channel index, for the decoder to enforce as well. When several declarations match a channel,
+// the last one wins.
+pub fn apply(bvh: &mut bvh::Bvh, clamps: &[Clamp], quality: &mut Quality) -> Result<Vec<Option<(f64, f64)>>, MocapError> {
+    let mut types = Vec::new();
+    push_channel_types(&bvh.hierarchy.root, &mut types);
+    let mut bounds = vec![None; types.len()];
+    let mut paths = vec![String::new(); types.len()];
+
+    for clamp in clamps.iter() {
+        let matches = selector::find_joints(&bvh.hierarchy.root, &Selector::parse(&clamp.selector)?);
+        if matches.is_empty() {
+            return Err(MocapError::JointNotFound(clamp.selector.clone()));
+        }
+        for joint_match in matches.iter() {
+            for index in joint_match.channel_index..joint_match.channel_index + joint_match.num_channels {
+                if types[index] == clamp.type_ {
+                    bounds[index] = Some((clamp.min, clamp.max));
+                    paths[index] = joint_match.path.clone();
+                }
+            }
+        }
+    }
+
+    for (index, bounds) in bounds.iter().enumerate() {
+        if let Some((min, max)) = *bounds {
+            let mut violations = 0;
+            for frame in bvh.motion.frames.iter_mut() {
+                if frame[index] < min || frame[index] > max {
+                    frame[index] = frame[index].clamp(min, max);
+                    violations += 1;
+                }
+            }
+            if violations > 0 {
+                quality.record(index, Event::Clamped(violations));
+                log::warning(format!("clamped {} of {} values of {} {} to [{}, {}]", violations, bvh.motion.frames.len(), paths[index], types[index].name(), min, max));
+            }
+        }
+    }
+
+    Ok(bounds)
+}
+
+fn push_channel_types(joint: &bvh::Joint, types: &mut Vec<ChannelType>) {
+    types.extend(joint.channels.iter().map(channel_type));
+    if let bvh::JointChildren::Joints(ref joints) = joint.children {
+        for child in joints.iter() {
+            push_channel_types(child, types);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conversion::ConversionSettings;
+    use depth::DEFAULT_MAX_DEPTH;
+    use periodic;
+    use raw;
+    use test_util;
+    use {build_bvh, build_mocap};
+
+    const NUM_FRAMES: usize = 30;
+    // Spine's RotationZ, a sine of amplitude 22
+    const SPINE_Z: usize = 6;
+
+    fn spine_clamp() -> Vec<Clamp> {
+        vec![Clamp { selector: "Spine".into(), type_: ChannelType::RotationZ, min: -5.0, max: 5.0 }]
+    }
+
+    #[test]
+    fn clips_values_past_the_bounds() {
+        let mut bvh = test_util::sine_clip(NUM_FRAMES);
+        let violations = bvh.motion.frames.iter().filter(|frame| frame[SPINE_Z].abs() > 5.0).count();
+        assert!(violations > 0);
+        let original = bvh.motion.frames.clone();
+        let mut quality = Quality::new(test_util::NUM_CHANNELS);
+
+        let (bounds, messages) = log::capture(|| apply(&mut bvh, &spine_clamp(), &mut quality).unwrap());
+        assert_eq!(bounds.iter().enumerate().filter(|(_, bounds)| bounds.is_some()).map(|(index, _)| index).collect::<Vec<_>>(), vec![SPINE_Z]);
+        assert_eq!(quality.channels[SPINE_Z].clamped as usize, violations);
+        assert_eq!(log::diagnostics(&messages), vec![format!("warning: clamped {} of {} values of Hips/Spine RotationZ to [-5, 5]", violations, NUM_FRAMES)]);
+        for (frame, original) in bvh.motion.frames.iter().zip(original.iter()) {
+            assert_eq!(frame[SPINE_Z], original[SPINE_Z].clamp(-5.0, 5.0));
+            // Every other channel is untouched
+            assert_eq!(frame[..SPINE_Z], original[..SPINE_Z]);
+            assert_eq!(frame[SPINE_Z + 1..], original[SPINE_Z + 1..]);
+        }
+    }
+
+    #[test]
+    fn range_is_the_clamp_span() {
+        let mut bvh = test_util::sine_clip(NUM_FRAMES);
+        log::capture(|| apply(&mut bvh, &spine_clamp(), &mut Quality::new(test_util::NUM_CHANNELS)).unwrap());
+        let mocap = build_mocap(&bvh, &ConversionSettings::default().settings());
+        let channel = mocap.channels()[SPINE_Z];
+        assert_eq!((channel.value_range_min, channel.value_range), (-5.0, 10.0));
+    }
+
+    #[test]
+    fn corrupted_deltas_decode_within_the_bounds() {
+        // The bounds on a channel whose range is wider, so levels past them decode out of bounds
+        // unless they're enforced
+        let mut mocap = build_mocap(&test_util::sine_clip(NUM_FRAMES), &ConversionSettings::default().settings());
+        let (bounds, _) = log::capture(|| apply(&mut test_util::sine_clip(NUM_FRAMES), &spine_clamp(), &mut Quality::new(test_util::NUM_CHANNELS)).unwrap());
+        for (channel, bounds) in mocap.channels_mut().into_iter().zip(bounds.iter()) {
+            channel.clamp = *bounds;
+        }
+        // Deltas that wander all over the level range
+        for (index, delta) in mocap.channels_mut()[SPINE_Z].deltas.iter_mut().enumerate() {
+            *delta = if index % 3 == 0 { 127 } else { -100 };
+        }
+        let channel = mocap.channels()[SPINE_Z].clone();
+        assert!(periodic::levels(&channel).iter().any(|level| channel.value_of(*level, mocap.channel_quantization_bits).abs() > 5.0));
+
+        // The bounds are stored, and enforced by whatever decodes the file
+        let mut data = Vec::new();
+        raw::write(&mocap, periodic::Limits::default(), &mut data).unwrap();
+        let read = raw::read(&data, DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(read.channels()[SPINE_Z].clamp, Some((-5.0, 5.0)));
+        for frame in build_bvh(&read).motion.frames.iter() {
+            assert!(frame[SPINE_Z] >= -5.0 && frame[SPINE_Z] <= 5.0, "{}", frame[SPINE_Z]);
+        }
+    }
+}
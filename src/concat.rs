@@ -0,0 +1,207 @@
+use error::MocapError;
+use log;
+use markers;
+use selector;
+use timing;
+use {build_bvh, build_mocap, make_lossless, Joint, JointChildren, Mocap, Settings};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AppendPath {
+    Quantized,
+    Requantized,
+}
+
+// Appends `other`'s frames to `mocap`. Both must share a skeleton and frame time.
+//
+// With `quantized` set, and when every channel of both clips has the same reference, range, clamp
+// bounds and bit depth, the delta streams are concatenated directly: the only change is that
+// `other`'s first delta per channel (which is relative to its initial level) is replaced by a
+// bridging delta from `mocap`'s last level. That's bit-exact with respect to both inputs and skips
+// decoding. The bridging delta acts as the keyframe at the seam: wrapping arithmetic means it
+// always lands the predictor exactly on `other`'s first level, whatever the distance. Otherwise
+// both clips are decoded and the combined frames are quantized again with `settings`, costing a
+// generation of precision.
+pub fn append(mocap: &mut Mocap, other: &Mocap, quantized: bool, settings: &Settings) -> Result<AppendPath, MocapError> {
+    if !same_skeleton(&mocap.root, &other.root) {
+        return Err(MocapError::SkeletonMismatch("can't append clips with different skeletons".into()));
+    }
+    if mocap.frame_time != other.frame_time {
+        return Err(MocapError::SkeletonMismatch(format!("can't append clips with different frame times ({} and {})", mocap.frame_time, other.frame_time)));
+    }
+
+    let mismatch = quantization_mismatch(mocap, other);
+    if quantized && mismatch.is_none() {
+        append_deltas(&mut mocap.root, &other.root);
+        mocap.markers.extend(markers::appended(&other.markers, mocap.num_frames));
+        mocap.timestamps = timing::appended(mocap, other);
+        mocap.num_frames += other.num_frames;
+        return Ok(AppendPath::Quantized);
+    }
+
+    if let (true, Some(mismatch)) = (quantized, mismatch) {
+        log::warning(format!("{}, falling back to re-quantization", mismatch));
+    }
+
+    let combined = {
+        let mut combined = build_bvh(mocap);
+        combined.motion.frames.extend(build_bvh(other).motion.frames);
+        combined.motion.num_frames += other.num_frames;
+        combined
+    };
+    let mut requantized = build_mocap(&combined, settings);
+    copy_names(&mocap.root, &mut requantized.root);
+    for (channel, original) in requantized.channels_mut().into_iter().zip(mocap.channels()) {
+        channel.clamp = original.clamp;
+    }
+    let lossless = mocap.channels().iter().map(|channel| channel.values.is_some()).collect::<Vec<_>>();
+    make_lossless(&mut requantized, &combined.motion.frames, &lossless);
+    requantized.metadata = mocap.metadata.clone();
+    requantized.markers = mocap.markers.clone();
+    requantized.markers.extend(markers::appended(&other.markers, mocap.num_frames));
+    requantized.timestamps = timing::appended(mocap, other);
+    *mocap = requantized;
+
+    Ok(AppendPath::Requantized)
+}
+
+pub fn same_skeleton(a: &Joint, b: &Joint) -> bool {
+    a.name == b.name && a.offset == b.offset
+        && a.channels.len() == b.channels.len()
+        && a.channels.iter().zip(b.channels.iter()).all(|(a, b)| a.type_ == b.type_)
+        && match (&a.children, &b.children) {
+            (JointChildren::Joints(a), JointChildren::Joints(b)) => a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| same_skeleton(a, b)),
+            (JointChildren::EndSite(a), JointChildren::EndSite(b)) => a == b,
+            _ => false,
+        }
+}
+
+// Why the clips' delta streams can't be joined directly, if they can't.
+fn quantization_mismatch(a: &Mocap, b: &Mocap) -> Option<String> {
+    if a.channel_quantization_bits != b.channel_quantization_bits {
+        return Some(format!("bit depths differ ({} and {})", a.channel_quantization_bits, b.channel_quantization_bits));
+    }
+    joint_quantization_mismatch(&a.root, &b.root, "")
+}
+
+fn joint_quantization_mismatch(a: &Joint, b: &Joint, parent_path: &str) -> Option<String> {
+    let name = selector::escape(&a.name);
+    let path = if parent_path.is_empty() { name } else { format!("{}/{}", parent_path, name) };
+
+    for (a_channel, b_channel) in a.channels.iter().zip(b.channels.iter()) {
+        if a_channel.reference != b_channel.reference || a_channel.value_range_min != b_channel.value_range_min || a_channel.value_range != b_channel.value_range {
+            return Some(format!("{} {} ranges differ", path, a_channel.type_.name()));
+        }
+        if a_channel.clamp != b_channel.clamp {
+            return Some(format!("{} {} clamp bounds differ", path, a_channel.type_.name()));
+        }
+        if a_channel.anchor_level != b_channel.anchor_level {
+            return Some(format!("{} {} anchors differ", path, a_channel.type_.name()));
+        }
+        if a_channel.bits != b_channel.bits {
+            return Some(format!("{} {} bit depths differ", path, a_channel.type_.name()));
+        }
+        if a_channel.values.is_some() != b_channel.values.is_some() {
+            return Some(format!("{} {} is lossless in only one clip", path, a_channel.type_.name()));
+        }
+    }
+
+    match (&a.children, &b.children) {
+        (JointChildren::Joints(a), JointChildren::Joints(b)) => a.iter().zip(b.iter()).filter_map(|(a, b)| joint_quantization_mismatch(a, b, &path)).next(),
+        _ => None,
+    }
+}
+
+fn append_deltas(joint: &mut Joint, other: &Joint) {
+    for (channel, other_channel) in joint.channels.iter_mut().zip(other.channels.iter()) {
+        if let (Some(values), Some(other_values)) = (&mut channel.values, &other_channel.values) {
+            values.extend(other_values.iter().cloned());
+            continue;
+        }
+        let last_value = channel.deltas.iter().fold(channel.initial_level, |value, delta| (value as i8).wrapping_add(*delta) as u8);
+
+        let mut deltas = other_channel.deltas.iter();
+        if let Some(first_delta) = deltas.next() {
+            let first_value = (other_channel.initial_level as i8).wrapping_add(*first_delta);
+            channel.deltas.push(first_value.wrapping_sub(last_value as i8));
+        }
+        channel.deltas.extend(deltas);
+    }
+
+    if let (JointChildren::Joints(joints), JointChildren::Joints(other_joints)) = (&mut joint.children, &other.children) {
+        for (joint, other_joint) in joints.iter_mut().zip(other_joints.iter()) {
+            append_deltas(joint, other_joint);
+        }
+    }
+}
+
+// `build_bvh` writes out original names, so restore the (possibly disambiguated) names after a
+// round trip through it.
+pub fn copy_names(from: &Joint, to: &mut Joint) {
+    to.name = from.name.clone();
+    to.original_name = from.original_name.clone();
+
+    if let (JointChildren::Joints(from_joints), JointChildren::Joints(to_joints)) = (&from.children, &mut to.children) {
+        for (from, to) in from_joints.iter().zip(to_joints.iter_mut()) {
+            copy_names(from, to);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conversion::ConversionSettings;
+    use depth::DEFAULT_MAX_DEPTH;
+    use periodic::Limits;
+    use raw;
+    use test_util;
+
+    const NUM_FRAMES: usize = 40;
+
+    fn settings() -> Settings {
+        ConversionSettings::default().settings()
+    }
+
+    fn clip<F: Fn(usize, usize) -> f64>(value: F) -> Mocap {
+        build_mocap(&test_util::parse(&test_util::clip_text(NUM_FRAMES, value)), &settings())
+    }
+
+    #[test]
+    fn quantized_append_is_bit_exact_with_the_decoded_inputs_when_ranges_match() {
+        // The same values backwards, so every channel has the same range in both clips
+        let first = clip(test_util::sine);
+        let second = clip(|frame, channel| test_util::sine(NUM_FRAMES - 1 - frame, channel));
+        let mut expected = build_bvh(&first).motion.frames;
+        expected.extend(build_bvh(&second).motion.frames);
+
+        let mut mocap = first.clone();
+        let (path, messages) = log::capture(|| append(&mut mocap, &second, true, &settings()).unwrap());
+        assert_eq!(path, AppendPath::Quantized);
+        assert!(messages.is_empty());
+        assert_eq!(mocap.num_frames as usize, 2 * NUM_FRAMES);
+        assert_eq!(build_bvh(&mocap).motion.frames, expected);
+
+        // And still once written out and read back
+        let mut data = Vec::new();
+        raw::write(&mocap, Limits::default(), &mut data).unwrap();
+        assert_eq!(build_bvh(&raw::read(&data, DEFAULT_MAX_DEPTH).unwrap()).motion.frames, expected);
+    }
+
+    #[test]
+    fn mismatched_ranges_fall_back_to_requantization_with_a_warning() {
+        let first = clip(test_util::sine);
+        let second = clip(|frame, channel| test_util::sine(frame, channel) * 2.0);
+
+        let mut mocap = first.clone();
+        let (path, messages) = log::capture(|| append(&mut mocap, &second, true, &settings()).unwrap());
+        assert_eq!(path, AppendPath::Requantized);
+        assert_eq!(log::diagnostics(&messages), vec!["warning: Hips TranslationX ranges differ, falling back to re-quantization".to_string()]);
+        assert_eq!(mocap.num_frames as usize, 2 * NUM_FRAMES);
+
+        // Not asked for, so nothing to warn about
+        let mut mocap = first.clone();
+        let (path, messages) = log::capture(|| append(&mut mocap, &second, false, &settings()).unwrap());
+        assert_eq!(path, AppendPath::Requantized);
+        assert!(messages.is_empty());
+    }
+}
@@ -0,0 +1,417 @@
+use std::io::{self, Write};
+
+use error::MocapError;
+use periodic;
+use raw::{self, Reader};
+use Mocap;
+
+// The .mcp container format: several clips in one file, plus reference poses shared between them.
+// All values are little-endian.
+//
+//   magic           b"MCPK"
+//   version         u8, FORMAT_VERSION
+//   reference poses u16 count, then per pose a u32 channel count and that many f64 channel values
+//                   (in `Mocap::channel_map` order)
+//   clips           u16 count, then per clip
+//                     name            string (u16 byte length + UTF-8)
+//                     reference pose  u16 index into the reference poses, or NO_REFERENCE_POSE
+//                     attributes      u16 count, then that many (key string, value string) pairs
+//                     thumbnail       u32 frame index, or NO_THUMBNAIL; if there's one, its pose as
+//                                     a u32 channel count and that many f32 channel values
+//                     alias           u16 index of an earlier clip whose encoded clip this one
+//                                     shares, or NO_ALIAS
+//                     clip            encoded as in a .raw file following its version, see raw.rs;
+//                                     not stored for an alias
+//
+// A clip with a reference pose has its first frame encoded as a delta from that pose instead of
+// from level 0: each channel's initial level is the pose's value quantized with the clip's own
+// range. Clips starting from about the same pose then start with near-zero deltas, which is what
+// makes many short clips cheap to store once the deltas are entropy coded. The initial levels are
+// also stored with the clip; the decoder derives them from the pose again and rejects the file if
+// they disagree.
+//
+// A clip's thumbnail is a pose for asset browsers to show, one of the clip's frames picked with
+// pack --thumbnail (see thumbnail.rs). It's stored decoded, as f32s, so a browser can draw it
+// without decoding the clip, and doesn't affect decoding.
+//
+// An alias is a clip entry without encoded data of its own, decoding as the earlier clip it
+// names, which pack --dedupe-clips alias makes of a duplicate clip (see dedupe.rs). It keeps its
+// own name, attributes and thumbnail, and has the same reference pose as the clip it aliases. It
+// can only alias a clip storing its own data.
+//
+// No channel map is stored beside a clip: its encoded hierarchy lists the joints and their
+// channels in flat index order, which is all `Mocap::channel_map` is, so a reader gets the map from
+// the decoded clip rather than a second copy that could disagree with it.
+pub const MAGIC: &[u8; 4] = b"MCPK";
+pub const FORMAT_VERSION: u8 = 5;
+
+pub const NO_REFERENCE_POSE: u16 = 0xffff;
+pub const NO_THUMBNAIL: u32 = 0xffffffff;
+pub const NO_ALIAS: u16 = 0xffff;
+
+// Clip attributes are for the runtime playing the clips back and don't affect decoding. These
+// keys have a meaning and are checked when packing; any other key is kept as-is.
+pub const ATTRIBUTE_KEYS: &[&str] = &[
+    "loop",       // true/false: whether the clip loops
+    "speed",      // Playback rate multiplier, > 0
+    "sync_start", // Start sync marker, a frame index
+    "sync_end",   // End sync marker, a frame index
+];
+
+#[derive(Debug)]
+pub struct Clip {
+    pub name: String,
+    pub reference_pose: Option<usize>,
+    pub attributes: Vec<(String, String)>,
+    pub thumbnail: Option<Thumbnail>,
+    pub alias: Option<usize>, // The clip whose encoded data this one shares; `mocap` is a copy of it
+    pub mocap: Mocap,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    pub frame: u32,
+    pub pose: Vec<f32>, // In `Mocap::channel_map` order
+}
+
+impl Clip {
+    // Sets an attribute, replacing any previous value for `key`.
+    pub fn set_attribute(&mut self, key: &str, value: &str) -> Result<(), MocapError> {
+        if !is_valid_attribute(key, value, self.mocap.num_frames) {
+            return Err(MocapError::Usage(format!("clip {}: invalid value for attribute {}: {}", self.name, key, value)));
+        }
+
+        match self.attributes.iter_mut().find(|attribute| attribute.0 == key) {
+            Some(attribute) => attribute.1 = value.into(),
+            None => self.attributes.push((key.into(), value.into())),
+        }
+        Ok(())
+    }
+}
+
+// Whether `value` is valid for a known attribute `key` of a clip of `num_frames` frames. Any value
+// is valid for other keys.
+pub fn is_valid_attribute(key: &str, value: &str, num_frames: u32) -> bool {
+    match key {
+        "loop" => value == "true" || value == "false",
+        "speed" => value.parse::<f32>().is_ok_and(|speed| speed.is_finite() && speed > 0.0),
+        "sync_start" | "sync_end" => value.parse::<u32>().is_ok_and(|frame| frame < num_frames),
+        _ => true,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Container {
+    pub reference_poses: Vec<Vec<f64>>,
+    pub clips: Vec<Clip>,
+}
+
+impl Container {
+    // The index of a stored reference pose within `tolerance` of `pose` on every channel, storing
+    // `pose` if there's none.
+    pub fn share_reference_pose(&mut self, pose: &[f64], tolerance: f64) -> usize {
+        let existing = self.reference_poses.iter().position(|reference_pose| {
+            reference_pose.len() == pose.len() && reference_pose.iter().zip(pose.iter()).all(|(a, b)| (a - b).abs() <= tolerance)
+        });
+        match existing {
+            Some(index) => index,
+            None => {
+                self.reference_poses.push(pose.to_vec());
+                self.reference_poses.len() - 1
+            }
+        }
+    }
+}
+
+// Re-bases `mocap`'s delta streams on `pose`. Lossless: only the first delta per channel and the
+// level it's relative to change.
+pub fn apply_reference_pose(mocap: &mut Mocap, pose: &[f64]) {
+    let bits = mocap.channel_quantization_bits;
+    for (channel, value) in mocap.channels_mut().into_iter().zip(pose.iter()) {
+        let initial_level = channel.level_of(*value, bits);
+        if let Some(first_delta) = channel.deltas.first_mut() {
+            let first_level = (channel.initial_level as i8).wrapping_add(*first_delta);
+            *first_delta = first_level.wrapping_sub(initial_level as i8);
+        }
+        channel.initial_level = initial_level;
+    }
+}
+
+pub fn write<W: Write>(container: &Container, limits: periodic::Limits, w: &mut W) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&[FORMAT_VERSION])?;
+
+    w.write_all(&raw::u16_len(container.reference_poses.len(), || "reference pose count".into())?.to_le_bytes())?;
+    for pose in container.reference_poses.iter() {
+        w.write_all(&(pose.len() as u32).to_le_bytes())?;
+        for value in pose.iter() {
+            w.write_all(&value.to_le_bytes())?;
+        }
+    }
+
+    // At most 65535 clips and reference poses, so an index never reaches NO_ALIAS or
+    // NO_REFERENCE_POSE
+    w.write_all(&raw::u16_len(container.clips.len(), || "clip count".into())?.to_le_bytes())?;
+    for clip in container.clips.iter() {
+        raw::write_string(&clip.name, w)?;
+        let reference_pose = match clip.reference_pose {
+            Some(index) if index < container.reference_poses.len() => index as u16,
+            Some(index) => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("clip {} uses reference pose {} of {}", clip.name, index, container.reference_poses.len()))),
+            None => NO_REFERENCE_POSE,
+        };
+        w.write_all(&reference_pose.to_le_bytes())?;
+        w.write_all(&raw::u16_len(clip.attributes.len(), || format!("attribute count of clip {}", clip.name))?.to_le_bytes())?;
+        for (key, value) in clip.attributes.iter() {
+            raw::write_string(key, w)?;
+            raw::write_string(value, w)?;
+        }
+        match clip.thumbnail {
+            Some(ref thumbnail) => {
+                w.write_all(&thumbnail.frame.to_le_bytes())?;
+                w.write_all(&(thumbnail.pose.len() as u32).to_le_bytes())?;
+                for value in thumbnail.pose.iter() {
+                    w.write_all(&value.to_le_bytes())?;
+                }
+            }
+            None => w.write_all(&NO_THUMBNAIL.to_le_bytes())?,
+        }
+        match clip.alias {
+            Some(index) if index < container.clips.len() => w.write_all(&(index as u16).to_le_bytes())?,
+            Some(index) => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("clip {} aliases clip {} of {}", clip.name, index, container.clips.len()))),
+            None => {
+                w.write_all(&NO_ALIAS.to_le_bytes())?;
+                raw::write_clip(&clip.mocap, limits, w)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Fails on joints deeper than `max_depth` (see depth.rs).
+pub fn read(data: &[u8], max_depth: usize) -> Result<Container, MocapError> {
+    Ok(read_runs(data, max_depth)?.0)
+}
+
+// `read`, also returning the runs of deltas in each clip's blocks (see `raw::read_clip_runs`), none
+// for an alias.
+pub fn read_runs(data: &[u8], max_depth: usize) -> Result<(Container, Vec<Vec<raw::Run>>), MocapError> {
+    let mut reader = Reader::with_max_depth(data, max_depth);
+
+    if reader.bytes(4)? != MAGIC {
+        return Err(MocapError::InvalidRaw("not a mocap container file".into()));
+    }
+    let version = reader.u8()?;
+    if version != FORMAT_VERSION {
+        return Err(MocapError::InvalidRaw(format!("unsupported container format version {}", version)));
+    }
+
+    let num_reference_poses = reader.u16()?;
+    let mut reference_poses = Vec::with_capacity(reader.capacity(num_reference_poses as usize, 4));
+    for _ in 0..num_reference_poses {
+        let num_channels = reader.u32()?;
+        let mut pose = Vec::new();
+        for _ in 0..num_channels {
+            pose.push(reader.f64()?);
+        }
+        reference_poses.push(pose);
+    }
+
+    let num_clips = reader.u16()?;
+    let mut clips = Vec::with_capacity(reader.capacity(num_clips as usize, 6));
+    let mut runs = Vec::with_capacity(clips.capacity());
+    for _ in 0..num_clips {
+        let name = reader.string()?;
+        let reference_pose = match reader.u16()? {
+            NO_REFERENCE_POSE => None,
+            index => Some(index as usize),
+        };
+        let num_attributes = reader.u16()?;
+        let mut attributes = Vec::with_capacity(reader.capacity(num_attributes as usize, 4));
+        for _ in 0..num_attributes {
+            attributes.push((reader.string()?, reader.string()?));
+        }
+        let thumbnail = match reader.u32()? {
+            NO_THUMBNAIL => None,
+            frame => {
+                let num_channels = reader.u32()?;
+                let mut pose = Vec::with_capacity(reader.capacity(num_channels as usize, 4));
+                for _ in 0..num_channels {
+                    pose.push(reader.f32()?);
+                }
+                Some(Thumbnail {
+                    frame: frame,
+                    pose: pose,
+                })
+            }
+        };
+        let alias = match reader.u16()? {
+            NO_ALIAS => None,
+            index => Some(index as usize),
+        };
+        let (mocap, clip_runs) = match alias {
+            Some(index) => (aliased_clip(&name, &clips, index, reference_pose)?.mocap.clone(), Vec::new()),
+            None => raw::read_clip_runs(&mut reader)?,
+        };
+        runs.push(clip_runs);
+        if let Some(index) = reference_pose {
+            check_reference_pose(&name, &mocap, reference_poses.get(index))?;
+        }
+        if let Some(ref thumbnail) = thumbnail {
+            check_thumbnail(&name, &mocap, thumbnail)?;
+        }
+        clips.push(Clip {
+            name: name,
+            reference_pose: reference_pose,
+            attributes: attributes,
+            thumbnail: thumbnail,
+            alias: alias,
+            mocap: mocap,
+        });
+    }
+    reader.finish()?;
+
+    Ok((Container {
+        reference_poses: reference_poses,
+        clips: clips,
+    }, runs))
+}
+
+// The clip an alias named `name` aliases, at `index` among the clips before it.
+fn aliased_clip<'a>(name: &str, clips: &'a [Clip], index: usize, reference_pose: Option<usize>) -> Result<&'a Clip, MocapError> {
+    let clip = clips.get(index).ok_or_else(|| MocapError::InvalidRaw(format!("clip {}: alias of clip {}, which doesn't come before it", name, index)))?;
+    if clip.alias.is_some() {
+        return Err(MocapError::InvalidRaw(format!("clip {}: alias of {}, which is an alias itself", name, clip.name)));
+    }
+    if clip.reference_pose != reference_pose {
+        return Err(MocapError::InvalidRaw(format!("clip {}: reference pose differs from that of {}, which it aliases", name, clip.name)));
+    }
+    Ok(clip)
+}
+
+fn check_reference_pose(name: &str, mocap: &Mocap, pose: Option<&Vec<f64>>) -> Result<(), MocapError> {
+    let pose = pose.ok_or_else(|| MocapError::InvalidRaw(format!("clip {}: reference pose out of range", name)))?;
+    let channels = mocap.channels();
+    if pose.len() != channels.len() {
+        return Err(MocapError::InvalidRaw(format!("clip {}: reference pose has {} channels, expected {}", name, pose.len(), channels.len())));
+    }
+    for (channel, value) in channels.into_iter().zip(pose.iter()) {
+        if channel.initial_level != channel.level_of(*value, mocap.channel_quantization_bits) {
+            return Err(MocapError::InvalidRaw(format!("clip {}: initial levels don't match the reference pose", name)));
+        }
+    }
+    Ok(())
+}
+
+fn check_thumbnail(name: &str, mocap: &Mocap, thumbnail: &Thumbnail) -> Result<(), MocapError> {
+    if thumbnail.frame >= mocap.num_frames {
+        return Err(MocapError::InvalidRaw(format!("clip {}: thumbnail frame {} out of range", name, thumbnail.frame)));
+    }
+    let num_channels = mocap.channels().len();
+    if thumbnail.pose.len() != num_channels {
+        return Err(MocapError::InvalidRaw(format!("clip {}: thumbnail pose has {} channels, expected {}", name, thumbnail.pose.len(), num_channels)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use build_bvh;
+    use depth::DEFAULT_MAX_DEPTH;
+    use options::Options;
+    use test_util::{self, clip_text, sine};
+
+    const NUM_CLIPS: usize = 10;
+
+    // Two-frame clips all starting from the same base pose, each going somewhere else from there
+    fn short_clips() -> Vec<String> {
+        (0..NUM_CLIPS).map(|clip| clip_text(2, |frame, channel| sine(if frame == 0 { 0 } else { clip * 3 + 1 }, channel))).collect()
+    }
+
+    // `clips` packed with `args`, as clip0.bvh, clip1.bvh and so on
+    fn pack(clips: &[String], args: &[&str]) -> Vec<u8> {
+        let dir = test_util::temp_dir("container");
+        let output_file_name = dir.join("clips.mcp");
+        let input_file_names = clips.iter().enumerate().map(|(index, text)| {
+            let file_name = dir.join(format!("clip{}.bvh", index));
+            fs::write(&file_name, text).unwrap();
+            file_name.to_string_lossy().into_owned()
+        }).collect::<Vec<_>>();
+        let options = Options::parse(["pack"].iter().chain(args.iter()).map(|arg| arg.to_string()).chain(Some(output_file_name.to_string_lossy().into_owned())).chain(input_file_names.iter().cloned())).unwrap();
+        ::pack(&output_file_name, &input_file_names, &options, None).unwrap();
+        fs::read(&output_file_name).unwrap()
+    }
+
+    fn decoded(clip: &Clip) -> Vec<Vec<f64>> {
+        build_bvh(&clip.mocap).motion.frames
+    }
+
+    #[test]
+    fn short_clips_from_one_pose_share_its_reference() {
+        let clips = short_clips();
+        let shared = pack(&clips, &["--reference-pose"]);
+        let plain = pack(&clips, &[]);
+
+        let container = read(&shared, DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(container.reference_poses, vec![test_util::parse(&clips[0]).motion.frames[0].clone()]);
+        assert_eq!(container.clips.len(), NUM_CLIPS);
+        let uncontained = read(&plain, DEFAULT_MAX_DEPTH).unwrap();
+        for (index, (clip, text)) in container.clips.iter().zip(clips.iter()).enumerate() {
+            assert_eq!(clip.name, format!("clip{}", index));
+            assert_eq!(clip.reference_pose, Some(0));
+            // Each channel starts at the pose's level, so the first frame is all zero deltas
+            assert!(clip.mocap.channels().iter().all(|channel| channel.deltas.first().is_none_or(|delta| *delta == 0)), "clip {}", index);
+
+            // Re-basing is lossless, and the clip decodes to its frames within a quantization step
+            let frames = decoded(clip);
+            assert_eq!(frames, decoded(&uncontained.clips[index]));
+            for (decoded, original) in frames.iter().zip(test_util::parse(text).motion.frames.iter()) {
+                for (channel, (decoded, original)) in decoded.iter().zip(original.iter()).enumerate() {
+                    let step = clip.mocap.channels()[channel].value_range as f64 / 255.0;
+                    assert!((decoded - original).abs() <= step + 1e-4, "clip {} channel {}: {} vs {}", index, channel, decoded, original);
+                }
+            }
+        }
+
+        // One pose is stored where ten would have been; the smaller first deltas only pay off once
+        // they're entropy coded, so the file itself is only the one pose bigger
+        let pose_size = 4 + test_util::NUM_CHANNELS * 8;
+        assert_eq!(shared.len(), plain.len() + pose_size);
+    }
+
+    #[test]
+    fn a_reference_pose_is_shared_within_the_tolerance() {
+        // Each clip starting a little further from the first
+        let clips = (0..4).map(|clip| clip_text(2, |frame, channel| sine(frame, channel) + if channel == 6 { clip as f64 * 0.01 } else { 0.0 })).collect::<Vec<_>>();
+        let exact = read(&pack(&clips, &["--reference-pose"]), DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(exact.reference_poses.len(), 4);
+        assert_eq!(exact.clips.iter().map(|clip| clip.reference_pose).collect::<Vec<_>>(), vec![Some(0), Some(1), Some(2), Some(3)]);
+
+        let tolerant = read(&pack(&clips, &["--reference-pose", "--reference-tolerance", "0.015"]), DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(tolerant.reference_poses.len(), 2);
+        assert_eq!(tolerant.clips.iter().map(|clip| clip.reference_pose).collect::<Vec<_>>(), vec![Some(0), Some(0), Some(1), Some(1)]);
+        for (clip, original) in tolerant.clips.iter().zip(exact.clips.iter()) {
+            assert_eq!(decoded(clip), decoded(original));
+        }
+    }
+
+    #[test]
+    fn refuses_initial_levels_off_the_reference_pose() {
+        let mut container = read(&pack(&short_clips()[..2], &["--reference-pose"]), DEFAULT_MAX_DEPTH).unwrap();
+        container.reference_poses[0][6] += 20.0;
+        let mut data = Vec::new();
+        write(&container, periodic::Limits::default(), &mut data).unwrap();
+        match read(&data, DEFAULT_MAX_DEPTH) {
+            Err(MocapError::InvalidRaw(message)) => assert_eq!(message, "clip clip0: initial levels don't match the reference pose"),
+            other => panic!("{:?}", other),
+        }
+
+        container.clips[1].reference_pose = Some(1);
+        let error = write(&container, periodic::Limits::default(), &mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), "clip clip1 uses reference pose 1 of 1");
+    }
+}
@@ -0,0 +1,661 @@
+use bind::BindPose;
+use error::MocapError;
+use mask::Mask;
+use profile::Lossless;
+use root_motion;
+use smooth::Filter;
+use targets::Targets;
+use writer;
+use {num_levels, RotationAnchor, Settings, TranslationReference};
+
+// The settings deciding how a conversion turns a clip into its outputs: the passes changing the
+// motion before it's encoded (smoothing, the partial-body mask, the bind pose subtracted, root
+// motion encoding), the channels stored losslessly, the quantization (bit depth or error targets,
+// translation reference, rotation anchor) and the .raw file's layout (seek index, block size,
+// longest delta run, sparse or bit-plane storage), and whether it's streamed with --calibration
+// (see writer.rs) rather than written once the clip is encoded. The command line, a profile's [encoding] table
+// and lossless settings (see profile.rs) and the input's directives (see directives.rs) all set
+// them through a `ConversionSettingsBuilder`, whose `build` checks the settings against each
+// other, so whichever way they're given the same combinations are refused with the same errors:
+//
+//   - the bit depth must be in [1, 8], and error targets positive
+//   - --sparse, with no delta blocks, can't be combined with --seek-index, --max-delta-run or
+//     --bit-planes
+//   - --max-delta-run and --block-frames must be at least 1
+//   - --smooth and --auto-smooth can't be combined
+//   - --root-motion-anchors requires --root-motion and must be at least 1
+//   - --root-motion can't be combined with --bind-pose
+//   - --block-frames requires --seek-index, --max-delta-run or a --calibration stream
+//   - a --calibration stream, quantizing every channel at --bits with no metadata, can't be
+//     sparse, bit planes, root motion, relative to a bind pose, lossless or meet error targets
+//
+// The errors name each setting as it was given: by its option, or by its key if a profile gave it
+// (see `in_profile`). Which joints a mask keeps depends on the clip (the upper body is found by
+// its joints' names) or the mask file, so mask.rs checks that when applying it.
+//
+// Settings a builder leaves unset take their defaults (8 bits, no error targets, no reference or
+// anchor, DEFAULT_BLOCK_FRAMES frames per block, none of the layouts or passes, lossless channels
+// or anchors every DEFAULT_ANCHOR_INTERVAL frames). `or` layers builders, so a conversion's
+// settings are the command line's, then the directives', then the profile's.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionSettings {
+    smooth: Option<Filter>,
+    auto_smooth: bool,
+    mask: Option<Mask>,
+    bind_pose: Option<BindPose>,
+    root_motion: bool,
+    root_motion_anchors: usize,
+    lossless: Vec<Lossless>,
+    channel_quantization_bits: u8,
+    translation_reference: TranslationReference,
+    rotation_anchor: RotationAnchor,
+    error_targets: Targets,
+    seek_index: bool,
+    block_frames: usize,
+    max_delta_run: Option<usize>,
+    sparse: bool,
+    bit_planes: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConversionSettingsBuilder {
+    smooth: Option<Filter>,
+    auto_smooth: Option<bool>,
+    mask: Option<Mask>,
+    bind_pose: Option<BindPose>,
+    root_motion: Option<bool>,
+    root_motion_anchors: Option<usize>,
+    lossless: Vec<Lossless>,
+    channel_quantization_bits: Option<u8>,
+    translation_reference: Option<TranslationReference>,
+    rotation_anchor: Option<RotationAnchor>,
+    rotation_error: Option<f64>,
+    translation_error: Option<f64>,
+    seek_index: Option<bool>,
+    block_frames: Option<usize>,
+    max_delta_run: Option<usize>,
+    sparse: Option<bool>,
+    bit_planes: Option<bool>,
+    streamed: bool, // Only the command line streams, with --calibration
+    profile_keys: Vec<&'static str>, // The settings a profile gave, by key
+}
+
+impl Default for ConversionSettings {
+    fn default() -> ConversionSettings {
+        ConversionSettings {
+            smooth: None,
+            auto_smooth: false,
+            mask: None,
+            bind_pose: None,
+            root_motion: false,
+            root_motion_anchors: root_motion::DEFAULT_ANCHOR_INTERVAL,
+            lossless: Vec::new(),
+            channel_quantization_bits: 8,
+            translation_reference: TranslationReference::None,
+            rotation_anchor: RotationAnchor::None,
+            error_targets: Targets { rotation: None, translation: None },
+            seek_index: false,
+            block_frames: writer::DEFAULT_BLOCK_FRAMES,
+            max_delta_run: None,
+            sparse: false,
+            bit_planes: false,
+        }
+    }
+}
+
+impl ConversionSettings {
+    // The quantization settings, for `build_mocap`. With error targets the bit depth is only the
+    // starting point; see targets.rs.
+    pub fn settings(&self) -> Settings {
+        Settings {
+            channel_quantization_bits: self.channel_quantization_bits,
+            translation_reference: self.translation_reference,
+            rotation_anchor: self.rotation_anchor,
+        }
+    }
+
+    pub fn smooth(&self) -> Option<&Filter> {
+        self.smooth.as_ref()
+    }
+
+    pub fn auto_smooth(&self) -> bool {
+        self.auto_smooth
+    }
+
+    pub fn mask(&self) -> Option<&Mask> {
+        self.mask.as_ref()
+    }
+
+    pub fn bind_pose(&self) -> Option<&BindPose> {
+        self.bind_pose.as_ref()
+    }
+
+    pub fn root_motion(&self) -> bool {
+        self.root_motion
+    }
+
+    // Frames between root motion anchors
+    pub fn root_motion_anchors(&self) -> usize {
+        self.root_motion_anchors
+    }
+
+    // The channels a profile stores losslessly
+    pub fn lossless(&self) -> &[Lossless] {
+        &self.lossless
+    }
+
+    pub fn channel_quantization_bits(&self) -> u8 {
+        self.channel_quantization_bits
+    }
+
+    pub fn translation_reference(&self) -> TranslationReference {
+        self.translation_reference
+    }
+
+    pub fn rotation_anchor(&self) -> RotationAnchor {
+        self.rotation_anchor
+    }
+
+    pub fn error_targets(&self) -> &Targets {
+        &self.error_targets
+    }
+
+    pub fn seek_index(&self) -> bool {
+        self.seek_index
+    }
+
+    // Frames per block, for a seek index or a --calibration stream
+    pub fn block_frames(&self) -> usize {
+        self.block_frames
+    }
+
+    pub fn max_delta_run(&self) -> Option<usize> {
+        self.max_delta_run
+    }
+
+    pub fn sparse(&self) -> bool {
+        self.sparse
+    }
+
+    pub fn bit_planes(&self) -> bool {
+        self.bit_planes
+    }
+
+    // Whether the .raw file gets a seek index, and its frames per block: --block-frames, at most
+    // --max-delta-run.
+    pub fn indexed_block_frames(&self) -> Option<usize> {
+        if !self.seek_index && self.max_delta_run.is_none() {
+            return None;
+        }
+        Some(self.max_delta_run.map_or(self.block_frames, |max_delta_run| self.block_frames.min(max_delta_run)))
+    }
+
+    // The lossy operations among these settings (relative to the default 8-bit encoding), as the
+    // usage text lists them.
+    pub fn lossy_operations(&self) -> Vec<String> {
+        let mut ret = Vec::new();
+        if !self.error_targets.is_empty() {
+            ret.push("quantization to meet error targets".into());
+        } else if self.channel_quantization_bits < 8 {
+            ret.push(format!("quantization to {} bits", self.channel_quantization_bits));
+        }
+        if self.smooth.is_some() || self.auto_smooth {
+            ret.push("smoothing".into());
+        }
+        if self.root_motion {
+            ret.push("root motion encoding".into());
+        }
+        if self.mask.is_some() {
+            ret.push("masking".into());
+        }
+        ret
+    }
+}
+
+impl ConversionSettingsBuilder {
+    pub fn smooth(&mut self, filter: Filter) -> &mut ConversionSettingsBuilder {
+        self.smooth = Some(filter);
+        self
+    }
+
+    pub fn auto_smooth(&mut self, auto_smooth: bool) -> &mut ConversionSettingsBuilder {
+        self.auto_smooth = Some(auto_smooth);
+        self
+    }
+
+    pub fn mask(&mut self, mask: Mask) -> &mut ConversionSettingsBuilder {
+        self.mask = Some(mask);
+        self
+    }
+
+    pub fn bind_pose(&mut self, bind_pose: BindPose) -> &mut ConversionSettingsBuilder {
+        self.bind_pose = Some(bind_pose);
+        self
+    }
+
+    pub fn root_motion(&mut self, root_motion: bool) -> &mut ConversionSettingsBuilder {
+        self.root_motion = Some(root_motion);
+        self
+    }
+
+    pub fn root_motion_anchors(&mut self, interval: usize) -> &mut ConversionSettingsBuilder {
+        self.root_motion_anchors = Some(interval);
+        self
+    }
+
+    // Adds to the channels stored losslessly
+    pub fn lossless(&mut self, lossless: Lossless) -> &mut ConversionSettingsBuilder {
+        self.lossless.push(lossless);
+        self
+    }
+
+    pub fn channel_quantization_bits(&mut self, bits: u8) -> &mut ConversionSettingsBuilder {
+        self.channel_quantization_bits = Some(bits);
+        self
+    }
+
+    pub fn translation_reference(&mut self, reference: TranslationReference) -> &mut ConversionSettingsBuilder {
+        self.translation_reference = Some(reference);
+        self
+    }
+
+    pub fn rotation_anchor(&mut self, anchor: RotationAnchor) -> &mut ConversionSettingsBuilder {
+        self.rotation_anchor = Some(anchor);
+        self
+    }
+
+    // In degrees
+    pub fn rotation_error(&mut self, target: f64) -> &mut ConversionSettingsBuilder {
+        self.rotation_error = Some(target);
+        self
+    }
+
+    // In the clip's units
+    pub fn translation_error(&mut self, target: f64) -> &mut ConversionSettingsBuilder {
+        self.translation_error = Some(target);
+        self
+    }
+
+    pub fn seek_index(&mut self, seek_index: bool) -> &mut ConversionSettingsBuilder {
+        self.seek_index = Some(seek_index);
+        self
+    }
+
+    pub fn block_frames(&mut self, block_frames: usize) -> &mut ConversionSettingsBuilder {
+        self.block_frames = Some(block_frames);
+        self
+    }
+
+    pub fn max_delta_run(&mut self, max_delta_run: usize) -> &mut ConversionSettingsBuilder {
+        self.max_delta_run = Some(max_delta_run);
+        self
+    }
+
+    pub fn sparse(&mut self, sparse: bool) -> &mut ConversionSettingsBuilder {
+        self.sparse = Some(sparse);
+        self
+    }
+
+    pub fn bit_planes(&mut self, bit_planes: bool) -> &mut ConversionSettingsBuilder {
+        self.bit_planes = Some(bit_planes);
+        self
+    }
+
+    // Whether the .raw file is streamed with --calibration
+    pub fn streamed(&mut self, streamed: bool) -> &mut ConversionSettingsBuilder {
+        self.streamed = streamed;
+        self
+    }
+
+    // Marks the settings given so far as a profile's, so `build`'s errors name them by their keys
+    // in its [encoding] table rather than by their options.
+    pub fn in_profile(&mut self) -> &mut ConversionSettingsBuilder {
+        self.profile_keys = self.keys();
+        self
+    }
+
+    // These settings, with `fallback`'s for the ones left unset. Lossless channels are both's.
+    pub fn or(&self, fallback: &ConversionSettingsBuilder) -> ConversionSettingsBuilder {
+        let keys = self.keys();
+        let mut profile_keys = self.profile_keys.clone();
+        profile_keys.extend(fallback.profile_keys.iter().filter(|key| !keys.contains(key)));
+        ConversionSettingsBuilder {
+            smooth: self.smooth.or(fallback.smooth),
+            auto_smooth: self.auto_smooth.or(fallback.auto_smooth),
+            mask: self.mask.clone().or_else(|| fallback.mask.clone()),
+            bind_pose: self.bind_pose.clone().or_else(|| fallback.bind_pose.clone()),
+            root_motion: self.root_motion.or(fallback.root_motion),
+            root_motion_anchors: self.root_motion_anchors.or(fallback.root_motion_anchors),
+            lossless: self.lossless.iter().chain(fallback.lossless.iter()).cloned().collect(),
+            channel_quantization_bits: self.channel_quantization_bits.or(fallback.channel_quantization_bits),
+            translation_reference: self.translation_reference.or(fallback.translation_reference),
+            rotation_anchor: self.rotation_anchor.or(fallback.rotation_anchor),
+            rotation_error: self.rotation_error.or(fallback.rotation_error),
+            translation_error: self.translation_error.or(fallback.translation_error),
+            seek_index: self.seek_index.or(fallback.seek_index),
+            block_frames: self.block_frames.or(fallback.block_frames),
+            max_delta_run: self.max_delta_run.or(fallback.max_delta_run),
+            sparse: self.sparse.or(fallback.sparse),
+            bit_planes: self.bit_planes.or(fallback.bit_planes),
+            streamed: self.streamed || fallback.streamed,
+            profile_keys: profile_keys,
+        }
+    }
+
+    // Errors are `Usage` errors naming the settings as they were given.
+    pub fn build(&self) -> Result<ConversionSettings, MocapError> {
+        let defaults = ConversionSettings::default();
+        let ret = ConversionSettings {
+            smooth: self.smooth,
+            auto_smooth: self.auto_smooth.unwrap_or(defaults.auto_smooth),
+            mask: self.mask.clone(),
+            bind_pose: self.bind_pose.clone(),
+            root_motion: self.root_motion.unwrap_or(defaults.root_motion),
+            root_motion_anchors: self.root_motion_anchors.unwrap_or(defaults.root_motion_anchors),
+            lossless: self.lossless.clone(),
+            channel_quantization_bits: self.channel_quantization_bits.unwrap_or(defaults.channel_quantization_bits),
+            translation_reference: self.translation_reference.unwrap_or(defaults.translation_reference),
+            rotation_anchor: self.rotation_anchor.unwrap_or(defaults.rotation_anchor),
+            error_targets: Targets {
+                rotation: self.rotation_error,
+                translation: self.translation_error,
+            },
+            seek_index: self.seek_index.unwrap_or(defaults.seek_index),
+            block_frames: self.block_frames.unwrap_or(defaults.block_frames),
+            max_delta_run: self.max_delta_run,
+            sparse: self.sparse.unwrap_or(defaults.sparse),
+            bit_planes: self.bit_planes.unwrap_or(defaults.bit_planes),
+        };
+        let name = |key: &'static str| if self.profile_keys.contains(&key) { key.to_string() } else { format!("--{}", key) };
+
+        if num_levels(ret.channel_quantization_bits).is_none() {
+            return Err(MocapError::Usage(format!("{} must be in [1, 8], got {}", name("bits"), ret.channel_quantization_bits)));
+        }
+        for &(key, target) in [("rot-error", ret.error_targets.rotation), ("trans-error", ret.error_targets.translation)].iter() {
+            if target.is_some_and(|target| !target.is_finite() || target <= 0.0) {
+                return Err(MocapError::Usage(format!("{} must be positive", name(key))));
+            }
+        }
+        if ret.sparse && ret.seek_index {
+            return Err(MocapError::Usage(format!("{} can't be combined with {}", name("sparse"), name("seek-index"))));
+        }
+        if ret.bit_planes && ret.sparse {
+            return Err(MocapError::Usage(format!("{} can't be combined with {}, which has no delta blocks", name("bit-planes"), name("sparse"))));
+        }
+        if ret.max_delta_run == Some(0) {
+            return Err(MocapError::Usage(format!("{} must be at least 1", name("max-delta-run"))));
+        }
+        if ret.max_delta_run.is_some() && ret.sparse {
+            return Err(MocapError::Usage(format!("{} can't be combined with {}, which has no delta blocks", name("max-delta-run"), name("sparse"))));
+        }
+        if ret.block_frames == 0 {
+            return Err(MocapError::Usage(format!("{} must be at least 1", name("block-frames"))));
+        }
+        if ret.smooth.is_some() && ret.auto_smooth {
+            return Err(MocapError::Usage(format!("{} and {} can't be combined", name("smooth"), name("auto-smooth"))));
+        }
+        if self.root_motion_anchors.is_some() && !ret.root_motion {
+            return Err(MocapError::Usage(format!("{} requires {}", name("root-motion-anchors"), name("root-motion"))));
+        }
+        if ret.root_motion_anchors == 0 {
+            return Err(MocapError::Usage(format!("{} must be at least 1", name("root-motion-anchors"))));
+        }
+        if ret.root_motion && ret.bind_pose.is_some() {
+            return Err(MocapError::Usage(format!("{} can't be combined with {}", name("root-motion"), name("bind-pose"))));
+        }
+        if ret.block_frames != defaults.block_frames && !ret.seek_index && ret.max_delta_run.is_none() && !self.streamed {
+            return Err(MocapError::Usage(format!("{} requires {}, {} or --calibration", name("block-frames"), name("seek-index"), name("max-delta-run"))));
+        }
+        if self.streamed {
+            // The writer's layout is always packed blocks
+            for &(key, given) in [("sparse", ret.sparse), ("bit-planes", ret.bit_planes)].iter() {
+                if given {
+                    return Err(MocapError::Usage(format!("{} can't be combined with --calibration", name(key))));
+                }
+            }
+            for &(key, given) in [("root-motion", ret.root_motion), ("bind-pose", ret.bind_pose.is_some())].iter() {
+                if given {
+                    return Err(MocapError::Usage(format!("{} can't be combined with --calibration, whose streamed file has no metadata", name(key))));
+                }
+            }
+            if !ret.lossless.is_empty() {
+                return Err(MocapError::Usage("lossless channels can't be combined with --calibration, which quantizes every channel".into()));
+            }
+            for &(key, target) in [("rot-error", ret.error_targets.rotation), ("trans-error", ret.error_targets.translation)].iter() {
+                if target.is_some() {
+                    return Err(MocapError::Usage(format!("{} can't be combined with --calibration, which quantizes at {}", name(key), name("bits"))));
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    // The keys of the settings given, as a profile's [encoding] table names them. Lossless
+    // channels are given in their own tables.
+    fn keys(&self) -> Vec<&'static str> {
+        let given = [
+            ("smooth", self.smooth.is_some()),
+            ("auto-smooth", self.auto_smooth.is_some()),
+            ("mask", self.mask.is_some()),
+            ("bind-pose", self.bind_pose.is_some()),
+            ("root-motion", self.root_motion.is_some()),
+            ("root-motion-anchors", self.root_motion_anchors.is_some()),
+            ("bits", self.channel_quantization_bits.is_some()),
+            ("translation-reference", self.translation_reference.is_some()),
+            ("rotation-anchor", self.rotation_anchor.is_some()),
+            ("rot-error", self.rotation_error.is_some()),
+            ("trans-error", self.translation_error.is_some()),
+            ("seek-index", self.seek_index.is_some()),
+            ("block-frames", self.block_frames.is_some()),
+            ("max-delta-run", self.max_delta_run.is_some()),
+            ("sparse", self.sparse.is_some()),
+            ("bit-planes", self.bit_planes.is_some()),
+        ];
+        given.iter().filter(|(_, given)| *given).map(|(key, _)| *key).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The message `builder` fails to build with
+    fn error(builder: &ConversionSettingsBuilder) -> String {
+        match builder.build() {
+            Err(MocapError::Usage(message)) => message,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    // `set`'s settings, as a profile's
+    fn profile<F: Fn(&mut ConversionSettingsBuilder)>(set: F) -> ConversionSettingsBuilder {
+        let mut ret = ConversionSettingsBuilder::default();
+        set(&mut ret);
+        ret.in_profile();
+        ret
+    }
+
+    #[test]
+    fn defaults() {
+        let settings = ConversionSettingsBuilder::default().build().unwrap();
+        assert_eq!(settings, ConversionSettings::default());
+        assert_eq!(settings.channel_quantization_bits(), 8);
+        assert_eq!(settings.root_motion_anchors(), root_motion::DEFAULT_ANCHOR_INTERVAL);
+        assert_eq!(settings.indexed_block_frames(), None);
+        assert!(settings.lossy_operations().is_empty());
+    }
+
+    #[test]
+    fn bits_must_be_in_range() {
+        for &bits in [0, 9, 255].iter() {
+            assert_eq!(error(ConversionSettingsBuilder::default().channel_quantization_bits(bits)), format!("--bits must be in [1, 8], got {}", bits));
+            assert_eq!(error(&profile(|builder| { builder.channel_quantization_bits(bits); })), format!("bits must be in [1, 8], got {}", bits));
+        }
+        for bits in 1..=8 {
+            assert!(ConversionSettingsBuilder::default().channel_quantization_bits(bits).build().is_ok());
+        }
+    }
+
+    #[test]
+    fn error_targets_must_be_positive() {
+        for &target in [0.0, -1.0, f64::NAN, f64::INFINITY].iter() {
+            assert_eq!(error(ConversionSettingsBuilder::default().rotation_error(target)), "--rot-error must be positive");
+            assert_eq!(error(ConversionSettingsBuilder::default().translation_error(target)), "--trans-error must be positive");
+            assert_eq!(error(&profile(|builder| { builder.translation_error(target); })), "trans-error must be positive");
+        }
+        let settings = ConversionSettingsBuilder::default().rotation_error(0.5).translation_error(0.1).build().unwrap();
+        assert_eq!((settings.error_targets().rotation, settings.error_targets().translation), (Some(0.5), Some(0.1)));
+    }
+
+    #[test]
+    fn sparse_excludes_a_seek_index() {
+        assert_eq!(error(ConversionSettingsBuilder::default().sparse(true).seek_index(true)), "--sparse can't be combined with --seek-index");
+        assert_eq!(error(&profile(|builder| { builder.sparse(true).seek_index(true); })), "sparse can't be combined with seek-index");
+        assert!(ConversionSettingsBuilder::default().sparse(true).seek_index(false).build().is_ok());
+    }
+
+    #[test]
+    fn sparse_excludes_bit_planes() {
+        assert_eq!(error(ConversionSettingsBuilder::default().sparse(true).bit_planes(true)), "--bit-planes can't be combined with --sparse, which has no delta blocks");
+        assert_eq!(error(&profile(|builder| { builder.sparse(true).bit_planes(true); })), "bit-planes can't be combined with sparse, which has no delta blocks");
+    }
+
+    #[test]
+    fn max_delta_run_must_be_at_least_1() {
+        assert_eq!(error(ConversionSettingsBuilder::default().max_delta_run(0)), "--max-delta-run must be at least 1");
+        assert_eq!(error(&profile(|builder| { builder.max_delta_run(0); })), "max-delta-run must be at least 1");
+        assert_eq!(ConversionSettingsBuilder::default().max_delta_run(10).build().unwrap().indexed_block_frames(), Some(10));
+    }
+
+    #[test]
+    fn sparse_excludes_max_delta_run() {
+        assert_eq!(error(ConversionSettingsBuilder::default().sparse(true).max_delta_run(10)), "--max-delta-run can't be combined with --sparse, which has no delta blocks");
+        assert_eq!(error(&profile(|builder| { builder.sparse(true).max_delta_run(10); })), "max-delta-run can't be combined with sparse, which has no delta blocks");
+    }
+
+    #[test]
+    fn block_frames_must_be_at_least_1() {
+        assert_eq!(error(ConversionSettingsBuilder::default().block_frames(0)), "--block-frames must be at least 1");
+        assert_eq!(error(&profile(|builder| { builder.seek_index(true).block_frames(0); })), "block-frames must be at least 1");
+        assert_eq!(ConversionSettingsBuilder::default().seek_index(true).block_frames(16).build().unwrap().indexed_block_frames(), Some(16));
+    }
+
+    #[test]
+    fn smoothing_is_one_filter_or_automatic() {
+        let filter = Filter::MovingAverage(3);
+        assert_eq!(error(ConversionSettingsBuilder::default().smooth(filter).auto_smooth(true)), "--smooth and --auto-smooth can't be combined");
+        assert_eq!(error(&profile(|builder| { builder.smooth(filter).auto_smooth(true); })), "smooth and auto-smooth can't be combined");
+        assert_eq!(ConversionSettingsBuilder::default().smooth(filter).auto_smooth(false).build().unwrap().smooth(), Some(&filter));
+    }
+
+    #[test]
+    fn root_motion_anchors_require_root_motion() {
+        assert_eq!(error(ConversionSettingsBuilder::default().root_motion_anchors(10)), "--root-motion-anchors requires --root-motion");
+        assert_eq!(error(ConversionSettingsBuilder::default().root_motion(false).root_motion_anchors(10)), "--root-motion-anchors requires --root-motion");
+        assert_eq!(error(&profile(|builder| { builder.root_motion(false).root_motion_anchors(10); })), "root-motion-anchors requires root-motion");
+        // A setting given nowhere is named by its option
+        assert_eq!(error(&profile(|builder| { builder.root_motion_anchors(10); })), "root-motion-anchors requires --root-motion");
+        assert_eq!(ConversionSettingsBuilder::default().root_motion(true).root_motion_anchors(10).build().unwrap().root_motion_anchors(), 10);
+    }
+
+    #[test]
+    fn root_motion_anchors_must_be_at_least_1() {
+        assert_eq!(error(ConversionSettingsBuilder::default().root_motion(true).root_motion_anchors(0)), "--root-motion-anchors must be at least 1");
+        assert_eq!(error(&profile(|builder| { builder.root_motion(true).root_motion_anchors(0); })), "root-motion-anchors must be at least 1");
+    }
+
+    #[test]
+    fn root_motion_excludes_a_bind_pose() {
+        assert_eq!(error(ConversionSettingsBuilder::default().root_motion(true).bind_pose(BindPose::Zero)), "--root-motion can't be combined with --bind-pose");
+        assert_eq!(error(&profile(|builder| { builder.root_motion(true).bind_pose(BindPose::FirstFrame); })), "root-motion can't be combined with bind-pose");
+        assert!(ConversionSettingsBuilder::default().root_motion(false).bind_pose(BindPose::Zero).build().is_ok());
+    }
+
+    #[test]
+    fn block_frames_require_blocks_of_their_own() {
+        assert_eq!(error(ConversionSettingsBuilder::default().block_frames(64)), "--block-frames requires --seek-index, --max-delta-run or --calibration");
+        assert_eq!(error(&profile(|builder| { builder.block_frames(64); })), "block-frames requires --seek-index, --max-delta-run or --calibration");
+        for builder in [
+            ConversionSettingsBuilder::default().block_frames(64).seek_index(true).clone(),
+            ConversionSettingsBuilder::default().block_frames(64).max_delta_run(100).clone(),
+            ConversionSettingsBuilder::default().block_frames(64).streamed(true).clone(),
+            ConversionSettingsBuilder::default().block_frames(writer::DEFAULT_BLOCK_FRAMES).clone(),
+        ].iter() {
+            assert!(builder.build().is_ok(), "{:?}", builder);
+        }
+    }
+
+    // The command line streaming with --calibration, over `profile`
+    fn streamed(profile: &ConversionSettingsBuilder) -> ConversionSettingsBuilder {
+        ConversionSettingsBuilder::default().streamed(true).or(profile)
+    }
+
+    #[test]
+    fn a_stream_is_packed_blocks() {
+        assert_eq!(error(ConversionSettingsBuilder::default().streamed(true).sparse(true)), "--sparse can't be combined with --calibration");
+        assert_eq!(error(&streamed(&profile(|builder| { builder.sparse(true); }))), "sparse can't be combined with --calibration");
+        assert_eq!(error(ConversionSettingsBuilder::default().streamed(true).bit_planes(true)), "--bit-planes can't be combined with --calibration");
+        assert_eq!(error(&streamed(&profile(|builder| { builder.bit_planes(true); }))), "bit-planes can't be combined with --calibration");
+        assert!(ConversionSettingsBuilder::default().streamed(true).seek_index(true).block_frames(64).build().is_ok());
+    }
+
+    #[test]
+    fn a_stream_has_no_metadata() {
+        assert_eq!(error(ConversionSettingsBuilder::default().streamed(true).root_motion(true)), "--root-motion can't be combined with --calibration, whose streamed file has no metadata");
+        assert_eq!(error(&streamed(&profile(|builder| { builder.root_motion(true); }))), "root-motion can't be combined with --calibration, whose streamed file has no metadata");
+        assert_eq!(error(ConversionSettingsBuilder::default().streamed(true).bind_pose(BindPose::Zero)), "--bind-pose can't be combined with --calibration, whose streamed file has no metadata");
+        assert_eq!(error(&streamed(&profile(|builder| { builder.bind_pose(BindPose::FirstFrame); }))), "bind-pose can't be combined with --calibration, whose streamed file has no metadata");
+        // A mask's metadata only names it
+        assert!(ConversionSettingsBuilder::default().streamed(true).mask(Mask::Upper).build().is_ok());
+    }
+
+    #[test]
+    fn a_stream_quantizes_every_channel_at_the_bit_depth() {
+        let lossless = Lossless { selector: "Hips".into(), type_: None };
+        assert_eq!(error(&streamed(&profile(|builder| { builder.lossless(lossless.clone()); }))), "lossless channels can't be combined with --calibration, which quantizes every channel");
+        assert_eq!(error(ConversionSettingsBuilder::default().streamed(true).rotation_error(0.5)), "--rot-error can't be combined with --calibration, which quantizes at --bits");
+        assert_eq!(error(&streamed(&profile(|builder| { builder.translation_error(0.1).channel_quantization_bits(6); }))), "trans-error can't be combined with --calibration, which quantizes at bits");
+        assert!(ConversionSettingsBuilder::default().streamed(true).channel_quantization_bits(6).build().is_ok());
+    }
+
+    #[test]
+    fn errors_name_each_setting_where_it_was_given() {
+        // The command line's --sparse over the profile's seek index
+        let mut command_line = ConversionSettingsBuilder::default();
+        command_line.sparse(true);
+        let merged = command_line.or(&profile(|builder| { builder.seek_index(true).sparse(false); }));
+        assert_eq!(error(&merged), "--sparse can't be combined with seek-index");
+
+        // A setting the command line overrides is the command line's
+        let mut command_line = ConversionSettingsBuilder::default();
+        command_line.channel_quantization_bits(0);
+        assert_eq!(error(&command_line.or(&profile(|builder| { builder.channel_quantization_bits(4); }))), "--bits must be in [1, 8], got 0");
+    }
+
+    #[test]
+    fn or_layers_settings_and_adds_lossless_channels() {
+        let lossless = |selector: &str| Lossless { selector: selector.into(), type_: None };
+        let mut command_line = ConversionSettingsBuilder::default();
+        command_line.channel_quantization_bits(6).mask(Mask::Upper);
+        let mut profile = ConversionSettingsBuilder::default();
+        profile.channel_quantization_bits(4).seek_index(true).mask(Mask::Lower).bind_pose(BindPose::Zero).lossless(lossless("Hips"));
+        let mut directives = ConversionSettingsBuilder::default();
+        directives.lossless(lossless("Head"));
+
+        let settings = command_line.or(&directives).or(&profile).build().unwrap();
+        assert_eq!(settings.channel_quantization_bits(), 6);
+        assert!(settings.seek_index());
+        assert_eq!(settings.mask(), Some(&Mask::Upper));
+        assert_eq!(settings.bind_pose(), Some(&BindPose::Zero));
+        assert_eq!(settings.lossless(), &[lossless("Head"), lossless("Hips")][..]);
+    }
+
+    #[test]
+    fn lossy_operations() {
+        let settings = ConversionSettingsBuilder::default().channel_quantization_bits(4).auto_smooth(true).root_motion(true).mask(Mask::Upper).build().unwrap();
+        assert_eq!(settings.lossy_operations(), vec!["quantization to 4 bits", "smoothing", "root motion encoding", "masking"]);
+        let settings = ConversionSettingsBuilder::default().channel_quantization_bits(4).rotation_error(0.5).bind_pose(BindPose::Zero).lossless(Lossless { selector: "Hips".into(), type_: None }).build().unwrap();
+        assert_eq!(settings.lossy_operations(), vec!["quantization to meet error targets"]);
+    }
+}
@@ -0,0 +1,537 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use bvh;
+
+use channel_map::ChannelDescriptor;
+use error::MocapError;
+use json::{self, Value};
+use names::{self, DuplicateNames};
+use raw::Reader;
+use {channel_type, rotation_channels, ChannelType};
+
+// Curve export with --export-curves, for runtimes that evaluate splines rather than sample frames.
+// Each channel of the decoded clip (as the output BVH has it) is fitted with cubic Hermite
+// segments: knots at some of its frames, each with a time, a value and an incoming and outgoing
+// tangent (in units, or degrees, per second), such that evaluating the curve at every frame's
+// time stays within --curve-tolerance of the frame's value.
+//
+// Knots are placed greedily: from each knot, the next is the furthest frame the segment between
+// them still fits, found by doubling the segment and then bisecting (so a segment that fits is
+// always kept, though a longer one past a failing length may be missed). Tangents come from finite
+// differences of the frames around a knot: central inside the clip, one-sided at its ends, the
+// same in and out. A segment between neighbouring frames always fits, since a Hermite segment
+// passes through both of its knots, so a noisy channel degrades to a knot at every frame rather
+// than missing the tolerance. Rotations are unwrapped first (a channel crossing from 179 to -179
+// degrees carries on to 181), so their curves are continuous, and values may leave [-180, 180).
+//
+// The file is JSON if its name ends in .json:
+//
+//   {
+//     "frame_time": 0.033333, "tolerance": 0.25,
+//     "channels": [
+//       { "joint": "Hips", "type": "TranslationX", "times": [0, 0.5, ...], "values": [...],
+//         "in_tangents": [...], "out_tangents": [...] },
+//       ...
+//     ]
+//   }
+//
+// and otherwise binary, little-endian: the magic "MCRV", a u32 FORMAT_VERSION, a u32 channel
+// count and an f32 frame time, then per channel in flat channel order a u32 knot count followed
+// by that many knots of four f32s (time, value, in tangent, out tangent). `evaluate` is the
+// reference evaluation; the conversion checks the fit with it and prints the largest error.
+//
+// A curve file can also be read back, as the --motion of a --hierarchy conversion, so curves edited
+// in another tool go through the pipeline like any clip (see `read`). Exporting and importing at
+// the clip's own rate gives back every frame within the tolerance, before quantization.
+
+pub const MAGIC: &[u8; 4] = b"MCRV";
+pub const FORMAT_VERSION: u32 = 1;
+
+pub const DEFAULT_TOLERANCE: f64 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Knot {
+    pub time: f64,
+    pub value: f64,
+    pub in_tangent: f64,
+    pub out_tangent: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Curve {
+    pub knots: Vec<Knot>, // By time
+}
+
+impl Curve {
+    // The curve's value at `time`, held at the first and last knots' values outside them.
+    pub fn evaluate(&self, time: f64) -> f64 {
+        let knots = &self.knots;
+        match knots.len() {
+            0 => return 0.0,
+            _ if time <= knots[0].time => return knots[0].value,
+            _ if time >= knots[knots.len() - 1].time => return knots[knots.len() - 1].value,
+            _ => (),
+        }
+        let end = knots.partition_point(|knot| knot.time <= time).min(knots.len() - 1);
+        hermite(&knots[end - 1], &knots[end], time)
+    }
+}
+
+// Fits every channel of `bvh`, in flat channel order.
+pub fn fit(bvh: &bvh::Bvh, tolerance: f64) -> Vec<Curve> {
+    let frames = &bvh.motion.frames;
+    let frame_time = bvh.motion.frame_time;
+    rotation_channels(&bvh.hierarchy.root).into_iter().enumerate().map(|(channel, rotation)| {
+        let mut values = frames.iter().map(|frame| frame[channel]).collect::<Vec<_>>();
+        if rotation {
+            unwrap(&mut values);
+        }
+        fit_channel(&values, frame_time, tolerance)
+    }).collect()
+}
+
+// The largest difference between `curves` and the frames they were fitted to, at the frames'
+// times (rotations the short way round).
+pub fn max_error(bvh: &bvh::Bvh, curves: &[Curve]) -> f64 {
+    let rotations = rotation_channels(&bvh.hierarchy.root);
+    let mut ret = 0.0f64;
+    for (index, frame) in bvh.motion.frames.iter().enumerate() {
+        let time = index as f64 * bvh.motion.frame_time;
+        for ((value, curve), rotation) in frame.iter().zip(curves.iter()).zip(rotations.iter()) {
+            let difference = curve.evaluate(time) - value;
+            let difference = if *rotation { difference - 360.0 * (difference / 360.0).round() } else { difference };
+            ret = ret.max(difference.abs());
+        }
+    }
+    ret
+}
+
+fn fit_channel(values: &[f64], frame_time: f64, tolerance: f64) -> Curve {
+    let knot = |frame: usize| {
+        let tangent = match (frame.checked_sub(1), values.get(frame + 1)) {
+            (Some(previous), Some(next)) => (next - values[previous]) / (2.0 * frame_time),
+            (None, Some(next)) => (next - values[frame]) / frame_time,
+            (Some(previous), None) => (values[frame] - values[previous]) / frame_time,
+            (None, None) => 0.0,
+        };
+        Knot {
+            time: frame as f64 * frame_time,
+            value: values[frame],
+            in_tangent: tangent,
+            out_tangent: tangent,
+        }
+    };
+    let fits = |start: usize, end: usize| {
+        let (first, last) = (knot(start), knot(end));
+        (start + 1..end).all(|frame| (hermite(&first, &last, frame as f64 * frame_time) - values[frame]).abs() <= tolerance)
+    };
+
+    let mut knots = Vec::new();
+    if values.is_empty() {
+        return Curve { knots: knots };
+    }
+    let last = values.len() - 1;
+    let mut start = 0;
+    knots.push(knot(0));
+    while start < last {
+        // The longest segment from `start` known to fit, and the shortest known not to
+        let (mut good, mut bad) = (start + 1, None);
+        let mut length = 2;
+        while bad.is_none() && good < last {
+            let end = (start + length).min(last);
+            if fits(start, end) {
+                good = end;
+                length *= 2;
+            } else {
+                bad = Some(end);
+            }
+        }
+        if let Some(mut bad) = bad {
+            while bad - good > 1 {
+                let middle = good + (bad - good) / 2;
+                if fits(start, middle) {
+                    good = middle;
+                } else {
+                    bad = middle;
+                }
+            }
+        }
+        knots.push(knot(good));
+        start = good;
+    }
+    Curve { knots: knots }
+}
+
+fn hermite(first: &Knot, last: &Knot, time: f64) -> f64 {
+    let length = last.time - first.time;
+    if length <= 0.0 {
+        return first.value;
+    }
+    let t = (time - first.time) / length;
+    let (t2, t3) = (t * t, t * t * t);
+    (2.0 * t3 - 3.0 * t2 + 1.0) * first.value + (t3 - 2.0 * t2 + t) * length * first.out_tangent + (3.0 * t2 - 2.0 * t3) * last.value + (t3 - t2) * length * last.in_tangent
+}
+
+// Makes a rotation channel continuous, moving each value by whole turns to within half a turn of
+// the one before.
+fn unwrap(values: &mut [f64]) {
+    for index in 1..values.len() {
+        let step = values[index] - values[index - 1];
+        values[index] -= 360.0 * ((step + 180.0) / 360.0).floor();
+    }
+}
+
+pub fn write_json<W: Write>(curves: &[Curve], channel_map: &[ChannelDescriptor], frame_time: f64, tolerance: f64, w: &mut W) -> io::Result<()> {
+    writeln!(w, "{{")?;
+    writeln!(w, "  \"frame_time\": {}, \"tolerance\": {},", frame_time, tolerance)?;
+    writeln!(w, "  \"channels\": [")?;
+    for (index, (curve, descriptor)) in curves.iter().zip(channel_map.iter()).enumerate() {
+        writeln!(w, "    {{ \"joint\": \"{}\", \"type\": \"{}\", \"times\": [{}], \"values\": [{}], \"in_tangents\": [{}], \"out_tangents\": [{}] }}{}",
+            json::escape(&descriptor.joint_name),
+            descriptor.channel_type.name(),
+            join(curve.knots.iter().map(|knot| knot.time)),
+            join(curve.knots.iter().map(|knot| knot.value)),
+            join(curve.knots.iter().map(|knot| knot.in_tangent)),
+            join(curve.knots.iter().map(|knot| knot.out_tangent)),
+            if index + 1 < curves.len() { "," } else { "" })?;
+    }
+    writeln!(w, "  ]")?;
+    writeln!(w, "}}")
+}
+
+pub fn write_binary<W: Write>(curves: &[Curve], frame_time: f64, w: &mut W) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    w.write_all(&(curves.len() as u32).to_le_bytes())?;
+    w.write_all(&(frame_time as f32).to_le_bytes())?;
+    for curve in curves.iter() {
+        w.write_all(&(curve.knots.len() as u32).to_le_bytes())?;
+        for knot in curve.knots.iter() {
+            for value in [knot.time, knot.value, knot.in_tangent, knot.out_tangent].iter() {
+                w.write_all(&(*value as f32).to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Reads a curve file (JSON if `json`, binary otherwise) for the skeleton `root`, returning a curve
+// per channel of the skeleton in flat channel order and the file's frame time. JSON channels are
+// matched to the skeleton's by joint name (as --duplicate-names disambiguate would make it) and
+// type, and may come in any order; binary ones are in flat channel order. Every channel of the
+// skeleton needs exactly one curve, every knot array as many entries as there are times, and knot
+// times have to be increasing.
+pub fn read(data: &[u8], json: bool, root: &mut bvh::Joint) -> Result<(Vec<Curve>, f64), MocapError> {
+    // Disambiguated just long enough to list the channels
+    let original_names = names::make_unique(root, DuplicateNames::Disambiguate)?;
+    let mut channels = Vec::new();
+    skeleton_channels(root, &mut channels);
+    restore_names(root, &original_names);
+    let describe = |channel: usize| format!("{} {}", channels[channel].0, channels[channel].1.name());
+
+    let (curves, frame_time) = if json {
+        read_json(data, &channels, &describe)?
+    } else {
+        read_binary(data, &channels, &describe)?
+    };
+    if !frame_time.is_finite() || frame_time <= 0.0 {
+        return Err(MocapError::InvalidCurves(format!("frame time {} is not a positive number", frame_time)));
+    }
+    for (channel, curve) in curves.iter().enumerate() {
+        if curve.knots.is_empty() {
+            return Err(MocapError::InvalidCurves(format!("{}: no knots", describe(channel))));
+        }
+        for (index, knot) in curve.knots.iter().enumerate() {
+            if ![knot.time, knot.value, knot.in_tangent, knot.out_tangent].iter().all(|value| value.is_finite()) {
+                return Err(MocapError::InvalidCurves(format!("{}: knot {} is not finite", describe(channel), index)));
+            }
+            if index > 0 && knot.time <= curve.knots[index - 1].time {
+                return Err(MocapError::InvalidCurves(format!("{}: knot {} at time {} is not after knot {} at time {}", describe(channel), index, knot.time, index - 1, curve.knots[index - 1].time)));
+            }
+        }
+    }
+    Ok((curves, frame_time))
+}
+
+fn read_json<F: Fn(usize) -> String>(data: &[u8], channels: &[(String, ChannelType)], describe: &F) -> Result<(Vec<Curve>, f64), MocapError> {
+    let invalid = MocapError::InvalidCurves;
+    let value = ::std::str::from_utf8(data).map_err(|_| invalid("invalid UTF-8".into()))
+        .and_then(|text| json::parse(text).map_err(|e| invalid(format!("invalid JSON: {}", e))))?;
+    let frame_time = value.get("frame_time").and_then(Value::as_f64).ok_or_else(|| invalid("expected a frame_time".into()))?;
+    let entries = value.get("channels").and_then(Value::as_array).ok_or_else(|| invalid("expected a channels array".into()))?;
+
+    let mut curves = vec![None; channels.len()];
+    for (index, entry) in entries.iter().enumerate() {
+        let field = |key: &str| entry.get(key).and_then(Value::as_str).ok_or_else(|| invalid(format!("channel {}: expected a {} name", index, key)));
+        let (joint, type_name) = (field("joint")?, field("type")?);
+        let location = format!("channel {} ({} {})", index, joint, type_name);
+        let type_ = ChannelType::from_name(type_name).ok_or_else(|| invalid(format!("{}: unknown channel type {}", location, type_name)))?;
+        let channel = channels.iter().position(|channel| channel.0 == joint && channel.1 == type_)
+            .ok_or_else(|| invalid(format!("{}: the skeleton has no such channel", location)))?;
+        if curves[channel].is_some() {
+            return Err(invalid(format!("{}: a second curve for {}", location, describe(channel))));
+        }
+
+        let numbers = |key: &str| -> Result<Vec<f64>, MocapError> {
+            let values = entry.get(key).and_then(Value::as_array).ok_or_else(|| invalid(format!("{}: expected a {} array", location, key)))?;
+            values.iter().map(|value| value.as_f64().ok_or_else(|| invalid(format!("{}: {} holds something other than numbers", location, key)))).collect()
+        };
+        let times = numbers("times")?;
+        let arrays = [("values", numbers("values")?), ("in_tangents", numbers("in_tangents")?), ("out_tangents", numbers("out_tangents")?)];
+        for (key, values) in arrays.iter() {
+            if values.len() != times.len() {
+                return Err(invalid(format!("{}: {} {} for {} knot times", location, values.len(), key, times.len())));
+            }
+        }
+        curves[channel] = Some(Curve {
+            knots: (0..times.len()).map(|knot| Knot {
+                time: times[knot],
+                value: arrays[0].1[knot],
+                in_tangent: arrays[1].1[knot],
+                out_tangent: arrays[2].1[knot],
+            }).collect(),
+        });
+    }
+
+    let missing = (0..channels.len()).filter(|channel| curves[*channel].is_none()).map(describe).collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(invalid(format!("no curves for the skeleton's {}", missing.join(", "))));
+    }
+    Ok((curves.into_iter().map(Option::unwrap).collect(), frame_time))
+}
+
+fn read_binary<F: Fn(usize) -> String>(data: &[u8], channels: &[(String, ChannelType)], describe: &F) -> Result<(Vec<Curve>, f64), MocapError> {
+    // The reader's errors are about running out of data, which is just as true of a curve file
+    let truncated = |e: MocapError| match e {
+        MocapError::InvalidRaw(message) => MocapError::InvalidCurves(message),
+        e => e,
+    };
+    let mut reader = Reader::new(data);
+    if reader.bytes(MAGIC.len()).map_err(truncated)? != MAGIC {
+        return Err(MocapError::InvalidCurves("not a curve file (wrong magic)".into()));
+    }
+    let version = reader.u32().map_err(truncated)?;
+    if version != FORMAT_VERSION {
+        return Err(MocapError::InvalidCurves(format!("unsupported format version {}", version)));
+    }
+    let num_curves = reader.u32().map_err(truncated)? as usize;
+    if num_curves != channels.len() {
+        return Err(MocapError::InvalidCurves(format!("{} curves for the skeleton's {} channels", num_curves, channels.len())));
+    }
+    let frame_time = reader.f32().map_err(truncated)? as f64;
+
+    let mut curves = Vec::with_capacity(num_curves);
+    for channel in 0..num_curves {
+        let num_knots = reader.u32().map_err(truncated)? as usize;
+        let mut knots = Vec::with_capacity(reader.capacity(num_knots, 16));
+        for _ in 0..num_knots {
+            let mut value = || reader.f32().map(|value| value as f64).map_err(|e| match truncated(e) {
+                MocapError::InvalidCurves(message) => MocapError::InvalidCurves(format!("{}: {}", describe(channel), message)),
+                e => e,
+            });
+            knots.push(Knot {
+                time: value()?,
+                value: value()?,
+                in_tangent: value()?,
+                out_tangent: value()?,
+            });
+        }
+        curves.push(Curve { knots: knots });
+    }
+    if reader.remaining() > 0 {
+        return Err(MocapError::InvalidCurves(format!("{} unexpected bytes after the last curve", reader.remaining())));
+    }
+    Ok((curves, frame_time))
+}
+
+// Every channel of the skeleton, in flat channel order, as (joint name, type)
+fn skeleton_channels(joint: &bvh::Joint, channels: &mut Vec<(String, ChannelType)>) {
+    channels.extend(joint.channels.iter().map(|channel| (joint.name.clone(), channel_type(channel))));
+    if let bvh::JointChildren::Joints(ref joints) = joint.children {
+        for joint in joints.iter() {
+            skeleton_channels(joint, channels);
+        }
+    }
+}
+
+fn restore_names(joint: &mut bvh::Joint, original_names: &HashMap<String, String>) {
+    if let Some(name) = original_names.get(&joint.name) {
+        joint.name = name.clone();
+    }
+    if let bvh::JointChildren::Joints(ref mut joints) = joint.children {
+        for joint in joints.iter_mut() {
+            restore_names(joint, original_names);
+        }
+    }
+}
+
+// The frames `curves` give at every multiple of `frame_time` from 0 until the last knot, in flat
+// channel order.
+pub fn sample(curves: &[Curve], frame_time: f64) -> Vec<Vec<f64>> {
+    let duration = curves.iter().filter_map(|curve| curve.knots.last()).map(|knot| knot.time).fold(0.0f64, f64::max);
+    // Allowing for times stored as f32s ending a hair before the last frame
+    let num_frames = (duration / frame_time + 1e-3).floor() as usize + 1;
+    (0..num_frames).map(|frame| curves.iter().map(|curve| curve.evaluate(frame as f64 * frame_time)).collect()).collect()
+}
+
+fn join<I: Iterator<Item = f64>>(values: I) -> String {
+    values.map(|value| value.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::slice;
+
+    use super::*;
+    use conversion::ConversionSettings;
+    use depth::DEFAULT_MAX_DEPTH;
+    use options::Options;
+    use test_util;
+    use {build_bvh, build_mocap};
+
+    const NUM_FRAMES: usize = 60;
+
+    const HIERARCHY: &str = "HIERARCHY
+ROOT Hips
+{
+\tOFFSET 0 0 0
+\tCHANNELS 2 Xposition Zrotation
+\tEnd Site
+\t{
+\t\tOFFSET 0 5 0
+\t}
+}
+";
+
+    fn skeleton() -> bvh::Joint {
+        test_util::parse(&test_util::motion_text(HIERARCHY, 2, 1, |_, _| 0.0)).hierarchy.root
+    }
+
+    fn read_json_text(text: &str) -> Result<(Vec<Curve>, f64), MocapError> {
+        read(text.as_bytes(), true, &mut skeleton())
+    }
+
+    fn channel(joint: &str, type_: &str, times: &str, values: &str, tangents: &str) -> String {
+        format!("{{ \"joint\": \"{}\", \"type\": \"{}\", \"times\": [{}], \"values\": [{}], \"in_tangents\": [{}], \"out_tangents\": [{}] }}", joint, type_, times, values, tangents, tangents)
+    }
+
+    fn file(channels: &[String]) -> String {
+        format!("{{ \"frame_time\": 0.5, \"tolerance\": 0.25, \"channels\": [{}] }}", channels.join(", "))
+    }
+
+    fn error(result: Result<(Vec<Curve>, f64), MocapError>) -> String {
+        match result {
+            Err(MocapError::InvalidCurves(message)) => message,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    // Export then import at the clip's rate, in either format
+    #[test]
+    fn round_trips_within_the_tolerance() {
+        let mut bvh = test_util::sine_clip(NUM_FRAMES);
+        let curves = fit(&bvh, DEFAULT_TOLERANCE);
+        assert!(max_error(&bvh, &curves) <= DEFAULT_TOLERANCE);
+        let channel_map = build_mocap(&bvh, &ConversionSettings::default().settings()).channel_map();
+
+        let mut json = Vec::new();
+        write_json(&curves, &channel_map, bvh.motion.frame_time, DEFAULT_TOLERANCE, &mut json).unwrap();
+        let mut binary = Vec::new();
+        write_binary(&curves, bvh.motion.frame_time, &mut binary).unwrap();
+        for (data, is_json) in [(json, true), (binary, false)].iter() {
+            let (read_curves, frame_time) = read(data, *is_json, &mut bvh.hierarchy.root).unwrap();
+            assert!((frame_time - bvh.motion.frame_time).abs() < 1e-6);
+            let frames = sample(&read_curves, frame_time);
+            assert_eq!(frames.len(), NUM_FRAMES);
+            for (frame, original) in frames.iter().zip(bvh.motion.frames.iter()) {
+                for (value, original) in frame.iter().zip(original.iter()) {
+                    // Binary knots are f32s
+                    assert!((value - original).abs() <= DEFAULT_TOLERANCE + 1e-3, "{} vs {}", value, original);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn imported_curves_convert_within_the_tolerance_and_a_step() {
+        let dir = test_util::temp_dir("curves");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        fs::write(path("in.bvh"), test_util::clip_text(NUM_FRAMES, test_util::sine)).unwrap();
+        let export = Options::parse(["--export-curves", &path("curves.json"), &path("in.bvh"), &path("out.bvh"), &path("out.csv"), &path("out.raw")].iter().map(|arg| arg.to_string())).unwrap();
+        ::convert(Path::new(&path("in.bvh")), Path::new(&path("out.bvh")), Path::new(&path("out.csv")), Path::new(&path("out.raw")), &export, None).unwrap();
+
+        let import = Options::parse(["--hierarchy", &path("in.bvh"), "--motion", &path("curves.json"), &path("back.bvh"), &path("back.csv"), &path("back.raw")].iter().map(|arg| arg.to_string())).unwrap();
+        ::convert(Path::new(&path("curves.json")), Path::new(&path("back.bvh")), Path::new(&path("back.csv")), Path::new(&path("back.raw")), &import, None).unwrap();
+
+        // The curves fit the exported conversion's output, and the import quantizes again
+        let exported = test_util::parse(&fs::read_to_string(path("out.bvh")).unwrap());
+        let imported = build_bvh(&::raw::read(&fs::read(path("back.raw")).unwrap(), DEFAULT_MAX_DEPTH).unwrap());
+        assert_eq!(imported.motion.frames.len(), NUM_FRAMES);
+        let steps = ::raw::read(&fs::read(path("back.raw")).unwrap(), DEFAULT_MAX_DEPTH).unwrap().channels().iter().map(|channel| channel.value_range as f64 / 255.0).collect::<Vec<_>>();
+        for (frame, original) in imported.motion.frames.iter().zip(exported.motion.frames.iter()) {
+            for (channel, (value, original)) in frame.iter().zip(original.iter()).enumerate() {
+                assert!((value - original).abs() <= DEFAULT_TOLERANCE + steps[channel] + 1e-4, "channel {}: {} vs {}", channel, value, original);
+            }
+        }
+    }
+
+    #[test]
+    fn json_channels_match_by_joint_and_type_in_any_order() {
+        let (curves, frame_time) = read_json_text(&file(&[
+            channel("Hips", "RotationZ", "0, 1", "10, 20", "0, 0"),
+            channel("Hips", "TranslationX", "0", "3", "0"),
+        ])).unwrap();
+        assert_eq!(frame_time, 0.5);
+        assert_eq!(curves[0].knots, vec![Knot { time: 0.0, value: 3.0, in_tangent: 0.0, out_tangent: 0.0 }]);
+        assert_eq!(curves[1].knots.len(), 2);
+        assert_eq!(sample(&curves, frame_time).len(), 3);
+    }
+
+    #[test]
+    fn reports_channels_missing_from_the_skeleton_or_the_file() {
+        let x = channel("Hips", "TranslationX", "0", "3", "0");
+        let z = channel("Hips", "RotationZ", "0", "3", "0");
+        assert_eq!(error(read_json_text(&file(slice::from_ref(&x)))), "no curves for the skeleton's Hips RotationZ");
+        assert_eq!(error(read_json_text(&file(&[x.clone(), z.clone(), channel("Tail", "RotationZ", "0", "3", "0")]))), "channel 2 (Tail RotationZ): the skeleton has no such channel");
+        assert_eq!(error(read_json_text(&file(&[x.clone(), channel("Hips", "RotationY", "0", "3", "0")]))), "channel 1 (Hips RotationY): the skeleton has no such channel");
+        assert_eq!(error(read_json_text(&file(&[x.clone(), z.clone(), x.clone()]))), "channel 2 (Hips TranslationX): a second curve for Hips TranslationX");
+        assert_eq!(error(read_json_text(&file(&[x, channel("Hips", "Spin", "0", "3", "0")]))), "channel 1 (Hips Spin): unknown channel type Spin");
+    }
+
+    #[test]
+    fn reports_knot_arrays_of_the_wrong_length() {
+        let x = channel("Hips", "TranslationX", "0", "3", "0");
+        assert_eq!(error(read_json_text(&file(&[x.clone(), channel("Hips", "RotationZ", "0, 1", "3, 4", "0")]))), "channel 1 (Hips RotationZ): 1 in_tangents for 2 knot times");
+        assert_eq!(error(read_json_text(&file(&[x, channel("Hips", "RotationZ", "0, 1", "3", "0, 0")]))), "channel 1 (Hips RotationZ): 1 values for 2 knot times");
+    }
+
+    #[test]
+    fn reports_knot_times_out_of_order() {
+        let x = channel("Hips", "TranslationX", "0", "3", "0");
+        assert_eq!(error(read_json_text(&file(&[x.clone(), channel("Hips", "RotationZ", "0, 2, 1", "3, 4, 5", "0, 0, 0")]))), "Hips RotationZ: knot 2 at time 1 is not after knot 1 at time 2");
+        assert_eq!(error(read_json_text(&file(&[x, channel("Hips", "RotationZ", "1, 1", "3, 4", "0, 0")]))), "Hips RotationZ: knot 1 at time 1 is not after knot 0 at time 1");
+    }
+
+    #[test]
+    fn reports_truncated_and_mismatched_binary_files() {
+        let curves = vec![Curve { knots: vec![Knot { time: 0.0, value: 1.0, in_tangent: 0.0, out_tangent: 0.0 }] }; 2];
+        let mut data = Vec::new();
+        write_binary(&curves, 0.5, &mut data).unwrap();
+        assert_eq!(read(&data, false, &mut skeleton()).unwrap().0, curves);
+
+        let message = error(read(&data[..data.len() - 2], false, &mut skeleton()));
+        assert!(message.starts_with("Hips RotationZ: "), "{}", message);
+        // Cut before the second curve's knot count
+        assert!(matches!(read(&data[..data.len() - 16 - 4], false, &mut skeleton()), Err(MocapError::InvalidCurves(_))));
+        let mut extra = data.clone();
+        extra.push(0);
+        assert_eq!(error(read(&extra, false, &mut skeleton())), "1 unexpected bytes after the last curve");
+
+        let mut data = Vec::new();
+        write_binary(&curves[..1], 0.5, &mut data).unwrap();
+        assert_eq!(error(read(&data, false, &mut skeleton())), "1 curves for the skeleton's 2 channels");
+    }
+}
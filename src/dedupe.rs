@@ -0,0 +1,205 @@
+use cache;
+use error::MocapError;
+use posematch::{self, Weights};
+use raw;
+use {build_bvh, root_motion, Mocap};
+
+// Duplicate clips, for batches that pack the same take twice under different names. pack
+// fingerprints each clip as it's packed, after any reference pose is applied: a hash of its
+// encoded data, the quantized delta streams and everything stored with them. A clip hashing the
+// same as one packed before it is an exact duplicate of it. With --dedupe-tolerance a clip with
+// the same skeleton and frame count as an earlier one is also compared with it decoded, at up to
+// NUM_SAMPLES frames evenly spaced through the clip, and is a near-duplicate if every sampled
+// pose is within the tolerance of the earlier clip's, in the channel metric of posematch.rs.
+//
+// What pack does with a duplicate depends on --dedupe-clips:
+//
+//   warn   Prints which clip it duplicates and stores it anyway (the default)
+//   skip   Prints which clip it duplicates and leaves it out of the container
+//   alias  Stores it as an alias of the earlier clip (see container.rs): an entry with its own
+//          name, attributes and thumbnail sharing the earlier clip's encoded data, which a
+//          near-duplicate then decodes as
+//
+// Duplicates are only looked for among the clips storing their own data; an alias is never
+// aliased itself, so a third copy aliases the first.
+
+// Frames compared per clip for near-duplicates
+pub const NUM_SAMPLES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    Warn,
+    Skip,
+    Alias,
+}
+
+#[derive(Debug, Clone)]
+pub struct Fingerprint {
+    hash: u128,
+    skeleton_hash: u128,
+    num_frames: u32,
+    samples: Vec<Vec<f64>>, // Decoded poses, only with a tolerance
+    weights: Option<Weights>, // Likewise
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Duplicate {
+    pub clip: usize, // Index in the container
+    pub distance: Option<f64>, // For a near-duplicate, the largest pose distance over the sampled frames
+}
+
+// The clips storing their own data, by index in the container.
+#[derive(Debug, Default)]
+pub struct Index {
+    clips: Vec<(usize, Fingerprint)>,
+}
+
+pub fn fingerprint(mocap: &Mocap, tolerance: Option<f64>) -> Result<Fingerprint, MocapError> {
+    let mut data = Vec::new();
+    raw::write_clip_header(mocap, &mut data)?;
+    for channel in mocap.channels() {
+        data.extend(channel.deltas.iter().map(|delta| *delta as u8));
+        for value in channel.values.iter().flatten() {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    let (mut samples, mut weights) = (Vec::new(), None);
+    if tolerance.is_some() && mocap.num_frames > 0 {
+        let mut bvh = build_bvh(mocap);
+        root_motion::decode(&mut bvh, &mocap.metadata)?;
+        let frames = &bvh.motion.frames;
+        let num_samples = frames.len().min(NUM_SAMPLES);
+        samples = (0..num_samples).map(|index| frames[index * (frames.len() - 1) / (num_samples - 1).max(1)].clone()).collect();
+        weights = Some(Weights::new(&bvh.hierarchy.root));
+    }
+
+    Ok(Fingerprint {
+        hash: cache::hash(&data),
+        skeleton_hash: mocap.skeleton_hash(),
+        num_frames: mocap.num_frames,
+        samples: samples,
+        weights: weights,
+    })
+}
+
+impl Index {
+    // The earliest clip `fingerprint` duplicates, exactly or within `tolerance`.
+    pub fn find(&self, fingerprint: &Fingerprint, tolerance: Option<f64>) -> Option<Duplicate> {
+        if let Some(&(clip, _)) = self.clips.iter().find(|(_, other)| other.hash == fingerprint.hash) {
+            return Some(Duplicate {
+                clip: clip,
+                distance: None,
+            });
+        }
+        let (tolerance, weights) = (tolerance?, fingerprint.weights.as_ref()?);
+        self.clips.iter().filter(|(_, other)| other.skeleton_hash == fingerprint.skeleton_hash && other.num_frames == fingerprint.num_frames).find_map(|(clip, other)| {
+            let distance = max_distance(&fingerprint.samples, &other.samples, weights);
+            if distance <= tolerance {
+                Some(Duplicate {
+                    clip: *clip,
+                    distance: Some(distance),
+                })
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn insert(&mut self, clip: usize, fingerprint: Fingerprint) {
+        self.clips.push((clip, fingerprint));
+    }
+}
+
+fn max_distance(a: &[Vec<f64>], b: &[Vec<f64>], weights: &Weights) -> f64 {
+    a.iter().zip(b.iter()).map(|(a, b)| posematch::pose_distance(a, b, weights)).fold(0.0, f64::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use build_mocap;
+    use container;
+    use conversion::ConversionSettings;
+    use depth::DEFAULT_MAX_DEPTH;
+    use options::Options;
+    use test_util;
+
+    const NUM_FRAMES: usize = 40;
+
+    // Packs `clips` (file stem, BVH text) from a directory of their own
+    fn pack(clips: &[(&str, String)], args: &[&str]) -> Vec<u8> {
+        let dir = test_util::temp_dir("dedupe");
+        let output_file_name = dir.join("clips.mcp");
+        let input_file_names = clips.iter().map(|(name, text)| {
+            let file_name = dir.join(format!("{}.bvh", name));
+            fs::write(&file_name, text).unwrap();
+            file_name.to_string_lossy().into_owned()
+        }).collect::<Vec<_>>();
+        let options = Options::parse(["pack"].iter().chain(args.iter()).map(|arg| arg.to_string()).chain(Some(output_file_name.to_string_lossy().into_owned())).chain(input_file_names.iter().cloned())).unwrap();
+        ::pack(&output_file_name, &input_file_names, &options, None).unwrap();
+        fs::read(&output_file_name).unwrap()
+    }
+
+    fn decoded(clip: &container::Clip) -> Vec<Vec<f64>> {
+        build_bvh(&clip.mocap).motion.frames
+    }
+
+    fn walk() -> String {
+        test_util::clip_text(NUM_FRAMES, test_util::sine)
+    }
+
+    #[test]
+    fn aliases_store_the_payload_once() {
+        let clips = [("walk", walk()), ("walk_copy", walk()), ("run", test_util::clip_text(NUM_FRAMES, |frame, channel| test_util::sine(frame * 2, channel)))];
+        let aliased = pack(&clips, &["--dedupe-clips", "alias", "--clip-attr", "walk_copy:loop=true,speed=2"]);
+        let warned = pack(&clips, &[]);
+        let single = pack(&clips[..1], &[]);
+
+        let container = container::read(&aliased, DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(container.clips.iter().map(|clip| (clip.name.as_str(), clip.alias)).collect::<Vec<_>>(), vec![("walk", None), ("walk_copy", Some(0)), ("run", None)]);
+        assert!(container.clips[0].attributes.is_empty());
+        assert_eq!(container.clips[1].attributes, vec![("loop".to_string(), "true".to_string()), ("speed".to_string(), "2".to_string())]);
+        assert_eq!(decoded(&container.clips[1]), decoded(&container.clips[0]));
+        assert_ne!(decoded(&container.clips[2]), decoded(&container.clips[0]));
+
+        // Warned about but stored twice, the copy takes about as much again as the clip on its own
+        // (less the container's header, and the attributes only the aliased one was packed with)
+        assert!(container::read(&warned, DEFAULT_MAX_DEPTH).unwrap().clips.iter().all(|clip| clip.alias.is_none()));
+        let clip_size = single.len() - (container::MAGIC.len() + 1 + 2 + 2);
+        assert!(warned.len() - aliased.len() >= clip_size - 64, "{} vs {} bytes for a {} byte clip", warned.len(), aliased.len(), clip_size);
+    }
+
+    #[test]
+    fn skipped_duplicates_are_left_out() {
+        let data = pack(&[("walk", walk()), ("walk_copy", walk())], &["--dedupe-clips", "skip"]);
+        let container = container::read(&data, DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(container.clips.iter().map(|clip| clip.name.as_str()).collect::<Vec<_>>(), vec!["walk"]);
+    }
+
+    #[test]
+    fn near_duplicates_need_a_tolerance() {
+        // The root's rotation turned a little, the metric ignoring the root's translation
+        let nudged = test_util::clip_text(NUM_FRAMES, |frame, channel| test_util::sine(frame, channel) + if channel == 3 { 0.5 } else { 0.0 });
+        let source = |text: &str| build_mocap(&test_util::parse(text), &ConversionSettings::default().settings());
+        let (original, copy) = (source(&walk()), source(&nudged));
+
+        for tolerance in [None, Some(1.0)].iter().cloned() {
+            let mut index = Index::default();
+            index.insert(0, fingerprint(&original, tolerance).unwrap());
+            assert_eq!(index.find(&fingerprint(&original, tolerance).unwrap(), tolerance), Some(Duplicate { clip: 0, distance: None }));
+            let near = index.find(&fingerprint(&copy, tolerance).unwrap(), tolerance);
+            match tolerance {
+                None => assert_eq!(near, None),
+                Some(tolerance) => assert!(near.is_some_and(|near| near.clip == 0 && near.distance.unwrap() <= tolerance), "{:?}", near),
+            }
+        }
+
+        // A tolerance below the distance finds nothing
+        let mut index = Index::default();
+        index.insert(0, fingerprint(&original, Some(0.0)).unwrap());
+        assert_eq!(index.find(&fingerprint(&copy, Some(0.0)).unwrap(), Some(0.0)), None);
+    }
+}
@@ -0,0 +1,115 @@
+use error::MocapError;
+
+// Hierarchy depth. Everything walking a skeleton (building, decoding, writing, and the BVH parser
+// itself) recurses once per joint level, so a pathologically deep hierarchy, or a file crafted to
+// be one, could overflow the stack. Instead every hierarchy is checked against a depth limit as it
+// comes in, before anything recurses into it: a BVH file's nesting before it's parsed, and a .raw
+// file's or container's joints as they're read. Past the limit (--max-depth, DEFAULT_MAX_DEPTH by
+// default) the input fails with an error. Real rigs are a few dozen joints deep at most.
+//
+// The limit can be raised for unusual rigs, such as long chains for ropes or tails; the work runs
+// on threads whose stacks are sized for that depth (see `stack_size`), so anything within the
+// limit fits. It's passed to everything reading a hierarchy (the --max-depth of the run's options,
+// or the `max_depth` of a raw::Reader) rather than held anywhere for the whole process.
+
+pub const DEFAULT_MAX_DEPTH: usize = 1024;
+
+// What a thread needs besides the recursion, and the most one level of it takes (debug builds are
+// by far the hungriest)
+const BASE_STACK_SIZE: usize = 8 << 20;
+const STACK_SIZE_PER_LEVEL: usize = 16 << 10;
+
+// The stack a thread walking hierarchies up to `max_depth` needs.
+pub fn stack_size(max_depth: usize) -> usize {
+    BASE_STACK_SIZE.saturating_add(max_depth.saturating_mul(STACK_SIZE_PER_LEVEL))
+}
+
+// Fails if a joint at `depth` (the root being at 1) is past `max_depth`.
+pub fn check(depth: usize, max_depth: usize) -> Result<(), MocapError> {
+    if depth > max_depth {
+        return Err(MocapError::TooDeep(max_depth));
+    }
+    Ok(())
+}
+
+// Checks the nesting of a BVH file's hierarchy without parsing it: a joint's braces nest one level
+// deeper than its parent's, and an end site's one deeper than its joint's.
+pub fn check_bvh(text: &str, max_depth: usize) -> Result<(), MocapError> {
+    let hierarchy = text.split("MOTION").next().unwrap_or("");
+    let mut nesting = 0usize;
+    for c in hierarchy.chars() {
+        match c {
+            '{' => {
+                nesting += 1;
+                // Past the deepest joint's end site
+                if nesting > max_depth.saturating_add(1) {
+                    return Err(MocapError::TooDeep(max_depth));
+                }
+            }
+            '}' => nesting = nesting.saturating_sub(1),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use conversion::ConversionSettings;
+    use periodic::Limits;
+    use raw;
+    use test_util;
+    use {build_bvh, build_mocap};
+
+    // A linear chain of `num_joints` joints, as BVH text with a couple of frames
+    fn chain(num_joints: usize) -> String {
+        let mut hierarchy = "HIERARCHY\nROOT Joint0\n{\nOFFSET 0 0 0\nCHANNELS 6 Xposition Yposition Zposition Zrotation Xrotation Yrotation\n".to_string();
+        for joint in 1..num_joints {
+            hierarchy.push_str(&format!("JOINT Joint{}\n{{\nOFFSET 0 1 0\nCHANNELS 3 Zrotation Xrotation Yrotation\n", joint));
+        }
+        hierarchy.push_str("End Site\n{\nOFFSET 0 1 0\n}\n");
+        for _ in 0..num_joints {
+            hierarchy.push_str("}\n");
+        }
+        test_util::motion_text(&hierarchy, 3 + 3 * num_joints, 2, |frame, channel| ((frame + channel) % 7) as f64)
+    }
+
+    // Walks hierarchies up to `max_depth` as the tool does, on a thread with room for them
+    fn on_sized_thread<T: Send + 'static, F: FnOnce() -> T + Send + 'static>(max_depth: usize, f: F) -> T {
+        thread::Builder::new().stack_size(stack_size(max_depth)).spawn(f).unwrap().join().unwrap()
+    }
+
+    // `bvh` as a .raw file
+    fn raw_chain(bvh: &::bvh::Bvh) -> Vec<u8> {
+        let mocap = build_mocap(bvh, &ConversionSettings::default().settings());
+        let mut data = Vec::new();
+        raw::write(&mocap, Limits::default(), &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn chains_of_thousands_of_joints() {
+        on_sized_thread(4096, || {
+            let deep = chain(3000);
+            check_bvh(&deep, 4096).unwrap();
+            let bvh = test_util::parse(&deep);
+            let data = raw_chain(&bvh);
+            assert_eq!(build_bvh(&raw::read(&data, 4096).unwrap()).motion.frames, bvh.motion.frames);
+        });
+    }
+
+    // Past the limit both fail cleanly, before anything recurses that deep
+    #[test]
+    fn past_the_limit() {
+        let data = on_sized_thread(4096, || raw_chain(&test_util::parse(&chain(3000))));
+        on_sized_thread(DEFAULT_MAX_DEPTH, move || {
+            assert!(matches!(check_bvh(&chain(3000), DEFAULT_MAX_DEPTH), Err(MocapError::TooDeep(DEFAULT_MAX_DEPTH))));
+            assert!(matches!(raw::read(&data, DEFAULT_MAX_DEPTH), Err(MocapError::TooDeep(DEFAULT_MAX_DEPTH))));
+            assert!(check_bvh(&chain(DEFAULT_MAX_DEPTH), DEFAULT_MAX_DEPTH).is_ok());
+            assert!(matches!(check_bvh(&chain(DEFAULT_MAX_DEPTH + 1), DEFAULT_MAX_DEPTH), Err(MocapError::TooDeep(_))));
+        });
+    }
+}
@@ -0,0 +1,65 @@
+use bvh;
+
+use error::MocapError;
+use selector;
+use channel_type;
+
+// Delta clips: an edited clip stored as its difference from a base clip with the same skeleton
+// and frame count, so a small edit compresses to mostly-constant channels. The difference is an
+// ordinary clip (and .raw file) whose metadata names the base; adding the base's frames back
+// reconstructs the edit.
+
+// Metadata key holding the base clip's file name
+pub const BASE_KEY: &str = "diff_base";
+
+// Subtracts `base`'s frames from `edited`'s.
+pub fn subtract(edited: &mut bvh::Bvh, base: &bvh::Bvh) -> Result<(), MocapError> {
+    check_compatible(edited, base)?;
+    for (frame, base_frame) in edited.motion.frames.iter_mut().zip(base.motion.frames.iter()) {
+        for (value, base_value) in frame.iter_mut().zip(base_frame.iter()) {
+            *value -= base_value;
+        }
+    }
+    Ok(())
+}
+
+// Adds `base`'s frames to a difference made by `subtract`.
+pub fn add(diff: &mut bvh::Bvh, base: &bvh::Bvh) -> Result<(), MocapError> {
+    check_compatible(diff, base)?;
+    for (frame, base_frame) in diff.motion.frames.iter_mut().zip(base.motion.frames.iter()) {
+        for (value, base_value) in frame.iter_mut().zip(base_frame.iter()) {
+            *value += base_value;
+        }
+    }
+    Ok(())
+}
+
+fn check_compatible(a: &bvh::Bvh, b: &bvh::Bvh) -> Result<(), MocapError> {
+    if let Some(mismatch) = skeleton_mismatch(&a.hierarchy.root, &b.hierarchy.root, "") {
+        return Err(MocapError::SkeletonMismatch(format!("the base clip's skeleton differs: {}", mismatch)));
+    }
+    if a.motion.frames.len() != b.motion.frames.len() {
+        return Err(MocapError::SkeletonMismatch(format!("the base clip has {} frames, not {}", b.motion.frames.len(), a.motion.frames.len())));
+    }
+    Ok(())
+}
+
+// Offsets may differ: the difference keeps the edited clip's.
+pub fn skeleton_mismatch(a: &bvh::Joint, b: &bvh::Joint, parent_path: &str) -> Option<String> {
+    let name = selector::escape(&a.name);
+    let path = if parent_path.is_empty() { name } else { format!("{}/{}", parent_path, name) };
+
+    if a.name != b.name {
+        return Some(format!("{} is {} in the base clip", path, b.name));
+    }
+    if a.channels.len() != b.channels.len() || a.channels.iter().zip(b.channels.iter()).any(|(a, b)| channel_type(a) != channel_type(b)) {
+        return Some(format!("{} has different channels", path));
+    }
+    match (&a.children, &b.children) {
+        (bvh::JointChildren::Joints(a), bvh::JointChildren::Joints(b)) if a.len() == b.len() => {
+            a.iter().zip(b.iter()).filter_map(|(a, b)| skeleton_mismatch(a, b, &path)).next()
+        }
+        (bvh::JointChildren::EndSite(_), bvh::JointChildren::EndSite(_)) => None,
+        _ => Some(format!("{} has different children", path)),
+    }
+}
@@ -0,0 +1,122 @@
+use conversion::ConversionSettingsBuilder;
+use options::Options;
+use {num_levels, RotationAnchor, TranslationReference};
+
+// Encoding settings carried by the input itself, for teams that annotate a clip's intended
+// compression in the BVH file. A line whose first non-blank character is `#` is a comment, and a
+// comment of the form
+//
+//   # mocap-<setting>: <value>
+//
+// is a directive setting that option for this file, as if it had been given on the command line:
+//
+//   # mocap-bits: <n>                                 --bits
+//   # mocap-translation-reference: <none|offset|mean> --translation-reference
+//   # mocap-rotation-anchor: <none|zero|rest>         --rotation-anchor
+//
+// Directives can go anywhere in the file, hierarchy or motion; with --hierarchy and --motion they
+// are read from the hierarchy file. A flag given on the command line overrides the file's
+// directive for that setting (and --rot-error/--trans-error override mocap-bits), so a batch can
+// still force a setting over every file. An unknown `mocap-` directive, an invalid value or the
+// same directive twice is an error, so typos don't go unnoticed; any other comment is ignored.
+// Comment lines are removed before the file is parsed.
+//
+// A mocap-bits below 8 is lossy like --bits, so --strict rejects it without --lossy.
+
+const PREFIX: &str = "mocap-";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Directives {
+    pub channel_quantization_bits: Option<u8>,
+    pub translation_reference: Option<TranslationReference>,
+    pub rotation_anchor: Option<RotationAnchor>,
+}
+
+impl Directives {
+    // Errors are reported as `"<line>: <message>"`.
+    pub fn parse(s: &str) -> Result<Directives, String> {
+        let mut ret = Directives::default();
+        for (index, line) in s.lines().enumerate() {
+            let directive = match comment(line).and_then(|comment| comment.trim().strip_prefix(PREFIX)) {
+                Some(directive) => directive,
+                None => continue,
+            };
+            let error = |message: String| format!("{}: {}", index + 1, message);
+            let (name, value) = directive.split_once(':').ok_or_else(|| error(format!("expected {}{}: <value>", PREFIX, directive.trim())))?;
+            let (name, value) = (name.trim(), value.trim());
+            let invalid = || error(format!("invalid value for {}{}: {}", PREFIX, name, value));
+            let given = match name {
+                "bits" => {
+                    let bits = value.parse::<u8>().ok().filter(|bits| num_levels(*bits).is_some()).ok_or_else(invalid)?;
+                    ret.channel_quantization_bits.replace(bits).is_some()
+                }
+                "translation-reference" => {
+                    let reference = match value {
+                        "none" => TranslationReference::None,
+                        "offset" => TranslationReference::Offset,
+                        "mean" => TranslationReference::Mean,
+                        _ => return Err(invalid()),
+                    };
+                    ret.translation_reference.replace(reference).is_some()
+                }
+                "rotation-anchor" => {
+                    let anchor = match value {
+                        "none" => RotationAnchor::None,
+                        "zero" => RotationAnchor::Zero,
+                        "rest" => RotationAnchor::Rest,
+                        _ => return Err(invalid()),
+                    };
+                    ret.rotation_anchor.replace(anchor).is_some()
+                }
+                _ => return Err(error(format!("unknown directive {}{}", PREFIX, name))),
+            };
+            if given {
+                return Err(error(format!("{}{} given twice", PREFIX, name)));
+            }
+        }
+        Ok(ret)
+    }
+
+    // Applies the directives no flag overrides to `conversion`, returning the ones applied as
+    // `<setting> <value>` for printing.
+    pub fn apply(&self, conversion: &mut ConversionSettingsBuilder, options: &Options) -> Vec<String> {
+        let mut ret = Vec::new();
+        if let Some(bits) = self.channel_quantization_bits {
+            if !options.given("--bits") && options.conversion.error_targets().is_empty() {
+                conversion.channel_quantization_bits(bits);
+                ret.push(format!("bits {}", bits));
+            }
+        }
+        if let Some(reference) = self.translation_reference {
+            if !options.given("--translation-reference") {
+                conversion.translation_reference(reference);
+                ret.push(format!("translation reference {:?}", reference).to_lowercase());
+            }
+        }
+        if let Some(anchor) = self.rotation_anchor {
+            if !options.given("--rotation-anchor") {
+                conversion.rotation_anchor(anchor);
+                ret.push(format!("rotation anchor {:?}", anchor).to_lowercase());
+            }
+        }
+        ret
+    }
+}
+
+// The text of `line` after the `#`, if it's a comment.
+fn comment(line: &str) -> Option<&str> {
+    line.trim_start().strip_prefix('#')
+}
+
+// `s` without its comment lines, for `bvh::parse`.
+pub fn strip_comments(s: String) -> String {
+    if !s.contains('#') {
+        return s;
+    }
+    let mut ret = String::with_capacity(s.len());
+    for line in s.lines().filter(|line| comment(line).is_none()) {
+        ret.push_str(line);
+        ret.push('\n');
+    }
+    ret
+}
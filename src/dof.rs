@@ -0,0 +1,77 @@
+use bvh;
+
+use {channel_type, count_bvh_channels};
+
+// Degrees of freedom per joint, for seeing how a rig's channels are distributed and spotting
+// over-parameterized joints, such as translation channels below the root. With --dof-summary a
+// conversion, batch or pack prints each clip's summary (of the skeleton it's loaded with, before
+// any channels are left out), as does `mocap stats --dof-summary` for compressed clips too: the
+// total channel count, how many joints have how many channels, and every joint's channels split
+// into translations and rotations, in hierarchy order.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JointDof {
+    pub name: String,
+    pub translations: usize,
+    pub rotations: usize,
+}
+
+impl JointDof {
+    pub fn channels(&self) -> usize {
+        self.translations + self.rotations
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub joints: Vec<JointDof>, // In hierarchy order
+    pub total: usize,
+}
+
+impl Summary {
+    pub fn new(root: &bvh::Joint) -> Summary {
+        let mut joints = Vec::new();
+        push_joints(root, &mut joints);
+        Summary {
+            joints: joints,
+            total: count_bvh_channels(root),
+        }
+    }
+
+    // The number of joints with each channel count, fewest channels first.
+    pub fn distribution(&self) -> Vec<(usize, usize)> {
+        let mut ret: Vec<(usize, usize)> = Vec::new();
+        for joint in self.joints.iter() {
+            match ret.iter_mut().find(|entry| entry.0 == joint.channels()) {
+                Some(entry) => entry.1 += 1,
+                None => ret.push((joint.channels(), 1)),
+            }
+        }
+        ret.sort();
+        ret
+    }
+
+    // The summary for printing: the totals, then a line per joint.
+    pub fn describe(&self) -> Vec<String> {
+        let distribution = self.distribution().iter()
+            .map(|(channels, joints)| format!("{} channels: {} joint{}", channels, joints, if *joints == 1 { "" } else { "s" }))
+            .collect::<Vec<_>>();
+        let mut ret = vec![format!("{} channels over {} joints ({})", self.total, self.joints.len(), distribution.join(", "))];
+        ret.extend(self.joints.iter().map(|joint| format!("{}: {} ({} translation, {} rotation)", joint.name, joint.channels(), joint.translations, joint.rotations)));
+        ret
+    }
+}
+
+fn push_joints(joint: &bvh::Joint, joints: &mut Vec<JointDof>) {
+    let translations = joint.channels.iter().filter(|channel| channel_type(channel).is_translation()).count();
+    joints.push(JointDof {
+        name: joint.name.clone(),
+        translations: translations,
+        rotations: joint.channels.len() - translations,
+    });
+    if let bvh::JointChildren::Joints(ref children) = joint.children {
+        for child in children.iter() {
+            push_joints(child, joints);
+        }
+    }
+}
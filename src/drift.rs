@@ -0,0 +1,195 @@
+use container;
+use error::MocapError;
+use raw::{self, Reader};
+use Mocap;
+
+// Delta chains and how far a corrupt delta can carry. A channel stored in the delta blocks is a
+// chain: every level is the one before plus a delta, so a single corrupt delta throws off every
+// frame after it, up to the next absolute level. A clip's chains start from their initial levels,
+// and a .raw file's seek index (see seek.rs) stores every chain's level at the start of every
+// block, which decoding starts the block from (see view.rs), so with one a corrupt delta only
+// affects the rest of its block. Channels stored otherwise (periodic, sparse, constant or lossless,
+// see raw.rs) store their levels or values outright and aren't chains. Containers have no seek
+// index, so each clip's chains run its whole length.
+//
+// `mocap stats --delta-runs` prints every chain's longest run of frames between absolute levels and
+// what a single corrupt delta could do in it: a delta can take the level anywhere on the grid, so
+// every frame to the end of the run could be off by up to the channel's whole value range (ignoring
+// any clamp bounds), which times the run is the cumulative figure. With --max-delta-run n a
+// conversion guarantees that no run is longer than n frames, writing the .raw file in blocks of at
+// most n frames (--block-frames, if smaller) with a seek index, and `mocap verify --max-delta-run n`
+// checks that a file meets it.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chain {
+    pub channel: usize, // Flat index
+    pub longest_run: u32, // Frames between absolute levels
+    pub max_error: f64, // Per frame, in the channel's units
+}
+
+impl Chain {
+    // The most a single corrupt delta could add up to over the frames it affects.
+    pub fn cumulative_error(&self) -> f64 {
+        self.max_error * self.longest_run as f64
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClipChains {
+    pub name: String, // Empty for a .raw file
+    pub anchored: bool, // Whether a seek index anchors every block
+    pub chains: Vec<Chain>, // In flat channel order
+}
+
+impl ClipChains {
+    pub fn longest_run(&self) -> u32 {
+        self.chains.iter().map(|chain| chain.longest_run).max().unwrap_or(0)
+    }
+}
+
+// The chains of `mocap`, from the runs of deltas in its blocks (see `raw::read_clip_runs`).
+pub fn chains(mocap: &Mocap, runs: &[raw::Run], anchored: bool) -> Vec<Chain> {
+    let channels = mocap.channels();
+    let mut longest_runs = vec![None; channels.len()];
+    for run in runs.iter() {
+        let longest_run = longest_runs[run.channel].get_or_insert(0);
+        *longest_run = (*longest_run).max(if anchored { run.num_frames } else { mocap.num_frames });
+    }
+    longest_runs.into_iter().enumerate().filter_map(|(channel, longest_run)| longest_run.map(|longest_run| Chain {
+        channel: channel,
+        longest_run: longest_run,
+        max_error: channels[channel].value_range as f64,
+    })).collect()
+}
+
+// The chains of every clip in a container or .raw file, in clip order. An alias has none of its
+// own.
+pub fn read(data: &[u8], max_depth: usize) -> Result<Vec<ClipChains>, MocapError> {
+    if data.starts_with(raw::MAGIC) {
+        let mut reader = Reader::with_max_depth(data, max_depth);
+        raw::read_magic(&mut reader)?;
+        let (mocap, runs) = raw::read_clip_runs(&mut reader)?;
+        // Only a seek index can follow the blocks
+        let anchored = reader.remaining() > 0;
+        return Ok(vec![ClipChains {
+            name: String::new(),
+            anchored: anchored,
+            chains: chains(&mocap, &runs, anchored),
+        }]);
+    }
+    let (container, runs) = container::read_runs(data, max_depth)?;
+    Ok(container.clips.iter().zip(runs.iter()).map(|(clip, runs)| ClipChains {
+        name: clip.name.clone(),
+        anchored: false,
+        chains: chains(&clip.mocap, runs, false),
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use depth::DEFAULT_MAX_DEPTH;
+
+    use std::fs;
+    use std::path::Path;
+
+    use bitpack;
+    use build_mocap;
+    use container::{Clip, Container};
+    use conversion::ConversionSettings;
+    use options::Options;
+    use periodic::Limits;
+    use test_util;
+    use verify;
+    use view::MocapView;
+
+    const NUM_FRAMES: usize = 61;
+
+    fn mocap() -> Mocap {
+        build_mocap(&test_util::sine_clip(NUM_FRAMES), &ConversionSettings::default().settings())
+    }
+
+    #[test]
+    fn chains_run_to_the_next_absolute_level() {
+        let mocap = mocap();
+        let mut data = Vec::new();
+        raw::write(&mocap, Limits::default(), &mut data).unwrap();
+        let clips = read(&data, DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(clips.len(), 1);
+        assert!(!clips[0].anchored);
+        assert_eq!(clips[0].chains.iter().map(|chain| chain.channel).collect::<Vec<_>>(), (0..test_util::NUM_CHANNELS).collect::<Vec<_>>());
+        assert_eq!(clips[0].longest_run(), NUM_FRAMES as u32);
+        for chain in clips[0].chains.iter() {
+            assert_eq!(chain.max_error, mocap.channels()[chain.channel].value_range as f64);
+            assert_eq!(chain.cumulative_error(), chain.max_error * NUM_FRAMES as f64);
+        }
+
+        // The last block is the shortest
+        let mut data = Vec::new();
+        raw::write_indexed(&mocap, 10, bitpack::Layout::Packed, Limits::default(), &mut data).unwrap();
+        let clips = read(&data, DEFAULT_MAX_DEPTH).unwrap();
+        assert!(clips[0].anchored);
+        assert_eq!(clips[0].longest_run(), 10);
+
+        // Containers have no seek index
+        let clip = Clip { name: "walk".into(), reference_pose: None, attributes: Vec::new(), thumbnail: None, alias: None, mocap: mocap };
+        let mut data = Vec::new();
+        container::write(&Container { reference_poses: Vec::new(), clips: vec![clip] }, Limits::default(), &mut data).unwrap();
+        let clips = read(&data, DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!((clips[0].name.as_str(), clips[0].anchored, clips[0].longest_run()), ("walk", false, NUM_FRAMES as u32));
+    }
+
+    // Converts a clip with `args`, returning the .raw file.
+    fn converted(name: &str, args: &[&str]) -> Vec<u8> {
+        let dir = test_util::temp_dir(name);
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        fs::write(path("in.bvh"), test_util::clip_text(NUM_FRAMES, test_util::sine)).unwrap();
+        let files = [path("in.bvh"), path("out.bvh"), path("out.csv"), path("out.raw")];
+        let options = Options::parse(args.iter().map(|arg| arg.to_string()).chain(files.iter().cloned())).unwrap();
+        ::convert(Path::new(&files[0]), Path::new(&files[1]), Path::new(&files[2]), Path::new(&files[3]), &options, None).unwrap();
+        fs::read(&files[3]).unwrap()
+    }
+
+    // The frames whose `channel` decodes differently once its delta at `frame` is corrupt.
+    fn affected_frames(data: &[u8], channel: usize, frame: u32) -> Vec<usize> {
+        let mut reader = Reader::new(data);
+        raw::read_magic(&mut reader).unwrap();
+        let (_, runs) = raw::read_clip_runs(&mut reader).unwrap();
+        let run = runs.iter().find(|run| run.channel == channel && run.start_frame <= frame && frame < run.start_frame + run.num_frames).unwrap();
+        // 8 bits, so a byte per delta
+        let mut corrupt = data.to_vec();
+        corrupt[run.offset + (frame - run.start_frame) as usize] ^= 0x40;
+
+        let clean = MocapView::parse(data, DEFAULT_MAX_DEPTH).unwrap().to_bvh(1);
+        let decoded = MocapView::parse(&corrupt, DEFAULT_MAX_DEPTH).unwrap().to_bvh(1);
+        (0..NUM_FRAMES).filter(|&index| {
+            for other in 0..test_util::NUM_CHANNELS {
+                if other != channel {
+                    assert_eq!(decoded.motion.frames[index][other], clean.motion.frames[index][other]);
+                }
+            }
+            decoded.motion.frames[index][channel] != clean.motion.frames[index][channel]
+        }).collect()
+    }
+
+    #[test]
+    fn max_delta_run_contains_a_corrupt_delta() {
+        let unanchored = converted("drift-unanchored", &[]);
+        assert_eq!(affected_frames(&unanchored, 7, 12), (12..NUM_FRAMES).collect::<Vec<_>>());
+        assert_eq!(verify::verify(&unanchored, "", Some(10), DEFAULT_MAX_DEPTH).len(), 1);
+
+        let anchored = converted("drift-anchored", &["--max-delta-run", "10"]);
+        assert_eq!(read(&anchored, DEFAULT_MAX_DEPTH).unwrap()[0].longest_run(), 10);
+        assert!(verify::verify(&anchored, "", Some(10), DEFAULT_MAX_DEPTH).is_empty());
+        let affected = affected_frames(&anchored, 7, 12);
+        assert_eq!(affected, (12..20).collect::<Vec<_>>());
+        assert!(affected.len() <= 10);
+
+        // --block-frames can only make the blocks shorter
+        let anchored = converted("drift-block-frames", &["--max-delta-run", "10", "--block-frames", "4"]);
+        assert_eq!(read(&anchored, DEFAULT_MAX_DEPTH).unwrap()[0].longest_run(), 4);
+        assert_eq!(affected_frames(&anchored, 7, 12), (12..16).collect::<Vec<_>>());
+        let anchored = converted("drift-long-blocks", &["--max-delta-run", "10", "--block-frames", "30"]);
+        assert_eq!(read(&anchored, DEFAULT_MAX_DEPTH).unwrap()[0].longest_run(), 10);
+    }
+}
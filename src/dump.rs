@@ -0,0 +1,281 @@
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use bitpack;
+use container;
+use error::MocapError;
+use options::Options;
+use periodic;
+use raw;
+use selector;
+use view::MocapView;
+use {decode_channel, max_level, read_clips, Channel, Joint, JointChildren, Mocap};
+
+// `mocap dump`: a .raw file or container as text, for reviewing changes to binary assets. The
+// output is deterministic (everything in file order, floats printed as the shortest string that
+// reads back to the same value), so diffing the dumps of two versions of a file shows what
+// changed:
+//
+//   file: raw version 14, seek index of 4 blocks
+//   clip walk
+//     frames: 120
+//     frame time: 0.033333
+//     bits: 8
+//     reference pose: none
+//     attribute loop = true
+//     metadata source_frames = 240
+//     marker 12: footstep
+//     joint Hips offset 0 0 0
+//       channel TranslationX: range [-1.5, 2.25], reference 0, initial level 0, clamp none, anchor none, encoding deltas, 120 bytes
+//       ...
+//       joint Hips/Spine offset 0 5.2 0
+//         ...
+//         end site 0 4 0
+//     values (first and last 5 frames)
+//       Hips TranslationX: 0 0.01 0.03 0.05 0.07 ... 1.9 2 2.1 2.2 2.25
+//
+// A channel's size is the bytes its data takes as `raw::write` stores it: its deltas packed at the
+// clip's bits, its periodic levels and corrections, its level if constant or its exact values if
+// lossless, leaving out the per-channel header fields every channel has. With --values <n> each
+// channel's first and last n decoded values are listed, every value if the clip has at most 2n
+// frames; by default the dump doesn't list any, as a clip's deltas run to megabytes.
+//
+// --full adds everything else for forensic use: the reference poses' values, every channel's
+// stored data (deltas, periodic levels and corrections, or lossless values), every decoded value
+// and the seek index entries, all VALUES_PER_LINE to a line prefixed with the first one's index.
+
+const VALUES_PER_LINE: usize = 16;
+
+pub fn run(input_file_name: &Path, options: &Options) -> Result<(), MocapError> {
+    let data = fs::read(input_file_name)?;
+    let stdout = io::stdout();
+    let mut w = BufWriter::new(stdout.lock());
+    write(input_file_name, &data, options, &mut w)?;
+    w.flush()?;
+    Ok(())
+}
+
+// The dump of `data`, read from `input_file_name`.
+pub fn write<W: Write>(input_file_name: &Path, data: &[u8], options: &Options, w: &mut W) -> Result<(), MocapError> {
+    let container = read_clips(input_file_name, data, options.max_depth)?;
+
+    let seek_table = if data.starts_with(raw::MAGIC) {
+        let view = MocapView::parse(data, options.max_depth)?;
+        let seek_table = view.seek_table().map(|entries| entries.to_vec());
+        writeln!(w, "file: raw version {}{}{}{}",
+            raw::FORMAT_VERSION,
+            if raw::is_sparse(data) { ", sparse track" } else { "" },
+            if raw::delta_layout(data) == bitpack::Layout::BitPlanes { ", bit planes" } else { "" },
+            seek_table.as_ref().map_or(String::new(), |entries| format!(", seek index of {} blocks", entries.len())))?;
+        seek_table
+    } else {
+        writeln!(w, "file: container version {}, {} reference poses", container::FORMAT_VERSION, container.reference_poses.len())?;
+        if options.dump_full {
+            for (index, pose) in container.reference_poses.iter().enumerate() {
+                writeln!(w, "reference pose {}", index)?;
+                write_values(w, "  ", pose)?;
+            }
+        }
+        None
+    };
+
+    for clip in container.clips.iter() {
+        let mocap = &clip.mocap;
+        writeln!(w, "clip {}", clip.name)?;
+        writeln!(w, "  frames: {}", mocap.num_frames)?;
+        writeln!(w, "  frame time: {}", mocap.frame_time)?;
+        writeln!(w, "  bits: {}", mocap.channel_quantization_bits)?;
+        writeln!(w, "  reference pose: {}", clip.reference_pose.map_or("none".into(), |index| index.to_string()))?;
+        if let Some(index) = clip.alias {
+            writeln!(w, "  alias of: {}", container.clips[index].name)?;
+        }
+        for (key, value) in clip.attributes.iter() {
+            writeln!(w, "  attribute {} = {}", key, value)?;
+        }
+        if let Some(ref thumbnail) = clip.thumbnail {
+            writeln!(w, "  thumbnail: frame {}", thumbnail.frame)?;
+            if options.dump_full {
+                write_values(w, "    ", &thumbnail.pose)?;
+            }
+        }
+        for (key, value) in mocap.metadata.iter() {
+            writeln!(w, "  metadata {} = {}", key, value)?;
+        }
+        for (frame, name) in mocap.markers.iter() {
+            writeln!(w, "  marker {}: {}", frame, name)?;
+        }
+        if !mocap.timestamps.is_empty() {
+            writeln!(w, "  timestamps: {} to {}", mocap.timestamps[0], mocap.timestamps[mocap.timestamps.len() - 1])?;
+            if options.dump_full {
+                write_values(w, "    ", &mocap.timestamps)?;
+            }
+        }
+        write_joint(w, mocap, &mocap.root, "", "  ", options.dump_full)?;
+
+        if options.dump_full || options.dump_values.is_some() {
+            match options.dump_values {
+                Some(count) if !options.dump_full && 2 * count < mocap.num_frames as usize => writeln!(w, "  values (first and last {} frames)", count)?,
+                _ => writeln!(w, "  values")?,
+            }
+            for (descriptor, channel) in mocap.channel_map().into_iter().zip(mocap.channels()) {
+                let mut values = Vec::with_capacity(mocap.num_frames as usize);
+                decode_channel(channel, mocap.channel_quantization_bits, |_, value| values.push(value));
+                let name = format!("{} {}", descriptor.joint_name, descriptor.channel_type.name());
+                match options.dump_values {
+                    _ if options.dump_full => {
+                        writeln!(w, "    {}:", name)?;
+                        write_values(w, "      ", &values)?;
+                    }
+                    Some(count) if 2 * count < values.len() => writeln!(w, "    {}: {} ... {}", name, join(&values[..count]), join(&values[values.len() - count..]))?,
+                    _ => writeln!(w, "    {}: {}", name, join(&values))?,
+                }
+            }
+        }
+    }
+
+    if let (true, Some(entries)) = (options.dump_full, seek_table) {
+        writeln!(w, "seek index")?;
+        for (index, entry) in entries.iter().enumerate() {
+            writeln!(w, "  entry {}: frame {}, offset {}, {} bytes, levels {}", index, entry.start_frame, entry.offset, entry.len, join(&entry.levels))?;
+        }
+    }
+    Ok(())
+}
+
+fn write_joint<W: Write>(w: &mut W, mocap: &Mocap, joint: &Joint, parent_path: &str, indent: &str, full: bool) -> io::Result<()> {
+    let name = selector::escape(&joint.name);
+    let path = if parent_path.is_empty() { name } else { format!("{}/{}", parent_path, name) };
+    writeln!(w, "{}joint {}{} offset {} {} {}", indent, path, joint.original_name.as_ref().map_or(String::new(), |name| format!(" (originally {})", name)), joint.offset.0, joint.offset.1, joint.offset.2)?;
+    let bits = mocap.channel_quantization_bits;
+    for channel in joint.channels.iter() {
+        let (encoding, size) = storage(channel, mocap.num_frames, bits);
+        writeln!(w, "{}  channel {}: range [{}, {}]{}, reference {}, initial level {}, clamp {}, anchor {}, encoding {}, {} byte{}",
+            indent,
+            channel.type_.name(),
+            channel.value_of(0, bits), channel.value_of(max_level(channel.bits(bits)), bits),
+            channel.bits.map_or(String::new(), |bits| format!(", {} bits", bits)),
+            channel.reference,
+            channel.initial_level,
+            channel.clamp.map_or("none".into(), |(min, max)| format!("[{}, {}]", min, max)),
+            channel.anchor_level.map_or("none".into(), |level| level.to_string()),
+            encoding,
+            size,
+            if size == 1 { "" } else { "s" })?;
+        if full {
+            let data_indent = format!("{}    ", indent);
+            match (&channel.values, periodic::encode(channel, bits)) {
+                (Some(values), _) => {
+                    writeln!(w, "{}values:", data_indent)?;
+                    write_values(w, &data_indent, values)?;
+                }
+                (None, Some(periodic)) => {
+                    writeln!(w, "{}period of {} levels:", data_indent, periodic.levels.len())?;
+                    write_values(w, &data_indent, &periodic.levels)?;
+                    for (frame, level) in periodic.corrections.iter() {
+                        writeln!(w, "{}correction {}: {}", data_indent, frame, level)?;
+                    }
+                }
+                (None, None) => {
+                    writeln!(w, "{}deltas:", data_indent)?;
+                    write_values(w, &data_indent, &channel.deltas)?;
+                }
+            }
+        }
+    }
+    match joint.children {
+        JointChildren::Joints(ref joints) => {
+            for child in joints.iter() {
+                write_joint(w, mocap, child, &path, &format!("{}  ", indent), full)?;
+            }
+        }
+        JointChildren::EndSite(ref offset) => writeln!(w, "{}  end site {} {} {}", indent, offset.0, offset.1, offset.2)?,
+    }
+    Ok(())
+}
+
+// How `raw::write` stores the channel, and the bytes its data takes, `bits` being the clip's.
+pub fn storage(channel: &Channel, num_frames: u32, bits: u8) -> (&'static str, usize) {
+    if let Some(ref values) = channel.values {
+        return if !values.is_empty() && values.iter().all(|value| value.to_bits() == values[0].to_bits()) {
+            ("constant lossless", 8)
+        } else {
+            ("lossless", 8 * values.len())
+        };
+    }
+    if num_frames == 0 {
+        return ("deltas", 0);
+    }
+    match periodic::encode(channel, bits) {
+        Some(ref periodic) if periodic.levels.len() == 1 && periodic.corrections.is_empty() => ("constant", periodic.encoded_size()),
+        Some(ref periodic) => ("periodic", periodic.encoded_size()),
+        None => ("deltas", bitpack::packed_len(channel.deltas.len(), channel.bits(bits))),
+    }
+}
+
+fn join<T: Display>(values: &[T]) -> String {
+    values.iter().map(|value| value.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+fn write_values<W: Write, T: Display>(w: &mut W, indent: &str, values: &[T]) -> io::Result<()> {
+    for (index, line) in values.chunks(VALUES_PER_LINE).enumerate() {
+        writeln!(w, "{}{}: {}", indent, index * VALUES_PER_LINE, join(line))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_util;
+
+    // tests/fixtures/clips.mcp is tests/fixtures/pivots.bvh and bom_crlf.bvh packed with
+    // `mocap pack --reference-pose --marker 2:step --fps 60 --clip-attr pivots:loop=true,speed=1.5
+    // --thumbnail average`, and clips.dump its dump (`mocap dump clips.mcp > clips.dump`). A change
+    // to either format has to regenerate both, and the dump's diff should show just that change.
+    fn dump(args: &[&str]) -> String {
+        let file_name = test_util::fixture("clips.mcp");
+        let options = Options::parse(["dump"].iter().cloned().chain(args.iter().cloned()).chain(Some(file_name.to_str().unwrap())).map(|arg| arg.to_string())).unwrap();
+        let mut ret = Vec::new();
+        write(&file_name, &fs::read(&file_name).unwrap(), &options, &mut ret).unwrap();
+        String::from_utf8(ret).unwrap()
+    }
+
+    #[test]
+    fn dumps_the_fixture_container_as_its_golden_dump() {
+        let golden = fs::read_to_string(test_util::fixture("clips.dump")).unwrap();
+        let dump = dump(&[]);
+        for (index, (line, expected)) in dump.lines().zip(golden.lines()).enumerate() {
+            assert_eq!(line, expected, "line {}", index + 1);
+        }
+        assert_eq!(dump, golden);
+    }
+
+    #[test]
+    fn lists_the_first_and_last_values_asked_for() {
+        // After each clip's joints, leaving out the middle of a clip with more than twice as many
+        let golden = fs::read_to_string(test_util::fixture("clips.dump")).unwrap();
+        let values = dump(&["--values", "2"]);
+        assert!(values.starts_with(&golden[..golden.find("clip bom_crlf").unwrap()]));
+        assert!(values.contains("  values (first and last 2 frames)\n    Hips TranslationX: 1 1.2500025033950806 ... 2.0000100135803223 2.250012516975403\n"), "{}", values);
+        // bom_crlf has only 4 frames
+        assert!(values.contains("  values\n    Hips TranslationX: 0 0.5000050067901611 1.0000100135803223 1.5000150203704834\n"), "{}", values);
+        let values = dump(&["--values", "3"]);
+        assert!(values.contains("  values\n    Hips TranslationX: 1 1.2500025033950806 1.5000050067901611 1.7500075101852417 2.0000100135803223 2.250012516975403\n"), "{}", values);
+    }
+
+    #[test]
+    fn a_full_dump_adds_the_stored_data() {
+        let full = dump(&["--full"]);
+        assert_eq!(dump(&["--full"]), full);
+        assert!(full.starts_with("file: container version 5, 2 reference poses\nreference pose 0\n  0: 1 2 3 0 0 0 0 0 0 0 0 0 0 0 0\nreference pose 1\n  0: 0 90 0 0 0 0 0 0 0\n"), "{}", full);
+        assert!(full.contains("    channel TranslationX: range [1, 2.250012516975403], reference 0, initial level 0, clamp none, anchor none, encoding deltas, 6 bytes\n      deltas:\n      0: 0 51 51 51 51 51\n"), "{}", full);
+        // Every line of the default dump is in it, in the same order
+        let mut lines = full.lines();
+        for line in dump(&[]).lines() {
+            assert!(lines.any(|full_line| full_line == line), "{} is missing", line);
+        }
+    }
+}
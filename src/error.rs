@@ -0,0 +1,68 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum MocapError {
+    Io(io::Error),
+    Parse(String),
+    Usage(String),
+    InvalidRaw(String),
+    InvalidProfile(String),
+    InvalidMarkers(String),
+    InvalidCurves(String),
+    InvalidTimestamps(String),
+    InvalidPatch(String),
+    InvalidRanges(String),
+    InvalidMocap(Vec<String>),
+    SkeletonMismatch(String),
+    JointNotFound(String),
+    InvalidSelector(String),
+    AmbiguousSelector(String, Vec<String>),
+    DuplicateJointNames(Vec<String>),
+    BatchFailed(usize, usize),
+    Regressed(usize),
+    SelfCheck(String),
+    Overflow(String),
+    TooDeep(usize),
+    Cancelled,
+    Internal(String),
+}
+
+impl fmt::Display for MocapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MocapError::Io(ref e) => write!(f, "I/O error: {}", e),
+            MocapError::Parse(ref message) => write!(f, "couldn't parse BVH: {}", message),
+            MocapError::Usage(ref message) => write!(f, "{}", message),
+            MocapError::InvalidRaw(ref message) => write!(f, "invalid raw file: {}", message),
+            MocapError::InvalidProfile(ref message) => write!(f, "invalid profile: {}", message),
+            MocapError::InvalidMarkers(ref message) => write!(f, "invalid marker file: {}", message),
+            MocapError::InvalidCurves(ref message) => write!(f, "invalid curve file: {}", message),
+            MocapError::InvalidTimestamps(ref message) => write!(f, "invalid timestamps file: {}", message),
+            MocapError::InvalidPatch(ref message) => write!(f, "invalid patch: {}", message),
+            MocapError::InvalidRanges(ref message) => write!(f, "invalid ranges file: {}", message),
+            MocapError::SkeletonMismatch(ref message) => write!(f, "{}", message),
+            MocapError::InvalidMocap(ref violations) => write!(f, "invalid mocap data:\n    {}", violations.join("\n    ")),
+            MocapError::JointNotFound(ref name) => write!(f, "no joint matches \"{}\"", name),
+            MocapError::InvalidSelector(ref message) => write!(f, "invalid joint selector {}", message),
+            MocapError::AmbiguousSelector(ref selector, ref paths) => write!(f, "\"{}\" matches several joints: {}", selector, paths.join(", ")),
+            MocapError::DuplicateJointNames(ref paths) => write!(f, "duplicate joint names: {}", paths.join(", ")),
+            MocapError::BatchFailed(failed, total) => write!(f, "{} of {} files failed", failed, total),
+            MocapError::Regressed(count) => write!(f, "{} regression{} against the previous report", count, if count == 1 { "" } else { "s" }),
+            MocapError::SelfCheck(ref message) => write!(f, "self-check failed: {}", message),
+            MocapError::Overflow(ref message) => write!(f, "arithmetic overflow: {}", message),
+            MocapError::TooDeep(max_depth) => write!(f, "the hierarchy is more than {} joints deep; raise --max-depth if that's intended", max_depth),
+            MocapError::Cancelled => write!(f, "cancelled"),
+            MocapError::Internal(ref message) => write!(f, "internal error: {}", message),
+        }
+    }
+}
+
+impl Error for MocapError {}
+
+impl From<io::Error> for MocapError {
+    fn from(e: io::Error) -> MocapError {
+        MocapError::Io(e)
+    }
+}
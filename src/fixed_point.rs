@@ -0,0 +1,246 @@
+use std::io::{self, Write};
+
+use channel_map;
+use error::MocapError;
+use {max_level, Mocap};
+
+// Fixed-point dequantization, for targets without floating point (such as a Cortex-M). With
+// --export-fixed-point a conversion writes a C header giving, per channel in flat channel order
+// (see --export-channel-map), integer constants that turn a quantization level into the channel's
+// value as a fixed-point number:
+//
+//   value * 2^shift = bias + level * scale
+//
+// so the value is in Qm.n format with n = shift fractional bits, in a word of 16 or 32 bits. The
+// header's `mocap_fixed_decode` is the reference decode, the same integer arithmetic as `decode`
+// here, and never overflows the word: bias, level * scale and their sum all fit. As in the texture
+// export, translation references and rotation anchors are folded into the bias, and rotations are
+// in degrees.
+//
+// Each channel gets the largest shift that fits its values in the word, which is the finest
+// precision the word allows. The worst-case representation error, against the float decode, is
+// computed over every level and written beside the channel; the conversion prints the largest.
+// With --fixed-point-precision each channel takes a 16-bit word if that's within the precision
+// and a 32-bit one otherwise, and the export fails, listing them, if any channel can't meet it in
+// 32 bits; without it every channel takes 32 bits. The header also carries the channel map (see
+// channel_map.rs), so the table's rows can be matched to joints without walking the hierarchy.
+//
+// Lossless channels have no levels, so a clip with any can't be exported. Clamp bounds from a
+// profile aren't applied.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FixedPoint {
+    pub word_bits: u8, // 16 or 32
+    pub shift: u8,
+    pub bias: i32,
+    pub scale: i32,
+    pub max_error: f64, // Against the float decode, over every level
+}
+
+impl FixedPoint {
+    // The value at `level`, times 2^shift.
+    pub fn decode(&self, level: u8) -> i32 {
+        self.bias + level as i32 * self.scale
+    }
+
+    // The best format for the levels decoding to `values` in a `word_bits` word; None if even a
+    // shift of 0 doesn't fit them.
+    fn fit(values: &[f64], word_bits: u8) -> Option<FixedPoint> {
+        let limit = ((1i64 << (word_bits - 1)) - 1) as f64;
+        let step = values.get(1).map_or(0.0, |value| value - values[0]);
+        (0..word_bits).rev().find_map(|shift| {
+            let factor = (1u64 << shift) as f64;
+            let (bias, scale) = ((values[0] * factor).round(), (step * factor).round());
+            let fits = |value: f64| value.abs() <= limit;
+            let top = (values.len() - 1) as f64;
+            if !fits(bias) || !fits(top * scale) || !fits(bias + top * scale) {
+                return None;
+            }
+            let mut ret = FixedPoint {
+                word_bits: word_bits,
+                shift: shift,
+                bias: bias as i32,
+                scale: scale as i32,
+                max_error: 0.0,
+            };
+            ret.max_error = values.iter().enumerate().map(|(level, value)| (ret.decode(level as u8) as f64 / factor - value).abs()).fold(0.0, f64::max);
+            Some(ret)
+        })
+    }
+}
+
+// Every channel's format, in flat channel order, within `precision` if given.
+pub fn formats(mocap: &Mocap, precision: Option<f64>) -> Result<Vec<FixedPoint>, MocapError> {
+    let bits = mocap.channel_quantization_bits;
+    let mut ret = Vec::new();
+    let mut failures = Vec::new();
+    for (descriptor, channel) in mocap.channel_map().into_iter().zip(mocap.channels()) {
+        let name = format!("{} {}", descriptor.joint_name, descriptor.channel_type.name());
+        if channel.values.is_some() {
+            return Err(MocapError::Usage(format!("--export-fixed-point: {} is lossless and has no levels", name)));
+        }
+        let values = (0..=max_level(channel.bits(bits))).map(|level| channel.value_of(level, bits)).collect::<Vec<_>>();
+        let words: &[u8] = if precision.is_some() { &[16, 32] } else { &[32] };
+        let candidates = words.iter().filter_map(|word_bits| FixedPoint::fit(&values, *word_bits)).collect::<Vec<_>>();
+        match candidates.iter().find(|format| precision.is_none_or(|precision| format.max_error <= precision)) {
+            Some(format) => ret.push(*format),
+            None => failures.push(match candidates.last() {
+                Some(format) => format!("{} (at best {} in 32 bits)", name, format.max_error),
+                None => format!("{} (its values don't fit 32 bits)", name),
+            }),
+        }
+    }
+    if !failures.is_empty() {
+        return Err(MocapError::Usage(format!("--export-fixed-point: these channels can't be represented within {}: {}", precision.unwrap_or(0.0), failures.join(", "))));
+    }
+    Ok(ret)
+}
+
+pub fn write_header<W: Write>(mocap: &Mocap, formats: &[FixedPoint], w: &mut W) -> io::Result<()> {
+    writeln!(w, "// Fixed-point dequantization constants, written by mocap --export-fixed-point.")?;
+    writeln!(w, "// value * 2^shift = bias + level * scale, in a word of word_bits bits.")?;
+    writeln!(w, "#include <stdint.h>")?;
+    writeln!(w)?;
+    writeln!(w, "#define MOCAP_NUM_CHANNELS {}", formats.len())?;
+    writeln!(w, "#define MOCAP_BITS {}", mocap.channel_quantization_bits)?;
+    writeln!(w)?;
+    writeln!(w, "typedef struct {{")?;
+    writeln!(w, "    int32_t bias;")?;
+    writeln!(w, "    int32_t scale;")?;
+    writeln!(w, "    uint8_t shift;")?;
+    writeln!(w, "    uint8_t word_bits;")?;
+    writeln!(w, "}} mocap_fixed_channel;")?;
+    writeln!(w)?;
+    writeln!(w, "static const mocap_fixed_channel mocap_fixed_channels[{}] = {{", formats.len().max(1))?;
+    for (descriptor, format) in mocap.channel_map().into_iter().zip(formats.iter()) {
+        let name = descriptor.joint_name.chars().map(|c| if c.is_control() { '?' } else { c }).collect::<String>();
+        writeln!(w, "    {{ {}, {}, {}, {} }}, // {}: {} {}, Q{}.{}, max error {}",
+            format.bias, format.scale, format.shift, format.word_bits,
+            descriptor.flat_index, name, descriptor.channel_type.name(),
+            format.word_bits - 1 - format.shift, format.shift, format.max_error)?;
+    }
+    if formats.is_empty() {
+        writeln!(w, "    {{ 0, 0, 0, 32 }},")?;
+    }
+    writeln!(w, "}};")?;
+    writeln!(w)?;
+    writeln!(w, "// Which joint and channel type each flat channel index is, as --export-channel-map writes")?;
+    channel_map::write_c(&mocap.channel_map(), w)?;
+    writeln!(w)?;
+    writeln!(w, "// The channel's value at `level`, times 2^shift")?;
+    writeln!(w, "static inline int32_t mocap_fixed_decode(const mocap_fixed_channel *channel, uint8_t level) {{")?;
+    writeln!(w, "    return channel->bias + (int32_t)level * channel->scale;")?;
+    writeln!(w, "}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use depth::DEFAULT_MAX_DEPTH;
+    use error::MocapError;
+    use test_util;
+    use {convert, raw};
+
+    // The fixture converted with `args`
+    fn fixture_mocap(args: &[&str]) -> Mocap {
+        let dir = test_util::temp_dir("fixed-point");
+        let path = |file_name: &str| dir.join(file_name);
+        convert(&test_util::fixture("pivots.bvh"), &path("out.bvh"), &path("out.csv"), &path("out.raw"), &test_util::options(args), None).unwrap();
+        raw::read(&fs::read(path("out.raw")).unwrap(), DEFAULT_MAX_DEPTH).unwrap()
+    }
+
+    // Every level of every channel decodes within the channel's reported error of the float decode,
+    // and that error is no worse than rounding to the format's precision
+    fn assert_within_reported_error(mocap: &Mocap, formats: &[FixedPoint]) {
+        let bits = mocap.channel_quantization_bits;
+        assert_eq!(formats.len(), mocap.channels().len());
+        for (index, (channel, format)) in mocap.channels().into_iter().zip(formats.iter()).enumerate() {
+            let factor = (1u64 << format.shift) as f64;
+            let levels = max_level(channel.bits(bits)) as u32 + 1;
+            assert!(format.max_error <= 0.5 / factor * levels as f64, "channel {}: {:?}", index, format);
+            for level in 0..levels {
+                let level = level as u8;
+                let error = (format.decode(level) as f64 / factor - channel.value_of(level, bits)).abs();
+                assert!(error <= format.max_error + 1e-12, "channel {} level {}: {} over {:?}", index, level, error, format);
+            }
+            let limit = (1i64 << (format.word_bits - 1)) - 1;
+            assert!((format.bias as i64).abs() <= limit && (format.decode(levels as u8 - 1) as i64).abs() <= limit, "channel {}: {:?}", index, format);
+        }
+    }
+
+    #[test]
+    fn integer_decodes_are_within_the_reported_error() {
+        for args in [&[][..], &["--bits", "4"][..], &["--bits", "6", "--rotation-anchor", "zero"][..]].iter() {
+            let mocap = fixture_mocap(args);
+            let formats = formats(&mocap, None).unwrap();
+            assert!(formats.iter().all(|format| format.word_bits == 32));
+            assert_within_reported_error(&mocap, &formats);
+        }
+    }
+
+    #[test]
+    fn picks_the_largest_shift_that_fits() {
+        let values = (0..=255).map(|level| -90.0 + level as f64 * 180.0 / 255.0).collect::<Vec<_>>();
+        let format = FixedPoint::fit(&values, 16).unwrap();
+        // level * scale spans 180, which takes 8 integer bits, leaving 7 of the 15 for the fraction
+        assert_eq!((format.word_bits, format.shift), (16, 7));
+        assert_eq!(format.bias, -90 * 128);
+        assert!(format.max_error <= 255.0 * 0.5 / 128.0);
+        let format = FixedPoint::fit(&values, 32).unwrap();
+        assert_eq!(format.shift, 23);
+        assert!(format.max_error <= 255.0 * 0.5 / (1u64 << 23) as f64);
+
+        // Too big for a word even as integers
+        assert_eq!(FixedPoint::fit(&[0.0, 40000.0], 16), None);
+        assert_eq!(FixedPoint::fit(&[0.0, 40000.0], 32).map(|format| format.shift), Some(15));
+        assert_eq!(FixedPoint::fit(&[0.0, 1e10], 32), None);
+    }
+
+    #[test]
+    fn a_precision_picks_16_bit_words_where_they_meet_it() {
+        let mocap = fixture_mocap(&[]);
+        let loose = formats(&mocap, Some(0.5)).unwrap();
+        assert!(loose.iter().all(|format| format.word_bits == 16 && format.max_error <= 0.5), "{:?}", loose);
+        assert_within_reported_error(&mocap, &loose);
+
+        let best = formats(&mocap, None).unwrap();
+        let precision = best.iter().map(|format| format.max_error).fold(0.0, f64::max) * 2.0;
+        let tight = formats(&mocap, Some(precision)).unwrap();
+        assert!(tight.iter().all(|format| format.max_error <= precision), "{:?}", tight);
+        assert!(tight.iter().any(|format| format.word_bits == 32));
+        assert_within_reported_error(&mocap, &tight);
+
+        match formats(&mocap, Some(1e-12)) {
+            Err(MocapError::Usage(ref message)) => assert!(message.starts_with("--export-fixed-point: these channels can't be represented within 0.000000000001: Hips TranslationX (at best "), "{}", message),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn refuses_lossless_channels() {
+        let mut mocap = fixture_mocap(&[]);
+        mocap.channels_mut()[1].values = Some(vec![0.0; mocap.num_frames as usize]);
+        match formats(&mocap, None) {
+            Err(MocapError::Usage(ref message)) => assert_eq!(message, "--export-fixed-point: Hips TranslationY is lossless and has no levels"),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn writes_a_header_line_per_channel() {
+        let mocap = fixture_mocap(&[]);
+        let formats = formats(&mocap, None).unwrap();
+        let mut header = Vec::new();
+        write_header(&mocap, &formats, &mut header).unwrap();
+        let header = String::from_utf8(header).unwrap();
+        assert!(header.contains(&format!("#define MOCAP_NUM_CHANNELS {}\n", formats.len())));
+        let format = formats[0];
+        let line = format!("    {{ {}, {}, {}, 32 }}, // 0: Hips TranslationX, Q{}.{}, max error {}\n", format.bias, format.scale, format.shift, 31 - format.shift, format.shift, format.max_error);
+        assert!(header.contains(&line), "{}", header);
+        assert_eq!(header.lines().filter(|line| line.starts_with("    { ") && line.contains(", max error ")).count(), formats.len());
+        assert!(header.contains("    { 0, 0, \"Hips\", \"TranslationX\" },\n"), "{}", header);
+        assert!(header.ends_with("    return channel->bias + (int32_t)level * channel->scale;\n}\n"));
+    }
+}
@@ -0,0 +1,160 @@
+use bvh;
+
+use math::Mat4;
+
+// Forward kinematics over a parsed hierarchy.
+//
+// A joint's local transform is `T(offset + translation channels) * R(rotation channels)`, with the
+// rotations composed in the order the joint declares its channels (so `Zrotation Xrotation
+// Yrotation` is `Rz * Rx * Ry`), all angles in degrees. World transforms are the product of the
+// local transforms from the root down. A joint without channels (CHANNELS 0) still has a transform,
+// its offset, and takes up no channel indices.
+
+pub fn local_transform(joint: &bvh::Joint, values: &[f64]) -> Mat4 {
+    let mut translation = (joint.offset.x, joint.offset.y, joint.offset.z);
+    let mut rotation = Mat4::identity();
+    for (channel, value) in joint.channels.iter().zip(values.iter()) {
+        match *channel {
+            bvh::Channel::XPosition => translation.0 += *value,
+            bvh::Channel::YPosition => translation.1 += *value,
+            bvh::Channel::ZPosition => translation.2 += *value,
+            bvh::Channel::XRotation => rotation = rotation * Mat4::rotation(0, *value),
+            bvh::Channel::YRotation => rotation = rotation * Mat4::rotation(1, *value),
+            bvh::Channel::ZRotation => rotation = rotation * Mat4::rotation(2, *value),
+        }
+    }
+
+    Mat4::translation(translation.0, translation.1, translation.2) * rotation
+}
+
+// Local transforms of every joint for one frame of channel values, in pre-order.
+pub fn local_transforms(root: &bvh::Joint, frame: &[f64]) -> Vec<Mat4> {
+    let mut ret = Vec::new();
+    let mut channel_index = 0;
+    push_local_transforms(root, frame, &mut channel_index, &mut ret);
+    ret
+}
+
+fn push_local_transforms(joint: &bvh::Joint, frame: &[f64], channel_index: &mut usize, transforms: &mut Vec<Mat4>) {
+    let num_channels = joint.channels.len();
+    transforms.push(local_transform(joint, &frame[*channel_index..*channel_index + num_channels]));
+    *channel_index += num_channels;
+
+    if let bvh::JointChildren::Joints(ref joints) = joint.children {
+        for child in joints.iter() {
+            push_local_transforms(child, frame, channel_index, transforms);
+        }
+    }
+}
+
+// World transforms of every joint for one frame of channel values, in pre-order (the same order
+// channel indices are assigned in).
+pub fn world_transforms(root: &bvh::Joint, frame: &[f64]) -> Vec<Mat4> {
+    let mut ret = Vec::new();
+    let mut channel_index = 0;
+    push_world_transforms(root, Mat4::identity(), frame, &mut channel_index, &mut ret);
+    ret
+}
+
+fn push_world_transforms(joint: &bvh::Joint, parent: Mat4, frame: &[f64], channel_index: &mut usize, transforms: &mut Vec<Mat4>) {
+    let num_channels = joint.channels.len();
+    let world = parent * local_transform(joint, &frame[*channel_index..*channel_index + num_channels]);
+    *channel_index += num_channels;
+    transforms.push(world);
+
+    if let bvh::JointChildren::Joints(ref joints) = joint.children {
+        for child in joints.iter() {
+            push_world_transforms(child, world, frame, channel_index, transforms);
+        }
+    }
+}
+
+// World positions of every end site for one frame, in pre-order.
+pub fn end_site_positions(root: &bvh::Joint, frame: &[f64]) -> Vec<(f64, f64, f64)> {
+    let transforms = world_transforms(root, frame);
+    let mut ret = Vec::new();
+    let mut joint_index = 0;
+    push_end_site_positions(root, &transforms, &mut joint_index, &mut ret);
+    ret
+}
+
+fn push_end_site_positions(joint: &bvh::Joint, transforms: &[Mat4], joint_index: &mut usize, positions: &mut Vec<(f64, f64, f64)>) {
+    let world = transforms[*joint_index];
+    *joint_index += 1;
+
+    match joint.children {
+        bvh::JointChildren::Joints(ref joints) => {
+            for child in joints.iter() {
+                push_end_site_positions(child, transforms, joint_index, positions);
+            }
+        }
+        bvh::JointChildren::EndSite(ref end_site) => {
+            let offset = &end_site.offset;
+            positions.push((world * Mat4::translation(offset.x, offset.y, offset.z)).position());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_util::chain_clip;
+
+    // Row-major, from its rotation rows and translation
+    fn transform(rotation: [[f64; 3]; 3], translation: [f64; 3]) -> Mat4 {
+        let mut ret = Mat4::identity();
+        for row in 0..3 {
+            ret.0[row][..3].copy_from_slice(&rotation[row]);
+            ret.0[row][3] = translation[row];
+        }
+        ret
+    }
+
+    fn assert_near(actual: &Mat4, expected: &Mat4) {
+        for (actual_row, expected_row) in actual.0.iter().zip(expected.0.iter()) {
+            for (actual, expected) in actual_row.iter().zip(expected_row.iter()) {
+                assert!((actual - expected).abs() < 1e-12, "{:?} != {:?}", actual, expected);
+            }
+        }
+    }
+
+    // Rz(90), and Rz(90) Rx(90)
+    const TURNED: [[f64; 3]; 3] = [[0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]];
+    const TURNED_AND_BENT: [[f64; 3]; 3] = [[0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+    const IDENTITY: [[f64; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    #[test]
+    fn world_transforms_compose_down_the_chain() {
+        let bvh = chain_clip();
+        let world = world_transforms(&bvh.hierarchy.root, &bvh.motion.frames[0]);
+        assert_eq!(world.len(), 3);
+        assert_near(&world[0], &transform(TURNED, [1.0, 2.0, 3.0]));
+        // The spine's offset turned to -X by the root, then bent
+        assert_near(&world[1], &transform(TURNED_AND_BENT, [-9.0, 2.0, 3.0]));
+        // The head's offset bent to +Z
+        assert_near(&world[2], &transform(TURNED_AND_BENT, [-9.0, 2.0, 8.0]));
+
+        let rest = world_transforms(&bvh.hierarchy.root, &bvh.motion.frames[1]);
+        assert_near(&rest[2], &transform(IDENTITY, [0.0, 15.0, 0.0]));
+    }
+
+    #[test]
+    fn local_transforms_are_each_joints_own() {
+        let bvh = chain_clip();
+        let local = local_transforms(&bvh.hierarchy.root, &bvh.motion.frames[0]);
+        assert_near(&local[0], &transform(TURNED, [1.0, 2.0, 3.0]));
+        assert_near(&local[1], &transform([[1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, 1.0, 0.0]], [0.0, 10.0, 0.0]));
+        assert_near(&local[2], &transform(IDENTITY, [0.0, 5.0, 0.0]));
+    }
+
+    #[test]
+    fn end_sites_are_at_their_offset_from_the_last_joint() {
+        let bvh = chain_clip();
+        let positions = end_site_positions(&bvh.hierarchy.root, &bvh.motion.frames[0]);
+        assert_eq!(positions.len(), 1);
+        let (x, y, z) = positions[0];
+        assert!((x + 9.0).abs() < 1e-12 && (y - 2.0).abs() < 1e-12 && (z - 10.0).abs() < 1e-12, "{:?}", positions[0]);
+        assert_eq!(end_site_positions(&bvh.hierarchy.root, &bvh.motion.frames[1]), vec![(0.0, 17.0, 0.0)]);
+    }
+}
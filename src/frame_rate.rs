@@ -0,0 +1,65 @@
+// Exact frame rates, with --rational-fps. A frame time is stored as an f32 number of seconds,
+// which can't hold 1/30 or 1001/30000 exactly, and BVH exporters round it further (0.033333), so
+// timestamps computed from it drift from the intended rate over a long clip (by a frame about
+// every hour at 0.033333 for 30 FPS), falling out of sync with video. With --rational-fps
+// the input's frame time is matched to a rate, as frames per second `numerator / denominator`:
+//
+//   - one of COMMON_RATES, the film, PAL, NTSC (the x/1.001 rates such as 29.97) and high frame
+//     rates, or
+//   - otherwise any whole number of frames per second,
+//
+// when 1 / frame time is within TOLERANCE (relative) of it, which is tighter than the 0.1% between
+// 30 and 29.97 but looser than a six-digit frame time's rounding. The frame time becomes exactly
+// denominator / numerator (as an f64) for every pass that follows, and the rate is recorded in the
+// metadata as `<numerator> <denominator>`; decoding writes the BVH frame time from the rate, as
+// the f64 nearest denominator / numerator, rather than from the stored f32. A frame time matching
+// no rate is kept as is, with a warning.
+
+// Metadata key holding the rate
+pub const KEY: &str = "frame_rate";
+
+// Frames per second, as (numerator, denominator)
+const COMMON_RATES: &[(u32, u32)] = &[
+    (24000, 1001), (24, 1), (25, 1), (30000, 1001), (30, 1), (48000, 1001), (48, 1), (50, 1),
+    (60000, 1001), (60, 1), (72, 1), (90, 1), (100, 1), (120000, 1001), (120, 1), (144, 1), (240, 1),
+];
+
+const TOLERANCE: f64 = 1e-4;
+
+// The rate `frame_time` (in seconds) is an approximation of, if any.
+pub fn detect(frame_time: f64) -> Option<(u32, u32)> {
+    if !frame_time.is_finite() || frame_time <= 0.0 {
+        return None;
+    }
+    let fps = 1.0 / frame_time;
+    let close = |(numerator, denominator): (u32, u32)| ((fps - numerator as f64 / denominator as f64) / fps).abs() <= TOLERANCE;
+    COMMON_RATES.iter().cloned().find(|rate| close(*rate))
+        .or_else(|| Some((fps.round() as u32, 1)).filter(|rate| rate.0 > 0 && close(*rate)))
+}
+
+pub fn frame_time(rate: (u32, u32)) -> f64 {
+    rate.1 as f64 / rate.0 as f64
+}
+
+pub fn metadata(rate: (u32, u32)) -> (String, String) {
+    (KEY.into(), format!("{} {}", rate.0, rate.1))
+}
+
+// The frame time the metadata's rate gives, if it records a valid one.
+pub fn recorded_frame_time(metadata: &[(String, String)]) -> Option<f64> {
+    let value = &metadata.iter().find(|entry| entry.0 == KEY)?.1;
+    let mut fields = value.split_whitespace().map(|field| field.parse::<u32>().ok());
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(Some(numerator)), Some(Some(denominator)), None) if numerator > 0 && denominator > 0 => Some(frame_time((numerator, denominator))),
+        _ => None,
+    }
+}
+
+// Describes the rate for printing, like "30000/1001 (29.97) FPS".
+pub fn describe(rate: (u32, u32)) -> String {
+    if rate.1 == 1 {
+        format!("{} FPS", rate.0)
+    } else {
+        format!("{}/{} ({:.3}) FPS", rate.0, rate.1, rate.0 as f64 / rate.1 as f64)
+    }
+}
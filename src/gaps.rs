@@ -0,0 +1,375 @@
+use bvh;
+
+use quality::{Event, Quality};
+use resample::Interpolation;
+use rotation_channels;
+
+// Repairing gaps in optical captures with --repair-gaps. When a marker is occluded, some
+// pipelines write a sentinel value (0, -9999, ...) into the channels it drives, and others hold
+// the last value they had until it's seen again. Either way the motion has gaps that would be
+// quantized (and widen the channel's range, for a sentinel) as if they were real. This pass finds
+// them in each channel and fills them in from the good samples either side.
+//
+// Two detectors find gaps; a frame is in a gap if either marks it:
+//   sentinel=<value>  A frame exactly at the value (as written in the file). May be given several
+//                     times, for pipelines using more than one
+//   flat=<frames>     A run of more than this many frames holding exactly the same value, on a channel
+//                     that normally moves: one whose value changes between at least MOVING_FRACTION of
+//                     its consecutive frames. The run's first frame is taken as the last good sample,
+//                     and the rest as the hold
+// like `--repair-gaps sentinel=-9999,flat=10`. A channel with a gap covering more than half the clip
+// doesn't count as moving, so holds that long are only caught by a sentinel.
+//
+// A gap with good samples on both sides is interpolated between them with --interpolation:
+// linearly, or along a cubic Hermite spline whose tangents are the motion's velocity at the good
+// samples either side (from the frame next to each, if it's good too), which keeps the motion
+// smooth through the gap. Rotations go the short way round. A gap touching the first or last frame
+// holds the nearest good sample, and a channel without any good samples is left alone.
+//
+// The pass runs first, on the input's frames, so the gaps (printed by `load`) are reported in
+// input frames and every later pass sees repaired motion. The repaired spans are recorded as
+// metadata, as `<flat channel index>:<first frame>-<last frame>` separated by spaces: as many as
+// fit in a metadata value (see raw.rs), then `+<count> more` if a badly occluded capture has more.
+// The printed list and the quality scores (see quality.rs) cover all of them.
+
+// Metadata key recording the repaired spans
+pub const KEY: &str = "repaired_gaps";
+
+// The room the recorded spans may take, leaving some for the `+<count> more`
+const MAX_RECORDED_LEN: usize = u16::MAX as usize - 32;
+
+// How often a channel's value has to change between frames for it to count as moving
+const MOVING_FRACTION: f64 = 0.5;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Detection {
+    pub sentinels: Vec<f64>,
+    pub flat_frames: Option<usize>, // Flat runs longer than this are gaps
+}
+
+impl Detection {
+    // Adds the detectors in `spec`, `<detector>=<value>` separated by commas, to these.
+    pub fn parse(&mut self, spec: &str) -> Option<()> {
+        for detector in spec.split(',') {
+            let (kind, value) = detector.split_once('=')?;
+            match kind {
+                "sentinel" => {
+                    let sentinel = value.parse::<f64>().ok()?;
+                    if sentinel.is_nan() {
+                        return None;
+                    }
+                    self.sentinels.push(sentinel);
+                }
+                "flat" => {
+                    let frames = value.parse::<usize>().ok()?;
+                    if frames == 0 {
+                        return None;
+                    }
+                    self.flat_frames = Some(frames);
+                }
+                _ => return None,
+            }
+        }
+        Some(())
+    }
+
+    // The value --repair-gaps takes for these detectors.
+    pub fn spec(&self) -> String {
+        let mut detectors = self.sentinels.iter().map(|sentinel| format!("sentinel={}", sentinel)).collect::<Vec<_>>();
+        if let Some(frames) = self.flat_frames {
+            detectors.push(format!("flat={}", frames));
+        }
+        detectors.join(",")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gap {
+    pub channel: usize, // Flat channel index
+    pub start: usize,
+    pub end: usize, // Exclusive
+    pub sentinel: bool, // Which detectors marked frames of it
+    pub flat: bool,
+}
+
+impl Gap {
+    // What marked the gap and how `repair` fills it, for printing.
+    pub fn describe(&self, num_frames: usize) -> String {
+        let cause = match (self.sentinel, self.flat) {
+            (true, true) => "sentinel values and a flat run",
+            (true, false) => "sentinel values",
+            _ => "a flat run",
+        };
+        let repair = match (self.start == 0, self.end == num_frames) {
+            (true, true) => "not repaired, as the channel has no good samples",
+            (false, false) => "interpolated",
+            _ => "held at the nearest good sample",
+        };
+        format!("{}, {}", cause, repair)
+    }
+}
+
+// Every gap `detection` finds, by channel in flat channel order and then by frame. Gaps are maximal,
+// so each has a good sample (or the clip's edge) on either side.
+pub fn find(bvh: &bvh::Bvh, detection: &Detection) -> Vec<Gap> {
+    const SENTINEL: u8 = 1;
+    const FLAT: u8 = 2;
+
+    let frames = &bvh.motion.frames;
+    let num_frames = frames.len();
+    let num_channels = frames.first().map_or(0, |frame| frame.len());
+    let mut ret = Vec::new();
+    for channel in 0..num_channels {
+        let values = frames.iter().map(|frame| frame[channel]).collect::<Vec<_>>();
+        let is_sentinel = |value: f64| detection.sentinels.contains(&value);
+        let mut marks = values.iter().map(|value| if is_sentinel(*value) { SENTINEL } else { 0 }).collect::<Vec<_>>();
+
+        if let Some(flat_frames) = detection.flat_frames {
+            let changes = values.windows(2).filter(|pair| pair[0] != pair[1] && !is_sentinel(pair[0]) && !is_sentinel(pair[1])).count();
+            if num_frames > 1 && changes as f64 >= MOVING_FRACTION * (num_frames - 1) as f64 {
+                let mut start = 0;
+                while start < num_frames {
+                    // At least one frame, even for a NaN
+                    let end = start + values[start..].iter().take_while(|value| **value == values[start]).count().max(1);
+                    if end - start > flat_frames && !is_sentinel(values[start]) {
+                        for mark in marks[start + 1..end].iter_mut() {
+                            *mark |= FLAT;
+                        }
+                    }
+                    start = end;
+                }
+            }
+        }
+
+        let mut frame = 0;
+        while frame < num_frames {
+            if marks[frame] == 0 {
+                frame += 1;
+                continue;
+            }
+            let start = frame;
+            let mut cause = 0;
+            while frame < num_frames && marks[frame] != 0 {
+                cause |= marks[frame];
+                frame += 1;
+            }
+            ret.push(Gap {
+                channel: channel,
+                start: start,
+                end: frame,
+                sentinel: cause & SENTINEL != 0,
+                flat: cause & FLAT != 0,
+            });
+        }
+    }
+    ret
+}
+
+// Fills in `gaps` (as `find` returns them) from the good samples around them, recording the
+// samples repaired in `quality` and returning the metadata recording the spans.
+pub fn repair(bvh: &mut bvh::Bvh, gaps: &[Gap], interpolation: Interpolation, quality: &mut Quality) -> Vec<(String, String)> {
+    let rotations = rotation_channels(&bvh.hierarchy.root);
+    let frames = &mut bvh.motion.frames;
+    let num_frames = frames.len();
+    let mut bad = vec![vec![false; num_frames]; rotations.len()];
+    for gap in gaps.iter() {
+        for bad in bad[gap.channel][gap.start..gap.end].iter_mut() {
+            *bad = true;
+        }
+    }
+
+    let mut repaired = Vec::new();
+    for gap in gaps.iter() {
+        let (channel, rotation, bad) = (gap.channel, rotations[gap.channel], &bad[gap.channel]);
+        let good = |frame: Option<usize>| frame.filter(|frame| *frame < num_frames && !bad[*frame]);
+        let before = good(gap.start.checked_sub(1));
+        let after = good(Some(gap.end));
+        // The short way round from `from` to `to`
+        let step = |from: f64, to: f64| if rotation { to - from - 360.0 * ((to - from + 180.0) / 360.0).floor() } else { to - from };
+
+        match (before, after) {
+            (None, None) => continue,
+            (Some(edge), None) | (None, Some(edge)) => {
+                let value = frames[edge][channel];
+                for frame in frames[gap.start..gap.end].iter_mut() {
+                    frame[channel] = value;
+                }
+            }
+            (Some(before), Some(after)) => {
+                let length = (after - before) as f64;
+                // The good samples unwrapped around the first one, and the motion's velocity (per
+                // frame) at each, as far as the good samples show it
+                let (p1, p2) = (frames[before][channel], frames[before][channel] + step(frames[before][channel], frames[after][channel]));
+                let straight = (p2 - p1) / length;
+                let m1 = good(before.checked_sub(1)).map_or(straight, |previous| step(frames[previous][channel], frames[before][channel]));
+                let m2 = good(Some(after + 1)).map_or(straight, |next| step(frames[after][channel], frames[next][channel]));
+                for frame in gap.start..gap.end {
+                    let t = (frame - before) as f64 / length;
+                    let value = match interpolation {
+                        Interpolation::Linear => p1 + (p2 - p1) * t,
+                        Interpolation::Cubic => {
+                            let (t2, t3) = (t * t, t * t * t);
+                            (2.0 * t3 - 3.0 * t2 + 1.0) * p1 + (t3 - 2.0 * t2 + t) * length * m1 + (3.0 * t2 - 2.0 * t3) * p2 + (t3 - t2) * length * m2
+                        }
+                    };
+                    frames[frame][channel] = match value {
+                        _ if !rotation || frames[before][channel].abs() > 180.0 || frames[after][channel].abs() > 180.0 => value,
+                        value if value > 180.0 => value - 360.0,
+                        value if value < -180.0 => value + 360.0,
+                        value => value,
+                    };
+                }
+            }
+        }
+        quality.record(channel, Event::Repaired((gap.end - gap.start) as u64));
+        repaired.push(format!("{}:{}-{}", channel, gap.start, gap.end - 1));
+    }
+    if repaired.is_empty() {
+        return Vec::new();
+    }
+    let mut recorded = String::new();
+    for (index, span) in repaired.iter().enumerate() {
+        if recorded.len() + 1 + span.len() > MAX_RECORDED_LEN {
+            recorded.push_str(&format!(" +{} more", repaired.len() - index));
+            break;
+        }
+        if index > 0 {
+            recorded.push(' ');
+        }
+        recorded.push_str(span);
+    }
+    vec![(KEY.into(), recorded)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_util::{clip_text, parse, sine};
+
+    const SENTINEL: f64 = -9999.0;
+
+    // 20 frames of a line per channel, `channel + frame`, with `gaps` (channel, frames) at SENTINEL
+    fn clip(gaps: &[(usize, std::ops::Range<usize>)]) -> bvh::Bvh {
+        parse(&clip_text(20, |frame, channel| {
+            if gaps.iter().any(|(gap_channel, frames)| *gap_channel == channel && frames.contains(&frame)) { SENTINEL } else { (channel + frame) as f64 }
+        }))
+    }
+
+    fn sentinels() -> Detection {
+        Detection { sentinels: vec![SENTINEL], flat_frames: None }
+    }
+
+    fn repaired(bvh: &mut bvh::Bvh, interpolation: Interpolation) -> (Vec<Gap>, Vec<(String, String)>, Quality) {
+        let gaps = find(bvh, &sentinels());
+        let mut quality = Quality::new(bvh.motion.frames[0].len());
+        let metadata = repair(bvh, &gaps, interpolation, &mut quality);
+        (gaps, metadata, quality)
+    }
+
+    fn column(bvh: &bvh::Bvh, channel: usize) -> Vec<f64> {
+        bvh.motion.frames.iter().map(|frame| frame[channel]).collect()
+    }
+
+    #[test]
+    fn interpolates_a_gap_mid_clip() {
+        for interpolation in [Interpolation::Linear, Interpolation::Cubic].iter() {
+            let mut bvh = clip(&[(1, 5..9)]);
+            let (gaps, metadata, quality) = repaired(&mut bvh, *interpolation);
+            assert_eq!(gaps, vec![Gap { channel: 1, start: 5, end: 9, sentinel: true, flat: false }]);
+            assert_eq!(gaps[0].describe(20), "sentinel values, interpolated");
+            // A line either way, its velocity the same on both sides
+            for (frame, value) in column(&bvh, 1).iter().enumerate() {
+                assert!((value - (1 + frame) as f64).abs() < 1e-9, "{:?} frame {}: {}", interpolation, frame, value);
+            }
+            assert_eq!(metadata, vec![(KEY.to_string(), "1:5-8".to_string())]);
+            assert_eq!(quality.channels[1].repaired, 4);
+            assert_eq!(quality.channels[0].repaired, 0);
+        }
+    }
+
+    #[test]
+    fn holds_the_nearest_good_sample_at_the_clip_edges() {
+        let mut bvh = clip(&[(2, 0..3), (3, 17..20)]);
+        let (gaps, metadata, _) = repaired(&mut bvh, Interpolation::Cubic);
+        assert_eq!(gaps.iter().map(|gap| gap.describe(20)).collect::<Vec<_>>(), vec!["sentinel values, held at the nearest good sample"; 2]);
+        assert_eq!(&column(&bvh, 2)[..4], &[5.0, 5.0, 5.0, 5.0]);
+        assert_eq!(&column(&bvh, 3)[16..], &[19.0, 19.0, 19.0, 19.0]);
+        assert_eq!(metadata[0].1, "2:0-2 3:17-19");
+    }
+
+    #[test]
+    fn repairs_overlapping_gaps_on_several_channels_separately() {
+        let mut bvh = clip(&[(0, 4..10), (7, 6..12), (7, 14..16), (14, 8..9)]);
+        let (gaps, metadata, quality) = repaired(&mut bvh, Interpolation::Linear);
+        assert_eq!(gaps.iter().map(|gap| (gap.channel, gap.start, gap.end)).collect::<Vec<_>>(), vec![(0, 4, 10), (7, 6, 12), (7, 14, 16), (14, 8, 9)]);
+        for channel in 0..15 {
+            assert_eq!(column(&bvh, channel), (0..20).map(|frame| (channel + frame) as f64).collect::<Vec<_>>(), "channel {}", channel);
+        }
+        assert_eq!(metadata[0].1, "0:4-9 7:6-11 7:14-15 14:8-8");
+        assert_eq!(quality.channels.iter().map(|channel| channel.repaired).filter(|repaired| *repaired > 0).collect::<Vec<_>>(), vec![6, 8, 1]);
+    }
+
+    #[test]
+    fn leaves_a_channel_without_good_samples_alone() {
+        let mut bvh = clip(&[(4, 0..20)]);
+        let (gaps, metadata, quality) = repaired(&mut bvh, Interpolation::Linear);
+        assert_eq!(gaps[0].describe(20), "sentinel values, not repaired, as the channel has no good samples");
+        assert!(column(&bvh, 4).iter().all(|value| *value == SENTINEL));
+        assert!(metadata.is_empty());
+        assert_eq!(quality.channels[4].repaired, 0);
+    }
+
+    #[test]
+    fn interpolates_rotations_the_short_way_round() {
+        // Spine Zrotation from 170 to -170 degrees across a gap
+        let mut bvh = parse(&clip_text(5, |frame, channel| match (channel, frame) {
+            (6, 0) | (6, 1) => 170.0,
+            (6, 2) => SENTINEL,
+            (6, _) => -170.0,
+            _ => 0.0,
+        }));
+        repaired(&mut bvh, Interpolation::Linear);
+        assert_eq!(column(&bvh, 6)[2], 180.0);
+    }
+
+    #[test]
+    fn flat_runs_are_gaps_on_moving_channels() {
+        let mut values = (0..40).map(|frame| sine(frame, 0)).collect::<Vec<_>>();
+        let held = values[19];
+        for value in values[20..30].iter_mut() {
+            *value = held;
+        }
+        let bvh = parse(&clip_text(40, |frame, channel| if channel == 0 { values[frame] } else { 1.0 }));
+        let detection = Detection { sentinels: Vec::new(), flat_frames: Some(5) };
+        // The run's first frame is the last good sample; the constant channels don't count
+        assert_eq!(find(&bvh, &detection), vec![Gap { channel: 0, start: 20, end: 30, sentinel: false, flat: true }]);
+        // An 11-frame run
+        assert!(find(&bvh, &Detection { sentinels: Vec::new(), flat_frames: Some(11) }).is_empty());
+    }
+
+    #[test]
+    fn records_as_many_spans_as_the_metadata_holds() {
+        // Every other frame of every channel missing
+        let num_frames = 2000;
+        let mut bvh = parse(&clip_text(num_frames, |frame, channel| if frame % 2 == 1 && frame < num_frames - 1 { SENTINEL } else { (channel + frame) as f64 }));
+        let (gaps, metadata, _) = repaired(&mut bvh, Interpolation::Linear);
+        assert_eq!(gaps.len(), 15 * 999);
+        let recorded = &metadata[0].1;
+        assert!(recorded.len() <= u16::MAX as usize);
+        let spans = recorded.split(' ').take_while(|span| span.contains(':')).count();
+        assert!(recorded.ends_with(&format!(" +{} more", gaps.len() - spans)), "{}", &recorded[recorded.len() - 40..]);
+        assert!(spans > 5000);
+    }
+
+    #[test]
+    fn parses_detectors() {
+        let mut detection = Detection::default();
+        assert_eq!(detection.parse("sentinel=-9999,flat=10,sentinel=0"), Some(()));
+        assert_eq!(detection, Detection { sentinels: vec![-9999.0, 0.0], flat_frames: Some(10) });
+        assert_eq!(detection.spec(), "sentinel=-9999,sentinel=0,flat=10");
+        for spec in ["flat=0", "sentinel=NaN", "sentinel", "hold=3", ""].iter() {
+            assert_eq!(Detection::default().parse(spec), None, "{}", spec);
+        }
+    }
+}
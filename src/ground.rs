@@ -0,0 +1,178 @@
+use bvh;
+
+use fk;
+
+// The fraction of frames allowed to dip below the estimated floor, so a few frames of noise or a
+// foot poking through the ground don't drag the estimate down.
+const FLOOR_PERCENTILE: f64 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ground {
+    pub up_axis: usize, // 0 = X, 1 = Y, 2 = Z, pointing up
+    pub floor_height: f64,
+}
+
+pub fn axis_name(axis: usize) -> &'static str {
+    ["X", "Y", "Z"][axis]
+}
+
+pub fn detect(bvh: &bvh::Bvh, up_axis: Option<usize>) -> Ground {
+    let up_axis = up_axis.unwrap_or_else(|| detect_up_axis(bvh));
+    Ground {
+        up_axis: up_axis,
+        floor_height: estimate_floor_height(bvh, up_axis),
+    }
+}
+
+// Captures mostly move across the floor, so the root translation axis that varies least is taken
+// to be up. If the root barely moves at all that tells us nothing, so fall back to the axis along
+// which the skeleton's rest pose is tallest. Non-finite samples (such as NaN occlusion sentinels,
+// see gaps.rs) are left out, here and in the floor height.
+pub fn detect_up_axis(bvh: &bvh::Bvh) -> usize {
+    let root = &bvh.hierarchy.root;
+    let mut variances = [None; 3];
+    for (index, channel) in root.channels.iter().enumerate() {
+        let axis = match *channel {
+            bvh::Channel::XPosition => 0,
+            bvh::Channel::YPosition => 1,
+            bvh::Channel::ZPosition => 2,
+            _ => continue,
+        };
+        variances[axis] = Some(variance(bvh.motion.frames.iter().map(|frame| frame[index])));
+    }
+
+    let horizontal_motion = variances.iter().filter_map(|variance| *variance).fold(0.0, f64::max);
+    if variances.iter().all(|variance| variance.is_some()) && horizontal_motion > 1e-6 {
+        return (0..3).min_by(|a, b| variances[*a].unwrap().total_cmp(&variances[*b].unwrap())).unwrap();
+    }
+
+    let rest_pose = vec![0.0; bvh.motion.frames.first().map_or(0, |frame| frame.len())];
+    let positions = fk::world_transforms(root, &rest_pose).iter().map(|transform| transform.position()).collect::<Vec<_>>();
+    let extent = |axis: usize| {
+        let values = positions.iter().map(|position| [position.0, position.1, position.2][axis]);
+        let (min, max) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), value| (min.min(value), max.max(value)));
+        max - min
+    };
+    (0..3).max_by(|a, b| extent(*a).total_cmp(&extent(*b))).unwrap()
+}
+
+// A low percentile over the clip of the lowest end site (typically a toe) along `up_axis`.
+pub fn estimate_floor_height(bvh: &bvh::Bvh, up_axis: usize) -> f64 {
+    let mut lowest = bvh.motion.frames.iter().filter_map(|frame| {
+        fk::end_site_positions(&bvh.hierarchy.root, frame).iter()
+            .map(|position| [position.0, position.1, position.2][up_axis])
+            .filter(|height| height.is_finite())
+            .fold(None, |lowest: Option<f64>, height| Some(lowest.map_or(height, |lowest| lowest.min(height))))
+    }).collect::<Vec<_>>();
+    if lowest.is_empty() {
+        return 0.0;
+    }
+
+    lowest.sort_by(|a, b| a.total_cmp(b));
+    lowest[((lowest.len() - 1) as f64 * FLOOR_PERCENTILE) as usize]
+}
+
+// Moves the whole clip along the up axis so the floor ends up at 0. This goes through the root's
+// offset, so it works whether or not the root has a translation channel on that axis.
+pub fn snap_to_ground(bvh: &mut bvh::Bvh, ground: &Ground) {
+    let offset = &mut bvh.hierarchy.root.offset;
+    match ground.up_axis {
+        0 => offset.x -= ground.floor_height,
+        1 => offset.y -= ground.floor_height,
+        _ => offset.z -= ground.floor_height,
+    }
+}
+
+fn variance<I: Iterator<Item = f64>>(values: I) -> f64 {
+    let values = values.filter(|value| value.is_finite()).collect::<Vec<_>>();
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mean = values.iter().sum::<f64>() / (values.len() as f64);
+    values.iter().map(|value| (value - mean) * (value - mean)).sum::<f64>() / (values.len() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util;
+
+    // A root with two legs reaching 45 units down along `up`: 0 = X, 1 = Y, 2 = Z
+    fn hierarchy(up: usize) -> String {
+        let down = |length: f64| {
+            let mut offset = [0.0; 3];
+            offset[up] = -length;
+            format!("{} {} {}", offset[0], offset[1], offset[2])
+        };
+        format!("HIERARCHY
+ROOT Hips
+{{
+\tOFFSET 0 0 0
+\tCHANNELS 3 Xposition Yposition Zposition
+\tJOINT LeftLeg
+\t{{
+\t\tOFFSET 5 5 5
+\t\tCHANNELS 3 Zrotation Xrotation Yrotation
+\t\tEnd Site
+\t\t{{
+\t\t\tOFFSET {}
+\t\t}}
+\t}}
+\tJOINT RightLeg
+\t{{
+\t\tOFFSET -5 -5 -5
+\t\tCHANNELS 3 Zrotation Xrotation Yrotation
+\t\tEnd Site
+\t\t{{
+\t\t\tOFFSET {}
+\t\t}}
+\t}}
+}}
+", down(40.0), down(40.0))
+    }
+
+    // Walking across the floor with the lower toes at `floor`, 45 below the hips, bobbing up a little
+    fn walk(up: usize, floor: f64) -> bvh::Bvh {
+        let hips = floor + 45.0;
+        let across = if up == 0 { 1 } else { 0 };
+        test_util::parse(&test_util::motion_text(&hierarchy(up), 9, 120, |frame, channel| match channel {
+            _ if channel == up => hips + 0.3 * (frame as f64 * 0.4).sin().abs(),
+            _ if channel == across => frame as f64 * 2.0,
+            0..=2 => (frame as f64 * 0.05).sin() * 20.0,
+            _ => 0.0,
+        }))
+    }
+
+    #[test]
+    fn detects_z_up() {
+        let ground = detect(&walk(2, 0.0), None);
+        assert_eq!(ground.up_axis, 2);
+        assert!(ground.floor_height.abs() < 0.1, "{}", ground.floor_height);
+    }
+
+    #[test]
+    fn detects_a_raised_floor() {
+        for up in [1, 2].iter() {
+            let ground = detect(&walk(*up, 12.3), None);
+            assert_eq!(ground.up_axis, *up);
+            assert!((ground.floor_height - 12.3).abs() < 0.1, "{}: {}", up, ground.floor_height);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_rest_pose_for_a_still_root() {
+        let bvh = test_util::parse(&test_util::motion_text(&hierarchy(2), 9, 10, |_, channel| if channel == 2 { 45.0 } else { 0.0 }));
+        assert_eq!(detect_up_axis(&bvh), 2);
+    }
+
+    #[test]
+    fn skips_non_finite_samples() {
+        let mut bvh = walk(2, 12.3);
+        bvh.motion.frames[7][0] = f64::NAN;
+        bvh.motion.frames[9][2] = f64::NAN;
+        bvh.motion.frames[11][1] = f64::INFINITY;
+        let ground = detect(&bvh, None);
+        assert_eq!(ground.up_axis, 2);
+        assert!((ground.floor_height - 12.3).abs() < 0.1, "{}", ground.floor_height);
+    }
+}
@@ -0,0 +1,291 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use bvh;
+
+use curves;
+use depth;
+use directives::{self, Directives};
+use error::MocapError;
+use log;
+use options::Options;
+use count_bvh_channels;
+
+// What to do with a BVH file whose `Frames:` header disagrees with the rows of motion it has, which
+// some exporters get wrong (--frame-count-mismatch): fail (the default), use every row, or use the
+// header's count, dropping the rows past it. The others warn; there's no using the header's count
+// with fewer rows than it says.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameCountMismatch {
+    Error,
+    Rows,
+    Header,
+}
+
+pub fn read_bvh(file_name: &Path, options: &Options) -> Result<bvh::Bvh, MocapError> {
+    read_bvh_with_directives(file_name, options).map(|(bvh, _)| bvh)
+}
+
+// `read_bvh`, also returning the settings directives in the file's comments (see directives.rs).
+pub fn read_bvh_with_directives(file_name: &Path, options: &Options) -> Result<(bvh::Bvh, Directives), MocapError> {
+    let input = read_normalized(file_name, options)?;
+    let directives = read_directives(file_name, &input)?;
+    let input = directives::strip_comments(input);
+    depth::check_bvh(&input, options.max_depth)?;
+    let mut bvh = bvh::parse(&input).map_err(|e| MocapError::Parse(format!("{:?}", e)))?;
+    let num_frames = bvh.motion.num_frames as usize;
+    reconcile_frame_count(&mut bvh.motion.frames, num_frames, file_name, options.frame_count_mismatch)?;
+    bvh.motion.num_frames = bvh.motion.frames.len() as u32;
+    Ok((bvh, directives))
+}
+
+// A skeleton and its motion kept in separate files (--hierarchy and --motion). The hierarchy file
+// is a BVH file; anything from its MOTION line on is ignored except for the frame time. The motion
+// file holds one line of channel values per frame, optionally preceded by a BVH motion header
+// (`MOTION`, `Frames: <n>`, `Frame Time: <seconds>`). Every line must have exactly one value per
+// channel of the hierarchy. The frame time comes from the motion file if it has one, then the
+// hierarchy file, then --override-frame-time. Settings directives are read from the hierarchy file.
+//
+// The motion file can also be a curve file, as --export-curves writes (JSON if its name ends in
+// .json, binary if it starts with the curves magic), whose curves are evaluated every
+// 1 / --curve-fps seconds, or at the file's own frame time, from 0 until the last knot.
+pub fn read_split_bvh(hierarchy_file_name: &Path, motion_file_name: &Path, options: &Options) -> Result<(bvh::Bvh, Directives), MocapError> {
+    let frame_time_of = |line: &str| line.strip_prefix("Frame Time:").map(|value| value.trim().parse::<f64>());
+
+    let hierarchy = read_normalized(hierarchy_file_name, options)?;
+    let directives = read_directives(hierarchy_file_name, &hierarchy)?;
+    let hierarchy = directives::strip_comments(hierarchy);
+    let (hierarchy, hierarchy_motion) = match hierarchy.lines().position(|line| line.trim() == "MOTION") {
+        Some(index) => {
+            let lines = hierarchy.lines().collect::<Vec<_>>();
+            (lines[..index].join("\n"), lines[index..].join("\n"))
+        }
+        None => (hierarchy.clone(), String::new()),
+    };
+    depth::check_bvh(&hierarchy, options.max_depth)?;
+    let mut bvh = bvh::parse(&format!("{}\nMOTION\nFrames: 0\nFrame Time: 1\n", hierarchy)).map_err(|e| MocapError::Parse(format!("{}: {:?}", hierarchy_file_name.display(), e)))?;
+    let hierarchy_frame_time = hierarchy_motion.lines().filter_map(|line| frame_time_of(line.trim())).next().and_then(|frame_time| frame_time.ok());
+    let num_channels = count_bvh_channels(&bvh.hierarchy.root);
+
+    let is_json = motion_file_name.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+    let data = fs::read(motion_file_name)?;
+    if is_json || data.starts_with(curves::MAGIC) {
+        let (curves, frame_time) = curves::read(&data, is_json, &mut bvh.hierarchy.root).map_err(|e| match e {
+            MocapError::InvalidCurves(message) => MocapError::InvalidCurves(format!("{}: {}", motion_file_name.display(), message)),
+            e => e,
+        })?;
+        bvh.motion.frame_time = options.curve_fps.map_or(frame_time, |fps| 1.0 / fps);
+        bvh.motion.frames = curves::sample(&curves, bvh.motion.frame_time);
+        bvh.motion.num_frames = bvh.motion.frames.len() as u32;
+        return Ok((bvh, directives));
+    }
+
+    let motion = read_normalized(motion_file_name, options)?;
+    let error = |line: usize, message: String| MocapError::Parse(format!("{}:{}: {}", motion_file_name.display(), line + 1, message));
+    let mut num_frames = None;
+    let mut frame_time = None;
+    let mut frames = Vec::new();
+    for (index, line) in motion.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if frames.is_empty() {
+            if line == "MOTION" {
+                continue;
+            }
+            if let Some(value) = line.strip_prefix("Frames:") {
+                num_frames = Some(value.trim().parse::<usize>().map_err(|_| error(index, format!("invalid frame count {}", value.trim())))?);
+                continue;
+            }
+            if let Some(value) = frame_time_of(line) {
+                frame_time = Some(value.map_err(|_| error(index, format!("invalid frame time {}", line)))?);
+                continue;
+            }
+        }
+
+        let frame = line.split_whitespace().map(|value| value.parse::<f64>().map_err(|_| error(index, format!("invalid channel value {}", value)))).collect::<Result<Vec<_>, _>>()?;
+        if frame.len() != num_channels {
+            return Err(error(index, format!("expected {} channel values (the channels of {}), got {}", num_channels, hierarchy_file_name.display(), frame.len())));
+        }
+        frames.push(frame);
+    }
+    if let Some(num_frames) = num_frames {
+        reconcile_frame_count(&mut frames, num_frames, motion_file_name, options.frame_count_mismatch)?;
+    }
+
+    bvh.motion.frame_time = frame_time.or(hierarchy_frame_time).or(options.override_frame_time)
+        .ok_or_else(|| MocapError::Parse(format!("{}: no frame time in the motion or hierarchy file; give one with --override-frame-time", motion_file_name.display())))?;
+    bvh.motion.num_frames = frames.len() as u32;
+    bvh.motion.frames = frames;
+    Ok((bvh, directives))
+}
+
+// Makes `frames` agree with the `num_frames` a header declares, as `policy` says.
+fn reconcile_frame_count(frames: &mut Vec<Vec<f64>>, num_frames: usize, file_name: &Path, policy: FrameCountMismatch) -> Result<(), MocapError> {
+    if frames.len() == num_frames {
+        return Ok(());
+    }
+    let mismatch = format!("{}: the header says {} frames, but there are {} rows of motion", file_name.display(), num_frames, frames.len());
+    match policy {
+        FrameCountMismatch::Error => Err(MocapError::Parse(format!("{} (--frame-count-mismatch rows uses the rows)", mismatch))),
+        FrameCountMismatch::Rows => {
+            log::warning(format!("{}, using the rows", mismatch));
+            Ok(())
+        }
+        FrameCountMismatch::Header if frames.len() > num_frames => {
+            log::warning(format!("{}, dropping the last {}", mismatch, frames.len() - num_frames));
+            frames.truncate(num_frames);
+            Ok(())
+        }
+        FrameCountMismatch::Header => Err(MocapError::Parse(format!("{}, too few for --frame-count-mismatch header", mismatch))),
+    }
+}
+
+fn read_directives(file_name: &Path, input: &str) -> Result<Directives, MocapError> {
+    Directives::parse(input).map_err(|message| MocapError::Parse(format!("{}:{}", file_name.display(), message)))
+}
+
+fn read_normalized(file_name: &Path, options: &Options) -> Result<String, MocapError> {
+    let mut input = String::new();
+    File::open(file_name)?.read_to_string(&mut input)?;
+
+    Ok(normalize(input, |message| if options.verbose {
+        log::debug(format!("{}: {}", file_name.display(), message));
+    }))
+}
+
+// Files exported by some Windows tools start with a UTF-8 BOM and/or use CR or CRLF line endings,
+// either of which can trip up `bvh::parse`. Strips the former and turns the latter into LF.
+fn normalize<F: FnMut(&str)>(input: String, mut log: F) -> String {
+    let mut input = input;
+
+    if input.starts_with('\u{feff}') {
+        input = input['\u{feff}'.len_utf8()..].to_string();
+        log("stripped leading byte order mark");
+    }
+
+    if input.contains('\r') {
+        input = input.replace("\r\n", "\n").replace('\r', "\n");
+        log("normalized CR line endings to LF");
+    }
+
+    input
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util;
+
+    #[test]
+    fn normalizes_a_bom_and_crlf_line_endings() {
+        let file_name = test_util::fixture("bom_crlf.bvh");
+        let data = fs::read(&file_name).unwrap();
+        assert!(data.starts_with(b"\xef\xbb\xbfHIERARCHY\r\n"));
+
+        let options = test_util::options(&["--verbose"]);
+        let (bvh, messages) = log::capture(|| read_bvh(&file_name, &options).unwrap());
+        assert_eq!(log::diagnostics(&messages), vec![
+            format!("debug: {}: stripped leading byte order mark", file_name.display()),
+            format!("debug: {}: normalized CR line endings to LF", file_name.display()),
+        ]);
+        assert_eq!(bvh.hierarchy.root.name, "Hips");
+        assert_eq!(bvh.motion.frames, vec![
+            vec![0.0, 90.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            vec![1.0, 91.0, 0.0, 5.0, 0.0, 0.0, 10.0, 0.0, 0.0],
+            vec![2.0, 92.0, 0.0, 10.0, 0.0, 0.0, 20.0, 0.0, 0.0],
+        ]);
+    }
+
+    #[test]
+    fn leaves_clean_input_alone() {
+        let text = test_util::clip_text(2, test_util::sine);
+        let mut logged = Vec::new();
+        assert_eq!(normalize(text.clone(), |message| logged.push(message.to_string())), text);
+        assert!(logged.is_empty());
+        assert_eq!(normalize("a\rb\r\nc".into(), |_| ()), "a\nb\nc");
+    }
+
+    // A clip of `rows` frames whose header says `declared`.
+    fn mismatched(rows: usize, declared: usize) -> String {
+        test_util::clip_text(rows, test_util::sine).replace(&format!("Frames: {}\n", rows), &format!("Frames: {}\n", declared))
+    }
+
+    fn read_mismatched(name: &str, rows: usize, declared: usize, args: &[&str]) -> (Result<bvh::Bvh, MocapError>, Vec<String>) {
+        let dir = test_util::temp_dir(name);
+        let file_name = dir.join("in.bvh");
+        fs::write(&file_name, mismatched(rows, declared)).unwrap();
+        let (result, messages) = log::capture(|| read_bvh(&file_name, &test_util::options(args)));
+        (result, log::diagnostics(&messages))
+    }
+
+    #[test]
+    fn frame_count_mismatches_fail_by_default() {
+        for &(rows, declared) in [(10, 8), (8, 10)].iter() {
+            match read_mismatched("frames-error", rows, declared, &[]).0 {
+                Err(MocapError::Parse(message)) => assert!(message.ends_with(&format!("in.bvh: the header says {} frames, but there are {} rows of motion (--frame-count-mismatch rows uses the rows)", declared, rows)), "{}", message),
+                other => panic!("{:?}", other.map(|bvh| bvh.motion.num_frames)),
+            }
+        }
+        let (bvh, messages) = read_mismatched("frames-match", 10, 10, &[]);
+        assert_eq!(bvh.unwrap().motion.frames.len(), 10);
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn frame_count_mismatches_can_use_the_rows() {
+        let expected = test_util::sine_clip(10).motion.frames;
+        for &declared in [8, 12].iter() {
+            let (bvh, messages) = read_mismatched("frames-rows", 10, declared, &["--frame-count-mismatch", "rows"]);
+            let bvh = bvh.unwrap();
+            assert_eq!(bvh.motion.num_frames, 10);
+            assert_eq!(bvh.motion.frames, expected);
+            assert_eq!(messages.len(), 1);
+            assert!(messages[0].starts_with("warning: ") && messages[0].ends_with(&format!("the header says {} frames, but there are 10 rows of motion, using the rows", declared)), "{}", messages[0]);
+        }
+    }
+
+    #[test]
+    fn frame_count_mismatches_can_use_the_header() {
+        // Over-declared: the extra rows are dropped
+        let (bvh, messages) = read_mismatched("frames-header", 10, 8, &["--frame-count-mismatch", "header"]);
+        let bvh = bvh.unwrap();
+        assert_eq!(bvh.motion.num_frames, 8);
+        assert_eq!(bvh.motion.frames, test_util::sine_clip(8).motion.frames);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].ends_with("the header says 8 frames, but there are 10 rows of motion, dropping the last 2"), "{}", messages[0]);
+
+        // Under-declared: there's nothing to make up the missing rows with
+        match read_mismatched("frames-header-short", 8, 10, &["--frame-count-mismatch", "header"]).0 {
+            Err(MocapError::Parse(message)) => assert!(message.ends_with("the header says 10 frames, but there are 8 rows of motion, too few for --frame-count-mismatch header"), "{}", message),
+            other => panic!("{:?}", other.map(|bvh| bvh.motion.num_frames)),
+        }
+    }
+
+    #[test]
+    fn split_motion_files_reconcile_their_frame_count() {
+        let dir = test_util::temp_dir("frames-split");
+        let (hierarchy, motion) = (dir.join("hierarchy.bvh"), dir.join("motion.txt"));
+        fs::write(&hierarchy, test_util::HIERARCHY).unwrap();
+        let text = mismatched(10, 12);
+        fs::write(&motion, &text[text.find("MOTION").unwrap()..]).unwrap();
+
+        match read_split_bvh(&hierarchy, &motion, &test_util::options(&[])) {
+            Err(MocapError::Parse(message)) => assert!(message.contains("motion.txt: the header says 12 frames, but there are 10 rows of motion"), "{}", message),
+            other => panic!("{:?}", other.map(|(bvh, _)| bvh.motion.num_frames)),
+        }
+        let ((bvh, _), messages) = log::capture(|| read_split_bvh(&hierarchy, &motion, &test_util::options(&["--frame-count-mismatch", "rows"])).unwrap());
+        assert_eq!(bvh.motion.num_frames, 10);
+        assert_eq!(bvh.motion.frames.len(), 10);
+        assert_eq!(log::diagnostics(&messages).len(), 1);
+        assert!(read_split_bvh(&hierarchy, &motion, &test_util::options(&["--frame-count-mismatch", "header"])).is_err());
+
+        // A motion file without a header has nothing to disagree with
+        let rows = text.lines().skip_while(|line| !line.starts_with("Frame Time:")).skip(1).collect::<Vec<_>>().join("\n");
+        fs::write(&motion, rows).unwrap();
+        let bvh = read_split_bvh(&hierarchy, &motion, &test_util::options(&["--override-frame-time", "0.5"])).unwrap().0;
+        assert_eq!((bvh.motion.num_frames, bvh.motion.frames.len()), (10, 10));
+    }
+}
@@ -0,0 +1,101 @@
+use std::io::{self, Write};
+
+use json;
+use {Joint, JointChildren, Mocap};
+
+// The skeleton as a joint graph, for robotics tools that want the rig without parsing BVH (not
+// full URDF): every joint with its parent, offset from the parent and channels, in the same
+// pre-order walk as `Mocap::channel_map`'s `joint_index`, and every end site with the joint it
+// ends. A joint's offset is in its parent's frame; the root's is from the origin. Names are the
+// disambiguated ones (see `names::make_unique`), so they're unique, with the name in the input as
+// `original_name` where it differs.
+//
+//   {
+//     "joints": [
+//       { "index": 0, "name": "Hips", "parent": null, "offset": [0, 0, 0], "channels": [...] },
+//       ...
+//     ],
+//     "end_sites": [
+//       { "parent": 2, "offset": [0, 5, 0] },
+//       ...
+//     ]
+//   }
+
+#[derive(Debug)]
+pub struct GraphJoint {
+    pub name: String,
+    pub original_name: Option<String>,
+    pub parent: Option<usize>,
+    pub offset: (f32, f32, f32),
+    pub channels: Vec<&'static str>,
+}
+
+#[derive(Debug)]
+pub struct EndSite {
+    pub parent: usize,
+    pub offset: (f32, f32, f32),
+}
+
+impl Mocap {
+    pub fn joint_graph(&self) -> (Vec<GraphJoint>, Vec<EndSite>) {
+        let (mut joints, mut end_sites) = (Vec::new(), Vec::new());
+        push_joints(&self.root, None, &mut joints, &mut end_sites);
+        (joints, end_sites)
+    }
+}
+
+fn push_joints(joint: &Joint, parent: Option<usize>, joints: &mut Vec<GraphJoint>, end_sites: &mut Vec<EndSite>) {
+    let index = joints.len();
+    joints.push(GraphJoint {
+        name: joint.name.clone(),
+        original_name: joint.original_name.clone(),
+        parent: parent,
+        offset: joint.offset,
+        channels: joint.channels.iter().map(|channel| channel.type_.name()).collect(),
+    });
+
+    match joint.children {
+        JointChildren::Joints(ref children) => {
+            for child in children.iter() {
+                push_joints(child, Some(index), joints, end_sites);
+            }
+        }
+        JointChildren::EndSite(offset) => end_sites.push(EndSite {
+            parent: index,
+            offset: offset,
+        }),
+    }
+}
+
+pub fn write_json<W: Write>(joints: &[GraphJoint], end_sites: &[EndSite], w: &mut W) -> io::Result<()> {
+    writeln!(w, "{{")?;
+    writeln!(w, "  \"joints\": [")?;
+    for (index, joint) in joints.iter().enumerate() {
+        let separator = if index + 1 < joints.len() { "," } else { "" };
+        let original_name = match joint.original_name {
+            Some(ref original_name) => format!(", \"original_name\": \"{}\"", json::escape(original_name)),
+            None => String::new(),
+        };
+        let parent = match joint.parent {
+            Some(parent) => parent.to_string(),
+            None => "null".into(),
+        };
+        let channels = joint.channels.iter().map(|channel| format!("\"{}\"", channel)).collect::<Vec<_>>().join(", ");
+        writeln!(w, "    {{ \"index\": {}, \"name\": \"{}\"{}, \"parent\": {}, \"offset\": [{}, {}, {}], \"channels\": [{}] }}{}",
+            index,
+            json::escape(&joint.name),
+            original_name,
+            parent,
+            joint.offset.0, joint.offset.1, joint.offset.2,
+            channels,
+            separator)?;
+    }
+    writeln!(w, "  ],")?;
+    writeln!(w, "  \"end_sites\": [")?;
+    for (index, end_site) in end_sites.iter().enumerate() {
+        let separator = if index + 1 < end_sites.len() { "," } else { "" };
+        writeln!(w, "    {{ \"parent\": {}, \"offset\": [{}, {}, {}] }}{}", end_site.parent, end_site.offset.0, end_site.offset.1, end_site.offset.2, separator)?;
+    }
+    writeln!(w, "  ]")?;
+    writeln!(w, "}}")
+}
@@ -0,0 +1,203 @@
+// Just enough JSON support for our exports, which are all written by hand, and for reading
+// reports back.
+
+pub fn escape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+// Parsed JSON, for reading back reports (see report.rs). Objects keep their keys in order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Object(ref entries) => entries.iter().find(|entry| entry.0 == key).map(|entry| &entry.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Number(number) => Some(number),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match *self {
+            Value::Array(ref values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+// Errors give the byte offset they were found at.
+pub fn parse(s: &str) -> Result<Value, String> {
+    let mut parser = Parser { chars: s.chars().collect(), position: 0 };
+    let ret = parser.value()?;
+    parser.skip_whitespace();
+    if parser.position < parser.chars.len() {
+        return Err(parser.error("unexpected characters after the value"));
+    }
+    Ok(ret)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> String {
+        format!("{} at character {}", message, self.position)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.position).is_some_and(|c| c.is_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.chars.get(self.position) == Some(&c) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {}", c)))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.chars.get(self.position).cloned() {
+            Some('{') => {
+                self.position += 1;
+                let mut entries = Vec::new();
+                if !self.eat('}') {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        self.expect(':')?;
+                        entries.push((key, self.value()?));
+                        if self.eat('}') {
+                            break;
+                        }
+                        self.expect(',')?;
+                    }
+                }
+                Ok(Value::Object(entries))
+            }
+            Some('[') => {
+                self.position += 1;
+                let mut values = Vec::new();
+                if !self.eat(']') {
+                    loop {
+                        values.push(self.value()?);
+                        if self.eat(']') {
+                            break;
+                        }
+                        self.expect(',')?;
+                    }
+                }
+                Ok(Value::Array(values))
+            }
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let start = self.position;
+                while self.chars.get(self.position).is_some_and(|c| c.is_ascii_digit() || "+-.eE".contains(*c)) {
+                    self.position += 1;
+                }
+                let number = self.chars[start..self.position].iter().collect::<String>();
+                number.parse().map(Value::Number).map_err(|_| self.error(&format!("invalid number {}", number)))
+            }
+            _ => {
+                for (word, value) in [("null", Value::Null), ("true", Value::Bool(true)), ("false", Value::Bool(false))].iter() {
+                    if self.chars[self.position..].iter().take(word.len()).cloned().eq(word.chars()) {
+                        self.position += word.len();
+                        return Ok(value.clone());
+                    }
+                }
+                Err(self.error("expected a value"))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.chars.get(self.position) != Some(&'"') {
+            return Err(self.error("expected a string"));
+        }
+        self.position += 1;
+        let mut ret = String::new();
+        loop {
+            let c = *self.chars.get(self.position).ok_or_else(|| self.error("unterminated string"))?;
+            self.position += 1;
+            match c {
+                '"' => return Ok(ret),
+                '\\' => {
+                    let escaped = *self.chars.get(self.position).ok_or_else(|| self.error("unterminated string"))?;
+                    self.position += 1;
+                    ret.push(match escaped {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'u' => {
+                            let hex = self.chars.get(self.position..self.position + 4).ok_or_else(|| self.error("invalid escape"))?.iter().collect::<String>();
+                            self.position += 4;
+                            u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32).ok_or_else(|| self.error("invalid escape"))?
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    });
+                }
+                c => ret.push(c),
+            }
+        }
+    }
+}
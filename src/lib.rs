@@ -0,0 +1,2176 @@
+// The conversion pipeline and file formats as a library; the mocap binary (main.rs) runs its
+// command line through `run_command_line`.
+
+extern crate bvh;
+
+mod adjust;
+mod batch;
+mod bind;
+mod bitpack;
+mod cache;
+mod cancel;
+pub mod channel_map;
+mod clamp;
+mod concat;
+pub mod conversion;
+mod container;
+mod curves;
+mod dedupe;
+mod depth;
+mod directives;
+mod diff;
+mod dof;
+mod drift;
+mod dump;
+pub mod error;
+mod fixed_point;
+mod fk;
+mod frame_rate;
+mod gaps;
+mod ground;
+mod input;
+mod joint_graph;
+mod json;
+mod locomotion;
+mod log;
+mod looping;
+mod lossless;
+mod manifest;
+mod markers;
+mod mask;
+mod math;
+mod matrices;
+pub mod metrics;
+mod mocap_diff;
+mod names;
+mod options;
+mod outliers;
+mod overrides;
+mod patch;
+pub mod periodic;
+mod posematch;
+mod prediction;
+mod profile;
+mod quality;
+mod ranges;
+pub mod raw;
+mod reencode;
+pub mod report;
+mod resample;
+mod residual;
+mod root_motion;
+mod seek;
+mod selector;
+mod self_check;
+mod shell;
+mod skeleton_hash;
+mod smooth;
+mod subtree;
+mod sweep;
+mod targets;
+#[cfg(test)]
+mod test_util;
+mod texture;
+mod thumbnail;
+mod timewarp;
+mod timing;
+mod transitions;
+mod validate;
+mod variance;
+mod verify;
+pub mod view;
+mod vq;
+mod writer;
+
+use std::fs;
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::panic;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::thread;
+use std::time::Instant;
+
+use conversion::ConversionSettings;
+use error::MocapError;
+use options::{Command, Options};
+use view::{ChannelData, MocapView};
+
+#[derive(Debug, Clone)]
+pub struct Mocap {
+    num_frames: u32,
+    frame_time: f32,
+    channel_quantization_bits: u8, // Must be in [1, 8]
+    root: Joint,
+    metadata: Vec<(String, String)>, // Free-form provenance, e.g. header values we overrode
+    markers: Vec<markers::Marker>, // Sorted by frame
+    timestamps: Vec<f64>, // Per frame, in seconds; empty if the frames are frame_time apart (see timing.rs)
+}
+
+#[derive(Debug, Clone)]
+struct Joint {
+    name: String,
+    original_name: Option<String>, // Set when `name` was disambiguated; see `names::make_unique`
+    offset: (f32, f32, f32),
+    channels: Vec<Channel>,
+    children: JointChildren,
+}
+
+#[derive(Debug, Clone)]
+pub struct Channel {
+    type_: ChannelType,
+    reference: f64, // Added back to every reconstructed value; see `TranslationReference`
+    value_range_min: f32,
+    value_range: f32,
+    initial_level: u8, // The level the first delta is relative to
+    clamp: Option<(f64, f64)>, // Hard bounds on decoded values, see `profile`
+    anchor_level: Option<u8>, // For anchored channels, the level that decodes to exactly `reference`; see `RotationAnchor`
+    bits: Option<u8>, // Its own bit depth, where it isn't the clip's (see reencode.rs)
+    values: Option<Vec<f64>>, // The exact values of a lossless channel (see profile.rs), which has no deltas
+    deltas: Vec<i8>,
+}
+
+// The number of levels at `bits` bits per level, 2^bits, for the bit depths the format supports,
+// [1, 8]; None for any other rather than a shift that overflows (or at 0 bits a grid of one
+// level, with no spacing).
+fn num_levels(bits: u8) -> Option<u16> {
+    if (1..=8).contains(&bits) {
+        Some(1 << bits)
+    } else {
+        None
+    }
+}
+
+// The top level at `bits` bits, 2^bits - 1. Every bit depth is checked with `num_levels` where it
+// comes in (options, `raw::read`, `Mocap::validate`); an unchecked one saturates, 0 bits to level
+// 0 and more than 8 to 255, instead of overflowing.
+fn max_level(bits: u8) -> u8 {
+    num_levels(bits).map_or(if bits == 0 { 0 } else { u8::MAX }, |num_levels| (num_levels - 1) as u8)
+}
+
+impl Channel {
+    // The channel's bit depth in a clip quantized at `channel_quantization_bits`.
+    pub fn bits(&self, channel_quantization_bits: u8) -> u8 {
+        self.bits.unwrap_or(channel_quantization_bits)
+    }
+
+    // The quantization level closest to `value`, computed from the stored (f32) range so that
+    // anything decoding the channel arrives at exactly the same level. `channel_quantization_bits`
+    // is the clip's, here and in `value_of`; a channel with a bit depth of its own uses that.
+    pub fn level_of(&self, value: f64, channel_quantization_bits: u8) -> u8 {
+        let max_level = max_level(self.bits(channel_quantization_bits)) as f64;
+        if self.value_range > 0.0 {
+            let level = match self.anchor_level {
+                Some(anchor_level) => anchor_level as f64 + ((value - self.reference) / (self.value_range as f64)) * max_level,
+                None => ((value - self.reference - (self.value_range_min as f64)) / (self.value_range as f64)) * max_level,
+            };
+            level.round().max(0.0).min(max_level) as u8
+        } else {
+            self.anchor_level.unwrap_or(0)
+        }
+    }
+
+    // The value a level decodes to, before clamping. An anchored channel's anchor level decodes
+    // to exactly `reference`, whatever the rounding of the f32 range.
+    pub fn value_of(&self, level: u8, channel_quantization_bits: u8) -> f64 {
+        let max_level = max_level(self.bits(channel_quantization_bits)) as f64;
+        match self.anchor_level {
+            Some(anchor_level) => self.reference + (((level as f64) - (anchor_level as f64)) / max_level) * (self.value_range as f64),
+            None => self.reference + (self.value_range_min as f64) + ((level as f64) / max_level) * (self.value_range as f64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelType {
+    TranslationX,
+    TranslationY,
+    TranslationZ,
+    RotationX,
+    RotationY,
+    RotationZ,
+}
+
+impl ChannelType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ChannelType::TranslationX => "TranslationX",
+            ChannelType::TranslationY => "TranslationY",
+            ChannelType::TranslationZ => "TranslationZ",
+            ChannelType::RotationX => "RotationX",
+            ChannelType::RotationY => "RotationY",
+            ChannelType::RotationZ => "RotationZ",
+        }
+    }
+
+    pub const ALL: [ChannelType; 6] = [ChannelType::TranslationX, ChannelType::TranslationY, ChannelType::TranslationZ, ChannelType::RotationX, ChannelType::RotationY, ChannelType::RotationZ];
+
+    pub fn from_name(name: &str) -> Option<ChannelType> {
+        ChannelType::ALL.iter().cloned().find(|type_| type_.name() == name)
+    }
+
+    pub fn is_translation(&self) -> bool {
+        matches!(*self, ChannelType::TranslationX | ChannelType::TranslationY | ChannelType::TranslationZ)
+    }
+}
+
+fn channel_type(channel: &bvh::Channel) -> ChannelType {
+    match *channel {
+        bvh::Channel::XPosition => ChannelType::TranslationX,
+        bvh::Channel::YPosition => ChannelType::TranslationY,
+        bvh::Channel::ZPosition => ChannelType::TranslationZ,
+        bvh::Channel::XRotation => ChannelType::RotationX,
+        bvh::Channel::YRotation => ChannelType::RotationY,
+        bvh::Channel::ZRotation => ChannelType::RotationZ,
+    }
+}
+
+// What translation channels are stored relative to. Root translation usually hovers around a large
+// absolute value, so storing it relative to a reference keeps `value_range_min` small, which both
+// reads better in the exported metadata and avoids losing precision to its f32 representation.
+// Rotation channels always use a reference of 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TranslationReference {
+    None,
+    Offset,
+    Mean,
+}
+
+// What rotation channels' quantization grids are centered on. By default a channel's levels span
+// its range from min to max, so no level need fall on any particular angle; an anchored channel's
+// grid instead has a level that decodes to exactly the anchor (0 degrees, or the channel's value in
+// the first frame, its rest pose), so that pose reconstructs without error, which matters when
+// clips are blended additively at runtime. Anchored channels round to the nearest level rather
+// than truncating, so their error is at most half a (possibly slightly larger) step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RotationAnchor {
+    None,
+    Zero,
+    Rest,
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub channel_quantization_bits: u8, // Must be in [1, 8]
+    pub translation_reference: TranslationReference,
+    pub rotation_anchor: RotationAnchor,
+}
+
+#[derive(Debug, Clone)]
+enum JointChildren {
+    Joints(Vec<Joint>),
+    EndSite((f32, f32, f32)),
+}
+
+pub fn build_mocap(bvh: &bvh::Bvh, settings: &Settings) -> Mocap {
+    let mut channel_index = 0;
+
+    Mocap {
+        num_frames: bvh.motion.num_frames,
+        frame_time: bvh.motion.frame_time as _,
+        channel_quantization_bits: settings.channel_quantization_bits,
+        root: build_joint(&bvh.hierarchy.root, &bvh.motion.frames, &mut channel_index, settings),
+        metadata: Vec::new(),
+        markers: Vec::new(),
+        timestamps: Vec::new(),
+    }
+}
+
+fn build_joint(bvh_joint: &bvh::Joint, frames: &Vec<Vec<f64>>, channel_index: &mut usize, settings: &Settings) -> Joint {
+    let channel_quantization_bits = settings.channel_quantization_bits;
+
+    let mut channels = Vec::new();
+    for channel in bvh_joint.channels.iter() {
+        let mut values = Vec::new();
+        for frame in frames.iter() {
+            values.push(frame[*channel_index]);
+        }
+
+        let offset = match *channel {
+            bvh::Channel::XPosition => Some(bvh_joint.offset.x),
+            bvh::Channel::YPosition => Some(bvh_joint.offset.y),
+            bvh::Channel::ZPosition => Some(bvh_joint.offset.z),
+            _ => None,
+        };
+        let anchored = offset.is_none() && settings.rotation_anchor != RotationAnchor::None;
+        let reference = match (offset, settings.translation_reference) {
+            (Some(offset), TranslationReference::Offset) => offset,
+            (Some(_), TranslationReference::Mean) if !values.is_empty() => values.iter().sum::<f64>() / (values.len() as f64),
+            (None, _) if settings.rotation_anchor == RotationAnchor::Rest => values.first().cloned().unwrap_or(0.0),
+            _ => 0.0,
+        };
+        for value in values.iter_mut() {
+            *value -= reference;
+        }
+
+        // A constant channel, which includes every channel of a single-frame clip (a pose), has a
+        // range of 0: each frame is quantized to level 0 and stored as a delta of 0 from the
+        // initial level, and decodes to reference + value_range_min (to f32 precision). A clip
+        // without frames gets an empty range at 0.
+        let mut value_range_min = values.first().cloned().unwrap_or(0.0);
+        let mut value_range_max = value_range_min;
+        for value in values.iter() {
+            if *value < value_range_min {
+                value_range_min = *value;
+            }
+            if *value > value_range_max {
+                value_range_max = *value;
+            }
+        }
+        let mut value_range = value_range_max - value_range_min;
+        let mut anchor_level = None;
+        let values = if anchored {
+            // The anchor (0, relative to the reference) must be on the grid
+            let (level, range) = anchored_grid(value_range_min.min(0.0), value_range_max.max(0.0), channel_quantization_bits);
+            anchor_level = Some(level);
+            // Rounded up to f32, so the grid still reaches both ends
+            value_range = if (range as f32 as f64) < range { f32::from_bits((range as f32).to_bits() + 1) as f64 } else { range as f32 as f64 };
+            value_range_min = -((level as f64) / (max_level(channel_quantization_bits) as f64)) * value_range;
+            let channel = Channel {
+                type_: channel_type(channel),
+                reference: reference,
+                value_range_min: value_range_min as _,
+                value_range: value_range as _,
+                initial_level: 0,
+                clamp: None,
+                anchor_level: anchor_level,
+                bits: None,
+                values: None,
+                deltas: Vec::new(),
+            };
+            values.iter().map(|value| channel.level_of(value + reference, channel_quantization_bits)).collect::<Vec<_>>()
+        } else {
+            values.iter().map(|value| if value_range > 0.0 {
+                (((value - value_range_min) / value_range) * (max_level(channel_quantization_bits) as f64)) as u8
+            } else {
+                0
+            }).collect::<Vec<_>>()
+        };
+
+        let mut deltas = Vec::with_capacity(values.len());
+        let mut previous_value = 0;
+        for value in values.iter() {
+            let value = *value;
+
+            let delta = (value as i8).wrapping_sub(previous_value as i8);
+            deltas.push(delta);
+
+            previous_value = value;
+        }
+
+        channels.push(Channel {
+            type_: channel_type(channel),
+            reference: reference,
+            value_range_min: value_range_min as _,
+            value_range: value_range as _,
+            initial_level: 0,
+            clamp: None,
+            anchor_level: anchor_level,
+            bits: None,
+            values: None,
+            deltas: deltas,
+        });
+
+        *channel_index += 1;
+    }
+
+    Joint {
+        name: bvh_joint.name.clone(),
+        original_name: None,
+        offset: (bvh_joint.offset.x as _, bvh_joint.offset.y as _, bvh_joint.offset.z as _),
+        channels: channels,
+        children: match bvh_joint.children {
+            bvh::JointChildren::Joints(ref bvh_joints) => JointChildren::Joints(bvh_joints.iter().map(|joint| build_joint(joint, frames, channel_index, settings)).collect()),
+            bvh::JointChildren::EndSite(ref bvh_end_site) => JointChildren::EndSite((bvh_end_site.offset.x as _, bvh_end_site.offset.y as _, bvh_end_site.offset.z as _)),
+        },
+    }
+}
+
+impl Mocap {
+    // Every channel in flat (channel map) order.
+    pub fn channels(&self) -> Vec<&Channel> {
+        let mut ret = Vec::new();
+        collect_channels(&self.root, &mut ret);
+        ret
+    }
+
+    pub fn channels_mut(&mut self) -> Vec<&mut Channel> {
+        let mut ret = Vec::new();
+        collect_channels_mut(&mut self.root, &mut ret);
+        ret
+    }
+}
+
+fn collect_channels<'a>(joint: &'a Joint, channels: &mut Vec<&'a Channel>) {
+    channels.extend(joint.channels.iter());
+    if let JointChildren::Joints(ref joints) = joint.children {
+        for joint in joints.iter() {
+            collect_channels(joint, channels);
+        }
+    }
+}
+
+fn collect_channels_mut<'a>(joint: &'a mut Joint, channels: &mut Vec<&'a mut Channel>) {
+    channels.extend(joint.channels.iter_mut());
+    if let JointChildren::Joints(ref mut joints) = joint.children {
+        for joint in joints.iter_mut() {
+            collect_channels_mut(joint, channels);
+        }
+    }
+}
+
+pub fn build_bvh(mocap: &Mocap) -> bvh::Bvh {
+    let mut frames = Vec::new();
+    reconstruct_frames(mocap, &mut frames);
+
+    bvh::Bvh {
+        hierarchy: bvh::Hierarchy {
+            root: build_bvh_joint(&mocap.root),
+        },
+        motion: bvh::Motion {
+            num_frames: mocap.num_frames,
+            frame_time: frame_rate::recorded_frame_time(&mocap.metadata).unwrap_or(mocap.frame_time as _),
+            frames: frames,
+        },
+    }
+}
+
+fn build_bvh_joint(joint: &Joint) -> bvh::Joint {
+    let mut channels = Vec::new();
+    for channel in joint.channels.iter() {
+        channels.push(match channel.type_ {
+            ChannelType::TranslationX => bvh::Channel::XPosition,
+            ChannelType::TranslationY => bvh::Channel::YPosition,
+            ChannelType::TranslationZ => bvh::Channel::ZPosition,
+            ChannelType::RotationX => bvh::Channel::XRotation,
+            ChannelType::RotationY => bvh::Channel::YRotation,
+            ChannelType::RotationZ => bvh::Channel::ZRotation,
+        });
+    }
+
+    bvh::Joint {
+        name: joint.original_name.as_ref().unwrap_or(&joint.name).clone(),
+        offset: build_bvh_offset(&joint.offset),
+        channels: channels,
+        children: match joint.children {
+            JointChildren::Joints(ref joints) => bvh::JointChildren::Joints(joints.iter().map(build_bvh_joint).collect()),
+            JointChildren::EndSite(ref offset) => bvh::JointChildren::EndSite(bvh::EndSite {
+                offset: build_bvh_offset(offset),
+            }),
+        },
+    }
+}
+
+// The level and range of an anchored grid covering [min, max], which contains 0: levels are
+// spaced range / max level apart with the returned level at 0. The anchor level is placed where 0
+// falls in proportion, keeping at least one level on each side of it that has values.
+fn anchored_grid(min: f64, max: f64, channel_quantization_bits: u8) -> (u8, f64) {
+    let max_level = max_level(channel_quantization_bits) as f64;
+    if max <= min {
+        return (0, 0.0);
+    }
+
+    let mut level = (max_level * -min / (max - min)).round();
+    if max_level >= 2.0 {
+        if min < 0.0 {
+            level = level.max(1.0);
+        }
+        if max > 0.0 {
+            level = level.min(max_level - 1.0);
+        }
+    }
+    let step = f64::max(
+        if level > 0.0 { -min / level } else { 0.0 },
+        if level < max_level { max / (max_level - level) } else { 0.0 });
+    (level as u8, step * max_level)
+}
+
+// Reconstructs every frame of `mocap` into `frames`, reusing the buffer's existing allocations so
+// repeated decodes (e.g. a playback loop) don't allocate once the buffer has grown to size.
+//
+// On return `frames` has `num_frames` rows, and `frames[frame][channel_index]` is the dequantized
+// value of that channel. Channel indices are assigned the same way `build_mocap` consumes them: a
+// depth-first, pre-order walk of the hierarchy from the root, and within each joint the order of
+// its channels. This is exactly the layout of `bvh::Motion::frames`.
+pub fn reconstruct_frames(mocap: &Mocap, frames: &mut Vec<Vec<f64>>) {
+    frames.resize(mocap.num_frames as usize, Vec::new());
+    for frame in frames.iter_mut() {
+        frame.clear();
+    }
+
+    reconstruct_joint_frames(&mocap.root, frames, mocap.channel_quantization_bits);
+}
+
+fn reconstruct_joint_frames(joint: &Joint, frames: &mut Vec<Vec<f64>>, channel_quantization_bits: u8) {
+    for channel in joint.channels.iter() {
+        decode_channel(channel, channel_quantization_bits, |index, value| frames[index].push(value));
+    }
+
+    if let JointChildren::Joints(ref joints) = joint.children {
+        for joint in joints.iter() {
+            reconstruct_joint_frames(joint, frames, channel_quantization_bits);
+        }
+    }
+}
+
+// Calls `f` with the frame index and decoded value of every frame of a channel, whether its
+// deltas are owned or borrowed from a buffer (see view.rs), or it's lossless.
+fn decode_channel<C: ChannelData, F: FnMut(usize, f64)>(data: &C, channel_quantization_bits: u8, mut f: F) {
+    let channel = data.channel();
+    if let Some(ref values) = channel.values {
+        for (index, value) in values.iter().enumerate() {
+            f(index, *value);
+        }
+        return;
+    }
+    let mut previous_value = channel.initial_level;
+    let mut index = 0;
+    data.for_each_delta(|delta| {
+        let value = (previous_value as i8).wrapping_add(delta) as u8;
+        let mut reconstructed = channel.value_of(value, channel_quantization_bits);
+        if let Some((min, max)) = channel.clamp {
+            reconstructed = reconstructed.clamp(min, max);
+        }
+        f(index, reconstructed);
+
+        previous_value = value;
+        index += 1;
+    });
+}
+
+// `reconstruct_frames` for long clips, on `threads` threads, each decoding a run of channels
+// straight into the rows: a thread holds its run's slice of every row, so the threads write
+// disjoint columns and the result is exactly the serial one. The slices are the only allocation
+// past the rows themselves, a pointer and length per row per thread.
+fn decode_channels_parallel<C: ChannelData + Sync>(channels: &[C], num_frames: usize, channel_quantization_bits: u8, threads: usize, frames: &mut Vec<Vec<f64>>) {
+    frames.resize(num_frames, Vec::new());
+    for row in frames.iter_mut() {
+        row.clear();
+        row.resize(channels.len(), 0.0);
+    }
+
+    let chunk_size = channels.len().div_ceil(threads).max(1);
+    let mut runs = (0..channels.len().div_ceil(chunk_size)).map(|_| Vec::with_capacity(num_frames)).collect::<Vec<Vec<&mut [f64]>>>();
+    for row in frames.iter_mut() {
+        for (run, slice) in runs.iter_mut().zip(row.chunks_mut(chunk_size)) {
+            run.push(slice);
+        }
+    }
+    thread::scope(|scope| {
+        for (channels, mut run) in channels.chunks(chunk_size).zip(runs) {
+            scope.spawn(move || {
+                for (offset, channel) in channels.iter().enumerate() {
+                    decode_channel(channel, channel_quantization_bits, |index, value| run[index][offset] = value);
+                }
+            });
+        }
+    });
+}
+
+fn count_bvh_channels(joint: &bvh::Joint) -> usize {
+    joint.channels.len() + match joint.children {
+        bvh::JointChildren::Joints(ref joints) => joints.iter().map(count_bvh_channels).sum(),
+        bvh::JointChildren::EndSite(_) => 0,
+    }
+}
+
+// Whether each channel is a rotation, in flat channel order.
+fn rotation_channels(joint: &bvh::Joint) -> Vec<bool> {
+    let mut ret = joint.channels.iter().map(|channel| !channel_type(channel).is_translation()).collect::<Vec<_>>();
+    if let bvh::JointChildren::Joints(ref joints) = joint.children {
+        for joint in joints.iter() {
+            ret.extend(rotation_channels(joint));
+        }
+    }
+    ret
+}
+
+fn build_bvh_offset(offset: &(f32, f32, f32)) -> bvh::Offset {
+    bvh::Offset {
+        x: offset.0 as _,
+        y: offset.1 as _,
+        z: offset.2 as _,
+    }
+}
+
+// Runs the command line `args` (without the program name) as the mocap binary does, on a thread
+// with room for the deepest hierarchy allowed, stopping early on Ctrl-C.
+pub fn run_command_line<I: Iterator<Item = String>>(args: I) -> Result<(), MocapError> {
+    let options = Options::parse(args)?;
+    let cancel = cancel::handle_interrupts();
+    thread::Builder::new().stack_size(depth::stack_size(options.max_depth)).spawn(move || run(&options, Some(cancel)))?
+        .join().unwrap_or_else(|payload| panic::resume_unwind(payload))
+}
+
+// Runs the command with its outputs written atomically (see manifest.rs), and with --manifest,
+// records them and writes the manifest. Conversion and batch write their own, a batch's with an
+// entry per file.
+fn run(options: &Options, cancel: Option<&AtomicBool>) -> Result<(), MocapError> {
+    let manifest_file_name = match options.manifest_file_name {
+        Some(ref manifest_file_name) if !matches!(options.command, Command::Convert { .. } | Command::Batch { .. }) => manifest_file_name,
+        _ => return manifest::atomically(|| run_command(options, cancel)),
+    };
+    let (result, outputs) = manifest::record(|| manifest::atomically(|| run_command(options, cancel)));
+    let entry = manifest::Entry {
+        sources: options.command.input_file_names().into_iter().map(|name| name.into()).collect(),
+        outputs: outputs,
+        error: result.as_ref().err().map(|e| e.to_string()),
+        cached: false,
+    };
+    manifest::write(Path::new(manifest_file_name), options.command.name(), options, &[entry])?;
+    result
+}
+
+fn run_command(options: &Options, cancel: Option<&AtomicBool>) -> Result<(), MocapError> {
+    match options.command {
+        Command::Convert { ref input_file_name, ref output_file_name, ref csv_file_name, ref raw_file_name } => convert_file(Path::new(input_file_name), Path::new(output_file_name), Path::new(csv_file_name), Path::new(raw_file_name), options, cancel),
+        Command::Batch { ref input_dir, ref output_dir } => batch::run(Path::new(input_dir), Path::new(output_dir), options, cancel),
+        Command::Decode { ref input_file_name, ref output_file_name } => decode(Path::new(input_file_name), Path::new(output_file_name), options),
+        Command::Concat { ref output_file_name, ref input_file_names } => concat(Path::new(output_file_name), input_file_names, options, cancel),
+        Command::Pack { ref output_file_name, ref input_file_names } => pack(Path::new(output_file_name), input_file_names, options, cancel),
+        Command::Unpack { ref input_file_name, ref output_dir } => unpack(Path::new(input_file_name), Path::new(output_dir), options, cancel),
+        Command::Info { ref input_file_name } => info(Path::new(input_file_name), options),
+        Command::Dump { ref input_file_name } => dump::run(Path::new(input_file_name), options),
+        Command::Verify { ref input_file_names } => verify_files(input_file_names, options),
+        Command::Stats { ref input_file_names } => stats(input_file_names, options),
+        Command::DiffMocap { ref first_file_name, ref second_file_name } => read_clips(Path::new(first_file_name), &fs::read(first_file_name)?, options.max_depth)
+            .and_then(|first| mocap_diff::run(&first, &read_clips(Path::new(second_file_name), &fs::read(second_file_name)?, options.max_depth)?, options)),
+        Command::Reencode { ref input_file_name, ref output_file_name } => reencode::run(Path::new(input_file_name), Path::new(output_file_name), options, cancel),
+        Command::Diff { ref base_file_name, ref input_file_name, ref raw_file_name } => diff(Path::new(base_file_name), Path::new(input_file_name), Path::new(raw_file_name), options, cancel),
+        Command::Match { ref query_file_name, ref input_file_name } => match_pose(Path::new(query_file_name), Path::new(input_file_name), options),
+        Command::Transitions { ref first_file_name, ref second_file_name } => find_transitions(Path::new(first_file_name), Path::new(second_file_name), options),
+        Command::MakePatch { ref old_file_name, ref new_file_name, ref patch_file_name } => patch::make(Path::new(old_file_name), Path::new(new_file_name), Path::new(patch_file_name), options.max_depth),
+        Command::ApplyPatch { ref old_file_name, ref patch_file_name, ref new_file_name } => patch::apply(Path::new(old_file_name), Path::new(patch_file_name), Path::new(new_file_name)),
+        Command::Shell { ref input_file_name } => shell::run(Path::new(input_file_name), options),
+        Command::SweepBits { ref input_file_name } => load(Path::new(input_file_name), options).and_then(|source| sweep::run(&source.bvh, &source.conversion.settings(), options, cancel)),
+    }
+}
+
+// A parsed input after the passes that reshape it before quantization, plus what those passes
+// need to hand on to the resulting `Mocap`.
+struct Source {
+    bvh: bvh::Bvh,
+    original_names: HashMap<String, String>,
+    metadata: Vec<(String, String)>,
+    markers: Vec<markers::Marker>,
+    timestamps: Vec<f64>, // Per frame, with --timestamps
+    clamps: Vec<Option<(f64, f64)>>, // Per flat channel index
+    lossless: Vec<bool>, // Per flat channel index
+    ranges: Vec<Option<(f64, f64)>>, // Per flat channel index, the --ranges-in range if any
+    noise_floors: Vec<f64>, // Per flat channel index, before any smoothing
+    quality: quality::Quality,
+    conversion: ConversionSettings, // The options' conversion settings, with the input's directives and the profile's applied
+    locomotion: Option<locomotion::Locomotion>, // With --locomotion
+}
+
+impl Source {
+    fn build_mocap(&self, settings: &Settings) -> Mocap {
+        let mut mocap = build_mocap(&self.bvh, settings);
+        names::restore_original_names(&mut mocap.root, &self.original_names);
+        mocap.metadata = self.metadata.clone();
+        mocap.markers = self.markers.clone();
+        mocap.timestamps = self.timestamps.clone();
+        for (channel, clamp) in mocap.channels_mut().into_iter().zip(self.clamps.iter()) {
+            channel.clamp = *clamp;
+        }
+        ranges::apply(&mut mocap, &self.bvh.motion.frames, &self.ranges);
+        make_lossless(&mut mocap, &self.bvh.motion.frames, &self.lossless);
+        mocap
+    }
+}
+
+// Replaces the deltas of the channels flagged in `lossless` (in flat channel order) with their
+// exact values from `frames`.
+fn make_lossless(mocap: &mut Mocap, frames: &[Vec<f64>], lossless: &[bool]) {
+    for (index, (channel, lossless)) in mocap.channels_mut().into_iter().zip(lossless.iter()).enumerate() {
+        if *lossless {
+            channel.values = Some(frames.iter().map(|frame| frame[index]).collect());
+            channel.deltas = Vec::new();
+        }
+    }
+}
+
+fn load(input_file_name: &Path, options: &Options) -> Result<Source, MocapError> {
+    let (bvh, directives) = input::read_bvh_with_directives(input_file_name, options)?;
+    load_bvh(bvh, &directives, input_file_name, options)
+}
+
+// Runs every pass `load` does on an already-read clip.
+fn load_bvh(mut bvh: bvh::Bvh, directives: &directives::Directives, input_file_name: &Path, options: &Options) -> Result<Source, MocapError> {
+    // Every pass indexes frames by flat channel index
+    let num_channels = count_bvh_channels(&bvh.hierarchy.root);
+    if let Some(frame) = bvh.motion.frames.iter().position(|frame| frame.len() != num_channels) {
+        return Err(MocapError::Parse(format!("{}: frame {} has {} values, but the hierarchy has {} channels", input_file_name.display(), frame, bvh.motion.frames[frame].len(), num_channels)));
+    }
+    if options.dof_summary {
+        print_dof_summary(&input_file_name.display().to_string(), &bvh.hierarchy.root);
+    }
+    let profile = match options.profile_file_name {
+        Some(ref profile_file_name) => profile::Profile::read(Path::new(profile_file_name))?,
+        None => profile::Profile::default(),
+    };
+    // The command line's settings, then the file's directives, then the profile's
+    let mut conversion = options.conversion_builder.clone();
+    let applied = directives.apply(&mut conversion, options);
+    if !applied.is_empty() {
+        log::info(format!("{}: {} from the file's comments", input_file_name.display(), applied.join(", ")));
+    }
+    // The command line's settings alone were checked when parsing it, and directives can't make
+    // them invalid
+    let conversion = profile.conversion(&conversion, options.profile_file_name.as_deref().unwrap_or_default())?;
+    if options.strict && !options.lossy {
+        let mut lossy_operations = conversion.lossy_operations();
+        if !profile.clamps.is_empty() {
+            lossy_operations.push("clamping to the profile's clamp bounds".into());
+        }
+        if profile.adjustments.iter().any(adjust::Adjustment::discards_values) {
+            lossy_operations.push("zeroing channels".into());
+        }
+        if !lossy_operations.is_empty() {
+            return Err(MocapError::Usage(format!("{}: --strict: the following lossy operations require --lossy: {} (from the file's comments or the profile)", input_file_name.display(), lossy_operations.join(", "))));
+        }
+    }
+    let mut metadata = Vec::new();
+    let mut quality = quality::Quality::new(num_channels);
+    if let Some(ref detection) = options.repair_gaps {
+        let gaps = gaps::find(&bvh, detection);
+        let names = smooth::channel_names(&bvh.hierarchy.root);
+        log::info(format!("{}: {} gap{} to repair", input_file_name.display(), gaps.len(), if gaps.len() == 1 { "" } else { "s" }));
+        for gap in gaps.iter() {
+            log::info(format!("    {}: frames {}-{}, {}", names[gap.channel], gap.start, gap.end - 1, gap.describe(bvh.motion.frames.len())));
+        }
+        metadata.extend(gaps::repair(&mut bvh, &gaps, options.interpolation, &mut quality));
+    }
+    let mut markers = options.markers.clone();
+    if let Some(ref markers_file_name) = options.markers_file_name {
+        markers.extend(markers::read_file(Path::new(markers_file_name))?);
+    }
+    markers::sort(&mut markers);
+    let mut timestamps = match options.timestamps_file_name {
+        Some(ref timestamps_file_name) => {
+            let timestamps = timing::read_file(Path::new(timestamps_file_name))?;
+            if timestamps.len() != bvh.motion.frames.len() {
+                return Err(MocapError::InvalidTimestamps(format!("{}: {} timestamps for the {} frames of {}", timestamps_file_name, timestamps.len(), bvh.motion.frames.len(), input_file_name.display())));
+            }
+            timestamps
+        }
+        None => Vec::new(),
+    };
+    if let Some(frame_time) = options.override_frame_time {
+        metadata.extend(overrides::override_frame_time(&mut bvh, frame_time));
+    }
+    if let Some(max_frames) = options.max_frames {
+        metadata.extend(overrides::truncate(&mut bvh, max_frames));
+        markers::drop_past_end(&mut markers, max_frames, true);
+        timestamps.truncate(max_frames as usize);
+    }
+    if let Some(frame_time) = timing::frame_time(&timestamps) {
+        log::info(format!("{}: variable frame timing, resampled to a frame time of {} on BVH output", input_file_name.display(), frame_time));
+        bvh.motion.frame_time = frame_time;
+    }
+    if options.loop_trim {
+        match looping::find_period(&bvh, options.loop_tolerance) {
+            Some(period) => {
+                log::info(format!("{}: loops every {} frames, keeping {} of {}", input_file_name.display(), period, period, bvh.motion.num_frames));
+                metadata.extend(looping::trim_to_period(&mut bvh, period));
+                markers::drop_past_end(&mut markers, period as u32, true);
+            }
+            None => log::warning(format!("{}: doesn't loop within tolerance {}, not trimming", input_file_name.display(), options.loop_tolerance)),
+        }
+    }
+    markers::drop_past_end(&mut markers, bvh.motion.num_frames, false);
+    if let Some(ref curve) = options.timewarp {
+        metadata.extend(timewarp::apply(&mut bvh, &mut markers, curve)?);
+    }
+    if let Some(fps) = options.fps {
+        metadata.extend(resample::apply(&mut bvh, &mut markers, fps, options.interpolation)?);
+    }
+    if options.rational_fps {
+        match frame_rate::detect(bvh.motion.frame_time) {
+            Some(rate) => {
+                log::info(format!("{}: frame time {} stored as exactly {}", input_file_name.display(), bvh.motion.frame_time, frame_rate::describe(rate)));
+                bvh.motion.frame_time = frame_rate::frame_time(rate);
+                metadata.push(frame_rate::metadata(rate));
+            }
+            None => log::warning(format!("{}: frame time {} isn't close to a common or whole frame rate, keeping it as is", input_file_name.display(), bvh.motion.frame_time)),
+        }
+    }
+    let original_names = names::make_unique(&mut bvh.hierarchy.root, options.duplicate_names)?;
+    if let Some(ref root) = options.root {
+        let (subtree, sources) = subtree::select_root(bvh, root, options.bake_ancestors)?;
+        bvh = subtree;
+        quality.remap(&sources);
+    }
+    metadata.extend(adjust::apply(&mut bvh, &profile.adjustments.iter().chain(options.adjustments.iter()).cloned().collect::<Vec<_>>())?);
+    if options.snap_to_ground {
+        let ground = ground::detect(&bvh, options.up_axis);
+        log::info(format!("{}: up axis {}, floor height {}", input_file_name.display(), ground::axis_name(ground.up_axis), ground.floor_height));
+        ground::snap_to_ground(&mut bvh, &ground);
+    }
+    let noise_floors = smooth::noise_floors(&bvh);
+    quality.set_noise_floors(&noise_floors);
+    if let Some(filter) = conversion.smooth() {
+        smooth::apply(&mut bvh, filter);
+    }
+    if conversion.auto_smooth() {
+        let filters = noise_floors.iter().map(|noise_floor| smooth::auto_filter(*noise_floor, bvh.motion.frame_time)).collect::<Vec<_>>();
+        let smoothed = filters.iter().filter(|filter| filter.is_some()).count();
+        log::info(format!("{}: auto-smoothing {} of {} channels", input_file_name.display(), smoothed, filters.len()));
+        for ((name, noise_floor), filter) in smooth::channel_names(&bvh.hierarchy.root).iter().zip(noise_floors.iter()).zip(filters.iter()) {
+            if let Some(ref filter) = *filter {
+                log::info(format!("    {}: noise {:.6}, {}", name, noise_floor, smooth::spec(filter)));
+            }
+        }
+        smooth::apply_filters(&mut bvh, &filters);
+    }
+    let locomotion = if options.locomotion {
+        let locomotion = locomotion::analyze(&bvh, options.up_axis);
+        log::info(format!("{}: {}", input_file_name.display(), locomotion.describe()));
+        metadata.push(locomotion.metadata());
+        Some(locomotion)
+    } else {
+        None
+    };
+    let bind_pose = match conversion.bind_pose() {
+        Some(bind_pose) => Some(bind::pose(&bvh, bind_pose, options)?),
+        None => None,
+    };
+    if let Some(mask) = conversion.mask() {
+        metadata.extend(mask::apply(&mut bvh, mask, bind_pose.as_deref())?);
+    }
+    let mut clamps = clamp::apply(&mut bvh, &profile.clamps, &mut quality)?;
+    let lossless = lossless::lossless_channels(&bvh, conversion.lossless())?;
+    if conversion.root_motion() {
+        metadata.extend(root_motion::encode(&mut bvh, conversion.root_motion_anchors())?);
+        // The bounds are on absolute values
+        for index in root_motion::channels(&bvh.hierarchy.root).iter().flatten() {
+            clamps[*index] = None;
+        }
+    }
+    if let Some(pose) = bind_pose {
+        metadata.extend(bind::subtract(&mut bvh, &pose));
+        // The bounds are on absolute values
+        clamps.clear();
+    }
+
+    let ranges = match options.ranges_in_file_name {
+        Some(ref ranges_file_name) => ranges::assign(&ranges::read_file(Path::new(ranges_file_name))?, &bvh, &lossless, &conversion.settings(), &input_file_name.display().to_string(), &mut quality),
+        None => Vec::new(),
+    };
+
+    metadata.extend(quality.metadata(bvh.motion.num_frames));
+
+    Ok(Source {
+        bvh: bvh,
+        original_names: original_names,
+        metadata: metadata,
+        markers: markers,
+        timestamps: timestamps,
+        clamps: clamps,
+        lossless: lossless,
+        ranges: ranges,
+        noise_floors: noise_floors,
+        quality: quality,
+        conversion: conversion,
+        locomotion: locomotion,
+    })
+}
+
+// Single-file conversion, followed by the --manifest and --report records if asked for. Those are
+// written whether or not the conversion succeeds.
+fn convert_file(input_file_name: &Path, output_file_name: &Path, csv_file_name: &Path, raw_file_name: &Path, options: &Options, cancel: Option<&AtomicBool>) -> Result<(), MocapError> {
+    let conversion = || manifest::atomically(|| convert(input_file_name, output_file_name, csv_file_name, raw_file_name, options, cancel));
+    let ((result, messages), outputs) = manifest::record(|| if options.report_file_name.is_some() {
+        log::capture(conversion)
+    } else {
+        (conversion(), Vec::new())
+    });
+    for message in messages.iter() {
+        message.print();
+    }
+
+    if let Some(ref manifest_file_name) = options.manifest_file_name {
+        let entry = manifest::Entry {
+            sources: vec![input_file_name.into()],
+            outputs: outputs.clone(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+            cached: false,
+        };
+        manifest::write(Path::new(manifest_file_name), "convert", options, &[entry])?;
+    }
+    if let Some(ref report_file_name) = options.report_file_name {
+        let mut report = report::RunReport::new("convert", options);
+        report.files.push(report::FileReport::new(input_file_name, &outputs, result.as_ref().map(|conversion| conversion.clone()).map_err(|e| e.to_string()), false, log::diagnostics(&messages)));
+        write_report(&report, report_file_name, options)?;
+    }
+    result.map(|_| ())
+}
+
+// Writes a --report, and with --compare-report prints how it differs from the previous one
+// (read first, as it may be the same file). Fails with --fail-on-regression if anything regressed.
+fn write_report(report: &report::RunReport, report_file_name: &str, options: &Options) -> Result<(), MocapError> {
+    let previous = match options.compare_report_file_name {
+        Some(ref file_name) => Some(report::RunReport::read(Path::new(file_name))?),
+        None => None,
+    };
+    report.write(Path::new(report_file_name))?;
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return Ok(()),
+    };
+
+    let diff = report.diff(&previous);
+    let threshold = options.regression_threshold;
+    println!();
+    println!("compared to {}:", options.compare_report_file_name.as_ref().unwrap());
+    for file in diff.files.iter() {
+        println!("    {}", file.source.display());
+        for change in file.changes.iter() {
+            let relative_change = change.relative_change();
+            let relative_change = if relative_change.is_finite() { format!(" ({:+.1}%)", relative_change * 100.0) } else { String::new() };
+            println!("        {}: {} -> {}{}{}", change.what, change.previous, change.current, relative_change, if change.is_regression(threshold) { "  REGRESSION" } else { "" });
+        }
+    }
+    for source in diff.added.iter() {
+        println!("    {}: new", source.display());
+    }
+    for source in diff.removed.iter() {
+        println!("    {}: no longer converted", source.display());
+    }
+    let regressions = diff.num_regressions(threshold);
+    if diff.files.is_empty() && diff.added.is_empty() && diff.removed.is_empty() {
+        println!("    no changes");
+    } else {
+        println!("{} regression{} (threshold {}%)", regressions, if regressions == 1 { "" } else { "s" }, threshold * 100.0);
+    }
+
+    if options.fail_on_regression && regressions > 0 {
+        return Err(MocapError::Regressed(regressions));
+    }
+    Ok(())
+}
+
+// Returns the details a --report records. Cancelled with `cancel` (see cancel.rs).
+fn convert(input_file_name: &Path, output_file_name: &Path, csv_file_name: &Path, raw_file_name: &Path, options: &Options, cancel: Option<&AtomicBool>) -> Result<report::Conversion, MocapError> {
+    let mut timings = Vec::new();
+    let mut phase_start = Instant::now();
+    // Also where a cancelled conversion stops
+    let mut end_phase = |name: &'static str| {
+        timings.push((name, phase_start.elapsed().as_secs_f64()));
+        phase_start = Instant::now();
+        cancel::check(cancel)
+    };
+
+    let mut source = match options.hierarchy_file_name {
+        Some(ref hierarchy_file_name) => {
+            let (bvh, directives) = input::read_split_bvh(Path::new(hierarchy_file_name), input_file_name, options)?;
+            load_bvh(bvh, &directives, input_file_name, options)?
+        }
+        None => load(input_file_name, options)?,
+    };
+    end_phase("load")?;
+    let settings = if source.conversion.error_targets().is_empty() {
+        source.conversion.settings()
+    } else {
+        let (settings, groups) = targets::choose(&source, &source.conversion.settings(), source.conversion.error_targets());
+        log::info(format!("{}: {} bits for the error targets", input_file_name.display(), settings.channel_quantization_bits));
+        for group in groups.iter().filter(|group| group.num_channels > 0) {
+            log::info(format!("    {}: {} channels, max error {:.6}{}", group.name, group.num_channels, group.max_error, group.target.map_or(String::new(), |target| format!(" (target {})", target))));
+            if group.num_missed > 0 {
+                log::warning(format!("{}: {} {} channels miss the target of {} even at 8 bits", input_file_name.display(), group.num_missed, group.name, group.target.unwrap()));
+            }
+        }
+        settings
+    };
+    let mut mocap = source.build_mocap(&settings);
+    let stats = if options.channel_variance || options.stats_json_file_name.is_some() {
+        variance::channel_stats(&mocap)
+    } else {
+        Vec::new()
+    };
+    if options.channel_variance {
+        mocap.metadata.push(variance::metadata(&stats));
+    }
+    if cfg!(debug_assertions) {
+        mocap.validate()?;
+    }
+    mocap.validate_leaves(&source.bvh.hierarchy.root)?;
+    for query in options.error_queries.iter() {
+        let error = metrics::channel_error(&mocap, &source.bvh, &query.joint, query.channel_type, query.frame)?;
+        log::info(format!("error at {} {} frame {}: {:.6}", query.joint, query.channel_type.name(), query.frame, error));
+    }
+    if options.skeleton_hash {
+        log::info(format!("{}: skeleton hash {:032x}", input_file_name.display(), mocap.skeleton_hash()));
+    }
+    if let Some(threshold) = options.outlier_threshold {
+        let outliers = outliers::find(&mocap, threshold, options.outlier_units);
+        log::info(format!("{} outlier{} over {}", outliers.len(), if outliers.len() == 1 { "" } else { "s" }, threshold));
+        for outlier in outliers.iter() {
+            log::info(format!("    {} {} frame {}: {:+}", outlier.joint_name, outlier.channel, outlier.frame, outlier.change));
+        }
+    }
+    if let Some(count) = options.num_correlations {
+        let channel_map = mocap.channel_map();
+        let name = |index: usize| format!("{} {}", channel_map[index].joint_name, channel_map[index].channel_type.name());
+        log::info("strongest channel correlations:".into());
+        for correlation in prediction::correlations(&mocap).into_iter().take(count) {
+            log::info(format!("    {} ~ {}: {:+.4}", name(correlation.channels.0), name(correlation.channels.1), correlation.correlation));
+        }
+    }
+    end_phase("encode")?;
+    //println!("Result: {:#?}", mocap);
+
+    let (num_output_frames, output_markers) = write_bvh(&mocap, output_file_name, options)?;
+    if options.self_check {
+        self_check::check(&manifest::written(output_file_name), &source.bvh, &source.original_names, num_output_frames, options)?;
+    }
+    cancel::check(cancel)?;
+
+    {
+        let mut csv = manifest::create(csv_file_name)?;
+        if options.csv_by_channel_type {
+            dump_channels_csv_by_type(&mocap, &mut csv)?;
+        } else {
+            dump_channels_csv(&mocap.root, &mut csv)?;
+        }
+    }
+
+    cancel::check(cancel)?;
+    periodic::take_search_counts();
+    let encoding_search = match options.calibration_file_name {
+        Some(ref calibration_file_name) => {
+            let num_clamped = stream_raw(&source, &mocap, Path::new(calibration_file_name), raw_file_name, options)?;
+            for (index, num_clamped) in num_clamped.into_iter().enumerate().filter(|(_, num_clamped)| *num_clamped > 0) {
+                source.quality.record(index, quality::Event::Overflowed(num_clamped));
+            }
+            None
+        }
+        None => {
+            let predicted = if options.predict_channels {
+                let (predicted, predictions) = prediction::encode(&mocap);
+                let channel_map = mocap.channel_map();
+                let name = |index: usize| format!("{} {}", channel_map[index].joint_name, channel_map[index].channel_type.name());
+                log::info(format!("{}: {} channel{} predicted from a partner", input_file_name.display(), predictions.len(), if predictions.len() == 1 { "" } else { "s" }));
+                for prediction in predictions.iter() {
+                    log::info(format!("    {} from {}: slope {}, intercept {}", name(prediction.channel), name(prediction.partner), prediction.slope, prediction.intercept));
+                }
+                Some(predicted)
+            } else {
+                None
+            };
+            let stored = predicted.as_ref().unwrap_or(&mocap);
+            let mut raw = manifest::create(raw_file_name)?;
+            if let Some(block_frames) = source.conversion.indexed_block_frames() {
+                let layout = if source.conversion.bit_planes() { bitpack::Layout::BitPlanes } else { bitpack::Layout::Packed };
+                raw::write_indexed(stored, block_frames, layout, options.search_limits(cancel), &mut raw)?;
+            } else if source.conversion.sparse() {
+                raw::write_sparse(stored, options.search_limits(cancel), &mut raw)?;
+            } else if source.conversion.bit_planes() {
+                raw::write_bit_planes(stored, options.search_limits(cancel), &mut raw)?;
+            } else {
+                raw::write(stored, options.search_limits(cancel), &mut raw)?;
+            }
+            if raw::is_static(&mocap) {
+                log::info(format!("{}: a static pose (no channel changes), stored as a single frame for {} frames", input_file_name.display(), mocap.num_frames));
+            }
+            let counts = periodic::take_search_counts();
+            if options.time_budget.is_some() {
+                log::info(format!("{}: {} channels searched for periodic encodings, {} past the time budget only checked for being constant", input_file_name.display(), counts.searched, counts.fell_back));
+            }
+            options.time_budget.map(|_| counts)
+        }
+    };
+
+    if let Some(ref ranges_file_name) = options.ranges_out_file_name {
+        ranges::write_file(&ranges::of(&mocap), Path::new(ranges_file_name))?;
+    }
+
+    if let Some(ref vq_file_name) = options.vq_file_name {
+        let vq = vq::encode(&mocap, &source.bvh.motion.frames, options.vq_codebook_size, &settings);
+        let mut encoded = Vec::new();
+        vq::write(&vq, options.search_limits(cancel), &mut encoded)?;
+        manifest::create(vq_file_name)?.write_all(&encoded)?;
+
+        let raw_size = fs::metadata(raw_file_name)?.len();
+        let error = metrics::reconstruction_error(&source.bvh.motion.frames, &vq::decode(&vq).motion.frames);
+        log::info(format!("vq: {} codebook entries, {} bytes ({:.2}x smaller than the raw file's {}), max error {:.6}, rms error {:.6}",
+            vq.codebook.num_frames, encoded.len(), raw_size as f64 / encoded.len() as f64, raw_size, error.max, error.rms));
+    }
+
+    if let Some(ref local_matrices_file_name) = options.local_matrices_file_name {
+        let mut output = BufWriter::new(manifest::create(local_matrices_file_name)?);
+        matrices::write_local(&build_bvh(&mocap), &mut output)?;
+    }
+
+    if let Some(ref world_matrices_file_name) = options.world_matrices_file_name {
+        let mut output = BufWriter::new(manifest::create(world_matrices_file_name)?);
+        matrices::write_world(&build_bvh(&mocap), &mut output)?;
+    }
+
+    if let Some(ref texture_file_name) = options.texture_file_name {
+        let mut output = BufWriter::new(manifest::create(texture_file_name)?);
+        texture::write(&mocap, options.texture_pow2, &mut output)?;
+        output.flush()?;
+    }
+
+    if let Some(ref fixed_point_file_name) = options.fixed_point_file_name {
+        let formats = fixed_point::formats(&mocap, options.fixed_point_precision)?;
+        let mut output = BufWriter::new(manifest::create(fixed_point_file_name)?);
+        fixed_point::write_header(&mocap, &formats, &mut output)?;
+        output.flush()?;
+        let num_16 = formats.iter().filter(|format| format.word_bits == 16).count();
+        log::info(format!("fixed point: {} channels in 16 bits, {} in 32 bits, max error {}", num_16, formats.len() - num_16, formats.iter().map(|format| format.max_error).fold(0.0, f64::max)));
+    }
+
+    if let Some(ref deltas_bvh_file_name) = options.deltas_bvh_file_name {
+        serialize_bvh(&residual::delta_bvh(&mocap), Path::new(deltas_bvh_file_name), options)?;
+    }
+
+    if let Some(ref curves_file_name) = options.curves_file_name {
+        let mut bvh = build_bvh(&mocap);
+        root_motion::decode(&mut bvh, &mocap.metadata)?;
+        let curves = curves::fit(&bvh, options.curve_tolerance);
+        let mut output = BufWriter::new(manifest::create(curves_file_name)?);
+        if Path::new(curves_file_name).extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json")) {
+            curves::write_json(&curves, &mocap.channel_map(), bvh.motion.frame_time, options.curve_tolerance, &mut output)?;
+        } else {
+            curves::write_binary(&curves, bvh.motion.frame_time, &mut output)?;
+        }
+        output.flush()?;
+        log::info(format!("curves: {} knots for {} frames of {} channels, max error {:.6}", curves.iter().map(|curve| curve.knots.len()).sum::<usize>(), bvh.motion.frames.len(), curves.len(), curves::max_error(&bvh, &curves)));
+    }
+
+    if let Some(ref markers_file_name) = options.export_markers_file_name {
+        let mut output = manifest::create(markers_file_name)?;
+        markers::write_json(&mocap.markers, mocap.frame_time, &mut output)?;
+    }
+    if let Some(ref markers_file_name) = options.save_markers_file_name {
+        markers::write_file(&output_markers, Path::new(markers_file_name))?;
+    }
+
+    if let Some(ref channel_map_file_name) = options.channel_map_file_name {
+        let mut output = manifest::create(channel_map_file_name)?;
+        channel_map::write_json(&mocap.channel_map(), &mut output)?;
+    }
+    if let Some(ref stats_json_file_name) = options.stats_json_file_name {
+        let mut output = manifest::create(stats_json_file_name)?;
+        variance::write_json(&mocap, &stats, &mut output)?;
+    }
+    if let Some(ref joint_graph_file_name) = options.joint_graph_file_name {
+        let mut output = manifest::create(joint_graph_file_name)?;
+        let (joints, end_sites) = mocap.joint_graph();
+        joint_graph::write_json(&joints, &end_sites, &mut output)?;
+    }
+    end_phase("write")?;
+
+    let channel_map = mocap.channel_map();
+    let (reconstruction_error, joint_errors) = if options.report_file_name.is_some() {
+        let reconstructed = build_bvh(&mocap).motion.frames;
+        // Joints without channels get no entry
+        let joints = channel_map.iter().map(|descriptor| descriptor.joint_index).collect::<Vec<_>>();
+        let joint_errors = metrics::grouped_reconstruction_errors(&source.bvh.motion.frames, &reconstructed, &joints).into_iter().enumerate()
+            .filter_map(|(index, error)| channel_map.iter().find(|descriptor| descriptor.joint_index == index).map(|descriptor| (descriptor.joint_name.clone(), error)))
+            .collect();
+        (Some(metrics::reconstruction_error(&source.bvh.motion.frames, &reconstructed)), joint_errors)
+    } else {
+        (None, Vec::new())
+    };
+    Ok(report::Conversion {
+        channels: channel_map.into_iter().zip(mocap.channels()).zip(source.noise_floors.iter()).zip(source.quality.channels.iter()).map(|(((descriptor, channel), noise_floor), quality)| report::ChannelReport {
+            joint: descriptor.joint_name,
+            type_: descriptor.channel_type,
+            bits: if channel.values.is_some() { 64 } else { mocap.channel_quantization_bits },
+            noise_floor: Some(*noise_floor),
+            quality: Some(*quality),
+            score: Some(quality.score(mocap.num_frames)),
+        }).collect(),
+        reconstruction_error: reconstruction_error,
+        joint_errors: joint_errors,
+        encoding_search: encoding_search,
+        locomotion: source.locomotion,
+        timings: timings,
+    })
+}
+
+// Writes the raw file one frame at a time with `writer::MocapWriter`, quantizing with the channel
+// ranges of a calibration clip instead of the input's own.
+// Returns the samples clamped to the calibration ranges, per flat channel index.
+fn stream_raw(source: &Source, mocap: &Mocap, calibration_file_name: &Path, raw_file_name: &Path, options: &Options) -> Result<Vec<u64>, MocapError> {
+    let calibration = load(calibration_file_name, options)?;
+    let mut ranges: Vec<(f64, f64)> = match calibration.bvh.motion.frames.first() {
+        Some(frame) => frame.iter().map(|value| (*value, *value)).collect(),
+        None => return Err(MocapError::Usage(format!("{}: calibration clip has no frames", calibration_file_name.display()))),
+    };
+    for frame in calibration.bvh.motion.frames.iter() {
+        for (range, value) in ranges.iter_mut().zip(frame.iter()) {
+            *range = (range.0.min(*value), range.1.max(*value));
+        }
+    }
+
+    let output = BufWriter::new(manifest::create(raw_file_name)?);
+    let block_frames = source.conversion.indexed_block_frames();
+    let mut writer = writer::MocapWriter::new(output, &mocap.root, source.bvh.motion.frame_time, &source.conversion.settings(), &ranges, block_frames.unwrap_or(source.conversion.block_frames()))?;
+    if block_frames.is_some() {
+        writer.enable_seek_index();
+    }
+    for frame in source.bvh.motion.frames.iter() {
+        writer.push_frame(frame)?;
+    }
+    let (_, stats) = writer.finish()?;
+    log::info(format!("streamed {} frames, {} samples clamped to the calibration ranges", stats.num_frames, stats.num_clamped.iter().sum::<u64>()));
+
+    Ok(stats.num_clamped)
+}
+
+// One frame of a clip, with the root motion integrated back if it has any: from the span of
+// frames between the anchors around it.
+fn decode_frame(view: &MocapView, frame: usize) -> Result<Vec<f64>, MocapError> {
+    let header = view.header();
+    match root_motion::span(&header.metadata, frame, header.num_frames as usize)? {
+        Some((start, end)) if frame < header.num_frames as usize => {
+            let mut frames = (start..=end).map(|frame| view.decode_frame_at(frame)).collect::<Result<Vec<_>, _>>()?;
+            root_motion::decode_span(&build_bvh_joint(&header.root), &mut frames, start, header.num_frames as usize, &header.metadata)?;
+            Ok(frames.swap_remove(frame - start))
+        }
+        _ => Ok(view.decode_frame_at(frame)?),
+    }
+}
+
+fn decode(input_file_name: &Path, output_file_name: &Path, options: &Options) -> Result<(), MocapError> {
+    let data = fs::read(input_file_name)?;
+    let (mut bvh, metadata, mut markers, timestamps) = if data.starts_with(vq::MAGIC) {
+        if options.frame.is_some() {
+            return Err(MocapError::Usage(format!("{}: decode --frame doesn't apply to vector-quantized files", input_file_name.display())));
+        }
+        let vq = vq::read(&data, options.max_depth)?;
+        (vq::decode(&vq), vq.codebook.metadata, Vec::new(), Vec::new())
+    } else {
+        let view = MocapView::parse(&data, options.max_depth)?;
+        let header = view.header();
+        match options.frame {
+            // Just that frame, with the markers on it
+            Some(frame) => (bvh::Bvh {
+                hierarchy: bvh::Hierarchy {
+                    root: build_bvh_joint(&header.root),
+                },
+                motion: bvh::Motion {
+                    num_frames: 1,
+                    frame_time: header.frame_time as _,
+                    frames: vec![decode_frame(&view, frame as usize)?],
+                },
+            }, header.metadata.clone(), header.markers.iter().filter(|marker| marker.0 == frame).map(|marker| (0, marker.1.clone())).collect(), Vec::new()),
+            None => (view.to_bvh(options.decode_threads), header.metadata.clone(), header.markers.clone(), header.timestamps.clone()),
+        }
+    };
+    if let Some(frame_time) = frame_rate::recorded_frame_time(&metadata) {
+        bvh.motion.frame_time = frame_time;
+    }
+    let diff_base = metadata.iter().find(|entry| entry.0 == diff::BASE_KEY).map(|entry| entry.1.clone());
+    match (&options.base_file_name, diff_base) {
+        (Some(base_file_name), Some(_)) => diff::add(&mut bvh, &load(Path::new(base_file_name), options)?.bvh)?,
+        (Some(_), None) => return Err(MocapError::Usage(format!("{}: not a diff, --base doesn't apply", input_file_name.display()))),
+        (None, Some(diff_base)) => log::warning(format!("{}: a difference from {}; decoding it without --base gives just the difference", input_file_name.display(), diff_base)),
+        (None, None) => (),
+    }
+    if options.frame.is_none() {
+        root_motion::decode(&mut bvh, &metadata)?;
+    }
+    if options.add_bind_pose && !bind::add(&mut bvh, &metadata)? {
+        return Err(MocapError::Usage(format!("{}: not converted relative to a bind pose, --add-bind-pose doesn't apply", input_file_name.display())));
+    }
+    if options.unroll_loop && !looping::unroll(&mut bvh, &metadata) {
+        log::warning(format!("{}: not a trimmed loop, nothing to unroll", input_file_name.display()));
+    }
+    match options.save_timestamps_file_name {
+        Some(ref timestamps_file_name) if !timestamps.is_empty() => timing::write_file(&timestamps, Path::new(timestamps_file_name))?,
+        Some(_) if options.frame.is_none() => return Err(MocapError::Usage(format!("{}: has no timestamps, --save-timestamps doesn't apply", input_file_name.display()))),
+        Some(_) => return Err(MocapError::Usage("decode --frame and --save-timestamps don't apply together".into())),
+        None if !timestamps.is_empty() => timing::resample(&mut bvh, &timestamps, &mut markers, options.interpolation),
+        None => (),
+    }
+    if let Some(frame) = options.pose_frame {
+        keep_pose_frame(&mut bvh, &mut markers, frame, output_file_name)?;
+    }
+    if let Some(ref markers_file_name) = options.save_markers_file_name {
+        markers::write_file(&markers, Path::new(markers_file_name))?;
+    }
+    serialize_bvh(&bvh, output_file_name, options)
+}
+
+// Stores an edited clip as its difference from a base clip (see diff.rs). Both go through the
+// usual input passes first. Clamp bounds from a profile aren't kept, as they bound the edited
+// values rather than the difference.
+fn diff(base_file_name: &Path, input_file_name: &Path, raw_file_name: &Path, options: &Options, cancel: Option<&AtomicBool>) -> Result<(), MocapError> {
+    let base = load(base_file_name, options)?;
+    let mut source = load(input_file_name, options)?;
+    diff::subtract(&mut source.bvh, &base.bvh)?;
+    source.clamps.clear();
+    source.metadata.push((diff::BASE_KEY.into(), base_file_name.display().to_string()));
+
+    let mocap = source.build_mocap(&source.conversion.settings());
+    if cfg!(debug_assertions) {
+        mocap.validate()?;
+    }
+    mocap.validate_leaves(&source.bvh.hierarchy.root)?;
+    let mut output = manifest::create(raw_file_name)?;
+    raw::write(&mocap, options.search_limits(cancel), &mut output)?;
+
+    let channels = mocap.channels();
+    println!("{} of {} channels unchanged from the base", channels.iter().filter(|channel| channel.value_range == 0.0 && channel.reference + channel.value_range_min as f64 == 0.0).count(), channels.len());
+    Ok(())
+}
+
+// Prints the frames of `input_file_name` nearest to the --frame of `query_file_name`.
+fn match_pose(query_file_name: &Path, input_file_name: &Path, options: &Options) -> Result<(), MocapError> {
+    let query = load(query_file_name, options)?.bvh;
+    let input = load(input_file_name, options)?.bvh;
+    if let Some(mismatch) = diff::skeleton_mismatch(&query.hierarchy.root, &input.hierarchy.root, "") {
+        return Err(MocapError::SkeletonMismatch(format!("{}: the skeleton differs from {}'s: {}", input_file_name.display(), query_file_name.display(), mismatch)));
+    }
+    let frame = options.frame.unwrap();
+    let pose = query.motion.frames.get(frame as usize)
+        .ok_or_else(|| MocapError::Usage(format!("{} has {} frames, there's no frame {}", query_file_name.display(), query.motion.frames.len(), frame)))?;
+
+    let matches = posematch::find_nearest_poses(&input, pose, options.match_metric, options.num_matches);
+    println!("frames of {} nearest to frame {} of {}:", input_file_name.display(), frame, query_file_name.display());
+    for (index, distance) in matches.iter() {
+        println!("    {:>6}  {:.6}", index, distance);
+    }
+    Ok(())
+}
+
+// Prints the best points to cut from `first_file_name` to `second_file_name`, and with
+// --emit-blended writes the two stitched at the best one.
+fn find_transitions(first_file_name: &Path, second_file_name: &Path, options: &Options) -> Result<(), MocapError> {
+    let first = load(first_file_name, options)?.bvh;
+    let second = load(second_file_name, options)?.bvh;
+    if let Some(mismatch) = diff::skeleton_mismatch(&first.hierarchy.root, &second.hierarchy.root, "") {
+        return Err(MocapError::SkeletonMismatch(format!("{}: the skeleton differs from {}'s: {}", second_file_name.display(), first_file_name.display(), mismatch)));
+    }
+    for (file_name, bvh) in [(first_file_name, &first), (second_file_name, &second)].iter() {
+        if bvh.motion.frames.len() < 2 {
+            return Err(MocapError::Usage(format!("{} has {} frames, transitions needs at least 2", file_name.display(), bvh.motion.frames.len())));
+        }
+    }
+    if first.motion.frame_time != second.motion.frame_time {
+        log::warning(format!("{} has frame time {} but {} has {}; blend lengths use the first", first_file_name.display(), first.motion.frame_time, second_file_name.display(), second.motion.frame_time));
+    }
+
+    let candidates = transitions::find(&first, &second, options.transition_stride, options.num_transitions);
+    println!("transitions from {} to {}, best first:", first_file_name.display(), second_file_name.display());
+    println!("    {:>6}  {:>6}  {:>12}  {:>12}  {:>12}  {:>6}", "from", "to", "score", "pose", "velocity", "blend");
+    for candidate in candidates.iter() {
+        println!("    {:>6}  {:>6}  {:>12.6}  {:>12.6}  {:>12.6}  {:>6}", candidate.frame_a, candidate.frame_b, candidate.score, candidate.pose_distance, candidate.velocity_mismatch, candidate.blend_frames);
+    }
+
+    if let Some(ref emit_blended_file_name) = options.emit_blended_file_name {
+        let best = &candidates[0];
+        let stitched = transitions::stitch(first, &second, best);
+        println!("writing {}: {} frames, cutting at frames {} -> {} and blending over {} frames", emit_blended_file_name, stitched.motion.frames.len(), best.frame_a, best.frame_b, best.blend_frames);
+        serialize_bvh(&stitched, Path::new(emit_blended_file_name), options)?;
+    }
+    Ok(())
+}
+
+fn concat(output_file_name: &Path, input_file_names: &[String], options: &Options, cancel: Option<&AtomicBool>) -> Result<(), MocapError> {
+    let mut mocap = raw::read(&fs::read(&input_file_names[0])?, options.max_depth)?;
+    let mut settings = options.conversion.settings();
+    settings.channel_quantization_bits = mocap.channel_quantization_bits;
+    let hierarchy = build_bvh_joint(&mocap.root);
+
+    for input_file_name in input_file_names[1..].iter() {
+        cancel::check(cancel)?;
+        let other = raw::read(&fs::read(input_file_name)?, options.max_depth)?;
+        let path = concat::append(&mut mocap, &other, options.quantized_append, &settings)
+            .map_err(|e| match e {
+                MocapError::SkeletonMismatch(message) => MocapError::SkeletonMismatch(format!("{}: {}", input_file_name, message)),
+                e => e,
+            })?;
+        println!("{}: {}", input_file_name, match path {
+            concat::AppendPath::Quantized => "appended in the quantized domain",
+            concat::AppendPath::Requantized => "appended by re-quantization",
+        });
+    }
+
+    if cfg!(debug_assertions) {
+        mocap.validate()?;
+    }
+    mocap.validate_leaves(&hierarchy)?;
+    let mut output = manifest::create(output_file_name)?;
+    raw::write(&mocap, options.search_limits(cancel), &mut output)?;
+
+    Ok(())
+}
+
+fn pack(output_file_name: &Path, input_file_names: &[String], options: &Options, cancel: Option<&AtomicBool>) -> Result<(), MocapError> {
+    let mut container = container::Container::default();
+    // Sums of the first-frame delta magnitudes over every clip, without and with reference poses
+    let mut first_deltas = (0, 0);
+    let mut stored_clips = dedupe::Index::default();
+    let mut skipped = Vec::new();
+
+    for input_file_name in input_file_names.iter() {
+        cancel::check(cancel)?;
+        let input_file_name = Path::new(input_file_name);
+        let name = input_file_name.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        if container.clips.iter().any(|clip| clip.name == name) || skipped.contains(&name) {
+            return Err(MocapError::Usage(format!("{}: there's already a clip named {}", input_file_name.display(), name)));
+        }
+
+        let source = load(input_file_name, options)?;
+        let mut mocap = source.build_mocap(&source.conversion.settings());
+        let fingerprint = dedupe::fingerprint(&mocap, options.dedupe_tolerance)?;
+        let mut alias = None;
+        if let Some(duplicate) = stored_clips.find(&fingerprint, options.dedupe_tolerance) {
+            let original = &container.clips[duplicate.clip];
+            let description = match duplicate.distance {
+                Some(distance) => format!("{}: near-duplicate of clip {} (pose distance up to {})", name, original.name, distance),
+                None => format!("{}: duplicate of clip {}", name, original.name),
+            };
+            match options.dedupe_clips {
+                dedupe::Policy::Warn => println!("{}", description),
+                dedupe::Policy::Skip => {
+                    println!("{}, skipped", description);
+                    skipped.push(name);
+                    continue;
+                }
+                dedupe::Policy::Alias => {
+                    println!("{}, stored as an alias of it", description);
+                    alias = Some(duplicate.clip);
+                }
+            }
+        }
+        first_deltas.0 += first_delta_magnitude(&mocap);
+
+        let mut reference_pose = None;
+        if let Some(index) = alias {
+            let original = &container.clips[index];
+            mocap = original.mocap.clone();
+            reference_pose = original.reference_pose;
+        } else if options.reference_pose {
+            if let Some(pose) = source.bvh.motion.frames.first() {
+                let index = container.share_reference_pose(pose, options.reference_tolerance);
+                container::apply_reference_pose(&mut mocap, &container.reference_poses[index]);
+                reference_pose = Some(index);
+            }
+        }
+        first_deltas.1 += first_delta_magnitude(&mocap);
+        let thumbnail = match options.thumbnail {
+            Some(policy) => pack_thumbnail(&mocap, &name, policy, options)?,
+            None => None,
+        };
+
+        if cfg!(debug_assertions) {
+            mocap.validate()?;
+        }
+        mocap.validate_leaves(&source.bvh.hierarchy.root)?;
+        if alias.is_none() {
+            stored_clips.insert(container.clips.len(), fingerprint);
+        }
+        container.clips.push(container::Clip {
+            name: name,
+            reference_pose: reference_pose,
+            attributes: Vec::new(),
+            thumbnail: thumbnail,
+            alias: alias,
+            mocap: mocap,
+        });
+    }
+
+    for (clip_name, attributes) in options.clip_attributes.iter() {
+        if skipped.contains(clip_name) {
+            return Err(MocapError::Usage(format!("--clip-attr: clip {} was skipped as a duplicate", clip_name)));
+        }
+        let clip = container.clips.iter_mut().find(|clip| clip.name == *clip_name)
+            .ok_or_else(|| MocapError::Usage(format!("--clip-attr: there's no clip named {}", clip_name)))?;
+        for (key, value) in attributes.iter() {
+            clip.set_attribute(key, value)?;
+        }
+    }
+
+    let mut output = BufWriter::new(manifest::create(output_file_name)?);
+    container::write(&container, options.search_limits(cancel), &mut output)?;
+
+    if options.reference_pose {
+        let pose_size = |pose: &Vec<f64>| 4 + pose.len() * 8;
+        let unshared_size: usize = container.clips.iter().filter_map(|clip| clip.reference_pose).map(|index| pose_size(&container.reference_poses[index])).sum();
+        let shared_size: usize = container.reference_poses.iter().map(pose_size).sum();
+        println!("{} clips, {} reference poses stored ({} bytes saved by sharing)", container.clips.len(), container.reference_poses.len(), unshared_size - shared_size);
+        println!("first-frame delta magnitude: {} from 0, {} from reference poses", first_deltas.0, first_deltas.1);
+    }
+
+    Ok(())
+}
+
+// The thumbnail of a clip being packed (see thumbnail.rs), written out with --export-thumbnails.
+fn pack_thumbnail(mocap: &Mocap, name: &str, policy: thumbnail::Policy, options: &Options) -> Result<Option<container::Thumbnail>, MocapError> {
+    let mut bvh = build_bvh(mocap);
+    bind::add(&mut bvh, &mocap.metadata)?;
+    root_motion::decode(&mut bvh, &mocap.metadata)?;
+    let frame = match thumbnail::select(&bvh, policy) {
+        Some(frame) => frame,
+        None => return Ok(None),
+    };
+    println!("{}: thumbnail frame {}", name, frame);
+    let pose = bvh.motion.frames.swap_remove(frame);
+    let ret = container::Thumbnail {
+        frame: frame as u32,
+        pose: pose.iter().map(|value| *value as f32).collect(),
+    };
+    if let Some(ref dir) = options.export_thumbnails_dir {
+        fs::create_dir_all(dir)?;
+        bvh.motion.num_frames = 1;
+        bvh.motion.frames = vec![pose];
+        serialize_bvh(&bvh, &Path::new(dir).join(format!("{}.bvh", name)), options)?;
+    }
+    Ok(Some(ret))
+}
+
+fn first_delta_magnitude(mocap: &Mocap) -> u64 {
+    mocap.channels().iter().filter_map(|channel| channel.deltas.first()).map(|delta| (*delta as i64).unsigned_abs()).sum()
+}
+
+fn unpack(input_file_name: &Path, output_dir: &Path, options: &Options, cancel: Option<&AtomicBool>) -> Result<(), MocapError> {
+    let container = container::read(&fs::read(input_file_name)?, options.max_depth)?;
+    fs::create_dir_all(output_dir)?;
+    for clip in container.clips.iter() {
+        cancel::check(cancel)?;
+        write_bvh(&clip.mocap, &output_dir.join(format!("{}.bvh", clip.name)), options)?;
+    }
+    Ok(())
+}
+
+fn info(input_file_name: &Path, options: &Options) -> Result<(), MocapError> {
+    let data = fs::read(input_file_name)?;
+    let seek_table = if data.starts_with(raw::MAGIC) {
+        MocapView::parse(&data, options.max_depth)?.seek_table().map(|entries| entries.len())
+    } else {
+        None
+    };
+    let container = read_clips(input_file_name, &data, options.max_depth)?;
+    let sparse = data.starts_with(raw::MAGIC) && raw::is_sparse(&data);
+    let bit_planes = data.starts_with(raw::MAGIC) && raw::delta_layout(&data) == bitpack::Layout::BitPlanes;
+
+    println!("{} clips, {} reference poses", container.clips.len(), container.reference_poses.len());
+    for clip in container.clips.iter() {
+        let mocap = &clip.mocap;
+        println!("{}: {} frames, frame time {}, {} bits, {} channels", clip.name, mocap.num_frames, mocap.frame_time, mocap.channel_quantization_bits, mocap.channels().len());
+        println!("    skeleton hash {:032x}", mocap.skeleton_hash());
+        if let Some(index) = clip.reference_pose {
+            println!("    reference pose {}", index);
+        }
+        if let Some(index) = clip.alias {
+            println!("    alias of {}", container.clips[index].name);
+        }
+        if let Some(ref thumbnail) = clip.thumbnail {
+            println!("    thumbnail frame {}", thumbnail.frame);
+        }
+        if let Some(num_blocks) = seek_table {
+            println!("    seek index: {} blocks", num_blocks);
+        }
+        if sparse {
+            println!("    sparse track");
+        }
+        if bit_planes {
+            println!("    bit planes");
+        }
+        let (known, other): (Vec<_>, Vec<_>) = clip.attributes.iter().partition(|attribute| container::ATTRIBUTE_KEYS.contains(&attribute.0.as_str()));
+        for (key, value) in known.into_iter().chain(other) {
+            println!("    {} = {}", key, value);
+        }
+        for (key, value) in mocap.metadata.iter() {
+            println!("    metadata: {} = {}", key, value);
+        }
+        for (frame, name) in mocap.markers.iter() {
+            println!("    marker: {} at frame {}", name, frame);
+        }
+        if !mocap.timestamps.is_empty() {
+            println!("    variable timing: {} to {} s, resampled at the frame time on BVH output", mocap.timestamps[0], mocap.timestamps[mocap.timestamps.len() - 1]);
+        }
+        if let Some(scores) = quality::scores(mocap) {
+            for (descriptor, score) in mocap.channel_map().into_iter().zip(scores).filter(|(_, score)| *score < quality::LOW_QUALITY) {
+                println!("    low quality: {} {} ({} of 255)", descriptor.joint_name, descriptor.channel_type.name(), score);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// `mocap stats --locomotion|--dof-summary`: the locomotion metrics (see locomotion.rs) or DOF
+// summary (see dof.rs) of every clip, which `load` prints for BVH files.
+fn stats(input_file_names: &[String], options: &Options) -> Result<(), MocapError> {
+    for input_file_name in input_file_names.iter() {
+        let input_file_name = Path::new(input_file_name);
+        if input_file_name.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("bvh")) {
+            if options.delta_runs {
+                return Err(MocapError::Usage(format!("{}: --delta-runs only applies to compressed files", input_file_name.display())));
+            }
+            load(input_file_name, options)?;
+            continue;
+        }
+        let data = fs::read(input_file_name)?;
+        let chains = if options.delta_runs { drift::read(&data, options.max_depth)? } else { Vec::new() };
+        for (index, clip) in read_clips(input_file_name, &data, options.max_depth)?.clips.iter().enumerate() {
+            let mut bvh = build_bvh(&clip.mocap);
+            bind::add(&mut bvh, &clip.mocap.metadata)?;
+            root_motion::decode(&mut bvh, &clip.mocap.metadata)?;
+            if options.dof_summary {
+                print_dof_summary(&clip.name, &bvh.hierarchy.root);
+            }
+            if options.locomotion {
+                println!("{}: {}", clip.name, locomotion::analyze(&bvh, options.up_axis).describe());
+            }
+            if let Some(chains) = chains.get(index) {
+                print_delta_runs(&clip.name, &clip.mocap, chains);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_delta_runs(name: &str, mocap: &Mocap, chains: &drift::ClipChains) {
+    let anchors = if chains.anchored { "a seek index anchors every block" } else { "no seek index, so they run from the first frame" };
+    println!("{}: {} delta-coded channels, longest run {} frames ({})", name, chains.chains.len(), chains.longest_run(), anchors);
+    let channel_map = mocap.channel_map();
+    for chain in chains.chains.iter() {
+        let descriptor = &channel_map[chain.channel];
+        println!("    {} {}: {} frames, up to {:.4} per frame, {:.4} cumulative", descriptor.joint_name, descriptor.channel_type.name(), chain.longest_run, chain.max_error, chain.cumulative_error());
+    }
+}
+
+fn print_dof_summary(name: &str, root: &bvh::Joint) {
+    let lines = dof::Summary::new(root).describe();
+    log::info(format!("{}: {}", name, lines[0]));
+    for line in lines[1..].iter() {
+        log::info(format!("    {}", line));
+    }
+}
+
+// Verifies every file (see verify.rs), printing what's wrong with each, and fails if any has a
+// problem. With --report the findings go in the report too.
+fn verify_files(input_file_names: &[String], options: &Options) -> Result<(), MocapError> {
+    let mut report = report::RunReport::new("verify", options);
+    let mut num_failed = 0;
+    for input_file_name in input_file_names.iter() {
+        let input_file_name = Path::new(input_file_name);
+        let name = input_file_name.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let findings = match fs::read(input_file_name) {
+            Ok(data) => verify::verify(&data, &name, options.conversion.max_delta_run(), options.max_depth),
+            Err(e) => vec![verify::Finding {
+                clip: String::new(),
+                location: String::new(),
+                frame: None,
+                message: MocapError::from(e).to_string(),
+            }],
+        };
+
+        if findings.is_empty() {
+            println!("{}: ok", input_file_name.display());
+        } else {
+            num_failed += 1;
+            for finding in findings.iter() {
+                let frame = finding.frame.map_or(String::new(), |frame| format!("frame {}", frame));
+                let location = [&finding.clip, &finding.location, &frame].iter().filter(|part| !part.is_empty()).map(|part| format!("{}: ", part)).collect::<String>();
+                println!("{}: {}{}", input_file_name.display(), location, finding.message);
+            }
+            println!("{}: {} problem{}", input_file_name.display(), findings.len(), if findings.len() == 1 { "" } else { "s" });
+        }
+
+        let result = if findings.is_empty() { Ok(report::Conversion::default()) } else { Err(format!("{} problems found by verify", findings.len())) };
+        let mut file = report::FileReport::new(input_file_name, &[], result, false, Vec::new());
+        file.findings = findings;
+        report.files.push(file);
+    }
+
+    if let Some(ref report_file_name) = options.report_file_name {
+        write_report(&report, report_file_name, options)?;
+    }
+    if num_failed > 0 {
+        return Err(MocapError::BatchFailed(num_failed, input_file_names.len()));
+    }
+    Ok(())
+}
+
+// A container, or a .raw file as a container of one clip named after the file.
+fn read_clips(input_file_name: &Path, data: &[u8], max_depth: usize) -> Result<container::Container, MocapError> {
+    if !data.starts_with(raw::MAGIC) {
+        return container::read(data, max_depth);
+    }
+    Ok(container::Container {
+        reference_poses: Vec::new(),
+        clips: vec![container::Clip {
+            name: input_file_name.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default(),
+            reference_pose: None,
+            attributes: Vec::new(),
+            thumbnail: None,
+            alias: None,
+            mocap: raw::read(data, max_depth)?,
+        }],
+    })
+}
+
+// With the root motion integrated back, if the clip has any, and resampled to uniform timing if
+// it has timestamps; just the --pose-frame with one. Returns the frames written and the markers as
+// they fall on them.
+fn write_bvh(mocap: &Mocap, output_file_name: &Path, options: &Options) -> Result<(u32, Vec<markers::Marker>), MocapError> {
+    let mut bvh = build_bvh(mocap);
+    root_motion::decode(&mut bvh, &mocap.metadata)?;
+    let mut markers = mocap.markers.clone();
+    if !mocap.timestamps.is_empty() {
+        timing::resample(&mut bvh, &mocap.timestamps, &mut markers, options.interpolation);
+    }
+    if let Some(frame) = options.pose_frame {
+        keep_pose_frame(&mut bvh, &mut markers, frame, output_file_name)?;
+    }
+    serialize_bvh(&bvh, output_file_name, options)?;
+    Ok((bvh.motion.num_frames, markers))
+}
+
+// Cuts a decoded clip down to the one frame `frame` (see thumbnail.rs), with the markers on it.
+fn keep_pose_frame(bvh: &mut bvh::Bvh, markers: &mut Vec<markers::Marker>, frame: thumbnail::PoseFrame, output_file_name: &Path) -> Result<(), MocapError> {
+    let num_frames = bvh.motion.frames.len();
+    if num_frames == 0 {
+        return Err(MocapError::Usage(format!("{}: the clip has no frames, --pose-frame doesn't apply", output_file_name.display())));
+    }
+    let (index, clamped) = frame.resolve(num_frames);
+    if clamped {
+        log::warning(format!("{}: --pose-frame {} is past the clip's {} frames, writing the last", output_file_name.display(), frame.spec(), num_frames));
+    }
+    let pose = bvh.motion.frames.swap_remove(index);
+    bvh.motion.frames = vec![pose];
+    bvh.motion.num_frames = 1;
+    markers.retain(|marker| marker.0 as usize == index);
+    for marker in markers.iter_mut() {
+        marker.0 = 0;
+    }
+    Ok(())
+}
+
+fn serialize_bvh(bvh: &bvh::Bvh, output_file_name: &Path, options: &Options) -> Result<(), MocapError> {
+    let mut serialized = Vec::new();
+    bvh::serialize(bvh, &mut serialized)?;
+    if options.crlf {
+        serialized = to_crlf(&serialized);
+    }
+    let mut output = manifest::create(output_file_name)?;
+    output.write_all(&serialized)?;
+
+    Ok(())
+}
+
+// `bvh::serialize` always writes LF line endings, so CRLF output is produced by rewriting them.
+fn to_crlf(serialized: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(serialized.len() + serialized.len() / 16);
+    let mut previous = 0;
+    for &byte in serialized.iter() {
+        if byte == b'\n' && previous != b'\r' {
+            ret.push(b'\r');
+        }
+        ret.push(byte);
+        previous = byte;
+    }
+    ret
+}
+
+fn dump_channels_csv<W: Write>(joint: &Joint, w: &mut W) -> io::Result<()> {
+    for channel in joint.channels.iter() {
+        for (index, delta) in channel.deltas.iter().enumerate() {
+            writeln!(w, "{};{}", index, delta)?;
+        }
+    }
+
+    if let JointChildren::Joints(ref joints) = joint.children {
+        for joint in joints.iter() {
+            dump_channels_csv(joint, w)?;
+        }
+    }
+
+    Ok(())
+}
+
+// The same deltas grouped by channel type: every TranslationX channel in the skeleton, then every
+// TranslationY and so on (each in pre-order), with every row labelled with its joint and channel
+// type, as <joint>;<channel type>;<frame>;<delta>.
+fn dump_channels_csv_by_type<W: Write>(mocap: &Mocap, w: &mut W) -> io::Result<()> {
+    let channels = mocap.channel_map().into_iter().zip(mocap.channels()).collect::<Vec<_>>();
+    for type_ in ChannelType::ALL.iter() {
+        for (descriptor, channel) in channels.iter().filter(|(descriptor, _)| descriptor.channel_type == *type_) {
+            for (index, delta) in channel.deltas.iter().enumerate() {
+                writeln!(w, "{};{};{};{}", descriptor.joint_name, type_.name(), index, delta)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use depth::DEFAULT_MAX_DEPTH;
+    use test_util;
+
+    fn settings(bits: u8) -> Settings {
+        Settings {
+            channel_quantization_bits: bits,
+            translation_reference: TranslationReference::None,
+            rotation_anchor: RotationAnchor::None,
+        }
+    }
+
+    #[test]
+    fn reconstruct_frames_reuses_the_buffer() {
+        let mocap = build_mocap(&test_util::sine_clip(90), &settings(8));
+        let mut frames = Vec::new();
+        reconstruct_frames(&mocap, &mut frames);
+        assert_eq!(frames, build_bvh(&mocap).motion.frames);
+
+        let allocations = |frames: &Vec<Vec<f64>>| (frames.as_ptr(), frames.capacity(), frames.iter().map(|frame| (frame.as_ptr(), frame.capacity())).collect::<Vec<_>>());
+        let before = allocations(&frames);
+        reconstruct_frames(&mocap, &mut frames);
+        assert_eq!(allocations(&frames), before);
+        assert_eq!(frames, build_bvh(&mocap).motion.frames);
+    }
+
+    #[test]
+    fn translation_references_keep_large_coordinates_precise() {
+        // Root translation around 1e5 with a small motion, like world coordinates far from the
+        // origin, and a root offset there too for the offset reference
+        let hierarchy = test_util::HIERARCHY.replacen("OFFSET 0 0 0", "OFFSET 100000 100000 100000", 1);
+        let value = |frame, channel| if channel < 3 { 1e5 + test_util::sine(frame, channel) * 0.005 } else { test_util::sine(frame, channel) };
+        let bvh = test_util::parse(&test_util::motion_text(&hierarchy, test_util::NUM_CHANNELS, 60, value));
+        let translation_error = |reference| {
+            let mocap = build_mocap(&bvh, &Settings { translation_reference: reference, ..settings(8) });
+            let frames = build_bvh(&mocap).motion.frames;
+            metrics::reconstruction_error(&bvh.motion.frames.iter().map(|frame| frame[..3].to_vec()).collect::<Vec<_>>(), &frames.iter().map(|frame| frame[..3].to_vec()).collect::<Vec<_>>()).max
+        };
+
+        let absolute = translation_error(TranslationReference::None);
+        for reference in [TranslationReference::Offset, TranslationReference::Mean].iter() {
+            let relative = translation_error(*reference);
+            // Within a quantization step of the motion's range (at most 0.14)
+            assert!(relative <= 0.14 / 255.0, "{:?}: {}", reference, relative);
+            assert!(relative * 4.0 < absolute, "{:?}: {} against {}", reference, relative, absolute);
+        }
+    }
+
+    // Rotation columns of the sine clip's frames
+    fn rotation_columns(frames: &[Vec<f64>]) -> Vec<Vec<f64>> {
+        frames.iter().map(|frame| frame[3..].to_vec()).collect()
+    }
+
+    #[test]
+    fn anchored_rotations_reconstruct_the_anchor_exactly() {
+        // Rotations through 0 at the first frame, for the zero anchor
+        let through_zero = test_util::parse(&test_util::clip_text(60, |frame, channel| test_util::sine(frame, channel) - test_util::sine(0, channel)));
+        for (bvh, anchor) in [(&through_zero, RotationAnchor::Zero), (&test_util::sine_clip(60), RotationAnchor::Rest)].iter() {
+            let mocap = build_mocap(bvh, &Settings { rotation_anchor: *anchor, ..settings(6) });
+            let mut data = Vec::new();
+            raw::write(&mocap, periodic::Limits::default(), &mut data).unwrap();
+            let decoded = build_bvh(&raw::read(&data, DEFAULT_MAX_DEPTH).unwrap()).motion.frames;
+            assert_eq!(rotation_columns(&decoded[..1]), rotation_columns(&bvh.motion.frames[..1]), "{:?}", anchor);
+        }
+    }
+
+    #[test]
+    fn anchored_rotations_are_no_less_precise() {
+        let bvh = test_util::sine_clip(60);
+        let original = rotation_columns(&bvh.motion.frames);
+        let error = |anchor| {
+            let frames = build_bvh(&build_mocap(&bvh, &Settings { rotation_anchor: anchor, ..settings(6) })).motion.frames;
+            metrics::reconstruction_error(&original, &rotation_columns(&frames))
+        };
+        let unanchored = error(RotationAnchor::None);
+        for anchor in [RotationAnchor::Zero, RotationAnchor::Rest].iter() {
+            let anchored = error(*anchor);
+            assert!(anchored.max <= unanchored.max && anchored.rms <= unanchored.rms, "{:?}: {:?} against {:?}", anchor, anchored, unanchored);
+        }
+    }
+
+    #[test]
+    fn num_levels_covers_only_the_supported_depths() {
+        assert_eq!(num_levels(0), None);
+        assert_eq!(num_levels(1), Some(2));
+        assert_eq!(num_levels(8), Some(256));
+        assert_eq!(num_levels(16), None);
+        assert_eq!(num_levels(31), None);
+    }
+
+    #[test]
+    fn max_level_saturates_past_the_supported_depths() {
+        assert_eq!(max_level(0), 0);
+        assert_eq!(max_level(1), 1);
+        assert_eq!(max_level(8), 255);
+        assert_eq!(max_level(16), 255);
+        assert_eq!(max_level(31), 255);
+    }
+
+    #[test]
+    fn strict_refuses_lossy_profile_settings() {
+        let dir = test_util::temp_dir("strict-clamp");
+        let profile_file_name = dir.join("profile.toml");
+        fs::write(&profile_file_name, "[channel.\"Spine\".RotationZ]\nclamp = [-5, 5]\n").unwrap();
+        let profile_file_name = profile_file_name.to_str().unwrap();
+
+        let strict = test_util::options(&["--strict", "--profile", profile_file_name]);
+        match load_bvh(test_util::sine_clip(30), &directives::Directives::default(), Path::new("in.bvh"), &strict) {
+            Err(MocapError::Usage(message)) => assert!(message.contains("clamping to the profile's clamp bounds"), "{}", message),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        let lossy = test_util::options(&["--strict", "--lossy", "--profile", profile_file_name]);
+        assert!(load_bvh(test_util::sine_clip(30), &directives::Directives::default(), Path::new("in.bvh"), &lossy).is_ok());
+
+        // Likewise a profile adjustment zeroing channels, unlike one the metadata can undo
+        fs::write(profile_file_name, "[channel.\"Spine\".RotationZ]\nmultiply = 0\n").unwrap();
+        match load_bvh(test_util::sine_clip(30), &directives::Directives::default(), Path::new("in.bvh"), &strict) {
+            Err(MocapError::Usage(message)) => assert!(message.contains("zeroing channels"), "{}", message),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        fs::write(profile_file_name, "[channel.\"Spine\".RotationZ]\nmultiply = -1\n").unwrap();
+        assert!(load_bvh(test_util::sine_clip(30), &directives::Directives::default(), Path::new("in.bvh"), &strict).is_ok());
+    }
+
+    #[test]
+    fn a_profile_setting_a_calibration_stream_cant_hold_is_refused() {
+        let dir = test_util::temp_dir("profile-calibration");
+        let profile_file_name = dir.join("profile.toml");
+        fs::write(&profile_file_name, "[encoding]\nroot-motion = true\n").unwrap();
+        let profile_file_name = profile_file_name.to_str().unwrap();
+
+        let options = test_util::options(&["--calibration", "calibration.bvh", "--profile", profile_file_name]);
+        match load_bvh(test_util::sine_clip(30), &directives::Directives::default(), Path::new("in.bvh"), &options) {
+            Err(MocapError::InvalidProfile(message)) => assert_eq!(message, format!("{}: [encoding]: root-motion can't be combined with --calibration, whose streamed file has no metadata", profile_file_name)),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        // As on the command line
+        match Options::parse(["--calibration", "calibration.bvh", "--root-motion", "in.bvh", "out.bvh", "out.csv", "out.raw"].iter().map(|arg| arg.to_string())) {
+            Err(MocapError::Usage(message)) => assert!(message.contains("--root-motion can't be combined with --calibration"), "{}", message),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+
+    // `text` converted end to end, with its .raw file and the file decoded from it
+    fn convert_and_decode(name: &str, text: &str) -> (Vec<u8>, bvh::Bvh) {
+        let dir = test_util::temp_dir(name);
+        let path = |file_name: &str| dir.join(file_name);
+        fs::write(path("in.bvh"), text).unwrap();
+        let options = test_util::options(&[]);
+        convert_file(&path("in.bvh"), &path("out.bvh"), &path("out.csv"), &path("out.raw"), &options, None).unwrap();
+        decode(&path("out.raw"), &path("decoded.bvh"), &options).unwrap();
+        (fs::read(path("out.raw")).unwrap(), test_util::parse(&fs::read_to_string(path("decoded.bvh")).unwrap()))
+    }
+
+    #[test]
+    fn a_single_frame_clip_round_trips_as_constant_channels() {
+        let text = test_util::clip_text(1, test_util::sine);
+        let (data, decoded) = convert_and_decode("single-frame", &text);
+
+        // Every channel constant: a range of 0, every frame at level 0
+        let read = raw::read(&data, DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(read.num_frames, 1);
+        assert!(read.channels().iter().all(|channel| channel.value_range == 0.0 && channel.initial_level == 0 && channel.deltas == [0]));
+        assert!(raw::is_static(&read));
+
+        let original = test_util::parse(&text);
+        assert_eq!(decoded.motion.frames.len(), 1);
+        for (value, expected) in decoded.motion.frames[0].iter().zip(original.motion.frames[0].iter()) {
+            // To f32 precision
+            assert!((value - expected).abs() <= expected.abs() * 1e-6, "{} != {}", value, expected);
+        }
+        assert_eq!(decoded.motion.frame_time as f32, original.motion.frame_time as f32);
+    }
+
+    #[test]
+    fn a_clip_without_frames_round_trips() {
+        let (data, decoded) = convert_and_decode("no-frames", &test_util::clip_text(0, test_util::sine));
+        let read = raw::read(&data, DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(read.num_frames, 0);
+        assert!(!raw::is_static(&read));
+        assert!(decoded.motion.frames.is_empty());
+        assert_eq!(skeleton_of(&decoded.hierarchy.root), skeleton_of(&test_util::sine_clip(1).hierarchy.root));
+    }
+
+    fn channel_data(mocap: &Mocap) -> Vec<Channel> {
+        mocap.channels().into_iter().cloned().collect()
+    }
+
+    #[test]
+    fn parallel_decode_matches_the_serial_one() {
+        let mocap = build_mocap(&test_util::sine_clip(97), &settings(6));
+        let mut serial = Vec::new();
+        reconstruct_frames(&mocap, &mut serial);
+        let channels = channel_data(&mocap);
+        // Fewer threads than channels, one per channel, and more than there are
+        for threads in [2, 4, test_util::NUM_CHANNELS, 40].iter() {
+            let mut frames = vec![vec![1.0; 3]; 5];
+            decode_channels_parallel(&channels, mocap.num_frames as usize, mocap.channel_quantization_bits, *threads, &mut frames);
+            assert_eq!(frames, serial, "{} threads", threads);
+        }
+    }
+
+    // A benchmark rather than a test: cargo test -- --ignored --nocapture parallel_decode_timing
+    #[test]
+    #[ignore]
+    fn parallel_decode_timing() {
+        let num_frames = 200_000;
+        let mocap = build_mocap(&test_util::sine_clip(num_frames), &settings(8));
+        let channels = channel_data(&mocap);
+        let time = |f: &dyn Fn(&mut Vec<Vec<f64>>)| {
+            let mut frames = Vec::new();
+            let start = Instant::now();
+            f(&mut frames);
+            (start.elapsed(), frames)
+        };
+        let (serial_time, serial) = time(&|frames| reconstruct_frames(&mocap, frames));
+        println!("serial: {:?}", serial_time);
+        for threads in [2, 4, 8].iter() {
+            let (parallel_time, frames) = time(&|frames| decode_channels_parallel(&channels, num_frames, mocap.channel_quantization_bits, *threads, frames));
+            println!("{} threads: {:?} ({:.2}x)", threads, parallel_time, serial_time.as_secs_f64() / parallel_time.as_secs_f64());
+            assert_eq!(frames, serial);
+        }
+    }
+
+    // A benchmark rather than a test: cargo test -- --ignored --nocapture reused_buffer_decode_timing
+    #[test]
+    #[ignore]
+    fn reused_buffer_decode_timing() {
+        let mocap = build_mocap(&test_util::sine_clip(2_000), &settings(8));
+        let decodes = 500;
+        let mut fresh = Vec::new();
+        let start = Instant::now();
+        for _ in 0..decodes {
+            fresh = Vec::new();
+            reconstruct_frames(&mocap, &mut fresh);
+        }
+        let fresh_time = start.elapsed();
+
+        let mut reused = Vec::new();
+        let start = Instant::now();
+        for _ in 0..decodes {
+            reconstruct_frames(&mocap, &mut reused);
+        }
+        let reused_time = start.elapsed();
+        println!("{} decodes of {} frames: fresh buffer {:?}, reused buffer {:?} ({:.2}x)",
+            decodes, mocap.num_frames, fresh_time, reused_time, fresh_time.as_secs_f64() / reused_time.as_secs_f64());
+        assert_eq!(reused, fresh);
+    }
+
+    // Joints declaring CHANNELS 0, as structural pivots: Pivot between Hips and Spine, and Hinge
+    // between Spine and Head
+    fn pivots() -> bvh::Bvh {
+        test_util::parse(&fs::read_to_string(test_util::fixture("pivots.bvh")).unwrap())
+    }
+
+    // A joint's name, channel count and offset, and whether it ends in an end site
+    type JointSummary = (String, usize, (f64, f64, f64), bool);
+
+    // Every joint's, in pre-order
+    fn skeleton(joint: &bvh::Joint, joints: &mut Vec<JointSummary>) {
+        let is_leaf = matches!(joint.children, bvh::JointChildren::EndSite(_));
+        joints.push((joint.name.clone(), joint.channels.len(), (joint.offset.x, joint.offset.y, joint.offset.z), is_leaf));
+        if let bvh::JointChildren::Joints(ref children) = joint.children {
+            for child in children.iter() {
+                skeleton(child, joints);
+            }
+        }
+    }
+
+    fn skeleton_of(root: &bvh::Joint) -> Vec<JointSummary> {
+        let mut ret = Vec::new();
+        skeleton(root, &mut ret);
+        ret
+    }
+
+    #[test]
+    fn zero_channel_joints_survive_reconstruction() {
+        let bvh = pivots();
+        let mocap = build_mocap(&bvh, &settings(8));
+        assert_eq!(mocap.channels().len(), 15);
+        assert!(mocap.validate().is_ok());
+        assert!(mocap.validate_leaves(&bvh.hierarchy.root).is_ok());
+
+        let mut data = Vec::new();
+        raw::write(&mocap, periodic::Limits::default(), &mut data).unwrap();
+        for decoded in [build_bvh(&mocap), build_bvh(&raw::read(&data, DEFAULT_MAX_DEPTH).unwrap()), view::MocapView::parse(&data, DEFAULT_MAX_DEPTH).unwrap().to_bvh(1)].iter() {
+            assert_eq!(skeleton_of(&decoded.hierarchy.root), skeleton_of(&bvh.hierarchy.root));
+            for (decoded, original) in decoded.motion.frames.iter().zip(bvh.motion.frames.iter()) {
+                assert_eq!(decoded.len(), original.len());
+                for (channel, (decoded, original)) in decoded.iter().zip(original.iter()).enumerate() {
+                    let step = mocap.channels()[channel].value_range as f64 / 255.0;
+                    assert!((decoded - original).abs() <= step + 1e-4, "channel {}: {} vs {}", channel, decoded, original);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fk_applies_zero_channel_offsets() {
+        let bvh = pivots();
+        // The first frame: only the root translated, to (1, 2, 3)
+        let positions = fk::world_transforms(&bvh.hierarchy.root, &bvh.motion.frames[0]).iter().map(|transform| transform.position()).collect::<Vec<_>>();
+        let expected = [(1.0, 2.0, 3.0), (1.0, 12.0, 3.0), (1.0, 17.0, 3.0), (3.0, 17.0, 3.0), (3.0, 21.0, 3.0), (6.0, 2.0, 3.0)];
+        assert_eq!(positions.len(), expected.len());
+        for (position, expected) in positions.iter().zip(expected.iter()) {
+            assert!((position.0 - expected.0).abs() < 1e-9 && (position.1 - expected.1).abs() < 1e-9 && (position.2 - expected.2).abs() < 1e-9, "{:?} vs {:?}", position, expected);
+        }
+        assert_eq!(fk::end_site_positions(&bvh.hierarchy.root, &bvh.motion.frames[0]).len(), 2);
+
+        // Spine's rotation turns Hinge's offset, which it passes on to Head
+        let mut frame = bvh.motion.frames[0].clone();
+        frame[6] = 90.0;
+        let positions = fk::world_transforms(&bvh.hierarchy.root, &frame).iter().map(|transform| transform.position()).collect::<Vec<_>>();
+        assert!((positions[3].0 - 1.0).abs() < 1e-9 && (positions[3].1 - 19.0).abs() < 1e-9, "{:?}", positions[3]);
+        assert!((positions[4].0 - -3.0).abs() < 1e-9 && (positions[4].1 - 19.0).abs() < 1e-9, "{:?}", positions[4]);
+    }
+
+    #[test]
+    fn channel_map_counts_zero_channel_joints() {
+        let mocap = build_mocap(&pivots(), &settings(8));
+        let (joints, end_sites) = mocap.joint_graph();
+        assert_eq!(joints.iter().map(|joint| (joint.name.as_str(), joint.parent, joint.channels.len())).collect::<Vec<_>>(), vec![
+            ("Hips", None, 6), ("Pivot", Some(0), 0), ("Spine", Some(1), 3), ("Hinge", Some(2), 0), ("Head", Some(3), 3), ("LeftLeg", Some(0), 3),
+        ]);
+        assert_eq!(end_sites.iter().map(|end_site| end_site.parent).collect::<Vec<_>>(), vec![4, 5]);
+
+        let map = mocap.channel_map();
+        assert_eq!(map.len(), 15);
+        for descriptor in map.iter() {
+            assert_eq!(joints[descriptor.joint_index].name, descriptor.joint_name);
+        }
+        assert_eq!(map.iter().map(|descriptor| descriptor.joint_index).collect::<Vec<_>>(), vec![0, 0, 0, 0, 0, 0, 2, 2, 2, 4, 4, 4, 5, 5, 5]);
+        assert_eq!(map.iter().map(|descriptor| descriptor.flat_index).collect::<Vec<_>>(), (0..15).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn zero_channel_joints_survive_the_container() {
+        let bvh = pivots();
+        let mocap = build_mocap(&bvh, &settings(8));
+        let expected = build_bvh(&mocap);
+        let container = container::Container {
+            reference_poses: Vec::new(),
+            clips: vec![container::Clip { name: "pivots".into(), reference_pose: None, attributes: Vec::new(), thumbnail: None, alias: None, mocap: mocap }],
+        };
+        let mut data = Vec::new();
+        container::write(&container, periodic::Limits::default(), &mut data).unwrap();
+        let read = container::read(&data, DEFAULT_MAX_DEPTH).unwrap();
+        let decoded = build_bvh(&read.clips[0].mocap);
+        assert_eq!(skeleton_of(&decoded.hierarchy.root), skeleton_of(&bvh.hierarchy.root));
+        assert_eq!(decoded.motion.frames, expected.motion.frames);
+        assert!(verify::verify(&data, "", None, DEFAULT_MAX_DEPTH).is_empty());
+    }
+
+    #[test]
+    fn error_at_a_zero_channel_joint_names_the_channel() {
+        let bvh = pivots();
+        let mocap = build_mocap(&bvh, &settings(8));
+        match metrics::channel_error(&mocap, &bvh, "Pivot", ChannelType::RotationZ, 0) {
+            Err(MocapError::Usage(message)) => assert_eq!(message, "Pivot has no RotationZ channel"),
+            other => panic!("{:?}", other),
+        }
+        match metrics::channel_error(&mocap, &bvh, "Nope", ChannelType::RotationZ, 0) {
+            Err(MocapError::JointNotFound(name)) => assert_eq!(name, "Nope"),
+            other => panic!("{:?}", other),
+        }
+        assert!(metrics::channel_error(&mocap, &bvh, "Spine", ChannelType::RotationZ, 0).is_ok());
+    }
+}
@@ -0,0 +1,275 @@
+use bvh;
+
+use fk;
+use ground;
+
+// Locomotion analysis, for building blend spaces: how fast a clip travels, how fast it turns, how
+// often it steps, and whether it moves across the floor at all. With --locomotion a conversion or
+// pack records the metrics in each clip's metadata (and a --report), and `mocap stats --locomotion`
+// prints them. They come from the clip's forward kinematics, over the ground plane that
+// ground.rs detects (--up-axis, or the axis the root moves least along):
+//
+//   speed             The length of the root's path across the floor over the clip's duration, in
+//                     units per second
+//   heading rate      The net change in the root's heading (the direction of its local axis after
+//                     the up axis, Z for a Y-up clip, projected onto the floor) over the duration, in
+//                     degrees per second, positive turning like a positive rotation about the up axis
+//   stride frequency  Full gait cycles per second, from the periodicity of foot contacts: the feet
+//                     are the two end sites lowest on average, a foot is in contact while it's
+//                     within CONTACT_FRACTION of the skeleton's height above the floor, and each
+//                     foot's period is the median number of frames between it touching down. None
+//                     if no foot touches down twice
+//   in place          Whether the root stays within IN_PLACE_FRACTION of the skeleton's height of
+//                     where it starts, across the floor, as in treadmill or idle clips
+//
+// The skeleton's height is the span of its joints and end sites along the up axis in the first
+// frame. The metadata value holds the four, separated by spaces, as `<speed> <heading rate>
+// <stride frequency|none> <in-place|traveling>`.
+//
+// Conversions analyze the clip after gap repair, retiming, --root and smoothing, but before
+// anything storing channels relative to something else (--mask, --bind-pose, --root-motion).
+
+// Metadata key holding the metrics
+pub const KEY: &str = "locomotion";
+
+const IN_PLACE_FRACTION: f64 = 0.05;
+const CONTACT_FRACTION: f64 = 0.03;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Locomotion {
+    pub speed: f64, // Units per second
+    pub heading_rate: f64, // Degrees per second
+    pub stride_frequency: Option<f64>, // Gait cycles per second
+    pub in_place: bool,
+}
+
+impl Locomotion {
+    pub fn metadata(&self) -> (String, String) {
+        (KEY.into(), format!("{} {} {} {}",
+            self.speed,
+            self.heading_rate,
+            self.stride_frequency.map_or("none".into(), |frequency| frequency.to_string()),
+            if self.in_place { "in-place" } else { "traveling" }))
+    }
+
+    // The metrics for printing.
+    pub fn describe(&self) -> String {
+        format!("{}, speed {:.3} units/s, heading rate {:.3} degrees/s, {}",
+            if self.in_place { "in place" } else { "traveling" },
+            self.speed,
+            self.heading_rate,
+            self.stride_frequency.map_or("no strides".into(), |frequency| format!("stride frequency {:.3} Hz", frequency)))
+    }
+}
+
+pub fn analyze(bvh: &bvh::Bvh, up_axis: Option<usize>) -> Locomotion {
+    let ground = ground::detect(bvh, up_axis);
+    let up = ground.up_axis;
+    // A positive rotation about the up axis turns `forward` towards `side`
+    let (forward, side) = ((up + 1) % 3, (up + 2) % 3);
+    let root = &bvh.hierarchy.root;
+    let frames = &bvh.motion.frames;
+    let duration = frames.len().saturating_sub(1) as f64 * bvh.motion.frame_time;
+
+    let mut positions = Vec::with_capacity(frames.len()); // The root's, across the floor
+    let mut headings = Vec::with_capacity(frames.len());
+    let mut feet = Vec::with_capacity(frames.len()); // Every end site's height, per frame
+    let mut height = 0.0;
+    for (index, frame) in frames.iter().enumerate() {
+        let transforms = fk::world_transforms(root, frame);
+        let m = &transforms[0].0;
+        let axis = |position: (f64, f64, f64), axis: usize| [position.0, position.1, position.2][axis];
+        let position = transforms[0].position();
+        positions.push((axis(position, forward), axis(position, side)));
+        // None while the forward axis points (nearly) straight up or down
+        let (along, across) = (m[forward][forward], m[side][forward]);
+        headings.push(if along.hypot(across) > 1e-6 { Some(across.atan2(along).to_degrees()) } else { None });
+        let end_sites = fk::end_site_positions(root, frame);
+        if index == 0 {
+            let heights = transforms.iter().map(|transform| axis(transform.position(), up)).chain(end_sites.iter().map(|position| axis(*position, up)));
+            let (min, max) = heights.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), height| (min.min(height), max.max(height)));
+            height = max - min;
+        }
+        feet.push(end_sites.into_iter().map(|position| axis(position, up)).collect::<Vec<_>>());
+    }
+
+    let path = positions.windows(2).map(|pair| (pair[1].0 - pair[0].0).hypot(pair[1].1 - pair[0].1)).sum::<f64>();
+    let turn = headings.windows(2).filter_map(|pair| match (pair[0], pair[1]) {
+        (Some(from), Some(to)) => Some(wrap(to - from)),
+        _ => None,
+    }).sum::<f64>();
+    let in_place = positions.first().is_none_or(|start| positions.iter().all(|position| (position.0 - start.0).hypot(position.1 - start.1) <= IN_PLACE_FRACTION * height));
+
+    Locomotion {
+        speed: if duration > 0.0 { path / duration } else { 0.0 },
+        heading_rate: if duration > 0.0 { turn / duration } else { 0.0 },
+        stride_frequency: stride_period(&feet, ground.floor_height + CONTACT_FRACTION * height).map(|period| 1.0 / (period * bvh.motion.frame_time)),
+        in_place: in_place,
+    }
+}
+
+// The feet's median frames between touchdowns (below `contact_height`), averaged over the feet
+// touching down at least twice.
+fn stride_period(heights: &[Vec<f64>], contact_height: f64) -> Option<f64> {
+    let num_end_sites = heights.first().map_or(0, |heights| heights.len());
+    let mut end_sites = (0..num_end_sites).collect::<Vec<_>>();
+    let total = |end_site: usize| heights.iter().map(|heights| heights[end_site]).sum::<f64>();
+    end_sites.sort_by(|x, y| total(*x).partial_cmp(&total(*y)).unwrap_or(::std::cmp::Ordering::Equal));
+
+    let periods = end_sites.into_iter().take(2).filter_map(|end_site| {
+        let contacts = heights.iter().map(|heights| heights[end_site] <= contact_height).collect::<Vec<_>>();
+        let touchdowns = (1..contacts.len()).filter(|frame| contacts[*frame] && !contacts[frame - 1]).collect::<Vec<_>>();
+        let mut intervals = touchdowns.windows(2).map(|pair| pair[1] - pair[0]).collect::<Vec<_>>();
+        if intervals.is_empty() {
+            return None;
+        }
+        intervals.sort();
+        Some(intervals[intervals.len() / 2] as f64)
+    }).collect::<Vec<_>>();
+    if periods.is_empty() {
+        return None;
+    }
+    Some(periods.iter().sum::<f64>() / periods.len() as f64)
+}
+
+// Into [-180, 180) degrees
+fn wrap(angle: f64) -> f64 {
+    angle - 360.0 * ((angle + 180.0) / 360.0).floor()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use std::fs;
+
+    use super::*;
+    use container;
+    use depth::DEFAULT_MAX_DEPTH;
+    use test_util;
+    use {convert, raw, Options};
+
+    // A root 40 units up with a head and two legs reaching down to the floor, 65 units tall
+    const WALKER: &str = "HIERARCHY
+ROOT Hips
+{
+    OFFSET 0.0 0.0 0.0
+    CHANNELS 6 Xposition Yposition Zposition Zrotation Xrotation Yrotation
+    JOINT Head
+    {
+        OFFSET 0.0 20.0 0.0
+        CHANNELS 3 Zrotation Xrotation Yrotation
+        End Site
+        {
+            OFFSET 0.0 5.0 0.0
+        }
+    }
+    JOINT LeftLeg
+    {
+        OFFSET 5.0 0.0 0.0
+        CHANNELS 3 Zrotation Xrotation Yrotation
+        End Site
+        {
+            OFFSET 0.0 -40.0 0.0
+        }
+    }
+    JOINT RightLeg
+    {
+        OFFSET -5.0 0.0 0.0
+        CHANNELS 3 Zrotation Xrotation Yrotation
+        End Site
+        {
+            OFFSET 0.0 -40.0 0.0
+        }
+    }
+}
+";
+    const FRAME_TIME: f64 = 0.033333;
+
+    // A clip walking at `speed` along Z while turning at `heading_rate`, each leg swinging up in turn
+    // every `period` frames (or never, for a `period` of 0)
+    fn walk(num_frames: usize, speed: f64, heading_rate: f64, period: usize) -> String {
+        test_util::motion_text(WALKER, 15, num_frames, |frame, channel| {
+            let t = frame as f64 * FRAME_TIME;
+            let phase = if period == 0 { 0.0 } else { (2.0 * PI * frame as f64 / period as f64).sin() };
+            match channel {
+                1 => 40.0,
+                2 => speed * t,
+                5 => heading_rate * t,
+                10 => 30.0 * phase.max(0.0),
+                13 => 30.0 * (-phase).max(0.0),
+                _ => 0.0,
+            }
+        })
+    }
+
+    fn analyze_walk(num_frames: usize, speed: f64, heading_rate: f64, period: usize) -> Locomotion {
+        analyze(&test_util::parse(&walk(num_frames, speed, heading_rate, period)), Some(1))
+    }
+
+    #[test]
+    fn measures_a_constant_speed_and_stepping_period() {
+        let locomotion = analyze_walk(121, 100.0, 0.0, 30);
+        assert!((locomotion.speed - 100.0).abs() < 1e-3, "{:?}", locomotion);
+        assert!(locomotion.heading_rate.abs() < 1e-6, "{:?}", locomotion);
+        let frequency = locomotion.stride_frequency.unwrap();
+        assert!((frequency - 1.0 / (30.0 * FRAME_TIME)).abs() < 1e-6, "{:?}", locomotion);
+        assert!(!locomotion.in_place);
+
+        let locomotion = analyze_walk(121, 50.0, 0.0, 20);
+        assert!((locomotion.speed - 50.0).abs() < 1e-3, "{:?}", locomotion);
+        assert!((locomotion.stride_frequency.unwrap() - 1.0 / (20.0 * FRAME_TIME)).abs() < 1e-6, "{:?}", locomotion);
+    }
+
+    #[test]
+    fn detects_clips_in_place() {
+        // Stepping on a treadmill, turning on the spot, and drifting less than 5% of the height
+        let treadmill = analyze_walk(121, 0.0, 0.0, 30);
+        assert!(treadmill.in_place && treadmill.speed == 0.0 && treadmill.stride_frequency.is_some(), "{:?}", treadmill);
+        let turning = analyze_walk(61, 0.0, 90.0, 0);
+        assert!(turning.in_place && turning.stride_frequency.is_none(), "{:?}", turning);
+        let drifting = analyze_walk(61, 1.5, 0.0, 0);
+        assert!(drifting.in_place && (drifting.speed - 1.5).abs() < 1e-3, "{:?}", drifting);
+        let traveling = analyze_walk(61, 2.0, 0.0, 0);
+        assert!(!traveling.in_place, "{:?}", traveling);
+    }
+
+    #[test]
+    fn measures_the_heading_rate_either_way_round() {
+        let left = analyze_walk(61, 0.0, 90.0, 0);
+        assert!((left.heading_rate - 90.0).abs() < 1e-3, "{:?}", left);
+
+        // Turning past 180 degrees, the wrong way round
+        let right = analyze_walk(121, 0.0, -120.0, 0);
+        assert!((right.heading_rate + 120.0).abs() < 1e-3, "{:?}", right);
+    }
+
+    #[test]
+    fn empty_and_single_frame_clips_stand_still() {
+        let locomotion = analyze_walk(1, 100.0, 90.0, 30);
+        assert_eq!(locomotion, Locomotion { speed: 0.0, heading_rate: 0.0, stride_frequency: None, in_place: true });
+        assert_eq!(locomotion.metadata(), (KEY.into(), "0 0 none in-place".into()));
+    }
+
+    #[test]
+    fn conversions_record_the_metrics() {
+        let dir = test_util::temp_dir("locomotion");
+        let path = |file_name: &str| dir.join(file_name);
+        fs::write(path("walk.bvh"), walk(121, 100.0, 0.0, 30)).unwrap();
+        fs::write(path("idle.bvh"), walk(31, 0.0, 0.0, 0)).unwrap();
+
+        let options = test_util::options(&["--locomotion", "--up-axis", "y"]);
+        let conversion = convert(&path("walk.bvh"), &path("out.bvh"), &path("out.csv"), &path("out.raw"), &options, None).unwrap();
+        let expected = analyze_walk(121, 100.0, 0.0, 30);
+        assert_eq!(conversion.locomotion, Some(expected));
+        let mocap = raw::read(&fs::read(path("out.raw")).unwrap(), DEFAULT_MAX_DEPTH).unwrap();
+        assert!(mocap.metadata.contains(&expected.metadata()));
+
+        let input_file_names = ["walk.bvh", "idle.bvh"].iter().map(|file_name| path(file_name).to_string_lossy().into_owned()).collect::<Vec<_>>();
+        let args = ["pack", "--locomotion", "--up-axis", "y", "clips.mcp"].iter().map(|arg| arg.to_string()).chain(input_file_names.iter().cloned());
+        ::pack(&path("clips.mcp"), &input_file_names, &Options::parse(args).unwrap(), None).unwrap();
+        let container = container::read(&fs::read(path("clips.mcp")).unwrap(), DEFAULT_MAX_DEPTH).unwrap();
+        let metadata = container.clips.iter().map(|clip| clip.mocap.metadata.iter().find(|(key, _)| key == KEY).unwrap().1.clone()).collect::<Vec<_>>();
+        assert_eq!(metadata, vec![expected.metadata().1, analyze_walk(31, 0.0, 0.0, 0).metadata().1]);
+        assert!(metadata[1].ends_with(" 0 none in-place"), "{}", metadata[1]);
+    }
+}
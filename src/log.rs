@@ -0,0 +1,92 @@
+use std::cell::RefCell;
+
+// Informational lines, warnings and debug messages go through here rather than straight to stdout
+// or stderr, so a batch job can collect the messages for each file and print them together (see
+// batch.rs).
+
+thread_local! {
+    static CAPTURED: RefCell<Option<Vec<Message>>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Info(String), // For stdout
+    Diagnostic(String), // A warning or debug message, for stderr
+}
+
+impl Message {
+    pub fn print(&self) {
+        match *self {
+            Message::Info(ref line) => println!("{}", line),
+            Message::Diagnostic(ref line) => eprintln!("{}", line),
+        }
+    }
+}
+
+pub fn info(message: String) {
+    emit(Message::Info(message));
+}
+
+pub fn warning(message: String) {
+    emit(Message::Diagnostic(format!("warning: {}", message)));
+}
+
+pub fn debug(message: String) {
+    emit(Message::Diagnostic(format!("debug: {}", message)));
+}
+
+fn emit(message: Message) {
+    CAPTURED.with(|captured| match *captured.borrow_mut() {
+        Some(ref mut messages) => messages.push(message),
+        None => message.print(),
+    });
+}
+
+// Runs `f`, returning the messages it emitted on this thread instead of printing them.
+pub fn capture<T, F: FnOnce() -> T>(f: F) -> (T, Vec<Message>) {
+    let previous = CAPTURED.with(|captured| captured.replace(Some(Vec::new())));
+    let ret = f();
+    let messages = CAPTURED.with(|captured| captured.replace(previous)).unwrap_or_default();
+    (ret, messages)
+}
+
+// The warnings and debug messages among `messages`, as a --report records them.
+pub fn diagnostics(messages: &[Message]) -> Vec<String> {
+    messages.iter().filter_map(|message| match *message {
+        Message::Diagnostic(ref line) => Some(line.clone()),
+        Message::Info(_) => None,
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_keeps_messages_in_order() {
+        let ((), messages) = capture(|| {
+            info("one".into());
+            warning("two".into());
+            info("three".into());
+            debug("four".into());
+        });
+        assert_eq!(messages, vec![
+            Message::Info("one".into()),
+            Message::Diagnostic("warning: two".into()),
+            Message::Info("three".into()),
+            Message::Diagnostic("debug: four".into()),
+        ]);
+        assert_eq!(diagnostics(&messages), vec!["warning: two".to_string(), "debug: four".to_string()]);
+    }
+
+    #[test]
+    fn nested_captures_restore_the_outer_one() {
+        let ((), outer) = capture(|| {
+            info("before".into());
+            let ((), inner) = capture(|| info("inner".into()));
+            assert_eq!(inner, vec![Message::Info("inner".into())]);
+            info("after".into());
+        });
+        assert_eq!(outer, vec![Message::Info("before".into()), Message::Info("after".into())]);
+    }
+}
@@ -0,0 +1,59 @@
+use bvh;
+
+// Looping clips. A clip loops with period p if every frame is within the tolerance (on every
+// channel) of the frame p earlier; a clip whose last frame matches its first loops with a period
+// one less than its length. Only one period needs to be stored: the decoder can replay it to get
+// the rest back.
+
+// Metadata keys recorded when a clip is trimmed to one period
+pub const LOOP_KEY: &str = "loop";
+pub const SOURCE_NUM_FRAMES_KEY: &str = "loop_source_num_frames";
+
+// The shortest period the clip loops with, if any.
+pub fn find_period(bvh: &bvh::Bvh, tolerance: f64) -> Option<usize> {
+    let frames = &bvh.motion.frames;
+    (1..frames.len()).find(|&period| {
+        same_frame(&frames[period], &frames[0], tolerance)
+            && (period..frames.len()).all(|index| same_frame(&frames[index], &frames[index % period], tolerance))
+    })
+}
+
+fn same_frame(a: &[f64], b: &[f64], tolerance: f64) -> bool {
+    a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() <= tolerance)
+}
+
+// Keeps only the first `period` frames, returning the metadata the decoder needs to unroll them.
+pub fn trim_to_period(bvh: &mut bvh::Bvh, period: usize) -> Vec<(String, String)> {
+    let original = bvh.motion.num_frames;
+    bvh.motion.frames.truncate(period);
+    bvh.motion.num_frames = period as u32;
+
+    vec![
+        (LOOP_KEY.into(), "true".into()),
+        (SOURCE_NUM_FRAMES_KEY.into(), format!("{}", original)),
+    ]
+}
+
+// Replays a clip trimmed by `trim_to_period` (going by its metadata) up to its original length.
+// Returns whether there was anything to unroll.
+pub fn unroll(bvh: &mut bvh::Bvh, metadata: &[(String, String)]) -> bool {
+    let value = |key: &str| metadata.iter().find(|entry| entry.0 == key).map(|entry| entry.1.as_str());
+    if value(LOOP_KEY) != Some("true") {
+        return false;
+    }
+    let num_frames = match value(SOURCE_NUM_FRAMES_KEY).and_then(|value| value.parse::<u32>().ok()) {
+        Some(num_frames) => num_frames,
+        None => return false,
+    };
+
+    let period = bvh.motion.frames.len();
+    if period == 0 {
+        return false;
+    }
+    for index in period..num_frames as usize {
+        let frame = bvh.motion.frames[index % period].clone();
+        bvh.motion.frames.push(frame);
+    }
+    bvh.motion.num_frames = num_frames;
+    true
+}
@@ -0,0 +1,106 @@
+use bvh;
+
+use error::MocapError;
+use profile::Lossless;
+use selector::{self, Selector};
+use {channel_type, count_bvh_channels, ChannelType};
+
+// Which channels the profile asks to store losslessly, per flat channel index. A lossless channel
+// keeps its exact f64 values instead of being quantized, at 8 bytes per frame rather than 1,
+// for channels whose errors show (a root's translation, say), while the rest stay quantized.
+pub fn lossless_channels(bvh: &bvh::Bvh, lossless: &[Lossless]) -> Result<Vec<bool>, MocapError> {
+    let mut ret = vec![false; count_bvh_channels(&bvh.hierarchy.root)];
+    if lossless.is_empty() {
+        return Ok(ret);
+    }
+
+    let mut types = Vec::new();
+    push_channel_types(&bvh.hierarchy.root, &mut types);
+    for lossless in lossless.iter() {
+        let matches = selector::find_joints(&bvh.hierarchy.root, &Selector::parse(&lossless.selector)?);
+        if matches.is_empty() {
+            return Err(MocapError::JointNotFound(lossless.selector.clone()));
+        }
+        for joint_match in matches.iter() {
+            for index in joint_match.channel_index..joint_match.channel_index + joint_match.num_channels {
+                if lossless.type_.is_none_or(|type_| types[index] == type_) {
+                    ret[index] = true;
+                }
+            }
+        }
+    }
+    Ok(ret)
+}
+
+fn push_channel_types(joint: &bvh::Joint, types: &mut Vec<ChannelType>) {
+    types.extend(joint.channels.iter().map(channel_type));
+    if let bvh::JointChildren::Joints(ref joints) = joint.children {
+        for child in joints.iter() {
+            push_channel_types(child, types);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use bitpack;
+    use conversion::ConversionSettings;
+    use depth::DEFAULT_MAX_DEPTH;
+    use periodic::Limits;
+    use raw;
+    use test_util;
+    use view::MocapView;
+    use {build_bvh, build_mocap, make_lossless, Mocap};
+
+    const NUM_FRAMES: usize = 35;
+
+    #[test]
+    fn selects_the_declared_channels() {
+        let bvh = test_util::sine_clip(2);
+        let lossless = [
+            Lossless { selector: "Hips".into(), type_: Some(ChannelType::TranslationY) },
+            Lossless { selector: "LeftLeg".into(), type_: None },
+        ];
+        let selected = lossless_channels(&bvh, &lossless).unwrap();
+        assert_eq!(selected.iter().enumerate().filter(|(_, lossless)| **lossless).map(|(index, _)| index).collect::<Vec<_>>(), vec![1, 12, 13, 14]);
+        assert!(matches!(lossless_channels(&bvh, &[Lossless { selector: "Tail".into(), type_: None }]), Err(MocapError::JointNotFound(_))));
+    }
+
+    #[test]
+    fn mixed_clip_decodes_each_channel_its_own_way() {
+        let bvh = test_util::sine_clip(NUM_FRAMES);
+        let lossless = lossless_channels(&bvh, &[Lossless { selector: "Hips".into(), type_: None }]).unwrap();
+        let mut mocap = build_mocap(&bvh, &ConversionSettings::default().settings());
+        make_lossless(&mut mocap, &bvh.motion.frames, &lossless);
+
+        type WriteRaw = fn(&Mocap, &mut Vec<u8>) -> io::Result<()>;
+        let writes: [WriteRaw; 4] = [
+            |mocap, w| raw::write(mocap, Limits::default(), w),
+            |mocap, w| raw::write_indexed(mocap, 8, bitpack::Layout::Packed, Limits::default(), w),
+            |mocap, w| raw::write_sparse(mocap, Limits::default(), w),
+            |mocap, w| raw::write_bit_planes(mocap, Limits::default(), w),
+        ];
+        for write in writes.iter() {
+            let mut data = Vec::new();
+            write(&mocap, &mut data).unwrap();
+            let read = raw::read(&data, DEFAULT_MAX_DEPTH).unwrap();
+            assert_eq!(read.channels().iter().map(|channel| channel.values.is_some()).collect::<Vec<_>>(), lossless);
+
+            let steps = read.channels().iter().map(|channel| channel.value_range as f64 / 255.0).collect::<Vec<_>>();
+            let decoded = build_bvh(&read).motion.frames;
+            assert_eq!(MocapView::parse(&data, DEFAULT_MAX_DEPTH).unwrap().to_bvh(1).motion.frames, decoded);
+            for (decoded, original) in decoded.iter().zip(bvh.motion.frames.iter()) {
+                for channel in 0..test_util::NUM_CHANNELS {
+                    if lossless[channel] {
+                        assert_eq!(decoded[channel], original[channel]);
+                    } else {
+                        assert!((decoded[channel] - original[channel]).abs() <= steps[channel] + 1e-4);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use std::cell::RefCell;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use cache;
+use cancel;
+use error::MocapError;
+use json;
+use options::Options;
+
+// A record of what a run produced, written with --manifest for pipelines to pick up:
+//
+//   {
+//     "tool": "mocap",
+//     "version": "0.1.0",
+//     "timestamp": "2024-01-01T12:00:00Z",
+//     "command": "batch",
+//     "settings": ["--bits", "8", ...],
+//     "settings_hash": "0123456789abcdef0123456789abcdef",
+//     "files": [
+//       { "source": "in/walk.bvh", "status": "ok", "cached": false, "outputs": [
+//         { "path": "out/walk.bvh", "size": 1234, "hash": "0123456789abcdef0123456789abcdef" }, ... ] },
+//       { "source": "in/bad.bvh", "status": "failed", "error": "...", "outputs": [] }
+//     ]
+//   }
+//
+// `settings` are the conversion options as command line arguments (see
+// `Options::conversion_args`), and `settings_hash` is the hash the batch cache keys on (those and
+// the contents of the files they name, see cache.rs), so a build system can tell whether outputs
+// were made the same way. An output's `hash` is the same 128-bit hash of its contents. A batch
+// has an entry per input file and every other command a single one; commands reading several
+// inputs (concat, pack, diff, ...) list them as `"sources": [...]` instead of `"source"`. Failed
+// files list no outputs, even if some were written before the failure.
+//
+// Outputs are recorded as they're written rather than listed per command: everything writing an
+// output file opens it with `create` (or calls `register` after writing it some other way), which
+// adds it to the outputs `record` is collecting on this thread. Each appears once, however many
+// times it was written. The manifest itself and any --report aren't listed.
+//
+// Outputs are also written atomically, so a failed or cancelled conversion (see cancel.rs) never
+// leaves a half-written file, or some of its outputs but not the others, where a pipeline would
+// pick it up. Within `atomically`, which every command, and each file of a batch, runs in, `create`
+// opens a temporary file next to the output instead (`.<file name>.<process id>.tmp`, hidden and
+// ignored by batch), and only once the work succeeds are they all renamed over the outputs; if it
+// fails they're removed. Anything reading back an output before then has to read `written(path)`.
+// A process killed outright can still leave temporary files, but never partial outputs.
+
+thread_local! {
+    static RECORDED: RefCell<Option<Vec<PathBuf>>> = const { RefCell::new(None) };
+    // Per output being written in `atomically`, the temporary file holding it
+    static STAGED: RefCell<Option<Vec<(PathBuf, PathBuf)>>> = const { RefCell::new(None) };
+}
+
+// `File::create`, recording the file as an output.
+pub fn create<P: AsRef<Path>>(path: P) -> io::Result<File> {
+    let staged = stage(path.as_ref());
+    let file = File::create(&staged)?;
+    register(path.as_ref());
+    if staged != path.as_ref() {
+        pause_for_interrupt();
+    }
+    Ok(file)
+}
+
+// For tests/cancel.rs: with PAUSE_AFTER_STAGING in the environment, the first output staged waits
+// (for a minute at most) for a Ctrl-C, so the test can interrupt a run at a known point, with an
+// output half written, however fast the run would otherwise finish.
+pub const PAUSE_AFTER_STAGING: &str = "MOCAP_TEST_PAUSE_AFTER_STAGING";
+
+fn pause_for_interrupt() {
+    static PAUSED: AtomicBool = AtomicBool::new(false);
+    if env::var_os(PAUSE_AFTER_STAGING).is_none() || PAUSED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let start = Instant::now();
+    while !cancel::interrupted() && start.elapsed() < Duration::from_secs(60) {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+// Where to write the output `path`: its temporary file within `atomically`, and otherwise `path`.
+pub fn stage(path: &Path) -> PathBuf {
+    STAGED.with(|staged| match *staged.borrow_mut() {
+        Some(ref mut staged) => match staged.iter().find(|(output, _)| output == path) {
+            Some((_, temporary)) => temporary.clone(),
+            None => {
+                let file_name = path.file_name().map_or("output".into(), |file_name| file_name.to_string_lossy().into_owned());
+                let temporary = path.with_file_name(format!(".{}.{}.tmp", file_name, process::id()));
+                staged.push((path.to_path_buf(), temporary.clone()));
+                temporary
+            }
+        },
+        None => path.to_path_buf(),
+    })
+}
+
+// Where the output `path` has been written so far.
+pub fn written(path: &Path) -> PathBuf {
+    STAGED.with(|staged| staged.borrow().as_ref()
+        .and_then(|staged| staged.iter().find(|(output, _)| output == path).map(|(_, temporary)| temporary.clone()))
+        .unwrap_or_else(|| path.to_path_buf()))
+}
+
+// Runs `f` with the outputs it writes on this thread staged, moving them into place if it
+// succeeds and removing them if it doesn't.
+pub fn atomically<T, F: FnOnce() -> Result<T, MocapError>>(f: F) -> Result<T, MocapError> {
+    let previous = STAGED.with(|staged| staged.replace(Some(Vec::new())));
+    let result = f();
+    let staged = STAGED.with(|staged| staged.replace(previous)).unwrap_or_default();
+    let mut outputs = staged.iter();
+    if result.is_ok() {
+        for (output, temporary) in outputs.by_ref() {
+            if let Err(e) = fs::rename(temporary, output) {
+                let _ = fs::remove_file(temporary);
+                for (_, temporary) in outputs {
+                    let _ = fs::remove_file(temporary);
+                }
+                return Err(e.into());
+            }
+        }
+    }
+    for (_, temporary) in outputs {
+        let _ = fs::remove_file(temporary);
+    }
+    result
+}
+
+pub fn register(path: &Path) {
+    RECORDED.with(|recorded| {
+        if let Some(ref mut paths) = *recorded.borrow_mut() {
+            if !paths.iter().any(|recorded| recorded == path) {
+                paths.push(path.to_path_buf());
+            }
+        }
+    });
+}
+
+// Runs `f`, returning the outputs it wrote on this thread, in the order they were first written.
+pub fn record<T, F: FnOnce() -> T>(f: F) -> (T, Vec<PathBuf>) {
+    let previous = RECORDED.with(|recorded| recorded.replace(Some(Vec::new())));
+    let ret = f();
+    let paths = RECORDED.with(|recorded| recorded.replace(previous)).unwrap_or_default();
+    (ret, paths)
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub sources: Vec<PathBuf>,
+    pub outputs: Vec<PathBuf>,
+    pub error: Option<String>,
+    pub cached: bool,
+}
+
+pub fn write(file_name: &Path, command: &str, options: &Options, entries: &[Entry]) -> Result<(), MocapError> {
+    let settings_hash = cache::settings_hash(options)?;
+    let mut w = File::create(file_name)?;
+    writeln!(w, "{{")?;
+    writeln!(w, "  \"tool\": \"mocap\",")?;
+    writeln!(w, "  \"version\": \"{}\",", env!("CARGO_PKG_VERSION"))?;
+    writeln!(w, "  \"timestamp\": \"{}\",", timestamp(SystemTime::now()))?;
+    writeln!(w, "  \"command\": \"{}\",", command)?;
+    writeln!(w, "  \"settings\": [{}],", options.conversion_args().iter().map(|arg| format!("\"{}\"", json::escape(arg))).collect::<Vec<_>>().join(", "))?;
+    writeln!(w, "  \"settings_hash\": \"{:032x}\",", settings_hash)?;
+    writeln!(w, "  \"files\": [")?;
+    for (index, entry) in entries.iter().enumerate() {
+        let quoted = |path: &PathBuf| format!("\"{}\"", json::escape(&path.to_string_lossy()));
+        match entry.sources.len() {
+            1 => write!(w, "    {{ \"source\": {}, ", quoted(&entry.sources[0]))?,
+            _ => write!(w, "    {{ \"sources\": [{}], ", entry.sources.iter().map(quoted).collect::<Vec<_>>().join(", "))?,
+        }
+        match entry.error {
+            None => write!(w, "\"status\": \"ok\", \"cached\": {}, ", entry.cached)?,
+            Some(ref error) => write!(w, "\"status\": \"failed\", \"error\": \"{}\", ", json::escape(error))?,
+        }
+        let mut outputs = Vec::new();
+        if entry.error.is_none() {
+            for output in entry.outputs.iter() {
+                let data = fs::read(output)?;
+                outputs.push(format!("{{ \"path\": {}, \"size\": {}, \"hash\": \"{:032x}\" }}", quoted(output), data.len(), cache::hash(&data)));
+            }
+        }
+        let separator = if index + 1 < entries.len() { "," } else { "" };
+        if outputs.is_empty() {
+            writeln!(w, "\"outputs\": [] }}{}", separator)?;
+        } else {
+            writeln!(w, "\"outputs\": [")?;
+            writeln!(w, "      {} ] }}{}", outputs.join(",\n      "), separator)?;
+        }
+    }
+    writeln!(w, "  ]")?;
+    writeln!(w, "}}")?;
+    Ok(())
+}
+
+// RFC 3339 in UTC, to the second.
+pub fn timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    let (days, seconds) = ((seconds / 86400) as i64, seconds % 86400);
+
+    // Days since 1970-01-01 to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, seconds / 3600, (seconds / 60) % 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use json::Value;
+    use test_util;
+
+    // Every file under `dir`, with its contents
+    fn files(dir: &Path, ret: &mut Vec<(PathBuf, Vec<u8>)>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files(&path, ret);
+            } else {
+                let data = fs::read(&path).unwrap();
+                ret.push((path, data));
+            }
+        }
+    }
+
+    // Runs mocap with `args` and --manifest in `dir`, checking that the manifest lists every file
+    // the run wrote, each exactly once with its size and hash. Returns the manifest's files.
+    fn check_run(dir: &Path, args: &[&str]) -> Vec<Value> {
+        let mut before = Vec::new();
+        files(dir, &mut before);
+        let manifest_file_name = dir.join("manifest.json");
+        let options = Options::parse(args.iter().map(|arg| arg.to_string()).chain(vec!["--manifest".to_string(), manifest_file_name.to_string_lossy().into_owned()])).unwrap();
+        ::run(&options, None).unwrap();
+
+        let mut written = Vec::new();
+        files(dir, &mut written);
+        written.retain(|file| file.0 != manifest_file_name && !before.contains(file));
+        let manifest = json::parse(&fs::read_to_string(&manifest_file_name).unwrap()).unwrap();
+        let entries = manifest.get("files").and_then(Value::as_array).unwrap().to_vec();
+        let mut listed = Vec::new();
+        for entry in entries.iter() {
+            assert_eq!(entry.get("status").and_then(Value::as_str), Some("ok"));
+            for output in entry.get("outputs").and_then(Value::as_array).unwrap().iter() {
+                let path = PathBuf::from(output.get("path").and_then(Value::as_str).unwrap());
+                let data = fs::read(&path).unwrap();
+                assert_eq!(output.get("size").and_then(Value::as_f64), Some(data.len() as f64), "{}", path.display());
+                assert_eq!(output.get("hash").and_then(Value::as_str), Some(format!("{:032x}", cache::hash(&data)).as_str()), "{}", path.display());
+                listed.push(path);
+            }
+        }
+        let mut listed_sorted = listed.clone();
+        listed_sorted.sort();
+        listed_sorted.dedup();
+        assert_eq!(listed_sorted.len(), listed.len(), "{:?} lists an output twice", args);
+        let mut written = written.into_iter().map(|file| file.0).collect::<Vec<_>>();
+        written.sort();
+        assert_eq!(listed_sorted, written, "{:?}", args);
+        fs::remove_file(&manifest_file_name).unwrap();
+        entries
+    }
+
+    #[test]
+    fn lists_every_output_of_every_command_once() {
+        let dir = test_util::temp_dir("manifest");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        fs::write(dir.join("walk.bvh"), test_util::clip_text(20, test_util::sine)).unwrap();
+        fs::write(dir.join("run.bvh"), test_util::clip_text(12, |frame, channel| test_util::sine(frame * 2, channel))).unwrap();
+
+        let entries = check_run(&dir, &[
+            "--marker", "3:step", "--export-channel-map", &path("map.json"), "--export-curves", &path("curves.json"),
+            "--export-markers", &path("markers.json"), "--save-markers", &path("markers.txt"), "--stats-json", &path("stats.json"),
+            "--ranges-out", &path("ranges.txt"), "--export-world-matrices", &path("world.bin"), "--export-joint-graph", &path("graph.json"),
+            "--export-fixed-point", &path("walk.h"),
+            &path("walk.bvh"), &path("out.bvh"), &path("out.csv"), &path("out.raw"),
+        ]);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].get("source").and_then(Value::as_str), Some(path("walk.bvh").as_str()));
+        assert_eq!(entries[0].get("outputs").and_then(Value::as_array).unwrap().len(), 12);
+
+        check_run(&dir, &["decode", &path("out.raw"), &path("decoded.bvh")]);
+        let entries = check_run(&dir, &["concat", &path("twice.raw"), &path("out.raw"), &path("out.raw")]);
+        assert_eq!(entries[0].get("sources").and_then(Value::as_array).unwrap().len(), 2);
+        check_run(&dir, &["pack", "--reference-pose", &path("clips.mcp"), &path("walk.bvh"), &path("run.bvh")]);
+        check_run(&dir, &["unpack", &path("clips.mcp"), &path("unpacked")]);
+        // Writing nothing, it lists nothing
+        let entries = check_run(&dir, &["info", &path("clips.mcp")]);
+        assert!(entries[0].get("outputs").and_then(Value::as_array).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_batch_lists_each_files_outputs_under_it() {
+        let dir = test_util::temp_dir("manifest-batch");
+        let (input_dir, output_dir) = (dir.join("in"), dir.join("out"));
+        fs::create_dir_all(input_dir.join("sub")).unwrap();
+        let inputs = [input_dir.join("walk.bvh"), input_dir.join("sub").join("run.bvh")];
+        for (index, input) in inputs.iter().enumerate() {
+            fs::write(input, test_util::clip_text(10 + index * 5, test_util::sine)).unwrap();
+        }
+        let entries = check_run(&dir, &["batch", "--recursive", input_dir.to_str().unwrap(), output_dir.to_str().unwrap()]);
+
+        let mut sources = entries.iter().map(|entry| PathBuf::from(entry.get("source").and_then(Value::as_str).unwrap())).collect::<Vec<_>>();
+        sources.sort();
+        let mut expected = inputs.to_vec();
+        expected.sort();
+        assert_eq!(sources, expected);
+        for entry in entries.iter() {
+            let source = PathBuf::from(entry.get("source").and_then(Value::as_str).unwrap());
+            let base = output_dir.join(source.strip_prefix(&input_dir).unwrap());
+            let outputs = entry.get("outputs").and_then(Value::as_array).unwrap().iter().map(|output| PathBuf::from(output.get("path").and_then(Value::as_str).unwrap())).collect::<Vec<_>>();
+            assert_eq!(outputs, ["bvh", "csv", "raw"].iter().map(|extension| base.with_extension(extension)).collect::<Vec<_>>());
+        }
+    }
+}
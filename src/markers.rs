@@ -0,0 +1,180 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use error::MocapError;
+use json;
+use log;
+use manifest;
+
+// Named events at specific frames (footsteps, sync points, ...). A marker track is kept sorted by
+// frame; several markers may share a frame.
+pub type Marker = (u32, String);
+
+// `<frame>:<name>`, as passed to --marker.
+pub fn parse(spec: &str) -> Option<Marker> {
+    let colon = spec.find(':')?;
+    let frame = spec[..colon].trim().parse().ok()?;
+    let name = spec[colon + 1..].trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((frame, name.into()))
+}
+
+// A marker file has one `<frame>,<name>` (or `<frame>:<name>`) per line. Blank lines and lines
+// starting with # are ignored.
+pub fn read_file(path: &Path) -> Result<Vec<Marker>, MocapError> {
+    let contents = fs::read_to_string(path)?;
+    let mut ret = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let marker = parse(&line.replacen(',', ":", 1))
+            .ok_or_else(|| MocapError::InvalidMarkers(format!("{}:{}: expected <frame>,<name>, got {}", path.display(), index + 1, line)))?;
+        ret.push(marker);
+    }
+    Ok(ret)
+}
+
+// Writes `markers` as a marker file, which --markers (and `read_file`) reads back.
+pub fn write_file(markers: &[Marker], path: &Path) -> Result<(), MocapError> {
+    if let Some((frame, name)) = markers.iter().find(|(_, name)| name.contains('\n') || name.contains('\r') || name.trim() != name) {
+        return Err(MocapError::InvalidMarkers(format!("marker {:?} at frame {} can't be written to a marker file", name, frame)));
+    }
+    let mut w = io::BufWriter::new(manifest::create(path)?);
+    writeln!(w, "# <frame>,<name>")?;
+    for (frame, name) in markers.iter() {
+        writeln!(w, "{},{}", frame, name)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+pub fn sort(markers: &mut [Marker]) {
+    // Stable, so markers on the same frame keep the order they were given in
+    markers.sort_by_key(|marker| marker.0);
+}
+
+// Drops the markers at or past `num_frames`, for when frames are trimmed off the end, warning about
+// each one if the frames weren't trimmed (`trimmed` false), since that means it was given wrong.
+pub fn drop_past_end(markers: &mut Vec<Marker>, num_frames: u32, trimmed: bool) {
+    markers.retain(|(frame, name)| {
+        if *frame < num_frames {
+            return true;
+        }
+        if !trimmed {
+            log::warning(format!("dropping marker {} at frame {}, past the end of the clip ({} frames)", name, frame, num_frames));
+        }
+        false
+    });
+}
+
+// `other`'s markers, moved to follow a clip of `num_frames` frames.
+pub fn appended(other: &[Marker], num_frames: u32) -> Vec<Marker> {
+    other.iter().map(|(frame, name)| (frame + num_frames, name.clone())).collect()
+}
+
+pub fn write_json<W: Write>(markers: &[Marker], frame_time: f32, w: &mut W) -> io::Result<()> {
+    writeln!(w, "[")?;
+    for (index, (frame, name)) in markers.iter().enumerate() {
+        let separator = if index + 1 < markers.len() { "," } else { "" };
+        writeln!(w, "  {{ \"frame\": {}, \"time\": {}, \"name\": \"{}\" }}{}", frame, *frame as f32 * frame_time, json::escape(name), separator)?;
+    }
+    writeln!(w, "]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use bvh;
+
+    use directives::Directives;
+    use log;
+    use resample::{self, Interpolation};
+    use test_util::{self, clip_text, parse as parse_bvh, sine, sine_clip};
+    use load_bvh;
+
+    // The markers `bvh` converted with `args` ends up with, and the warnings on the way
+    fn converted(bvh: bvh::Bvh, args: &[&str]) -> (Vec<Marker>, Vec<String>) {
+        let (source, messages) = log::capture(|| load_bvh(bvh, &Directives::default(), Path::new("in.bvh"), &test_util::options(args)).unwrap());
+        (source.markers, log::diagnostics(&messages))
+    }
+
+    fn markers(frames: &[u32]) -> Vec<Marker> {
+        frames.iter().map(|frame| (*frame, format!("m{}", frame))).collect()
+    }
+
+    const MARKERS: &[&str] = &["--marker", "5:m5", "--marker", "19:m19", "--marker", "20:m20", "--marker", "35:m35"];
+
+    #[test]
+    fn max_frames_drops_the_markers_it_trims_off() {
+        let (kept, warnings) = converted(sine_clip(40), &[MARKERS, &["--max-frames", "20"]].concat());
+        assert_eq!(kept, markers(&[5, 19]));
+        // Only the truncation itself is worth a warning
+        assert_eq!(warnings, vec!["warning: truncating 40 frames to 20".to_string()]);
+
+        // Without trimming, a marker past the end was given wrong and is dropped loudly
+        let (kept, warnings) = converted(sine_clip(30), MARKERS);
+        assert_eq!(kept, markers(&[5, 19, 20]));
+        assert_eq!(warnings, vec!["warning: dropping marker m35 at frame 35, past the end of the clip (30 frames)".to_string()]);
+    }
+
+    #[test]
+    fn loop_trim_keeps_the_markers_in_the_first_period() {
+        // Three periods of 16 frames
+        let looping = parse_bvh(&clip_text(48, |frame, channel| sine(frame % 16, channel)));
+        let (kept, warnings) = converted(looping, &[MARKERS, &["--loop-trim"]].concat());
+        assert_eq!(kept, markers(&[5]));
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn resampling_moves_markers_to_the_nearest_output_frame() {
+        // 0.033333 s frames to 60 fps: twice as many
+        let mut upsampled = markers(&[0, 5, 19, 39]);
+        let mut bvh = sine_clip(40);
+        resample::apply(&mut bvh, &mut upsampled, 60.0, Interpolation::Linear).unwrap();
+        assert_eq!(bvh.motion.frames.len(), 78);
+        assert_eq!(upsampled.iter().map(|marker| marker.0).collect::<Vec<_>>(), vec![0, 10, 38, 77]);
+
+        // And to 10 fps, every third frame, rounding to the nearest; the last stays on the clip
+        let mut downsampled = markers(&[0, 4, 5, 38, 39]);
+        let mut bvh = sine_clip(40);
+        resample::apply(&mut bvh, &mut downsampled, 10.0, Interpolation::Cubic).unwrap();
+        assert_eq!(bvh.motion.frames.len(), 13);
+        assert_eq!(downsampled.iter().map(|marker| marker.0).collect::<Vec<_>>(), vec![0, 1, 2, 12, 12]);
+        // Still in order, with their names
+        assert_eq!(downsampled[3].1, "m38");
+
+        // Through a conversion with --fps too
+        let (kept, _) = converted(sine_clip(40), &["--marker", "5:m5", "--fps", "60"]);
+        assert_eq!(kept, vec![(10, "m5".to_string())]);
+    }
+
+    #[test]
+    fn parses_specs_and_files() {
+        assert_eq!(parse("12: Footstep "), Some((12, "Footstep".into())));
+        for spec in ["12", "12:", "x:Footstep", "-1:Footstep"].iter() {
+            assert_eq!(parse(spec), None, "{}", spec);
+        }
+
+        let dir = test_util::temp_dir("markers");
+        let path = dir.join("markers.txt");
+        let track = vec![(0, "start".to_string()), (3, "step, left".to_string()), (3, "sync".to_string())];
+        write_file(&track, &path).unwrap();
+        assert_eq!(read_file(&path).unwrap(), track);
+        assert!(write_file(&[(1, " padded".into())], &path).is_err());
+
+        fs::write(&path, "# frames\n\n1,a\nb\n").unwrap();
+        match read_file(&path) {
+            Err(MocapError::InvalidMarkers(message)) => assert_eq!(message, format!("{}:4: expected <frame>,<name>, got b", path.display())),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::path::Path;
+
+use bvh;
+
+use error::MocapError;
+use profile::Profile;
+use selector::{self, Selector};
+use count_bvh_channels;
+
+// Partial-body variants of a clip, for runtimes blending an upper-body layer over a lower-body one.
+// With --mask only the selected joints keep their motion; every other channel is held at the pose
+// (the bind pose with --bind-pose, otherwise the first frame) on every frame. Nothing is removed
+// from the hierarchy, so every variant of a clip has the same skeleton and they can be posed
+// together. The mask's name is recorded in the metadata.
+//
+// The upper body is the subtree of the first joint below the root (in pre-order) whose name looks
+// like the start of the spine; the lower body is everything else, including the root. A mask file
+// is a profile (see profile.rs) with a [mask] table naming the joints to keep.
+
+// Metadata key holding the mask's name
+pub const KEY: &str = "mask";
+
+// Lowercased name fragments marking the first spine joint
+const SPINE_NAMES: &[&str] = &["spine", "back", "chest", "torso", "abdomen", "waist"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mask {
+    Upper,
+    Lower,
+    File(String),
+}
+
+impl Mask {
+    // `upper`, `lower` or a mask file, as --mask takes.
+    pub fn parse(s: &str) -> Mask {
+        match s {
+            "upper" => Mask::Upper,
+            "lower" => Mask::Lower,
+            file_name => Mask::File(file_name.into()),
+        }
+    }
+}
+
+// Holds the channels `mask` doesn't keep at `pose` (the first frame if None), returning the
+// metadata recording it.
+pub fn apply(bvh: &mut bvh::Bvh, mask: &Mask, pose: Option<&[f64]>) -> Result<Vec<(String, String)>, MocapError> {
+    let (name, kept) = kept_channels(bvh, mask)?;
+    let pose = match pose {
+        Some(pose) => pose.to_vec(),
+        None => match bvh.motion.frames.first() {
+            Some(frame) => frame.clone(),
+            None => return Ok(vec![(KEY.into(), name)]),
+        },
+    };
+    for frame in bvh.motion.frames.iter_mut() {
+        for ((value, pose_value), kept) in frame.iter_mut().zip(pose.iter()).zip(kept.iter()) {
+            if !*kept {
+                *value = *pose_value;
+            }
+        }
+    }
+    Ok(vec![(KEY.into(), name)])
+}
+
+// The mask's name and whether it keeps each channel, in flat channel order.
+pub fn kept_channels(bvh: &bvh::Bvh, mask: &Mask) -> Result<(String, Vec<bool>), MocapError> {
+    let root = &bvh.hierarchy.root;
+    let mut ret = vec![false; count_bvh_channels(root)];
+    let name = match *mask {
+        Mask::Upper | Mask::Lower => {
+            let mut names = Vec::new();
+            push_names(root, &mut names);
+            let upper_body = selector::find_joints(root, &Selector::parse("**")?).into_iter().zip(names.iter())
+                .skip(1)
+                .find(|(_, name)| SPINE_NAMES.iter().any(|spine_name| name.to_lowercase().contains(spine_name)))
+                .map(|(joint_match, _)| joint_match)
+                .ok_or_else(|| MocapError::Usage(format!("no joint below the root is named like the start of the spine ({}), so there's no upper body; use a mask file", SPINE_NAMES.join(", "))))?;
+            let selector = Selector::parse(&format!("/{}/**", upper_body.path))?;
+            for joint_match in selector::find_joints(root, &selector).iter() {
+                for kept in ret[joint_match.channel_index..joint_match.channel_index + joint_match.num_channels].iter_mut() {
+                    *kept = true;
+                }
+            }
+            if *mask == Mask::Lower {
+                for kept in ret.iter_mut() {
+                    *kept = !*kept;
+                }
+                "lower".to_string()
+            } else {
+                "upper".to_string()
+            }
+        }
+        Mask::File(ref file_name) => {
+            let mask = Profile::read(Path::new(file_name))?.mask
+                .ok_or_else(|| MocapError::InvalidProfile(format!("{}: no [mask] table", file_name)))?;
+            // Which would hold every channel
+            if mask.joints.is_empty() {
+                return Err(MocapError::InvalidProfile(format!("{}: [mask] lists no joints to keep", file_name)));
+            }
+            for joint in mask.joints.iter() {
+                let matches = selector::find_joints(root, &Selector::parse(joint)?);
+                if matches.is_empty() {
+                    return Err(MocapError::JointNotFound(joint.clone()));
+                }
+                for joint_match in matches.iter() {
+                    for kept in ret[joint_match.channel_index..joint_match.channel_index + joint_match.num_channels].iter_mut() {
+                        *kept = true;
+                    }
+                }
+            }
+            mask.name.unwrap_or_else(|| Path::new(file_name).file_stem().map_or(file_name.clone(), |stem| stem.to_string_lossy().into_owned()))
+        }
+    };
+    Ok((name, ret))
+}
+
+fn push_names<'a>(joint: &'a bvh::Joint, names: &mut Vec<&'a str>) {
+    names.push(&joint.name);
+    if let bvh::JointChildren::Joints(ref joints) = joint.children {
+        for child in joints.iter() {
+            push_names(child, names);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use test_util::{sine_clip, temp_dir};
+
+    #[test]
+    fn upper_and_lower_split_at_the_spine() {
+        let clip = sine_clip(2);
+        let (name, kept) = kept_channels(&clip, &Mask::Upper).unwrap();
+        assert_eq!(name, "upper");
+        // Spine and Head, not Hips or LeftLeg
+        assert_eq!(kept, (0..15).map(|channel| (6..12).contains(&channel)).collect::<Vec<_>>());
+        let (name, lower) = kept_channels(&clip, &Mask::Lower).unwrap();
+        assert_eq!(name, "lower");
+        assert_eq!(lower, kept.iter().map(|kept| !kept).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn a_mask_file_keeping_no_joints_is_refused() {
+        let dir = temp_dir("mask");
+        let file_name = dir.join("mask.toml").to_string_lossy().into_owned();
+        fs::write(&file_name, "[mask]\njoints = []\n").unwrap();
+        match kept_channels(&sine_clip(2), &Mask::File(file_name.clone())) {
+            Err(MocapError::InvalidProfile(message)) => assert_eq!(message, format!("{}: [mask] lists no joints to keep", file_name)),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::ops::Mul;
+
+// Row-major 4x4 affine transform acting on column vectors, so `a * b` applies `b` first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mat4(pub [[f64; 4]; 4]);
+
+impl Mat4 {
+    pub fn identity() -> Mat4 {
+        Mat4([
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn translation(x: f64, y: f64, z: f64) -> Mat4 {
+        let mut ret = Mat4::identity();
+        ret.0[0][3] = x;
+        ret.0[1][3] = y;
+        ret.0[2][3] = z;
+        ret
+    }
+
+    // Rotation of `degrees` around a single axis (0 = X, 1 = Y, 2 = Z), right-handed.
+    pub fn rotation(axis: usize, degrees: f64) -> Mat4 {
+        let (s, c) = degrees.to_radians().sin_cos();
+        let (a, b) = match axis {
+            0 => (1, 2),
+            1 => (2, 0),
+            _ => (0, 1),
+        };
+        let mut ret = Mat4::identity();
+        ret.0[a][a] = c;
+        ret.0[a][b] = -s;
+        ret.0[b][a] = s;
+        ret.0[b][b] = c;
+        ret
+    }
+
+    pub fn position(&self) -> (f64, f64, f64) {
+        (self.0[0][3], self.0[1][3], self.0[2][3])
+    }
+
+    // Decomposes the rotation part into Euler angles (in degrees) for the given axis order, such
+    // that `rotation(order[0], a) * rotation(order[1], b) * rotation(order[2], c)` reproduces it.
+    // `order` must be a permutation of the three axes.
+    pub fn euler_angles(&self, order: [usize; 3]) -> [f64; 3] {
+        let m = &self.0;
+        let (i, j, k) = (order[0], order[1], order[2]);
+        let sign = if (i + 1) % 3 == j { 1.0 } else { -1.0 };
+
+        let b = (sign * m[i][k]).clamp(-1.0, 1.0).asin();
+        let (a, c) = if b.cos() > 1e-9 {
+            ((-sign * m[j][k]).atan2(m[k][k]), (-sign * m[i][j]).atan2(m[i][i]))
+        } else {
+            // Gimbal lock; only the sum/difference of a and c is defined, so put it all in a.
+            ((sign * m[k][j]).atan2(m[j][j]), 0.0)
+        };
+
+        [a.to_degrees(), b.to_degrees(), c.to_degrees()]
+    }
+}
+
+impl Mul for Mat4 {
+    type Output = Mat4;
+
+    fn mul(self, other: Mat4) -> Mat4 {
+        let mut ret = [[0.0; 4]; 4];
+        for (row, ret_row) in ret.iter_mut().enumerate() {
+            for (col, value) in ret_row.iter_mut().enumerate() {
+                *value = (0..4).map(|index| self.0[row][index] * other.0[index][col]).sum();
+            }
+        }
+        Mat4(ret)
+    }
+}
@@ -0,0 +1,101 @@
+use std::io::{self, Write};
+
+use bvh;
+
+use fk;
+use math::Mat4;
+
+// Matrix exports, for renderers that want transforms rather than channel values.
+//
+// The file is a flat array of little-endian f32s with no header: for each frame, for each joint in
+// pre-order (the `joint_index` order of the channel map), one 4x4 matrix of 16 floats. Matrices are
+// column-major, as OpenGL expects them: the first four floats are the first column, and the
+// translation is in floats 12, 13 and 14. They transform column vectors (`p' = M * p`).
+
+// Each joint's local transform (see `fk::local_transform`), leaving composition to the renderer.
+pub fn write_local<W: Write>(bvh: &bvh::Bvh, w: &mut W) -> io::Result<()> {
+    for frame in bvh.motion.frames.iter() {
+        for transform in fk::local_transforms(&bvh.hierarchy.root, frame).iter() {
+            write_matrix(transform, w)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Each joint's world transform (see `fk::world_transforms`): the full forward kinematics baked in.
+pub fn write_world<W: Write>(bvh: &bvh::Bvh, w: &mut W) -> io::Result<()> {
+    for frame in bvh.motion.frames.iter() {
+        for transform in fk::world_transforms(&bvh.hierarchy.root, frame).iter() {
+            write_matrix(transform, w)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_matrix<W: Write>(matrix: &Mat4, w: &mut W) -> io::Result<()> {
+    for col in 0..4 {
+        for row in 0..4 {
+            w.write_all(&(matrix.0[row][col] as f32).to_le_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_util::chain_clip;
+
+    // The f32s in `data`, as `write_*` writes them
+    fn floats(data: &[u8]) -> Vec<f32> {
+        data.chunks(4).map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect()
+    }
+
+    fn assert_near(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected.iter()) {
+            assert!((actual - expected).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn world_matrices_are_column_major_per_joint_per_frame() {
+        let mut data = Vec::new();
+        write_world(&chain_clip(), &mut data).unwrap();
+        let floats = floats(&data);
+        // 2 frames of 3 joints
+        assert_eq!(floats.len(), 2 * 3 * 16);
+        assert_near(&floats[..48], &[
+            // Hips: turned 90 degrees about Z, at (1, 2, 3)
+            0.0, 1.0, 0.0, 0.0,  -1.0, 0.0, 0.0, 0.0,  0.0, 0.0, 1.0, 0.0,  1.0, 2.0, 3.0, 1.0,
+            // Spine: also bent 90 degrees about X, its offset turned to -X
+            0.0, 1.0, 0.0, 0.0,  0.0, 0.0, 1.0, 0.0,  1.0, 0.0, 0.0, 0.0,  -9.0, 2.0, 3.0, 1.0,
+            // Head: its offset bent to +Z
+            0.0, 1.0, 0.0, 0.0,  0.0, 0.0, 1.0, 0.0,  1.0, 0.0, 0.0, 0.0,  -9.0, 2.0, 8.0, 1.0,
+        ]);
+        // At rest, just the offsets added up
+        assert_near(&floats[48 + 32 + 12..48 + 32 + 16], &[0.0, 15.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn local_matrices_leave_composition_to_the_renderer() {
+        let mut data = Vec::new();
+        write_local(&chain_clip(), &mut data).unwrap();
+        let floats = floats(&data);
+        assert_eq!(floats.len(), 2 * 3 * 16);
+        assert_near(&floats[..48], &[
+            // Hips, as its world matrix
+            0.0, 1.0, 0.0, 0.0,  -1.0, 0.0, 0.0, 0.0,  0.0, 0.0, 1.0, 0.0,  1.0, 2.0, 3.0, 1.0,
+            // Spine: bent 90 degrees about X at its offset
+            1.0, 0.0, 0.0, 0.0,  0.0, 0.0, 1.0, 0.0,  0.0, -1.0, 0.0, 0.0,  0.0, 10.0, 0.0, 1.0,
+            // Head: just its offset
+            1.0, 0.0, 0.0, 0.0,  0.0, 1.0, 0.0, 0.0,  0.0, 0.0, 1.0, 0.0,  0.0, 5.0, 0.0, 1.0,
+        ]);
+        // At rest the spine is just its offset too
+        assert_near(&floats[48 + 16..48 + 32], &[1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 10.0, 0.0, 1.0]);
+    }
+}
@@ -0,0 +1,102 @@
+use bvh;
+
+use error::MocapError;
+use {decode_channel, ChannelType, Joint, JointChildren, Mocap};
+
+// Error between original and reconstructed frames, over every value of every channel. Rotation
+// errors are in degrees and translation errors in the file's units.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconstructionError {
+    pub max: f64,
+    pub rms: f64,
+}
+
+pub fn reconstruction_error(original: &[Vec<f64>], reconstructed: &[Vec<f64>]) -> ReconstructionError {
+    columns_error(original, reconstructed, |_| true)
+}
+
+// The error over the channels (flat indices) `group` assigns each group index, per group.
+pub fn grouped_reconstruction_errors(original: &[Vec<f64>], reconstructed: &[Vec<f64>], group: &[usize]) -> Vec<ReconstructionError> {
+    let num_groups = group.iter().map(|index| index + 1).max().unwrap_or(0);
+    (0..num_groups).map(|index| columns_error(original, reconstructed, |channel| group.get(channel) == Some(&index))).collect()
+}
+
+fn columns_error<F: Fn(usize) -> bool>(original: &[Vec<f64>], reconstructed: &[Vec<f64>], include: F) -> ReconstructionError {
+    let mut max: f64 = 0.0;
+    let mut sum_squares = 0.0;
+    let mut count = 0;
+    for (original, reconstructed) in original.iter().zip(reconstructed.iter()) {
+        for (channel, (original, reconstructed)) in original.iter().zip(reconstructed.iter()).enumerate() {
+            if !include(channel) {
+                continue;
+            }
+            let error = (original - reconstructed).abs();
+            max = max.max(error);
+            sum_squares += error * error;
+            count += 1;
+        }
+    }
+
+    ReconstructionError {
+        max: max,
+        rms: if count > 0 { (sum_squares / (count as f64)).sqrt() } else { 0.0 },
+    }
+}
+
+// The absolute reconstruction error of one channel value: `joint`'s (by name, as disambiguated)
+// `channel_type` channel at `frame`, decoded from `mocap` the way the BVH output is, against its
+// value in `original`. The `Mocap` only holds the quantized levels, so the original has to be
+// supplied, and has to be the BVH it was built from: the input after the passes that reshape it
+// before quantization, where channels like --root-motion's displacements are still in the form
+// the `Mocap` stores. Only that channel is decoded, so tooling can inspect a frame without
+// reconstructing the whole clip.
+pub fn channel_error(mocap: &Mocap, original: &bvh::Bvh, joint: &str, channel_type: ChannelType, frame: usize) -> Result<f64, MocapError> {
+    let channel_map = mocap.channel_map();
+    let flat_index = match channel_map.iter().find(|descriptor| descriptor.joint_name == joint && descriptor.channel_type == channel_type) {
+        Some(descriptor) => descriptor.flat_index,
+        // A joint can have no channels at all, and so no descriptors
+        None if has_joint(&mocap.root, joint) => return Err(MocapError::Usage(format!("{} has no {} channel", joint, channel_type.name()))),
+        None => return Err(MocapError::JointNotFound(joint.into())),
+    };
+    let original_value = original.motion.frames.get(frame).and_then(|values| values.get(flat_index))
+        .ok_or_else(|| MocapError::Usage(format!("frame {} is past the original's last frame ({} frames)", frame, original.motion.frames.len())))?;
+
+    let mut decoded = None;
+    decode_channel(mocap.channels()[flat_index], mocap.channel_quantization_bits, |index, value| if index == frame {
+        decoded = Some(value);
+    });
+    let decoded = decoded.ok_or_else(|| MocapError::Usage(format!("frame {} is past the last frame ({} frames)", frame, mocap.num_frames)))?;
+    Ok((original_value - decoded).abs())
+}
+
+fn has_joint(joint: &Joint, name: &str) -> bool {
+    joint.name == name || match joint.children {
+        JointChildren::Joints(ref joints) => joints.iter().any(|joint| has_joint(joint, name)),
+        JointChildren::EndSite(_) => false,
+    }
+}
+
+// A --error-at query: `<joint>:<type>@<frame>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorQuery {
+    pub joint: String,
+    pub channel_type: ChannelType,
+    pub frame: usize,
+}
+
+impl ErrorQuery {
+    // The joint name may itself contain colons or @s; the type is after the last colon and the
+    // frame after the last @.
+    pub fn parse(spec: &str) -> Option<ErrorQuery> {
+        let (channel, frame) = spec.rsplit_once('@')?;
+        let (joint, type_name) = channel.rsplit_once(':')?;
+        if joint.is_empty() {
+            return None;
+        }
+        Some(ErrorQuery {
+            joint: joint.into(),
+            channel_type: ChannelType::from_name(type_name)?,
+            frame: frame.parse().ok()?,
+        })
+    }
+}
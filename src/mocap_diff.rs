@@ -0,0 +1,385 @@
+use std::io::{self, Write};
+
+use container::Container;
+use error::MocapError;
+use json;
+use manifest;
+use options::Options;
+use periodic;
+use selector;
+use {decode_channel, max_level, Channel, ChannelType, Joint, JointChildren, Mocap};
+
+// `mocap diff-mocap`: how two encodings of the same motion differ, for seeing what a change of
+// settings did to each channel rather than just to the overall error. Clips are paired by name
+// (two single-clip files, such as .raw files, are paired whatever their names), and channels by
+// their place in the channel map, which needs the two clips' skeletons to have the same joint
+// names, channels and structure; if they don't, the differences between the hierarchies are listed
+// instead.
+//
+// For every channel it lists both sides' bit depth, encoding (lossless, anchored, or how
+// `raw::write_clip` stores the deltas: constant, periodic or in the delta blocks) and decoded
+// range, and the RMS and max differences between the decoded values, over the frames both clips
+// have. A channel whose settings are the same on both sides (every quantization parameter,
+// initial level included) but which decodes differently is flagged: the same settings should
+// always produce the same deltas, so that's an encoder determinism bug or a different input.
+//
+// With --diff-json the comparison is also written as JSON:
+//
+//   {
+//     "clips": [
+//       {
+//         "name": "walk", "frames": [120, 120],
+//         "hierarchy": ["Hips/Spine: named Chest in b", ...],   empty if the skeletons match
+//         "channels": [
+//           {
+//             "joint": "Hips", "type": "RotationZ",
+//             "a": { "bits": 8, "encoding": "deltas", "range": [-10, 40], "clamp": null },
+//             "b": { ... },
+//             "rms": 0.01, "max": 0.05, "nondeterministic": false
+//           },
+//           ...
+//         ]
+//       }
+//     ],
+//     "unpaired": [{ "name": "run", "in": "a" }, ...]
+//   }
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelSettings {
+    pub bits: u8,
+    pub encoding: &'static str,
+    pub range: (f64, f64), // The values level 0 and the top level decode to
+    pub clamp: Option<(f64, f64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelComparison {
+    pub joint: String,
+    pub type_: ChannelType,
+    pub a: ChannelSettings,
+    pub b: ChannelSettings,
+    pub rms: f64,
+    pub max: f64,
+    pub nondeterministic: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClipComparison {
+    pub name: String,
+    pub num_frames: (u32, u32),
+    pub hierarchy: Vec<String>,
+    pub channels: Vec<ChannelComparison>,
+}
+
+pub fn run(a: &Container, b: &Container, options: &Options) -> Result<(), MocapError> {
+    let mut clips = Vec::new();
+    let mut unpaired = Vec::new();
+    if a.clips.len() == 1 && b.clips.len() == 1 {
+        clips.push(compare(&a.clips[0].name, &a.clips[0].mocap, &b.clips[0].mocap));
+    } else {
+        for clip in a.clips.iter() {
+            match b.clips.iter().find(|other| other.name == clip.name) {
+                Some(other) => clips.push(compare(&clip.name, &clip.mocap, &other.mocap)),
+                None => unpaired.push((clip.name.clone(), "a")),
+            }
+        }
+        unpaired.extend(b.clips.iter().filter(|clip| !a.clips.iter().any(|other| other.name == clip.name)).map(|clip| (clip.name.clone(), "b")));
+    }
+
+    for clip in clips.iter() {
+        print(clip);
+    }
+    for (name, side) in unpaired.iter() {
+        println!("clip {}: only in {}", name, side);
+    }
+
+    if let Some(ref json_file_name) = options.diff_json_file_name {
+        write_json(&clips, &unpaired, &mut manifest::create(json_file_name)?)?;
+    }
+    Ok(())
+}
+
+pub fn compare(name: &str, a: &Mocap, b: &Mocap) -> ClipComparison {
+    let mut hierarchy = Vec::new();
+    hierarchy_differences(&a.root, &b.root, "", &mut hierarchy);
+    let channels = if hierarchy.is_empty() {
+        a.channel_map().into_iter().zip(a.channels().into_iter().zip(b.channels())).map(|(descriptor, (channel_a, channel_b))| {
+            let (values_a, values_b) = (decode(channel_a, a.channel_quantization_bits), decode(channel_b, b.channel_quantization_bits));
+            let differences = values_a.iter().zip(values_b.iter()).map(|(a, b)| (a - b).abs()).collect::<Vec<_>>();
+            let same_settings = channel_a.bits(a.channel_quantization_bits) == channel_b.bits(b.channel_quantization_bits)
+                && channel_a.reference == channel_b.reference
+                && channel_a.value_range_min == channel_b.value_range_min
+                && channel_a.value_range == channel_b.value_range
+                && channel_a.initial_level == channel_b.initial_level
+                && channel_a.clamp == channel_b.clamp
+                && channel_a.anchor_level == channel_b.anchor_level
+                && channel_a.values.is_some() == channel_b.values.is_some();
+            ChannelComparison {
+                joint: descriptor.joint_name,
+                type_: descriptor.channel_type,
+                a: settings(channel_a, a.channel_quantization_bits),
+                b: settings(channel_b, b.channel_quantization_bits),
+                rms: if differences.is_empty() { 0.0 } else { (differences.iter().map(|difference| difference * difference).sum::<f64>() / differences.len() as f64).sqrt() },
+                max: differences.iter().cloned().fold(0.0, f64::max),
+                nondeterministic: same_settings && values_a.len() == values_b.len() && differences.iter().any(|difference| *difference != 0.0),
+            }
+        }).collect()
+    } else {
+        Vec::new()
+    };
+
+    ClipComparison {
+        name: name.into(),
+        num_frames: (a.num_frames, b.num_frames),
+        hierarchy: hierarchy,
+        channels: channels,
+    }
+}
+
+fn decode(channel: &Channel, bits: u8) -> Vec<f64> {
+    let mut ret = Vec::new();
+    decode_channel(channel, bits, |_, value| ret.push(value));
+    ret
+}
+
+fn settings(channel: &Channel, bits: u8) -> ChannelSettings {
+    let encoding = if channel.values.is_some() {
+        "lossless"
+    } else if channel.anchor_level.is_some() {
+        "anchored"
+    } else {
+        match periodic::encode(channel, bits) {
+            Some(ref periodic) if periodic.levels.len() == 1 && periodic.corrections.is_empty() => "constant",
+            Some(_) => "periodic",
+            None => "deltas",
+        }
+    };
+    ChannelSettings {
+        bits: if channel.values.is_some() { 64 } else { channel.bits(bits) },
+        encoding: encoding,
+        range: (channel.value_of(0, bits), channel.value_of(max_level(channel.bits(bits)), bits)),
+        clamp: channel.clamp,
+    }
+}
+
+// Every way `b`'s hierarchy differs from `a`'s that keeps their channels from lining up: joint
+// names, channel types and children. Subtrees that differ in shape aren't compared further.
+pub fn hierarchy_differences(a: &Joint, b: &Joint, parent_path: &str, differences: &mut Vec<String>) {
+    let name = selector::escape(&a.name);
+    let path = if parent_path.is_empty() { name } else { format!("{}/{}", parent_path, name) };
+
+    if a.name != b.name {
+        differences.push(format!("{}: named {} in b", path, b.name));
+    }
+    let channel_names = |joint: &Joint| joint.channels.iter().map(|channel| channel.type_.name()).collect::<Vec<_>>().join(" ");
+    if channel_names(a) != channel_names(b) {
+        differences.push(format!("{}: channels {} in a, {} in b", path, channel_names(a), channel_names(b)));
+    }
+    match (&a.children, &b.children) {
+        (JointChildren::Joints(a), JointChildren::Joints(b)) if a.len() == b.len() => {
+            for (a, b) in a.iter().zip(b.iter()) {
+                hierarchy_differences(a, b, &path, differences);
+            }
+        }
+        (JointChildren::EndSite(_), JointChildren::EndSite(_)) => (),
+        (a, b) => differences.push(format!("{}: {} in a, {} in b", path, describe_children(a), describe_children(b))),
+    }
+}
+
+fn describe_children(children: &JointChildren) -> String {
+    match *children {
+        JointChildren::Joints(ref joints) => format!("{} child joint{}", joints.len(), if joints.len() == 1 { "" } else { "s" }),
+        JointChildren::EndSite(_) => "an end site".into(),
+    }
+}
+
+fn print(clip: &ClipComparison) {
+    println!("clip {}: {} frames in a, {} in b", clip.name, clip.num_frames.0, clip.num_frames.1);
+    if !clip.hierarchy.is_empty() {
+        println!("    the skeletons differ:");
+        for difference in clip.hierarchy.iter() {
+            println!("        {}", difference);
+        }
+        return;
+    }
+    let side = |settings: &ChannelSettings| format!("{:>2} {:<8} [{:.4}, {:.4}]", settings.bits, settings.encoding, settings.range.0, settings.range.1);
+    println!("    {:<32} {:<34} {:<34} {:>12} {:>12}", "channel", "a: bits encoding range", "b: bits encoding range", "rms", "max");
+    for channel in clip.channels.iter() {
+        println!("    {:<32} {:<34} {:<34} {:>12.6} {:>12.6}{}",
+            format!("{} {}", channel.joint, channel.type_.name()),
+            side(&channel.a),
+            side(&channel.b),
+            channel.rms,
+            channel.max,
+            if channel.nondeterministic { "  decodes differently with the same settings" } else { "" });
+    }
+    let num_nondeterministic = clip.channels.iter().filter(|channel| channel.nondeterministic).count();
+    if num_nondeterministic > 0 {
+        println!("    {} channel{} decode differently despite identical settings", num_nondeterministic, if num_nondeterministic == 1 { "" } else { "s" });
+    }
+}
+
+pub fn write_json<W: Write>(clips: &[ClipComparison], unpaired: &[(String, &str)], w: &mut W) -> io::Result<()> {
+    let string = |s: &str| format!("\"{}\"", json::escape(s));
+    let side = |settings: &ChannelSettings| format!("{{ \"bits\": {}, \"encoding\": \"{}\", \"range\": [{}, {}], \"clamp\": {} }}",
+        settings.bits,
+        settings.encoding,
+        settings.range.0, settings.range.1,
+        settings.clamp.map_or("null".into(), |(min, max)| format!("[{}, {}]", min, max)));
+
+    writeln!(w, "{{")?;
+    writeln!(w, "  \"clips\": [")?;
+    for (index, clip) in clips.iter().enumerate() {
+        writeln!(w, "    {{")?;
+        writeln!(w, "      \"name\": {}, \"frames\": [{}, {}],", string(&clip.name), clip.num_frames.0, clip.num_frames.1)?;
+        writeln!(w, "      \"hierarchy\": [{}],", clip.hierarchy.iter().map(|difference| string(difference)).collect::<Vec<_>>().join(", "))?;
+        writeln!(w, "      \"channels\": [")?;
+        for (channel_index, channel) in clip.channels.iter().enumerate() {
+            writeln!(w, "        {{ \"joint\": {}, \"type\": \"{}\", \"a\": {}, \"b\": {}, \"rms\": {}, \"max\": {}, \"nondeterministic\": {} }}{}",
+                string(&channel.joint),
+                channel.type_.name(),
+                side(&channel.a),
+                side(&channel.b),
+                channel.rms,
+                channel.max,
+                channel.nondeterministic,
+                if channel_index + 1 < clip.channels.len() { "," } else { "" })?;
+        }
+        writeln!(w, "      ]")?;
+        writeln!(w, "    }}{}", if index + 1 < clips.len() { "," } else { "" })?;
+    }
+    writeln!(w, "  ],")?;
+    writeln!(w, "  \"unpaired\": [{}]", unpaired.iter().map(|(name, side)| format!("{{ \"name\": {}, \"in\": \"{}\" }}", string(name), side)).collect::<Vec<_>>().join(", "))?;
+    writeln!(w, "}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use container::Clip;
+    use depth::DEFAULT_MAX_DEPTH;
+    use json::Value;
+    use raw;
+    use reencode;
+    use test_util;
+    use {build_mocap, RotationAnchor, Settings, TranslationReference};
+
+    const NUM_FRAMES: usize = 40;
+
+    fn encoded(text: &str, bits: u8) -> Mocap {
+        build_mocap(&test_util::parse(text), &Settings {
+            channel_quantization_bits: bits,
+            translation_reference: TranslationReference::None,
+            rotation_anchor: RotationAnchor::None,
+        })
+    }
+
+    fn walk() -> Mocap {
+        encoded(&test_util::clip_text(NUM_FRAMES, test_util::sine), 6)
+    }
+
+    fn container(clips: Vec<(&str, Mocap)>) -> Container {
+        Container {
+            reference_poses: Vec::new(),
+            clips: clips.into_iter().map(|(name, mocap)| Clip {
+                name: name.into(),
+                reference_pose: None,
+                attributes: Vec::new(),
+                thumbnail: None,
+                alias: None,
+                mocap: mocap,
+            }).collect(),
+        }
+    }
+
+    #[test]
+    fn a_clip_compared_with_itself_has_no_differences() {
+        let comparison = compare("walk", &walk(), &walk());
+        assert_eq!(comparison.num_frames, (NUM_FRAMES as u32, NUM_FRAMES as u32));
+        assert!(comparison.hierarchy.is_empty());
+        assert_eq!(comparison.channels.len(), test_util::NUM_CHANNELS);
+        for channel in comparison.channels.iter() {
+            assert_eq!(channel.a, channel.b);
+            assert_eq!((channel.rms, channel.max, channel.nondeterministic), (0.0, 0.0, false), "{} {}", channel.joint, channel.type_.name());
+            assert_eq!(channel.a.bits, 6);
+        }
+        assert_eq!(comparison.channels[6].joint, "Spine");
+    }
+
+    #[test]
+    fn a_reencode_differs_only_in_the_channels_given_new_bits() {
+        let dir = test_util::temp_dir("mocap-diff");
+        let (input_file_name, output_file_name) = (dir.join("in.raw"), dir.join("out.raw"));
+        let mut data = Vec::new();
+        raw::write(&walk(), periodic::Limits::default(), &mut data).unwrap();
+        fs::write(&input_file_name, data).unwrap();
+        let options = Options::parse(["reencode", "--bits-for", "Spine:*=3", input_file_name.to_str().unwrap(), output_file_name.to_str().unwrap()].iter().map(|arg| arg.to_string())).unwrap();
+        reencode::run(&input_file_name, &output_file_name, &options, None).unwrap();
+        let reencoded = raw::read(&fs::read(&output_file_name).unwrap(), DEFAULT_MAX_DEPTH).unwrap();
+
+        let comparison = compare("walk", &walk(), &reencoded);
+        for (index, channel) in comparison.channels.iter().enumerate() {
+            if channel.joint == "Spine" {
+                assert_eq!((channel.a.bits, channel.b.bits), (6, 3));
+                assert!(channel.rms > 0.0 && channel.max >= channel.rms, "channel {}: {:?}", index, channel);
+            } else {
+                assert_eq!(channel.a, channel.b, "channel {}", index);
+                assert_eq!((channel.rms, channel.max), (0.0, 0.0), "channel {}", index);
+            }
+            assert!(!channel.nondeterministic);
+        }
+        assert_eq!(comparison.channels.iter().filter(|channel| channel.rms > 0.0).count(), 3);
+    }
+
+    #[test]
+    fn flags_channels_decoding_differently_with_the_same_settings() {
+        let mut tampered = walk();
+        {
+            let mut channels = tampered.channels_mut();
+            let deltas = &mut channels[9].deltas;
+            deltas[10] = deltas[10].wrapping_add(1);
+        }
+        let comparison = compare("walk", &walk(), &tampered);
+        let flagged = comparison.channels.iter().enumerate().filter(|(_, channel)| channel.nondeterministic).map(|(index, _)| index).collect::<Vec<_>>();
+        assert_eq!(flagged, vec![9]);
+        assert!(comparison.channels[9].max > 0.0);
+    }
+
+    #[test]
+    fn lists_hierarchy_differences_instead_of_channels() {
+        let renamed = test_util::clip_text(NUM_FRAMES, test_util::sine).replace("JOINT Spine", "JOINT Chest");
+        let comparison = compare("walk", &walk(), &encoded(&renamed, 6));
+        assert_eq!(comparison.hierarchy, vec!["Hips/Spine: named Chest in b".to_string()]);
+        assert!(comparison.channels.is_empty());
+
+        let legless = test_util::motion_text(test_util::CHAIN, 9, NUM_FRAMES, test_util::sine);
+        let comparison = compare("walk", &walk(), &encoded(&legless, 6));
+        assert_eq!(comparison.hierarchy, vec!["Hips: 2 child joints in a, 1 child joint in b".to_string()]);
+    }
+
+    #[test]
+    fn pairs_clips_by_name_and_writes_json() {
+        let dir = test_util::temp_dir("mocap-diff-json");
+        let json_file_name = dir.join("diff.json");
+        let options = Options::parse(["diff-mocap", "--diff-json", json_file_name.to_str().unwrap(), "a.mcp", "b.mcp"].iter().map(|arg| arg.to_string())).unwrap();
+        let faster = encoded(&test_util::clip_text(NUM_FRAMES, |frame, channel| test_util::sine(frame * 2, channel)), 6);
+        let a = container(vec![("walk", walk()), ("idle", walk())]);
+        let b = container(vec![("run", faster), ("walk", walk())]);
+        run(&a, &b, &options).unwrap();
+
+        let json = json::parse(&fs::read_to_string(&json_file_name).unwrap()).unwrap();
+        let clips = json.get("clips").and_then(Value::as_array).unwrap();
+        assert_eq!(clips.len(), 1);
+        assert_eq!(clips[0].get("name").and_then(Value::as_str), Some("walk"));
+        let channels = clips[0].get("channels").and_then(Value::as_array).unwrap();
+        assert_eq!(channels.len(), test_util::NUM_CHANNELS);
+        assert_eq!(channels[3].get("type").and_then(Value::as_str), Some("RotationZ"));
+        assert_eq!(channels[3].get("a").and_then(|a| a.get("bits")).and_then(Value::as_f64), Some(6.0));
+        assert!(channels.iter().all(|channel| channel.get("rms").and_then(Value::as_f64) == Some(0.0) && channel.get("nondeterministic").and_then(Value::as_bool) == Some(false)));
+        let unpaired = json.get("unpaired").and_then(Value::as_array).unwrap().iter()
+            .map(|clip| (clip.get("name").and_then(Value::as_str).unwrap(), clip.get("in").and_then(Value::as_str).unwrap())).collect::<Vec<_>>();
+        assert_eq!(unpaired, vec![("idle", "a"), ("run", "b")]);
+    }
+}
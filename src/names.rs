@@ -0,0 +1,203 @@
+use std::collections::{HashMap, HashSet};
+
+use bvh;
+
+use error::MocapError;
+use selector;
+use {Joint, JointChildren};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicateNames {
+    Error,
+    Disambiguate,
+}
+
+struct JointPath {
+    name: String,
+    path: String,
+    position: Option<(String, usize)>, // The parent's path and the index among its children; None for the root
+}
+
+// Makes joint names unique so everything that looks joints up by name has a single answer.
+//
+// With `DuplicateNames::Error` any duplicate is an error listing the paths of every joint sharing
+// a name, each sibling sharing its path too told apart by its position among its parent's children
+// (`Hips/Spine (child 2 of Hips)`). With `DuplicateNames::Disambiguate` the first joint (in pre-order) keeps its name and
+// later ones get `#2`, `#3`, ... appended; all name-based options then refer to the disambiguated
+// names. The returned map goes from disambiguated names back to the originals, which
+// `restore_original_names` records on the `Mocap` joints so the BVH output keeps the source names.
+pub fn make_unique(root: &mut bvh::Joint, policy: DuplicateNames) -> Result<HashMap<String, String>, MocapError> {
+    let mut paths = Vec::new();
+    collect_paths(root, "", None, &mut paths);
+
+    let mut counts = HashMap::new();
+    for joint in paths.iter() {
+        *counts.entry(joint.name.clone()).or_insert(0) += 1;
+    }
+    if counts.values().all(|count| *count == 1) {
+        return Ok(HashMap::new());
+    }
+
+    if policy == DuplicateNames::Error {
+        let duplicates = paths.iter().filter(|joint| counts[&joint.name] > 1).collect::<Vec<_>>();
+        let described = duplicates.iter().map(|joint| match joint.position {
+            Some((ref parent_path, child)) if duplicates.iter().filter(|other| other.path == joint.path).count() > 1 => format!("{} (child {} of {})", joint.path, child + 1, parent_path),
+            _ => joint.path.clone(),
+        }).collect();
+        return Err(MocapError::DuplicateJointNames(described));
+    }
+
+    let mut used = paths.iter().map(|joint| joint.name.clone()).collect::<HashSet<_>>();
+    let mut seen = HashMap::new();
+    let mut original_names = HashMap::new();
+    disambiguate(root, &mut used, &mut seen, &mut original_names);
+    Ok(original_names)
+}
+
+pub fn restore_original_names(joint: &mut Joint, original_names: &HashMap<String, String>) {
+    joint.original_name = original_names.get(&joint.name).cloned();
+
+    if let JointChildren::Joints(ref mut joints) = joint.children {
+        for joint in joints.iter_mut() {
+            restore_original_names(joint, original_names);
+        }
+    }
+}
+
+// Every joint's path, in pre-order.
+fn collect_paths(joint: &bvh::Joint, parent_path: &str, position: Option<usize>, paths: &mut Vec<JointPath>) {
+    let name = selector::escape(&joint.name);
+    let path = if parent_path.is_empty() { name } else { format!("{}/{}", parent_path, name) };
+    paths.push(JointPath {
+        name: joint.name.clone(),
+        path: path.clone(),
+        position: position.map(|child| (parent_path.to_string(), child)),
+    });
+
+    if let bvh::JointChildren::Joints(ref joints) = joint.children {
+        for (child, joint) in joints.iter().enumerate() {
+            collect_paths(joint, &path, Some(child), paths);
+        }
+    }
+}
+
+fn disambiguate(joint: &mut bvh::Joint, used: &mut HashSet<String>, seen: &mut HashMap<String, usize>, original_names: &mut HashMap<String, String>) {
+    let occurrence = {
+        let count = seen.entry(joint.name.clone()).or_insert(0);
+        *count += 1;
+        *count
+    };
+
+    if occurrence > 1 {
+        let mut suffix = occurrence;
+        let mut name = format!("{}#{}", joint.name, suffix);
+        while used.contains(&name) {
+            suffix += 1;
+            name = format!("{}#{}", joint.name, suffix);
+        }
+
+        used.insert(name.clone());
+        original_names.insert(name.clone(), joint.name.clone());
+        joint.name = name;
+    }
+
+    if let bvh::JointChildren::Joints(ref mut joints) = joint.children {
+        for child in joints.iter_mut() {
+            disambiguate(child, used, seen, original_names);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use directives::Directives;
+    use test_util;
+    use {build_bvh, load_bvh};
+
+    // Two sibling Spines (the same path) and a third inside the second
+    const HIERARCHY: &str = "HIERARCHY
+ROOT Hips
+{
+\tOFFSET 0 0 0
+\tCHANNELS 3 Xposition Yposition Zposition
+\tJOINT Spine
+\t{
+\t\tOFFSET 0 10 0
+\t\tCHANNELS 3 Zrotation Xrotation Yrotation
+\t\tEnd Site
+\t\t{
+\t\t\tOFFSET 0 5 0
+\t\t}
+\t}
+\tJOINT Spine
+\t{
+\t\tOFFSET 0 -10 0
+\t\tCHANNELS 3 Zrotation Xrotation Yrotation
+\t\tJOINT Spine
+\t\t{
+\t\t\tOFFSET 0 -10 0
+\t\t\tCHANNELS 3 Zrotation Xrotation Yrotation
+\t\t\tEnd Site
+\t\t\t{
+\t\t\t\tOFFSET 0 -5 0
+\t\t\t}
+\t\t}
+\t}
+}
+";
+
+    fn clip() -> bvh::Bvh {
+        test_util::parse(&test_util::motion_text(HIERARCHY, 12, 10, test_util::sine))
+    }
+
+    fn names(joint: &bvh::Joint, ret: &mut Vec<String>) {
+        ret.push(joint.name.clone());
+        if let bvh::JointChildren::Joints(ref joints) = joint.children {
+            for joint in joints.iter() {
+                names(joint, ret);
+            }
+        }
+    }
+
+    #[test]
+    fn error_tells_siblings_with_the_same_path_apart() {
+        match make_unique(&mut clip().hierarchy.root, DuplicateNames::Error) {
+            Err(MocapError::DuplicateJointNames(paths)) => assert_eq!(paths, vec!["Hips/Spine (child 1 of Hips)", "Hips/Spine (child 2 of Hips)", "Hips/Spine/Spine"]),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn unique_names_are_left_alone() {
+        let mut bvh = test_util::sine_clip(5);
+        assert!(make_unique(&mut bvh.hierarchy.root, DuplicateNames::Error).unwrap().is_empty());
+    }
+
+    #[test]
+    fn disambiguate_numbers_later_joints_and_restores_the_names_on_output() {
+        let mut bvh = clip();
+        let original_names = make_unique(&mut bvh.hierarchy.root, DuplicateNames::Disambiguate).unwrap();
+        let mut disambiguated = Vec::new();
+        names(&bvh.hierarchy.root, &mut disambiguated);
+        assert_eq!(disambiguated, vec!["Hips", "Spine", "Spine#2", "Spine#3"]);
+        assert_eq!(original_names.get("Spine#3").map(String::as_str), Some("Spine"));
+
+        let source = load_bvh(clip(), &Directives::default(), Path::new("in.bvh"), &test_util::options(&[])).unwrap();
+        let output = build_bvh(&source.build_mocap(&source.conversion.settings()));
+        let mut output_names = Vec::new();
+        names(&output.hierarchy.root, &mut output_names);
+        assert_eq!(output_names, vec!["Hips", "Spine", "Spine", "Spine"]);
+        assert_eq!(output.motion.frames.len(), 10);
+    }
+
+    #[test]
+    fn selectors_refer_to_disambiguated_names() {
+        let source = load_bvh(clip(), &Directives::default(), Path::new("in.bvh"), &test_util::options(&["--lossy", "--root", "Spine#2"])).unwrap();
+        let mut subtree = Vec::new();
+        names(&source.bvh.hierarchy.root, &mut subtree);
+        assert_eq!(subtree, vec!["Spine#2", "Spine#3"]);
+    }
+}
@@ -0,0 +1,1719 @@
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use adjust::Adjustment;
+use curves;
+use dedupe;
+use depth;
+use error::MocapError;
+use markers::{self, Marker};
+use bind::BindPose;
+use conversion::{ConversionSettings, ConversionSettingsBuilder};
+use mask::Mask;
+use gaps::Detection;
+use smooth;
+use timewarp::Curve;
+use thumbnail;
+use names::DuplicateNames;
+use input::FrameCountMismatch;
+use outliers;
+use periodic;
+use posematch::Metric;
+use reencode::BitsFor;
+use metrics::ErrorQuery;
+use resample::Interpolation;
+use vq;
+use writer;
+use {RotationAnchor, TranslationReference};
+
+pub const USAGE: &str = "usage: mocap [options] <input.bvh> <output.bvh> <output.csv> <output.raw>
+       mocap --hierarchy <file.bvh> --motion <file> [options] <output.bvh> <output.csv> <output.raw>
+       mocap batch [options] <input dir> <output dir>
+       mocap decode [options] <input.raw> <output.bvh>
+       mocap concat [options] <output.raw> <input.raw> <input.raw>...
+       mocap pack [options] <output.mcp> <input.bvh>...
+       mocap unpack [options] <input.mcp> <output dir>
+       mocap info <input.mcp|input.raw>
+       mocap dump [--values <n>] [--full] <input.mcp|input.raw>
+       mocap verify [options] <input.mcp|input.raw>...
+       mocap stats --locomotion|--dof-summary|--delta-runs [options] <input.bvh|input.mcp|input.raw>...
+       mocap diff [options] <base.bvh> <edited.bvh> <output.raw>
+       mocap diff-mocap [--diff-json <file>] <a.mcp|a.raw> <b.mcp|b.raw>
+       mocap reencode --bits-for <joint>:<type|*>=<bits>... [--source <file.bvh>] [options] <input.mcp|input.raw> <output>
+       mocap match [options] --frame <n> <a.bvh> <b.bvh>
+       mocap transitions [options] <a.bvh> <b.bvh>
+       mocap make-patch <old.mcp|old.raw> <new.mcp|new.raw> <patch>
+       mocap apply-patch <old.mcp|old.raw> <patch> <new.mcp|new.raw>
+       mocap shell [options] <input.bvh>
+       mocap --sweep-bits [--sweep-csv <file>] [options] <input.bvh>
+
+batch compresses every .bvh file in <input dir>, writing <name>.bvh, <name>.csv and <name>.raw
+into <output dir>. It keeps going past files that fail and summarizes them at the end.
+
+decode reconstructs a BVH file from a .raw file (or a --vq file).
+
+concat joins .raw files sharing a skeleton and frame time into one. By default every input is decoded and the
+combined motion quantized again (using --bits/--translation-reference); with --quantized-append,
+inputs with identical channel ranges, clamp bounds and bit depths are joined without
+re-quantizing; otherwise it says why not and falls back to re-quantizing.
+
+pack compresses several .bvh files into one container, one clip per file named after the file.
+unpack writes every clip in a container to <output dir> as <clip name>.bvh.
+
+info prints the clips in a container (or a .raw file) with their attributes.
+
+dump prints a container (or a .raw file) as deterministic text for reviewing changes to it with a
+diff: the header, every clip's fields, attributes, metadata and markers, and the hierarchy with
+every channel's range, encoding and size, but no motion unless asked for (see dump.rs).
+
+verify decodes every clip of each file and checks it thoroughly: the block layout, every level
+against the bit depth, lossless values against their clamp bounds, the seek index's levels and the
+clip attributes (see verify.rs). It prints every problem found, with the clip, channel and frame,
+and fails if there are any.
+
+stats --locomotion prints each clip's locomotion metrics: average ground speed, heading change
+rate, stride frequency and whether it travels or stays in place (see locomotion.rs). BVH inputs
+go through the same passes as for conversion; compressed clips are decoded first. stats
+--dof-summary prints each clip's channel count per joint and in total (see dof.rs). stats
+--delta-runs prints, per channel of a compressed clip stored as a chain of deltas, the longest run
+of frames between absolute levels and how far off a single corrupt delta could put it (see
+drift.rs).
+
+diff compresses the difference between an edited clip and the base clip it was made from (same
+skeleton and frame count), which for small edits is mostly constant. decode --base adds the base
+back.
+
+diff-mocap compares two encodings of the same clips, such as conversions with different settings:
+per channel, both sides' bit depth, encoding and range and the RMS and max differences between
+the decoded values, flagging channels with identical settings that decode differently. Clips are
+paired by name; if their skeletons differ it lists the differences instead (see mocap_diff.rs).
+
+reencode stores the channels --bits-for selects differently, quantizing only those again (from
+--source if given, else from the file's own decode) and keeping every other channel, the metadata,
+markers and attributes as they are, in a file of the input's format. A channel can be given any
+bit depth in [1, 8], whatever the clip's, or 64 (lossless); see reencode.rs.
+
+match finds the frames of b.bvh most similar to frame n of a.bvh (same skeleton), for building
+transitions, and prints them nearest first with their distances (see posematch.rs).
+
+transitions finds the best points to cut from a.bvh to b.bvh (same skeleton), scoring every pair
+of frames by pose distance plus velocity mismatch, and prints the best pairs with their scores and
+a suggested blend length (see transitions.rs).
+
+make-patch writes a patch taking the old version of a file to the new one, storing only the
+channel runs and headers that differ between them, and apply-patch makes the new version from the
+old one and the patch, byte for byte, checking both against the hashes the patch records (see
+patch.rs).
+
+shell loads a clip through the usual input passes and reads commands from the terminal for
+inspecting it: the hierarchy, channel statistics, ASCII plots of a channel, interpolated samples,
+world positions and the error at a given bit depth; help lists them (see shell.rs).
+
+--sweep-bits compresses the input at every bit depth from 1 to 8 and prints the raw size and
+reconstruction error for each, instead of writing any outputs.
+
+options:
+    --recursive             batch: also process subdirectories, mirroring them in the output
+    --jobs <n>              batch: convert n files at a time on separate threads (default: one per logical CPU)
+    --cache-dir <dir>       batch: keep every file's outputs in a cache keyed by its contents and the
+                            conversion options, and copy them from there when both are unchanged
+    --manifest <file>       Write a JSON record of the run, for any command: every output file written with its
+                            size, content hash and source, the settings used and their hash, and which
+                            inputs failed (see manifest.rs)
+    --report <file>         Write a JSON report of the run for CI: per input, the settings, input and output
+                            sizes, channel bit depths, reconstruction error, warnings, timings and any
+                            error (see report.rs); for verify, every problem found
+    --compare-report <file> With --report, compare the run against a previous report and print what changed:
+                            output sizes, overall and per-joint error, and channel bit depths
+    --regression-threshold <fraction>
+                            How much worse than in the previous report a size or error may get before it is
+                            flagged as a regression (default 0.05)
+    --fail-on-regression    With --compare-report, fail if anything regressed
+    --cache-max-size <size> batch: with --cache-dir, remove the least recently used entries after the batch
+                            until the cache is at most this size, in bytes or with a K, M or G suffix
+    --quantized-append      concat: join delta streams directly when channel ranges and bit depths match
+    --reference-pose        pack: encode each clip's first frame as a delta from a reference pose stored in
+                            the container, shared by clips starting from the same pose, instead of from 0
+    --reference-tolerance <t>
+                            pack: how far apart (per channel) two clips' first frames may be while sharing
+                            a reference pose (default 0)
+    --clip-attr <clip>:<key>=<value>[,<key>=<value>...]
+                            pack: set attributes on a clip, for the runtime playing it back. Known keys are
+                            loop (true/false), speed (> 0) and sync_start/sync_end (frame indices); any
+                            other key is stored as-is. May be given several times
+    --thumbnail <average|energy>
+                            pack: embed a thumbnail pose per clip, the frame nearest the clip's average pose
+                            or the one moving the most (see thumbnail.rs)
+    --export-thumbnails <dir>
+                            pack: also write each clip's thumbnail to <dir>/<clip>.bvh as a single-frame
+                            BVH file; implies --thumbnail average unless given
+    --dedupe-clips <warn|skip|alias>
+                            pack: what to do with a clip whose encoded data duplicates an earlier clip's:
+                            say so and store it anyway (the default), leave it out, or store it as an
+                            alias sharing the earlier clip's data under its own name and attributes (see
+                            dedupe.rs)
+    --dedupe-tolerance <distance>
+                            pack: also count a clip as a duplicate of an earlier one with the same skeleton
+                            and frame count if its poses, sampled through the clip, are all within this
+                            distance of the earlier clip's (the channel metric of mocap match)
+    --hierarchy <file.bvh>  Take the skeleton from this BVH file (ignoring any motion in it) and the motion
+    --motion <file>         from the --motion file: rows of channel values, one per frame, optionally after a
+                            BVH MOTION header (see input.rs), or a curve file as --export-curves writes
+    --curve-fps <rate>      Evaluate a curve file given as --motion at this many frames per second rather
+                            than at the file's own frame time
+    --marker <frame>:<name> Add a named event at a frame (of the input, before any trimming). May be given
+                            several times
+    --markers <file>        Add the markers listed in a file, one <frame>,<name> per line
+    --override-frame-time <seconds>
+                            Replace the frame time from the input's header, keeping the original as metadata
+    --max-frames <n>        Keep only the first n frames, keeping the original frame count as metadata
+    --timewarp <curve>      Retime the clip along a curve of <output time>=<input time> points in seconds,
+                            like 0=0,1.0=0.4,2.0=3.0, interpolating every channel at the warped times (see
+                            timewarp.rs). The frame time stays the same
+    --fps <rate>            Resample the clip to this many frames per second, above or below its own rate,
+                            interpolating every channel between frames (rotations the short way round; see
+                            resample.rs)
+    --rational-fps          Store the frame time as an exact frame rate, such as 30 or 30000/1001 (29.97), when
+                            it's close to a common or whole one, and write it exactly on BVH output (see
+                            frame_rate.rs)
+    --timestamps <file>     Time the frames from a file of one time in seconds per frame, for captures with
+                            jittery sampling: kept in the .raw file, and BVH output resampled to uniform
+                            timing at the median frame interval (see timing.rs)
+    --interpolation <linear|cubic>
+                            How --fps interpolates between frames: linearly, or along a Catmull-Rom spline,
+                            which keeps the motion's velocity smooth but can overshoot around sharp
+                            changes (default linear). Also how --repair-gaps fills gaps, and how BVH output
+                            with --timestamps (or decode and unpack of a clip with timestamps) is resampled
+    --repair-gaps <sentinel=<value>|flat=<frames>>[,...]
+                            Find occlusion gaps in every channel, as frames at a sentinel value or runs of
+                            more than the given frames holding one value on a moving channel, and fill them
+                            in from the good samples around them, printing every gap (see gaps.rs). May be
+                            given several times. Lossy
+    --loop-trim             If the clip loops (every frame matches the one a period earlier), keep only one
+                            period and record that it loops
+    --loop-tolerance <t>    How far apart (per channel) frames may be while still matching, for --loop-trim
+                            (default 0.01)
+    --base <file.bvh>       decode: reconstruct a diff's edited clip by adding the base clip it was made from
+    --unroll-loop           decode: replay a clip trimmed with --loop-trim up to its original length
+    --frame <n>             match: the frame of a.bvh to look for. decode: write only frame n, decoded with
+                            the file's seek index if it has one
+    --matches <n>           match: how many of the nearest frames to print (default 5)
+    --position-metric       match: compare joint positions (by forward kinematics) rather than channel values
+    --top <n>               transitions: how many candidate pairs to print (default 5)
+    --stride <n>            transitions: only score every nth frame of each clip, for speed (default 1)
+    --emit-blended <file>   transitions: write the two clips stitched at the best pair, crossfaded over the
+                            suggested blend, as a BVH file
+    --pose-frame [<n>|last] Write only frame n (default 0) or the last frame, as a single-frame pose: the output
+                            BVH of a conversion or batch, decode's output or unpack's clips; n past the last
+                            frame is clamped to it (see thumbnail.rs)
+    --add-bind-pose         decode: add back the bind pose a clip was converted relative to (--bind-pose)
+    --threads-decode <n>    decode: reconstruct channels on n threads (default 1); the output is the same
+    --duplicate-names <error|disambiguate>
+                            What to do when several joints share a name (default disambiguate). Disambiguated
+                            joints are renamed <name>#2, <name>#3, ... in pre-order, and every option selecting a
+                            joint refers to them by that name; the output BVH keeps the original names
+    --frame-count-mismatch <error|rows|header>
+                            What to do when a BVH file's Frames: header disagrees with its rows of motion
+                            (default error): use every row, or the header's count, dropping the rows past it
+    --max-depth <n>         Fail on hierarchies more than n joints deep, rather than risk running out of stack
+                            on a pathological or malicious file (default 1024; see depth.rs)
+    --root <joint>          Treat the selected joint as the root, discarding everything outside its subtree
+    --bake-ancestors        With --root, bake the discarded ancestors' motion into the new root's channels
+    --adjust <joint>:<type>+=<value>|<joint>:<type>*=<value>
+                            Add a constant to, or multiply by a constant, one channel type of the selected
+                            joints for the whole clip, such as LeftShoulder:RotationX+=7 or
+                            RightHand:RotationZ*=-1, keeping rotations within [-180, 180) (see adjust.rs).
+                            May be given several times; applies after a profile's adjustments
+    --snap-to-ground        Detect the floor and move the clip so it is at height 0
+    --up-axis <x|y|z>       The up axis for ground detection (default: detected from the root's motion)
+    --smooth <moving-average:<frames>|one-euro:<min cutoff>[,<beta>]>
+                            Smooth every channel over time before quantization, to remove capture jitter:
+                            with a centered moving average over an odd number of frames, or a one euro
+                            filter with the given minimum cutoff (in Hz) and speed coefficient (default 0).
+                            Off by default. Lossy: the output no longer matches the capture
+    --auto-smooth           Smooth each channel with a moving average sized to its estimated noise floor,
+                            leaving quiet channels alone, and print the windows chosen (see smooth.rs).
+                            Lossy, like --smooth
+    --profile <file>        Per-project settings, such as per-channel clamp bounds and encoding defaults (see
+                            profile.rs)
+    --bits <n>              Channel quantization bits, in [1, 8] (default 8). Like --translation-reference and
+                            --rotation-anchor, may also be set by a `# mocap-bits: <n>` comment in the input,
+                            which the flag overrides (see directives.rs)
+    --rot-error <degrees>   Quantize at the lowest bit depth keeping every rotation channel's error within this
+    --trans-error <units>   and every translation channel's within this, overriding --bits; either may be
+                            given alone. Prints the bit depth chosen and each channel group's error
+    --translation-reference <none|offset|mean>
+                            Store translation channels relative to the joint offset or channel mean (default none)
+    --bind-pose <first-frame|zero|file.bvh[@frame]>
+                            Store channels relative to a bind pose: the clip's first frame, all zeros, or
+                            a frame (default 0) of a BVH file with the same skeleton, such as the base
+                            an additive layer goes on. Rotations are wrapped into [-180, 180) degrees;
+                            the pose is recorded for decode --add-bind-pose
+    --mask <upper|lower|file.toml>
+                            Keep only the upper or lower body's motion, or that of the joints a mask file
+                            lists (see mask.rs), holding every other channel at the bind pose (or the first
+                            frame). The skeleton is unchanged
+    --root-motion           Store the root's X/Z translation and yaw as per-frame forward/sideways displacement
+                            and turn relative to its heading, integrated back on decode, for locomotion
+                            clips (see root_motion.rs). Needs Xposition, Zposition and Yrotation on the root
+    --root-motion-anchors <n>
+                            With --root-motion, record the absolute trajectory every n frames to bound the
+                            integration's drift (default 30)
+    --rotation-anchor <none|zero|rest>
+                            Center rotation channels' quantization on 0 degrees or their first frame's
+                            value, so that angle decodes exactly (default none)
+    --seek-index            Split the .raw file's deltas into blocks and add an index of where each block is
+                            and every channel's level at its start, so a player can decode any frame
+                            without reading the frames before it (see seek.rs)
+    --block-frames <n>      Frames per block with --seek-index or --calibration (default 256)
+    --max-delta-run <n>     Guarantee an absolute level for every channel at least every n frames, bounding what
+                            a corrupt delta can affect: write the .raw file in blocks of at most n frames with
+                            a seek index. verify: check that every file meets it (see drift.rs)
+    --sparse                Store the .raw file's moving channels as, per frame, only the channels whose
+                            level changed, which is smaller for mostly static scenes (see raw.rs)
+    --bit-planes            Store the .raw file's deltas as bit planes, most significant bits first: the same
+                            size, but it can compress better with gzip or zstd afterwards (see bitpack.rs)
+    --predict-channels      Store channels strongly correlated with another (such as mirrored limbs) in the .raw
+                            file as the residual against a linear prediction from it, where that's smaller
+                            (see prediction.rs)
+    --channel-variance      Record every channel's variance in the metadata, for choosing which channels to
+                            drop at a lower level of detail (see variance.rs)
+    --stats-json <file>     Write every channel's min, max, mean and variance as JSON
+    --error-at <joint>:<type>@<frame>
+                            Print the reconstruction error of one channel at one frame, such as
+                            Hips:RotationY@120, decoding only that channel. May be given several times
+    --skeleton-hash         Print a hash of the skeleton (joint names, topology, offsets and channels, but not
+                            the motion), for grouping clips by rig (see skeleton_hash.rs). mocap info prints
+                            every clip's
+    --report-outliers <threshold>
+                            Print every frame where a channel changes by more than this since the frame
+                            before, largest first, to find capture glitches (see outliers.rs)
+    --outlier-units <levels|physical>
+                            Whether --report-outliers' threshold is in quantization levels (the default) or
+                            in degrees and the file's units
+    --report-correlations <n>
+                            Print the n most strongly correlated pairs of channels, by their quantization
+                            levels (see prediction.rs)
+    --locomotion            Analyze each clip's ground speed, heading rate, stride frequency and whether it's in
+                            place, printing them and recording them in the metadata and any --report (see
+                            locomotion.rs). Also applies to pack, and selects the analysis for stats
+    --dof-summary           Print the channel count of every joint, how many joints have how many channels and
+                            the total (see dof.rs). Also applies to batch and pack, and selects the summary
+                            for stats
+    --delta-runs            stats: print every delta-coded channel's longest run between absolute levels and
+                            the error a single corrupt delta could cause in it (see drift.rs)
+    --time-budget <ms>      Spend at most this long per clip looking for periodic channel encodings, storing
+                            the channels left over as deltas, and print how many fell back. Bounds the time
+                            a huge clip takes to write, at some cost in size (see periodic.rs)
+    --calibration <file.bvh>
+                            Write the .raw file incrementally, one frame at a time, quantizing with the
+                            calibration clip's channel ranges (values outside them are clamped). The
+                            streamed file has no metadata or markers
+    --ranges-out <file>     Write the range each channel was quantized with, per joint and channel type, as
+                            a ranges file (see ranges.rs)
+    --ranges-in <file>      Quantize the channels a ranges file lists with its ranges instead of their own,
+                            clamping (with a warning) values outside them, so a character's clips share one
+                            grid. Also applies to batch and pack
+    --vq <file>             Experimental: also write the clip vector-quantized, as indices into a codebook
+                            of representative frames, and report the size and error against the raw file
+    --vq-codebook-size <n>  The number of codebook entries for --vq, in [1, 65536] (default 64)
+    --crlf                  Write the output BVH with CRLF line endings (default LF)
+    --csv-group-by <joint|channel-type>
+                            Write the CSV's channels joint by joint (the default), or grouped by channel type
+                            across the skeleton (every TranslationX, then every TranslationY, ...) with each
+                            row labelled <joint>;<channel type>;<frame>;<delta>
+    --self-check            Read the output BVH back and fail if it doesn't parse (with the parse error) or
+                            its hierarchy or frame count differs from the source's
+    --export-local-matrices <file>
+                            Write every joint's dequantized local transform per frame as 4x4 f32 matrices
+                            (column-major, frame-major, joints in pre-order; see matrices.rs)
+    --export-world-matrices <file>
+                            Like --export-local-matrices, with every joint's world transform instead
+    --export-texture <file> Write every channel's quantized level per frame as a texture for a shader to sample:
+                            a metadata blob, then one byte per texel, a row per channel and a column per
+                            frame (see texture.rs for the layout)
+    --texture-pow2          Pad --export-texture's width and height up to powers of two
+    --export-fixed-point <file.h>
+                            Write a C header of per-channel integer constants (bias, scale and shift in a
+                            16- or 32-bit word) for dequantizing levels without floating point, with each
+                            channel's worst-case error against the float decode, and the channel map as a
+                            table (see fixed_point.rs)
+    --fixed-point-precision <p>
+                            Use 16-bit words for the channels they represent within this (in units or
+                            degrees) and 32-bit ones otherwise, failing if a channel can't meet it at all
+    --export-deltas-bvh <file>
+                            Debugging: write a BVH file of the skeleton whose motion is every channel's quantized
+                            deltas rather than its values, to see the residual signal (see residual.rs)
+    --export-curves <file>  Write every channel fitted with cubic Hermite segments (knot times, values and in
+                            and out tangents), as JSON for a .json file name and binary otherwise, and print
+                            the fit's largest error (see curves.rs)
+    --curve-tolerance <t>   How far --export-curves' curves may stray from any frame, in units or degrees
+                            (default 0.25)
+    --export-markers <file> Write the marker track as JSON
+    --save-markers <file>   Write the marker track as a marker file (see --markers), so the markers survive a
+                            round trip through the BVH output. Also applies to decode
+    --save-timestamps <file>
+                            decode: write the clip's frames as captured, not resampled, and their timestamps
+                            as a file --timestamps reads back
+    --export-channel-map <file>
+                            Write the flat channel index -> joint/channel type map as JSON. A .raw file or
+                            container holds it already, as its hierarchy's channel order
+    --export-joint-graph <file>
+                            Write the skeleton as a JSON joint graph (names, parents, offsets and channels,
+                            but no motion) for robotics tools (see joint_graph.rs)
+    --sweep-csv <file>      With --sweep-bits, also write the table as CSV
+    --diff-json <file>      diff-mocap: also write the comparison as JSON
+    --values <n>            dump: also list every channel's first and last n decoded values
+    --full                  dump: list everything, every channel's stored data and decoded values included
+    --bits-for <joint>:<type|*>=<bits>
+                            reencode: the bits to store the joint's channels of that type (or all of them)
+                            at, in [1, 8] or 64 for lossless. Can be repeated; later ones take precedence
+    --source <file.bvh>     reencode: quantize the selected channels again from this clip, with the encoded
+                            file's skeleton and frame count, instead of from the file's own decode
+    --verbose               Print debug information to stderr
+    --strict                Fail if any lossy operation is in effect without --lossy
+    --lossy                 Explicitly accept lossy operations under --strict
+
+Options taking a <joint> accept a joint path selector: names separated by /, matched against the
+end of each joint's path (a leading / anchors at the root). Within a name * and ? are wildcards, a
+** path segment matches any number of joints, and \\ escapes the next character. For example
+LeftHand, Chest/LeftShoulder, /Hips/Spine, */LeftHand/*, Prop\\/Sword.
+
+Lossy operations (relative to the default 8-bit encoding):
+    quantization below 8 bits (--bits), or to meet error targets (--rot-error, --trans-error)
+    loop trimming with a nonzero tolerance (--loop-trim)
+    vector quantization (--vq)
+    smoothing (--smooth, --auto-smooth)
+    resampling (--fps)
+    frame time rounding to an exact rate (--rational-fps)
+    time warping (--timewarp)
+    gap repair (--repair-gaps)
+    root motion encoding (--root-motion)
+    resampling to uniform frame timing on BVH output (--timestamps)
+    frame truncation (--max-frames, --frame-count-mismatch header)
+    single-frame pose output (--pose-frame)
+    subtree selection (--root)
+    zeroing channels (--adjust <joint>:<type>*=0, or a profile's multiply = 0)
+    ground snapping (--snap-to-ground)
+    masking (--mask)
+    clamping to given ranges (--calibration, --ranges-in) or a profile's clamp bounds
+    clip deduplication, leaving duplicates out (--dedupe-clips skip) or aliasing near-duplicates
+        (--dedupe-clips alias with --dedupe-tolerance)
+
+Other adjustments (--adjust) are recorded in the metadata, so the opposite adjustment undoes them up
+to rounding, and exact duplicates aliased (--dedupe-clips alias alone) decode the same as the clips
+they alias, so neither counts.";
+
+#[derive(Debug)]
+pub enum Command {
+    Convert {
+        input_file_name: String,
+        output_file_name: String,
+        csv_file_name: String,
+        raw_file_name: String,
+    },
+    Batch {
+        input_dir: String,
+        output_dir: String,
+    },
+    Decode {
+        input_file_name: String,
+        output_file_name: String,
+    },
+    Concat {
+        output_file_name: String,
+        input_file_names: Vec<String>,
+    },
+    Pack {
+        output_file_name: String,
+        input_file_names: Vec<String>,
+    },
+    Unpack {
+        input_file_name: String,
+        output_dir: String,
+    },
+    Info {
+        input_file_name: String,
+    },
+    Dump {
+        input_file_name: String,
+    },
+    Verify {
+        input_file_names: Vec<String>,
+    },
+    Stats {
+        input_file_names: Vec<String>,
+    },
+    Diff {
+        base_file_name: String,
+        input_file_name: String,
+        raw_file_name: String,
+    },
+    DiffMocap {
+        first_file_name: String,
+        second_file_name: String,
+    },
+    Reencode {
+        input_file_name: String,
+        output_file_name: String,
+    },
+    Match {
+        query_file_name: String,
+        input_file_name: String,
+    },
+    Transitions {
+        first_file_name: String,
+        second_file_name: String,
+    },
+    MakePatch {
+        old_file_name: String,
+        new_file_name: String,
+        patch_file_name: String,
+    },
+    ApplyPatch {
+        old_file_name: String,
+        patch_file_name: String,
+        new_file_name: String,
+    },
+    Shell {
+        input_file_name: String,
+    },
+    SweepBits {
+        input_file_name: String,
+    },
+}
+
+impl Command {
+    // As recorded in manifests and reports
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Command::Convert { .. } => "convert",
+            Command::Batch { .. } => "batch",
+            Command::Decode { .. } => "decode",
+            Command::Concat { .. } => "concat",
+            Command::Pack { .. } => "pack",
+            Command::Unpack { .. } => "unpack",
+            Command::Info { .. } => "info",
+            Command::Dump { .. } => "dump",
+            Command::Verify { .. } => "verify",
+            Command::Stats { .. } => "stats",
+            Command::Diff { .. } => "diff",
+            Command::DiffMocap { .. } => "diff-mocap",
+            Command::Reencode { .. } => "reencode",
+            Command::Match { .. } => "match",
+            Command::Transitions { .. } => "transitions",
+            Command::MakePatch { .. } => "make-patch",
+            Command::ApplyPatch { .. } => "apply-patch",
+            Command::Shell { .. } => "shell",
+            Command::SweepBits { .. } => "sweep-bits",
+        }
+    }
+
+    // The input files named on the command line (a batch's input directory for batch)
+    pub fn input_file_names(&self) -> Vec<&str> {
+        match *self {
+            Command::Convert { ref input_file_name, .. } | Command::Decode { ref input_file_name, .. } | Command::Unpack { ref input_file_name, .. } | Command::Info { ref input_file_name } | Command::Dump { ref input_file_name } | Command::Reencode { ref input_file_name, .. } | Command::Shell { ref input_file_name } | Command::SweepBits { ref input_file_name } => vec![input_file_name],
+            Command::Batch { ref input_dir, .. } => vec![input_dir],
+            Command::Concat { ref input_file_names, .. } | Command::Pack { ref input_file_names, .. } | Command::Verify { ref input_file_names } | Command::Stats { ref input_file_names } => input_file_names.iter().map(|name| name.as_str()).collect(),
+            Command::Diff { ref base_file_name, ref input_file_name, .. } => vec![base_file_name, input_file_name],
+            Command::DiffMocap { ref first_file_name, ref second_file_name } | Command::Transitions { ref first_file_name, ref second_file_name } => vec![first_file_name, second_file_name],
+            Command::Match { ref query_file_name, ref input_file_name } => vec![query_file_name, input_file_name],
+            Command::MakePatch { ref old_file_name, ref new_file_name, .. } => vec![old_file_name, new_file_name],
+            Command::ApplyPatch { ref old_file_name, ref patch_file_name, .. } => vec![old_file_name, patch_file_name],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub command: Command,
+    pub recursive: bool,
+    pub jobs: Option<usize>,
+    pub max_depth: usize,
+    pub cache_dir: Option<String>,
+    pub cache_max_size: Option<u64>,
+    pub manifest_file_name: Option<String>,
+    pub report_file_name: Option<String>,
+    pub compare_report_file_name: Option<String>,
+    pub regression_threshold: f64,
+    pub fail_on_regression: bool,
+    pub quantized_append: bool,
+    pub reference_pose: bool,
+    pub reference_tolerance: f64,
+    pub clip_attributes: Vec<(String, Vec<(String, String)>)>,
+    pub thumbnail: Option<thumbnail::Policy>,
+    pub export_thumbnails_dir: Option<String>,
+    pub dedupe_clips: dedupe::Policy,
+    pub dedupe_tolerance: Option<f64>,
+    pub hierarchy_file_name: Option<String>,
+    pub motion_file_name: Option<String>,
+    pub curve_fps: Option<f64>,
+    pub markers: Vec<Marker>,
+    pub markers_file_name: Option<String>,
+    pub timestamps_file_name: Option<String>,
+    pub override_frame_time: Option<f64>,
+    pub max_frames: Option<u32>,
+    pub timewarp: Option<Curve>,
+    pub fps: Option<f64>,
+    pub rational_fps: bool,
+    pub interpolation: Interpolation,
+    pub repair_gaps: Option<Detection>,
+    pub loop_trim: bool,
+    pub loop_tolerance: f64,
+    pub unroll_loop: bool,
+    pub base_file_name: Option<String>,
+    pub add_bind_pose: bool,
+    pub frame: Option<u32>, // match's query frame, or decode's only frame
+    pub pose_frame: Option<thumbnail::PoseFrame>,
+    pub num_matches: usize,
+    pub match_metric: Metric,
+    pub num_transitions: usize,
+    pub transition_stride: usize,
+    pub emit_blended_file_name: Option<String>,
+    pub decode_threads: usize,
+    pub duplicate_names: DuplicateNames,
+    pub frame_count_mismatch: FrameCountMismatch,
+    pub root: Option<String>,
+    pub bake_ancestors: bool,
+    pub adjustments: Vec<Adjustment>,
+    pub snap_to_ground: bool,
+    pub up_axis: Option<usize>,
+    pub profile_file_name: Option<String>,
+    pub conversion_builder: ConversionSettingsBuilder, // As given on the command line
+    pub conversion: ConversionSettings, // Likewise, validated
+    pub calibration_file_name: Option<String>,
+    pub ranges_out_file_name: Option<String>,
+    pub ranges_in_file_name: Option<String>,
+    pub channel_variance: bool,
+    pub locomotion: bool,
+    pub dof_summary: bool,
+    pub delta_runs: bool,
+    pub stats_json_file_name: Option<String>,
+    pub error_queries: Vec<ErrorQuery>,
+    pub skeleton_hash: bool,
+    pub outlier_threshold: Option<f64>,
+    pub outlier_units: outliers::Units,
+    pub num_correlations: Option<usize>,
+    pub predict_channels: bool,
+    pub time_budget: Option<u64>, // Milliseconds
+    pub vq_file_name: Option<String>,
+    pub vq_codebook_size: usize,
+    pub crlf: bool,
+    pub csv_by_channel_type: bool,
+    pub self_check: bool,
+    pub channel_map_file_name: Option<String>,
+    pub joint_graph_file_name: Option<String>,
+    pub export_markers_file_name: Option<String>,
+    pub save_markers_file_name: Option<String>,
+    pub save_timestamps_file_name: Option<String>,
+    pub local_matrices_file_name: Option<String>,
+    pub world_matrices_file_name: Option<String>,
+    pub texture_file_name: Option<String>,
+    pub texture_pow2: bool,
+    pub deltas_bvh_file_name: Option<String>,
+    pub fixed_point_file_name: Option<String>,
+    pub fixed_point_precision: Option<f64>,
+    pub curves_file_name: Option<String>,
+    pub curve_tolerance: f64,
+    pub sweep_csv_file_name: Option<String>,
+    pub diff_json_file_name: Option<String>,
+    pub dump_values: Option<usize>,
+    pub dump_full: bool,
+    pub bits_for: Vec<BitsFor>,
+    pub reencode_source_file_name: Option<String>,
+    pub verbose: bool,
+    pub strict: bool,
+    pub lossy: bool,
+    pub given: Vec<String>, // Every option on the command line, for settings they override
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            command: Command::Batch {
+                input_dir: String::new(),
+                output_dir: String::new(),
+            },
+            recursive: false,
+            jobs: None,
+            max_depth: depth::DEFAULT_MAX_DEPTH,
+            cache_dir: None,
+            cache_max_size: None,
+            manifest_file_name: None,
+            report_file_name: None,
+            compare_report_file_name: None,
+            regression_threshold: 0.05,
+            fail_on_regression: false,
+            quantized_append: false,
+            reference_pose: false,
+            reference_tolerance: 0.0,
+            clip_attributes: Vec::new(),
+            thumbnail: None,
+            export_thumbnails_dir: None,
+            dedupe_clips: dedupe::Policy::Warn,
+            dedupe_tolerance: None,
+            hierarchy_file_name: None,
+            motion_file_name: None,
+            curve_fps: None,
+            markers: Vec::new(),
+            markers_file_name: None,
+            timestamps_file_name: None,
+            override_frame_time: None,
+            max_frames: None,
+            timewarp: None,
+            fps: None,
+            rational_fps: false,
+            interpolation: Interpolation::Linear,
+            repair_gaps: None,
+            loop_trim: false,
+            loop_tolerance: 0.01,
+            unroll_loop: false,
+            base_file_name: None,
+            add_bind_pose: false,
+            frame: None,
+            pose_frame: None,
+            num_matches: 5,
+            match_metric: Metric::Channels,
+            num_transitions: 5,
+            transition_stride: 1,
+            emit_blended_file_name: None,
+            decode_threads: 1,
+            duplicate_names: DuplicateNames::Disambiguate,
+            frame_count_mismatch: FrameCountMismatch::Error,
+            root: None,
+            bake_ancestors: false,
+            adjustments: Vec::new(),
+            snap_to_ground: false,
+            up_axis: None,
+            profile_file_name: None,
+            conversion_builder: ConversionSettingsBuilder::default(),
+            conversion: ConversionSettings::default(),
+            calibration_file_name: None,
+            ranges_out_file_name: None,
+            ranges_in_file_name: None,
+            channel_variance: false,
+            locomotion: false,
+            dof_summary: false,
+            delta_runs: false,
+            stats_json_file_name: None,
+            error_queries: Vec::new(),
+            skeleton_hash: false,
+            outlier_threshold: None,
+            outlier_units: outliers::Units::Levels,
+            num_correlations: None,
+            predict_channels: false,
+            time_budget: None,
+            vq_file_name: None,
+            vq_codebook_size: 64,
+            crlf: false,
+            csv_by_channel_type: false,
+            self_check: false,
+            channel_map_file_name: None,
+            joint_graph_file_name: None,
+            export_markers_file_name: None,
+            save_markers_file_name: None,
+            save_timestamps_file_name: None,
+            local_matrices_file_name: None,
+            world_matrices_file_name: None,
+            texture_file_name: None,
+            texture_pow2: false,
+            deltas_bvh_file_name: None,
+            fixed_point_file_name: None,
+            fixed_point_precision: None,
+            curves_file_name: None,
+            curve_tolerance: curves::DEFAULT_TOLERANCE,
+            sweep_csv_file_name: None,
+            diff_json_file_name: None,
+            dump_values: None,
+            dump_full: false,
+            bits_for: Vec::new(),
+            reencode_source_file_name: None,
+            verbose: false,
+            strict: false,
+            lossy: false,
+            given: Vec::new(),
+        }
+    }
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Options, MocapError> {
+        let mut ret = Options::default();
+        let mut positional = Vec::new();
+
+        let mut args = args.peekable();
+        let subcommand = match args.peek().map(|arg| arg.as_str()) {
+            Some("batch") | Some("decode") | Some("concat") | Some("pack") | Some("unpack") | Some("info") | Some("dump") | Some("verify") | Some("stats") | Some("diff") | Some("diff-mocap") | Some("reencode") | Some("match") | Some("transitions") | Some("make-patch") | Some("apply-patch") | Some("shell") => args.next(),
+            _ => None,
+        };
+        let batch = subcommand.as_deref() == Some("batch");
+
+        let mut sweep_bits = false;
+        while let Some(arg) = args.next() {
+            if arg.starts_with("--") {
+                ret.given.push(arg.clone());
+            }
+            match arg.as_str() {
+                "--hierarchy" => ret.hierarchy_file_name = Some(value(&arg, args.next())?),
+                "--motion" => ret.motion_file_name = Some(value(&arg, args.next())?),
+                "--curve-fps" => ret.curve_fps = Some(parse_value(&arg, args.next())?),
+                "--marker" => {
+                    let spec = value(&arg, args.next())?;
+                    ret.markers.push(markers::parse(&spec).ok_or_else(|| usage(format!("invalid value for {}: {}", arg, spec)))?);
+                }
+                "--markers" => ret.markers_file_name = Some(value(&arg, args.next())?),
+                "--timestamps" => ret.timestamps_file_name = Some(value(&arg, args.next())?),
+                "--override-frame-time" => ret.override_frame_time = Some(parse_value(&arg, args.next())?),
+                "--max-frames" => ret.max_frames = Some(parse_value(&arg, args.next())?),
+                "--loop-trim" => ret.loop_trim = true,
+                "--loop-tolerance" => ret.loop_tolerance = parse_value(&arg, args.next())?,
+                "--unroll-loop" => ret.unroll_loop = true,
+                "--base" => ret.base_file_name = Some(value(&arg, args.next())?),
+                "--add-bind-pose" => ret.add_bind_pose = true,
+                "--frame" => ret.frame = Some(parse_value(&arg, args.next())?),
+                // The frame is optional
+                "--pose-frame" => ret.pose_frame = Some(match args.peek().and_then(|value| thumbnail::PoseFrame::parse(value)) {
+                    Some(frame) => {
+                        args.next();
+                        frame
+                    }
+                    None => thumbnail::PoseFrame::Index(0),
+                }),
+                "--matches" => ret.num_matches = parse_value(&arg, args.next())?,
+                "--position-metric" => ret.match_metric = Metric::Positions,
+                "--top" => ret.num_transitions = parse_value(&arg, args.next())?,
+                "--stride" => ret.transition_stride = parse_value(&arg, args.next())?,
+                "--emit-blended" => ret.emit_blended_file_name = Some(value(&arg, args.next())?),
+                "--threads-decode" => ret.decode_threads = parse_value(&arg, args.next())?,
+                "--duplicate-names" => ret.duplicate_names = match value(&arg, args.next())?.as_str() {
+                    "error" => DuplicateNames::Error,
+                    "disambiguate" => DuplicateNames::Disambiguate,
+                    other => return Err(usage(format!("invalid value for {}: {}", arg, other))),
+                },
+                "--frame-count-mismatch" => ret.frame_count_mismatch = match value(&arg, args.next())?.as_str() {
+                    "error" => FrameCountMismatch::Error,
+                    "rows" => FrameCountMismatch::Rows,
+                    "header" => FrameCountMismatch::Header,
+                    other => return Err(usage(format!("invalid value for {}: {}", arg, other))),
+                },
+                "--root" => ret.root = Some(value(&arg, args.next())?),
+                "--bake-ancestors" => ret.bake_ancestors = true,
+                "--adjust" => {
+                    let spec = value(&arg, args.next())?;
+                    ret.adjustments.push(Adjustment::parse(&spec).ok_or_else(|| usage(format!("invalid value for {}: {}", arg, spec)))?);
+                }
+                "--snap-to-ground" => ret.snap_to_ground = true,
+                "--timewarp" => {
+                    let spec = value(&arg, args.next())?;
+                    ret.timewarp = Some(Curve::parse(&spec).map_err(|message| usage(format!("invalid value for {}: {}", arg, message)))?);
+                }
+                "--fps" => ret.fps = Some(parse_value(&arg, args.next())?),
+                "--rational-fps" => ret.rational_fps = true,
+                "--interpolation" => ret.interpolation = match value(&arg, args.next())?.as_str() {
+                    "linear" => Interpolation::Linear,
+                    "cubic" => Interpolation::Cubic,
+                    other => return Err(usage(format!("invalid value for {}: {}", arg, other))),
+                },
+                "--repair-gaps" => {
+                    let spec = value(&arg, args.next())?;
+                    ret.repair_gaps.get_or_insert_with(Detection::default).parse(&spec).ok_or_else(|| usage(format!("invalid value for {}: {}", arg, spec)))?;
+                }
+                "--smooth" => {
+                    let spec = value(&arg, args.next())?;
+                    ret.conversion_builder.smooth(smooth::parse(&spec).ok_or_else(|| usage(format!("invalid value for {}: {}", arg, spec)))?);
+                }
+                "--auto-smooth" => {
+                    ret.conversion_builder.auto_smooth(true);
+                }
+                "--up-axis" => ret.up_axis = Some(match value(&arg, args.next())?.to_lowercase().as_str() {
+                    "x" => 0,
+                    "y" => 1,
+                    "z" => 2,
+                    other => return Err(usage(format!("invalid value for {}: {}", arg, other))),
+                }),
+                "--profile" => ret.profile_file_name = Some(value(&arg, args.next())?),
+                "--bits" => {
+                    ret.conversion_builder.channel_quantization_bits(parse_value(&arg, args.next())?);
+                }
+                "--translation-reference" => {
+                    ret.conversion_builder.translation_reference(match value(&arg, args.next())?.as_str() {
+                        "none" => TranslationReference::None,
+                        "offset" => TranslationReference::Offset,
+                        "mean" => TranslationReference::Mean,
+                        other => return Err(usage(format!("invalid value for {}: {}", arg, other))),
+                    });
+                }
+                "--bind-pose" => {
+                    ret.conversion_builder.bind_pose(BindPose::parse(&value(&arg, args.next())?));
+                }
+                "--root-motion" => {
+                    ret.conversion_builder.root_motion(true);
+                }
+                "--root-motion-anchors" => {
+                    ret.conversion_builder.root_motion_anchors(parse_value(&arg, args.next())?);
+                }
+                "--mask" => {
+                    ret.conversion_builder.mask(Mask::parse(&value(&arg, args.next())?));
+                }
+                "--rot-error" => {
+                    ret.conversion_builder.rotation_error(parse_value(&arg, args.next())?);
+                }
+                "--trans-error" => {
+                    ret.conversion_builder.translation_error(parse_value(&arg, args.next())?);
+                }
+                "--rotation-anchor" => {
+                    ret.conversion_builder.rotation_anchor(match value(&arg, args.next())?.as_str() {
+                        "none" => RotationAnchor::None,
+                        "zero" => RotationAnchor::Zero,
+                        "rest" => RotationAnchor::Rest,
+                        other => return Err(usage(format!("invalid value for {}: {}", arg, other))),
+                    });
+                }
+                "--calibration" => ret.calibration_file_name = Some(value(&arg, args.next())?),
+                "--ranges-out" => ret.ranges_out_file_name = Some(value(&arg, args.next())?),
+                "--ranges-in" => ret.ranges_in_file_name = Some(value(&arg, args.next())?),
+                "--seek-index" => {
+                    ret.conversion_builder.seek_index(true);
+                }
+                "--sparse" => {
+                    ret.conversion_builder.sparse(true);
+                }
+                "--bit-planes" => {
+                    ret.conversion_builder.bit_planes(true);
+                }
+                "--channel-variance" => ret.channel_variance = true,
+                "--locomotion" => ret.locomotion = true,
+                "--dof-summary" => ret.dof_summary = true,
+                "--delta-runs" => ret.delta_runs = true,
+                "--max-delta-run" => {
+                    ret.conversion_builder.max_delta_run(parse_value(&arg, args.next())?);
+                }
+                "--stats-json" => ret.stats_json_file_name = Some(value(&arg, args.next())?),
+                "--error-at" => {
+                    let spec = value(&arg, args.next())?;
+                    ret.error_queries.push(ErrorQuery::parse(&spec).ok_or_else(|| usage(format!("invalid value for {}: {}", arg, spec)))?);
+                }
+                "--skeleton-hash" => ret.skeleton_hash = true,
+                "--report-outliers" => ret.outlier_threshold = Some(parse_value(&arg, args.next())?),
+                "--report-correlations" => ret.num_correlations = Some(parse_value(&arg, args.next())?),
+                "--predict-channels" => ret.predict_channels = true,
+                "--outlier-units" => ret.outlier_units = match value(&arg, args.next())?.as_str() {
+                    "levels" => outliers::Units::Levels,
+                    "physical" => outliers::Units::Physical,
+                    other => return Err(usage(format!("invalid value for {}: {}", arg, other))),
+                },
+                "--block-frames" => {
+                    ret.conversion_builder.block_frames(parse_value(&arg, args.next())?);
+                }
+                "--time-budget" => ret.time_budget = Some(parse_value(&arg, args.next())?),
+                "--vq" => ret.vq_file_name = Some(value(&arg, args.next())?),
+                "--vq-codebook-size" => ret.vq_codebook_size = parse_value(&arg, args.next())?,
+                "--crlf" => ret.crlf = true,
+                "--csv-group-by" => ret.csv_by_channel_type = match value(&arg, args.next())?.as_str() {
+                    "joint" => false,
+                    "channel-type" => true,
+                    other => return Err(usage(format!("invalid value for {}: {}", arg, other))),
+                },
+                "--self-check" => ret.self_check = true,
+                "--export-markers" => ret.export_markers_file_name = Some(value(&arg, args.next())?),
+                "--save-markers" => ret.save_markers_file_name = Some(value(&arg, args.next())?),
+                "--save-timestamps" => ret.save_timestamps_file_name = Some(value(&arg, args.next())?),
+                "--export-channel-map" => ret.channel_map_file_name = Some(value(&arg, args.next())?),
+                "--export-joint-graph" => ret.joint_graph_file_name = Some(value(&arg, args.next())?),
+                "--export-local-matrices" => ret.local_matrices_file_name = Some(value(&arg, args.next())?),
+                "--export-texture" => ret.texture_file_name = Some(value(&arg, args.next())?),
+                "--texture-pow2" => ret.texture_pow2 = true,
+                "--export-fixed-point" => ret.fixed_point_file_name = Some(value(&arg, args.next())?),
+                "--fixed-point-precision" => ret.fixed_point_precision = Some(parse_value(&arg, args.next())?),
+                "--export-deltas-bvh" => ret.deltas_bvh_file_name = Some(value(&arg, args.next())?),
+                "--export-curves" => ret.curves_file_name = Some(value(&arg, args.next())?),
+                "--curve-tolerance" => ret.curve_tolerance = parse_value(&arg, args.next())?,
+                "--export-world-matrices" => ret.world_matrices_file_name = Some(value(&arg, args.next())?),
+                "--recursive" => ret.recursive = true,
+                "--jobs" => ret.jobs = Some(parse_value(&arg, args.next())?),
+                "--max-depth" => ret.max_depth = parse_value(&arg, args.next())?,
+                "--manifest" => ret.manifest_file_name = Some(value(&arg, args.next())?),
+                "--report" => ret.report_file_name = Some(value(&arg, args.next())?),
+                "--compare-report" => ret.compare_report_file_name = Some(value(&arg, args.next())?),
+                "--regression-threshold" => ret.regression_threshold = parse_value(&arg, args.next())?,
+                "--fail-on-regression" => ret.fail_on_regression = true,
+                "--cache-dir" => ret.cache_dir = Some(value(&arg, args.next())?),
+                "--cache-max-size" => {
+                    let size = value(&arg, args.next())?;
+                    ret.cache_max_size = Some(parse_size(&size).ok_or_else(|| usage(format!("invalid value for {}: {}", arg, size)))?);
+                }
+                "--quantized-append" => ret.quantized_append = true,
+                "--reference-pose" => ret.reference_pose = true,
+                "--reference-tolerance" => ret.reference_tolerance = parse_value(&arg, args.next())?,
+                "--clip-attr" => ret.clip_attributes.push(parse_clip_attributes(&arg, value(&arg, args.next())?)?),
+                "--thumbnail" => ret.thumbnail = Some(match value(&arg, args.next())?.as_str() {
+                    "average" => thumbnail::Policy::Average,
+                    "energy" => thumbnail::Policy::Energy,
+                    other => return Err(usage(format!("invalid value for {}: {}", arg, other))),
+                }),
+                "--export-thumbnails" => ret.export_thumbnails_dir = Some(value(&arg, args.next())?),
+                "--dedupe-clips" => ret.dedupe_clips = match value(&arg, args.next())?.as_str() {
+                    "warn" => dedupe::Policy::Warn,
+                    "skip" => dedupe::Policy::Skip,
+                    "alias" => dedupe::Policy::Alias,
+                    other => return Err(usage(format!("invalid value for {}: {}", arg, other))),
+                },
+                "--dedupe-tolerance" => ret.dedupe_tolerance = Some(parse_value(&arg, args.next())?),
+                "--sweep-bits" => sweep_bits = true,
+                "--sweep-csv" => ret.sweep_csv_file_name = Some(value(&arg, args.next())?),
+                "--diff-json" => ret.diff_json_file_name = Some(value(&arg, args.next())?),
+                "--values" => ret.dump_values = Some(parse_value(&arg, args.next())?),
+                "--full" => ret.dump_full = true,
+                "--bits-for" => {
+                    let spec = value(&arg, args.next())?;
+                    ret.bits_for.push(BitsFor::parse(&spec).ok_or_else(|| usage(format!("invalid value for {}: {}", arg, spec)))?);
+                }
+                "--source" => ret.reencode_source_file_name = Some(value(&arg, args.next())?),
+                "--verbose" => ret.verbose = true,
+                "--strict" => ret.strict = true,
+                "--lossy" => ret.lossy = true,
+                _ if arg.starts_with("--") => return Err(usage(format!("unknown option {}", arg))),
+                _ => positional.push(arg),
+            }
+        }
+        ret.conversion_builder.streamed(ret.calibration_file_name.is_some());
+        ret.conversion = ret.conversion_builder.build().map_err(|e| match e {
+            MocapError::Usage(message) => usage(message),
+            e => e,
+        })?;
+
+        if subcommand.is_some() && sweep_bits {
+            return Err(usage("--sweep-bits only applies to single-file conversion".into()));
+        }
+        if ret.hierarchy_file_name.is_some() != ret.motion_file_name.is_some() {
+            return Err(usage("--hierarchy and --motion must be given together".into()));
+        }
+        if ret.hierarchy_file_name.is_some() && (subcommand.is_some() || sweep_bits) {
+            return Err(usage("--hierarchy and --motion only apply to single-file conversion".into()));
+        }
+        if ret.curve_fps.is_some() && ret.motion_file_name.is_none() {
+            return Err(usage("--curve-fps requires --motion".into()));
+        }
+        if ret.curve_fps.is_some_and(|fps| !fps.is_finite() || fps <= 0.0) {
+            return Err(usage("--curve-fps must be positive".into()));
+        }
+        let expected = match subcommand.as_deref() {
+            Some("batch") | Some("decode") | Some("unpack") => 2,
+            Some("concat") => ::std::cmp::max(positional.len(), 3),
+            Some("pack") => ::std::cmp::max(positional.len(), 2),
+            Some("info") | Some("dump") | Some("shell") => 1,
+            Some("verify") | Some("stats") => ::std::cmp::max(positional.len(), 1),
+            Some("diff") | Some("make-patch") | Some("apply-patch") => 3,
+            Some("diff-mocap") | Some("reencode") | Some("match") | Some("transitions") => 2,
+            _ if sweep_bits => 1,
+            _ if ret.hierarchy_file_name.is_some() => 3,
+            _ => 4,
+        };
+        if positional.len() != expected {
+            return Err(usage(format!("expected {}{} file names, got {}", if subcommand.as_deref() == Some("concat") || subcommand.as_deref() == Some("pack") || subcommand.as_deref() == Some("verify") || subcommand.as_deref() == Some("stats") { "at least " } else { "" }, expected, positional.len())));
+        }
+        let mut positional = positional.into_iter();
+        let mut next = || positional.next().unwrap();
+        ret.command = match subcommand.as_deref() {
+            Some("batch") => Command::Batch {
+                input_dir: next(),
+                output_dir: next(),
+            },
+            Some("decode") => Command::Decode {
+                input_file_name: next(),
+                output_file_name: next(),
+            },
+            Some("concat") => Command::Concat {
+                output_file_name: next(),
+                input_file_names: (1..expected).map(|_| next()).collect(),
+            },
+            Some("pack") => Command::Pack {
+                output_file_name: next(),
+                input_file_names: (1..expected).map(|_| next()).collect(),
+            },
+            Some("unpack") => Command::Unpack {
+                input_file_name: next(),
+                output_dir: next(),
+            },
+            Some("info") => Command::Info {
+                input_file_name: next(),
+            },
+            Some("dump") => Command::Dump {
+                input_file_name: next(),
+            },
+            Some("verify") => Command::Verify {
+                input_file_names: (0..expected).map(|_| next()).collect(),
+            },
+            Some("stats") => Command::Stats {
+                input_file_names: (0..expected).map(|_| next()).collect(),
+            },
+            Some("diff") => Command::Diff {
+                base_file_name: next(),
+                input_file_name: next(),
+                raw_file_name: next(),
+            },
+            Some("diff-mocap") => Command::DiffMocap {
+                first_file_name: next(),
+                second_file_name: next(),
+            },
+            Some("reencode") => Command::Reencode {
+                input_file_name: next(),
+                output_file_name: next(),
+            },
+            Some("match") => Command::Match {
+                query_file_name: next(),
+                input_file_name: next(),
+            },
+            Some("transitions") => Command::Transitions {
+                first_file_name: next(),
+                second_file_name: next(),
+            },
+            Some("make-patch") => Command::MakePatch {
+                old_file_name: next(),
+                new_file_name: next(),
+                patch_file_name: next(),
+            },
+            Some("apply-patch") => Command::ApplyPatch {
+                old_file_name: next(),
+                patch_file_name: next(),
+                new_file_name: next(),
+            },
+            Some("shell") => Command::Shell {
+                input_file_name: next(),
+            },
+            _ if sweep_bits => Command::SweepBits {
+                input_file_name: next(),
+            },
+            _ => Command::Convert {
+                input_file_name: match ret.motion_file_name {
+                    Some(ref motion_file_name) => motion_file_name.clone(),
+                    None => next(),
+                },
+                output_file_name: next(),
+                csv_file_name: next(),
+                raw_file_name: next(),
+            },
+        };
+
+        if ret.recursive && !batch {
+            return Err(usage("--recursive only applies to batch".into()));
+        }
+        if ret.jobs.is_some() && !batch {
+            return Err(usage("--jobs only applies to batch".into()));
+        }
+        if ret.max_depth == 0 {
+            return Err(usage("--max-depth must be at least 1".into()));
+        }
+        if ret.jobs == Some(0) {
+            return Err(usage("--jobs must be at least 1".into()));
+        }
+        if ret.report_file_name.is_some() && (subcommand.is_some() && !batch && subcommand.as_deref() != Some("verify") || sweep_bits) {
+            return Err(usage("--report only applies to single-file conversion, batch and verify".into()));
+        }
+        if ret.compare_report_file_name.is_some() && ret.report_file_name.is_none() {
+            return Err(usage("--compare-report requires --report".into()));
+        }
+        if ret.fail_on_regression && ret.compare_report_file_name.is_none() {
+            return Err(usage("--fail-on-regression requires --compare-report".into()));
+        }
+        if ret.regression_threshold.is_nan() || ret.regression_threshold < 0.0 {
+            return Err(usage("--regression-threshold must be at least 0".into()));
+        }
+        if ret.cache_dir.is_some() && !batch {
+            return Err(usage("--cache-dir only applies to batch".into()));
+        }
+        if ret.cache_max_size.is_some() && ret.cache_dir.is_none() {
+            return Err(usage("--cache-max-size requires --cache-dir".into()));
+        }
+        if ret.quantized_append && subcommand.as_deref() != Some("concat") {
+            return Err(usage("--quantized-append only applies to concat".into()));
+        }
+        if ret.reference_pose && subcommand.as_deref() != Some("pack") {
+            return Err(usage("--reference-pose only applies to pack".into()));
+        }
+        if !ret.clip_attributes.is_empty() && subcommand.as_deref() != Some("pack") {
+            return Err(usage("--clip-attr only applies to pack".into()));
+        }
+        if (ret.thumbnail.is_some() || ret.export_thumbnails_dir.is_some()) && subcommand.as_deref() != Some("pack") {
+            return Err(usage("--thumbnail and --export-thumbnails only apply to pack".into()));
+        }
+        if (ret.given("--dedupe-clips") || ret.dedupe_tolerance.is_some()) && subcommand.as_deref() != Some("pack") {
+            return Err(usage("--dedupe-clips and --dedupe-tolerance only apply to pack".into()));
+        }
+        if ret.dedupe_tolerance.is_some_and(|tolerance| tolerance.is_nan() || tolerance < 0.0) {
+            return Err(usage("--dedupe-tolerance must not be negative".into()));
+        }
+        if ret.export_thumbnails_dir.is_some() && ret.thumbnail.is_none() {
+            ret.thumbnail = Some(thumbnail::Policy::Average);
+        }
+        if ret.reference_tolerance != 0.0 && !ret.reference_pose {
+            return Err(usage("--reference-tolerance requires --reference-pose".into()));
+        }
+        if ret.reference_tolerance.is_nan() || ret.reference_tolerance < 0.0 {
+            return Err(usage("--reference-tolerance must not be negative".into()));
+        }
+        if ret.diff_json_file_name.is_some() && subcommand.as_deref() != Some("diff-mocap") {
+            return Err(usage("--diff-json only applies to diff-mocap".into()));
+        }
+        if (!ret.bits_for.is_empty() || ret.reencode_source_file_name.is_some()) && subcommand.as_deref() != Some("reencode") {
+            return Err(usage("--bits-for and --source only apply to reencode".into()));
+        }
+        if ret.bits_for.is_empty() && subcommand.as_deref() == Some("reencode") {
+            return Err(usage("reencode requires --bits-for".into()));
+        }
+        if (ret.dump_values.is_some() || ret.dump_full) && subcommand.as_deref() != Some("dump") {
+            return Err(usage("--values and --full only apply to dump".into()));
+        }
+        if ret.sweep_csv_file_name.is_some() && !sweep_bits {
+            return Err(usage("--sweep-csv requires --sweep-bits".into()));
+        }
+        if subcommand.is_some() && (ret.calibration_file_name.is_some() || ret.vq_file_name.is_some() || ret.channel_map_file_name.is_some() || ret.joint_graph_file_name.is_some() || ret.export_markers_file_name.is_some() || ret.local_matrices_file_name.is_some() || ret.world_matrices_file_name.is_some() || ret.texture_file_name.is_some() || ret.deltas_bvh_file_name.is_some() || ret.fixed_point_file_name.is_some() || ret.curves_file_name.is_some()) {
+            return Err(usage("--export-* options only apply to single-file conversion".into()));
+        }
+        if ret.texture_pow2 && ret.texture_file_name.is_none() {
+            return Err(usage("--texture-pow2 requires --export-texture".into()));
+        }
+        if ret.fixed_point_precision.is_some() && ret.fixed_point_file_name.is_none() {
+            return Err(usage("--fixed-point-precision requires --export-fixed-point".into()));
+        }
+        if ret.fixed_point_precision.is_some_and(|precision| precision.is_nan() || precision <= 0.0) {
+            return Err(usage("--fixed-point-precision must be positive".into()));
+        }
+        if ret.curve_tolerance != curves::DEFAULT_TOLERANCE && ret.curves_file_name.is_none() {
+            return Err(usage("--curve-tolerance requires --export-curves".into()));
+        }
+        if !ret.curve_tolerance.is_finite() || ret.curve_tolerance <= 0.0 {
+            return Err(usage("--curve-tolerance must be positive".into()));
+        }
+        if ret.save_markers_file_name.is_some() && (subcommand.is_some() && subcommand.as_deref() != Some("decode") || sweep_bits) {
+            return Err(usage("--save-markers only applies to single-file conversion and decode".into()));
+        }
+        if ret.timestamps_file_name.is_some() && (subcommand.is_some() || sweep_bits) {
+            return Err(usage("--timestamps only applies to single-file conversion".into()));
+        }
+        if ret.timestamps_file_name.is_some() && (ret.fps.is_some() || ret.timewarp.is_some() || ret.loop_trim || ret.override_frame_time.is_some()) {
+            return Err(usage("--timestamps can't be combined with --fps, --timewarp, --loop-trim or --override-frame-time, which retime the clip".into()));
+        }
+        if ret.timestamps_file_name.is_some() && (ret.calibration_file_name.is_some() || ret.vq_file_name.is_some()) {
+            return Err(usage("--timestamps can't be combined with --calibration or --vq, whose files don't keep them".into()));
+        }
+        if ret.save_timestamps_file_name.is_some() && subcommand.as_deref() != Some("decode") {
+            return Err(usage("--save-timestamps only applies to decode".into()));
+        }
+
+        if ret.override_frame_time.is_some_and(|frame_time| frame_time.is_nan() || frame_time <= 0.0) {
+            return Err(usage("--override-frame-time must be positive".into()));
+        }
+        if ret.max_frames == Some(0) {
+            return Err(usage("--max-frames must be at least 1".into()));
+        }
+
+        if ret.vq_codebook_size != 64 && ret.vq_file_name.is_none() {
+            return Err(usage("--vq-codebook-size requires --vq".into()));
+        }
+        if ret.vq_codebook_size < 1 || ret.vq_codebook_size > vq::MAX_CODEBOOK_SIZE {
+            return Err(usage(format!("--vq-codebook-size must be in [1, {}], got {}", vq::MAX_CODEBOOK_SIZE, ret.vq_codebook_size)));
+        }
+
+        if ret.loop_tolerance.is_nan() || ret.loop_tolerance < 0.0 {
+            return Err(usage("--loop-tolerance must not be negative".into()));
+        }
+        if ret.unroll_loop && subcommand.as_deref() != Some("decode") {
+            return Err(usage("--unroll-loop only applies to decode".into()));
+        }
+        if ret.base_file_name.is_some() && subcommand.as_deref() != Some("decode") {
+            return Err(usage("--base only applies to decode".into()));
+        }
+        let is_match = subcommand.as_deref() == Some("match");
+        if is_match && ret.frame.is_none() {
+            return Err(usage("match requires --frame".into()));
+        }
+        if ret.frame.is_some() && !is_match && subcommand.as_deref() != Some("decode") {
+            return Err(usage("--frame only applies to match and decode".into()));
+        }
+        if ret.frame.is_some() && (ret.base_file_name.is_some() || ret.unroll_loop) {
+            return Err(usage("decode --frame can't be combined with --base or --unroll-loop".into()));
+        }
+        if ret.pose_frame.is_some() && (subcommand.is_some() && !batch && subcommand.as_deref() != Some("decode") && subcommand.as_deref() != Some("unpack") || sweep_bits) {
+            return Err(usage("--pose-frame only applies to single-file conversion, batch, decode and unpack".into()));
+        }
+        if ret.pose_frame.is_some() && (ret.frame.is_some() || ret.self_check || ret.save_timestamps_file_name.is_some()) {
+            return Err(usage("--pose-frame can't be combined with --frame, --self-check or --save-timestamps".into()));
+        }
+        if ret.conversion.seek_index() && (subcommand.is_some() && !batch || sweep_bits) {
+            return Err(usage("--seek-index only applies to single-file conversion and batch".into()));
+        }
+        if ret.conversion.sparse() && (subcommand.is_some() && !batch || sweep_bits) {
+            return Err(usage("--sparse only applies to single-file conversion and batch".into()));
+        }
+        if ret.conversion.bit_planes() && (subcommand.is_some() && !batch || sweep_bits) {
+            return Err(usage("--bit-planes only applies to single-file conversion and batch".into()));
+        }
+        if ret.self_check && (subcommand.is_some() && !batch || sweep_bits) {
+            return Err(usage("--self-check only applies to single-file conversion and batch".into()));
+        }
+        if ret.csv_by_channel_type && (subcommand.is_some() && !batch || sweep_bits) {
+            return Err(usage("--csv-group-by only applies to single-file conversion and batch".into()));
+        }
+        if ret.channel_variance && (subcommand.is_some() && !batch || sweep_bits) {
+            return Err(usage("--channel-variance only applies to single-file conversion and batch".into()));
+        }
+        if ret.channel_variance && ret.calibration_file_name.is_some() {
+            return Err(usage("--channel-variance can't be combined with --calibration, whose streamed file has no metadata".into()));
+        }
+        let stats = subcommand.as_deref() == Some("stats");
+        if ret.locomotion && (subcommand.is_some() && !batch && !stats && subcommand.as_deref() != Some("pack") || sweep_bits) {
+            return Err(usage("--locomotion only applies to single-file conversion, batch, pack and stats".into()));
+        }
+        if ret.locomotion && ret.calibration_file_name.is_some() {
+            return Err(usage("--locomotion can't be combined with --calibration, whose streamed file has no metadata".into()));
+        }
+        if ret.dof_summary && (subcommand.is_some() && !batch && !stats && subcommand.as_deref() != Some("pack") || sweep_bits) {
+            return Err(usage("--dof-summary only applies to single-file conversion, batch, pack and stats".into()));
+        }
+        if ret.delta_runs && !stats {
+            return Err(usage("--delta-runs only applies to stats".into()));
+        }
+        if stats && !ret.locomotion && !ret.dof_summary && !ret.delta_runs {
+            return Err(usage("stats requires --locomotion, --dof-summary or --delta-runs".into()));
+        }
+        if ret.conversion.max_delta_run().is_some() && (subcommand.is_some() && !batch && subcommand.as_deref() != Some("verify") || sweep_bits) {
+            return Err(usage("--max-delta-run only applies to single-file conversion, batch and verify".into()));
+        }
+        if ret.stats_json_file_name.is_some() && (subcommand.is_some() || sweep_bits) {
+            return Err(usage("--stats-json only applies to single-file conversion".into()));
+        }
+        if !ret.error_queries.is_empty() && (subcommand.is_some() || sweep_bits) {
+            return Err(usage("--error-at only applies to single-file conversion".into()));
+        }
+        if ret.skeleton_hash && (subcommand.is_some() && !batch || sweep_bits) {
+            return Err(usage("--skeleton-hash only applies to conversion and batch".into()));
+        }
+        if ret.outlier_threshold.is_some() && (subcommand.is_some() || sweep_bits) {
+            return Err(usage("--report-outliers only applies to single-file conversion".into()));
+        }
+        if ret.outlier_threshold.is_some_and(|threshold| threshold.is_nan() || threshold < 0.0) {
+            return Err(usage("--report-outliers must be at least 0".into()));
+        }
+        if ret.given("--outlier-units") && ret.outlier_threshold.is_none() {
+            return Err(usage("--outlier-units requires --report-outliers".into()));
+        }
+        if ret.num_correlations.is_some() && (subcommand.is_some() || sweep_bits) {
+            return Err(usage("--report-correlations only applies to single-file conversion".into()));
+        }
+        if ret.predict_channels && (subcommand.is_some() && !batch || sweep_bits) {
+            return Err(usage("--predict-channels only applies to conversion and batch".into()));
+        }
+        if ret.ranges_out_file_name.is_some() && (subcommand.is_some() || sweep_bits) {
+            return Err(usage("--ranges-out only applies to single-file conversion".into()));
+        }
+        if ret.ranges_in_file_name.is_some() && (subcommand.is_some() && !batch && subcommand.as_deref() != Some("pack") || sweep_bits) {
+            return Err(usage("--ranges-in only applies to conversion, batch and pack".into()));
+        }
+        if (ret.ranges_out_file_name.is_some() || ret.ranges_in_file_name.is_some()) && ret.calibration_file_name.is_some() {
+            return Err(usage("--ranges-out and --ranges-in can't be combined with --calibration, which quantizes with the calibration clip's ranges".into()));
+        }
+        if ret.predict_channels && ret.calibration_file_name.is_some() {
+            return Err(usage("--predict-channels can't be combined with --calibration, whose streamed file has no metadata".into()));
+        }
+        if !is_match && (ret.num_matches != 5 || ret.match_metric != Metric::Channels) {
+            return Err(usage("--matches and --position-metric only apply to match".into()));
+        }
+        if ret.num_matches == 0 {
+            return Err(usage("--matches must be at least 1".into()));
+        }
+        if subcommand.as_deref() != Some("transitions") && (ret.num_transitions != 5 || ret.transition_stride != 1 || ret.emit_blended_file_name.is_some()) {
+            return Err(usage("--top, --stride and --emit-blended only apply to transitions".into()));
+        }
+        if ret.num_transitions == 0 || ret.transition_stride == 0 {
+            return Err(usage("--top and --stride must be at least 1".into()));
+        }
+        if ret.fps.is_some_and(|fps| !fps.is_finite() || fps <= 0.0) {
+            return Err(usage("--fps must be positive".into()));
+        }
+        if ret.interpolation != Interpolation::Linear && ret.fps.is_none() && ret.repair_gaps.is_none() && ret.timestamps_file_name.is_none() && !matches!(subcommand.as_deref(), Some("decode") | Some("unpack") | Some("shell")) {
+            return Err(usage("--interpolation requires --fps, --repair-gaps or --timestamps, or decode, unpack or shell".into()));
+        }
+        if !ret.conversion.error_targets().is_empty() && (subcommand.is_some() && !batch || sweep_bits) {
+            return Err(usage("--rot-error and --trans-error only apply to single-file conversion and batch".into()));
+        }
+        if ret.add_bind_pose && subcommand.as_deref() != Some("decode") {
+            return Err(usage("--add-bind-pose only applies to decode".into()));
+        }
+        if ret.decode_threads != 1 && subcommand.as_deref() != Some("decode") {
+            return Err(usage("--threads-decode only applies to decode".into()));
+        }
+        if ret.decode_threads == 0 {
+            return Err(usage("--threads-decode must be at least 1".into()));
+        }
+
+        if ret.bake_ancestors && ret.root.is_none() {
+            return Err(usage("--bake-ancestors requires --root".into()));
+        }
+
+        if ret.strict && !ret.lossy {
+            let lossy_operations = ret.lossy_operations();
+            if !lossy_operations.is_empty() {
+                return Err(MocapError::Usage(format!("--strict: the following lossy operations require --lossy: {}", lossy_operations.join(", "))));
+            }
+        }
+
+        Ok(ret)
+    }
+
+    // Whether `option` was given on the command line.
+    pub fn given(&self, option: &str) -> bool {
+        self.given.iter().any(|given| given == option)
+    }
+
+
+    // The options a conversion's outputs depend on, as command line arguments; options at their
+    // defaults are left out, except for the encoding settings. Recorded in manifests and hashed
+    // into batch cache keys, so any new option affecting conversion output belongs here.
+    pub fn conversion_args(&self) -> Vec<String> {
+        let mut ret = Vec::new();
+        {
+            let mut push = |option: &str, value: Option<String>| {
+                ret.push(option.to_string());
+                ret.extend(value);
+            };
+            if let Some(ref hierarchy_file_name) = self.hierarchy_file_name {
+                push("--hierarchy", Some(hierarchy_file_name.clone()));
+            }
+            if let Some(fps) = self.curve_fps {
+                push("--curve-fps", Some(format!("{}", fps)));
+            }
+            for (frame, name) in self.markers.iter() {
+                push("--marker", Some(format!("{}:{}", frame, name)));
+            }
+            if let Some(ref markers_file_name) = self.markers_file_name {
+                push("--markers", Some(markers_file_name.clone()));
+            }
+            if let Some(ref timestamps_file_name) = self.timestamps_file_name {
+                push("--timestamps", Some(timestamps_file_name.clone()));
+                if self.interpolation == Interpolation::Cubic {
+                    push("--interpolation", Some("cubic".into()));
+                }
+            }
+            if let Some(ref detection) = self.repair_gaps {
+                push("--repair-gaps", Some(detection.spec()));
+                if self.interpolation == Interpolation::Cubic {
+                    push("--interpolation", Some("cubic".into()));
+                }
+            }
+            if let Some(frame_time) = self.override_frame_time {
+                push("--override-frame-time", Some(format!("{}", frame_time)));
+            }
+            if let Some(max_frames) = self.max_frames {
+                push("--max-frames", Some(format!("{}", max_frames)));
+            }
+            if self.loop_trim {
+                push("--loop-trim", None);
+                push("--loop-tolerance", Some(format!("{}", self.loop_tolerance)));
+            }
+            if let Some(ref curve) = self.timewarp {
+                push("--timewarp", Some(curve.spec()));
+            }
+            if let Some(fps) = self.fps {
+                push("--fps", Some(format!("{}", fps)));
+                if self.interpolation == Interpolation::Cubic {
+                    push("--interpolation", Some("cubic".into()));
+                }
+            }
+            if self.rational_fps {
+                push("--rational-fps", None);
+            }
+            if self.duplicate_names == DuplicateNames::Error {
+                push("--duplicate-names", Some("error".into()));
+            }
+            match self.frame_count_mismatch {
+                FrameCountMismatch::Error => (),
+                FrameCountMismatch::Rows => push("--frame-count-mismatch", Some("rows".into())),
+                FrameCountMismatch::Header => push("--frame-count-mismatch", Some("header".into())),
+            }
+            if let Some(ref root) = self.root {
+                push("--root", Some(root.clone()));
+            }
+            for adjustment in self.adjustments.iter() {
+                push("--adjust", Some(adjustment.spec()));
+            }
+            if self.bake_ancestors {
+                push("--bake-ancestors", None);
+            }
+            if self.snap_to_ground {
+                push("--snap-to-ground", None);
+            }
+            if let Some(filter) = self.conversion.smooth() {
+                push("--smooth", Some(smooth::spec(filter)));
+            }
+            if self.conversion.auto_smooth() {
+                push("--auto-smooth", None);
+            }
+            if let Some(up_axis) = self.up_axis {
+                push("--up-axis", Some(["x", "y", "z"][up_axis].into()));
+            }
+            if let Some(ref profile_file_name) = self.profile_file_name {
+                push("--profile", Some(profile_file_name.clone()));
+            }
+            push("--bits", Some(format!("{}", self.conversion.channel_quantization_bits())));
+            push("--translation-reference", Some(match self.conversion.translation_reference() {
+                TranslationReference::None => "none",
+                TranslationReference::Offset => "offset",
+                TranslationReference::Mean => "mean",
+            }.into()));
+            match self.conversion.bind_pose() {
+                None => (),
+                Some(BindPose::FirstFrame) => push("--bind-pose", Some("first-frame".into())),
+                Some(BindPose::Zero) => push("--bind-pose", Some("zero".into())),
+                Some(BindPose::File(ref file_name, 0)) => push("--bind-pose", Some(file_name.clone())),
+                Some(BindPose::File(ref file_name, frame)) => push("--bind-pose", Some(format!("{}@{}", file_name, frame))),
+            }
+            if self.conversion.root_motion() {
+                push("--root-motion", None);
+                push("--root-motion-anchors", Some(format!("{}", self.conversion.root_motion_anchors())));
+            }
+            match self.conversion.mask() {
+                None => (),
+                Some(Mask::Upper) => push("--mask", Some("upper".into())),
+                Some(Mask::Lower) => push("--mask", Some("lower".into())),
+                Some(Mask::File(ref file_name)) => push("--mask", Some(file_name.clone())),
+            }
+            if let Some(target) = self.conversion.error_targets().rotation {
+                push("--rot-error", Some(format!("{}", target)));
+            }
+            if let Some(target) = self.conversion.error_targets().translation {
+                push("--trans-error", Some(format!("{}", target)));
+            }
+            match self.conversion.rotation_anchor() {
+                RotationAnchor::None => (),
+                RotationAnchor::Zero => push("--rotation-anchor", Some("zero".into())),
+                RotationAnchor::Rest => push("--rotation-anchor", Some("rest".into())),
+            }
+            if let Some(ref calibration_file_name) = self.calibration_file_name {
+                push("--calibration", Some(calibration_file_name.clone()));
+            }
+            if let Some(ref ranges_file_name) = self.ranges_in_file_name {
+                push("--ranges-in", Some(ranges_file_name.clone()));
+            }
+            if self.conversion.seek_index() {
+                push("--seek-index", None);
+            }
+            if self.conversion.sparse() {
+                push("--sparse", None);
+            }
+            if self.conversion.bit_planes() {
+                push("--bit-planes", None);
+            }
+            if self.predict_channels {
+                push("--predict-channels", None);
+            }
+            if self.channel_variance {
+                push("--channel-variance", None);
+            }
+            if self.locomotion {
+                push("--locomotion", None);
+            }
+            if self.conversion.block_frames() != writer::DEFAULT_BLOCK_FRAMES {
+                push("--block-frames", Some(format!("{}", self.conversion.block_frames())));
+            }
+            if let Some(max_delta_run) = self.conversion.max_delta_run() {
+                push("--max-delta-run", Some(format!("{}", max_delta_run)));
+            }
+            if let Some(time_budget) = self.time_budget {
+                push("--time-budget", Some(format!("{}", time_budget)));
+            }
+            if self.vq_file_name.is_some() {
+                push("--vq-codebook-size", Some(format!("{}", self.vq_codebook_size)));
+            }
+            if self.crlf {
+                push("--crlf", None);
+            }
+            if let Some(frame) = self.pose_frame {
+                push("--pose-frame", Some(frame.spec()));
+            }
+            if self.csv_by_channel_type {
+                push("--csv-group-by", Some("channel-type".into()));
+            }
+        }
+        ret
+    }
+
+    // What bounds the periodic search of each clip written (see periodic.rs): the --time-budget,
+    // and `cancel`.
+    pub fn search_limits<'a>(&self, cancel: Option<&'a AtomicBool>) -> periodic::Limits<'a> {
+        periodic::Limits {
+            time_budget: self.time_budget.map(Duration::from_millis),
+            cancel: cancel,
+        }
+    }
+
+    // Operations enabled by these options that lose data beyond the default 8-bit encoding, as
+    // listed in the usage text. `--strict` refuses to run any of them without `--lossy`.
+    pub fn lossy_operations(&self) -> Vec<String> {
+        let mut ret = self.conversion.lossy_operations();
+        if self.loop_trim && self.loop_tolerance > 0.0 {
+            ret.push("loop trimming".into());
+        }
+        if self.vq_file_name.is_some() {
+            ret.push("vector quantization".into());
+        }
+        if self.fps.is_some() {
+            ret.push("resampling".into());
+        }
+        if self.rational_fps {
+            ret.push("frame time rounding".into());
+        }
+        if self.timewarp.is_some() {
+            ret.push("time warping".into());
+        }
+        if self.repair_gaps.is_some() {
+            ret.push("gap repair".into());
+        }
+        if self.timestamps_file_name.is_some() {
+            ret.push("resampling to uniform frame timing".into());
+        }
+        if self.max_frames.is_some() || self.frame_count_mismatch == FrameCountMismatch::Header {
+            ret.push("frame truncation".into());
+        }
+        if self.pose_frame.is_some() {
+            ret.push("single-frame pose output".into());
+        }
+        if self.root.is_some() {
+            ret.push("subtree selection".into());
+        }
+        if self.adjustments.iter().any(Adjustment::discards_values) {
+            ret.push("zeroing channels".into());
+        }
+        if self.snap_to_ground {
+            ret.push("ground snapping".into());
+        }
+        if self.calibration_file_name.is_some() || self.ranges_in_file_name.is_some() {
+            ret.push("clamping to given ranges".into());
+        }
+        if self.dedupe_clips == dedupe::Policy::Skip || (self.dedupe_clips == dedupe::Policy::Alias && self.dedupe_tolerance.is_some()) {
+            ret.push("clip deduplication".into());
+        }
+        ret
+    }
+}
+
+fn value(option: &str, value: Option<String>) -> Result<String, MocapError> {
+    value.ok_or_else(|| usage(format!("{} requires a value", option)))
+}
+
+fn parse_value<T: FromStr>(option: &str, value: Option<String>) -> Result<T, MocapError> {
+    let value = self::value(option, value)?;
+    value.parse().map_err(|_| usage(format!("invalid value for {}: {}", option, value)))
+}
+
+// `<clip>:<key>=<value>,...`. Values can't contain commas.
+fn parse_clip_attributes(option: &str, value: String) -> Result<(String, Vec<(String, String)>), MocapError> {
+    let invalid = || usage(format!("invalid value for {}: {}", option, value));
+    let colon = value.find(':').ok_or_else(invalid)?;
+    let (clip, attributes) = (&value[..colon], &value[colon + 1..]);
+    if clip.is_empty() {
+        return Err(invalid());
+    }
+    let attributes = attributes.split(',').map(|attribute| {
+        let equals = attribute.find('=').ok_or_else(invalid)?;
+        let (key, value) = (&attribute[..equals], &attribute[equals + 1..]);
+        if key.is_empty() {
+            return Err(invalid());
+        }
+        Ok((key.into(), value.into()))
+    }).collect::<Result<Vec<_>, _>>()?;
+    Ok((clip.into(), attributes))
+}
+
+// A byte count, optionally followed by K, M or G (powers of 1024).
+fn parse_size(value: &str) -> Option<u64> {
+    let (digits, multiplier) = match value.chars().last()?.to_ascii_uppercase() {
+        'K' => (&value[..value.len() - 1], 1 << 10),
+        'M' => (&value[..value.len() - 1], 1 << 20),
+        'G' => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+fn usage(message: String) -> MocapError {
+    MocapError::Usage(format!("{}\n\n{}", message, USAGE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every lossy operation, as the arguments enabling it
+    const LOSSY: &[&[&str]] = &[
+        &["--bits", "4"],
+        &["--rot-error", "0.5"],
+        &["--trans-error", "0.1"],
+        &["--loop-trim"],
+        &["--vq", "out.vq"],
+        &["--smooth", "moving-average:3"],
+        &["--auto-smooth"],
+        &["--fps", "60"],
+        &["--timewarp", "0=0,1=0.5"],
+        &["--repair-gaps", "flat=5"],
+        &["--root-motion"],
+        &["--timestamps", "times.txt"],
+        &["--max-frames", "10"],
+        &["--frame-count-mismatch", "header"],
+        &["--pose-frame"],
+        &["--root", "Spine"],
+        &["--mask", "upper"],
+        &["--calibration", "calibration.bvh"],
+        &["--ranges-in", "ranges.tsv"],
+        &["--rational-fps"],
+        &["--snap-to-ground"],
+        &["--adjust", "Spine:RotationZ*=0"],
+    ];
+
+    // Lossy operations only pack makes
+    const LOSSY_PACK: &[&[&str]] = &[
+        &["--dedupe-clips", "skip"],
+        &["--dedupe-clips", "alias", "--dedupe-tolerance", "0.5"],
+    ];
+
+    fn parse(args: &[&str]) -> Result<Options, MocapError> {
+        Options::parse(args.iter().chain(["in.bvh", "out.bvh", "out.csv", "out.raw"].iter()).map(|arg| arg.to_string()))
+    }
+
+    fn parse_pack(args: &[&str]) -> Result<Options, MocapError> {
+        Options::parse(["pack"].iter().chain(args.iter()).chain(["out.mcp", "a.bvh", "b.bvh"].iter()).map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn strict_refuses_every_lossy_operation() {
+        type Parse = fn(&[&str]) -> Result<Options, MocapError>;
+        for (args, parse) in LOSSY.iter().map(|args| (args, parse as Parse)).chain(LOSSY_PACK.iter().map(|args| (args, parse_pack as Parse))) {
+            assert!(parse(args).is_ok(), "{:?}", args);
+            match parse(&[&["--strict"], *args].concat()) {
+                Err(MocapError::Usage(message)) => assert!(message.starts_with("--strict: the following lossy operations require --lossy"), "{:?}: {}", args, message),
+                other => panic!("{:?}: {:?}", args, other.map(|_| ())),
+            }
+            assert!(parse(&[&["--strict", "--lossy"], *args].concat()).is_ok(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn strict_allows_lossless_options() {
+        for args in [&["--seek-index"][..], &["--sparse"], &["--bit-planes"], &["--predict-channels"], &["--translation-reference", "mean"], &["--loop-trim", "--loop-tolerance", "0"],
+                     &["--adjust", "Spine:RotationZ+=7"], &["--adjust", "Spine:RotationZ*=-1"]].iter() {
+            assert!(parse(&[&["--strict"], *args].concat()).is_ok(), "{:?}", args);
+        }
+        for args in [&["--dedupe-clips", "alias"][..], &["--dedupe-tolerance", "0.5"], &["--dedupe-clips", "warn", "--dedupe-tolerance", "0.5"]].iter() {
+            assert!(parse_pack(&[&["--strict"], *args].concat()).is_ok(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn usage_lists_every_lossy_operation() {
+        let section = &USAGE[USAGE.find("Lossy operations").unwrap()..];
+        for args in LOSSY.iter().chain(LOSSY_PACK.iter()) {
+            assert!(section.contains(args[0]), "{} isn't listed", args[0]);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use {max_level, Mocap};
+
+// Outlier frames, for finding capture glitches (a joint popping or teleporting for a frame) before
+// they're compressed in. With --report-outliers a conversion lists every frame where a channel
+// changes by more than a threshold since the frame before, largest first, as joint, channel,
+// frame and the change.
+//
+// The change is read from the encoded delta streams, so it's in quantization levels: the level
+// a frame decodes to minus the previous frame's, whatever the wrapping delta stored for it. With
+// --outlier-units physical the threshold and the changes are in degrees and the file's units
+// instead, each level being worth the channel's level spacing; at a low bit depth that's a
+// coarse measure. Lossless channels have no levels, so their exact values are compared, in
+// levels of the clip's bit depth over their range as for any other channel. Frame 0 has
+// nothing before it and is never an outlier.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Units {
+    Levels,
+    Physical,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outlier {
+    pub joint_name: String,
+    pub channel: &'static str,
+    pub frame: usize,
+    pub change: f64, // Signed, in `Units`
+}
+
+pub fn find(mocap: &Mocap, threshold: f64, units: Units) -> Vec<Outlier> {
+    let bits = mocap.channel_quantization_bits;
+    let mut ret = Vec::new();
+    for (descriptor, channel) in mocap.channel_map().into_iter().zip(mocap.channels()) {
+        let spacing = channel.value_of(1, bits) - channel.value_of(0, bits);
+        let changes = match channel.values {
+            Some(ref values) => values.windows(2).map(|pair| match units {
+                Units::Levels if spacing > 0.0 => (pair[1] - pair[0]) / spacing,
+                Units::Levels => 0.0,
+                Units::Physical => pair[1] - pair[0],
+            }).collect::<Vec<_>>(),
+            None => {
+                let mut level = channel.initial_level;
+                let mut levels = Vec::with_capacity(channel.deltas.len());
+                for delta in channel.deltas.iter() {
+                    level = (level as i8).wrapping_add(*delta) as u8;
+                    levels.push(level.min(max_level(bits)));
+                }
+                levels.windows(2).map(|pair| {
+                    let change = pair[1] as f64 - pair[0] as f64;
+                    match units {
+                        Units::Levels => change,
+                        Units::Physical => change * spacing,
+                    }
+                }).collect()
+            }
+        };
+        for (index, change) in changes.into_iter().enumerate().filter(|(_, change)| change.abs() > threshold) {
+            ret.push(Outlier {
+                joint_name: descriptor.joint_name.clone(),
+                channel: descriptor.channel_type.name(),
+                frame: index + 1,
+                change: change,
+            });
+        }
+    }
+    // Stable, so equal changes stay in channel order
+    ret.sort_by(|a, b| b.change.abs().total_cmp(&a.change.abs()));
+    ret
+}
@@ -0,0 +1,79 @@
+use bvh;
+
+use log;
+
+// Fixes for exporters that write wrong motion headers. Both return provenance metadata recording
+// the value they replaced, and warn about what changed.
+
+pub fn override_frame_time(bvh: &mut bvh::Bvh, frame_time: f64) -> Vec<(String, String)> {
+    log::warning(format!("overriding frame time {} with {}", bvh.motion.frame_time, frame_time));
+    let original = bvh.motion.frame_time;
+    bvh.motion.frame_time = frame_time;
+
+    vec![("source_frame_time".into(), format!("{}", original))]
+}
+
+// Keeps only frames 0..max_frames. Everything downstream (ranges, deltas) only ever sees the kept
+// frames, so this is exactly a trim.
+pub fn truncate(bvh: &mut bvh::Bvh, max_frames: u32) -> Vec<(String, String)> {
+    if max_frames >= bvh.motion.num_frames {
+        return Vec::new();
+    }
+
+    log::warning(format!("truncating {} frames to {}", bvh.motion.num_frames, max_frames));
+    let original = bvh.motion.num_frames;
+    bvh.motion.num_frames = max_frames;
+    bvh.motion.frames.truncate(max_frames as usize);
+
+    vec![("source_num_frames".into(), format!("{}", original))]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use depth::DEFAULT_MAX_DEPTH;
+    use log;
+    use raw;
+    use test_util;
+
+    const NUM_FRAMES: usize = 30;
+
+    #[test]
+    fn conversion_applies_and_records_the_overrides() {
+        let dir = test_util::temp_dir("overrides");
+        let file_names = ["in.bvh", "out.bvh", "out.csv", "out.raw"].iter().map(|name| dir.join(name)).collect::<Vec<_>>();
+        fs::write(&file_names[0], test_util::clip_text(NUM_FRAMES, test_util::sine)).unwrap();
+        let options = test_util::options(&["--override-frame-time", "0.0166667", "--max-frames", "20"]);
+
+        let (result, messages) = log::capture(|| ::convert(&file_names[0], &file_names[1], &file_names[2], &file_names[3], &options, None));
+        result.unwrap();
+        assert_eq!(log::diagnostics(&messages), vec![
+            "warning: overriding frame time 0.033333 with 0.0166667".to_string(),
+            "warning: truncating 30 frames to 20".to_string(),
+        ]);
+
+        let output = fs::read_to_string(&file_names[1]).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+        let motion = lines.iter().position(|line| *line == "MOTION").unwrap();
+        assert_eq!(lines[motion + 1], "Frames: 20");
+        // To the f32 precision the clip stores frame times in
+        let frame_time = lines[motion + 2].trim_start_matches("Frame Time: ").parse::<f64>().unwrap();
+        assert_eq!(frame_time as f32, 0.0166667);
+        assert_eq!(lines.len() - (motion + 3), 20);
+
+        let mocap = raw::read(&fs::read(&file_names[3]).unwrap(), DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(mocap.num_frames, 20);
+        assert!(mocap.metadata.contains(&("source_frame_time".into(), "0.033333".into())));
+        assert!(mocap.metadata.contains(&("source_num_frames".into(), "30".into())));
+    }
+
+    #[test]
+    fn truncating_to_the_clip_length_changes_nothing() {
+        let mut bvh = test_util::sine_clip(NUM_FRAMES);
+        let (metadata, messages) = log::capture(|| super::truncate(&mut bvh, NUM_FRAMES as u32));
+        assert!(metadata.is_empty());
+        assert!(messages.is_empty());
+        assert_eq!(bvh.motion.frames.len(), NUM_FRAMES);
+    }
+}
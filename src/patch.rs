@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use cache;
+use container;
+use error::MocapError;
+use log;
+use manifest;
+use raw::{self, Reader};
+
+// Patches between two versions of a compressed file (a container or a .raw file), for shipping an
+// updated animation to players who have the old one without sending all of it again. `mocap
+// make-patch <old> <new> <patch>` writes a patch holding only the parts of the new file that
+// differ from the old one, and `mocap apply-patch <old> <patch> <new>` makes the new file from the
+// old one and the patch, byte for byte.
+//
+// A file is split into segments: every run of a channel's deltas in each clip's blocks (see
+// `raw::read_clip_runs`), and the bytes between them, which hold the file and clip headers (with
+// the channels stored there, the metadata and the markers), the block frame counts and any seek
+// index. A segment of the new file is copied from the old one if the old one has the same segment,
+// the same run of the same clip, or the bytes before it, with the same bytes; otherwise the patch
+// stores it. Clips are matched by name, a .raw file's clip being unnamed. A clip whose skeleton or
+// frame count differs from the old one's is stored whole, with a warning, as a clip only the new
+// file has is.
+//
+// The format, all values little-endian:
+//
+//   magic       b"MCPT"
+//   version     u8, FORMAT_VERSION
+//   old hash    u128, `cache::hash` of the file the patch applies to
+//   new hash    u128, likewise of the file it makes
+//   operations  u32 count, then per operation, making the new file in order, a u8 kind:
+//                 0 = copy: a u64 offset into the old file and a u64 length
+//                 1 = data: a u32 length and that many bytes
+//
+// apply-patch refuses an old file whose hash differs from the patch's, and checks the file it
+// makes against the new hash before writing it.
+
+pub const MAGIC: &[u8; 4] = b"MCPT";
+pub const FORMAT_VERSION: u8 = 1;
+
+const OP_COPY: u8 = 0;
+const OP_DATA: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    Copy(usize, usize), // Offset into the old file, length
+    Data(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Gap(String, usize), // The bytes before a clip's nth run
+    Run(String, usize, usize), // A clip's run by block and channel
+    Tail, // The bytes after the last run
+}
+
+impl Key {
+    fn clip(&self) -> Option<&str> {
+        match *self {
+            Key::Gap(ref clip, _) | Key::Run(ref clip, _, _) => Some(clip),
+            Key::Tail => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Segment {
+    key: Key,
+    start: usize,
+    end: usize,
+}
+
+// A file's clips, as (name, skeleton hash, frame count), and its segments in file order.
+struct Split {
+    clips: Vec<(String, u128, u32)>,
+    segments: Vec<Segment>,
+}
+
+pub fn make(old_file_name: &Path, new_file_name: &Path, patch_file_name: &Path, max_depth: usize) -> Result<(), MocapError> {
+    let (old, new) = (fs::read(old_file_name)?, fs::read(new_file_name)?);
+    let (old_split, new_split) = (split(&old, max_depth)?, split(&new, max_depth)?);
+    if old.starts_with(raw::MAGIC) != new.starts_with(raw::MAGIC) {
+        log::warning(format!("{} and {} aren't both containers or both .raw files, storing the whole of {}", old_file_name.display(), new_file_name.display(), new_file_name.display()));
+    }
+
+    // The clips whose segments can be copied
+    let mut matching = Vec::new();
+    for &(ref name, skeleton_hash, num_frames) in new_split.clips.iter() {
+        match old_split.clips.iter().find(|clip| clip.0 == *name) {
+            Some(clip) if clip.1 == skeleton_hash && clip.2 == num_frames => matching.push(name.as_str()),
+            Some(_) => log::warning(format!("{}: skeleton or frame count differs from {}, storing it whole", clip_label(name, new_file_name), old_file_name.display())),
+            None => {}
+        }
+    }
+
+    let old_segments = old_split.segments.iter().map(|segment| (&segment.key, segment)).collect::<HashMap<_, _>>();
+    let mut ops = Vec::new();
+    let (mut num_runs, mut num_changed_runs) = (0, 0);
+    for segment in new_split.segments.iter() {
+        let bytes = &new[segment.start..segment.end];
+        let source = old_segments.get(&segment.key)
+            .filter(|old_segment| segment.key.clip().is_none_or(|clip| matching.contains(&clip)) && old[old_segment.start..old_segment.end] == *bytes);
+        if let Key::Run(..) = segment.key {
+            num_runs += 1;
+            num_changed_runs += source.is_none() as usize;
+        }
+        match source {
+            Some(old_segment) => push_copy(&mut ops, old_segment.start, bytes.len()),
+            None => push_data(&mut ops, bytes),
+        }
+    }
+    if patched(&old, &ops)? != new {
+        return Err(MocapError::Internal("the patch doesn't reproduce the new file".into()));
+    }
+
+    let mut patch = Vec::new();
+    patch.extend_from_slice(MAGIC);
+    patch.push(FORMAT_VERSION);
+    patch.extend_from_slice(&cache::hash(&old).to_le_bytes());
+    patch.extend_from_slice(&cache::hash(&new).to_le_bytes());
+    patch.extend_from_slice(&(ops.len() as u32).to_le_bytes());
+    for op in ops.iter() {
+        match *op {
+            Op::Copy(offset, len) => {
+                patch.push(OP_COPY);
+                patch.extend_from_slice(&(offset as u64).to_le_bytes());
+                patch.extend_from_slice(&(len as u64).to_le_bytes());
+            }
+            Op::Data(ref bytes) => {
+                patch.push(OP_DATA);
+                patch.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+                patch.extend_from_slice(bytes);
+            }
+        }
+    }
+    manifest::create(patch_file_name)?.write_all(&patch)?;
+
+    let stored = ops.iter().map(|op| if let Op::Data(ref bytes) = *op { bytes.len() } else { 0 }).sum::<usize>();
+    println!("{}: {} of {} channel runs changed, {} of {} bytes stored ({} byte patch)", patch_file_name.display(), num_changed_runs, num_runs, stored, new.len(), patch.len());
+    Ok(())
+}
+
+pub fn apply(old_file_name: &Path, patch_file_name: &Path, new_file_name: &Path) -> Result<(), MocapError> {
+    let old = fs::read(old_file_name)?;
+    let (old_hash, new_hash, ops) = read(&fs::read(patch_file_name)?).map_err(|e| match e {
+        MocapError::InvalidRaw(message) => MocapError::InvalidPatch(message),
+        e => e,
+    })?;
+    if cache::hash(&old) != old_hash {
+        return Err(MocapError::InvalidPatch(format!("{} isn't the file the patch was made from (its hash differs)", old_file_name.display())));
+    }
+    let new = patched(&old, &ops)?;
+    if cache::hash(&new) != new_hash {
+        return Err(MocapError::InvalidPatch("the patched file's hash doesn't match the one the patch records".into()));
+    }
+    manifest::create(new_file_name)?.write_all(&new)?;
+    Ok(())
+}
+
+// Splits a container or .raw file into segments.
+fn split(data: &[u8], max_depth: usize) -> Result<Split, MocapError> {
+    let (clips, runs) = if data.starts_with(raw::MAGIC) {
+        let mut reader = Reader::with_max_depth(data, max_depth);
+        raw::read_magic(&mut reader)?;
+        let (mocap, runs) = raw::read_clip_runs(&mut reader)?;
+        (vec![(String::new(), mocap)], vec![runs])
+    } else {
+        let (container, runs) = container::read_runs(data, max_depth)?;
+        (container.clips.into_iter().map(|clip| (clip.name, clip.mocap)).collect::<Vec<_>>(), runs)
+    };
+
+    let mut segments = Vec::new();
+    let mut position = 0;
+    for ((name, _), runs) in clips.iter().zip(runs.iter()) {
+        for (index, run) in runs.iter().enumerate() {
+            if run.offset > position {
+                segments.push(Segment {
+                    key: Key::Gap(name.clone(), index),
+                    start: position,
+                    end: run.offset,
+                });
+            }
+            segments.push(Segment {
+                key: Key::Run(name.clone(), run.block, run.channel),
+                start: run.offset,
+                end: run.offset + run.len,
+            });
+            position = run.offset + run.len;
+        }
+    }
+    segments.push(Segment {
+        key: Key::Tail,
+        start: position,
+        end: data.len(),
+    });
+
+    Ok(Split {
+        clips: clips.iter().map(|(name, mocap)| (name.clone(), mocap.skeleton_hash(), mocap.num_frames)).collect(),
+        segments: segments,
+    })
+}
+
+// The old file's hash, the new file's and the operations making it.
+fn read(data: &[u8]) -> Result<(u128, u128, Vec<Op>), MocapError> {
+    let mut reader = Reader::new(data);
+    if reader.bytes(4)? != MAGIC {
+        return Err(MocapError::InvalidPatch("not a mocap patch file".into()));
+    }
+    let version = reader.u8()?;
+    if version != FORMAT_VERSION {
+        return Err(MocapError::InvalidPatch(format!("unsupported patch format version {}", version)));
+    }
+    let mut hash = || -> Result<u128, MocapError> {
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(reader.bytes(16)?);
+        Ok(u128::from_le_bytes(bytes))
+    };
+    let (old_hash, new_hash) = (hash()?, hash()?);
+
+    let num_ops = reader.u32()?;
+    let mut ops = Vec::with_capacity(reader.capacity(num_ops as usize, 5));
+    for _ in 0..num_ops {
+        ops.push(match reader.u8()? {
+            OP_COPY => Op::Copy(reader.u64()? as usize, reader.u64()? as usize),
+            OP_DATA => {
+                let len = reader.u32()?;
+                Op::Data(reader.bytes(len as usize)?.to_vec())
+            }
+            kind => return Err(MocapError::InvalidPatch(format!("invalid operation {}", kind))),
+        });
+    }
+    reader.finish()?;
+    Ok((old_hash, new_hash, ops))
+}
+
+// `old` with `ops` applied.
+fn patched(old: &[u8], ops: &[Op]) -> Result<Vec<u8>, MocapError> {
+    let mut ret = Vec::new();
+    for op in ops.iter() {
+        match *op {
+            Op::Copy(offset, len) => {
+                let bytes = offset.checked_add(len).and_then(|end| old.get(offset..end))
+                    .ok_or_else(|| MocapError::InvalidPatch(format!("copies bytes {} to {} of a {} byte file", offset, offset.saturating_add(len), old.len())))?;
+                ret.extend_from_slice(bytes);
+            }
+            Op::Data(ref bytes) => ret.extend_from_slice(bytes),
+        }
+    }
+    Ok(ret)
+}
+
+// Appends a copy, extending the last operation if it copies the bytes just before.
+fn push_copy(ops: &mut Vec<Op>, offset: usize, len: usize) {
+    if len == 0 {
+        return;
+    }
+    if let Some(&mut Op::Copy(last_offset, ref mut last_len)) = ops.last_mut() {
+        if last_offset + *last_len == offset {
+            *last_len += len;
+            return;
+        }
+    }
+    ops.push(Op::Copy(offset, len));
+}
+
+// Appends data, to the last operation if that's data too.
+fn push_data(ops: &mut Vec<Op>, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    if let Some(&mut Op::Data(ref mut last)) = ops.last_mut() {
+        last.extend_from_slice(bytes);
+        return;
+    }
+    ops.push(Op::Data(bytes.to_vec()));
+}
+
+// How to name a clip in messages: a .raw file's by the file's name.
+fn clip_label(name: &str, file_name: &Path) -> String {
+    if name.is_empty() {
+        file_name.display().to_string()
+    } else {
+        format!("clip {}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bvh;
+
+    use build_mocap;
+    use container::{Clip, Container};
+    use conversion::ConversionSettings;
+    use depth::DEFAULT_MAX_DEPTH;
+    use periodic::Limits;
+    use test_util;
+
+    fn clip(name: &str, bvh: &bvh::Bvh) -> Clip {
+        Clip {
+            name: name.into(),
+            reference_pose: None,
+            attributes: Vec::new(),
+            thumbnail: None,
+            alias: None,
+            mocap: build_mocap(bvh, &ConversionSettings::default().settings()),
+        }
+    }
+
+    fn packed(clips: Vec<Clip>) -> Vec<u8> {
+        let mut ret = Vec::new();
+        container::write(&Container { reference_poses: Vec::new(), clips: clips }, Limits::default(), &mut ret).unwrap();
+        ret
+    }
+
+    // Makes a patch from `old` to `new` and applies it, returning the patch and the patched file.
+    fn round_trip(name: &str, old: &[u8], new: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let dir = test_util::temp_dir(name);
+        fs::write(dir.join("old"), old).unwrap();
+        fs::write(dir.join("new"), new).unwrap();
+        make(&dir.join("old"), &dir.join("new"), &dir.join("patch"), DEFAULT_MAX_DEPTH).unwrap();
+        apply(&dir.join("old"), &dir.join("patch"), &dir.join("patched")).unwrap();
+        (fs::read(dir.join("patch")).unwrap(), fs::read(dir.join("patched")).unwrap())
+    }
+
+    // The ranges of the new file the patch stores rather than copies.
+    fn stored_ranges(ops: &[Op]) -> Vec<(usize, usize)> {
+        let mut ret = Vec::new();
+        let mut position = 0;
+        for op in ops.iter() {
+            let len = match *op {
+                Op::Copy(_, len) => len,
+                Op::Data(ref bytes) => {
+                    ret.push((position, position + bytes.len()));
+                    bytes.len()
+                }
+            };
+            position += len;
+        }
+        ret
+    }
+
+    #[test]
+    fn stores_only_the_changed_runs() {
+        let walk = test_util::sine_clip(60);
+        let mut run = test_util::sine_clip(60);
+        // Inside the channel's range, so its header doesn't change
+        for frame in run.motion.frames[10..20].iter_mut() {
+            frame[7] = 0.0;
+        }
+        let old = packed(vec![clip("walk", &walk), clip("run", &test_util::sine_clip(60))]);
+        let new = packed(vec![clip("walk", &walk), clip("run", &run)]);
+        assert_ne!(old, new);
+
+        let (patch, patched) = round_trip("patch-targeted", &old, &new);
+        assert_eq!(patched, new);
+        let (old_hash, new_hash, ops) = read(&patch).unwrap();
+        assert_eq!((old_hash, new_hash), (cache::hash(&old), cache::hash(&new)));
+
+        // Every byte stored is in one of run's channel 7 runs
+        let stored = stored_ranges(&ops);
+        assert!(!stored.is_empty());
+        let changed = split(&new, DEFAULT_MAX_DEPTH).unwrap().segments.into_iter()
+            .filter(|segment| segment.key.clip() == Some("run") && matches!(segment.key, Key::Run(_, _, 7)))
+            .collect::<Vec<_>>();
+        for &(start, end) in stored.iter() {
+            assert!(changed.iter().any(|segment| segment.start <= start && end <= segment.end), "bytes {} to {} aren't in a changed run", start, end);
+        }
+        assert!(patch.len() < new.len() / 4, "{} byte patch for a {} byte file", patch.len(), new.len());
+    }
+
+    #[test]
+    fn a_patch_between_identical_files_stores_nothing() {
+        let mut data = Vec::new();
+        raw::write(&build_mocap(&test_util::sine_clip(30), &ConversionSettings::default().settings()), Limits::default(), &mut data).unwrap();
+        let (patch, patched) = round_trip("patch-no-op", &data, &data);
+        assert_eq!(patched, data);
+        let (_, _, ops) = read(&patch).unwrap();
+        assert_eq!(ops, vec![Op::Copy(0, data.len())]);
+    }
+
+    #[test]
+    fn a_clip_with_another_frame_count_is_stored_whole() {
+        let old = packed(vec![clip("walk", &test_util::sine_clip(30))]);
+        let new = packed(vec![clip("walk", &test_util::sine_clip(31))]);
+        let ((patch, patched), messages) = log::capture(|| round_trip("patch-frame-count", &old, &new));
+        assert_eq!(patched, new);
+        assert!(log::diagnostics(&messages).iter().any(|message| message.contains("clip walk: skeleton or frame count differs")), "{:?}", messages);
+
+        // No run is copied
+        let (_, _, ops) = read(&patch).unwrap();
+        let runs = split(&new, DEFAULT_MAX_DEPTH).unwrap().segments.into_iter().filter(|segment| matches!(segment.key, Key::Run(..))).collect::<Vec<_>>();
+        let stored = stored_ranges(&ops);
+        for run in runs.iter() {
+            assert!(stored.iter().any(|&(start, end)| start <= run.start && run.end <= end));
+        }
+    }
+
+    #[test]
+    fn applying_to_another_file_is_refused() {
+        let dir = test_util::temp_dir("patch-hash");
+        let old = packed(vec![clip("walk", &test_util::sine_clip(30))]);
+        let new = packed(vec![clip("walk", &test_util::sine_clip(40))]);
+        fs::write(dir.join("old"), &old).unwrap();
+        fs::write(dir.join("new"), &new).unwrap();
+        fs::write(dir.join("other"), packed(vec![clip("walk", &test_util::sine_clip(35))])).unwrap();
+        make(&dir.join("old"), &dir.join("new"), &dir.join("patch"), DEFAULT_MAX_DEPTH).unwrap();
+
+        match apply(&dir.join("other"), &dir.join("patch"), &dir.join("patched")) {
+            Err(MocapError::InvalidPatch(message)) => assert!(message.contains("isn't the file the patch was made from"), "{}", message),
+            other => panic!("{:?}", other),
+        }
+        assert!(!dir.join("patched").exists());
+
+        // A patch whose operations don't make the recorded file
+        let mut patch = fs::read(dir.join("patch")).unwrap();
+        let new_hash = (cache::hash(&new) ^ 1).to_le_bytes();
+        patch[21..37].copy_from_slice(&new_hash);
+        fs::write(dir.join("wrong"), &patch).unwrap();
+        match apply(&dir.join("old"), &dir.join("wrong"), &dir.join("patched")) {
+            Err(MocapError::InvalidPatch(message)) => assert!(message.contains("hash doesn't match"), "{}", message),
+            other => panic!("{:?}", other),
+        }
+        assert!(!dir.join("patched").exists());
+    }
+
+    #[test]
+    fn malformed_patches_are_rejected() {
+        let old = packed(vec![clip("walk", &test_util::sine_clip(30))]);
+        let new = packed(vec![clip("walk", &test_util::sine_clip(40))]);
+        let (patch, _) = round_trip("patch-malformed", &old, &new);
+        assert!(read(&patch).is_ok());
+        assert!(read(&patch[..patch.len() - 1]).is_err());
+        assert!(read(b"MCPX").is_err());
+
+        let mut version = patch.clone();
+        version[4] = 99;
+        assert!(read(&version).is_err());
+        let mut kind = patch.clone();
+        kind[41] = 7;
+        assert!(read(&kind).is_err());
+
+        // A copy past the end of the old file
+        assert!(patched(&old, &[Op::Copy(old.len() - 1, 2)]).is_err());
+        assert!(patched(&old, &[Op::Copy(usize::MAX, 2)]).is_err());
+    }
+}
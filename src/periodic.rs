@@ -0,0 +1,272 @@
+use std::cell::Cell;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+
+use bitpack;
+use cancel;
+use Channel;
+
+// Periodic channels. A cycle (a walk, a run) repeats, so a channel of one can be stored as its
+// levels over a single period, repeated up to the clip length, plus corrections for the frames
+// where the repeat isn't exact: frame i decodes to its correction if it has one, and otherwise to
+// the level at i % period. Corrections are exact levels, so this is lossless. Constant channels
+// are the degenerate case, a period of one frame.
+//
+// `raw::write_clip` stores a channel this way only when it comes out smaller than the packed
+// deltas it would otherwise take in the delta blocks, so clips that don't repeat are unaffected.
+//
+// Looking for a period is by far the slowest part of writing a clip. With --time-budget the
+// writer only searches as long as the budget allows (per clip, checked between channels); the
+// channels after that only get the cheap check for being constant, falling back to deltas
+// otherwise. Which channels that is depends on how fast the machine is, so a budgeted file is
+// the same size or larger than an unbudgeted one, and may differ between runs. Tools describing
+// a file's encodings (dump, diff-mocap) assume the full search. `Search` does the budgeting and
+// counts, per thread, the channels searched and the ones that fell back, for the conversion to
+// report.
+
+// Periods are looked for in at most this many frames from the start of the clip, and can be at
+// most half of that, to keep the autocorrelation cheap on long clips
+const WINDOW: usize = 2048;
+
+// Lags whose autocorrelation is at least this fraction of the best are taken as candidate periods,
+// so a period isn't mistaken for the multiple of it that happens to correlate marginally better
+const PEAK_FRACTION: f64 = 0.9;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Periodic {
+    pub levels: Vec<u8>, // One period
+    pub corrections: Vec<(u32, u8)>, // Frame and level, by increasing frame
+}
+
+impl Periodic {
+    // Bytes in the .raw file, past the storage flag. A constant channel is stored as just its
+    // level.
+    pub fn encoded_size(&self) -> usize {
+        if self.levels.len() == 1 && self.corrections.is_empty() {
+            return 1;
+        }
+        4 + self.levels.len() + 4 + 5 * self.corrections.len()
+    }
+
+    // The levels of `num_frames` frames.
+    pub fn expand(&self, num_frames: usize) -> Vec<u8> {
+        let mut ret = (0..num_frames).map(|index| self.levels[index % self.levels.len()]).collect::<Vec<_>>();
+        for (frame, level) in self.corrections.iter() {
+            ret[*frame as usize] = *level;
+        }
+        ret
+    }
+
+    // The deltas of `num_frames` frames, as a channel starting at `initial_level` stores them.
+    pub fn deltas(&self, num_frames: usize, initial_level: u8) -> Vec<i8> {
+        let mut previous_level = initial_level;
+        self.expand(num_frames).into_iter().map(|level| {
+            let delta = (level as i8).wrapping_sub(previous_level as i8);
+            previous_level = level;
+            delta
+        }).collect()
+    }
+}
+
+thread_local! {
+    static SEARCH_COUNTS: Cell<SearchCounts> = const { Cell::new(SearchCounts { searched: 0, fell_back: 0 }) };
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SearchCounts {
+    pub searched: usize, // Channels given the full search
+    pub fell_back: usize, // Channels only checked for being constant, the budget being spent
+}
+
+// What bounds the search for each clip written: the --time-budget, None searching every channel,
+// and the run's cancel token (see cancel.rs).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits<'a> {
+    pub time_budget: Option<Duration>,
+    pub cancel: Option<&'a AtomicBool>,
+}
+
+// Returns the channels searched on this thread since the last call, and resets the counts.
+pub fn take_search_counts() -> SearchCounts {
+    SEARCH_COUNTS.with(|counts| counts.replace(SearchCounts::default()))
+}
+
+// Encodes a clip's channels one after another within the time budget, which starts with the
+// search, until `cancel` is set.
+pub struct Search<'a> {
+    deadline: Option<Instant>,
+    cancel: Option<&'a AtomicBool>,
+}
+
+impl<'a> Search<'a> {
+    pub fn start(limits: Limits<'a>) -> Search<'a> {
+        Search {
+            deadline: limits.time_budget.and_then(|budget| Instant::now().checked_add(budget)),
+            cancel: limits.cancel,
+        }
+    }
+
+    // `encode`, or once the budget is spent, a constant encoding only.
+    pub fn encode(&self, channel: &Channel, bits: u8) -> Option<Periodic> {
+        // A cancelled conversion only needs the clip finished quickly (see cancel.rs)
+        let within_budget = self.deadline.is_none_or(|deadline| Instant::now() < deadline) && !cancel::is_cancelled(self.cancel);
+        SEARCH_COUNTS.with(|counts| {
+            let mut updated = counts.get();
+            if within_budget {
+                updated.searched += 1;
+            } else {
+                updated.fell_back += 1;
+            }
+            counts.set(updated);
+        });
+        let levels = levels(channel);
+        encode_levels(&levels, channel.bits(bits), within_budget)
+    }
+}
+
+// A periodic encoding of `channel`, if one is smaller than its deltas packed at its bit depth in a
+// clip of `bits` bits.
+pub fn encode(channel: &Channel, bits: u8) -> Option<Periodic> {
+    encode_levels(&levels(channel), channel.bits(bits), true)
+}
+
+// `encode` on the channel's levels, looking for periods only if `search`.
+fn encode_levels(levels: &[u8], bits: u8, search: bool) -> Option<Periodic> {
+    let packed_len = bitpack::packed_len(levels.len(), bits);
+    let mut candidates = vec![1];
+    if search {
+        candidates.extend(estimate_period(levels));
+    }
+    candidates.into_iter()
+        .map(|period| encode_with_period(levels, period))
+        .filter(|periodic| periodic.encoded_size() < packed_len)
+        .min_by_key(|periodic| periodic.encoded_size())
+}
+
+// The channel's level in every frame.
+pub fn levels(channel: &Channel) -> Vec<u8> {
+    let mut previous_level = channel.initial_level;
+    channel.deltas.iter().map(|delta| {
+        previous_level = (previous_level as i8).wrapping_add(*delta) as u8;
+        previous_level
+    }).collect()
+}
+
+// The dominant period of `levels`: the shortest lag whose autocorrelation (over the first WINDOW
+// frames) is a local peak within PEAK_FRACTION of the best one. None for a constant or too short
+// channel, or one without a peak.
+pub fn estimate_period(levels: &[u8]) -> Option<usize> {
+    let window = &levels[..levels.len().min(WINDOW)];
+    let mean = window.iter().map(|level| *level as f64).sum::<f64>() / (window.len().max(1) as f64);
+    let centered = window.iter().map(|level| *level as f64 - mean).collect::<Vec<_>>();
+    let variance = centered.iter().map(|x| x * x).sum::<f64>();
+    if variance == 0.0 {
+        return None;
+    }
+
+    let max_lag = window.len() / 2;
+    let correlations = (0..max_lag + 2).map(|lag| if lag < window.len() {
+        let overlap = window.len() - lag;
+        centered[..overlap].iter().zip(centered[lag..].iter()).map(|(a, b)| a * b).sum::<f64>() / variance * (window.len() as f64) / (overlap as f64)
+    } else {
+        0.0
+    }).collect::<Vec<_>>();
+
+    let is_peak = |lag: usize| correlations[lag] > correlations[lag - 1] && correlations[lag] >= correlations[lag + 1];
+    let best = (2..=max_lag).filter(|&lag| is_peak(lag)).map(|lag| correlations[lag]).fold(f64::NEG_INFINITY, f64::max);
+    if best <= 0.0 {
+        return None;
+    }
+    (2..=max_lag).find(|&lag| is_peak(lag) && correlations[lag] >= best * PEAK_FRACTION)
+}
+
+fn encode_with_period(levels: &[u8], period: usize) -> Periodic {
+    let period = period.min(levels.len());
+    Periodic {
+        levels: levels[..period].to_vec(),
+        corrections: levels.iter().enumerate().skip(period)
+            .filter(|&(index, level)| *level != levels[index % period])
+            .map(|(index, level)| (index as u32, *level))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+    use conversion::ConversionSettings;
+    use depth::DEFAULT_MAX_DEPTH;
+    use raw;
+    use test_util;
+    use {build_bvh, build_mocap};
+
+    const PERIOD: usize = 24;
+    const NUM_FRAMES: usize = 10 * PERIOD;
+
+    fn cycle(frame: usize) -> u8 {
+        (((frame % PERIOD) as f64 / PERIOD as f64 * std::f64::consts::TAU).sin() * 100.0 + 128.0) as u8
+    }
+
+    #[test]
+    fn exact_cycle_is_one_period() {
+        let levels = (0..NUM_FRAMES).map(cycle).collect::<Vec<_>>();
+        assert_eq!(estimate_period(&levels), Some(PERIOD));
+        let periodic = encode_levels(&levels, 8, true).unwrap();
+        assert_eq!(periodic.levels.len(), PERIOD);
+        assert!(periodic.corrections.is_empty());
+        assert_eq!(periodic.expand(NUM_FRAMES), levels);
+        assert!(periodic.encoded_size() < bitpack::packed_len(NUM_FRAMES, 8) / 4);
+    }
+
+    #[test]
+    fn noisy_cycle_gets_corrections() {
+        let mut levels = (0..NUM_FRAMES).map(cycle).collect::<Vec<_>>();
+        for frame in [30, 31, 100, 200].iter() {
+            levels[*frame] = levels[*frame].wrapping_add(3);
+        }
+        assert_eq!(estimate_period(&levels), Some(PERIOD));
+        let periodic = encode_levels(&levels, 8, true).unwrap();
+        assert_eq!(periodic.corrections.len(), 4);
+        assert_eq!(periodic.expand(NUM_FRAMES), levels);
+    }
+
+    #[test]
+    fn channels_that_dont_repeat_keep_their_deltas() {
+        let levels = (0..NUM_FRAMES).map(|frame| ((frame * frame * 7919) % 251) as u8).collect::<Vec<_>>();
+        assert_eq!(encode_levels(&levels, 8, true), None);
+        // A constant channel is the one-frame period
+        assert_eq!(encode_levels(&[9; NUM_FRAMES], 8, false), Some(Periodic { levels: vec![9], corrections: Vec::new() }));
+    }
+
+    #[test]
+    fn periodic_clip_round_trips_smaller() {
+        let bvh = test_util::parse(&test_util::clip_text(NUM_FRAMES, |frame, channel| test_util::sine(frame % PERIOD, channel)));
+        let mocap = build_mocap(&bvh, &ConversionSettings::default().settings());
+        let mut data = Vec::new();
+        raw::write(&mocap, Limits::default(), &mut data).unwrap();
+        assert_eq!(build_bvh(&raw::read(&data, DEFAULT_MAX_DEPTH).unwrap()).motion.frames, build_bvh(&mocap).motion.frames);
+        assert!(data.len() < test_util::NUM_CHANNELS * NUM_FRAMES / 3, "{} bytes", data.len());
+
+        // A cancelled search only checks for constant channels
+        let cancelled = AtomicBool::new(true);
+        let search = Search::start(Limits { time_budget: None, cancel: Some(&cancelled) });
+        assert!(mocap.channels().iter().all(|channel| search.encode(channel, 8).is_none()));
+    }
+
+    // Each search has its own budget, so a spent one doesn't hold back another
+    #[test]
+    fn a_spent_budget_only_checks_for_constant_channels() {
+        let bvh = test_util::parse(&test_util::clip_text(NUM_FRAMES, |frame, channel| test_util::sine(frame % PERIOD, channel)));
+        let mocap = build_mocap(&bvh, &ConversionSettings::default().settings());
+        take_search_counts();
+        let spent = Search::start(Limits { time_budget: Some(Duration::from_secs(0)), cancel: None });
+        let unlimited = Search::start(Limits::default());
+        for channel in mocap.channels() {
+            assert_eq!(spent.encode(channel, 8), None);
+            assert!(unlimited.encode(channel, 8).is_some());
+        }
+        assert_eq!(take_search_counts(), SearchCounts { searched: test_util::NUM_CHANNELS, fell_back: test_util::NUM_CHANNELS });
+    }
+}
@@ -0,0 +1,158 @@
+use bvh;
+
+use fk;
+use rotation_channels;
+
+// Pose distances, for finding where one clip could transition into another (`mocap match`).
+//
+// The channel metric is a weighted Euclidean distance over channel values, rotations differing
+// the short way round (so 179 and -179 degrees are 2 apart). The default weights ignore the root's
+// translation, since a transition moves the clip to where the other one is anyway, and weigh every
+// other channel by 1 / (1 + depth): a joint turning near the root moves everything below it, so
+// its angle matters more than a finger's.
+//
+// The position metric instead runs forward kinematics and takes the RMS distance between
+// corresponding joints, relative to the root's position. It's slower, but isn't fooled by
+// different rotations giving the same pose.
+
+// One frame of channel values, in flat channel order
+pub type Pose = [f64];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    Channels,
+    Positions,
+}
+
+// Per-channel weights for the channel metric, in flat channel order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Weights {
+    pub channels: Vec<f64>,
+    rotations: Vec<bool>,
+}
+
+impl Weights {
+    // The default scheme described above.
+    pub fn new(root: &bvh::Joint) -> Weights {
+        let mut channels = Vec::new();
+        push_weights(root, 0, &mut channels);
+        Weights {
+            channels: channels,
+            rotations: rotation_channels(root),
+        }
+    }
+}
+
+fn push_weights(joint: &bvh::Joint, depth: usize, weights: &mut Vec<f64>) {
+    let weight = 1.0 / (1.0 + depth as f64);
+    weights.extend(joint.channels.iter().map(|channel| match *channel {
+        bvh::Channel::XPosition | bvh::Channel::YPosition | bvh::Channel::ZPosition if depth == 0 => 0.0,
+        _ => weight,
+    }));
+    if let bvh::JointChildren::Joints(ref joints) = joint.children {
+        for child in joints.iter() {
+            push_weights(child, depth + 1, weights);
+        }
+    }
+}
+
+pub fn pose_distance(a: &Pose, b: &Pose, weights: &Weights) -> f64 {
+    let sum = a.iter().zip(b.iter()).zip(weights.channels.iter().zip(weights.rotations.iter())).map(|((a, b), (weight, rotation))| {
+        let difference = if *rotation { b - a - 360.0 * ((b - a + 180.0) / 360.0).floor() } else { b - a };
+        weight * difference * difference
+    }).sum::<f64>();
+    // + 0.0 turns the -0.0 an empty sum gives, for a skeleton without channels, into 0.0
+    sum.sqrt() + 0.0
+}
+
+pub fn position_distance(root: &bvh::Joint, a: &Pose, b: &Pose) -> f64 {
+    let (a, b) = (relative_positions(root, a), relative_positions(root, b));
+    let sum = a.iter().zip(b.iter()).map(|(a, b)| (a.0 - b.0).powi(2) + (a.1 - b.1).powi(2) + (a.2 - b.2).powi(2)).sum::<f64>();
+    (sum / (a.len() as f64)).sqrt()
+}
+
+fn relative_positions(root: &bvh::Joint, pose: &Pose) -> Vec<(f64, f64, f64)> {
+    let positions = fk::world_transforms(root, pose).iter().map(|transform| transform.position()).collect::<Vec<_>>();
+    let origin = positions[0];
+    positions.iter().map(|position| (position.0 - origin.0, position.1 - origin.1, position.2 - origin.2)).collect()
+}
+
+// The `count` frames of `bvh` nearest to `query` (a pose of the same skeleton), nearest first, as
+// frame index and distance.
+pub fn find_nearest_poses(bvh: &bvh::Bvh, query: &Pose, metric: Metric, count: usize) -> Vec<(usize, f64)> {
+    let root = &bvh.hierarchy.root;
+    let weights = Weights::new(root);
+    let mut ret = bvh.motion.frames.iter().enumerate().map(|(index, frame)| (index, match metric {
+        Metric::Channels => pose_distance(query, frame, &weights),
+        Metric::Positions => position_distance(root, query, frame),
+    })).collect::<Vec<_>>();
+    // Stable, so ties go to the earlier frame
+    ret.sort_by(|a, b| a.1.total_cmp(&b.1));
+    ret.truncate(count);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_util::{self, CHAIN};
+
+    #[test]
+    fn a_clip_searched_for_its_own_frames_finds_them() {
+        let bvh = test_util::sine_clip(40);
+        for metric in [Metric::Channels, Metric::Positions].iter().cloned() {
+            for (index, frame) in bvh.motion.frames.iter().enumerate() {
+                let nearest = find_nearest_poses(&bvh, frame, metric, 3);
+                assert_eq!(nearest.len(), 3);
+                assert_eq!(nearest[0].0, index, "{:?}", metric);
+                assert!(nearest[0].1.abs() < 1e-9, "{:?} frame {}: {}", metric, index, nearest[0].1);
+                assert!(nearest[1].1 > nearest[0].1 && nearest[2].1 >= nearest[1].1);
+            }
+        }
+    }
+
+    #[test]
+    fn channel_distances_are_weighted_and_wrap() {
+        let bvh = test_util::parse(&test_util::clip_text(1, |_, _| 0.0));
+        let weights = Weights::new(&bvh.hierarchy.root);
+        // The root's translation doesn't count, and Spine (one below the root) weighs 1/2
+        assert_eq!(&weights.channels[..9], &[0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.5, 0.5, 0.5]);
+
+        let mut a = vec![0.0; test_util::NUM_CHANNELS];
+        let mut b = a.clone();
+        a[0] = 100.0;
+        assert_eq!(pose_distance(&a, &b, &weights), 0.0);
+        a[3] = 179.0;
+        b[3] = -179.0;
+        assert!((pose_distance(&a, &b, &weights) - 2.0).abs() < 1e-9);
+        a[6] = 4.0;
+        assert!((pose_distance(&a, &b, &weights) - (4.0f64 + 8.0).sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn the_position_metric_ranks_poses_by_where_the_joints_end_up() {
+        // From the rest pose: the spine twisted 90 degrees about its own length, which moves no
+        // joint, and bent 30 degrees, which does. Both with the root somewhere else.
+        let poses = [
+            [50.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 30.0, 0.0],
+            [0.0, 0.0, 50.0, 0.0, 0.0, 0.0, 0.0, 0.0, 90.0],
+        ];
+        let bvh = test_util::parse(&test_util::motion_text(CHAIN, 9, 2, |frame, channel| poses[frame][channel]));
+        let rest = [0.0; 9];
+
+        let nearest = find_nearest_poses(&bvh, &rest, Metric::Positions, 2);
+        assert_eq!(nearest[0].0, 1);
+        assert!(nearest[0].1 < 1e-9, "{}", nearest[0].1);
+        // Of the three joints, the root and spine stay put and the head, 5 along the spine, moves
+        // 2 * 5 * sin(15 degrees)
+        let expected = 10.0 * 15.0f64.to_radians().sin() / 3.0f64.sqrt();
+        assert_eq!(nearest[1].0, 0);
+        assert!((nearest[1].1 - expected).abs() < 1e-9, "{} vs {}", nearest[1].1, expected);
+
+        // Going by the channels, the twist is three times the bend
+        let nearest = find_nearest_poses(&bvh, &rest, Metric::Channels, 2);
+        assert_eq!(nearest.iter().map(|nearest| nearest.0).collect::<Vec<_>>(), vec![0, 1]);
+        assert!((nearest[1].1 / nearest[0].1 - 3.0).abs() < 1e-9);
+    }
+}
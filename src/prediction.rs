@@ -0,0 +1,403 @@
+use dump;
+use error::MocapError;
+use view::ChannelData;
+use {max_level, num_levels, Channel, Mocap};
+
+// Channel correlation and joint-pair prediction. Symmetric limbs move together (in a walk the
+// left and right arm swings are near mirror images), so one channel's levels often follow
+// another's closely. With --report-correlations a conversion lists the most strongly correlated
+// pairs of channels: the Pearson correlation between their quantization levels over the clip,
+// strongest (nearest -1 or 1) first. Lossless channels have no levels and constant ones no
+// correlation, so neither is paired.
+//
+// With --predict-channels the .raw file stores a channel as its residual against a linear
+// prediction from its partner's levels instead:
+//
+//   prediction = clamp(round(slope * partner level + intercept), 0, max level)
+//   stored level = (level - prediction) mod 2^bits
+//
+// the slope and intercept being the least-squares fit over the clip, rounded to 4 decimals.
+// Perfectly correlated channels leave a constant residual, which the .raw file stores as a single
+// level (see raw.rs), and closely correlated ones a nearly constant or periodic one. In the delta
+// blocks every delta takes the clip's bits however small it is, so there a residual only pays off
+// once the file is entropy coded, which isn't counted. Pairs are taken strongest first among those
+// correlated by at least MIN_CORRELATION, in whichever direction saves more, and a channel is only
+// stored this way when the .raw file comes out smaller: the bytes `raw::write` stores the residual
+// in plus its prediction's record have to be fewer than the channel would take as usual. A channel
+// can be the partner of any number of others, and a partner can itself be predicted, as long as
+// following partners never leads back to the channel (pairs that would are skipped).
+//
+// The predictions are recorded in the metadata, as the channel's and its partner's flat indices
+// (see --export-channel-map), the slope and the intercept of each, separated by spaces. Reading
+// the .raw file (`raw::read`, or a `MocapView`) restores the channels, each after its partner, and
+// drops the record, so everything past that sees the levels as quantized. Re-encoding a clip
+// stores them as usual again. The initial levels aren't predicted, and the quantization parameters
+// are kept, so restoring is exact.
+
+// Metadata key recording the predictions
+pub const KEY: &str = "predicted_channels";
+
+// Pairs correlated less strongly than this aren't tried for prediction
+const MIN_CORRELATION: f64 = 0.9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Correlation {
+    pub channels: (usize, usize), // Flat indices, the first the lower
+    pub correlation: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Prediction {
+    pub channel: usize, // Flat indices
+    pub partner: usize,
+    pub slope: f64,
+    pub intercept: f64,
+}
+
+impl Prediction {
+    fn predict(&self, partner_level: u8, bits: u8) -> u16 {
+        (self.slope * partner_level as f64 + self.intercept).round().clamp(0.0, max_level(bits) as f64) as u16
+    }
+
+    // The levels stored for a channel with `levels`, its partner having `partner_levels`.
+    fn residual(&self, levels: &[u8], partner_levels: &[u8], bits: u8) -> Vec<u8> {
+        let num_levels = num_levels(bits).unwrap();
+        levels.iter().zip(partner_levels.iter()).map(|(level, partner_level)| ((*level as u16 + num_levels - self.predict(*partner_level, bits)) % num_levels) as u8).collect()
+    }
+
+    // The inverse of `residual`.
+    fn restore(&self, residual: &[u8], partner_levels: &[u8], bits: u8) -> Vec<u8> {
+        let num_levels = num_levels(bits).unwrap();
+        residual.iter().zip(partner_levels.iter()).map(|(level, partner_level)| ((*level as u16 + self.predict(*partner_level, bits)) % num_levels) as u8).collect()
+    }
+}
+
+// Every pair of channels with a correlation, strongest first.
+pub fn correlations(mocap: &Mocap) -> Vec<Correlation> {
+    // Each channel's levels, centered and scaled to unit length, so a pair's correlation is their
+    // dot product
+    let normalized = mocap.channels().into_iter().map(|channel| {
+        if channel.values.is_some() {
+            return None;
+        }
+        let levels = levels(channel).into_iter().map(f64::from).collect::<Vec<_>>();
+        let mean = levels.iter().sum::<f64>() / levels.len() as f64;
+        let length = levels.iter().map(|level| (level - mean) * (level - mean)).sum::<f64>().sqrt();
+        if length == 0.0 || length.is_nan() {
+            return None;
+        }
+        Some(levels.into_iter().map(|level| (level - mean) / length).collect::<Vec<_>>())
+    }).collect::<Vec<_>>();
+
+    let mut ret = Vec::new();
+    for (a, x) in normalized.iter().enumerate() {
+        for (b, y) in normalized.iter().enumerate().skip(a + 1) {
+            if let (Some(x), Some(y)) = (x, y) {
+                ret.push(Correlation {
+                    channels: (a, b),
+                    correlation: x.iter().zip(y.iter()).map(|(x, y)| x * y).sum::<f64>().clamp(-1.0, 1.0),
+                });
+            }
+        }
+    }
+    // Stable, so equal correlations stay in channel order
+    ret.sort_by(|a, b| b.correlation.abs().total_cmp(&a.correlation.abs()));
+    ret
+}
+
+// `mocap` with the channels that come out smaller predicted from their partners, and the
+// predictions, which the returned clip's metadata records; `mocap` itself if none do.
+pub fn encode(mocap: &Mocap) -> (Mocap, Vec<Prediction>) {
+    let bits = mocap.channel_quantization_bits;
+    let channels = mocap.channels();
+    let levels = channels.iter().map(|channel| levels(*channel)).collect::<Vec<_>>();
+    let costs = channels.iter().map(|channel| cost(channel, bits)).collect::<Vec<_>>();
+
+    let mut partners = vec![None; channels.len()];
+    let mut predicted = Vec::new();
+    for correlation in correlations(mocap).into_iter().take_while(|correlation| correlation.correlation.abs() >= MIN_CORRELATION) {
+        let (a, b) = correlation.channels;
+        let best = [(a, b), (b, a)].iter().filter(|(channel, partner)| partners[*channel].is_none() && !leads_to(&partners, *partner, *channel)).filter_map(|&(channel, partner)| {
+            let prediction = fit(channel, partner, &levels[channel], &levels[partner]);
+            let stored = Channel {
+                deltas: deltas(&prediction.residual(&levels[channel], &levels[partner], bits), channels[channel].initial_level),
+                ..channels[channel].clone()
+            };
+            // The record's key and length prefixes come with the first prediction
+            let overhead = record(&prediction).len() + 1 + if predicted.is_empty() { 2 + KEY.len() + 2 } else { 0 };
+            let saving = costs[channel] as i64 - (cost(&stored, bits) + overhead) as i64;
+            if saving > 0 {
+                Some((saving, prediction, stored.deltas))
+            } else {
+                None
+            }
+        }).max_by_key(|candidate| candidate.0);
+        if let Some((_, prediction, stored)) = best {
+            partners[prediction.channel] = Some(prediction.partner);
+            predicted.push((prediction, stored));
+        }
+    }
+
+    let mut ret = mocap.clone();
+    if predicted.is_empty() {
+        return (ret, Vec::new());
+    }
+    let records = predicted.iter().map(|(prediction, _)| record(prediction)).collect::<Vec<_>>();
+    {
+        let mut channels = ret.channels_mut();
+        for (prediction, stored) in predicted.iter_mut() {
+            channels[prediction.channel].deltas = std::mem::take(stored);
+        }
+    }
+    ret.metadata.push((KEY.into(), records.join(" ")));
+    (ret, predicted.into_iter().map(|(prediction, _)| prediction).collect())
+}
+
+// Whether following partners from `channel` reaches `target`.
+fn leads_to(partners: &[Option<usize>], channel: usize, target: usize) -> bool {
+    let mut channel = channel;
+    loop {
+        if channel == target {
+            return true;
+        }
+        match partners[channel] {
+            Some(partner) => channel = partner,
+            None => return false,
+        }
+    }
+}
+
+// The least-squares prediction of `levels` from `partner_levels`.
+fn fit(channel: usize, partner: usize, levels: &[u8], partner_levels: &[u8]) -> Prediction {
+    let count = levels.len() as f64;
+    let mean = levels.iter().map(|level| *level as f64).sum::<f64>() / count;
+    let partner_mean = partner_levels.iter().map(|level| *level as f64).sum::<f64>() / count;
+    let (covariance, variance) = levels.iter().zip(partner_levels.iter()).fold((0.0, 0.0), |(covariance, variance), (level, partner_level)| {
+        let (x, y) = (*partner_level as f64 - partner_mean, *level as f64 - mean);
+        (covariance + x * y, variance + x * x)
+    });
+    let slope = round(covariance / variance);
+    Prediction {
+        channel: channel,
+        partner: partner,
+        slope: slope,
+        intercept: round(mean - slope * partner_mean),
+    }
+}
+
+// To 4 decimals, as the record holds it
+fn round(x: f64) -> f64 {
+    format!("{:.4}", x).parse().unwrap()
+}
+
+// `prediction` as the metadata records it.
+fn record(prediction: &Prediction) -> String {
+    format!("{} {} {} {}", prediction.channel, prediction.partner, prediction.slope, prediction.intercept)
+}
+
+// The bytes `raw::write` stores the channel in.
+fn cost(channel: &Channel, bits: u8) -> usize {
+    dump::storage(channel, channel.deltas.len() as u32, bits).1
+}
+
+// The predictions `metadata` records for a clip with `channels`, each after its partner if that's
+// predicted too, so they can be restored in order; none if it doesn't record any.
+pub fn read(metadata: &[(String, String)], channels: &[&Channel]) -> Result<Vec<Prediction>, MocapError> {
+    let record = match metadata.iter().find(|entry| entry.0 == KEY) {
+        Some(entry) => &entry.1,
+        None => return Ok(Vec::new()),
+    };
+    let invalid = || MocapError::InvalidRaw(format!("invalid channel predictions {}", record));
+    let fields = record.split_whitespace().collect::<Vec<_>>();
+    if fields.len() % 4 != 0 {
+        return Err(invalid());
+    }
+    let mut partners = vec![None; channels.len()];
+    let mut pending = Vec::new();
+    for fields in fields.chunks(4) {
+        let index = |field: &str| field.parse::<usize>().ok().filter(|index| *index < channels.len() && channels[*index].values.is_none());
+        let number = |field: &str| field.parse::<f64>().ok().filter(|number| number.is_finite());
+        let prediction = match (index(fields[0]), index(fields[1]), number(fields[2]), number(fields[3])) {
+            (Some(channel), Some(partner), Some(slope), Some(intercept)) if channel != partner && partners[channel].is_none() => Prediction {
+                channel: channel,
+                partner: partner,
+                slope: slope,
+                intercept: intercept,
+            },
+            _ => return Err(invalid()),
+        };
+        partners[prediction.channel] = Some(prediction.partner);
+        pending.push(prediction);
+    }
+
+    // Repeatedly take the predictions whose partners are restored
+    let mut ret = Vec::with_capacity(pending.len());
+    while !pending.is_empty() {
+        let (ready, waiting): (Vec<_>, Vec<_>) = pending.into_iter().partition(|prediction: &Prediction| partners[prediction.partner].is_none());
+        if ready.is_empty() {
+            return Err(MocapError::InvalidRaw(format!("channel predictions {} form a cycle", record)));
+        }
+        for prediction in ready.iter() {
+            partners[prediction.channel] = None;
+        }
+        ret.extend(ready);
+        pending = waiting;
+    }
+    Ok(ret)
+}
+
+// The levels of every channel `predictions` (from `read`) restore, in their order, from their
+// stored levels, which `stored_levels` gives for a flat channel index.
+pub fn restore<F: Fn(usize) -> Vec<u8>>(predictions: &[Prediction], bits: u8, stored_levels: F) -> Vec<(usize, Vec<u8>)> {
+    let mut ret: Vec<(usize, Vec<u8>)> = Vec::with_capacity(predictions.len());
+    for prediction in predictions.iter() {
+        let partner_levels = match ret.iter().find(|restored| restored.0 == prediction.partner) {
+            Some(restored) => restored.1.clone(),
+            None => stored_levels(prediction.partner),
+        };
+        let levels = prediction.restore(&stored_levels(prediction.channel), &partner_levels, bits);
+        ret.push((prediction.channel, levels));
+    }
+    ret
+}
+
+// Restores the channels predicted in `mocap`, as read, dropping the record.
+pub fn decode(mocap: &mut Mocap) -> Result<(), MocapError> {
+    let restored = {
+        let channels = mocap.channels();
+        let predictions = read(&mocap.metadata, &channels)?;
+        restore(&predictions, mocap.channel_quantization_bits, |index| levels(channels[index]))
+    };
+    let mut channels = mocap.channels_mut();
+    for (index, levels) in restored {
+        channels[index].deltas = deltas(&levels, channels[index].initial_level);
+    }
+    mocap.metadata.retain(|entry| entry.0 != KEY);
+    Ok(())
+}
+
+// Every frame's level.
+pub fn levels<C: ChannelData>(channel: &C) -> Vec<u8> {
+    let mut level = channel.channel().initial_level;
+    let mut ret = Vec::new();
+    channel.for_each_delta(|delta| {
+        level = (level as i8).wrapping_add(delta) as u8;
+        ret.push(level);
+    });
+    ret
+}
+
+// The deltas of a channel that starts at `initial_level` and has `levels`.
+pub fn deltas(levels: &[u8], initial_level: u8) -> Vec<i8> {
+    let mut previous_level = initial_level;
+    levels.iter().map(|level| {
+        let delta = (*level as i8).wrapping_sub(previous_level as i8);
+        previous_level = *level;
+        delta
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conversion::ConversionSettings;
+    use depth::DEFAULT_MAX_DEPTH;
+    use periodic::Limits;
+    use raw;
+    use test_util;
+    use view::MocapView;
+    use {build_bvh, build_mocap};
+
+    const NUM_FRAMES: usize = 60;
+    const SPINE_Z: usize = 6;
+    const HEAD_Z: usize = 9;
+
+    // Head RotationZ the negation of Spine RotationZ
+    fn anti_correlated() -> Mocap {
+        let mut bvh = test_util::sine_clip(NUM_FRAMES);
+        for frame in bvh.motion.frames.iter_mut() {
+            frame[HEAD_Z] = -frame[SPINE_Z];
+        }
+        build_mocap(&bvh, &ConversionSettings::default().settings())
+    }
+
+    fn raw(mocap: &Mocap) -> Vec<u8> {
+        let mut ret = Vec::new();
+        raw::write(mocap, Limits::default(), &mut ret).unwrap();
+        ret
+    }
+
+    #[test]
+    fn finds_the_anti_correlated_pair_first() {
+        let correlations = correlations(&anti_correlated());
+        assert_eq!(correlations[0].channels, (SPINE_Z, HEAD_Z));
+        assert!(correlations[0].correlation < -0.9999, "{}", correlations[0].correlation);
+        assert!(correlations.windows(2).all(|pair| pair[0].correlation.abs() >= pair[1].correlation.abs()));
+    }
+
+    #[test]
+    fn anti_correlated_residual_is_near_zero_and_decodes_exactly() {
+        let mocap = anti_correlated();
+        let (encoded, predictions) = encode(&mocap);
+        assert_eq!(predictions.len(), 1);
+        let prediction = predictions[0];
+        assert_eq!((prediction.channel, prediction.partner), (SPINE_Z, HEAD_Z));
+        assert!((prediction.slope + 1.0).abs() < 1e-3, "{}", prediction.slope);
+        assert_eq!(encoded.metadata.last().unwrap(), &(KEY.to_string(), record(&prediction)));
+
+        // The stored levels are the residual, off its most common level by at most one level
+        let residual = levels(encoded.channels()[SPINE_Z]);
+        let first = residual[0];
+        assert!(residual.iter().all(|level| (*level as i8).wrapping_sub(first as i8).abs() <= 1), "{:?}", residual);
+
+        let data = raw(&encoded);
+        assert!(data.len() < raw(&mocap).len());
+        let read = raw::read(&data, DEFAULT_MAX_DEPTH).unwrap();
+        assert!(read.metadata.iter().all(|entry| entry.0 != KEY));
+        for (read, original) in read.channels().into_iter().zip(mocap.channels()) {
+            assert_eq!(read.deltas, original.deltas);
+        }
+        let expected = build_bvh(&mocap).motion.frames;
+        assert_eq!(MocapView::parse(&data, DEFAULT_MAX_DEPTH).unwrap().to_bvh(1).motion.frames, expected);
+    }
+
+    #[test]
+    fn uncorrelated_clips_arent_predicted() {
+        let mocap = build_mocap(&test_util::sine_clip(NUM_FRAMES), &ConversionSettings::default().settings());
+        let (encoded, predictions) = encode(&mocap);
+        assert!(predictions.is_empty());
+        assert_eq!(encoded.metadata, mocap.metadata);
+    }
+
+    #[test]
+    fn reads_predictions_in_dependency_order() {
+        let mocap = anti_correlated();
+        let channels = mocap.channels();
+        let metadata = |record: &str| vec![(KEY.to_string(), record.to_string())];
+
+        // 2 from 1 from 0, recorded the other way round
+        let predictions = read(&metadata("2 1 1 0 1 0 -1 255"), &channels).unwrap();
+        assert_eq!(predictions.iter().map(|prediction| (prediction.channel, prediction.partner)).collect::<Vec<_>>(), vec![(1, 0), (2, 1)]);
+        assert!(read(&[], &channels).unwrap().is_empty());
+
+        assert!(matches!(read(&metadata("1 0 1 0 2 1 1 0 0 2 1 0"), &channels), Err(MocapError::InvalidRaw(ref message)) if message.ends_with("form a cycle")));
+        for record in ["1 0 1", "1 1 1 0", "1 99 1 0", "1 0 NaN 0", "1 0 1 0 1 2 1 0"].iter() {
+            assert!(matches!(read(&metadata(record), &channels), Err(MocapError::InvalidRaw(ref message)) if message.starts_with("invalid channel predictions")), "{}", record);
+        }
+    }
+
+    #[test]
+    fn restores_chained_predictions() {
+        let mocap = anti_correlated();
+        let levels = mocap.channels().into_iter().map(levels).collect::<Vec<_>>();
+        let chain = [
+            Prediction { channel: 1, partner: 0, slope: 0.5, intercept: 3.0 },
+            Prediction { channel: 2, partner: 1, slope: -1.0, intercept: 255.0 },
+        ];
+        let mut stored = levels.clone();
+        stored[1] = chain[0].residual(&levels[1], &levels[0], 8);
+        stored[2] = chain[1].residual(&levels[2], &levels[1], 8);
+        let restored = restore(&chain, 8, |index| stored[index].clone());
+        assert_eq!(restored, vec![(1, levels[1].clone()), (2, levels[2].clone())]);
+    }
+}
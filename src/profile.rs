@@ -0,0 +1,486 @@
+use std::fs;
+use std::path::Path;
+
+use adjust::{Adjustment, Operation};
+use bind::BindPose;
+use conversion::{ConversionSettings, ConversionSettingsBuilder};
+use error::MocapError;
+use mask;
+use smooth;
+use {ChannelType, RotationAnchor, TranslationReference};
+
+// Profiles hold per-project settings that don't fit on a command line, passed with --profile. They
+// are written in a small subset of TOML:
+//
+//   # Knees can't hyperextend
+//   [channel."LeftKnee".RotationX]
+//   clamp = [0, 150]
+//
+// Tables are `[a.b.c]` headers whose parts are bare words or "quoted" strings, followed by
+// `key = value` lines; values are strings, numbers, booleans or single-line arrays of them.
+// Comments start with `#`. Every setting a profile can contain is listed below; anything else is
+// an error, so typos don't go unnoticed.
+//
+//   [channel."<joint>".<channel type>]   settings for one channel type (TranslationX, RotationZ,
+//                                          ...) of the joints matching a selector
+//   clamp = [<min>, <max>]                 hard bounds: source values are clipped to them before
+//                                          quantization and decoded values are kept within them
+//   lossless = true                        store the exact values rather than quantizing them
+//   add = <value>                          add a constant to every value, as --adjust
+//                                          <joint>:<type>+=<value> (see adjust.rs)
+//   multiply = <value>                     multiply every value by a constant, as --adjust
+//                                          <joint>:<type>*=<value>
+//
+//   [joint."<joint>"]                      settings for every channel of the joints matching a
+//                                          selector
+//   lossless = true                        as above
+//
+//   [mask]                                 a partial-body mask, for --mask <file> (see mask.rs)
+//   name = "<name>"                        recorded in the metadata (default: the file name)
+//   joints = ["<joint>", ...]              the joints to keep; LeftArm/** keeps a subtree
+//
+//   [encoding]                             the conversion settings (see conversion.rs), each as the
+//                                          option of the same name, which overrides it, as does
+//                                          the input's directive (see directives.rs)
+//   smooth = "<filter>"
+//   auto-smooth = true
+//   mask = "<upper|lower|file>"
+//   bind-pose = "<first-frame|zero|file.bvh[@frame]>"
+//   root-motion = true
+//   root-motion-anchors = <n>
+//   bits = <n>
+//   translation-reference = "<none|offset|mean>"
+//   rotation-anchor = "<none|zero|rest>"
+//   rot-error = <degrees>
+//   trans-error = <units>
+//   seek-index = true
+//   block-frames = <n>
+//   max-delta-run = <n>
+//   sparse = true
+//   bit-planes = true
+
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub clamps: Vec<Clamp>,
+    pub adjustments: Vec<Adjustment>, // In the order given
+    pub mask: Option<Mask>,
+    pub encoding: ConversionSettingsBuilder, // With the lossless channels
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clamp {
+    pub selector: String,
+    pub type_: ChannelType,
+    pub min: f64,
+    pub max: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lossless {
+    pub selector: String,
+    pub type_: Option<ChannelType>, // Every channel if None
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Mask {
+    pub name: Option<String>,
+    pub joints: Vec<String>, // Selectors
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Number(f64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+impl Profile {
+    pub fn read(path: &Path) -> Result<Profile, MocapError> {
+        Profile::parse(&fs::read_to_string(path)?).map_err(|e| match e {
+            MocapError::InvalidProfile(message) => MocapError::InvalidProfile(format!("{}:{}", path.display(), message)),
+            e => e,
+        })
+    }
+
+    // Errors are reported as `InvalidProfile("<line>: <message>")`.
+    pub fn parse(s: &str) -> Result<Profile, MocapError> {
+        let mut ret = Profile::default();
+        let mut table = Vec::new();
+        for (index, line) in s.lines().enumerate() {
+            let error = |message: String| MocapError::InvalidProfile(format!("{}: {}", index + 1, message));
+
+            let mut parser = Parser { chars: line.chars().collect(), position: 0 };
+            parser.skip_whitespace();
+            if parser.at_end() {
+                continue;
+            }
+
+            if parser.eat('[') {
+                table = parser.keys(']').map_err(&error)?;
+                parser.skip_whitespace();
+                if !parser.at_end() {
+                    return Err(error("unexpected characters after table header".into()));
+                }
+                continue;
+            }
+
+            let keys = parser.keys('=').map_err(&error)?;
+            let value = parser.value().map_err(&error)?;
+            parser.skip_whitespace();
+            if !parser.at_end() {
+                return Err(error("unexpected characters after value".into()));
+            }
+
+            let mut path = table.clone();
+            path.extend(keys);
+            ret.set(&path, value).map_err(&error)?;
+        }
+        ret.encoding.in_profile();
+        Ok(ret)
+    }
+
+    // `conversion`'s settings, with the profile's for the ones it leaves unset. A combination
+    // `conversion` alone allows but the profile's settings don't is reported as an invalid profile,
+    // naming the profile's keys; `file_name` is the profile's.
+    pub fn conversion(&self, conversion: &ConversionSettingsBuilder, file_name: &str) -> Result<ConversionSettings, MocapError> {
+        conversion.or(&self.encoding).build().map_err(|e| match e {
+            MocapError::Usage(message) => MocapError::InvalidProfile(format!("{}: [encoding]: {}", file_name, message)),
+            e => e,
+        })
+    }
+
+    fn set(&mut self, path: &[String], value: Value) -> Result<(), String> {
+        let path = path.iter().map(|part| part.as_str()).collect::<Vec<_>>();
+        match path.as_slice() {
+            ["channel", selector, type_name, "clamp"] => {
+                let type_ = ChannelType::from_name(type_name).ok_or_else(|| format!("unknown channel type {}", type_name))?;
+                let (min, max) = match value {
+                    Value::Array(ref values) => match values.as_slice() {
+                        [Value::Number(min), Value::Number(max)] if min <= max => (*min, *max),
+                        _ => return Err("clamp must be [<min>, <max>] with min <= max".into()),
+                    },
+                    _ => return Err("clamp must be [<min>, <max>] with min <= max".into()),
+                };
+                self.clamps.push(Clamp {
+                    selector: (*selector).into(),
+                    type_: type_,
+                    min: min,
+                    max: max,
+                });
+                Ok(())
+            }
+            ["channel", selector, type_name, "lossless"] => {
+                let type_ = ChannelType::from_name(type_name).ok_or_else(|| format!("unknown channel type {}", type_name))?;
+                self.set_lossless(selector, Some(type_), value)
+            }
+            ["channel", selector, type_name, key @ "add"] | ["channel", selector, type_name, key @ "multiply"] => {
+                let type_ = ChannelType::from_name(type_name).ok_or_else(|| format!("unknown channel type {}", type_name))?;
+                let value = match value {
+                    Value::Number(value) => value,
+                    _ => return Err(format!("{} must be a number", key)),
+                };
+                self.adjustments.push(Adjustment {
+                    selector: (*selector).into(),
+                    type_: type_,
+                    operation: if *key == "add" { Operation::Add(value) } else { Operation::Multiply(value) },
+                });
+                Ok(())
+            }
+            ["joint", selector, "lossless"] => self.set_lossless(selector, None, value),
+            ["mask", "name"] => match value {
+                Value::String(name) => {
+                    self.mask.get_or_insert_with(Mask::default).name = Some(name);
+                    Ok(())
+                }
+                _ => Err("name must be a string".into()),
+            },
+            ["mask", "joints"] => match value {
+                Value::Array(values) => {
+                    let mut joints = Vec::new();
+                    for value in values {
+                        match value {
+                            Value::String(joint) => joints.push(joint),
+                            _ => return Err("joints must be an array of joint selectors".into()),
+                        }
+                    }
+                    self.mask.get_or_insert_with(Mask::default).joints = joints;
+                    Ok(())
+                }
+                _ => Err("joints must be an array of joint selectors".into()),
+            },
+            ["encoding", key @ "smooth"] => {
+                let filter = match value {
+                    Value::String(ref spec) => smooth::parse(spec),
+                    _ => None,
+                };
+                self.encoding.smooth(filter.ok_or_else(|| format!("{} must be \"moving-average:<frames>\" or \"one-euro:<min cutoff>[,<beta>]\"", key))?);
+                Ok(())
+            }
+            ["encoding", key @ "mask"] | ["encoding", key @ "bind-pose"] => {
+                let spec = match value {
+                    Value::String(spec) => spec,
+                    _ => return Err(format!("{} must be a string", key)),
+                };
+                if *key == "mask" {
+                    self.encoding.mask(mask::Mask::parse(&spec));
+                } else {
+                    self.encoding.bind_pose(BindPose::parse(&spec));
+                }
+                Ok(())
+            }
+            ["encoding", key @ "root-motion-anchors"] => {
+                self.encoding.root_motion_anchors(whole(key, value)?);
+                Ok(())
+            }
+            ["encoding", key @ "bits"] => {
+                let bits = whole(key, value)?;
+                if bits > u8::MAX as usize {
+                    return Err(format!("bits must be in [1, 8], got {}", bits));
+                }
+                self.encoding.channel_quantization_bits(bits as u8);
+                Ok(())
+            }
+            ["encoding", "translation-reference"] => {
+                self.encoding.translation_reference(match value {
+                    Value::String(ref reference) if reference == "none" => TranslationReference::None,
+                    Value::String(ref reference) if reference == "offset" => TranslationReference::Offset,
+                    Value::String(ref reference) if reference == "mean" => TranslationReference::Mean,
+                    _ => return Err("translation-reference must be \"none\", \"offset\" or \"mean\"".into()),
+                });
+                Ok(())
+            }
+            ["encoding", "rotation-anchor"] => {
+                self.encoding.rotation_anchor(match value {
+                    Value::String(ref anchor) if anchor == "none" => RotationAnchor::None,
+                    Value::String(ref anchor) if anchor == "zero" => RotationAnchor::Zero,
+                    Value::String(ref anchor) if anchor == "rest" => RotationAnchor::Rest,
+                    _ => return Err("rotation-anchor must be \"none\", \"zero\" or \"rest\"".into()),
+                });
+                Ok(())
+            }
+            ["encoding", key @ "rot-error"] | ["encoding", key @ "trans-error"] => {
+                let target = match value {
+                    Value::Number(target) => target,
+                    _ => return Err(format!("{} must be a number", key)),
+                };
+                if *key == "rot-error" {
+                    self.encoding.rotation_error(target);
+                } else {
+                    self.encoding.translation_error(target);
+                }
+                Ok(())
+            }
+            ["encoding", key @ "block-frames"] => {
+                self.encoding.block_frames(whole(key, value)?);
+                Ok(())
+            }
+            ["encoding", key @ "max-delta-run"] => {
+                self.encoding.max_delta_run(whole(key, value)?);
+                Ok(())
+            }
+            ["encoding", key @ "auto-smooth"] | ["encoding", key @ "root-motion"] | ["encoding", key @ "seek-index"] | ["encoding", key @ "sparse"] | ["encoding", key @ "bit-planes"] => {
+                let enabled = match value {
+                    Value::Bool(enabled) => enabled,
+                    _ => return Err(format!("{} must be true or false", key)),
+                };
+                match *key {
+                    "auto-smooth" => self.encoding.auto_smooth(enabled),
+                    "root-motion" => self.encoding.root_motion(enabled),
+                    "seek-index" => self.encoding.seek_index(enabled),
+                    "sparse" => self.encoding.sparse(enabled),
+                    _ => self.encoding.bit_planes(enabled),
+                };
+                Ok(())
+            }
+            _ => Err(format!("unknown setting {}", path.join("."))),
+        }
+    }
+
+    fn set_lossless(&mut self, selector: &str, type_: Option<ChannelType>, value: Value) -> Result<(), String> {
+        match value {
+            Value::Bool(true) => {
+                self.encoding.lossless(Lossless {
+                    selector: selector.into(),
+                    type_: type_,
+                });
+            }
+            Value::Bool(false) => (),
+            _ => return Err("lossless must be true or false".into()),
+        }
+        Ok(())
+    }
+}
+
+// A whole, non-negative number for `key`.
+fn whole(key: &str, value: Value) -> Result<usize, String> {
+    match value {
+        Value::Number(number) if number >= 0.0 && number.fract() == 0.0 && number <= u32::MAX as f64 => Ok(number as usize),
+        _ => Err(format!("{} must be a whole number", key)),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn at_end(&self) -> bool {
+        self.position >= self.chars.len() || self.chars[self.position] == '#'
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).cloned()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c == ' ' || c == '\t') {
+            self.position += 1;
+        }
+    }
+
+    // Dotted keys up to and including `terminator`.
+    fn keys(&mut self, terminator: char) -> Result<Vec<String>, String> {
+        let mut ret = Vec::new();
+        loop {
+            self.skip_whitespace();
+            ret.push(match self.peek() {
+                Some('"') => self.string()?,
+                _ => {
+                    let start = self.position;
+                    while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+                        self.position += 1;
+                    }
+                    if self.position == start {
+                        return Err("expected a key".into());
+                    }
+                    self.chars[start..self.position].iter().collect()
+                }
+            });
+            self.skip_whitespace();
+            if self.eat(terminator) {
+                return Ok(ret);
+            }
+            if !self.eat('.') {
+                return Err(format!("expected . or {}", terminator));
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some('[') => {
+                self.position += 1;
+                let mut values = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.eat(']') {
+                        return Ok(Value::Array(values));
+                    }
+                    values.push(self.value()?);
+                    self.skip_whitespace();
+                    if !self.eat(',') && self.peek() != Some(']') {
+                        return Err("expected , or ] in array".into());
+                    }
+                }
+            }
+            _ => {
+                let start = self.position;
+                while self.peek().is_some_and(|c| !c.is_whitespace() && c != ',' && c != ']' && c != '#') {
+                    self.position += 1;
+                }
+                let word = self.chars[start..self.position].iter().collect::<String>();
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => word.replace('_', "").parse().map(Value::Number).map_err(|_| format!("invalid value {}", word)),
+                }
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.position += 1;
+        let mut ret = String::new();
+        loop {
+            match self.peek() {
+                None => return Err("unterminated string".into()),
+                Some('"') => {
+                    self.position += 1;
+                    return Ok(ret);
+                }
+                Some('\\') => {
+                    self.position += 1;
+                    match self.peek() {
+                        Some('n') => ret.push('\n'),
+                        Some('t') => ret.push('\t'),
+                        Some(c @ '"') | Some(c @ '\\') => ret.push(c),
+                        _ => return Err("invalid escape in string".into()),
+                    }
+                    self.position += 1;
+                }
+                Some(c) => {
+                    ret.push(c);
+                    self.position += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use smooth::Filter;
+
+    fn conversion(profile: &str, conversion: &ConversionSettingsBuilder) -> Result<ConversionSettings, MocapError> {
+        Profile::parse(profile).unwrap().conversion(conversion, "p.toml")
+    }
+
+    #[test]
+    fn encoding_sets_the_conversion_settings() {
+        let settings = conversion("[encoding]\nsmooth = \"moving-average:5\"\nmask = \"upper\"\nroot-motion = true\nroot-motion-anchors = 10\n", &ConversionSettingsBuilder::default()).unwrap();
+        assert_eq!(settings.smooth(), Some(&Filter::MovingAverage(5)));
+        assert_eq!(settings.mask(), Some(&mask::Mask::Upper));
+        assert!(settings.root_motion());
+        assert_eq!(settings.root_motion_anchors(), 10);
+
+        // The command line's settings win
+        let mut builder = ConversionSettingsBuilder::default();
+        builder.root_motion_anchors(20);
+        assert_eq!(conversion("[encoding]\nroot-motion = true\nroot-motion-anchors = 10\n", &builder).unwrap().root_motion_anchors(), 20);
+    }
+
+    #[test]
+    fn encoding_errors_name_the_keys() {
+        let error = |profile: &str, builder: &ConversionSettingsBuilder| match conversion(profile, builder) {
+            Err(MocapError::InvalidProfile(message)) => message,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(error("[encoding]\nbits = 9\n", &ConversionSettingsBuilder::default()), "p.toml: [encoding]: bits must be in [1, 8], got 9");
+        assert_eq!(error("[encoding]\nroot-motion = true\nbind-pose = \"zero\"\n", &ConversionSettingsBuilder::default()), "p.toml: [encoding]: root-motion can't be combined with bind-pose");
+
+        // A conflict between the command line and the profile names each where it was given
+        let mut builder = ConversionSettingsBuilder::default();
+        builder.sparse(true);
+        assert_eq!(error("[encoding]\nseek-index = true\n", &builder), "p.toml: [encoding]: --sparse can't be combined with seek-index");
+
+        // As does one with a --calibration stream
+        let mut builder = ConversionSettingsBuilder::default();
+        builder.streamed(true);
+        assert_eq!(error("[encoding]\nroot-motion = true\n", &builder), "p.toml: [encoding]: root-motion can't be combined with --calibration, whose streamed file has no metadata");
+    }
+}
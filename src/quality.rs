@@ -0,0 +1,231 @@
+use Mocap;
+
+// How far each channel can be trusted, after the passes that patch the capture up. Those passes
+// report what they did to each channel as events into a shared accumulator (`Quality`), in flat
+// channel order, instead of only printing warnings:
+//
+//   Repaired    samples --repair-gaps filled in (see gaps.rs)
+//   Clamped     samples clipped to the profile's bounds (see clamp.rs)
+//   Overflowed  samples outside the calibration ranges a --calibration stream clamped (see writer.rs),
+//               or outside the --ranges-in ranges (see ranges.rs)
+//
+// along with each channel's noise floor (see smooth.rs). `ChannelQuality::score` condenses them
+// into a byte, 255 for a channel nothing happened to: scaled down by the fraction of the clip's
+// frames patched up, and again for a noise floor above NOISE_THRESHOLD (in channel units, degrees
+// for rotations). A clip with any channel below 255 records every channel's score as metadata,
+// space-separated in flat channel order; `mocap info` lists the channels below LOW_QUALITY, and
+// the --report has the counts behind each score. A --calibration stream has no metadata, so its
+// overflows are only in the report.
+//
+// Events are counted against the clip's channels as they are when the pass runs; `remap` follows
+// --root discarding channels.
+
+// Metadata key holding the scores
+pub const KEY: &str = "channel_quality";
+
+// The score below which `mocap info` lists a channel
+pub const LOW_QUALITY: u8 = 192;
+
+// Noise floors up to this don't lower the score
+const NOISE_THRESHOLD: f64 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Repaired(u64),
+    Clamped(u64),
+    Overflowed(u64),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChannelQuality {
+    pub repaired: u64,
+    pub clamped: u64,
+    pub overflowed: u64,
+    pub noise_floor: Option<f64>,
+}
+
+impl ChannelQuality {
+    pub fn score(&self, num_frames: u32) -> u8 {
+        let patched = self.repaired.saturating_add(self.clamped).saturating_add(self.overflowed);
+        let trusted = if num_frames == 0 { 1.0 } else { 1.0 - (patched as f64 / num_frames as f64).min(1.0) };
+        let noise = match self.noise_floor {
+            Some(noise_floor) if noise_floor > NOISE_THRESHOLD => NOISE_THRESHOLD / noise_floor,
+            _ => 1.0,
+        };
+        (255.0 * trusted * noise).round() as u8
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Quality {
+    pub channels: Vec<ChannelQuality>,
+}
+
+impl Quality {
+    pub fn new(num_channels: usize) -> Quality {
+        Quality {
+            channels: vec![ChannelQuality::default(); num_channels],
+        }
+    }
+
+    pub fn record(&mut self, channel: usize, event: Event) {
+        let quality = &mut self.channels[channel];
+        match event {
+            Event::Repaired(count) => quality.repaired = quality.repaired.saturating_add(count),
+            Event::Clamped(count) => quality.clamped = quality.clamped.saturating_add(count),
+            Event::Overflowed(count) => quality.overflowed = quality.overflowed.saturating_add(count),
+        }
+    }
+
+    pub fn set_noise_floors(&mut self, noise_floors: &[f64]) {
+        for (quality, noise_floor) in self.channels.iter_mut().zip(noise_floors.iter()) {
+            quality.noise_floor = Some(*noise_floor);
+        }
+    }
+
+    // Rebuilds the channels after a pass changed them: `sources` has, for each new channel, the
+    // old one it came from, or None for a new channel nothing has happened to.
+    pub fn remap(&mut self, sources: &[Option<usize>]) {
+        self.channels = sources.iter().map(|source| source.map_or(ChannelQuality::default(), |source| self.channels[source])).collect();
+    }
+
+    // The metadata recording every channel's score, unless they're all 255.
+    pub fn metadata(&self, num_frames: u32) -> Option<(String, String)> {
+        let scores = self.channels.iter().map(|quality| quality.score(num_frames)).collect::<Vec<_>>();
+        if scores.iter().all(|score| *score == 255) {
+            return None;
+        }
+        Some((KEY.into(), scores.iter().map(|score| score.to_string()).collect::<Vec<_>>().join(" ")))
+    }
+}
+
+// The scores recorded in `mocap`'s metadata, if it has any (and as many as it has channels).
+pub fn scores(mocap: &Mocap) -> Option<Vec<u8>> {
+    let value = &mocap.metadata.iter().find(|(key, _)| key == KEY)?.1;
+    let scores = value.split(' ').map(|score| score.parse().ok()).collect::<Option<Vec<u8>>>()?;
+    if scores.len() != mocap.channels().len() {
+        return None;
+    }
+    Some(scores)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use depth::DEFAULT_MAX_DEPTH;
+    use test_util;
+    use {convert, raw};
+
+    fn quality(repaired: u64, clamped: u64, overflowed: u64, noise_floor: Option<f64>) -> ChannelQuality {
+        ChannelQuality {
+            repaired: repaired,
+            clamped: clamped,
+            overflowed: overflowed,
+            noise_floor: noise_floor,
+        }
+    }
+
+    #[test]
+    fn scores_scale_with_the_frames_patched_and_the_noise() {
+        assert_eq!(quality(0, 0, 0, None).score(100), 255);
+        assert_eq!(quality(0, 0, 0, Some(NOISE_THRESHOLD)).score(100), 255);
+        assert_eq!(quality(10, 0, 0, None).score(100), 230);
+        assert_eq!(quality(10, 20, 20, None).score(100), 128);
+        assert_eq!(quality(0, 0, 0, Some(NOISE_THRESHOLD * 2.0)).score(100), 128);
+        assert_eq!(quality(50, 0, 0, Some(NOISE_THRESHOLD * 2.0)).score(100), 64);
+
+        // More patches than frames (a sample clamped and then overflowed), and no frames at all
+        assert_eq!(quality(80, 80, 0, None).score(100), 0);
+        assert_eq!(quality(u64::MAX, u64::MAX, 1, None).score(100), 0);
+        assert_eq!(quality(3, 0, 0, None).score(0), 255);
+    }
+
+    #[test]
+    fn records_events_and_remaps_them() {
+        let mut quality = Quality::new(3);
+        quality.record(0, Event::Repaired(2));
+        quality.record(0, Event::Repaired(3));
+        quality.record(1, Event::Clamped(4));
+        quality.record(2, Event::Overflowed(u64::MAX));
+        quality.record(2, Event::Overflowed(1));
+        quality.set_noise_floors(&[0.5, 0.0]);
+        assert_eq!(quality.channels, vec![self::quality(5, 0, 0, Some(0.5)), self::quality(0, 4, 0, Some(0.0)), self::quality(0, 0, u64::MAX, None)]);
+
+        quality.remap(&[Some(2), None, Some(0)]);
+        assert_eq!(quality.channels, vec![self::quality(0, 0, u64::MAX, None), self::quality(0, 0, 0, None), self::quality(5, 0, 0, Some(0.5))]);
+    }
+
+    #[test]
+    fn metadata_only_for_a_clip_with_a_channel_below_255() {
+        let mut quality = Quality::new(3);
+        assert_eq!(quality.metadata(10), None);
+        quality.record(1, Event::Clamped(5));
+        assert_eq!(quality.metadata(10), Some((KEY.to_string(), "255 128 255".to_string())));
+    }
+
+    // A clip with one channel for each event: Spine RotationZ occluded for 3 frames (--repair-gaps),
+    // Head RotationZ beyond the profile's bounds for 4 (clamped), LeftLeg RotationZ beyond the
+    // --ranges-in range for 10 (overflowed) and LeftLeg RotationX noisy
+    #[test]
+    fn counts_each_event_on_its_channel() {
+        let noise = test_util::noise(8, 40);
+        let text = test_util::clip_text(40, |frame, channel| match channel {
+            6 if (10..13).contains(&frame) => 999.0,
+            9 => if frame < 4 { 10.0 } else { 0.0 },
+            12 => frame as f64,
+            13 => test_util::sine(frame, channel) + noise[frame] * 0.5,
+            _ => test_util::sine(frame, channel),
+        });
+        let dir = test_util::temp_dir("quality-events");
+        let path = |file_name: &str| dir.join(file_name);
+        fs::write(path("in.bvh"), text).unwrap();
+        fs::write(path("profile.toml"), "[channel.\"Head\".RotationZ]\nclamp = [-5, 5]\n").unwrap();
+        fs::write(path("ranges.txt"), "LeftLeg\tRotationZ\t0\t29.5\n").unwrap();
+        let arg = |file_name: &str| path(file_name).to_string_lossy().into_owned();
+        let options = test_util::options(&["--repair-gaps", "sentinel=999", "--profile", &arg("profile.toml"), "--ranges-in", &arg("ranges.txt")]);
+        let conversion = convert(&path("in.bvh"), &path("out.bvh"), &path("out.csv"), &path("out.raw"), &options, None).unwrap();
+
+        let channels = conversion.channels.iter().map(|channel| channel.quality.unwrap()).collect::<Vec<_>>();
+        let counts = channels.iter().map(|channel| (channel.repaired, channel.clamped, channel.overflowed)).collect::<Vec<_>>();
+        let mut expected = vec![(0, 0, 0); test_util::NUM_CHANNELS];
+        expected[6] = (3, 0, 0);
+        expected[9] = (0, 4, 0);
+        expected[12] = (0, 0, 10);
+        assert_eq!(counts, expected);
+
+        let noise_floor = channels[13].noise_floor.unwrap();
+        assert!(noise_floor > 0.4 && noise_floor < 0.6, "{}", noise_floor);
+        assert!(channels.iter().enumerate().all(|(index, channel)| index == 13 || channel.noise_floor.unwrap() <= NOISE_THRESHOLD));
+
+        // The scores the report has are the ones the clip records
+        let scores = conversion.channels.iter().map(|channel| channel.score.unwrap()).collect::<Vec<_>>();
+        let mut expected = vec![255; test_util::NUM_CHANNELS];
+        expected[6] = 236;
+        expected[9] = 230;
+        expected[12] = 191;
+        expected[13] = (255.0 * NOISE_THRESHOLD / noise_floor).round() as u8;
+        assert_eq!(scores, expected);
+        let mocap = raw::read(&fs::read(path("out.raw")).unwrap(), DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(super::scores(&mocap), Some(expected));
+    }
+
+    #[test]
+    fn scores_need_one_per_channel() {
+        let dir = test_util::temp_dir("quality-scores");
+        let path = |file_name: &str| dir.join(file_name);
+        fs::write(path("in.bvh"), test_util::clip_text(10, test_util::sine)).unwrap();
+        convert(&path("in.bvh"), &path("out.bvh"), &path("out.csv"), &path("out.raw"), &test_util::options(&[]), None).unwrap();
+        let mut mocap = raw::read(&fs::read(path("out.raw")).unwrap(), DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(scores(&mocap), None);
+
+        let num_channels = mocap.channels().len();
+        mocap.metadata.push((KEY.into(), vec!["200"; num_channels].join(" ")));
+        assert_eq!(scores(&mocap), Some(vec![200; num_channels]));
+        mocap.metadata.last_mut().unwrap().1 = vec!["200"; num_channels + 1].join(" ");
+        assert_eq!(scores(&mocap), None);
+        mocap.metadata.last_mut().unwrap().1 = vec!["x"; num_channels].join(" ");
+        assert_eq!(scores(&mocap), None);
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use bvh;
+
+use error::MocapError;
+use log;
+use manifest;
+use quality::{self, Quality};
+use smooth;
+use {channel_type, max_level, ChannelType, Mocap, RotationAnchor, Settings};
+
+// Channel ranges carried over from one run to the next, so a character's clips quantize onto the
+// same grid. A clip's channels normally each span the clip's own values, so the same pose decodes a
+// little differently from clip to clip; with the ranges of one run reused, every clip's levels
+// decode to the same values. --ranges-out writes the ranges a conversion quantized with as a
+// ranges file, and --ranges-in quantizes with a ranges file's instead of computing them: every
+// channel it lists takes the listed range, and values outside it are clamped to it, with a warning
+// and an Overflowed event per channel (see quality.rs). Channels it doesn't list, lossless channels
+// and anchored rotation channels (whose grid is placed around each clip's own anchor) are quantized
+// with their own ranges as usual.
+//
+// A ranges file has one `<joint>\t<channel type>\t<min>\t<range>` per channel, channel types named
+// as in the CSV output and min absolute (the channel's reference plus its value_range_min), so a
+// translation stored relative to its mean keeps the same grid whatever the mean. Blank lines and
+// lines starting with # are ignored. The ranges are of the values as quantized, after every input
+// pass, so the runs sharing them should make the same passes (--root-motion, --bind-pose, ...).
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Range {
+    pub joint: String,
+    pub channel_type: ChannelType,
+    pub min: f64, // Absolute
+    pub range: f64,
+}
+
+// The ranges `mocap`'s channels are quantized with, in flat channel order, leaving out the ones
+// --ranges-in leaves alone.
+pub fn of(mocap: &Mocap) -> Vec<Range> {
+    let channel_map = mocap.channel_map();
+    mocap.channels().into_iter().zip(channel_map.iter())
+        .filter(|(channel, _)| channel.values.is_none() && channel.anchor_level.is_none())
+        .map(|(channel, descriptor)| Range {
+            joint: descriptor.joint_name.clone(),
+            channel_type: channel.type_,
+            min: channel.reference + channel.value_range_min as f64,
+            range: channel.value_range as f64,
+        }).collect()
+}
+
+pub fn read_file(path: &Path) -> Result<Vec<Range>, MocapError> {
+    let contents = fs::read_to_string(path)?;
+    let mut ret: Vec<Range> = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let invalid = || MocapError::InvalidRanges(format!("{}:{}: expected <joint>\\t<channel type>\\t<min>\\t<range>, got {}", path.display(), index + 1, line));
+        let fields = line.split('\t').map(|field| field.trim()).collect::<Vec<_>>();
+        if fields.len() != 4 || fields[0].is_empty() {
+            return Err(invalid());
+        }
+        let channel_type = ChannelType::from_name(fields[1]).ok_or_else(invalid)?;
+        let min = fields[2].parse::<f64>().ok().filter(|min| min.is_finite()).ok_or_else(invalid)?;
+        let range = fields[3].parse::<f64>().ok().filter(|range| range.is_finite() && *range >= 0.0).ok_or_else(invalid)?;
+        if ret.iter().any(|other| other.joint == fields[0] && other.channel_type == channel_type) {
+            return Err(MocapError::InvalidRanges(format!("{}:{}: {} {} is listed twice", path.display(), index + 1, fields[0], channel_type.name())));
+        }
+        ret.push(Range {
+            joint: fields[0].into(),
+            channel_type: channel_type,
+            min: min,
+            range: range,
+        });
+    }
+    Ok(ret)
+}
+
+// Writes `ranges` as a ranges file, which --ranges-in (and `read_file`) reads back.
+pub fn write_file(ranges: &[Range], path: &Path) -> Result<(), MocapError> {
+    if let Some(range) = ranges.iter().find(|range| range.joint.contains(['\t', '\n', '\r']) || range.joint.trim() != range.joint) {
+        return Err(MocapError::InvalidRanges(format!("joint {:?} can't be written to a ranges file", range.joint)));
+    }
+    let mut w = io::BufWriter::new(manifest::create(path)?);
+    writeln!(w, "# <joint>\t<channel type>\t<min>\t<range>")?;
+    for range in ranges.iter() {
+        writeln!(w, "{}\t{}\t{}\t{}", range.joint, range.channel_type.name(), range.min, range.range)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+// The (absolute min, range) from `ranges` each of `bvh`'s channels is to be quantized with, in flat
+// channel order, None for the channels quantized with their own. Counts the samples outside them
+// into `quality` and warns about them, and about channels `ranges` doesn't list.
+pub fn assign(ranges: &[Range], bvh: &bvh::Bvh, lossless: &[bool], settings: &Settings, clip_name: &str, quality: &mut Quality) -> Vec<Option<(f64, f64)>> {
+    let ranges = ranges.iter().map(|range| ((range.joint.as_str(), range.channel_type.name()), (range.min, range.range))).collect::<HashMap<_, _>>();
+    let mut ret = Vec::new();
+    let mut unlisted = 0;
+    push_joint(&bvh.hierarchy.root, &ranges, lossless, settings.rotation_anchor != RotationAnchor::None, &mut ret, &mut unlisted);
+    if unlisted > 0 {
+        log::warning(format!("{}: {} channel{} not in the --ranges-in file, quantized with their own ranges", clip_name, unlisted, if unlisted == 1 { "" } else { "s" }));
+    }
+
+    let mut clamped = Vec::new();
+    for (index, range) in ret.iter().enumerate() {
+        if let Some((min, range)) = *range {
+            // A file's ranges are a clip's f32 parameters, which its own extremes can fall just
+            // outside of
+            let tolerance = (min.abs() + range) * f32::EPSILON as f64;
+            let num_clamped = bvh.motion.frames.iter().filter(|frame| frame[index] < min - tolerance || frame[index] > min + range + tolerance).count() as u64;
+            if num_clamped > 0 {
+                quality.record(index, quality::Event::Overflowed(num_clamped));
+                clamped.push((index, num_clamped));
+            }
+        }
+    }
+    if !clamped.is_empty() {
+        let names = smooth::channel_names(&bvh.hierarchy.root);
+        let counts = clamped.iter().map(|(index, num_clamped)| format!("{} ({})", names[*index], num_clamped)).collect::<Vec<_>>();
+        log::warning(format!("{}: values outside the --ranges-in ranges clamped, in {}", clip_name, counts.join(", ")));
+    }
+    ret
+}
+
+// Requantizes the channels of `mocap` given a range by `assign`, from the values in `frames`.
+pub fn apply(mocap: &mut Mocap, frames: &[Vec<f64>], ranges: &[Option<(f64, f64)>]) {
+    let max_level = max_level(mocap.channel_quantization_bits) as f64;
+    for (index, (channel, range)) in mocap.channels_mut().into_iter().zip(ranges.iter()).enumerate() {
+        let (min, range) = match *range {
+            Some((min, range)) => (min - channel.reference, range),
+            None => continue,
+        };
+        channel.value_range_min = min as _;
+        channel.value_range = range as _;
+        channel.deltas.clear();
+        let mut previous_level = 0;
+        for frame in frames.iter() {
+            let value = (frame[index] - channel.reference).clamp(min, min + range);
+            let level = if range > 0.0 { (((value - min) / range) * max_level) as u8 } else { 0 };
+            channel.deltas.push((level as i8).wrapping_sub(previous_level as i8));
+            previous_level = level;
+        }
+    }
+}
+
+// Leaves lossless channels (flagged in `lossless`, in flat channel order) and, if `anchored`,
+// rotation channels out.
+fn push_joint(joint: &bvh::Joint, ranges: &HashMap<(&str, &str), (f64, f64)>, lossless: &[bool], anchored: bool, ret: &mut Vec<Option<(f64, f64)>>, unlisted: &mut usize) {
+    for channel in joint.channels.iter() {
+        let channel_type = channel_type(channel);
+        if lossless.get(ret.len()) == Some(&true) || anchored && !channel_type.is_translation() {
+            ret.push(None);
+            continue;
+        }
+        let range = ranges.get(&(joint.name.as_str(), channel_type.name())).cloned();
+        *unlisted += range.is_none() as usize;
+        ret.push(range);
+    }
+    if let bvh::JointChildren::Joints(ref children) = joint.children {
+        for child in children.iter() {
+            push_joint(child, ranges, lossless, anchored, ret, unlisted);
+        }
+    }
+}
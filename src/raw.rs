@@ -0,0 +1,1072 @@
+use std::io::{self, Write};
+
+use bitpack;
+use depth;
+use error::MocapError;
+use markers::Marker;
+use periodic::{self, Limits, Periodic};
+use prediction;
+use seek;
+use timing;
+use {collect_channels_mut, max_level, num_levels, Channel, ChannelType, Joint, JointChildren, Mocap};
+
+// The .raw format. All values are little-endian.
+//
+//   magic           b"MOCP"
+//   version         u8, FORMAT_VERSION
+//   num_frames      u32
+//   frame_time      f32
+//   bits            u8, channel_quantization_bits, the bit depth of every channel without one of its
+//                   own
+//   layout          u8: where the channels left out of the header are, 0 = packed in the delta
+//                   blocks, 1 = in a sparse track, 2 = as bit planes in the delta blocks (see
+//                   bitpack.rs)
+//   metadata        u16 count, then that many (key string, value string) pairs
+//   markers         u32 count, then that many (frame u32, name string) pairs, sorted by frame, each
+//                   before num_frames
+//   timestamps      u32 count, 0 or num_frames, then that many f64 times in seconds, increasing;
+//                   none if the frames are frame_time apart (see timing.rs)
+//   root            joint, see below
+//   sparse track    only if sparse: per frame, a u16 count of the channels whose level changed
+//                   from the previous frame (for the first frame, from the initial level), then
+//                   per changed channel by increasing index a u16 index among the channels stored
+//                   in the track (in `Mocap::channel_map` order) and its new level u8, on the
+//                   channel's grid
+//   deltas          blocks of frames until the block frame counts add up to num_frames, each a u32
+//                   frame count (> 0) followed by the deltas of every channel in the block,
+//                   channel-major, channels in `Mocap::channel_map` order leaving out those stored
+//                   in the header or the sparse track;
+//                   each channel's deltas packed at its bit depth per frame, or as bit planes, and
+//                   padded to a whole byte (see bitpack.rs)
+//   seek index      optional, see seek.rs
+//
+// A joint is written as
+//
+//   name            string (u16 byte length + UTF-8)
+//   original name   u8 0/1 presence flag + string if present
+//   offset          3 x f32
+//   channel count   u8, possibly 0: a joint declaring CHANNELS 0, a pivot only there for its offset
+//   channels        per channel, in the joint's CHANNELS order: type u8 (see `channel_type_id`),
+//                   reference f64, value_range_min f32, value_range f32, initial_level u8,
+//                   clamp u8 0/1 presence flag + min f64, max f64 if present,
+//                   anchor level u8 0/1 presence flag + level u8 if present,
+//                   bits u8, the channel's own bit depth in [1, 8], or 0 for the clip's `bits`
+//                   (only a partially re-encoded clip has others, see reencode.rs),
+//                   storage u8 0 = deltas in the delta blocks
+//                              1 = periodic (see periodic.rs): a u32 period, that many levels
+//                                  (u8), a u32 correction count and that many (frame u32,
+//                                  level u8) corrections by increasing frame
+//                              2 = lossless (see profile.rs): num_frames exact f64 values; the
+//                                  quantization parameters are kept but unused
+//                              3 = constant: the level (u8) of every frame
+//                              4 = constant lossless: the exact value (f64) of every frame
+//   children        u8 0 = joints, followed by a u16 count and that many joints
+//                      1 = end site, followed by its offset as 3 x f32
+//
+// `write` stores all frames in a single block, and channels that repeat periodically (or don't
+// change at all) in the header when that's smaller; `write_indexed` splits the frames into smaller
+// blocks followed by a seek index, and smaller blocks let `writer::MocapWriter` stream frames out as
+// they arrive, without periodic channels. A static clip, where every channel is
+// constant (see `is_static`), thus takes a single frame's worth of values however long it is.
+//
+// `write_sparse` (--sparse) stores the channels that aren't periodic or lossless in the sparse
+// track instead, leaving the blocks empty: a frame where nothing moves takes 2 bytes, and every
+// channel that does 3 more. That beats the packed deltas of every channel in every frame when a
+// scene is mostly still, with only a few channels moving at a time; when most channels move in
+// most frames it's several times larger. Reading expands the track into deltas, a channel holding
+// its level through the frames it's not listed in, so decoding is the same either way.
+//
+// `write_bit_planes` (--bit-planes) is `write` with each channel's deltas in a block stored as
+// bit planes (see bitpack.rs), as `write_indexed` can too: the same bytes in an order that
+// compresses better afterwards.
+//
+// A channel predicted from a partner with --predict-channels is stored as its residual, recorded
+// in the metadata (see prediction.rs); reading restores it.
+//
+// Channel order is stored exactly as declared in the source, not canonicalized, so a decoded BVH
+// has the same CHANNELS lines as the input.
+//
+// Counts and string lengths are checked when writing too: one that doesn't fit its field (a
+// metadata value longer than 65535 bytes, say) is an `InvalidInput` error rather than a truncated
+// length the reader would misparse everything after.
+//
+// Counts read from a file are checked against the bytes left before anything is allocated for
+// them, so a corrupt or hostile file fails cleanly instead of exhausting memory: `num_frames` must
+// fit the delta blocks channels stored in them would take, and the frames periodic channels expand
+// to are capped at MAX_PERIODIC_EXPANSION bytes per byte of input.
+pub const MAGIC: &[u8; 4] = b"MOCP";
+pub const FORMAT_VERSION: u8 = 15;
+
+// Where num_frames is, so a streaming writer can fill it in at the end
+pub const NUM_FRAMES_OFFSET: u64 = 5;
+
+// Far beyond what real clips reach (a constant channel takes 2 bytes however long it is, but
+// shares the file with a header of some size), while keeping a small file from decoding to more
+// than a few gigabytes
+const MAX_PERIODIC_EXPANSION: usize = 1 << 16;
+
+// The header's layout byte
+const LAYOUT_PACKED: u8 = 0;
+const LAYOUT_SPARSE: u8 = 1;
+const LAYOUT_BIT_PLANES: u8 = 2;
+
+// The periodic search stops once `limits`' time budget is spent or its cancel token set (see
+// cancel.rs), so the clip is still written in full, just quickly.
+pub fn write<W: Write>(mocap: &Mocap, limits: Limits, w: &mut W) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&[FORMAT_VERSION])?;
+    write_clip(mocap, limits, w)
+}
+
+// `write` with the delta blocks as bit planes.
+pub fn write_bit_planes<W: Write>(mocap: &Mocap, limits: Limits, w: &mut W) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&[FORMAT_VERSION])?;
+    write_clip_layout(mocap, LAYOUT_BIT_PLANES, limits, w)
+}
+
+// `write` with the channels that would go in the delta blocks in a sparse track. There can be at
+// most 65535 of them.
+pub fn write_sparse<W: Write>(mocap: &Mocap, limits: Limits, w: &mut W) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&[FORMAT_VERSION])?;
+    write_clip_layout(mocap, LAYOUT_SPARSE, limits, w)
+}
+
+// Whether the .raw file in `data` has a sparse track (it must have been read successfully).
+pub fn is_sparse(data: &[u8]) -> bool {
+    layout_byte(data) == LAYOUT_SPARSE
+}
+
+// How the .raw file in `data` stores the deltas in its blocks (it must have been read
+// successfully).
+pub fn delta_layout(data: &[u8]) -> bitpack::Layout {
+    if layout_byte(data) == LAYOUT_BIT_PLANES { bitpack::Layout::BitPlanes } else { bitpack::Layout::Packed }
+}
+
+fn layout_byte(data: &[u8]) -> u8 {
+    // It follows the magic, version, frame count, frame time and bits
+    data[NUM_FRAMES_OFFSET as usize + 9]
+}
+
+// `write` with blocks of `block_frames` frames (the last may be shorter), in `layout`, and a seek
+// index.
+pub fn write_indexed<W: Write>(mocap: &Mocap, block_frames: usize, layout: bitpack::Layout, limits: Limits, w: &mut W) -> io::Result<()> {
+    let mut header = Vec::new();
+    header.extend_from_slice(MAGIC);
+    header.push(FORMAT_VERSION);
+    let periodic = encode_periodic(mocap, limits);
+    let layout_byte = if layout == bitpack::Layout::BitPlanes { LAYOUT_BIT_PLANES } else { LAYOUT_PACKED };
+    write_header(mocap, &periodic, layout_byte, &mut header)?;
+    w.write_all(&header)?;
+
+    let channels = mocap.channels().into_iter().zip(periodic.iter())
+        .filter(|(channel, periodic)| periodic.is_none() && channel.values.is_none())
+        .map(|(channel, _)| channel)
+        .collect::<Vec<_>>();
+    let block_frames = block_frames.max(1);
+    let num_frames = mocap.num_frames as usize;
+    let mut levels = channels.iter().map(|channel| channel.initial_level).collect::<Vec<_>>();
+    let mut offset = header.len() as u64;
+    let mut entries = Vec::new();
+    for start in (0..num_frames).step_by(block_frames) {
+        let end = (start + block_frames).min(num_frames);
+        let mut block = ((end - start) as u32).to_le_bytes().to_vec();
+        let block_levels = levels.clone();
+        for (channel, level) in channels.iter().zip(levels.iter_mut()) {
+            let deltas = &channel.deltas[start..end];
+            bitpack::pack(deltas, channel.bits(mocap.channel_quantization_bits), layout, &mut block);
+            *level = deltas.iter().fold(*level, |level, delta| (level as i8).wrapping_add(*delta) as u8);
+        }
+        w.write_all(&block)?;
+        entries.push(seek::Entry {
+            start_frame: start as u32,
+            offset: offset,
+            len: block.len() as u32,
+            levels: block_levels,
+        });
+        offset += block.len() as u64;
+    }
+    seek::write(&entries, offset, w)
+}
+
+// Everything following the version, so the encoding can be shared with the container format.
+pub fn write_clip<W: Write>(mocap: &Mocap, limits: Limits, w: &mut W) -> io::Result<()> {
+    write_clip_layout(mocap, LAYOUT_PACKED, limits, w)
+}
+
+fn write_clip_layout<W: Write>(mocap: &Mocap, layout: u8, limits: Limits, w: &mut W) -> io::Result<()> {
+    let channels = mocap.channels();
+    let periodic = encode_periodic(mocap, limits);
+    write_header(mocap, &periodic, layout, w)?;
+
+    let delta_layout = if layout == LAYOUT_BIT_PLANES { bitpack::Layout::BitPlanes } else { bitpack::Layout::Packed };
+    if mocap.num_frames > 0 {
+        w.write_all(&mocap.num_frames.to_le_bytes())?;
+        let mut packed = Vec::new();
+        for (channel, periodic) in channels.iter().zip(periodic.iter()) {
+            if periodic.is_none() && channel.values.is_none() && layout != LAYOUT_SPARSE {
+                bitpack::pack(&channel.deltas, channel.bits(mocap.channel_quantization_bits), delta_layout, &mut packed);
+            }
+        }
+        w.write_all(&packed)?;
+    }
+
+    Ok(())
+}
+
+// Each channel's periodic encoding, if it's stored that way, in flat channel order; searched for
+// within the time budget (see periodic.rs).
+fn encode_periodic(mocap: &Mocap, limits: Limits) -> Vec<Option<Periodic>> {
+    if mocap.num_frames > 0 {
+        let search = periodic::Search::start(limits);
+        mocap.channels().iter().map(|channel| search.encode(channel, mocap.channel_quantization_bits)).collect()
+    } else {
+        Vec::new()
+    }
+}
+
+// Everything up to the deltas, with every channel's deltas to follow in the blocks.
+pub fn write_clip_header<W: Write>(mocap: &Mocap, w: &mut W) -> io::Result<()> {
+    write_header(mocap, &[], LAYOUT_PACKED, w)
+}
+
+// `periodic` is in flat channel order, the channels it leaves out stored in the blocks, or with
+// LAYOUT_SPARSE in the sparse track.
+fn write_header<W: Write>(mocap: &Mocap, periodic: &[Option<Periodic>], layout: u8, w: &mut W) -> io::Result<()> {
+    let sparse = layout == LAYOUT_SPARSE;
+    w.write_all(&mocap.num_frames.to_le_bytes())?;
+    w.write_all(&mocap.frame_time.to_le_bytes())?;
+    w.write_all(&[mocap.channel_quantization_bits, layout])?;
+    w.write_all(&u16_len(mocap.metadata.len(), || "metadata entry count".into())?.to_le_bytes())?;
+    for (key, value) in mocap.metadata.iter() {
+        write_string(key, w)?;
+        write_described_string(value, || format!("length of the {} metadata value", key), w)?;
+    }
+    w.write_all(&(mocap.markers.len() as u32).to_le_bytes())?;
+    for (frame, name) in mocap.markers.iter() {
+        w.write_all(&frame.to_le_bytes())?;
+        write_string(name, w)?;
+    }
+    w.write_all(&(mocap.timestamps.len() as u32).to_le_bytes())?;
+    for time in mocap.timestamps.iter() {
+        w.write_all(&time.to_le_bytes())?;
+    }
+    write_joint(&mocap.root, &mut periodic.iter(), w)?;
+
+    if sparse {
+        let channels = mocap.channels().into_iter().enumerate()
+            .filter(|(index, channel)| periodic.get(*index).is_none_or(Option::is_none) && channel.values.is_none())
+            .map(|(_, channel)| channel)
+            .collect::<Vec<_>>();
+        write_sparse_track(&channels, mocap.num_frames as usize, w)?;
+    }
+    Ok(())
+}
+
+fn write_sparse_track<W: Write>(channels: &[&Channel], num_frames: usize, w: &mut W) -> io::Result<()> {
+    if channels.len() > u16::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("a sparse track can hold at most {} channels, not {}", u16::MAX, channels.len())));
+    }
+    let mut levels = channels.iter().map(|channel| channel.initial_level).collect::<Vec<_>>();
+    let mut changes = Vec::new();
+    for frame in 0..num_frames {
+        changes.clear();
+        for (index, (channel, level)) in channels.iter().zip(levels.iter_mut()).enumerate() {
+            let delta = channel.deltas[frame];
+            if delta != 0 {
+                *level = (*level as i8).wrapping_add(delta) as u8;
+                changes.push((index as u16, *level));
+            }
+        }
+        w.write_all(&(changes.len() as u16).to_le_bytes())?;
+        for (index, level) in changes.iter() {
+            w.write_all(&index.to_le_bytes())?;
+            w.write_all(&[*level])?;
+        }
+    }
+    Ok(())
+}
+
+fn write_joint<'a, W: Write, I: Iterator<Item = &'a Option<Periodic>>>(joint: &Joint, periodic: &mut I, w: &mut W) -> io::Result<()> {
+    write_string(&joint.name, w)?;
+    match joint.original_name {
+        Some(ref original_name) => {
+            w.write_all(&[1])?;
+            write_string(original_name, w)?;
+        }
+        None => w.write_all(&[0])?,
+    }
+    write_offset(&joint.offset, w)?;
+
+    if joint.channels.len() > u8::MAX as usize {
+        return Err(too_long(joint.channels.len(), u8::MAX as usize, &format!("channel count of {}", joint.name)));
+    }
+    w.write_all(&[joint.channels.len() as u8])?;
+    for channel in joint.channels.iter() {
+        w.write_all(&[channel_type_id(channel.type_)])?;
+        w.write_all(&channel.reference.to_le_bytes())?;
+        w.write_all(&channel.value_range_min.to_le_bytes())?;
+        w.write_all(&channel.value_range.to_le_bytes())?;
+        w.write_all(&[channel.initial_level])?;
+        match channel.clamp {
+            Some((min, max)) => {
+                w.write_all(&[1])?;
+                w.write_all(&min.to_le_bytes())?;
+                w.write_all(&max.to_le_bytes())?;
+            }
+            None => w.write_all(&[0])?,
+        }
+        match channel.anchor_level {
+            Some(level) => w.write_all(&[1, level])?,
+            None => w.write_all(&[0])?,
+        }
+        w.write_all(&[channel.bits.unwrap_or(0)])?;
+        match (periodic.next(), &channel.values) {
+            (_, Some(values)) if !values.is_empty() && values.iter().all(|value| value.to_bits() == values[0].to_bits()) => {
+                w.write_all(&[4])?;
+                w.write_all(&values[0].to_le_bytes())?;
+            }
+            (_, Some(values)) => {
+                w.write_all(&[2])?;
+                for value in values.iter() {
+                    w.write_all(&value.to_le_bytes())?;
+                }
+            }
+            (Some(Some(periodic)), None) if periodic.levels.len() == 1 && periodic.corrections.is_empty() => w.write_all(&[3, periodic.levels[0]])?,
+            (Some(Some(periodic)), None) => {
+                w.write_all(&[1])?;
+                w.write_all(&(periodic.levels.len() as u32).to_le_bytes())?;
+                w.write_all(&periodic.levels)?;
+                w.write_all(&(periodic.corrections.len() as u32).to_le_bytes())?;
+                for (frame, level) in periodic.corrections.iter() {
+                    w.write_all(&frame.to_le_bytes())?;
+                    w.write_all(&[*level])?;
+                }
+            }
+            _ => w.write_all(&[0])?,
+        }
+    }
+
+    match joint.children {
+        JointChildren::Joints(ref joints) => {
+            w.write_all(&[0])?;
+            w.write_all(&u16_len(joints.len(), || format!("child count of {}", joint.name))?.to_le_bytes())?;
+            for joint in joints.iter() {
+                write_joint(joint, periodic, w)?;
+            }
+        }
+        JointChildren::EndSite(ref offset) => {
+            w.write_all(&[1])?;
+            write_offset(offset, w)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn write_string<W: Write>(s: &str, w: &mut W) -> io::Result<()> {
+    write_described_string(s, || format!("length of the string \"{}...\"", s.chars().take(20).collect::<String>()), w)
+}
+
+// `write_string`, with `what` describing the string's length if it's too long.
+pub fn write_described_string<W: Write, F: FnOnce() -> String>(s: &str, what: F, w: &mut W) -> io::Result<()> {
+    w.write_all(&u16_len(s.len(), what)?.to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+// `len` as a u16 count or length, or an `InvalidInput` error if it doesn't fit; `what` describes
+// it for the error.
+pub fn u16_len<F: FnOnce() -> String>(len: usize, what: F) -> io::Result<u16> {
+    if len > u16::MAX as usize {
+        return Err(too_long(len, u16::MAX as usize, &what()));
+    }
+    Ok(len as u16)
+}
+
+fn too_long(len: usize, max: usize, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("the {} is {}, more than the format's limit of {}", what, len, max))
+}
+
+fn write_offset<W: Write>(offset: &(f32, f32, f32), w: &mut W) -> io::Result<()> {
+    w.write_all(&offset.0.to_le_bytes())?;
+    w.write_all(&offset.1.to_le_bytes())?;
+    w.write_all(&offset.2.to_le_bytes())
+}
+
+// Reads a .raw file, checking but otherwise ignoring any seek index. Fails on joints deeper than
+// `max_depth` (see depth.rs).
+pub fn read(data: &[u8], max_depth: usize) -> Result<Mocap, MocapError> {
+    let mut reader = Reader::with_max_depth(data, max_depth);
+    read_magic(&mut reader)?;
+    let (mocap, blocks, block_channels) = read_clip_blocks(&mut reader)?;
+    if reader.remaining() > 0 {
+        seek::read(&mut reader, &blocks, &block_bits(&mocap, &block_channels))?;
+    }
+    reader.finish()?;
+
+    Ok(mocap)
+}
+
+pub fn read_magic(reader: &mut Reader) -> Result<(), MocapError> {
+    if reader.bytes(4)? != MAGIC {
+        return Err(MocapError::InvalidRaw("not a mocap raw file".into()));
+    }
+    let version = reader.u8()?;
+    if version != FORMAT_VERSION {
+        return Err(MocapError::InvalidRaw(format!("unsupported format version {}", version)));
+    }
+    Ok(())
+}
+
+pub fn read_clip(reader: &mut Reader) -> Result<Mocap, MocapError> {
+    Ok(read_clip_blocks(reader)?.0)
+}
+
+// A run of one channel's deltas in one of a clip's blocks, for patches (see patch.rs) and delta
+// chain analysis (drift.rs): the block's index and frames, the channel's flat index, and where its
+// packed deltas are.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Run {
+    pub block: usize,
+    pub start_frame: u32,
+    pub num_frames: u32,
+    pub channel: usize,
+    pub offset: usize,
+    pub len: usize,
+}
+
+// `read_clip`, also returning the runs of deltas in its blocks, in file order.
+pub fn read_clip_runs(reader: &mut Reader) -> Result<(Mocap, Vec<Run>), MocapError> {
+    let (mocap, blocks, block_channels) = read_clip_blocks(reader)?;
+    let bits = block_bits(&mocap, &block_channels);
+    let mut runs = Vec::new();
+    for (index, &(start_frame, offset, _)) in blocks.iter().enumerate() {
+        let num_frames = blocks.get(index + 1).map_or(mocap.num_frames, |block| block.0) - start_frame;
+        let mut offset = offset as usize + 4;
+        for (channel, bits) in block_channels.iter().zip(bits.iter()) {
+            let len = bitpack::packed_len(num_frames as usize, *bits);
+            runs.push(Run {
+                block: index,
+                start_frame: start_frame,
+                num_frames: num_frames,
+                channel: *channel,
+                offset: offset,
+                len: len,
+            });
+            offset += len;
+        }
+    }
+    Ok((mocap, runs))
+}
+
+// `read_clip`, also returning where every block is and the flat indices of the channels stored in
+// them, for checking a seek index with.
+fn read_clip_blocks(reader: &mut Reader) -> Result<(Mocap, Vec<seek::Block>, Vec<usize>), MocapError> {
+    let (mut ret, layout) = read_clip_header(reader)?;
+    let block_channels = ret.channels().into_iter().enumerate().filter(|(_, channel)| is_in_blocks(channel)).map(|(index, _)| index).collect::<Vec<_>>();
+
+    let num_frames = ret.num_frames;
+    let clip_bits = ret.channel_quantization_bits;
+    let mut channels = ret.channels_mut().into_iter().filter(|channel| is_in_blocks(channel)).collect::<Vec<_>>();
+    let mut levels = channels.iter().map(|channel| channel.initial_level).collect::<Vec<_>>();
+    let mut blocks = Vec::new();
+    let mut remaining = num_frames;
+    while remaining > 0 {
+        let offset = reader.position();
+        let block_frames = read_block_frames(reader, remaining)?;
+        for (channel, level) in channels.iter_mut().zip(levels.iter_mut()) {
+            let bits = channel.bits(clip_bits);
+            let deltas = &mut channel.deltas;
+            let len = block_frames as usize;
+            bitpack::unpack(reader.bytes(bitpack::packed_len(len, bits))?, len, len, bits, layout, level, |delta| deltas.push(delta));
+        }
+        blocks.push((num_frames - remaining, offset as u64, (reader.position() - offset) as u32));
+        remaining -= block_frames;
+    }
+
+    prediction::decode(&mut ret)?;
+    Ok((ret, blocks, block_channels))
+}
+
+// The bit depth of each channel stored in the blocks, `block_channels` being their flat indices.
+fn block_bits(mocap: &Mocap, block_channels: &[usize]) -> Vec<u8> {
+    let channels = mocap.channels();
+    block_channels.iter().map(|index| channels[*index].bits(mocap.channel_quantization_bits)).collect()
+}
+
+// Everything up to the delta blocks, and how the deltas in them are stored. Channels stored in the
+// blocks are left without deltas; periodic and sparse ones come with theirs, expanded, and
+// lossless ones with their values (see `is_in_blocks`).
+pub fn read_clip_header(reader: &mut Reader) -> Result<(Mocap, bitpack::Layout), MocapError> {
+    let num_frames = reader.u32()?;
+    let frame_time = reader.f32()?;
+    let channel_quantization_bits = reader.u8()?;
+    if num_levels(channel_quantization_bits).is_none() {
+        return Err(MocapError::InvalidRaw(format!("invalid channel quantization bits {}", channel_quantization_bits)));
+    }
+    let (sparse, layout) = match reader.u8()? {
+        LAYOUT_PACKED => (false, bitpack::Layout::Packed),
+        LAYOUT_SPARSE => (true, bitpack::Layout::Packed),
+        LAYOUT_BIT_PLANES => (false, bitpack::Layout::BitPlanes),
+        layout => return Err(MocapError::InvalidRaw(format!("invalid layout {}", layout))),
+    };
+
+    let num_metadata = reader.u16()?;
+    let mut metadata = Vec::with_capacity(reader.capacity(num_metadata as usize, 4));
+    for _ in 0..num_metadata {
+        metadata.push((reader.string()?, reader.string()?));
+    }
+
+    let num_markers = reader.u32()?;
+    let mut markers = Vec::new();
+    for _ in 0..num_markers {
+        let (frame, name) = (reader.u32()?, reader.string()?);
+        if frame >= num_frames {
+            return Err(MocapError::InvalidRaw(format!("marker {} at frame {} is past the last frame ({} frames)", name, frame, num_frames)));
+        }
+        if markers.last().is_some_and(|previous: &Marker| previous.0 > frame) {
+            return Err(MocapError::InvalidRaw(format!("marker {} at frame {} is out of order", name, frame)));
+        }
+        markers.push((frame, name));
+    }
+
+    let num_timestamps = reader.u32()?;
+    if num_timestamps != 0 && num_timestamps != num_frames {
+        return Err(MocapError::InvalidRaw(format!("{} timestamps for {} frames", num_timestamps, num_frames)));
+    }
+    let mut timestamps = Vec::with_capacity(reader.capacity(num_timestamps as usize, 8));
+    for _ in 0..num_timestamps {
+        timestamps.push(reader.f64()?);
+    }
+    if !timing::is_valid(&timestamps) {
+        return Err(MocapError::InvalidRaw("timestamps aren't finite and increasing".into()));
+    }
+
+    let mut periodic = Vec::new();
+    let mut root = read_joint(reader, num_frames, 1, &mut periodic)?;
+
+    let mut channels = Vec::new();
+    collect_channels_mut(&mut root, &mut channels);
+    if sparse {
+        let mut sparse_channels = channels.iter_mut().zip(periodic.iter()).filter(|(channel, periodic)| periodic.is_none() && channel.values.is_none()).map(|(channel, _)| &mut **channel).collect::<Vec<_>>();
+        read_sparse_track(reader, num_frames, channel_quantization_bits, &mut sparse_channels)?;
+    }
+    let num_periodic_channels = periodic.iter().filter(|periodic| periodic.is_some()).count();
+    let block_channels = channels.iter().zip(periodic.iter()).filter(|(channel, periodic)| is_in_blocks(channel) && periodic.is_none()).map(|(channel, _)| channel).collect::<Vec<_>>();
+    let num_block_channels = block_channels.len();
+    let block_len = block_channels.iter().try_fold(0u64, |len, channel| {
+        (bitpack::packed_len(num_frames as usize, channel.bits(channel_quantization_bits)) as u64).checked_add(len)
+    }).ok_or_else(|| MocapError::Overflow(format!("{} frames of {} channels", num_frames, num_block_channels)))?;
+    if block_len > reader.remaining() as u64 {
+        return Err(MocapError::InvalidRaw(format!("{} frames of {} channels don't fit the {} bytes left", num_frames, num_block_channels, reader.remaining())));
+    }
+    let is_constant_lossless = |channel: &Channel| num_frames > 1 && channel.values.as_ref().is_some_and(|values| values.len() == 1);
+    let num_constant_lossless_channels = channels.iter().filter(|channel| is_constant_lossless(channel)).count();
+    if (num_frames as u64) * ((num_periodic_channels + 8 * num_constant_lossless_channels) as u64) > (MAX_PERIODIC_EXPANSION as u64) * (reader.len() as u64) {
+        return Err(MocapError::InvalidRaw(format!("{} frames of {} periodic or constant channels is implausibly many for a {} byte file", num_frames, num_periodic_channels + num_constant_lossless_channels, reader.len())));
+    }
+    for (channel, periodic) in channels.into_iter().zip(periodic.iter()) {
+        if let Some(ref periodic) = *periodic {
+            channel.deltas = periodic.deltas(num_frames as usize, channel.initial_level);
+        }
+        if is_constant_lossless(channel) {
+            let value = channel.values.as_ref().unwrap()[0];
+            channel.values = Some(vec![value; num_frames as usize]);
+        }
+    }
+
+    let mocap = Mocap {
+        num_frames: num_frames,
+        frame_time: frame_time,
+        channel_quantization_bits: channel_quantization_bits,
+        root: root,
+        metadata: metadata,
+        markers: markers,
+        timestamps: timestamps,
+    };
+    Ok((mocap, layout))
+}
+
+// Fills in the deltas of the channels in a sparse track.
+fn read_sparse_track(reader: &mut Reader, num_frames: u32, bits: u8, channels: &mut [&mut Channel]) -> Result<(), MocapError> {
+    if (num_frames as u64) * 2 > reader.remaining() as u64 {
+        return Err(MocapError::InvalidRaw(format!("a sparse track of {} frames doesn't fit the {} bytes left", num_frames, reader.remaining())));
+    }
+    if (num_frames as u64) * (channels.len() as u64) > (MAX_PERIODIC_EXPANSION as u64) * (reader.len() as u64) {
+        return Err(MocapError::InvalidRaw(format!("{} frames of {} sparse channels is implausibly many for a {} byte file", num_frames, channels.len(), reader.len())));
+    }
+    let max_levels = channels.iter().map(|channel| max_level(channel.bits(bits))).collect::<Vec<_>>();
+    let mut levels = channels.iter().map(|channel| channel.initial_level).collect::<Vec<_>>();
+    let mut deltas = vec![0; channels.len()];
+    for channel in channels.iter_mut() {
+        channel.deltas.reserve(num_frames as usize);
+    }
+    for frame in 0..num_frames {
+        let num_changes = reader.u16()?;
+        let mut previous_index = None;
+        for delta in deltas.iter_mut() {
+            *delta = 0;
+        }
+        for _ in 0..num_changes {
+            let (index, level) = (reader.u16()?, reader.u8()?);
+            if index as usize >= channels.len() || previous_index.is_some_and(|previous| index <= previous) || level > max_levels[index as usize] {
+                return Err(MocapError::InvalidRaw(format!("invalid sparse track change of channel {} to level {} at frame {}", index, level, frame)));
+            }
+            previous_index = Some(index);
+            let index = index as usize;
+            deltas[index] = (level as i8).wrapping_sub(levels[index] as i8);
+            levels[index] = level;
+        }
+        for (channel, delta) in channels.iter_mut().zip(deltas.iter()) {
+            channel.deltas.push(*delta);
+        }
+    }
+    Ok(())
+}
+
+// Whether a clip is a static pose: it has frames and none of its channels change. Such a clip is
+// stored as a single frame of constant channels.
+pub fn is_static(mocap: &Mocap) -> bool {
+    mocap.num_frames > 0 && mocap.channels().iter().all(|channel| match channel.values {
+        Some(ref values) => values.iter().all(|value| value.to_bits() == values[0].to_bits()),
+        None => periodic::levels(channel).windows(2).all(|pair| pair[0] == pair[1]),
+    })
+}
+
+// Whether a channel returned by `read_clip_header` is stored in the blocks, rather than being
+// periodic, sparse or lossless.
+pub fn is_in_blocks(channel: &Channel) -> bool {
+    channel.deltas.is_empty() && channel.values.is_none()
+}
+
+// The frame count starting a delta block, `remaining` being the frames not read yet.
+pub fn read_block_frames(reader: &mut Reader, remaining: u32) -> Result<u32, MocapError> {
+    let block_frames = reader.u32()?;
+    if block_frames == 0 || block_frames > remaining {
+        return Err(MocapError::InvalidRaw(format!("invalid block of {} frames with {} frames left", block_frames, remaining)));
+    }
+    Ok(block_frames)
+}
+
+// `periodic` gets each channel's periodic encoding, if it has one, in flat channel order; they're
+// expanded once the frame count has been checked.
+// `depth` is the joint's, the root's being 1.
+fn read_joint(reader: &mut Reader, num_frames: u32, depth: usize, periodic: &mut Vec<Option<Periodic>>) -> Result<Joint, MocapError> {
+    depth::check(depth, reader.max_depth)?;
+    let name = reader.string()?;
+    let original_name = match reader.u8()? {
+        0 => None,
+        _ => Some(reader.string()?),
+    };
+    let offset = reader.offset()?;
+
+    let num_channels = reader.u8()?;
+    let mut channels = Vec::with_capacity(num_channels as usize);
+    for _ in 0..num_channels {
+        let type_id = reader.u8()?;
+        channels.push(Channel {
+            type_: channel_type_from_id(type_id).ok_or_else(|| MocapError::InvalidRaw(format!("invalid channel type {}", type_id)))?,
+            reference: reader.f64()?,
+            value_range_min: reader.f32()?,
+            value_range: reader.f32()?,
+            initial_level: reader.u8()?,
+            clamp: match reader.u8()? {
+                0 => None,
+                _ => Some((reader.f64()?, reader.f64()?)),
+            },
+            anchor_level: match reader.u8()? {
+                0 => None,
+                _ => Some(reader.u8()?),
+            },
+            bits: match reader.u8()? {
+                0 => None,
+                bits if num_levels(bits).is_some() => Some(bits),
+                bits => return Err(MocapError::InvalidRaw(format!("invalid channel bits {}", bits))),
+            },
+            values: None,
+            deltas: Vec::new(),
+        });
+        periodic.push(match reader.u8()? {
+            0 => None,
+            1 => Some(read_periodic(reader, num_frames)?),
+            2 => {
+                if (num_frames as u64) * 8 > reader.remaining() as u64 {
+                    return Err(MocapError::InvalidRaw(format!("{} lossless values don't fit the {} bytes left", num_frames, reader.remaining())));
+                }
+                let mut values = Vec::with_capacity(num_frames as usize);
+                for _ in 0..num_frames {
+                    values.push(reader.f64()?);
+                }
+                channels.last_mut().unwrap().values = Some(values);
+                None
+            }
+            3 => {
+                if num_frames == 0 {
+                    return Err(MocapError::InvalidRaw("a constant channel in a clip without frames".into()));
+                }
+                Some(Periodic {
+                    levels: vec![reader.u8()?],
+                    corrections: Vec::new(),
+                })
+            }
+            4 => {
+                if num_frames == 0 {
+                    return Err(MocapError::InvalidRaw("a constant channel in a clip without frames".into()));
+                }
+                // Expanded by `read_clip_header` once every channel is known
+                channels.last_mut().unwrap().values = Some(vec![reader.f64()?]);
+                None
+            }
+            storage => return Err(MocapError::InvalidRaw(format!("invalid channel storage {}", storage))),
+        });
+    }
+
+    let children = match reader.u8()? {
+        0 => {
+            let num_joints = reader.u16()?;
+            let mut joints = Vec::with_capacity(reader.capacity(num_joints as usize, MIN_JOINT_SIZE));
+            for _ in 0..num_joints {
+                joints.push(read_joint(reader, num_frames, depth + 1, periodic)?);
+            }
+            JointChildren::Joints(joints)
+        }
+        1 => JointChildren::EndSite(reader.offset()?),
+        kind => return Err(MocapError::InvalidRaw(format!("invalid joint children kind {}", kind))),
+    };
+
+    Ok(Joint {
+        name: name,
+        original_name: original_name,
+        offset: offset,
+        channels: channels,
+        children: children,
+    })
+}
+
+// Name length, original name flag, offset, channel count and children kind
+const MIN_JOINT_SIZE: usize = 2 + 1 + 12 + 1 + 1;
+
+fn read_periodic(reader: &mut Reader, num_frames: u32) -> Result<Periodic, MocapError> {
+    let period = reader.u32()?;
+    if period == 0 || period > num_frames {
+        return Err(MocapError::InvalidRaw(format!("invalid period of {} frames in a clip of {}", period, num_frames)));
+    }
+    let levels = reader.bytes(period as usize)?.to_vec();
+
+    let num_corrections = reader.u32()?;
+    let mut corrections: Vec<(u32, u8)> = Vec::new();
+    for _ in 0..num_corrections {
+        let frame = reader.u32()?;
+        if frame < period || frame >= num_frames || corrections.last().is_some_and(|last| frame <= last.0) {
+            return Err(MocapError::InvalidRaw(format!("invalid periodic channel correction at frame {}", frame)));
+        }
+        corrections.push((frame, reader.u8()?));
+    }
+
+    Ok(Periodic {
+        levels: levels,
+        corrections: corrections,
+    })
+}
+
+pub fn channel_type_id(type_: ChannelType) -> u8 {
+    match type_ {
+        ChannelType::TranslationX => 0,
+        ChannelType::TranslationY => 1,
+        ChannelType::TranslationZ => 2,
+        ChannelType::RotationX => 3,
+        ChannelType::RotationY => 4,
+        ChannelType::RotationZ => 5,
+    }
+}
+
+pub fn channel_type_from_id(id: u8) -> Option<ChannelType> {
+    match id {
+        0 => Some(ChannelType::TranslationX),
+        1 => Some(ChannelType::TranslationY),
+        2 => Some(ChannelType::TranslationZ),
+        3 => Some(ChannelType::RotationX),
+        4 => Some(ChannelType::RotationY),
+        5 => Some(ChannelType::RotationZ),
+        _ => None,
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+    max_depth: usize, // Joints deeper fail to read (see depth.rs)
+}
+
+impl<'a> Reader<'a> {
+    // A reader of data without a hierarchy, or one at most the default depth.
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader::with_max_depth(data, depth::DEFAULT_MAX_DEPTH)
+    }
+
+    pub fn with_max_depth(data: &'a [u8], max_depth: usize) -> Reader<'a> {
+        Reader {
+            data: data,
+            position: 0,
+            max_depth: max_depth,
+        }
+    }
+
+    // Fails if anything is left unread.
+    pub fn finish(&self) -> Result<(), MocapError> {
+        if self.position != self.data.len() {
+            return Err(MocapError::InvalidRaw(format!("{} unexpected bytes after the channel data", self.data.len() - self.position)));
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    // How many of `count` items of at least `min_size` bytes each to preallocate for: no more than
+    // the rest of the data could hold.
+    pub fn capacity(&self, count: usize, min_size: usize) -> usize {
+        count.min(self.remaining() / min_size.max(1))
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], MocapError> {
+        if self.data.len() - self.position < len {
+            return Err(MocapError::InvalidRaw(format!("unexpected end of file at byte {}", self.data.len())));
+        }
+        let ret = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(ret)
+    }
+
+    fn array<T: Default + AsMut<[u8]>>(&mut self) -> Result<T, MocapError> {
+        let mut ret = T::default();
+        let len = ret.as_mut().len();
+        ret.as_mut().copy_from_slice(self.bytes(len)?);
+        Ok(ret)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, MocapError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, MocapError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, MocapError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, MocapError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, MocapError> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    pub fn f64(&mut self) -> Result<f64, MocapError> {
+        Ok(f64::from_le_bytes(self.array()?))
+    }
+
+    pub fn string(&mut self) -> Result<String, MocapError> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| MocapError::InvalidRaw("invalid UTF-8 in string".into()))
+    }
+
+    fn offset(&mut self) -> Result<(f32, f32, f32), MocapError> {
+        Ok((self.f32()?, self.f32()?, self.f32()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conversion::ConversionSettings;
+    use depth::DEFAULT_MAX_DEPTH;
+    use test_util;
+    use view::MocapView;
+    use {build_bvh, build_mocap};
+
+    fn raw_bytes(bvh: &bvh::Bvh) -> Vec<u8> {
+        let mut ret = Vec::new();
+        write(&build_mocap(bvh, &ConversionSettings::default().settings()), Limits::default(), &mut ret).unwrap();
+        ret
+    }
+
+    fn with_num_frames(mut data: Vec<u8>, num_frames: u32) -> Vec<u8> {
+        let offset = NUM_FRAMES_OFFSET as usize;
+        data[offset..offset + 4].copy_from_slice(&num_frames.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn keeps_each_joints_channel_order() {
+        let hierarchy = test_util::HIERARCHY
+            .replacen("CHANNELS 6 Xposition Yposition Zposition Zrotation Xrotation Yrotation", "CHANNELS 6 Yrotation Xposition Zrotation Zposition Xrotation Yposition", 1)
+            .replacen("CHANNELS 3 Zrotation Xrotation Yrotation", "CHANNELS 3 Xrotation Yrotation Zrotation", 1);
+        let bvh = test_util::parse(&test_util::motion_text(&hierarchy, test_util::NUM_CHANNELS, 10, test_util::sine));
+        let decoded = build_bvh(&read(&raw_bytes(&bvh), DEFAULT_MAX_DEPTH).unwrap());
+
+        let mut serialized = Vec::new();
+        bvh::serialize(&decoded, &mut serialized).unwrap();
+        let channel_lines = |text: &str| text.lines().filter(|line| line.trim_start().starts_with("CHANNELS")).map(|line| line.trim().to_string()).collect::<Vec<_>>();
+        assert_eq!(channel_lines(&String::from_utf8(serialized).unwrap()), vec![
+            "CHANNELS 6 Yrotation Xposition Zrotation Zposition Xrotation Yposition",
+            "CHANNELS 3 Xrotation Yrotation Zrotation",
+            "CHANNELS 3 Zrotation Xrotation Yrotation",
+            "CHANNELS 3 Zrotation Xrotation Yrotation",
+        ]);
+    }
+
+    #[test]
+    fn refuses_a_frame_count_the_blocks_cant_hold() {
+        let data = raw_bytes(&test_util::sine_clip(40));
+        assert_eq!(read(&data, DEFAULT_MAX_DEPTH).unwrap().num_frames, 40);
+        for num_frames in [4_000_000_000, 1 << 20, data.len() as u32].iter() {
+            match read(&with_num_frames(data.clone(), *num_frames), DEFAULT_MAX_DEPTH) {
+                Err(MocapError::InvalidRaw(message)) => assert!(message.contains("don't fit"), "{}", message),
+                other => panic!("{} frames: {:?}", num_frames, other.map(|mocap| mocap.num_frames)),
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_a_frame_count_periodic_channels_would_expand_past_the_cap() {
+        // Every channel constant, so all of them are stored in the header with nothing in the blocks
+        let data = raw_bytes(&test_util::parse(&test_util::clip_text(40, |_, channel| channel as f64)));
+        assert_eq!(read(&data, DEFAULT_MAX_DEPTH).unwrap().num_frames, 40);
+        match read(&with_num_frames(data, 4_000_000_000), DEFAULT_MAX_DEPTH) {
+            Err(MocapError::InvalidRaw(message)) => assert!(message.contains("implausibly many"), "{}", message),
+            other => panic!("{:?}", other.map(|mocap| mocap.num_frames)),
+        }
+    }
+
+    // The sine clip at 4 bits, with Hips' channels at 8 bits of their own, as reencode leaves it
+    fn mixed_depth_clip() -> Mocap {
+        let bvh = test_util::sine_clip(45);
+        let mut settings = ConversionSettings::default().settings();
+        let fine = build_mocap(&bvh, &settings);
+        settings.channel_quantization_bits = 4;
+        let mut ret = build_mocap(&bvh, &settings);
+        for (channel, fine) in ret.channels_mut().into_iter().zip(fine.channels()).take(6) {
+            *channel = Channel {
+                bits: Some(8),
+                ..fine.clone()
+            };
+        }
+        ret
+    }
+
+    #[test]
+    fn channels_keep_their_own_bit_depth_in_every_layout() {
+        let mocap = mixed_depth_clip();
+        let expected = build_bvh(&mocap).motion.frames;
+        type WriteRaw = fn(&Mocap, &mut Vec<u8>) -> io::Result<()>;
+        let writes: [WriteRaw; 4] = [
+            |mocap, w| write(mocap, Limits::default(), w),
+            |mocap, w| write_indexed(mocap, 8, bitpack::Layout::Packed, Limits::default(), w),
+            |mocap, w| write_sparse(mocap, Limits::default(), w),
+            |mocap, w| write_indexed(mocap, 8, bitpack::Layout::BitPlanes, Limits::default(), w),
+        ];
+        for write in writes.iter() {
+            let mut data = Vec::new();
+            write(&mocap, &mut data).unwrap();
+            let read = read(&data, DEFAULT_MAX_DEPTH).unwrap();
+            assert_eq!(read.channels().iter().map(|channel| channel.bits).collect::<Vec<_>>(), mocap.channels().iter().map(|channel| channel.bits).collect::<Vec<_>>());
+            assert_eq!(build_bvh(&read).motion.frames, expected);
+
+            let view = MocapView::parse(&data, DEFAULT_MAX_DEPTH).unwrap();
+            assert_eq!(view.to_bvh(1).motion.frames, expected);
+            assert_eq!(view.to_bvh(3).motion.frames, expected);
+            for frame in [0, 7, 8, 30, 44].iter() {
+                assert_eq!(view.decode_frame_at(*frame).unwrap(), expected[*frame]);
+            }
+        }
+    }
+
+    // A clip holding still but for Spine's X rotation, which ramps up over 10 frames and back down
+    // over the last 10
+    #[test]
+    fn sparse_track_holds_only_the_moving_channels_changes() {
+        let bvh = test_util::parse(&test_util::clip_text(60, |frame, channel| match channel {
+            7 => (frame.min(30).saturating_sub(20) as f64 - frame.saturating_sub(50) as f64) * 3.0,
+            _ => test_util::sine(0, channel),
+        }));
+        let mocap = build_mocap(&bvh, &ConversionSettings::default().settings());
+        let (mut sparse, mut dense) = (Vec::new(), Vec::new());
+        write_sparse(&mocap, Limits::default(), &mut sparse).unwrap();
+        write(&mocap, Limits::default(), &mut dense).unwrap();
+        assert!(is_sparse(&sparse) && !is_sparse(&dense));
+
+        // Unchanged channels hold their levels through the frames without changes
+        let expected = build_bvh(&mocap).motion.frames;
+        assert_eq!(build_bvh(&read(&sparse, DEFAULT_MAX_DEPTH).unwrap()).motion.frames, expected);
+        let view = MocapView::parse(&sparse, DEFAULT_MAX_DEPTH).unwrap();
+        for frame in [0, 19, 25, 40, 59].iter() {
+            assert_eq!(view.decode_frame_at(*frame).unwrap(), expected[*frame]);
+        }
+
+        // The constant channels are in the header, so the track holds Spine alone, as index 0, listed
+        // with its new level on only the frames where that changes. The track ends the file but for
+        // the (empty) block's frame count.
+        let channel = &mocap.channels()[7];
+        let mut previous = channel.initial_level;
+        let changes = periodic::levels(channel).into_iter().enumerate().filter(|(_, level)| *level != ::std::mem::replace(&mut previous, *level)).collect::<Vec<_>>();
+        assert_eq!(changes.iter().map(|(frame, _)| *frame).collect::<Vec<_>>(), (21..31).chain(51..60).collect::<Vec<_>>());
+        let track_len = 2 * mocap.num_frames as usize + 3 * changes.len();
+        let mut reader = Reader::new(&sparse[sparse.len() - 4 - track_len..]);
+        let mut listed = Vec::new();
+        for frame in 0..mocap.num_frames as usize {
+            for _ in 0..reader.u16().unwrap() {
+                listed.push((frame, reader.u16().unwrap(), reader.u8().unwrap()));
+            }
+        }
+        assert_eq!(listed, changes.iter().map(|(frame, level)| (*frame, 0, *level)).collect::<Vec<_>>());
+        assert_eq!(reader.u32().unwrap(), mocap.num_frames);
+        assert_eq!(reader.remaining(), 0);
+    }
+
+    #[test]
+    fn refuses_a_channel_bit_depth_off_the_format() {
+        let mut mocap = mixed_depth_clip();
+        mocap.channels_mut()[0].bits = Some(9);
+        let mut data = Vec::new();
+        write(&mocap, Limits::default(), &mut data).unwrap();
+        match read(&data, DEFAULT_MAX_DEPTH) {
+            Err(MocapError::InvalidRaw(message)) => assert_eq!(message, "invalid channel bits 9"),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn refuses_a_metadata_value_longer_than_its_length_field() {
+        let mut mocap = build_mocap(&test_util::sine_clip(4), &ConversionSettings::default().settings());
+        mocap.metadata.push(("long".into(), "x".repeat(70000)));
+        let error = write(&mocap, Limits::default(), &mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), "the length of the long metadata value is 70000, more than the format's limit of 65535");
+
+        // The longest that fits reads back whole, with the frames after it
+        mocap.metadata[0].1 = "x".repeat(65535);
+        let mut data = Vec::new();
+        write(&mocap, Limits::default(), &mut data).unwrap();
+        let read = read(&data, DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(read.metadata, mocap.metadata);
+        assert_eq!(build_bvh(&read).motion.frames, build_bvh(&mocap).motion.frames);
+    }
+}
@@ -0,0 +1,349 @@
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+
+use bitpack;
+use bvh;
+
+use container::{self, Container};
+use error::MocapError;
+use lossless;
+use manifest;
+use mocap_diff;
+use options::Options;
+use profile::Lossless;
+use raw;
+use view::MocapView;
+use {build_bvh, build_mocap, count_bvh_channels, load, num_levels, Channel, ChannelType, Mocap, Settings};
+
+// `mocap reencode`: changes how some channels of an already encoded file are stored, without
+// converting the whole clip again. Each --bits-for names channels (a joint selector and a channel
+// type, or * for all of the joint's channels) and the bits to store them at. Only those channels
+// are quantized again, from the source clip given with --source (which must have the file's
+// skeleton and frame count) or else from the file's own decode; every other channel keeps its
+// encoding exactly, so its header and deltas are written back byte for byte, and so do the
+// clip's metadata, markers, attributes and reference pose. The output has the input's format: a
+// container stays a container, and a .raw file keeps its seek index or sparse track.
+//
+// A channel can be given any bit depth in [1, 8], which the file stores as the channel's own when
+// it isn't the clip's (see raw.rs), or 64, storing it losslessly. Giving it the depth it already
+// has quantizes it again, say after fixing the source, or turns a lossless channel back to
+// deltas. Without --source a channel given more bits than it had keeps the values it decoded to;
+// only the source has the precision to fill the finer grid. Quantizing a channel again uses the
+// --translation-reference and --rotation-anchor given, which may not be the ones the rest of the
+// clip was converted with.
+//
+// Every re-encoded clip gets a "reencode" metadata entry listing the --bits-for given (and the
+// source if any), so a partially re-encoded file can be told from a fresh conversion.
+
+// Metadata key recording a partial re-encode
+pub const KEY: &str = "reencode";
+
+// The bits a channel stored losslessly is given
+pub const LOSSLESS_BITS: u8 = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BitsFor {
+    pub selector: String,
+    pub type_: Option<ChannelType>, // Every channel of the joint if None
+    pub bits: u8,
+}
+
+impl BitsFor {
+    // `<joint>:<type|*>=<bits>`. The joint selector may itself contain colons; the type is after
+    // the last one.
+    pub fn parse(spec: &str) -> Option<BitsFor> {
+        let (channels, bits) = spec.rsplit_once('=')?;
+        let (selector, type_name) = channels.rsplit_once(':')?;
+        if selector.is_empty() {
+            return None;
+        }
+        Some(BitsFor {
+            selector: selector.into(),
+            type_: if type_name == "*" { None } else { Some(ChannelType::from_name(type_name)?) },
+            bits: bits.parse().ok()?,
+        })
+    }
+
+    pub fn spec(&self) -> String {
+        format!("{}:{}={}", self.selector, self.type_.map_or("*", |type_| type_.name()), self.bits)
+    }
+}
+
+pub fn run(input_file_name: &Path, output_file_name: &Path, options: &Options, cancel: Option<&AtomicBool>) -> Result<(), MocapError> {
+    let data = fs::read(input_file_name)?;
+    let is_raw = data.starts_with(raw::MAGIC);
+    let mut container = if is_raw {
+        Container {
+            reference_poses: Vec::new(),
+            clips: vec![container::Clip {
+                name: String::new(),
+                reference_pose: None,
+                attributes: Vec::new(),
+                thumbnail: None,
+                alias: None,
+                mocap: raw::read(&data, options.max_depth)?,
+            }],
+        }
+    } else {
+        container::read(&data, options.max_depth)?
+    };
+
+    let source = match options.reencode_source_file_name {
+        Some(ref source_file_name) => {
+            if container.clips.len() != 1 {
+                return Err(MocapError::Usage(format!("--source needs a file with one clip, {} has {}", input_file_name.display(), container.clips.len())));
+            }
+            Some(load(Path::new(source_file_name), options)?)
+        }
+        None => None,
+    };
+
+    let mut metadata = options.bits_for.iter().map(BitsFor::spec).collect::<Vec<_>>().join(",");
+    if let Some(ref source_file_name) = options.reencode_source_file_name {
+        metadata = format!("{} from {}", metadata, source_file_name);
+    }
+    for clip in container.clips.iter_mut() {
+        let settings = Settings {
+            channel_quantization_bits: clip.mocap.channel_quantization_bits,
+            ..options.conversion.settings()
+        };
+        let decoded = build_bvh(&clip.mocap);
+        let bvh = source.as_ref().map_or(&decoded, |source| &source.bvh);
+        let reencoded = build_mocap(bvh, &settings);
+        if source.is_some() {
+            check_source(&clip.mocap, &reencoded)?;
+        }
+        let bits = selected_bits(&decoded, &options.bits_for)?;
+        if bits.iter().all(Option::is_none) {
+            return Err(MocapError::Usage(format!("clip {}: --bits-for matches no channels", clip.name)));
+        }
+        // The clip quantized at each other depth asked for
+        let mut requantized = Vec::new();
+        for bits in bits.iter().flatten() {
+            if *bits != LOSSLESS_BITS && *bits != settings.channel_quantization_bits && !requantized.iter().any(|(depth, _)| depth == bits) {
+                requantized.push((*bits, build_mocap(bvh, &Settings { channel_quantization_bits: *bits, ..settings })));
+            }
+        }
+        let reencoded_channels = reencoded.channels();
+        let requantized = requantized.iter().map(|(bits, mocap)| (*bits, mocap.channels())).collect::<Vec<_>>();
+        for (index, (channel, bits)) in clip.mocap.channels_mut().into_iter().zip(bits.iter()).enumerate() {
+            let bits = match *bits {
+                Some(bits) => bits,
+                None => continue,
+            };
+            let clamp = channel.clamp;
+            *channel = match requantized.iter().find(|(depth, _)| *depth == bits) {
+                Some((_, channels)) => Channel {
+                    bits: Some(bits),
+                    ..channels[index].clone()
+                },
+                None => reencoded_channels[index].clone(),
+            };
+            channel.clamp = clamp;
+            if bits == LOSSLESS_BITS {
+                channel.values = Some(bvh.motion.frames.iter().map(|frame| frame[index]).collect());
+                channel.deltas = Vec::new();
+            }
+        }
+        if let Some(index) = clip.reference_pose {
+            container::apply_reference_pose(&mut clip.mocap, &container.reference_poses[index]);
+        }
+        clip.mocap.metadata.push((KEY.into(), metadata.clone()));
+        clip.mocap.validate()?;
+    }
+
+    let mut output = manifest::create(output_file_name)?;
+    if !is_raw {
+        container::write(&container, options.search_limits(cancel), &mut output)?;
+    } else if raw::is_sparse(&data) {
+        raw::write_sparse(&container.clips[0].mocap, options.search_limits(cancel), &mut output)?;
+    } else if let Some(entries) = MocapView::parse(&data, options.max_depth)?.seek_table() {
+        let block_frames = entries.get(1).map_or(container.clips[0].mocap.num_frames, |entry| entry.start_frame);
+        raw::write_indexed(&container.clips[0].mocap, block_frames as usize, raw::delta_layout(&data), options.search_limits(cancel), &mut output)?;
+    } else if raw::delta_layout(&data) == bitpack::Layout::BitPlanes {
+        raw::write_bit_planes(&container.clips[0].mocap, options.search_limits(cancel), &mut output)?;
+    } else {
+        raw::write(&container.clips[0].mocap, options.search_limits(cancel), &mut output)?;
+    }
+    Ok(())
+}
+
+// The source must have the encoded clip's skeleton and frame count for its channels to line up.
+fn check_source(mocap: &Mocap, source: &Mocap) -> Result<(), MocapError> {
+    let mut differences = Vec::new();
+    mocap_diff::hierarchy_differences(&mocap.root, &source.root, "", &mut differences);
+    if !differences.is_empty() {
+        return Err(MocapError::SkeletonMismatch(format!("the source's skeleton differs from the encoded clip's: {}", differences.join(", "))));
+    }
+    if source.num_frames != mocap.num_frames {
+        return Err(MocapError::SkeletonMismatch(format!("the source has {} frames, the encoded clip {}", source.num_frames, mocap.num_frames)));
+    }
+    Ok(())
+}
+
+// The bits `bits_for` gives each channel of `bvh`, the decoded clip, in flat channel order; None
+// for the channels it leaves alone. A later --bits-for overrides an earlier one.
+fn selected_bits(bvh: &bvh::Bvh, bits_for: &[BitsFor]) -> Result<Vec<Option<u8>>, MocapError> {
+    let mut ret = vec![None; count_bvh_channels(&bvh.hierarchy.root)];
+    for bits_for in bits_for.iter() {
+        if num_levels(bits_for.bits).is_none() && bits_for.bits != LOSSLESS_BITS {
+            return Err(MocapError::Usage(format!("--bits-for {}: bits must be in [1, 8], or {} to store the channels losslessly", bits_for.spec(), LOSSLESS_BITS)));
+        }
+        let selected = lossless::lossless_channels(bvh, &[Lossless {
+            selector: bits_for.selector.clone(),
+            type_: bits_for.type_,
+        }])?;
+        for (bits, selected) in ret.iter_mut().zip(selected) {
+            if selected {
+                *bits = Some(bits_for.bits);
+            }
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use depth::DEFAULT_MAX_DEPTH;
+    use periodic::Limits;
+    use raw::Reader;
+    use test_util;
+    use {max_level, RotationAnchor, TranslationReference};
+
+    const NUM_FRAMES: usize = 61;
+
+    // Writes a .raw file in one of the layouts
+    type WriteRaw = fn(&Mocap, &mut Vec<u8>);
+
+    fn settings(bits: u8) -> Settings {
+        Settings {
+            channel_quantization_bits: bits,
+            translation_reference: TranslationReference::None,
+            rotation_anchor: RotationAnchor::None,
+        }
+    }
+
+    // The sine clip as a source file and a 4-bit .raw file written by `write`.
+    fn files(dir: &Path, write: WriteRaw) -> (String, String) {
+        let source_file_name = dir.join("source.bvh");
+        fs::write(&source_file_name, test_util::clip_text(NUM_FRAMES, test_util::sine)).unwrap();
+        let raw_file_name = dir.join("in.raw");
+        let mut data = Vec::new();
+        write(&build_mocap(&test_util::sine_clip(NUM_FRAMES), &settings(4)), &mut data);
+        fs::write(&raw_file_name, data).unwrap();
+        (source_file_name.to_str().unwrap().into(), raw_file_name.to_str().unwrap().into())
+    }
+
+    fn reencode(dir: &Path, args: &[&str], input_file_name: &str) -> Result<Vec<u8>, MocapError> {
+        let output_file_name = dir.join("out.raw");
+        let output = output_file_name.to_str().unwrap();
+        let options = Options::parse(["reencode"].iter().chain(args.iter()).chain([input_file_name, output].iter()).map(|arg| arg.to_string()))?;
+        run(Path::new(input_file_name), &output_file_name, &options, None)?;
+        Ok(fs::read(&output_file_name).unwrap())
+    }
+
+    // Each channel's stored bytes: its header record and its runs in the delta blocks, in order.
+    fn channel_bytes(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut reader = Reader::new(data);
+        raw::read_magic(&mut reader).unwrap();
+        let (mocap, runs) = raw::read_clip_runs(&mut reader).unwrap();
+        let mut ret = mocap.channels().into_iter().map(|channel| {
+            let mut record = Vec::new();
+            record.extend_from_slice(&channel.reference.to_le_bytes());
+            record.extend_from_slice(&channel.value_range_min.to_le_bytes());
+            record.extend_from_slice(&channel.value_range.to_le_bytes());
+            record.push(channel.initial_level);
+            record.push(channel.bits.unwrap_or(0));
+            record
+        }).collect::<Vec<_>>();
+        for run in runs.iter() {
+            ret[run.channel].extend_from_slice(&data[run.offset..run.offset + run.len]);
+        }
+        ret
+    }
+
+    #[test]
+    fn keeps_untouched_channels_byte_identical() {
+        let layouts: [(&str, WriteRaw); 4] = [
+            ("packed", |mocap, w| raw::write(mocap, Limits::default(), w).unwrap()),
+            ("indexed", |mocap, w| raw::write_indexed(mocap, 16, bitpack::Layout::Packed, Limits::default(), w).unwrap()),
+            ("sparse", |mocap, w| raw::write_sparse(mocap, Limits::default(), w).unwrap()),
+            ("bit planes", |mocap, w| raw::write_bit_planes(mocap, Limits::default(), w).unwrap()),
+        ];
+        for (name, write) in layouts.iter() {
+            let dir = test_util::temp_dir("reencode-untouched");
+            let (source_file_name, raw_file_name) = files(&dir, *write);
+            let input = fs::read(&raw_file_name).unwrap();
+            let output = reencode(&dir, &["--bits-for", "Hips:*=8", "--source", &source_file_name], &raw_file_name).unwrap();
+
+            let (before, after) = (channel_bytes(&input), channel_bytes(&output));
+            // Hips' 6 channels are re-encoded; the other 9 are untouched
+            for (index, (before, after)) in before.iter().zip(after.iter()).enumerate() {
+                if index < 6 {
+                    assert_ne!(before, after, "{}: channel {}", name, index);
+                } else {
+                    assert_eq!(before, after, "{}: channel {}", name, index);
+                }
+            }
+            let (a, b) = (raw::read(&input, DEFAULT_MAX_DEPTH).unwrap(), raw::read(&output, DEFAULT_MAX_DEPTH).unwrap());
+            for (a, b) in a.channels().into_iter().zip(b.channels()).skip(6) {
+                assert_eq!((&a.deltas, a.initial_level, a.bits), (&b.deltas, b.initial_level, b.bits), "{}", name);
+            }
+            assert_eq!(raw::is_sparse(&output), raw::is_sparse(&input), "{}", name);
+            assert_eq!(raw::delta_layout(&output), raw::delta_layout(&input), "{}", name);
+        }
+    }
+
+    #[test]
+    fn targeted_channels_meet_the_new_bit_depth() {
+        let dir = test_util::temp_dir("reencode-bits");
+        let (source_file_name, raw_file_name) = files(&dir, |mocap, w| raw::write(mocap, Limits::default(), w).unwrap());
+        let output = reencode(&dir, &["--bits-for", "Hips:*=8", "--bits-for", "Head:RotationX=6", "--source", &source_file_name], &raw_file_name).unwrap();
+        let mocap = raw::read(&output, DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(mocap.channel_quantization_bits, 4);
+        assert!(mocap.metadata.contains(&(KEY.into(), format!("Hips:*=8,Head:RotationX=6 from {}", source_file_name))));
+
+        let source = test_util::sine_clip(NUM_FRAMES);
+        let decoded = build_bvh(&mocap);
+        for (index, channel) in mocap.channels().into_iter().enumerate() {
+            let bits = match index {
+                0..=5 => 8,
+                10 => 6,
+                _ => 4,
+            };
+            assert_eq!(channel.bits(mocap.channel_quantization_bits), bits, "channel {}", index);
+            assert_eq!(channel.bits, if bits == 4 { None } else { Some(bits) }, "channel {}", index);
+            // Within a step on the channel's own grid (`build_mocap` rounds down to a level)
+            let step = channel.value_range as f64 / max_level(bits) as f64;
+            for (frame, source_frame) in decoded.motion.frames.iter().zip(source.motion.frames.iter()) {
+                assert!((frame[index] - source_frame[index]).abs() <= step * 1.0001, "channel {}: {} vs {}", index, frame[index], source_frame[index]);
+            }
+        }
+    }
+
+    #[test]
+    fn stores_channels_given_64_bits_losslessly() {
+        let dir = test_util::temp_dir("reencode-lossless");
+        let (source_file_name, raw_file_name) = files(&dir, |mocap, w| raw::write(mocap, Limits::default(), w).unwrap());
+        let output = reencode(&dir, &["--bits-for", "Spine:RotationZ=64", "--source", &source_file_name], &raw_file_name).unwrap();
+        let decoded = build_bvh(&raw::read(&output, DEFAULT_MAX_DEPTH).unwrap());
+        let source = test_util::sine_clip(NUM_FRAMES);
+        for (frame, source_frame) in decoded.motion.frames.iter().zip(source.motion.frames.iter()) {
+            assert_eq!(frame[6], source_frame[6]);
+        }
+    }
+
+    #[test]
+    fn refuses_bit_depths_the_format_cant_store() {
+        let dir = test_util::temp_dir("reencode-invalid");
+        let (_, raw_file_name) = files(&dir, |mocap, w| raw::write(mocap, Limits::default(), w).unwrap());
+        for bits in ["0", "9", "16"].iter() {
+            match reencode(&dir, &["--bits-for", &format!("Hips:*={}", bits)], &raw_file_name) {
+                Err(MocapError::Usage(message)) => assert!(message.contains("bits must be in [1, 8]"), "{}", message),
+                other => panic!("{} bits: {:?}", bits, other.map(|_| ())),
+            }
+        }
+    }
+}
@@ -0,0 +1,476 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use error::MocapError;
+use json::{self, Value};
+use locomotion::Locomotion;
+use manifest;
+use metrics::ReconstructionError;
+use options::Options;
+use periodic::SearchCounts;
+use quality::ChannelQuality;
+use verify::Finding;
+use ChannelType;
+
+// A machine-readable account of a conversion run, written with --report for CI. Single-file and
+// batch runs share the schema:
+//
+//   {
+//     "report_version": 1,
+//     "tool": "mocap", "version": "0.1.0", "timestamp": "2024-01-01T12:00:00Z",
+//     "command": "convert" | "batch" | "verify",
+//     "settings": ["--bits", "8", ...],
+//     "files": [
+//       {
+//         "source": "walk.bvh",
+//         "status": "ok" | "failed",
+//         "error": "...",                        only if failed
+//         "cached": false,                       outputs copied from --cache-dir
+//         "input_size": 1234,                    null if the input couldn't be read
+//         "outputs": [{ "path": "walk.raw", "size": 567 }, ...],
+//         "channels": [{ "joint": "Hips", "type": "TranslationX", "bits": 8, "noise": 0.002,   64 bits if lossless
+//                        "quality": 255, "repaired": 0, "clamped": 0, "overflowed": 0 }, ...],   see quality.rs
+//         "reconstruction_error": { "max": 0.1, "rms": 0.01 },    null if not computed
+//         "joint_errors": [{ "joint": "Hips", "max": 0.1, "rms": 0.01 }, ...],   joints with channels
+//         "encoding_search": { "searched": 40, "fell_back": 2 },    with --time-budget, else null
+//         "locomotion": { "speed": 1.4, "heading_rate": 0, "stride_frequency": 0.9, "in_place": false },   with --locomotion, else null; see locomotion.rs
+//         "warnings": ["warning: ...", ...],
+//         "findings": [{ "clip": "walk", "location": "Hips RotationZ", "frame": 12, "message": "..." }, ...],   verify only; frame may be null
+//         "timings": { "load": 0.01, "encode": 0.002, "write": 0.004 }    seconds
+//       }
+//     ]
+//   }
+//
+// Fields can be added without changing REPORT_VERSION; it's bumped when any are removed or change
+// meaning. Fields that only a successful conversion produces are empty (or null) for failed and
+// cached files, and failed files list no outputs.
+//
+// `RunReport::read` reads a report back and `diff` compares two, for --compare-report.
+pub const REPORT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone)]
+pub struct RunReport {
+    pub command: String,
+    pub settings: Vec<String>,
+    pub files: Vec<FileReport>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileReport {
+    pub source: PathBuf,
+    pub error: Option<String>,
+    pub cached: bool,
+    pub input_size: Option<u64>,
+    pub outputs: Vec<(PathBuf, u64)>,
+    pub conversion: Conversion,
+    pub warnings: Vec<String>,
+    pub findings: Vec<Finding>, // Problems `mocap verify` found; see verify.rs
+}
+
+// What `convert` found out along the way.
+#[derive(Debug, Clone, Default)]
+pub struct Conversion {
+    pub channels: Vec<ChannelReport>,
+    pub reconstruction_error: Option<ReconstructionError>,
+    pub joint_errors: Vec<(String, ReconstructionError)>,
+    pub encoding_search: Option<SearchCounts>, // With --time-budget: the channels the periodic search covered (see periodic.rs)
+    pub locomotion: Option<Locomotion>, // With --locomotion
+    pub timings: Vec<(&'static str, f64)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelReport {
+    pub joint: String,
+    pub type_: ChannelType,
+    pub bits: u8,
+    pub noise_floor: Option<f64>, // Before any smoothing (see smooth.rs); not in older reports
+    pub quality: Option<ChannelQuality>, // What the passes did to the channel (see quality.rs); not in older reports
+    pub score: Option<u8>, // Its quality score; not in older reports
+}
+
+impl RunReport {
+    pub fn new(command: &str, options: &Options) -> RunReport {
+        RunReport {
+            command: command.into(),
+            settings: options.conversion_args(),
+            files: Vec::new(),
+        }
+    }
+
+    pub fn write_json<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let string = |s: &str| format!("\"{}\"", json::escape(s));
+        let strings = |strings: &[String]| format!("[{}]", strings.iter().map(|s| string(s)).collect::<Vec<_>>().join(", "));
+        let quality = |channel: &ChannelReport| match (channel.quality, channel.score) {
+            (Some(quality), Some(score)) => format!(", \"quality\": {}, \"repaired\": {}, \"clamped\": {}, \"overflowed\": {}", score, quality.repaired, quality.clamped, quality.overflowed),
+            _ => String::new(),
+        };
+
+        writeln!(w, "{{")?;
+        writeln!(w, "  \"report_version\": {},", REPORT_VERSION)?;
+        writeln!(w, "  \"tool\": \"mocap\", \"version\": \"{}\", \"timestamp\": \"{}\",", env!("CARGO_PKG_VERSION"), manifest::timestamp(SystemTime::now()))?;
+        writeln!(w, "  \"command\": {},", string(&self.command))?;
+        writeln!(w, "  \"settings\": {},", strings(&self.settings))?;
+        writeln!(w, "  \"files\": [")?;
+        for (index, file) in self.files.iter().enumerate() {
+            writeln!(w, "    {{")?;
+            writeln!(w, "      \"source\": {},", string(&file.source.to_string_lossy()))?;
+            match file.error {
+                None => writeln!(w, "      \"status\": \"ok\",")?,
+                Some(ref error) => writeln!(w, "      \"status\": \"failed\", \"error\": {},", string(error))?,
+            }
+            writeln!(w, "      \"cached\": {},", file.cached)?;
+            writeln!(w, "      \"input_size\": {},", file.input_size.map_or("null".into(), |size| format!("{}", size)))?;
+            writeln!(w, "      \"outputs\": [{}],", file.outputs.iter().map(|(path, size)| format!("{{ \"path\": {}, \"size\": {} }}", string(&path.to_string_lossy()), size)).collect::<Vec<_>>().join(", "))?;
+            writeln!(w, "      \"channels\": [{}],", file.conversion.channels.iter().map(|channel| format!("{{ \"joint\": {}, \"type\": \"{}\", \"bits\": {}, \"noise\": {}{} }}", string(&channel.joint), channel.type_.name(), channel.bits, channel.noise_floor.map_or("null".into(), |noise_floor| noise_floor.to_string()), quality(channel))).collect::<Vec<_>>().join(", "))?;
+            writeln!(w, "      \"reconstruction_error\": {},", file.conversion.reconstruction_error.map_or("null".into(), |error| format!("{{ \"max\": {}, \"rms\": {} }}", error.max, error.rms)))?;
+            writeln!(w, "      \"joint_errors\": [{}],", file.conversion.joint_errors.iter().map(|(joint, error)| format!("{{ \"joint\": {}, \"max\": {}, \"rms\": {} }}", string(joint), error.max, error.rms)).collect::<Vec<_>>().join(", "))?;
+            writeln!(w, "      \"encoding_search\": {},", file.conversion.encoding_search.map_or("null".into(), |counts| format!("{{ \"searched\": {}, \"fell_back\": {} }}", counts.searched, counts.fell_back)))?;
+            writeln!(w, "      \"locomotion\": {},", file.conversion.locomotion.map_or("null".into(), |locomotion| format!("{{ \"speed\": {}, \"heading_rate\": {}, \"stride_frequency\": {}, \"in_place\": {} }}", locomotion.speed, locomotion.heading_rate, locomotion.stride_frequency.map_or("null".into(), |frequency| frequency.to_string()), locomotion.in_place)))?;
+            writeln!(w, "      \"warnings\": {},", strings(&file.warnings))?;
+            writeln!(w, "      \"findings\": [{}],", file.findings.iter().map(|finding| format!("{{ \"clip\": {}, \"location\": {}, \"frame\": {}, \"message\": {} }}", string(&finding.clip), string(&finding.location), finding.frame.map_or("null".into(), |frame| frame.to_string()), string(&finding.message))).collect::<Vec<_>>().join(", "))?;
+            writeln!(w, "      \"timings\": {{ {} }}", file.conversion.timings.iter().map(|(phase, seconds)| format!("\"{}\": {}", phase, seconds)).collect::<Vec<_>>().join(", "))?;
+            writeln!(w, "    }}{}", if index + 1 < self.files.len() { "," } else { "" })?;
+        }
+        writeln!(w, "  ]")?;
+        writeln!(w, "}}")
+    }
+
+    pub fn write(&self, file_name: &Path) -> io::Result<()> {
+        self.write_json(&mut File::create(file_name)?)
+    }
+
+    pub fn read(file_name: &Path) -> Result<RunReport, MocapError> {
+        let invalid = |message: String| MocapError::Usage(format!("{}: not a valid report: {}", file_name.display(), message));
+        let value = json::parse(&fs::read_to_string(file_name)?).map_err(&invalid)?;
+        RunReport::from_json(&value).map_err(&invalid)
+    }
+
+    fn from_json(value: &Value) -> Result<RunReport, String> {
+        let version = value.get("report_version").and_then(Value::as_f64).ok_or("no report_version")?;
+        if version != REPORT_VERSION as f64 {
+            return Err(format!("report version {}, expected {}", version, REPORT_VERSION));
+        }
+
+        let mut files = Vec::new();
+        for file in read_array(value, "files")? {
+            let source = read_string(file, "source")?;
+            let error = match read_string(file, "status")?.as_str() {
+                "ok" => None,
+                _ => Some(read_string(file, "error")?),
+            };
+            let mut outputs = Vec::new();
+            for output in read_array(file, "outputs")? {
+                outputs.push((PathBuf::from(read_string(output, "path")?), read_number(output, "size")? as u64));
+            }
+            let mut channels = Vec::new();
+            for channel in read_array(file, "channels")? {
+                let type_name = read_string(channel, "type")?;
+                channels.push(ChannelReport {
+                    joint: read_string(channel, "joint")?,
+                    type_: ChannelType::from_name(&type_name).ok_or_else(|| format!("unknown channel type {}", type_name))?,
+                    bits: read_number(channel, "bits")? as u8,
+                    noise_floor: channel.get("noise").and_then(Value::as_f64),
+                    // Only what's compared is read back
+                    quality: None,
+                    score: None,
+                });
+            }
+            let reconstruction_error = match file.get("reconstruction_error") {
+                Some(Value::Null) | None => None,
+                Some(error) => Some(read_reconstruction_error(error)?),
+            };
+            let mut joint_errors = Vec::new();
+            for error in read_array(file, "joint_errors").unwrap_or(&[]) {
+                joint_errors.push((read_string(error, "joint")?, read_reconstruction_error(error)?));
+            }
+            files.push(FileReport {
+                source: PathBuf::from(source),
+                error: error,
+                cached: file.get("cached").and_then(Value::as_bool).unwrap_or(false),
+                input_size: file.get("input_size").and_then(Value::as_f64).map(|size| size as u64),
+                outputs: outputs,
+                conversion: Conversion {
+                    channels: channels,
+                    reconstruction_error: reconstruction_error,
+                    joint_errors: joint_errors,
+                    // Only what's compared is read back
+                    encoding_search: None,
+                    locomotion: None,
+                    timings: Vec::new(),
+                },
+                warnings: read_array(file, "warnings")?.iter().filter_map(|warning| warning.as_str().map(String::from)).collect(),
+                findings: Vec::new(), // Nor findings
+            });
+        }
+
+        Ok(RunReport {
+            command: read_string(value, "command")?,
+            settings: read_array(value, "settings")?.iter().filter_map(|arg| arg.as_str().map(String::from)).collect(),
+            files: files,
+        })
+    }
+
+    // What changed from `previous`, another run over (some of) the same sources. Files are
+    // matched by source path and outputs by path; channels by joint and type.
+    pub fn diff(&self, previous: &RunReport) -> ReportDiff {
+        let mut ret = ReportDiff::default();
+        for file in self.files.iter() {
+            let previous_file = match previous.files.iter().find(|previous_file| previous_file.source == file.source) {
+                Some(previous_file) => previous_file,
+                None => {
+                    ret.added.push(file.source.clone());
+                    continue;
+                }
+            };
+
+            let mut changes = Vec::new();
+            if previous_file.error.is_none() && file.error.is_some() {
+                changes.push(Change::new("status (now failing)".into(), 0.0, 1.0, Direction::HigherIsWorse));
+            }
+            for (path, size) in file.outputs.iter() {
+                if let Some((_, previous_size)) = previous_file.outputs.iter().find(|output| output.0 == *path) {
+                    changes.push(Change::new(format!("{} size", path.display()), *previous_size as f64, *size as f64, Direction::HigherIsWorse));
+                }
+            }
+            if let (Some(previous_error), Some(error)) = (previous_file.conversion.reconstruction_error, file.conversion.reconstruction_error) {
+                changes.push(Change::new("max error".into(), previous_error.max, error.max, Direction::HigherIsWorse));
+                changes.push(Change::new("rms error".into(), previous_error.rms, error.rms, Direction::HigherIsWorse));
+            }
+            for (joint, error) in file.conversion.joint_errors.iter() {
+                if let Some((_, previous_error)) = previous_file.conversion.joint_errors.iter().find(|entry| entry.0 == *joint) {
+                    changes.push(Change::new(format!("{} max error", joint), previous_error.max, error.max, Direction::HigherIsWorse));
+                    changes.push(Change::new(format!("{} rms error", joint), previous_error.rms, error.rms, Direction::HigherIsWorse));
+                }
+            }
+            for channel in file.conversion.channels.iter() {
+                if let Some(previous_channel) = previous_file.conversion.channels.iter().find(|previous_channel| previous_channel.joint == channel.joint && previous_channel.type_ == channel.type_) {
+                    changes.push(Change::new(format!("{} {} bits", channel.joint, channel.type_.name()), previous_channel.bits as f64, channel.bits as f64, Direction::Neutral));
+                }
+            }
+
+            changes.retain(|change| change.previous != change.current);
+            if !changes.is_empty() {
+                ret.files.push(FileDiff {
+                    source: file.source.clone(),
+                    changes: changes,
+                });
+            }
+        }
+        ret.removed = previous.files.iter().filter(|previous_file| !self.files.iter().any(|file| file.source == previous_file.source)).map(|previous_file| previous_file.source.clone()).collect();
+        ret
+    }
+}
+
+// See `RunReport::diff`. Only files with changes are listed.
+#[derive(Debug, Clone, Default)]
+pub struct ReportDiff {
+    pub files: Vec<FileDiff>,
+    pub added: Vec<PathBuf>, // Sources only in the current report
+    pub removed: Vec<PathBuf>, // Sources only in the previous one
+}
+
+#[derive(Debug, Clone)]
+pub struct FileDiff {
+    pub source: PathBuf,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    HigherIsWorse,
+    Neutral, // Neither a regression nor an improvement by itself, like a bit depth
+}
+
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub what: String,
+    pub previous: f64,
+    pub current: f64,
+    pub direction: Direction,
+}
+
+impl Change {
+    fn new(what: String, previous: f64, current: f64, direction: Direction) -> Change {
+        Change {
+            what: what,
+            previous: previous,
+            current: current,
+            direction: direction,
+        }
+    }
+
+    // Relative to the previous value; infinite if that was 0.
+    pub fn relative_change(&self) -> f64 {
+        if self.previous == 0.0 {
+            if self.current == 0.0 { 0.0 } else { f64::INFINITY * self.current.signum() }
+        } else {
+            (self.current - self.previous) / self.previous.abs()
+        }
+    }
+
+    // Whether this got worse by more than `threshold`, a fraction of the previous value.
+    pub fn is_regression(&self, threshold: f64) -> bool {
+        self.direction == Direction::HigherIsWorse && self.relative_change() > threshold
+    }
+}
+
+impl ReportDiff {
+    pub fn num_regressions(&self, threshold: f64) -> usize {
+        self.files.iter().map(|file| file.changes.iter().filter(|change| change.is_regression(threshold)).count()).sum()
+    }
+}
+
+fn read_array<'a>(value: &'a Value, key: &str) -> Result<&'a [Value], String> {
+    value.get(key).and_then(Value::as_array).ok_or_else(|| format!("no {} array", key))
+}
+
+fn read_string(value: &Value, key: &str) -> Result<String, String> {
+    value.get(key).and_then(Value::as_str).map(String::from).ok_or_else(|| format!("no {} string", key))
+}
+
+fn read_number(value: &Value, key: &str) -> Result<f64, String> {
+    value.get(key).and_then(Value::as_f64).ok_or_else(|| format!("no {} number", key))
+}
+
+fn read_reconstruction_error(value: &Value) -> Result<ReconstructionError, String> {
+    Ok(ReconstructionError {
+        max: read_number(value, "max")?,
+        rms: read_number(value, "rms")?,
+    })
+}
+
+impl FileReport {
+    // The report for one input, with the sizes of its outputs if it succeeded.
+    pub fn new(source: &Path, outputs: &[PathBuf], result: Result<Conversion, String>, cached: bool, warnings: Vec<String>) -> FileReport {
+        let (conversion, error) = match result {
+            Ok(conversion) => (conversion, None),
+            Err(error) => (Conversion::default(), Some(error)),
+        };
+        FileReport {
+            source: source.to_path_buf(),
+            cached: cached,
+            input_size: fs::metadata(source).ok().map(|metadata| metadata.len()),
+            outputs: if error.is_none() {
+                outputs.iter().filter_map(|output| fs::metadata(output).ok().map(|metadata| (output.clone(), metadata.len()))).collect()
+            } else {
+                Vec::new()
+            },
+            error: error,
+            conversion: conversion,
+            warnings: warnings,
+            findings: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util;
+
+    fn report(files: Vec<FileReport>) -> RunReport {
+        RunReport {
+            command: "batch".into(),
+            settings: vec!["--bits".into(), "8".into()],
+            files: files,
+        }
+    }
+
+    fn file(source: &str, raw_size: u64, max_error: f64, bits: u8) -> FileReport {
+        FileReport {
+            source: source.into(),
+            input_size: Some(1000),
+            outputs: vec![(format!("{}.raw", source).into(), raw_size)],
+            conversion: Conversion {
+                channels: vec![ChannelReport { joint: "Hips".into(), type_: ChannelType::RotationZ, bits: bits, noise_floor: None, quality: None, score: None }],
+                reconstruction_error: Some(ReconstructionError { max: max_error, rms: max_error / 2.0 }),
+                joint_errors: vec![("Hips".into(), ReconstructionError { max: max_error, rms: max_error / 2.0 })],
+                ..Conversion::default()
+            },
+            ..FileReport::default()
+        }
+    }
+
+    fn changes(diff: &ReportDiff, source: &str) -> Vec<(String, f64, f64)> {
+        diff.files.iter().find(|file| file.source.as_path() == Path::new(source)).unwrap().changes.iter().map(|change| (change.what.clone(), change.previous, change.current)).collect()
+    }
+
+    #[test]
+    fn identical_reports_have_no_changes() {
+        let previous = report(vec![file("walk", 500, 0.1, 8)]);
+        let diff = previous.diff(&previous);
+        assert!(diff.files.is_empty() && diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(diff.num_regressions(0.0), 0);
+    }
+
+    #[test]
+    fn lists_size_error_and_bit_depth_changes() {
+        let previous = report(vec![file("walk", 500, 0.1, 8), file("run", 500, 0.1, 8)]);
+        let current = report(vec![file("walk", 600, 0.05, 6), file("run", 500, 0.1, 8)]);
+        let diff = current.diff(&previous);
+        assert_eq!(diff.files.len(), 1);
+        assert_eq!(changes(&diff, "walk"), vec![
+            ("walk.raw size".to_string(), 500.0, 600.0),
+            ("max error".to_string(), 0.1, 0.05),
+            ("rms error".to_string(), 0.05, 0.025),
+            ("Hips max error".to_string(), 0.1, 0.05),
+            ("Hips rms error".to_string(), 0.05, 0.025),
+            ("Hips RotationZ bits".to_string(), 8.0, 6.0),
+        ]);
+    }
+
+    #[test]
+    fn regressions_are_growth_past_the_threshold() {
+        let previous = report(vec![file("walk", 500, 0.1, 8)]);
+        let current = report(vec![file("walk", 540, 0.1, 4)]);
+        let diff = current.diff(&previous);
+        // The size grew by 8%; the bit depth drop is neutral
+        assert_eq!(diff.num_regressions(0.05), 1);
+        assert_eq!(diff.num_regressions(0.1), 0);
+
+        // Errors appearing from nothing are always regressions
+        let change = Change::new("max error".into(), 0.0, 0.1, Direction::HigherIsWorse);
+        assert_eq!(change.relative_change(), f64::INFINITY);
+        assert!(change.is_regression(1000.0));
+        assert!(!Change::new("max error".into(), 0.2, 0.1, Direction::HigherIsWorse).is_regression(0.0));
+    }
+
+    #[test]
+    fn a_newly_failing_file_is_a_regression() {
+        let previous = report(vec![file("walk", 500, 0.1, 8)]);
+        let current = report(vec![FileReport { source: "walk".into(), error: Some("no such file".into()), ..FileReport::default() }]);
+        let diff = current.diff(&previous);
+        assert_eq!(changes(&diff, "walk"), vec![("status (now failing)".to_string(), 0.0, 1.0)]);
+        assert_eq!(diff.num_regressions(0.5), 1);
+    }
+
+    #[test]
+    fn sources_in_only_one_report_are_added_or_removed() {
+        let previous = report(vec![file("walk", 500, 0.1, 8), file("run", 500, 0.1, 8)]);
+        let current = report(vec![file("walk", 500, 0.1, 8), file("jump", 500, 0.1, 8)]);
+        let diff = current.diff(&previous);
+        assert!(diff.files.is_empty());
+        assert_eq!(diff.added, vec![PathBuf::from("jump")]);
+        assert_eq!(diff.removed, vec![PathBuf::from("run")]);
+    }
+
+    #[test]
+    fn a_written_report_reads_back_for_comparison() {
+        let dir = test_util::temp_dir("report");
+        let file_name = dir.join("report.json");
+        let written = report(vec![file("walk", 500, 0.1, 8), FileReport { source: "run".into(), error: Some("bad \"clip\"".into()), ..FileReport::default() }]);
+        written.write(&file_name).unwrap();
+
+        let read = RunReport::read(&file_name).unwrap();
+        assert_eq!(read.command, written.command);
+        assert_eq!(read.settings, written.settings);
+        assert_eq!(read.files[1].error, written.files[1].error);
+        let diff = read.diff(&written);
+        assert!(diff.files.is_empty() && diff.added.is_empty() && diff.removed.is_empty());
+
+        fs::write(&file_name, "{ \"report_version\": 99 }").unwrap();
+        assert!(matches!(RunReport::read(&file_name), Err(MocapError::Usage(ref message)) if message.contains("report version 99")));
+    }
+}
@@ -0,0 +1,230 @@
+use bvh;
+
+use error::MocapError;
+use markers::Marker;
+use rotation_channels;
+
+// Resampling to another frame rate with --fps. Every channel is sampled at the new frame times,
+// interpolating linearly between the two nearest input frames, rotations the short way round (so a
+// channel wrapping from 179 to -179 degrees doesn't swing through 0). Upsampling fills in frames
+// between the captured ones; downsampling only samples, without filtering first, so motion faster
+// than half the new rate aliases (--smooth first helps).
+//
+// Linear interpolation keeps every value between the frames either side, but the velocity jumps at
+// every input frame, which shows when upsampling a lot. With --interpolation cubic each channel
+// follows a Catmull-Rom spline through the input frames instead, whose velocity is continuous; it
+// can overshoot the input values around sharp changes (a step, or a channel hitting a limit), so
+// it's not the default. The spline's tangent at the first and last frames is taken from the one
+// frame next to them, as if the motion carried on in a straight line.
+//
+// The clip keeps its duration, up to the last whole output frame. Markers move to the output frame
+// nearest to their time, and the source frame time is recorded as metadata.
+
+// Metadata key recording the source frame time
+pub const KEY: &str = "resample_source_frame_time";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    Cubic, // Catmull-Rom
+}
+
+// Resamples `bvh` and `markers` (sorted) to `fps` frames per second, returning the metadata
+// recording it.
+pub fn apply(bvh: &mut bvh::Bvh, markers: &mut [Marker], fps: f64, interpolation: Interpolation) -> Result<Vec<(String, String)>, MocapError> {
+    let frame_time = bvh.motion.frame_time;
+    let num_frames = bvh.motion.frames.len();
+    if num_frames == 0 {
+        return Err(MocapError::Usage("--fps needs a clip with frames".into()));
+    }
+    if frame_time.is_nan() || frame_time <= 0.0 {
+        return Err(MocapError::Usage(format!("--fps needs a positive frame time, not {}", frame_time)));
+    }
+    let output_frame_time = 1.0 / fps;
+    let duration = (num_frames - 1) as f64 * frame_time;
+    let num_output_frames = (duration / output_frame_time + 1e-9).floor() + 1.0;
+    if num_output_frames.is_nan() || num_output_frames > u32::MAX as f64 {
+        return Err(MocapError::Overflow(format!("resampling {} frames to {} fps makes {:e} frames, more than a clip can have", num_frames, fps, num_output_frames)));
+    }
+    let num_output_frames = num_output_frames as usize;
+
+    let rotations = rotation_channels(&bvh.hierarchy.root);
+    let frames = (0..num_output_frames)
+        .map(|index| sample(&bvh.motion.frames, &rotations, (index as f64 * output_frame_time / frame_time).min((num_frames - 1) as f64), interpolation))
+        .collect::<Vec<_>>();
+    for marker in markers.iter_mut() {
+        marker.0 = ((marker.0 as f64 * frame_time / output_frame_time).round() as u32).min(num_output_frames as u32 - 1);
+    }
+
+    bvh.motion.frames = frames;
+    bvh.motion.num_frames = num_output_frames as u32;
+    bvh.motion.frame_time = output_frame_time;
+    Ok(vec![(KEY.into(), format!("{}", frame_time))])
+}
+
+// Every channel at fractional frame `position` (in [0, frames.len() - 1]), interpolating between
+// the frames either side (and for a cubic the ones either side of those), rotations (flagged in
+// flat channel order) the short way round. A rotation between two angles in [-180, 180] stays in
+// that range, so crossing the wrap doesn't widen the channel's range.
+pub fn sample(frames: &[Vec<f64>], rotations: &[bool], position: f64, interpolation: Interpolation) -> Vec<f64> {
+    let last = frames.len() - 1;
+    let (before, fraction) = (position.floor() as usize, position.fract());
+    let (a, b) = (&frames[before], &frames[(before + 1).min(last)]);
+    // Past the first or last frame, the points the spline's end tangents are taken from
+    let (previous, next) = (before.checked_sub(1).map(|index| &frames[index]), frames.get(before + 2));
+    (0..a.len()).zip(rotations.iter()).map(|(index, rotation)| {
+        let (a, b) = (a[index], b[index]);
+        // The short way round from `from` to `to`
+        let step = |from: f64, to: f64| if *rotation { to - from - 360.0 * ((to - from + 180.0) / 360.0).floor() } else { to - from };
+        let value = match interpolation {
+            Interpolation::Linear => a + step(a, b) * fraction,
+            Interpolation::Cubic => {
+                // The four points unwrapped around `a`, extrapolating linearly past either end
+                let (p1, p2) = (a, a + step(a, b));
+                let p0 = previous.map_or(2.0 * p1 - p2, |previous| p1 - step(previous[index], a));
+                let p3 = next.map_or(2.0 * p2 - p1, |next| p2 + step(b, next[index]));
+                let t = fraction;
+                0.5 * (2.0 * p1 + (p2 - p0) * t + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t * t + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t * t * t)
+            }
+        };
+        if !*rotation {
+            return value;
+        }
+        match value {
+            _ if a.abs() > 180.0 || b.abs() > 180.0 => value,
+            value if value > 180.0 => value - 360.0,
+            value if value < -180.0 => value + 360.0,
+            value => value,
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_util::{self, sine_clip};
+
+    // The largest difference between two clips' frames
+    fn max_difference(a: &[Vec<f64>], b: &[Vec<f64>]) -> f64 {
+        assert_eq!(a.len(), b.len());
+        a.iter().zip(b.iter()).flat_map(|(a, b)| a.iter().zip(b.iter()).map(|(a, b)| (a - b).abs())).fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn upsampling_then_decimating_gives_the_clip_back() {
+        let original = sine_clip(40);
+        let fps = 1.0 / original.motion.frame_time;
+
+        // Four times the rate keeps every input frame, so going back loses nothing
+        let mut bvh = sine_clip(40);
+        let metadata = apply(&mut bvh, &mut [], fps * 4.0, Interpolation::Linear).unwrap();
+        assert_eq!(metadata, vec![(KEY.to_string(), "0.033333".to_string())]);
+        assert_eq!((bvh.motion.frames.len(), bvh.motion.num_frames), (157, 157));
+        assert!((bvh.motion.frame_time - original.motion.frame_time / 4.0).abs() < 1e-12);
+        apply(&mut bvh, &mut [], fps, Interpolation::Linear).unwrap();
+        assert_eq!(bvh.motion.frames.len(), 40);
+        assert!(max_difference(&bvh.motion.frames, &original.motion.frames) < 1e-6);
+
+        // One and a half times, half the input frames fall between output frames and come back
+        // interpolated twice over. The channels move at most 0.855 per frame squared (the largest
+        // sine's amplitude of 38 times 0.15 squared), so each interpolation is off by at most 0.855
+        // times the interval squared over 8: 1/8 and then (2/3)^2/8 of a frame. (Over an even
+        // number of frame intervals, so the last frame is kept both ways.)
+        let (original, mut bvh) = (sine_clip(41), sine_clip(41));
+        apply(&mut bvh, &mut [], fps * 1.5, Interpolation::Linear).unwrap();
+        assert_eq!(bvh.motion.frames.len(), 61);
+        apply(&mut bvh, &mut [], fps, Interpolation::Linear).unwrap();
+        let difference = max_difference(&bvh.motion.frames, &original.motion.frames);
+        assert!(difference > 1e-3 && difference < 0.855 * (1.0 + 4.0 / 9.0) / 8.0, "{}", difference);
+    }
+
+    #[test]
+    fn upsampled_rotations_take_the_short_way_round() {
+        // The root's Z rotation wrapping from 170 to -170 degrees, and its X translation moving
+        // the same 340 the long way
+        let mut bvh = test_util::parse(&test_util::clip_text(2, |frame, channel| match channel {
+            0 | 3 => if frame == 0 { 170.0 } else { -170.0 },
+            _ => 0.0,
+        }));
+        let fps = 3.0 / bvh.motion.frame_time;
+        apply(&mut bvh, &mut [], fps, Interpolation::Linear).unwrap();
+        let values = |channel: usize| bvh.motion.frames.iter().map(|frame| frame[channel]).collect::<Vec<_>>();
+        for (value, expected) in values(3).iter().zip([170.0, 176.666667, -176.666667, -170.0].iter()) {
+            assert!((value - expected).abs() < 1e-4, "{:?}", values(3));
+        }
+        for (value, expected) in values(0).iter().zip([170.0, 56.666667, -56.666667, -170.0].iter()) {
+            assert!((value - expected).abs() < 1e-4, "{:?}", values(0));
+        }
+    }
+
+    #[test]
+    fn cubic_interpolation_follows_a_smooth_curve() {
+        // Upsampled eight times, against the sines the clip samples at every output frame. Away
+        // from the ends, where the spline's tangents are guesses, Catmull-Rom's error shrinks with
+        // the cube of the frame interval and linear's only with its square.
+        let original = sine_clip(40);
+        let upsampled = |interpolation: Interpolation| {
+            let mut bvh = sine_clip(40);
+            apply(&mut bvh, &mut [], 8.0 / original.motion.frame_time, interpolation).unwrap();
+            bvh.motion.frames
+        };
+        let expected = (0..313).map(|index| (0..test_util::NUM_CHANNELS).map(|channel| {
+            let frame = index as f64 / 8.0;
+            (frame * 0.15 + channel as f64).sin() * (10.0 + channel as f64 * 2.0)
+        }).collect::<Vec<_>>()).collect::<Vec<_>>();
+        let (linear, cubic) = (upsampled(Interpolation::Linear), upsampled(Interpolation::Cubic));
+        let interior = 8..305;
+        let linear_error = max_difference(&linear[interior.clone()], &expected[interior.clone()]);
+        let cubic_error = max_difference(&cubic[interior.clone()], &expected[interior]);
+        assert!(linear_error > 0.05 && cubic_error < linear_error / 10.0, "linear {} vs cubic {}", linear_error, cubic_error);
+        // Both still pass through the input frames
+        for (frame, original) in original.motion.frames.iter().enumerate() {
+            assert!(max_difference(&cubic[frame * 8..frame * 8 + 1], ::std::slice::from_ref(original)) < 1e-6);
+        }
+
+        // Its velocity doesn't jump at the input frames the way linear's does: the change in
+        // velocity across a frame is about the same as between the output frames around it
+        let jump = |frames: &[Vec<f64>], index: usize| (frames[index + 1][14] - 2.0 * frames[index][14] + frames[index - 1][14]).abs();
+        assert!(jump(&linear, 160) > 10.0 * jump(&linear, 164));
+        assert!(jump(&cubic, 160) < 2.0 * jump(&cubic, 164));
+    }
+
+    #[test]
+    fn cubic_interpolation_of_a_line_is_the_line() {
+        // Including at the ends, whose tangents carry the motion on in a straight line
+        let line = |frame: f64, channel: usize| frame * (channel as f64 - 7.0) * 0.5;
+        let mut bvh = test_util::parse(&test_util::clip_text(5, |frame, channel| line(frame as f64, channel)));
+        let fps = 4.0 / bvh.motion.frame_time;
+        apply(&mut bvh, &mut [], fps, Interpolation::Cubic).unwrap();
+        assert_eq!(bvh.motion.frames.len(), 17);
+        for (index, frame) in bvh.motion.frames.iter().enumerate() {
+            for (channel, value) in frame.iter().enumerate() {
+                assert!((value - line(index as f64 / 4.0, channel)).abs() < 1e-6, "frame {} channel {}: {}", index, channel, value);
+            }
+        }
+    }
+
+    #[test]
+    fn cubic_interpolation_overshoots_a_step() {
+        // Which is why it isn't the default
+        let step = |frame: usize| if frame < 4 { 0.0 } else { 10.0 };
+        let resampled = |interpolation: Interpolation| {
+            let mut bvh = test_util::parse(&test_util::clip_text(8, |frame, _| step(frame)));
+            let fps = 4.0 / bvh.motion.frame_time;
+            apply(&mut bvh, &mut [], fps, interpolation).unwrap();
+            bvh.motion.frames.iter().map(|frame| frame[0]).collect::<Vec<_>>()
+        };
+        let (linear, cubic) = (resampled(Interpolation::Linear), resampled(Interpolation::Cubic));
+        assert!(linear.iter().all(|value| (0.0..=10.0).contains(value)));
+        assert!(cubic.iter().any(|value| *value < -0.1) && cubic.iter().any(|value| *value > 10.1), "{:?}", cubic);
+    }
+
+    #[test]
+    fn refuses_clips_it_cant_resample() {
+        let mut empty = test_util::parse(&test_util::clip_text(0, test_util::sine));
+        assert!(matches!(apply(&mut empty, &mut [], 60.0, Interpolation::Linear), Err(MocapError::Usage(_))));
+        let mut bvh = sine_clip(2);
+        assert!(matches!(apply(&mut bvh, &mut [], 1e300, Interpolation::Linear), Err(MocapError::Overflow(_))));
+    }
+}
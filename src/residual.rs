@@ -0,0 +1,31 @@
+use bvh;
+
+use {build_bvh_joint, Mocap};
+
+// The residual BVH, a debugging aid for the delta scheme and not an animation: with
+// --export-deltas-bvh a conversion also writes the clip's skeleton with, in place of every
+// channel's motion, the quantized deltas the .raw file codes, as whole numbers (-128 to 127).
+// Loaded in a viewer or plotted per channel, the residual signal shows where compression
+// struggles: a channel that's smooth at this bit depth sits near 0, and one that's noisy or
+// moving fast swings widely or wraps. The pose it "plays" is meaningless.
+//
+// Frame 0 holds the first delta, from level 0, so it's the first level wrapped into an i8 rather
+// than a change. Lossless channels (see profile.rs) store no deltas and are 0 throughout.
+
+pub fn delta_bvh(mocap: &Mocap) -> bvh::Bvh {
+    let channels = mocap.channels();
+    let frames = (0..mocap.num_frames as usize).map(|frame| {
+        channels.iter().map(|channel| channel.deltas.get(frame).map_or(0.0, |delta| *delta as f64)).collect()
+    }).collect();
+
+    bvh::Bvh {
+        hierarchy: bvh::Hierarchy {
+            root: build_bvh_joint(&mocap.root),
+        },
+        motion: bvh::Motion {
+            num_frames: mocap.num_frames,
+            frame_time: mocap.frame_time as _,
+            frames: frames,
+        },
+    }
+}
@@ -0,0 +1,331 @@
+use bvh;
+
+use error::MocapError;
+use {channel_type, ChannelType};
+
+// Root motion encoding, with --root-motion. A locomotion clip's root wanders across the floor, so
+// its X and Z translation and its yaw (Y rotation) span a wide range and never repeat, even when
+// the character does the same thing every step. Stored instead as what the character does each
+// frame, moving forward and sideways relative to where it's facing and turning, the values are
+// small, nearly constant for steady walking, and loop along with the rest of the body.
+//
+// So before quantization the root's Xposition, Zposition and Yrotation channels are replaced by,
+// per frame, the displacement since the previous frame in the previous frame's heading (strafe in
+// Xposition, forward in Zposition, where heading 0 faces +Z) and the change in yaw, wrapped into
+// [-180, 180) degrees; frame 0 stores zeros. The heading is the Yrotation channel as is, whatever
+// the channel order, and Y is taken as up.
+//
+// Decoding integrates them back, which accumulates the quantization error. To bound that drift
+// the absolute X, Z and yaw are recorded every --root-motion-anchors frames (and at the last
+// frame) in the metadata, as the interval followed by each anchor's three values, separated by
+// spaces. That's some 60 bytes per anchor, and a metadata value holds at most 65535 (see raw.rs),
+// so an interval leaving more than about a thousand anchors is refused, suggesting one that fits.
+// Each span between anchors is integrated from the one at its start, and the difference
+// from the one at its end spread over it linearly, so the trajectory passes through every anchor
+// exactly and has no jumps. Between anchors a frame's position is within the sum of the
+// displacement channels' quantization errors (half a level each) over the span, plus the heading
+// error turning them, of the original; typically a small fraction of a unit at 8 bits. Yaw comes
+// back within [-180, 180) if every anchor's was, and otherwise continuous from each span's start.
+//
+// The conversion's BVH output, `mocap unpack` and `mocap decode` integrate the channels
+// automatically; the .raw file and the CSV hold them as encoded.
+
+// Metadata key holding the anchors
+pub const KEY: &str = "root_motion";
+
+pub const DEFAULT_ANCHOR_INTERVAL: usize = 30;
+
+// The root's X, Z and yaw channels, as flat channel indices (the root's come first)
+#[derive(Debug, Clone, Copy)]
+struct RootChannels {
+    x: usize,
+    z: usize,
+    yaw: usize,
+}
+
+impl RootChannels {
+    fn find(root: &bvh::Joint) -> Option<RootChannels> {
+        let index = |type_: ChannelType| root.channels.iter().position(|channel| channel_type(channel) == type_);
+        Some(RootChannels {
+            x: index(ChannelType::TranslationX)?,
+            z: index(ChannelType::TranslationZ)?,
+            yaw: index(ChannelType::RotationY)?,
+        })
+    }
+
+    // (x, z, yaw) in `frame`
+    fn get(&self, frame: &[f64]) -> [f64; 3] {
+        [frame[self.x], frame[self.z], frame[self.yaw]]
+    }
+
+    fn set(&self, frame: &mut [f64], values: [f64; 3]) {
+        frame[self.x] = values[0];
+        frame[self.z] = values[1];
+        frame[self.yaw] = values[2];
+    }
+}
+
+// The flat indices of the root's X, Z and yaw channels, which `encode` replaces.
+pub fn channels(root: &bvh::Joint) -> Option<[usize; 3]> {
+    RootChannels::find(root).map(|channels| [channels.x, channels.z, channels.yaw])
+}
+
+// Replaces the root's X, Z and yaw channels with per-frame displacements, returning the metadata
+// recording the anchors.
+pub fn encode(bvh: &mut bvh::Bvh, anchor_interval: usize) -> Result<Vec<(String, String)>, MocapError> {
+    let channels = RootChannels::find(&bvh.hierarchy.root)
+        .ok_or_else(|| MocapError::Usage(format!("--root-motion needs a root with Xposition, Zposition and Yrotation channels, and {} hasn't", bvh.hierarchy.root.name)))?;
+    let frames = &mut bvh.motion.frames;
+    let absolute = frames.iter().map(|frame| channels.get(frame)).collect::<Vec<_>>();
+    let mut metadata = vec![anchor_interval.to_string()];
+    for frame in anchor_frames(absolute.len(), anchor_interval) {
+        metadata.extend(absolute[frame].iter().map(|value| value.to_string()));
+    }
+
+    for (index, frame) in frames.iter_mut().enumerate() {
+        let relative = match index.checked_sub(1) {
+            None => [0.0; 3],
+            Some(previous) => {
+                let ([x0, z0, yaw0], [x1, z1, yaw1]) = (absolute[previous], absolute[index]);
+                let (dx, dz) = (x1 - x0, z1 - z0);
+                let (sin, cos) = yaw0.to_radians().sin_cos();
+                [dx * cos - dz * sin, dx * sin + dz * cos, wrap(yaw1 - yaw0)]
+            }
+        };
+        channels.set(frame, relative);
+    }
+    let metadata = metadata.join(" ");
+    if metadata.len() > u16::MAX as usize {
+        // Anchors take about the same space each, so this many more frames apart fit
+        let fitting_interval = anchor_interval * metadata.len() / u16::MAX as usize + 1;
+        return Err(MocapError::Usage(format!("--root-motion-anchors {} records {} bytes of anchors for {} frames, more than the metadata can hold ({}); use an interval of about {} or more",
+            anchor_interval, metadata.len(), absolute.len(), u16::MAX, fitting_interval)));
+    }
+    Ok(vec![(KEY.into(), metadata)])
+}
+
+// Integrates the displacements back into absolute channels, if `metadata` records root motion;
+// returns whether it did.
+pub fn decode(bvh: &mut bvh::Bvh, metadata: &[(String, String)]) -> Result<bool, MocapError> {
+    let num_frames = bvh.motion.frames.len();
+    decode_span(&bvh.hierarchy.root, &mut bvh.motion.frames, 0, num_frames, metadata)
+}
+
+// The frames (first, last inclusive) `decode_span` needs to decode `frame` of a clip of
+// `num_frames`: the span of anchors around it. None if `metadata` doesn't record root motion.
+pub fn span(metadata: &[(String, String)], frame: usize, num_frames: usize) -> Result<Option<(usize, usize)>, MocapError> {
+    let anchors = match Anchors::read(metadata, num_frames)? {
+        Some(anchors) => anchors,
+        None => return Ok(None),
+    };
+    let start = frame / anchors.interval * anchors.interval;
+    Ok(Some((start, (start + anchors.interval).min(num_frames.saturating_sub(1)))))
+}
+
+// `decode` on frames `first_frame`.. of a clip of `num_frames`. `first_frame` has to be an anchor,
+// and the frames have to run to the next anchor or the end of the clip.
+pub fn decode_span(root: &bvh::Joint, frames: &mut [Vec<f64>], first_frame: usize, num_frames: usize, metadata: &[(String, String)]) -> Result<bool, MocapError> {
+    let anchors = match Anchors::read(metadata, num_frames)? {
+        Some(anchors) => anchors,
+        None => return Ok(false),
+    };
+    let channels = RootChannels::find(root).ok_or_else(|| MocapError::InvalidRaw("root motion recorded for a root without Xposition, Zposition and Yrotation channels".into()))?;
+    let wrap_yaw = anchors.values.iter().all(|values| (-180.0..=180.0).contains(&values[2]));
+
+    let relative = frames.iter().map(|frame| channels.get(frame)).collect::<Vec<_>>();
+    let frame_numbers = first_frame..first_frame + frames.len();
+    for (anchor, &start) in anchors.frames.iter().enumerate().filter(|(_, frame)| frame_numbers.contains(*frame)) {
+        let mut values = vec![anchors.values[anchor]];
+        if let Some(end) = anchors.frames.get(anchor + 1).cloned().filter(|end| frame_numbers.contains(end)) {
+            for frame in start + 1..=end {
+                let [x, z, yaw] = values[values.len() - 1];
+                let [strafe, forward, turn] = relative[frame - first_frame];
+                let (sin, cos) = yaw.to_radians().sin_cos();
+                values.push([x + strafe * cos + forward * sin, z - strafe * sin + forward * cos, yaw + turn]);
+            }
+            // The difference from the anchor at the end, spread over the span
+            let (integrated, anchored) = (values[values.len() - 1], anchors.values[anchor + 1]);
+            let error = [anchored[0] - integrated[0], anchored[1] - integrated[1], wrap(anchored[2] - integrated[2])];
+            for (index, value) in values.iter_mut().enumerate() {
+                let fraction = index as f64 / (end - start) as f64;
+                for (value, error) in value.iter_mut().zip(error.iter()) {
+                    *value += error * fraction;
+                }
+            }
+            // The end anchor's frame starts the next span
+            values.pop();
+        }
+        for (index, [x, z, yaw]) in values.into_iter().enumerate() {
+            channels.set(&mut frames[start - first_frame + index], [x, z, if wrap_yaw { wrap(yaw) } else { yaw }]);
+        }
+    }
+    Ok(true)
+}
+
+// The anchors recorded in the metadata
+struct Anchors {
+    interval: usize,
+    frames: Vec<usize>,
+    values: Vec<[f64; 3]>, // Per anchor frame: x, z and yaw
+}
+
+impl Anchors {
+    fn read(metadata: &[(String, String)], num_frames: usize) -> Result<Option<Anchors>, MocapError> {
+        let value = match metadata.iter().find(|entry| entry.0 == KEY) {
+            Some(entry) => &entry.1,
+            None => return Ok(None),
+        };
+        let invalid = || MocapError::InvalidRaw("invalid root motion anchors".into());
+        let mut fields = value.split_whitespace();
+        let interval = fields.next().and_then(|interval| interval.parse::<usize>().ok()).filter(|interval| *interval > 0).ok_or_else(invalid)?;
+        let values = fields.map(|value| value.parse::<f64>()).collect::<Result<Vec<_>, _>>().map_err(|_| invalid())?;
+        let frames = anchor_frames(num_frames, interval);
+        if values.len() != 3 * frames.len() {
+            return Err(MocapError::InvalidRaw(format!("{} root motion anchor values for {} anchors", values.len(), frames.len())));
+        }
+        Ok(Some(Anchors {
+            interval: interval,
+            frames: frames,
+            values: values.chunks(3).map(|values| [values[0], values[1], values[2]]).collect(),
+        }))
+    }
+}
+
+// Every interval-th frame, and the last
+fn anchor_frames(num_frames: usize, interval: usize) -> Vec<usize> {
+    let mut ret = (0..num_frames).step_by(interval).collect::<Vec<_>>();
+    if num_frames > 0 && ret.last() != Some(&(num_frames - 1)) {
+        ret.push(num_frames - 1);
+    }
+    ret
+}
+
+// Into [-180, 180) degrees
+fn wrap(angle: f64) -> f64 {
+    angle - 360.0 * ((angle + 180.0) / 360.0).floor()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use conversion::ConversionSettings;
+    use test_util::{motion_text, parse, sine, HIERARCHY, NUM_CHANNELS};
+    use {build_bvh, build_mocap, Mocap};
+
+    const RADIUS: f64 = 200.0;
+
+    // A walk once round a circle of RADIUS over `num_frames` frames, facing along it: the root at
+    // (RADIUS (1 - cos a), RADIUS sin a) turned to a, back where it started on the last frame.
+    fn circle(num_frames: usize) -> bvh::Bvh {
+        parse(&motion_text(HIERARCHY, NUM_CHANNELS, num_frames, |frame, channel| {
+            let angle = 360.0 * frame as f64 / (num_frames - 1) as f64;
+            match channel {
+                0 => RADIUS * (1.0 - angle.to_radians().cos()),
+                2 => RADIUS * angle.to_radians().sin(),
+                5 => wrap(angle),
+                _ => sine(frame, channel),
+            }
+        }))
+    }
+
+    // `bvh` encoded with root motion anchored every `interval` frames and quantized, with its
+    // anchors
+    fn quantized(mut bvh: bvh::Bvh, interval: usize) -> (Mocap, Vec<(String, String)>) {
+        let metadata = encode(&mut bvh, interval).unwrap();
+        (build_mocap(&bvh, &ConversionSettings::default().settings()), metadata)
+    }
+
+    fn distance(a: &[f64], b: &[f64]) -> f64 {
+        ((a[0] - b[0]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+    }
+
+    #[test]
+    fn encodes_a_circular_walk_as_steady_steps() {
+        let mut bvh = circle(361);
+        let metadata = encode(&mut bvh, 30).unwrap();
+        // 1 degree per frame along the circle: the same step forward and turn every frame
+        let step = 2.0 * RADIUS * 0.5f64.to_radians().sin();
+        for frame in bvh.motion.frames[1..].iter() {
+            assert!((frame[0] - step * 0.5f64.to_radians().sin()).abs() < 1e-9, "strafe {}", frame[0]);
+            assert!((frame[2] - step * 0.5f64.to_radians().cos()).abs() < 1e-9, "forward {}", frame[2]);
+            assert!((frame[5] - 1.0).abs() < 1e-9, "turn {}", frame[5]);
+        }
+        assert_eq!(&bvh.motion.frames[0][..6], &[0.0, sine(0, 1), 0.0, sine(0, 3), sine(0, 4), 0.0]);
+
+        // The interval, then frames 0, 30, ..., 360
+        let fields = metadata[0].1.split(' ').collect::<Vec<_>>();
+        assert_eq!(metadata[0].0, KEY);
+        assert_eq!(fields[0], "30");
+        assert_eq!(fields.len(), 1 + 3 * 13);
+    }
+
+    #[test]
+    fn a_circular_walk_closes_within_the_quantization_error() {
+        let original = circle(361);
+        for interval in [10, 30, 360].iter() {
+            let (mocap, metadata) = quantized(circle(361), *interval);
+            let mut decoded = build_bvh(&mocap);
+            assert!(decode(&mut decoded, &metadata).unwrap());
+            let frames = &decoded.motion.frames;
+            // The anchors are exact, so the walk ends where it started
+            assert!(distance(&frames[360], &frames[0]) < 1e-9);
+            assert!(distance(&frames[360], &original.motion.frames[360]) < 1e-9);
+
+            // Between anchors each frame's within half a level of each step channel per frame
+            // since the last anchor, and that of the turn swinging them round
+            let level = |index: usize| mocap.root.channels[index].value_range as f64 / 255.0;
+            let span_bound = *interval as f64 * (0.5 * (level(0) + level(2)) + 0.5 * level(5).to_radians() * RADIUS * 2.0 * std::f64::consts::PI / 360.0 * *interval as f64);
+            let max_error = frames.iter().zip(original.motion.frames.iter()).map(|(decoded, original)| distance(decoded, original)).fold(0.0, f64::max);
+            assert!(max_error < span_bound, "interval {}: {} >= {}", interval, max_error, span_bound);
+            let max_yaw_error = frames.iter().zip(original.motion.frames.iter()).map(|(decoded, original)| wrap(decoded[5] - original[5]).abs()).fold(0.0, f64::max);
+            assert!(max_yaw_error <= 0.5 * level(5) * *interval as f64 + 1e-9, "interval {}: yaw {}", interval, max_yaw_error);
+            assert!(frames.iter().all(|frame| (-180.0..180.0).contains(&frame[5])));
+        }
+    }
+
+    #[test]
+    fn decoding_a_span_matches_decoding_the_clip() {
+        let (mocap, metadata) = quantized(circle(100), 30);
+        let quantized = build_bvh(&mocap);
+        let mut decoded = build_bvh(&mocap);
+        decode(&mut decoded, &metadata).unwrap();
+
+        for (frame, expected_span) in [(0, (0, 30)), (29, (0, 30)), (30, (30, 60)), (95, (90, 99)), (99, (90, 99))].iter() {
+            let (start, end) = span(&metadata, *frame, 100).unwrap().unwrap();
+            assert_eq!((start, end), *expected_span);
+            let mut frames = quantized.motion.frames[start..=end].to_vec();
+            assert!(decode_span(&quantized.hierarchy.root, &mut frames, start, 100, &metadata).unwrap());
+            assert_eq!(frames[*frame - start], decoded.motion.frames[*frame]);
+        }
+        assert_eq!(span(&[], 10, 100).unwrap(), None);
+    }
+
+    #[test]
+    fn refuses_more_anchors_than_the_metadata_holds() {
+        let mut bvh = circle(3000);
+        match encode(&mut bvh, 1) {
+            Err(MocapError::Usage(message)) => {
+                assert!(message.starts_with("--root-motion-anchors 1 records "), "{}", message);
+                let fitting_interval = message.rsplit("about ").next().unwrap().split(' ').next().unwrap().parse::<usize>().unwrap();
+                assert!(encode(&mut circle(3000), fitting_interval).is_ok());
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_a_root_without_the_channels() {
+        let hierarchy = HIERARCHY.replacen("CHANNELS 6 Xposition Yposition Zposition Zrotation Xrotation Yrotation", "CHANNELS 6 Xposition Yposition Zposition Zrotation Xrotation Xrotation", 1);
+        let mut bvh = parse(&motion_text(&hierarchy, NUM_CHANNELS, 2, sine));
+        assert!(encode(&mut bvh, 30).is_err());
+
+        // Or anchors that don't match the clip
+        let mut bvh = circle(100);
+        let metadata = vec![(KEY.to_string(), "30 0 0 0".to_string())];
+        match decode(&mut bvh, &metadata) {
+            Err(MocapError::InvalidRaw(message)) => assert_eq!(message, "3 root motion anchor values for 5 anchors"),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::io::{self, Write};
+
+use error::MocapError;
+use raw::Reader;
+use max_level;
+
+// The seek index of a .raw file, so a player can seek to a frame without reading every block
+// before it. It optionally follows the last delta block:
+//
+//   magic           b"SEEK"
+//   block count     u32, the number of delta blocks
+//   blocks          per block, in file order:
+//                     start frame     u32, the block's first frame
+//                     offset          u64, where its frame count is, from the start of the file
+//                     length          u32 bytes, frame count included
+//                     levels          a u8 per channel stored in the blocks, in `Mocap::channel_map`
+//                                     order: its level before the block's first frame, which its
+//                                     first delta is from
+//   index offset    u64, where the magic is, from the start of the file
+//
+// With the levels a block decodes on its own, so seeking to a frame is a binary search for its
+// block and unpacking at most that block. The index offset comes last so a player can find the
+// index by reading the end of the file. `raw::write_indexed` and `writer::MocapWriter` write it
+// with blocks of any size; readers check the start frames, offsets and lengths against the blocks
+// actually in the file, and that every level is on its channel's grid.
+pub const MAGIC: &[u8; 4] = b"SEEK";
+
+// Where a block is: its start frame, offset and length, as in its entry
+pub type Block = (u32, u64, u32);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub start_frame: u32,
+    pub offset: u64,
+    pub len: u32,
+    pub levels: Vec<u8>,
+}
+
+// `index_offset` is where the index starts, past the last block.
+pub fn write<W: Write>(entries: &[Entry], index_offset: u64, w: &mut W) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&(entries.len() as u32).to_le_bytes())?;
+    for entry in entries.iter() {
+        w.write_all(&entry.start_frame.to_le_bytes())?;
+        w.write_all(&entry.offset.to_le_bytes())?;
+        w.write_all(&entry.len.to_le_bytes())?;
+        w.write_all(&entry.levels)?;
+    }
+    w.write_all(&index_offset.to_le_bytes())
+}
+
+// Reads the index at the reader's position, which must be just past the last block, checking it
+// against `blocks`, every block actually read. `bits` is the bit depth of each channel stored in
+// the blocks.
+pub fn read(reader: &mut Reader, blocks: &[Block], bits: &[u8]) -> Result<Vec<Entry>, MocapError> {
+    let index_offset = reader.position() as u64;
+    if reader.bytes(MAGIC.len())? != MAGIC {
+        return Err(MocapError::InvalidRaw("unexpected bytes after the channel data".into()));
+    }
+    let num_entries = reader.u32()?;
+    if num_entries as usize != blocks.len() {
+        return Err(MocapError::InvalidRaw(format!("the seek index lists {} blocks, but the file has {}", num_entries, blocks.len())));
+    }
+    let max_levels = bits.iter().map(|bits| max_level(*bits)).collect::<Vec<_>>();
+    let mut entries = Vec::with_capacity(blocks.len());
+    for (index, &(start_frame, offset, len)) in blocks.iter().enumerate() {
+        let entry = Entry {
+            start_frame: reader.u32()?,
+            offset: reader.u64()?,
+            len: reader.u32()?,
+            levels: reader.bytes(bits.len())?.to_vec(),
+        };
+        if (entry.start_frame, entry.offset, entry.len) != (start_frame, offset, len) {
+            return Err(MocapError::InvalidRaw(format!("seek index entry {} doesn't match block {} (frame {}, offset {}, {} bytes)", index, index, start_frame, offset, len)));
+        }
+        if let Some(channel) = entry.levels.iter().zip(max_levels.iter()).position(|(level, max_level)| level > max_level) {
+            return Err(MocapError::InvalidRaw(format!("seek index entry {} has level {}, past the {} bit grid", index, entry.levels[channel], bits[channel])));
+        }
+        entries.push(entry);
+    }
+    if reader.u64()? != index_offset {
+        return Err(MocapError::InvalidRaw("the seek index's offset doesn't match where it starts".into()));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use bitpack::Layout;
+    use conversion::ConversionSettingsBuilder;
+    use depth::DEFAULT_MAX_DEPTH;
+    use error::MocapError;
+    use periodic::Limits;
+    use raw;
+    use test_util;
+    use view::MocapView;
+    use {build_bvh, build_mocap, Mocap};
+
+    const NUM_FRAMES: usize = 45;
+
+    fn clip(bits: u8) -> Mocap {
+        // A constant channel too, which isn't stored in the blocks or listed in the levels
+        let mut bvh = test_util::sine_clip(NUM_FRAMES);
+        for frame in bvh.motion.frames.iter_mut() {
+            frame[4] = 3.0;
+        }
+        let mut ret = build_mocap(&bvh, &ConversionSettingsBuilder::default().channel_quantization_bits(bits).build().unwrap().settings());
+        ret.markers = vec![(0, "start".into()), (20, "contact".into()), (20, "lift".into()), (44, "end".into())];
+        ret
+    }
+
+    fn indexed(mocap: &Mocap, block_frames: usize, layout: Layout) -> Vec<u8> {
+        let mut ret = Vec::new();
+        raw::write_indexed(mocap, block_frames, layout, Limits::default(), &mut ret).unwrap();
+        ret
+    }
+
+    #[test]
+    fn entries_point_at_their_blocks() {
+        for block_frames in [1, 2, 7, 44, 45, 46, 1000].iter().cloned() {
+            let mocap = clip(8);
+            let data = indexed(&mocap, block_frames, Layout::Packed);
+            let view = MocapView::parse(&data, DEFAULT_MAX_DEPTH).unwrap();
+            let entries = view.seek_table().unwrap();
+            assert_eq!(entries.len(), NUM_FRAMES.div_ceil(block_frames), "{} frame blocks", block_frames);
+
+            let block_channels = mocap.channels().into_iter().zip(view.header().channels()).filter(|(_, header)| raw::is_in_blocks(header)).map(|(channel, _)| channel).collect::<Vec<_>>();
+            assert!(!block_channels.is_empty() && block_channels.len() < test_util::NUM_CHANNELS);
+            for (index, entry) in entries.iter().enumerate() {
+                let start = index * block_frames;
+                assert_eq!(entry.start_frame as usize, start);
+                let frame_count = u32::from_le_bytes([data[entry.offset as usize], data[entry.offset as usize + 1], data[entry.offset as usize + 2], data[entry.offset as usize + 3]]);
+                assert_eq!(frame_count as usize, block_frames.min(NUM_FRAMES - start));
+                if let Some(next) = entries.get(index + 1) {
+                    assert_eq!(entry.offset + entry.len as u64, next.offset);
+                }
+                // Each channel's level before the block, as decoding from the start gets there
+                let levels = block_channels.iter().map(|channel| channel.deltas[..start].iter().fold(channel.initial_level, |level, delta| (level as i8).wrapping_add(*delta) as u8)).collect::<Vec<_>>();
+                assert_eq!(entry.levels, levels, "{} frame blocks, block {}", block_frames, index);
+            }
+            assert_eq!(raw::read(&data, DEFAULT_MAX_DEPTH).unwrap().markers, mocap.markers);
+        }
+    }
+
+    #[test]
+    fn random_frames_decode_as_sequentially() {
+        let mut state = 1u32;
+        for bits in [3, 8].iter().cloned() {
+            let mocap = clip(bits);
+            let sequential = build_bvh(&mocap).motion.frames;
+            for block_frames in [1, 4, 16, 45].iter().cloned() {
+                for layout in [Layout::Packed, Layout::BitPlanes].iter().cloned() {
+                    let data = indexed(&mocap, block_frames, layout);
+                    let view = MocapView::parse(&data, DEFAULT_MAX_DEPTH).unwrap();
+                    for _ in 0..60 {
+                        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                        let frame = (state >> 16) as usize % NUM_FRAMES;
+                        assert_eq!(view.decode_frame_at(frame).unwrap(), sequential[frame], "{} bits, {} frame blocks, {:?}, frame {}", bits, block_frames, layout, frame);
+                    }
+                    assert!(matches!(view.decode_frame_at(NUM_FRAMES), Err(MocapError::Usage(_))));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_an_index_disagreeing_with_the_blocks() {
+        let data = indexed(&clip(8), 16, Layout::Packed);
+        let index_offset = u64::from_le_bytes(data[data.len() - 8..].try_into().unwrap()) as usize;
+        let first_entry = index_offset + 8;
+
+        // The first entry's start frame, offset and length, and the index offset
+        for position in [first_entry, first_entry + 4, first_entry + 12, data.len() - 8].iter() {
+            let mut corrupted = data.clone();
+            corrupted[*position] ^= 1;
+            assert!(matches!(MocapView::parse(&corrupted, DEFAULT_MAX_DEPTH), Err(MocapError::InvalidRaw(_))), "byte {}", position);
+        }
+
+        // A level past a 3-bit grid
+        let data = indexed(&clip(3), 16, Layout::Packed);
+        let index_offset = u64::from_le_bytes(data[data.len() - 8..].try_into().unwrap()) as usize;
+        let mut corrupted = data.clone();
+        corrupted[index_offset + 8 + 16] = 8;
+        assert!(matches!(MocapView::parse(&corrupted, DEFAULT_MAX_DEPTH), Err(MocapError::InvalidRaw(ref message)) if message.contains("past the 3 bit grid")));
+    }
+}
@@ -0,0 +1,163 @@
+use bvh;
+
+use error::MocapError;
+
+// Joint selectors, used by every option that picks joints.
+//
+// A selector is a `/`-separated list of segments matched against the names along a joint's path
+// from the root, ending at the selected joint:
+//
+//   LeftHand                    any joint named LeftHand
+//   Chest/LeftShoulder          a LeftShoulder whose parent is Chest
+//   /Hips/Spine                 anchored at the root: Spine directly under the root joint Hips
+//   */LeftHand/*                any child of a LeftHand that isn't itself the root
+//   LeftHand/**                 LeftHand and every joint below it
+//
+// Within a segment `*` matches any run of characters and `?` any single character; a `**` segment
+// matches any number (including zero) of whole joints. Without a leading `/` the selector can
+// start matching at any depth. `\` escapes the next character, so names containing `/`, `*`, `?`
+// or `\` can still be selected exactly (`Prop\/Sword`). Selectors match the disambiguated joint
+// names (`Spine#2`), see `names::make_unique`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    anchored: bool,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    AnyJoints,
+    Pattern(Vec<Token>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Char(char),
+    AnyChar,
+    AnyChars,
+}
+
+impl Selector {
+    pub fn parse(s: &str) -> Result<Selector, MocapError> {
+        let invalid = |message: &str| MocapError::InvalidSelector(format!("\"{}\": {}", s, message));
+
+        let mut anchored = false;
+        let mut segments = Vec::new();
+        let mut tokens = Vec::new();
+        let mut escaped_segment = false;
+        let mut chars = s.chars();
+        let mut at_start = true;
+        loop {
+            let c = chars.next();
+            match c {
+                Some('/') if at_start => anchored = true,
+                None | Some('/') => {
+                    if tokens.is_empty() {
+                        return Err(invalid("empty path segment"));
+                    }
+                    if !escaped_segment && tokens == [Token::AnyChars, Token::AnyChars] {
+                        segments.push(Segment::AnyJoints);
+                    } else {
+                        segments.push(Segment::Pattern(tokens));
+                    }
+                    tokens = Vec::new();
+                    escaped_segment = false;
+                    if c.is_none() {
+                        break;
+                    }
+                }
+                Some('\\') => {
+                    tokens.push(Token::Char(chars.next().ok_or_else(|| invalid("trailing escape character"))?));
+                    escaped_segment = true;
+                }
+                Some('*') => tokens.push(Token::AnyChars),
+                Some('?') => tokens.push(Token::AnyChar),
+                Some(c) => tokens.push(Token::Char(c)),
+            }
+            at_start = false;
+        }
+
+        Ok(Selector {
+            anchored: anchored,
+            segments: segments,
+        })
+    }
+
+    // `path` is the joint names from the root down to (and including) the joint being tested.
+    pub fn matches(&self, path: &[&str]) -> bool {
+        if self.anchored {
+            matches_segments(&self.segments, path)
+        } else {
+            (0..path.len()).any(|start| matches_segments(&self.segments, &path[start..]))
+        }
+    }
+}
+
+fn matches_segments(segments: &[Segment], path: &[&str]) -> bool {
+    match segments.split_first() {
+        None => path.is_empty(),
+        Some((&Segment::AnyJoints, rest)) => (0..path.len() + 1).any(|skip| matches_segments(rest, &path[skip..])),
+        Some((Segment::Pattern(tokens), rest)) => match path.split_first() {
+            Some((name, path)) => matches_pattern(tokens, &name.chars().collect::<Vec<_>>()) && matches_segments(rest, path),
+            None => false,
+        },
+    }
+}
+
+fn matches_pattern(tokens: &[Token], name: &[char]) -> bool {
+    match tokens.split_first() {
+        None => name.is_empty(),
+        Some((&Token::AnyChars, rest)) => (0..name.len() + 1).any(|skip| matches_pattern(rest, &name[skip..])),
+        Some((&Token::AnyChar, rest)) => !name.is_empty() && matches_pattern(rest, &name[1..]),
+        Some((&Token::Char(c), rest)) => name.first() == Some(&c) && matches_pattern(rest, &name[1..]),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JointMatch {
+    pub joint_index: usize, // Pre-order
+    pub channel_index: usize, // Flat index of the joint's first channel
+    pub num_channels: usize,
+    pub path: String, // Escaped, for messages
+}
+
+// Every joint under (and including) `root` matching `selector`, in pre-order.
+pub fn find_joints(root: &bvh::Joint, selector: &Selector) -> Vec<JointMatch> {
+    let mut ret = Vec::new();
+    push_matches(root, selector, &mut Vec::new(), &mut 0, &mut 0, &mut ret);
+    ret
+}
+
+fn push_matches<'a>(joint: &'a bvh::Joint, selector: &Selector, path: &mut Vec<&'a str>, joint_index: &mut usize, channel_index: &mut usize, matches: &mut Vec<JointMatch>) {
+    path.push(&joint.name);
+    if selector.matches(path) {
+        matches.push(JointMatch {
+            joint_index: *joint_index,
+            channel_index: *channel_index,
+            num_channels: joint.channels.len(),
+            path: path.iter().map(|name| escape(name)).collect::<Vec<_>>().join("/"),
+        });
+    }
+
+    *joint_index += 1;
+    *channel_index += joint.channels.len();
+
+    if let bvh::JointChildren::Joints(ref joints) = joint.children {
+        for child in joints.iter() {
+            push_matches(child, selector, path, joint_index, channel_index, matches);
+        }
+    }
+    path.pop();
+}
+
+// Escapes a joint name so it can be used as a literal selector segment, or shown as part of a path.
+pub fn escape(name: &str) -> String {
+    let mut ret = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c == '/' || c == '*' || c == '?' || c == '\\' {
+            ret.push('\\');
+        }
+        ret.push(c);
+    }
+    ret
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use bvh;
+
+use error::MocapError;
+use input;
+use options::Options;
+use selector;
+use {channel_type, count_bvh_channels};
+
+// --self-check: reads the BVH output back with `bvh::parse` (after the same line ending
+// normalization inputs get) and fails if it doesn't parse, or if its hierarchy isn't the source's:
+// the same joints with the same (original) names, channels and offsets, and end sites, and as many
+// frames as were encoded. Offsets are compared at the f32 precision the encoding keeps them at.
+// Cheap insurance against the serializer writing something unusual inputs make unreadable.
+pub fn check(output_file_name: &Path, source: &bvh::Bvh, original_names: &HashMap<String, String>, num_frames: u32, options: &Options) -> Result<(), MocapError> {
+    let fail = |message: String| MocapError::SelfCheck(format!("{}: {}", output_file_name.display(), message));
+    let output = match input::read_bvh(output_file_name, options) {
+        Ok(output) => output,
+        Err(MocapError::Parse(message)) => return Err(fail(format!("doesn't parse: {}", message))),
+        Err(e) => return Err(e),
+    };
+
+    let mut differences = Vec::new();
+    compare_joints(&source.hierarchy.root, &output.hierarchy.root, original_names, "", &mut differences);
+    if !differences.is_empty() {
+        return Err(fail(format!("the hierarchy differs from the source's: {}", differences.join(", "))));
+    }
+    if output.motion.frames.len() != num_frames as usize {
+        return Err(fail(format!("{} frames, expected {}", output.motion.frames.len(), num_frames)));
+    }
+    let num_channels = count_bvh_channels(&output.hierarchy.root);
+    if let Some(frame) = output.motion.frames.iter().position(|frame| frame.len() != num_channels) {
+        return Err(fail(format!("frame {} has {} values, expected {}", frame, output.motion.frames[frame].len(), num_channels)));
+    }
+    Ok(())
+}
+
+fn compare_joints(source: &bvh::Joint, output: &bvh::Joint, original_names: &HashMap<String, String>, parent_path: &str, differences: &mut Vec<String>) {
+    let name = selector::escape(&source.name);
+    let path = if parent_path.is_empty() { name } else { format!("{}/{}", parent_path, name) };
+
+    let expected_name = original_names.get(&source.name).unwrap_or(&source.name);
+    if output.name != *expected_name {
+        differences.push(format!("{}: named {}", path, output.name));
+    }
+    if !same_offset(&source.offset, &output.offset) {
+        differences.push(format!("{}: offset {} {} {}, expected {} {} {}", path, output.offset.x, output.offset.y, output.offset.z, source.offset.x, source.offset.y, source.offset.z));
+    }
+    let channel_names = |joint: &bvh::Joint| joint.channels.iter().map(|channel| channel_type(channel).name()).collect::<Vec<_>>().join(" ");
+    if channel_names(source) != channel_names(output) {
+        differences.push(format!("{}: channels {}, expected {}", path, channel_names(output), channel_names(source)));
+    }
+    match (&source.children, &output.children) {
+        (bvh::JointChildren::Joints(source), bvh::JointChildren::Joints(output)) if source.len() == output.len() => {
+            for (source, output) in source.iter().zip(output.iter()) {
+                compare_joints(source, output, original_names, &path, differences);
+            }
+        }
+        (bvh::JointChildren::EndSite(source), bvh::JointChildren::EndSite(output)) => {
+            if !same_offset(&source.offset, &output.offset) {
+                differences.push(format!("{}: end site offset {} {} {}, expected {} {} {}", path, output.offset.x, output.offset.y, output.offset.z, source.offset.x, source.offset.y, source.offset.z));
+            }
+        }
+        (source, output) => differences.push(format!("{}: {}, expected {}", path, describe_children(output), describe_children(source))),
+    }
+}
+
+fn same_offset(a: &bvh::Offset, b: &bvh::Offset) -> bool {
+    a.x as f32 == b.x as f32 && a.y as f32 == b.y as f32 && a.z as f32 == b.z as f32
+}
+
+fn describe_children(children: &bvh::JointChildren) -> String {
+    match *children {
+        bvh::JointChildren::Joints(ref joints) => format!("{} child joint{}", joints.len(), if joints.len() == 1 { "" } else { "s" }),
+        bvh::JointChildren::EndSite(_) => "an end site".into(),
+    }
+}
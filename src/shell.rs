@@ -0,0 +1,527 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+
+use bvh;
+
+use error::MocapError;
+use fk;
+use metrics;
+use options::Options;
+use resample::{self, Interpolation};
+use selector::{self, JointMatch, Selector};
+use smooth;
+use {build_bvh, channel_type, load, rotation_channels, ChannelType, Settings, Source};
+
+// An interactive prompt for poking at a clip: `mocap shell <input.bvh>` loads it through the usual
+// input passes and reads commands a line at a time, printing to the terminal, until `quit` or the
+// end of the input. A command that fails prints why and the prompt carries on; an unknown one
+// prints the command list. Joints are joint path selectors, as for the options taking a <joint>, and
+// `stats` and `err` take every joint one matches. The commands:
+//
+//   tree                              The hierarchy, with every joint's channels
+//   stats <joint>                     Every channel's min, max, mean and standard deviation
+//   plot <joint> <type> [<a>..<b>]    One channel over frames a to b (exclusive, either end
+//                                     optional), as an ASCII plot
+//   sample <time>s | sample <frame>   Every channel at a time in seconds or a fractional frame,
+//                                     interpolated with --interpolation
+//   fk <frame> <joint>                A joint's world position at a frame
+//   err --bits <n> [<joint>]          The reconstruction error of every channel (of the joint) when
+//                                     the clip is quantized to n bits
+//   help, quit
+//
+// Parsing and running commands (`parse`, `Session::execute`) are kept apart from the terminal, so
+// `run_script` can drive a session from any reader, such as a script piped into `mocap shell`,
+// which then doesn't print the prompt.
+
+const HELP: &str = "commands:
+    tree                              print the hierarchy
+    stats <joint>                     channel min, max, mean and standard deviation
+    plot <joint> <type> [<a>..<b>]    ASCII plot of a channel over frames a to b (exclusive)
+    sample <time>s | sample <frame>   every channel at a time in seconds or a fractional frame
+    fk <frame> <joint>                a joint's world position
+    err --bits <n> [<joint>]          reconstruction error at n bits
+    help                              print this
+    quit                              leave the shell";
+
+pub const PROMPT: &str = "mocap> ";
+
+// The size of a plot, in characters
+const PLOT_HEIGHT: usize = 12;
+const PLOT_WIDTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Tree,
+    Stats(String),
+    Plot {
+        joint: String,
+        channel_type: ChannelType,
+        start: Option<usize>,
+        end: Option<usize>,
+    },
+    Sample(Time),
+    Fk {
+        frame: usize,
+        joint: String,
+    },
+    Err {
+        bits: u8,
+        joint: Option<String>,
+    },
+    Help,
+    Quit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Time {
+    Seconds(f64),
+    Frame(f64),
+}
+
+// A line's command, None for a blank line or a comment (starting with #).
+pub fn parse(line: &str) -> Result<Option<Command>, String> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let (name, args) = match words.split_first() {
+        Some((name, _)) if name.starts_with('#') => return Ok(None),
+        Some((name, args)) => (*name, args),
+        None => return Ok(None),
+    };
+    let expect = |counts: &[usize], form: &str| if counts.contains(&args.len()) { Ok(()) } else { Err(format!("usage: {}", form)) };
+    Ok(Some(match name {
+        "tree" => {
+            expect(&[0], "tree")?;
+            Command::Tree
+        }
+        "stats" => {
+            expect(&[1], "stats <joint>")?;
+            Command::Stats(args[0].into())
+        }
+        "plot" => {
+            expect(&[2, 3], "plot <joint> <type> [<a>..<b>]")?;
+            let channel_type = ChannelType::from_name(args[1]).ok_or_else(|| format!("invalid channel type {} (expected one of {})", args[1], ChannelType::ALL.iter().map(|type_| type_.name()).collect::<Vec<_>>().join(", ")))?;
+            let (start, end) = match args.get(2) {
+                Some(range) => parse_range(range).ok_or_else(|| format!("invalid frame range {} (expected <a>..<b>)", range))?,
+                None => (None, None),
+            };
+            Command::Plot {
+                joint: args[0].into(),
+                channel_type: channel_type,
+                start: start,
+                end: end,
+            }
+        }
+        "sample" => {
+            expect(&[1], "sample <time>s | sample <frame>")?;
+            let (number, seconds) = match args[0].strip_suffix('s') {
+                Some(number) => (number, true),
+                None => (args[0], false),
+            };
+            let value = number.parse::<f64>().ok().filter(|value| value.is_finite() && *value >= 0.0).ok_or_else(|| format!("invalid time {}", args[0]))?;
+            Command::Sample(if seconds { Time::Seconds(value) } else { Time::Frame(value) })
+        }
+        "fk" => {
+            expect(&[2], "fk <frame> <joint>")?;
+            Command::Fk {
+                frame: args[0].parse().map_err(|_| format!("invalid frame {}", args[0]))?,
+                joint: args[1].into(),
+            }
+        }
+        "err" => {
+            expect(&[2, 3], "err --bits <n> [<joint>]")?;
+            if args[0] != "--bits" {
+                return Err("usage: err --bits <n> [<joint>]".into());
+            }
+            Command::Err {
+                bits: args[1].parse().ok().filter(|bits| (1..=8).contains(bits)).ok_or_else(|| format!("invalid bit depth {} (expected 1 to 8)", args[1]))?,
+                joint: args.get(2).map(|joint| joint.to_string()),
+            }
+        }
+        "help" => Command::Help,
+        "quit" | "exit" => Command::Quit,
+        other => return Err(format!("unknown command {}\n{}", other, HELP)),
+    }))
+}
+
+// `<a>..<b>`, either end optional.
+fn parse_range(spec: &str) -> Option<(Option<usize>, Option<usize>)> {
+    let dots = spec.find("..")?;
+    let bound = |s: &str| if s.is_empty() { Some(None) } else { s.parse().ok().map(Some) };
+    Some((bound(&spec[..dots])?, bound(&spec[dots + 2..])?))
+}
+
+pub struct Session {
+    name: String,
+    source: Source,
+    interpolation: Interpolation,
+}
+
+impl Session {
+    pub fn load(input_file_name: &Path, options: &Options) -> Result<Session, MocapError> {
+        Ok(Session {
+            name: input_file_name.display().to_string(),
+            source: load(input_file_name, options)?,
+            interpolation: options.interpolation,
+        })
+    }
+
+    // Runs `command`, printing to `w`; false once the session is over.
+    pub fn execute<W: Write>(&self, command: &Command, w: &mut W) -> Result<bool, MocapError> {
+        let bvh = &self.source.bvh;
+        let root = &bvh.hierarchy.root;
+        let frames = &bvh.motion.frames;
+        match *command {
+            Command::Tree => write_tree(root, 0, w)?,
+            Command::Stats(ref joint) => {
+                let names = smooth::channel_names(root);
+                for joint_match in find_joints(root, joint)? {
+                    for index in joint_match.channel_index..joint_match.channel_index + joint_match.num_channels {
+                        let values = frames.iter().map(|frame| frame[index]).collect::<Vec<_>>();
+                        if values.is_empty() {
+                            writeln!(w, "{}: no frames", names[index])?;
+                            continue;
+                        }
+                        let mean = values.iter().sum::<f64>() / values.len() as f64;
+                        let variance = values.iter().map(|value| (value - mean) * (value - mean)).sum::<f64>() / values.len() as f64;
+                        writeln!(w, "{}: min {:.4}, max {:.4}, mean {:.4}, std dev {:.4}", names[index],
+                            values.iter().cloned().fold(f64::INFINITY, f64::min), values.iter().cloned().fold(f64::NEG_INFINITY, f64::max), mean, variance.sqrt())?;
+                    }
+                }
+            }
+            Command::Plot { ref joint, channel_type: type_, start, end } => {
+                let joint_match = find_joint(root, joint)?;
+                let channel = channel_types(root)[joint_match.channel_index..joint_match.channel_index + joint_match.num_channels].iter().position(|other| *other == type_)
+                    .ok_or_else(|| MocapError::Usage(format!("{} has no {} channel", joint_match.path, type_.name())))?;
+                let (start, end) = (start.unwrap_or(0), end.unwrap_or(frames.len()).min(frames.len()));
+                if start >= end {
+                    return Err(MocapError::Usage(format!("no frames to plot between {} and {} ({} frames)", start, end, frames.len())));
+                }
+                let values = frames[start..end].iter().map(|frame| frame[joint_match.channel_index + channel]).collect::<Vec<_>>();
+                writeln!(w, "{} {}, frames {} to {}:", joint_match.path, type_.name(), start, end - 1)?;
+                write_plot(&values, start, w)?;
+            }
+            Command::Sample(time) => {
+                let position = match time {
+                    Time::Seconds(seconds) => seconds / bvh.motion.frame_time,
+                    Time::Frame(frame) => frame,
+                };
+                if frames.is_empty() || !position.is_finite() || position > (frames.len() - 1) as f64 {
+                    return Err(MocapError::Usage(format!("that's past the last frame ({} frames, {} s apart)", frames.len(), bvh.motion.frame_time)));
+                }
+                let values = resample::sample(frames, &rotation_channels(root), position, self.interpolation);
+                writeln!(w, "frame {:.4}:", position)?;
+                for (name, value) in smooth::channel_names(root).iter().zip(values.iter()) {
+                    writeln!(w, "    {}: {:.4}", name, value)?;
+                }
+            }
+            Command::Fk { frame, ref joint } => {
+                let joint_match = find_joint(root, joint)?;
+                let values = frames.get(frame).ok_or_else(|| MocapError::Usage(format!("frame {} is past the last frame ({} frames)", frame, frames.len())))?;
+                let (x, y, z) = fk::world_transforms(root, values)[joint_match.joint_index].position();
+                writeln!(w, "{} at frame {}: {:.4} {:.4} {:.4}", joint_match.path, frame, x, y, z)?;
+            }
+            Command::Err { bits, ref joint } => {
+                let settings = Settings {
+                    channel_quantization_bits: bits,
+                    ..self.source.conversion.settings()
+                };
+                let decoded = build_bvh(&self.source.build_mocap(&settings)).motion.frames;
+                let channels = match *joint {
+                    Some(ref joint) => find_joints(root, joint)?.into_iter().flat_map(|joint_match| joint_match.channel_index..joint_match.channel_index + joint_match.num_channels).collect::<Vec<_>>(),
+                    None => (0..channel_types(root).len()).collect(),
+                };
+                let errors = metrics::grouped_reconstruction_errors(frames, &decoded, &(0..channel_types(root).len()).collect::<Vec<_>>());
+                let names = smooth::channel_names(root);
+                for index in channels.iter() {
+                    writeln!(w, "{}: max error {:.6}, rms error {:.6}", names[*index], errors[*index].max, errors[*index].rms)?;
+                }
+                if joint.is_none() {
+                    let error = metrics::reconstruction_error(frames, &decoded);
+                    writeln!(w, "all channels at {} bits: max error {:.6}, rms error {:.6}", bits, error.max, error.rms)?;
+                }
+            }
+            Command::Help => writeln!(w, "{}", HELP)?,
+            Command::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+// Runs the commands read from `input` until `quit` or the end of it, printing the prompt before
+// each if `prompt`.
+pub fn run_script<R: BufRead, W: Write>(session: &Session, input: R, w: &mut W, prompt: bool) -> Result<(), MocapError> {
+    let mut lines = input.lines();
+    loop {
+        if prompt {
+            write!(w, "{}", PROMPT)?;
+            w.flush()?;
+        }
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        let result = match parse(&line) {
+            Ok(Some(command)) => session.execute(&command, w),
+            Ok(None) => Ok(true),
+            Err(message) => Err(MocapError::Usage(message)),
+        };
+        match result {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            // An I/O error is writing to the terminal; the rest only fail the command
+            Err(MocapError::Io(e)) => return Err(MocapError::Io(e)),
+            Err(e) => writeln!(w, "error: {}", e)?,
+        }
+    }
+    if prompt {
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+pub fn run(input_file_name: &Path, options: &Options) -> Result<(), MocapError> {
+    let session = Session::load(input_file_name, options)?;
+    let stdin = io::stdin();
+    let prompt = stdin.is_terminal();
+    let stdout = io::stdout();
+    let mut w = stdout.lock();
+    if prompt {
+        let bvh = &session.source.bvh;
+        writeln!(w, "{}: {} frames, {} channels; type help for the commands", session.name, bvh.motion.frames.len(), channel_types(&bvh.hierarchy.root).len())?;
+    }
+    run_script(&session, stdin.lock(), &mut w, prompt)
+}
+
+fn find_joints(root: &bvh::Joint, selector_string: &str) -> Result<Vec<JointMatch>, MocapError> {
+    let matches = selector::find_joints(root, &Selector::parse(selector_string)?);
+    if matches.is_empty() {
+        return Err(MocapError::JointNotFound(selector_string.into()));
+    }
+    Ok(matches)
+}
+
+fn find_joint(root: &bvh::Joint, selector_string: &str) -> Result<JointMatch, MocapError> {
+    let mut matches = find_joints(root, selector_string)?;
+    if matches.len() > 1 {
+        return Err(MocapError::AmbiguousSelector(selector_string.into(), matches.into_iter().map(|joint_match| joint_match.path).collect()));
+    }
+    Ok(matches.remove(0))
+}
+
+// Every channel's type, in flat channel order.
+fn channel_types(joint: &bvh::Joint) -> Vec<ChannelType> {
+    let mut ret = joint.channels.iter().map(channel_type).collect::<Vec<_>>();
+    if let bvh::JointChildren::Joints(ref joints) = joint.children {
+        for joint in joints.iter() {
+            ret.extend(channel_types(joint));
+        }
+    }
+    ret
+}
+
+fn write_tree<W: Write>(joint: &bvh::Joint, depth: usize, w: &mut W) -> io::Result<()> {
+    let channels = joint.channels.iter().map(|channel| channel_type(channel).name()).collect::<Vec<_>>();
+    writeln!(w, "{}{}{}", "  ".repeat(depth), joint.name, if channels.is_empty() { String::new() } else { format!(" [{}]", channels.join(" ")) })?;
+    if let bvh::JointChildren::Joints(ref joints) = joint.children {
+        for joint in joints.iter() {
+            write_tree(joint, depth + 1, w)?;
+        }
+    }
+    Ok(())
+}
+
+// Plots `values`, from frame `start` on, PLOT_HEIGHT rows high: a column per frame, or with more
+// frames than PLOT_WIDTH, per run of frames, spanning the run's values.
+fn write_plot<W: Write>(values: &[f64], start: usize, w: &mut W) -> io::Result<()> {
+    let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let row = |value: f64| if max > min { ((value - min) / (max - min) * (PLOT_HEIGHT - 1) as f64).round() as usize } else { PLOT_HEIGHT / 2 };
+    let num_columns = values.len().min(PLOT_WIDTH);
+    let mut grid = vec![vec![' '; num_columns]; PLOT_HEIGHT];
+    for column in 0..num_columns {
+        let run = &values[column * values.len() / num_columns..((column + 1) * values.len() / num_columns).max(column * values.len() / num_columns + 1)];
+        let (low, high) = (run.iter().cloned().fold(f64::INFINITY, f64::min), run.iter().cloned().fold(f64::NEG_INFINITY, f64::max));
+        for line in grid[row(low)..=row(high)].iter_mut() {
+            line[column] = '*';
+        }
+    }
+
+    let labels = (format!("{:.4}", max), format!("{:.4}", min));
+    let label_width = labels.0.len().max(labels.1.len());
+    for (index, line) in grid.iter().enumerate().rev() {
+        let label = match index {
+            index if index == PLOT_HEIGHT - 1 => &labels.0,
+            0 => &labels.1,
+            _ => "",
+        };
+        writeln!(w, "{:>width$} |{}", label, line.iter().collect::<String>(), width = label_width)?;
+    }
+    writeln!(w, "{:>width$} +{}", "", "-".repeat(num_columns), width = label_width)?;
+    let (first, last) = (start.to_string(), (start + values.len() - 1).to_string());
+    writeln!(w, "{:>width$}  {}{:>last_width$}", "", first, last, width = label_width, last_width = num_columns.saturating_sub(first.len()).max(last.len() + 1))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use test_util;
+
+    const NUM_FRAMES: usize = 30;
+
+    fn session() -> Session {
+        let dir = test_util::temp_dir("shell");
+        fs::write(dir.join("in.bvh"), test_util::clip_text(NUM_FRAMES, test_util::sine)).unwrap();
+        Session::load(&dir.join("in.bvh"), &test_util::options(&[])).unwrap()
+    }
+
+    // The output of running `script`.
+    fn run(session: &Session, script: &str, prompt: bool) -> String {
+        let mut output = Vec::new();
+        run_script(session, script.as_bytes(), &mut output, prompt).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse("tree"), Ok(Some(Command::Tree)));
+        assert_eq!(parse("  stats   Spine "), Ok(Some(Command::Stats("Spine".into()))));
+        assert_eq!(parse("plot Hips RotationZ 10..20"), Ok(Some(Command::Plot { joint: "Hips".into(), channel_type: ChannelType::RotationZ, start: Some(10), end: Some(20) })));
+        assert_eq!(parse("plot Hips RotationZ ..20"), Ok(Some(Command::Plot { joint: "Hips".into(), channel_type: ChannelType::RotationZ, start: None, end: Some(20) })));
+        assert_eq!(parse("plot Hips RotationZ"), Ok(Some(Command::Plot { joint: "Hips".into(), channel_type: ChannelType::RotationZ, start: None, end: None })));
+        assert_eq!(parse("sample 1.5s"), Ok(Some(Command::Sample(Time::Seconds(1.5)))));
+        assert_eq!(parse("sample 12.25"), Ok(Some(Command::Sample(Time::Frame(12.25)))));
+        assert_eq!(parse("fk 12 Head"), Ok(Some(Command::Fk { frame: 12, joint: "Head".into() })));
+        assert_eq!(parse("err --bits 4"), Ok(Some(Command::Err { bits: 4, joint: None })));
+        assert_eq!(parse("err --bits 4 Spine"), Ok(Some(Command::Err { bits: 4, joint: Some("Spine".into()) })));
+        assert_eq!(parse("help"), Ok(Some(Command::Help)));
+        assert_eq!(parse("quit"), Ok(Some(Command::Quit)));
+        assert_eq!(parse("exit"), Ok(Some(Command::Quit)));
+        assert_eq!(parse(""), Ok(None));
+        assert_eq!(parse("   "), Ok(None));
+        assert_eq!(parse("# stats Spine"), Ok(None));
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        assert_eq!(parse("tree Hips"), Err("usage: tree".into()));
+        assert_eq!(parse("stats"), Err("usage: stats <joint>".into()));
+        assert!(parse("plot Hips Twist").unwrap_err().starts_with("invalid channel type Twist"));
+        assert_eq!(parse("plot Hips RotationZ 10-20"), Err("invalid frame range 10-20 (expected <a>..<b>)".into()));
+        assert_eq!(parse("plot Hips RotationZ a..20"), Err("invalid frame range a..20 (expected <a>..<b>)".into()));
+        assert_eq!(parse("sample -1s"), Err("invalid time -1s".into()));
+        assert_eq!(parse("sample soon"), Err("invalid time soon".into()));
+        assert_eq!(parse("fk x Head"), Err("invalid frame x".into()));
+        assert_eq!(parse("err --bits 9"), Err("invalid bit depth 9 (expected 1 to 8)".into()));
+        assert_eq!(parse("err -b 4"), Err("usage: err --bits <n> [<joint>]".into()));
+        assert_eq!(parse("dance"), Err(format!("unknown command dance\n{}", HELP)));
+    }
+
+    #[test]
+    fn runs_a_scripted_session() {
+        let session = session();
+        let output = run(&session, "tree\n# a comment\n\nsample 2\nfk 0 Head\n", false);
+        let frame = &session.source.bvh.motion.frames[2];
+        let sample = smooth::channel_names(&session.source.bvh.hierarchy.root).iter().zip(frame.iter())
+            .map(|(name, value)| format!("    {}: {:.4}\n", name, value)).collect::<String>();
+        let (x, y, z) = fk::world_transforms(&session.source.bvh.hierarchy.root, &session.source.bvh.motion.frames[0])[2].position();
+        assert_eq!(output, format!("\
+Hips [TranslationX TranslationY TranslationZ RotationZ RotationX RotationY]
+  Spine [RotationZ RotationX RotationY]
+    Head [RotationZ RotationX RotationY]
+  LeftLeg [RotationZ RotationX RotationY]
+frame 2.0000:
+{}Hips/Spine/Head at frame 0: {:.4} {:.4} {:.4}
+", sample, x, y, z));
+
+        // A second in is frame 30, past the last
+        let output = run(&session, "sample 0.5s\nsample 1s\n", false);
+        assert!(output.starts_with("frame 15.000"), "{}", output);
+        assert!(output.ends_with("error: that's past the last frame (30 frames, 0.033333 s apart)\n"), "{}", output);
+    }
+
+    #[test]
+    fn failing_commands_print_why_and_carry_on() {
+        let session = session();
+        let output = run(&session, "dance\nstats Nope\nplot Head TranslationX\nplot Head RotationX 40..\nfk 30 Head\nstats Head\nquit\ntree\n", false);
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "error: unknown command dance");
+        let rest = &lines[1 + HELP.lines().count()..];
+        assert_eq!(rest[0], "error: no joint matches \"Nope\"");
+        assert_eq!(rest[1], "error: Hips/Spine/Head has no TranslationX channel");
+        assert_eq!(rest[2], "error: no frames to plot between 40 and 30 (30 frames)");
+        assert_eq!(rest[3], "error: frame 30 is past the last frame (30 frames)");
+        // Stats still runs, and nothing after quit does
+        assert_eq!(rest.len(), 7);
+        assert!(rest[4..].iter().all(|line| line.starts_with("Head Rotation") && line.contains(", std dev ")), "{:?}", rest);
+    }
+
+    #[test]
+    fn stats_cover_every_channel_of_the_joint() {
+        let session = session();
+        let output = run(&session, "stats Spine\n", false);
+        let values = session.source.bvh.motion.frames.iter().map(|frame| frame[6]).collect::<Vec<_>>();
+        let mean = values.iter().sum::<f64>() / NUM_FRAMES as f64;
+        let std_dev = (values.iter().map(|value| (value - mean) * (value - mean)).sum::<f64>() / NUM_FRAMES as f64).sqrt();
+        let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], format!("Spine RotationZ: min {:.4}, max {:.4}, mean {:.4}, std dev {:.4}", min, max, mean, std_dev));
+        assert!(lines[1].starts_with("Spine RotationX: ") && lines[2].starts_with("Spine RotationY: "));
+    }
+
+    #[test]
+    fn err_reports_the_error_at_a_bit_depth() {
+        let session = session();
+        let output = run(&session, "err --bits 4 Spine\n", false);
+        assert_eq!(output.lines().count(), 3);
+        assert!(output.starts_with("Spine RotationZ: max error "), "{}", output);
+
+        // Every channel, then all of them
+        let parse_max = |line: &str| line.split("max error ").nth(1).unwrap().split(',').next().unwrap().parse::<f64>().unwrap();
+        let coarse = run(&session, "err --bits 2\n", false);
+        let fine = run(&session, "err --bits 8\n", false);
+        assert_eq!(fine.lines().count(), test_util::NUM_CHANNELS + 1);
+        let last = fine.lines().last().unwrap();
+        assert!(last.starts_with("all channels at 8 bits: "), "{}", last);
+        assert!(parse_max(last) < parse_max(coarse.lines().last().unwrap()));
+    }
+
+    #[test]
+    fn plots_a_column_per_frame() {
+        let session = session();
+        let output = run(&session, "plot Hips TranslationX 0..10\n", false);
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "Hips TranslationX, frames 0 to 9:");
+        // The title, the rows, the axis and the frame numbers
+        assert_eq!(lines.len(), 1 + PLOT_HEIGHT + 2);
+        let rows = lines[1..=PLOT_HEIGHT].iter().map(|line| line.split('|').nth(1).unwrap()).collect::<Vec<_>>();
+        assert!(rows.iter().all(|row| row.chars().count() == 10));
+        for column in 0..10 {
+            assert!(rows.iter().any(|row| row.chars().nth(column) == Some('*')));
+        }
+        assert!(lines[PLOT_HEIGHT + 1].ends_with(&format!("+{}", "-".repeat(10))));
+        assert!(lines[PLOT_HEIGHT + 2].trim().starts_with('0') && lines[PLOT_HEIGHT + 2].ends_with('9'));
+
+        // At most PLOT_WIDTH columns, each spanning a run of frames
+        let mut output = Vec::new();
+        write_plot(&(0..200).map(|frame| frame as f64).collect::<Vec<_>>(), 0, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let rows = output.lines().take(PLOT_HEIGHT).map(|line| line.split('|').nth(1).unwrap().to_string()).collect::<Vec<_>>();
+        assert!(rows.iter().all(|row| row.chars().count() == PLOT_WIDTH));
+        // Rising: the top row's star is at the right, the bottom row's at the left
+        assert_eq!(rows[0].chars().last(), Some('*'));
+        assert_eq!(rows[PLOT_HEIGHT - 1].chars().next(), Some('*'));
+    }
+
+    #[test]
+    fn prompts_before_each_command() {
+        let session = session();
+        assert_eq!(run(&session, "help\n", true), format!("{}{}\n{}\n", PROMPT, HELP, PROMPT));
+        assert_eq!(run(&session, "quit\nhelp\n", true), PROMPT);
+        assert_eq!(run(&session, "help\n", false), format!("{}\n", HELP));
+    }
+}
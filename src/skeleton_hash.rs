@@ -0,0 +1,59 @@
+use cache;
+use raw;
+use {Joint, JointChildren, Mocap};
+
+// Skeleton hashes, for tracking which rig a clip was captured on: clips with the same hash have
+// the same skeleton, and a changed hash means the skeleton changed, whatever the motion. The hash
+// is the 128-bit FNV-1a of cache.rs over this serialization of the hierarchy, walked in pre-order:
+//
+//   b"mocap skeleton 1"
+//   per joint
+//     name         u32 byte length + UTF-8, the name in the source (before any disambiguation)
+//     offset       three f32s, X Y Z
+//     channels     u8 count, then each channel's type as a u8, as in a .raw file (see raw.rs)
+//     children     u32 count of child joints, which follow, or 0xffffffff and the end site's
+//                  offset as three f32s
+//
+// All little-endian, with -0.0 taken as 0.0. Motion, metadata and the encoding don't take part,
+// so a clip has the same hash from its BVH source, its .raw file and any re-encoding of it, but
+// offsets compare exactly: one rounded differently gives a different hash. Channel order is part
+// of the skeleton, as the shared-skeleton operations (concat, diff) need it to match.
+
+const PREFIX: &[u8] = b"mocap skeleton 1";
+const END_SITE: u32 = 0xffffffff;
+
+impl Mocap {
+    pub fn skeleton_hash(&self) -> u128 {
+        let mut data = PREFIX.to_vec();
+        push_joint(&self.root, &mut data);
+        cache::hash(&data)
+    }
+}
+
+fn push_joint(joint: &Joint, data: &mut Vec<u8>) {
+    let name = joint.original_name.as_ref().unwrap_or(&joint.name);
+    data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    data.extend_from_slice(name.as_bytes());
+    push_offset(&joint.offset, data);
+    data.push(joint.channels.len() as u8);
+    data.extend(joint.channels.iter().map(|channel| raw::channel_type_id(channel.type_)));
+    match joint.children {
+        JointChildren::Joints(ref joints) => {
+            data.extend_from_slice(&(joints.len() as u32).to_le_bytes());
+            for child in joints.iter() {
+                push_joint(child, data);
+            }
+        }
+        JointChildren::EndSite(ref offset) => {
+            data.extend_from_slice(&END_SITE.to_le_bytes());
+            push_offset(offset, data);
+        }
+    }
+}
+
+fn push_offset(offset: &(f32, f32, f32), data: &mut Vec<u8>) {
+    for value in [offset.0, offset.1, offset.2].iter() {
+        // + 0.0 turns -0.0 into 0.0
+        data.extend_from_slice(&(value + 0.0).to_le_bytes());
+    }
+}
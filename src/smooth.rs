@@ -0,0 +1,321 @@
+use std::f64::consts::PI;
+
+use bvh;
+
+use log;
+use {channel_type, rotation_channels};
+
+// Smoothing the input before quantization, with --smooth. Optical captures jitter even when the
+// actor stands still, which costs bits in every delta; filtering each channel over time removes
+// most of it. This changes the motion, so it's lossy relative to the capture (see
+// `Options::lossy_operations`).
+//
+// Rotation channels are filtered as continuous angles, so a channel wrapping from 180 to -180
+// degrees isn't averaged through 0; the result is put back on each frame's original side of the
+// wrap.
+//
+// With --auto-smooth each channel gets its own moving average instead, chosen from its noise floor:
+// an estimate of the standard deviation of the jitter on top of the motion, from the median
+// absolute fourth difference. Smooth motion barely contributes to that (it's 0 for anything up to
+// a cubic, where a second difference would count every curve as noise), and the median ignores the
+// odd sudden movement. Averaging n frames of white noise divides its deviation by sqrt(n), so
+// the window is the smallest that brings the noise down to AUTO_SMOOTH_TARGET_NOISE, capped so it
+// reaches at most AUTO_SMOOTH_MAX_LAG seconds to either side. Channels already that quiet are left
+// alone. The estimator and the policy are separate (`noise_floor` and `auto_filter`); the noise
+// floors are also in the --report, with or without smoothing.
+
+// The derivative cutoff of the one euro filter, in Hz, as recommended by its authors
+const ONE_EURO_DERIVATIVE_CUTOFF: f64 = 1.0;
+
+// In channel units (degrees for rotations)
+const AUTO_SMOOTH_TARGET_NOISE: f64 = 0.01;
+const AUTO_SMOOTH_MAX_LAG: f64 = 0.05;
+
+// The median absolute fourth difference of white noise is this many times its standard deviation:
+// a fourth difference (1, -4, 6, -4, 1) has deviation sqrt(70) times the noise's, and the median
+// absolute value of a normal variable is 0.6745 times its deviation
+const FOURTH_DIFFERENCE_MEDIAN: f64 = 5.6433;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    // A centered moving average over an odd number of frames, shrinking (symmetrically) at the
+    // ends of the clip so the first and last frames stay put as much as possible
+    MovingAverage(usize),
+
+    // The one euro filter (Casiez et al. 2012): a low-pass filter whose cutoff (in Hz) rises
+    // with the speed of the motion, so still poses are smoothed heavily while fast motion
+    // doesn't lag. `beta` is how quickly the cutoff rises
+    OneEuro { min_cutoff: f64, beta: f64 },
+}
+
+// `moving-average:<frames>` or `one-euro:<min cutoff>[,<beta>]`, as passed to --smooth.
+pub fn parse(spec: &str) -> Option<Filter> {
+    let colon = spec.find(':')?;
+    let (kind, parameters) = (&spec[..colon], &spec[colon + 1..]);
+    match kind {
+        "moving-average" => {
+            let window = parameters.parse::<usize>().ok()?;
+            if window % 2 == 0 {
+                return None;
+            }
+            Some(Filter::MovingAverage(window))
+        }
+        "one-euro" => {
+            let mut parameters = parameters.splitn(2, ',');
+            let min_cutoff = parameters.next()?.parse::<f64>().ok()?;
+            let beta = match parameters.next() {
+                Some(beta) => beta.parse::<f64>().ok()?,
+                None => 0.0,
+            };
+            if !min_cutoff.is_finite() || min_cutoff <= 0.0 || !beta.is_finite() || beta < 0.0 {
+                return None;
+            }
+            Some(Filter::OneEuro { min_cutoff: min_cutoff, beta: beta })
+        }
+        _ => None,
+    }
+}
+
+// The value --smooth takes for `filter`.
+pub fn spec(filter: &Filter) -> String {
+    match *filter {
+        Filter::MovingAverage(window) => format!("moving-average:{}", window),
+        Filter::OneEuro { min_cutoff, beta } => format!("one-euro:{},{}", min_cutoff, beta),
+    }
+}
+
+pub fn apply(bvh: &mut bvh::Bvh, filter: &Filter) {
+    let filters = vec![Some(*filter); rotation_channels(&bvh.hierarchy.root).len()];
+    apply_filters(bvh, &filters);
+}
+
+// Smooths each channel with its own filter (in flat channel order), if it has one.
+pub fn apply_filters(bvh: &mut bvh::Bvh, filters: &[Option<Filter>]) {
+    let rotations = rotation_channels(&bvh.hierarchy.root);
+    let frame_time = bvh.motion.frame_time;
+    if filters.iter().any(|filter| matches!(filter, Some(Filter::OneEuro { .. }))) && (frame_time.is_nan() || frame_time <= 0.0) {
+        log::warning(format!("frame time {} isn't positive, so the one euro filter can't be applied; not smoothing", frame_time));
+        return;
+    }
+    let frames = &mut bvh.motion.frames;
+    for ((index, rotation), filter) in rotations.into_iter().enumerate().zip(filters.iter()) {
+        let filter = match *filter {
+            Some(ref filter) => filter,
+            None => continue,
+        };
+        let original = frames.iter().map(|frame| frame[index]).collect::<Vec<_>>();
+        let values = if rotation { unwrap(&original) } else { original.clone() };
+        let smoothed = match *filter {
+            Filter::MovingAverage(window) => moving_average(&values, window),
+            Filter::OneEuro { min_cutoff, beta } => one_euro(&values, frame_time, min_cutoff, beta),
+        };
+        for (((frame, smoothed), value), original) in frames.iter_mut().zip(smoothed.iter()).zip(values.iter()).zip(original.iter()) {
+            frame[index] = smoothed - (value - original);
+        }
+    }
+}
+
+// Every channel's noise floor, in flat channel order.
+pub fn noise_floors(bvh: &bvh::Bvh) -> Vec<f64> {
+    let frames = &bvh.motion.frames;
+    rotation_channels(&bvh.hierarchy.root).into_iter().enumerate().map(|(index, rotation)| {
+        let values = frames.iter().map(|frame| frame[index]).collect::<Vec<_>>();
+        noise_floor(&if rotation { unwrap(&values) } else { values })
+    }).collect()
+}
+
+// The estimated standard deviation of the noise in `values`, or 0 for fewer than five.
+pub fn noise_floor(values: &[f64]) -> f64 {
+    let mut differences = values.windows(5).map(|window| (window[0] - 4.0 * window[1] + 6.0 * window[2] - 4.0 * window[3] + window[4]).abs()).collect::<Vec<_>>();
+    if differences.is_empty() {
+        return 0.0;
+    }
+    differences.sort_by(|a, b| a.total_cmp(b));
+    let middle = differences.len() / 2;
+    let median = if differences.len() % 2 == 0 { (differences[middle - 1] + differences[middle]) / 2.0 } else { differences[middle] };
+    median / FOURTH_DIFFERENCE_MEDIAN
+}
+
+// The filter --auto-smooth uses for a channel with `noise_floor`, if any.
+pub fn auto_filter(noise_floor: f64, frame_time: f64) -> Option<Filter> {
+    if noise_floor.is_nan() || noise_floor <= AUTO_SMOOTH_TARGET_NOISE || frame_time.is_nan() || frame_time <= 0.0 {
+        return None;
+    }
+    let max_radius = (AUTO_SMOOTH_MAX_LAG / frame_time).floor() as usize;
+    let frames = (noise_floor / AUTO_SMOOTH_TARGET_NOISE).powi(2).ceil().min((2 * max_radius + 1) as f64) as usize;
+    // Rounding up to an odd window
+    let window = frames | 1;
+    if window == 1 {
+        return None;
+    }
+    Some(Filter::MovingAverage(window))
+}
+
+// "<joint> <channel type>" for each channel, in flat channel order, for reporting.
+pub fn channel_names(joint: &bvh::Joint) -> Vec<String> {
+    let mut ret = joint.channels.iter().map(|channel| format!("{} {}", joint.name, channel_type(channel).name())).collect::<Vec<_>>();
+    if let bvh::JointChildren::Joints(ref joints) = joint.children {
+        for child in joints.iter() {
+            ret.extend(channel_names(child));
+        }
+    }
+    ret
+}
+
+// Adds multiples of 360 degrees so consecutive angles are at most 180 degrees apart.
+fn unwrap(angles: &[f64]) -> Vec<f64> {
+    let mut ret = Vec::with_capacity(angles.len());
+    let mut offset = 0.0;
+    for (index, angle) in angles.iter().enumerate() {
+        if index > 0 {
+            let previous = angles[index - 1] + offset;
+            offset += 360.0 * ((previous - (angle + offset)) / 360.0).round();
+        }
+        ret.push(angle + offset);
+    }
+    ret
+}
+
+fn moving_average(values: &[f64], window: usize) -> Vec<f64> {
+    let mut prefix_sums = Vec::with_capacity(values.len() + 1);
+    prefix_sums.push(0.0);
+    for value in values.iter() {
+        let sum = prefix_sums.last().unwrap() + value;
+        prefix_sums.push(sum);
+    }
+    (0..values.len()).map(|index| {
+        let radius = (window / 2).min(index).min(values.len() - 1 - index);
+        (prefix_sums[index + radius + 1] - prefix_sums[index - radius]) / ((2 * radius + 1) as f64)
+    }).collect()
+}
+
+fn one_euro(values: &[f64], frame_time: f64, min_cutoff: f64, beta: f64) -> Vec<f64> {
+    let alpha = |cutoff: f64| 1.0 / (1.0 + 1.0 / (2.0 * PI * cutoff * frame_time));
+    let mut ret = Vec::with_capacity(values.len());
+    let mut previous: Option<(f64, f64)> = None; // Filtered value and derivative
+    for value in values.iter() {
+        let filtered = match previous {
+            None => (*value, 0.0),
+            Some((previous_value, previous_derivative)) => {
+                let derivative = (value - previous_value) / frame_time;
+                let derivative = previous_derivative + alpha(ONE_EURO_DERIVATIVE_CUTOFF) * (derivative - previous_derivative);
+                let cutoff = min_cutoff + beta * derivative.abs();
+                (previous_value + alpha(cutoff) * (value - previous_value), derivative)
+            }
+        };
+        ret.push(filtered.0);
+        previous = Some(filtered);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use directives::Directives;
+    use load_bvh;
+    use test_util::{self, noise};
+
+    fn within(value: f64, expected: f64, tolerance: f64) -> bool {
+        (value - expected).abs() <= expected * tolerance
+    }
+
+    #[test]
+    fn estimates_the_deviation_of_white_noise() {
+        for (seed, deviation) in [(1, 0.01), (2, 0.1), (3, 2.0)].iter() {
+            let samples = noise(*seed, 4000).iter().map(|value| value * deviation).collect::<Vec<_>>();
+            assert!(within(noise_floor(&samples), *deviation, 0.1), "{} vs {}", noise_floor(&samples), deviation);
+        }
+
+        // Motion up to a cubic doesn't count, and the odd sudden movement barely does
+        let cubic = (0..4000).map(|frame| {
+            let t = frame as f64 / 100.0;
+            t * t * t * 0.1 - t * t + 3.0 * t
+        }).collect::<Vec<_>>();
+        assert!(noise_floor(&cubic) < 1e-6, "{}", noise_floor(&cubic));
+        let mut noisy = cubic.iter().zip(noise(4, 4000).iter()).map(|(value, noise)| value + noise * 0.1).collect::<Vec<_>>();
+        assert!(within(noise_floor(&noisy), 0.1, 0.1));
+        for frame in (100..4000).step_by(400) {
+            noisy[frame] += 50.0;
+        }
+        assert!(within(noise_floor(&noisy), 0.1, 0.15), "{}", noise_floor(&noisy));
+
+        assert_eq!(noise_floor(&[1.0, 5.0, -3.0, 2.0]), 0.0);
+    }
+
+    #[test]
+    fn smooths_more_noise_with_wider_windows_up_to_the_lag_cap() {
+        let frame_time = 1.0 / 120.0;
+        // Quiet enough already, or no frame time to go by
+        for (noise_floor, frame_time) in [(0.0, frame_time), (AUTO_SMOOTH_TARGET_NOISE, frame_time), (f64::NAN, frame_time), (0.1, 0.0), (0.1, f64::NAN)].iter() {
+            assert_eq!(auto_filter(*noise_floor, *frame_time), None, "{} {}", noise_floor, frame_time);
+        }
+        // sqrt(window) times the target, rounded up to an odd window, and at 120 fps at most 6
+        // frames (50 ms) to either side
+        let windows = [0.015, 0.02, 0.03, 0.035, 0.1].iter().map(|noise_floor| auto_filter(*noise_floor, frame_time)).collect::<Vec<_>>();
+        assert_eq!(windows, [3, 5, 9, 13, 13].iter().map(|window| Some(Filter::MovingAverage(*window))).collect::<Vec<_>>());
+        // At 30 fps, only one
+        assert_eq!(auto_filter(0.1, 0.033333), Some(Filter::MovingAverage(3)));
+        // Just above the target takes more than one frame, so the smallest window there is
+        assert_eq!(auto_filter(AUTO_SMOOTH_TARGET_NOISE * 1.0001, frame_time), Some(Filter::MovingAverage(3)));
+    }
+
+    #[test]
+    fn noisy_channels_are_smoothed_and_clean_ones_left_alone() {
+        // A slow motion, with noise on Spine's Z rotation and, a little, Head's
+        let clean = |frame: usize, channel: usize| (frame as f64 * 0.01 + channel as f64).sin() * (10.0 + channel as f64 * 2.0);
+        let (spine_noise, head_noise) = (noise(5, 600), noise(6, 600));
+        let mut bvh = test_util::parse(&test_util::clip_text(600, |frame, channel| clean(frame, channel) + match channel {
+            6 => spine_noise[frame] * 0.2,
+            9 => head_noise[frame] * 0.02,
+            _ => 0.0,
+        }));
+        bvh.motion.frame_time = 1.0 / 120.0;
+        let original = bvh.motion.frames.clone();
+
+        let floors = noise_floors(&bvh);
+        let filters = floors.iter().map(|floor| auto_filter(*floor, bvh.motion.frame_time)).collect::<Vec<_>>();
+        let windows = filters.iter().map(|filter| match *filter {
+            Some(Filter::MovingAverage(window)) => window,
+            None => 1,
+            Some(filter) => panic!("{:?}", filter),
+        }).collect::<Vec<_>>();
+        assert!(windows[6] > windows[9] && windows[9] > 1, "{:?}", windows);
+        assert!(windows.iter().enumerate().all(|(channel, window)| *window == 1 || channel == 6 || channel == 9), "{:?}", windows);
+        apply_filters(&mut bvh, &filters);
+
+        let rms_error = |frames: &[Vec<f64>], channel: usize| (frames.iter().enumerate().map(|(frame, values)| (values[channel] - clean(frame, channel)).powi(2)).sum::<f64>() / frames.len() as f64).sqrt();
+        for channel in 0..test_util::NUM_CHANNELS {
+            match channel {
+                // Down from about 0.2, to about 0.2 / sqrt(13) plus the little the average bends
+                // the motion
+                6 => assert!(rms_error(&bvh.motion.frames, 6) < rms_error(&original, 6) / 3.0),
+                9 => assert!(rms_error(&bvh.motion.frames, 9) < rms_error(&original, 9) / 1.5),
+                _ => assert!(bvh.motion.frames.iter().zip(original.iter()).all(|(frame, original)| frame[channel] == original[channel]), "channel {}", channel),
+            }
+        }
+    }
+
+    #[test]
+    fn conversions_estimate_noise_floors_with_or_without_smoothing() {
+        let spine_noise = noise(7, 200);
+        let text = test_util::clip_text(200, |frame, channel| (frame as f64 * 0.02 + channel as f64).sin() + if channel == 6 { spine_noise[frame] * 0.1 } else { 0.0 });
+        let load = |args: &[&str]| log::capture(|| load_bvh(test_util::parse(&text), &Directives::default(), Path::new("in.bvh"), &test_util::options(args)).unwrap());
+
+        let (plain, messages) = load(&[]);
+        assert!(messages.is_empty(), "{:?}", messages);
+        assert!(within(plain.noise_floors[6], 0.1, 0.2), "{:?}", plain.noise_floors);
+        assert!(plain.noise_floors.iter().enumerate().all(|(channel, floor)| channel == 6 || *floor < AUTO_SMOOTH_TARGET_NOISE));
+
+        let (smoothed, messages) = load(&["--auto-smooth"]);
+        assert_eq!(smoothed.noise_floors, plain.noise_floors);
+        assert_eq!(messages, vec![
+            log::Message::Info("in.bvh: auto-smoothing 1 of 15 channels".into()),
+            log::Message::Info(format!("    Spine RotationZ: noise {:.6}, moving-average:3", plain.noise_floors[6])),
+        ]);
+        assert_ne!(smoothed.bvh.motion.frames, plain.bvh.motion.frames);
+    }
+}
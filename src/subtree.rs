@@ -0,0 +1,174 @@
+use bvh;
+
+use error::MocapError;
+use fk;
+use selector::{self, Selector};
+
+// Re-roots `bvh` at the joint matching `selector`, discarding everything outside its subtree and
+// rebuilding the motion columns to match the new hierarchy. The selector must match exactly one
+// joint. Also returns, for each channel of the new hierarchy, the flat index of the channel it
+// came from, or None for the new root's baked channels.
+//
+// Without `bake_ancestors` the new root keeps its own offset and channels, so it moves relative to
+// where its parent used to be. With it, the new root's channels are replaced by world-space
+// translation and rotation tracks computed with FK through the discarded ancestors, so every joint
+// in the subtree ends up in the same world position it had in the full file.
+pub fn select_root(bvh: bvh::Bvh, selector_string: &str, bake_ancestors: bool) -> Result<(bvh::Bvh, Vec<Option<usize>>), MocapError> {
+    let (joint_index, channel_start) = {
+        let selector = Selector::parse(selector_string)?;
+        let matches = selector::find_joints(&bvh.hierarchy.root, &selector);
+        match matches.len() {
+            0 => return Err(MocapError::JointNotFound(selector_string.into())),
+            1 => (matches[0].joint_index, matches[0].channel_index),
+            _ => return Err(MocapError::AmbiguousSelector(selector_string.into(), matches.into_iter().map(|joint_match| joint_match.path).collect())),
+        }
+    };
+
+    let bvh::Bvh { hierarchy, motion } = bvh;
+    let baked_frames = if bake_ancestors {
+        Some(motion.frames.iter().map(|frame| fk::world_transforms(&hierarchy.root, frame)[joint_index]).collect::<Vec<_>>())
+    } else {
+        None
+    };
+
+    let mut root = take_joint(hierarchy.root, joint_index, &mut 0).unwrap();
+    let own_channels = root.channels.len();
+    let subtree_channels = count_channels(&root);
+
+    let mut frames = motion.frames.iter().map(|frame| frame[channel_start..channel_start + subtree_channels].to_vec()).collect::<Vec<_>>();
+    let mut sources = (channel_start..channel_start + subtree_channels).map(Some).collect::<Vec<_>>();
+
+    if let Some(baked_frames) = baked_frames {
+        let rotation_order = rotation_order(&root.channels).unwrap_or([2, 0, 1]);
+
+        let mut previous_angles: Option<[f64; 3]> = None;
+        for (frame, world) in frames.iter_mut().zip(baked_frames.iter()) {
+            let position = world.position();
+            let mut angles = world.euler_angles(rotation_order);
+            if let Some(previous_angles) = previous_angles {
+                // Keep the tracks continuous instead of jumping at +-180 degrees, which would
+                // blow up the quantization range.
+                for (angle, previous_angle) in angles.iter_mut().zip(previous_angles.iter()) {
+                    *angle += ((previous_angle - *angle) / 360.0).round() * 360.0;
+                }
+            }
+            previous_angles = Some(angles);
+
+            let mut values = vec![position.0, position.1, position.2];
+            values.extend_from_slice(&angles);
+            values.extend_from_slice(&frame[own_channels..]);
+            *frame = values;
+        }
+
+        sources.splice(..own_channels, vec![None; 6]);
+
+        root.offset = bvh::Offset { x: 0.0, y: 0.0, z: 0.0 };
+        root.channels = vec![bvh::Channel::XPosition, bvh::Channel::YPosition, bvh::Channel::ZPosition];
+        root.channels.extend(rotation_order.iter().map(|axis| match *axis {
+            0 => bvh::Channel::XRotation,
+            1 => bvh::Channel::YRotation,
+            _ => bvh::Channel::ZRotation,
+        }));
+    }
+
+    Ok((bvh::Bvh {
+        hierarchy: bvh::Hierarchy {
+            root: root,
+        },
+        motion: bvh::Motion {
+            num_frames: motion.num_frames,
+            frame_time: motion.frame_time,
+            frames: frames,
+        },
+    }, sources))
+}
+
+fn take_joint(joint: bvh::Joint, target_index: usize, joint_index: &mut usize) -> Option<bvh::Joint> {
+    if *joint_index == target_index {
+        return Some(joint);
+    }
+    *joint_index += 1;
+
+    if let bvh::JointChildren::Joints(joints) = joint.children {
+        for child in joints.into_iter() {
+            if let Some(joint) = take_joint(child, target_index, joint_index) {
+                return Some(joint);
+            }
+        }
+    }
+
+    None
+}
+
+fn count_channels(joint: &bvh::Joint) -> usize {
+    joint.channels.len() + match joint.children {
+        bvh::JointChildren::Joints(ref joints) => joints.iter().map(count_channels).sum(),
+        bvh::JointChildren::EndSite(_) => 0,
+    }
+}
+
+// The joint's rotation axis order, if it has exactly one rotation channel per axis.
+fn rotation_order(channels: &[bvh::Channel]) -> Option<[usize; 3]> {
+    let axes = channels.iter().filter_map(|channel| match *channel {
+        bvh::Channel::XRotation => Some(0),
+        bvh::Channel::YRotation => Some(1),
+        bvh::Channel::ZRotation => Some(2),
+        _ => None,
+    }).collect::<Vec<_>>();
+
+    if axes.len() == 3 && axes[0] != axes[1] && axes[1] != axes[2] && axes[0] != axes[2] {
+        Some([axes[0], axes[1], axes[2]])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::Mat4;
+    use test_util;
+
+    const NUM_FRAMES: usize = 20;
+
+    fn assert_close(a: &Mat4, b: &Mat4) {
+        for (a, b) in a.0.iter().flat_map(|row| row.iter()).zip(b.0.iter().flat_map(|row| row.iter())) {
+            assert!((a - b).abs() < 1e-9, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn baked_subtree_keeps_its_world_transforms() {
+        let full = test_util::sine_clip(NUM_FRAMES);
+        let (subtree, sources) = select_root(test_util::sine_clip(NUM_FRAMES), "Spine", true).unwrap();
+        assert_eq!(subtree.hierarchy.root.name, "Spine");
+        // The baked root's six tracks, then Head's channels
+        assert_eq!(sources, vec![None, None, None, None, None, None, Some(9), Some(10), Some(11)]);
+
+        for (full_frame, frame) in full.motion.frames.iter().zip(subtree.motion.frames.iter()) {
+            let full_transforms = fk::world_transforms(&full.hierarchy.root, full_frame);
+            let transforms = fk::world_transforms(&subtree.hierarchy.root, frame);
+            assert_eq!(transforms.len(), 2);
+            // Spine and Head, the second and third joints of the full file
+            assert_close(&transforms[0], &full_transforms[1]);
+            assert_close(&transforms[1], &full_transforms[2]);
+        }
+    }
+
+    #[test]
+    fn unbaked_subtree_keeps_its_own_channels() {
+        let full = test_util::sine_clip(NUM_FRAMES);
+        let (subtree, sources) = select_root(test_util::sine_clip(NUM_FRAMES), "Spine", false).unwrap();
+        let offset = &subtree.hierarchy.root.offset;
+        assert_eq!((offset.x, offset.y, offset.z), (0.0, 10.0, 0.0));
+        assert_eq!(sources, (6..12).map(Some).collect::<Vec<_>>());
+        for (full_frame, frame) in full.motion.frames.iter().zip(subtree.motion.frames.iter()) {
+            assert_eq!(frame[..], full_frame[6..12]);
+        }
+    }
+
+    #[test]
+    fn refuses_a_missing_joint() {
+        assert!(matches!(select_root(test_util::sine_clip(NUM_FRAMES), "Tail", false), Err(MocapError::JointNotFound(_))));
+    }
+}
@@ -0,0 +1,48 @@
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+
+use bvh;
+
+use cancel;
+use error::MocapError;
+use manifest;
+use metrics;
+use options::Options;
+use raw;
+use {build_mocap, reconstruct_frames, Settings};
+
+// Runs the pipeline on the already-parsed frames at every bit depth and prints the resulting
+// size/error tradeoff, optionally also writing it as CSV.
+pub fn run(bvh: &bvh::Bvh, settings: &Settings, options: &Options, cancel: Option<&AtomicBool>) -> Result<(), MocapError> {
+    let mut settings = settings.clone();
+    let mut frames = Vec::new();
+    let mut rows = Vec::new();
+    for bits in 1..9 {
+        cancel::check(cancel)?;
+        settings.channel_quantization_bits = bits;
+        let mocap = build_mocap(bvh, &settings);
+
+        let mut raw = Vec::new();
+        raw::write(&mocap, options.search_limits(cancel), &mut raw)?;
+
+        reconstruct_frames(&mocap, &mut frames);
+        let error = metrics::reconstruction_error(&bvh.motion.frames, &frames);
+
+        rows.push((bits, raw.len(), error));
+    }
+
+    println!("{:>4} {:>12} {:>14} {:>14}", "bits", "raw size", "max error", "rms error");
+    for &(bits, raw_size, ref error) in rows.iter() {
+        println!("{:>4} {:>12} {:>14.6} {:>14.6}", bits, raw_size, error.max, error.rms);
+    }
+
+    if let Some(ref csv_file_name) = options.sweep_csv_file_name {
+        let mut csv = manifest::create(csv_file_name)?;
+        writeln!(csv, "bits,raw_size,max_error,rms_error")?;
+        for &(bits, raw_size, ref error) in rows.iter() {
+            writeln!(csv, "{},{},{},{}", bits, raw_size, error.max, error.rms)?;
+        }
+    }
+
+    Ok(())
+}
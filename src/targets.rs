@@ -0,0 +1,70 @@
+use max_level;
+use Mocap;
+use Settings;
+use Source;
+
+// Error targets, with --rot-error and --trans-error: rather than a bit depth, the largest error
+// acceptable in rotation channels (in degrees) and translation channels (in the clip's units). The
+// clip is quantized at the lowest bit depth at which every channel of a type with a target stays
+// within it, overriding --bits. A channel's error is at most half a quantization step, its range
+// divided by the number of steps at that depth; lossless channels have none. The bit depth is
+// shared by every channel, so a channel that can't meet its target even at 8 bits (one with a very
+// wide range) holds the others at 8; a profile can make such a channel lossless instead.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Targets {
+    pub rotation: Option<f64>,
+    pub translation: Option<f64>,
+}
+
+impl Targets {
+    pub fn is_empty(&self) -> bool {
+        self.rotation.is_none() && self.translation.is_none()
+    }
+}
+
+// How the channels of one type group fare at the chosen bit depth.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub name: &'static str,
+    pub target: Option<f64>,
+    pub num_channels: usize,
+    pub max_error: f64, // The bound, over the group's channels
+    pub num_missed: usize, // Channels whose bound exceeds the target
+}
+
+// The encoding settings meeting `targets` for `source` (`settings` with its bit depth replaced),
+// and how each channel group fares with them.
+pub fn choose(source: &Source, settings: &Settings, targets: &Targets) -> (Settings, Vec<Group>) {
+    let mut settings = Settings {
+        channel_quantization_bits: 8,
+        translation_reference: settings.translation_reference,
+        rotation_anchor: settings.rotation_anchor,
+    };
+    for bits in 1..=8 {
+        settings.channel_quantization_bits = bits;
+        let groups = groups(&source.build_mocap(&settings), targets);
+        if bits == 8 || groups.iter().all(|group| group.num_missed == 0) {
+            return (settings, groups);
+        }
+    }
+    unreachable!()
+}
+
+fn groups(mocap: &Mocap, targets: &Targets) -> Vec<Group> {
+    let max_level = max_level(mocap.channel_quantization_bits) as f64;
+    [("rotation", false), ("translation", true)].iter().map(|&(name, translation)| {
+        let target = if translation { targets.translation } else { targets.rotation };
+        let errors = mocap.channels().into_iter()
+            .filter(|channel| channel.type_.is_translation() == translation)
+            .map(|channel| if channel.values.is_some() { 0.0 } else { (channel.value_range as f64) / max_level / 2.0 })
+            .collect::<Vec<_>>();
+        Group {
+            name: name,
+            target: target,
+            num_channels: errors.len(),
+            max_error: errors.iter().cloned().fold(0.0, f64::max),
+            num_missed: errors.iter().filter(|error| target.is_some_and(|target| **error > target)).count(),
+        }
+    }).collect()
+}
@@ -0,0 +1,163 @@
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bvh;
+
+use options::Options;
+
+// Fixtures shared by the modules' tests.
+
+// A small skeleton: Hips (6 channels), Spine and Head (3 each) and LeftLeg (3), 15 channels in all.
+pub const HIERARCHY: &str = "HIERARCHY
+ROOT Hips
+{
+\tOFFSET 0 0 0
+\tCHANNELS 6 Xposition Yposition Zposition Zrotation Xrotation Yrotation
+\tJOINT Spine
+\t{
+\t\tOFFSET 0 10 0
+\t\tCHANNELS 3 Zrotation Xrotation Yrotation
+\t\tJOINT Head
+\t\t{
+\t\t\tOFFSET 0 10 0
+\t\t\tCHANNELS 3 Zrotation Xrotation Yrotation
+\t\t\tEnd Site
+\t\t\t{
+\t\t\t\tOFFSET 0 5 0
+\t\t\t}
+\t\t}
+\t}
+\tJOINT LeftLeg
+\t{
+\t\tOFFSET 5 0 0
+\t\tCHANNELS 3 Zrotation Xrotation Yrotation
+\t\tEnd Site
+\t\t{
+\t\t\tOFFSET 0 -40 0
+\t\t}
+\t}
+}
+";
+
+pub const NUM_CHANNELS: usize = 15;
+
+// A chain of three joints for checking transforms by hand: Hips (6 channels), Spine 10 up from it
+// (3) and Head 5 up from that (none), with an end site 2 further up.
+pub const CHAIN: &str = "HIERARCHY
+ROOT Hips
+{
+\tOFFSET 0 0 0
+\tCHANNELS 6 Xposition Yposition Zposition Zrotation Xrotation Yrotation
+\tJOINT Spine
+\t{
+\t\tOFFSET 0 10 0
+\t\tCHANNELS 3 Zrotation Xrotation Yrotation
+\t\tJOINT Head
+\t\t{
+\t\t\tOFFSET 0 5 0
+\t\t\tCHANNELS 0
+\t\t\tEnd Site
+\t\t\t{
+\t\t\t\tOFFSET 0 2 0
+\t\t\t}
+\t\t}
+\t}
+}
+";
+
+// Two frames of CHAIN: the root at (1, 2, 3) turned 90 degrees about Z with the spine bent 90
+// degrees about X, then every channel at 0.
+pub fn chain_clip() -> bvh::Bvh {
+    let posed = [1.0, 2.0, 3.0, 90.0, 0.0, 0.0, 0.0, 90.0, 0.0];
+    parse(&motion_text(CHAIN, 9, 2, |frame, channel| if frame == 0 { posed[channel] } else { 0.0 }))
+}
+
+// HIERARCHY with `num_frames` frames of `value(frame, channel)`, as BVH text.
+pub fn clip_text<F: Fn(usize, usize) -> f64>(num_frames: usize, value: F) -> String {
+    motion_text(HIERARCHY, NUM_CHANNELS, num_frames, value)
+}
+
+// `hierarchy` with `num_frames` frames of `value(frame, channel)` for its `num_channels` channels.
+pub fn motion_text<F: Fn(usize, usize) -> f64>(hierarchy: &str, num_channels: usize, num_frames: usize, value: F) -> String {
+    let mut ret = format!("{}MOTION\nFrames: {}\nFrame Time: 0.033333\n", hierarchy, num_frames);
+    for frame in 0..num_frames {
+        let values = (0..num_channels).map(|channel| format!("{}", value(frame, channel))).collect::<Vec<_>>();
+        ret.push_str(&values.join(" "));
+        ret.push('\n');
+    }
+    ret
+}
+
+pub fn parse(text: &str) -> bvh::Bvh {
+    bvh::parse(text).unwrap()
+}
+
+// A moving clip of HIERARCHY: every channel a sine of its own phase and amplitude.
+pub fn sine_clip(num_frames: usize) -> bvh::Bvh {
+    parse(&clip_text(num_frames, sine))
+}
+
+pub fn sine(frame: usize, channel: usize) -> f64 {
+    (frame as f64 * 0.15 + channel as f64).sin() * (10.0 + channel as f64 * 2.0)
+}
+
+// `count` samples of normally distributed noise with a standard deviation of 1, the same for the
+// same `seed` (xorshift64* into Box-Muller).
+pub fn noise(seed: u64, count: usize) -> Vec<f64> {
+    let mut state = seed.wrapping_mul(0x9e3779b97f4a7c15) | 1;
+    let mut uniform = || {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        // In (0, 1], so the logarithm is finite
+        ((state.wrapping_mul(0x2545f4914f6cdd1d) >> 11) + 1) as f64 / (1u64 << 53) as f64
+    };
+    (0..count).map(|_| (-2.0 * uniform().ln()).sqrt() * (2.0 * ::std::f64::consts::PI * uniform()).cos()).collect()
+}
+
+// Options for a conversion of in.bvh with `args`.
+pub fn options(args: &[&str]) -> Options {
+    Options::parse(args.iter().chain(["in.bvh", "out.bvh", "out.csv", "out.raw"].iter()).map(|arg| arg.to_string())).unwrap()
+}
+
+// A file in tests/fixtures.
+pub fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(name)
+}
+
+// An empty directory of its own for a test, removed with everything in it when the test is done
+// with it (dropping the guard), whether the test passed or not.
+pub fn temp_dir(name: &str) -> TempDir {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = env::temp_dir().join(format!("mocap-test-{}-{}-{}", name, std::process::id(), NEXT.fetch_add(1, Ordering::SeqCst)));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    TempDir { path: path }
+}
+
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
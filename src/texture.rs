@@ -0,0 +1,89 @@
+use std::io::{self, Write};
+
+use {max_level, Mocap};
+
+// Texture export, for GPU skinning that samples the animation in a vertex shader. Where the .raw
+// file is a stream of deltas to be decoded in order, this is every channel's quantized level at
+// every frame, laid out as a 2D texture of one byte per texel (an R8 texture) that can be uploaded
+// as is: one row per channel, in flat channel order (see --export-channel-map), one column per
+// frame. Ahead of the texels is a small metadata blob with what the shader needs to turn a level
+// back into a channel value. Everything is little-endian:
+//
+//   offset  size  field
+//   0       4     magic, "MCTX"
+//   4       4     u32 version, FORMAT_VERSION
+//   8       4     u32 number of channels (rows holding data)
+//   12      4     u32 number of frames (columns holding data)
+//   16      4     u32 texture width in texels, at least the number of frames
+//   20      4     u32 texture height in texels, at least the number of channels
+//   24      4     f32 frame time, in seconds
+//   28      1     u8 bits per level; levels run from 0 to 2^bits - 1
+//   29      3     zero
+//   32      16n   per channel (n channels), four f32s:
+//                   scale, bias   the channel's value at a level is bias + level * scale
+//                   min, max      the range decoded values are clamped to (-/+ f32::MAX if none)
+//   32+16n  w*h   the texels, row by row: row c column f holds channel c's level at frame f
+//
+// A shader reading the texture as normalized bytes (UNORM, 0 to 1) gets level / 255, so the value
+// is bias + sample * 255 * scale, clamped to [min, max]. Rotations are in degrees, in the channel's
+// order within its joint. Translation references and rotation anchors are folded into the bias;
+// as f32s, a channel far from the origin loses some precision there.
+//
+// Past the last frame each row repeats its last level, so clamped or filtered sampling at the edge
+// stays on the final pose, and rows past the last channel are zero. By default the texture is
+// exactly one texel per frame and channel; with --texture-pow2 both dimensions are padded up to
+// powers of two for hardware that needs them. Lossless channels are quantized to the clip's
+// levels like the rest, and channels the file stores in another form (such as --root-motion's
+// displacements) are levels of that form, as in the .raw file.
+
+pub const MAGIC: &[u8; 4] = b"MCTX";
+pub const FORMAT_VERSION: u32 = 1;
+
+pub fn write<W: Write>(mocap: &Mocap, pow2: bool, w: &mut W) -> io::Result<()> {
+    let bits = mocap.channel_quantization_bits;
+    let channels = mocap.channels();
+    let num_frames = mocap.num_frames as usize;
+    let (mut width, mut height) = (num_frames.max(1), channels.len().max(1));
+    if pow2 {
+        width = width.next_power_of_two();
+        height = height.next_power_of_two();
+    }
+
+    w.write_all(MAGIC)?;
+    for value in [FORMAT_VERSION, channels.len() as u32, mocap.num_frames, width as u32, height as u32].iter() {
+        w.write_all(&value.to_le_bytes())?;
+    }
+    w.write_all(&mocap.frame_time.to_le_bytes())?;
+    w.write_all(&[bits, 0, 0, 0])?;
+
+    for channel in channels.iter() {
+        let scale = channel.value_of(1, bits) - channel.value_of(0, bits);
+        let (min, max) = channel.clamp.map_or((-f32::MAX, f32::MAX), |(min, max)| (min as f32, max as f32));
+        for value in [scale as f32, channel.value_of(0, bits) as f32, min, max].iter() {
+            w.write_all(&value.to_le_bytes())?;
+        }
+    }
+
+    let mut row = Vec::with_capacity(width);
+    for channel in channels.iter() {
+        row.clear();
+        match channel.values {
+            Some(ref values) => row.extend(values.iter().map(|value| channel.level_of(*value, bits))),
+            None => {
+                let mut level = channel.initial_level;
+                for delta in channel.deltas.iter() {
+                    level = (level as i8).wrapping_add(*delta) as u8;
+                    row.push(level.min(max_level(channel.bits(bits))));
+                }
+            }
+        }
+        let last = row.last().cloned().unwrap_or(0);
+        row.resize(width, last);
+        w.write_all(&row)?;
+    }
+    let empty = vec![0; width];
+    for _ in channels.len()..height {
+        w.write_all(&empty)?;
+    }
+    Ok(())
+}
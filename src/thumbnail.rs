@@ -0,0 +1,199 @@
+use bvh;
+
+use posematch::{self, Metric, Weights};
+use rotation_channels;
+
+// Thumbnail poses, for asset browsers showing one representative pose per clip. With --thumbnail
+// pack picks a frame of each clip and embeds its pose in the container (see container.rs), and
+// with --export-thumbnails it also writes that frame to <dir>/<clip name>.bvh as a single-frame
+// BVH file. The frame is picked from the decoded clip, with the channel metric of posematch.rs:
+//
+//   average  The frame nearest the clip's average pose, each rotation channel averaged as an
+//            angle (so 179 and -179 degrees average to 180), which for a clip that's mostly one
+//            pose is a frame of that pose
+//   energy   The frame moving the most: the largest sum of its distances to the frames before and
+//            after it, which lands in the most energetic part of the clip
+//
+// Ties go to the earlier frame.
+//
+// --pose-frame writes a chosen frame as a single-frame pose likewise, in place of the whole clip:
+// the output BVH of a conversion or batch, decode's output and unpack's clips. It's a frame index
+// or `last`, frame 0 if none is given; an index past the last frame is clamped to it, with a
+// warning.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    Average,
+    Energy,
+}
+
+// A --pose-frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PoseFrame {
+    Index(u32),
+    Last,
+}
+
+impl PoseFrame {
+    pub fn parse(s: &str) -> Option<PoseFrame> {
+        match s {
+            "last" => Some(PoseFrame::Last),
+            _ => s.parse().ok().map(PoseFrame::Index),
+        }
+    }
+
+    pub fn spec(&self) -> String {
+        match *self {
+            PoseFrame::Index(index) => index.to_string(),
+            PoseFrame::Last => "last".into(),
+        }
+    }
+
+    // The frame in a clip of `num_frames` (> 0) frames, and whether it had to be clamped to the last.
+    pub fn resolve(&self, num_frames: usize) -> (usize, bool) {
+        match *self {
+            PoseFrame::Index(index) if (index as usize) < num_frames => (index as usize, false),
+            PoseFrame::Index(_) => (num_frames - 1, true),
+            PoseFrame::Last => (num_frames - 1, false),
+        }
+    }
+}
+
+// The thumbnail frame of `bvh` under `policy`; None if it has no frames.
+pub fn select(bvh: &bvh::Bvh, policy: Policy) -> Option<usize> {
+    let frames = &bvh.motion.frames;
+    if frames.is_empty() {
+        return None;
+    }
+    match policy {
+        Policy::Average => {
+            let average = average_pose(&bvh.hierarchy.root, frames);
+            posematch::find_nearest_poses(bvh, &average, Metric::Channels, 1).first().map(|nearest| nearest.0)
+        }
+        Policy::Energy => {
+            let weights = Weights::new(&bvh.hierarchy.root);
+            let steps = frames.windows(2).map(|pair| posematch::pose_distance(&pair[0], &pair[1], &weights)).collect::<Vec<_>>();
+            let energy = |frame: usize| frame.checked_sub(1).map_or(0.0, |previous| steps[previous]) + steps.get(frame).cloned().unwrap_or(0.0);
+            // max_by keeps the last of equal frames, so reversed it keeps the earliest
+            (0..frames.len()).rev().max_by(|x, y| energy(*x).total_cmp(&energy(*y)))
+        }
+    }
+}
+
+fn average_pose(root: &bvh::Joint, frames: &[Vec<f64>]) -> Vec<f64> {
+    let count = frames.len() as f64;
+    rotation_channels(root).into_iter().enumerate().map(|(index, rotation)| {
+        if rotation {
+            let (sin, cos) = frames.iter().fold((0.0, 0.0), |(sin, cos), frame| {
+                let (s, c) = frame[index].to_radians().sin_cos();
+                (sin + s, cos + c)
+            });
+            sin.atan2(cos).to_degrees()
+        } else {
+            frames.iter().map(|frame| frame[index]).sum::<f64>() / count
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use std::fs;
+
+    use super::*;
+    use container;
+    use depth::DEFAULT_MAX_DEPTH;
+    use test_util;
+    use Options;
+
+    const BURST: ::std::ops::Range<usize> = 30..36;
+
+    // A clip holding one pose but for a burst of motion in BURST
+    fn burst_text(num_frames: usize) -> String {
+        test_util::clip_text(num_frames, |frame, channel| {
+            let pose = test_util::sine(0, channel);
+            if BURST.contains(&frame) && channel >= 3 {
+                pose + 40.0 * (PI * (frame - BURST.start + 1) as f64 / (BURST.len() + 1) as f64).sin()
+            } else {
+                pose
+            }
+        })
+    }
+
+    #[test]
+    fn energy_picks_the_burst_and_average_the_idle_pose() {
+        let bvh = test_util::parse(&burst_text(90));
+        let energy = select(&bvh, Policy::Energy).unwrap();
+        assert!(BURST.contains(&energy), "{}", energy);
+        let average = select(&bvh, Policy::Average).unwrap();
+        assert!(!BURST.contains(&average), "{}", average);
+        assert_eq!(bvh.motion.frames[average], bvh.motion.frames[0]);
+    }
+
+    #[test]
+    fn ties_go_to_the_earlier_frame() {
+        let bvh = test_util::parse(&test_util::clip_text(10, |_, channel| channel as f64));
+        assert_eq!(select(&bvh, Policy::Energy), Some(0));
+        assert_eq!(select(&bvh, Policy::Average), Some(0));
+
+        let mut bvh = bvh;
+        bvh.motion.frames.clear();
+        assert_eq!(select(&bvh, Policy::Energy), None);
+        assert_eq!(select(&bvh, Policy::Average), None);
+    }
+
+    #[test]
+    fn averages_rotations_as_angles() {
+        let bvh = test_util::parse(&test_util::clip_text(2, |frame, channel| match channel {
+            0 | 6 => [179.0, -179.0][frame],
+            _ => frame as f64,
+        }));
+        let average = average_pose(&bvh.hierarchy.root, &bvh.motion.frames);
+        assert!(average[0].abs() < 1e-9, "{}", average[0]);
+        assert!((average[6].abs() - 180.0).abs() < 1e-9, "{}", average[6]);
+        assert!((average[7] - 0.5).abs() < 1e-9, "{}", average[7]);
+    }
+
+    #[test]
+    fn pose_frames_resolve_within_the_clip() {
+        assert_eq!(PoseFrame::parse("3"), Some(PoseFrame::Index(3)));
+        assert_eq!(PoseFrame::parse("last"), Some(PoseFrame::Last));
+        assert_eq!(PoseFrame::parse("-1"), None);
+        assert_eq!(PoseFrame::parse("first"), None);
+        assert_eq!(PoseFrame::Index(3).spec(), "3");
+        assert_eq!(PoseFrame::Last.spec(), "last");
+
+        assert_eq!(PoseFrame::Index(3).resolve(10), (3, false));
+        assert_eq!(PoseFrame::Index(10).resolve(10), (9, true));
+        assert_eq!(PoseFrame::Last.resolve(10), (9, false));
+    }
+
+    // Pack embeds each clip's thumbnail pose and exports it as a single-frame BVH file
+    #[test]
+    fn pack_embeds_and_exports_the_thumbnails() {
+        let dir = test_util::temp_dir("thumbnail");
+        let path = |file_name: &str| dir.join(file_name);
+        fs::write(path("burst.bvh"), burst_text(90)).unwrap();
+        fs::write(path("sine.bvh"), test_util::clip_text(40, test_util::sine)).unwrap();
+        let input_file_names = ["burst.bvh", "sine.bvh"].iter().map(|file_name| path(file_name).to_string_lossy().into_owned()).collect::<Vec<_>>();
+        let export_dir = path("thumbnails").to_string_lossy().into_owned();
+        let args = ["pack", "--thumbnail", "energy", "--export-thumbnails", &export_dir, "clips.mcp"];
+        let options = Options::parse(args.iter().map(|arg| arg.to_string()).chain(input_file_names.iter().cloned())).unwrap();
+        ::pack(&path("clips.mcp"), &input_file_names, &options, None).unwrap();
+
+        let container = container::read(&fs::read(path("clips.mcp")).unwrap(), DEFAULT_MAX_DEPTH).unwrap();
+        for clip in container.clips.iter() {
+            let thumbnail = clip.thumbnail.as_ref().unwrap();
+            let decoded = ::build_bvh(&clip.mocap);
+            assert_eq!(Some(thumbnail.frame as usize), select(&decoded, Policy::Energy), "{}", clip.name);
+            let exported = test_util::parse(&fs::read_to_string(path("thumbnails").join(format!("{}.bvh", clip.name))).unwrap());
+            assert_eq!(exported.motion.frames.len(), 1);
+            let pose = &decoded.motion.frames[thumbnail.frame as usize];
+            assert_eq!(thumbnail.pose.len(), pose.len());
+            for ((embedded, exported), decoded) in thumbnail.pose.iter().zip(exported.motion.frames[0].iter()).zip(pose.iter()) {
+                assert!((*embedded as f64 - decoded).abs() < 1e-4 && (exported - decoded).abs() < 1e-4, "{}: {} {} {}", clip.name, embedded, exported, decoded);
+            }
+        }
+        assert!(BURST.contains(&(container.clips[0].thumbnail.as_ref().unwrap().frame as usize)));
+    }
+}
@@ -0,0 +1,236 @@
+use bvh;
+
+use error::MocapError;
+use log;
+use markers::Marker;
+use resample::{self, Interpolation};
+use rotation_channels;
+
+// Non-uniform retiming with --timewarp. A curve of control points maps output time to input
+// time (both in seconds from the first frame), linearly between the points:
+//
+//   0=0,1.0=0.4,2.0=3.0     the first second plays 0.4 s of the clip, the next one 2.6 s
+//
+// The output times must be increasing and the input times must not decrease, so the clip never
+// plays backwards (equal input times hold a pose). The output keeps the frame time and lasts
+// until the last point's output time; every channel is sampled at the warped times, interpolating
+// linearly between the two nearest input frames (rotations the short way round). Markers move to
+// the output frame nearest to where their input frame is first played.
+
+// Metadata key recording the curve
+pub const KEY: &str = "timewarp";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Curve {
+    points: Vec<(f64, f64)>, // Output time, input time
+}
+
+impl Curve {
+    // `<output time>=<input time>,...`, as passed to --timewarp.
+    pub fn parse(spec: &str) -> Result<Curve, String> {
+        let mut points = Vec::new();
+        for point in spec.split(',') {
+            let equals = point.find('=').ok_or_else(|| format!("expected <output time>=<input time>, got {}", point))?;
+            let parse = |time: &str| time.trim().parse::<f64>().ok().filter(|time| time.is_finite() && *time >= 0.0)
+                .ok_or_else(|| format!("invalid time {}", time.trim()));
+            points.push((parse(&point[..equals])?, parse(&point[equals + 1..])?));
+        }
+        Curve::new(points)
+    }
+
+    pub fn new(points: Vec<(f64, f64)>) -> Result<Curve, String> {
+        if points.len() < 2 {
+            return Err("a curve needs at least two points".into());
+        }
+        for pair in points.windows(2) {
+            if pair[1].0 <= pair[0].0 {
+                return Err(format!("output times must increase, but {} follows {}", pair[1].0, pair[0].0));
+            }
+            if pair[1].1 < pair[0].1 {
+                return Err(format!("input times must not decrease, but {} follows {} (at output time {})", pair[1].1, pair[0].1, pair[1].0));
+            }
+        }
+        Ok(Curve { points: points })
+    }
+
+    // The curve as --timewarp takes it.
+    pub fn spec(&self) -> String {
+        self.points.iter().map(|(output, input)| format!("{}={}", output, input)).collect::<Vec<_>>().join(",")
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.points.last().unwrap().0
+    }
+
+    // The input time played at `output_time`. Before the first point it's the first point's input
+    // time, and after the last the last one's.
+    pub fn input_time(&self, output_time: f64) -> f64 {
+        let index = self.points.iter().position(|point| point.0 > output_time).unwrap_or(self.points.len());
+        match index {
+            0 => self.points[0].1,
+            index if index == self.points.len() => self.points[index - 1].1,
+            index => {
+                let (a, b) = (self.points[index - 1], self.points[index]);
+                a.1 + (b.1 - a.1) * (output_time - a.0) / (b.0 - a.0)
+            }
+        }
+    }
+
+    // The first output time at which `input_time` is played, if it is at all.
+    pub fn output_time(&self, input_time: f64) -> Option<f64> {
+        self.points.windows(2).find(|pair| pair[0].1 <= input_time && input_time <= pair[1].1).map(|pair| {
+            let (a, b) = (pair[0], pair[1]);
+            if b.1 == a.1 { a.0 } else { a.0 + (b.0 - a.0) * (input_time - a.1) / (b.1 - a.1) }
+        })
+    }
+}
+
+// Retimes `bvh` and `markers` (sorted) along `curve`, returning the metadata recording it.
+pub fn apply(bvh: &mut bvh::Bvh, markers: &mut Vec<Marker>, curve: &Curve) -> Result<Vec<(String, String)>, MocapError> {
+    let frame_time = bvh.motion.frame_time;
+    let num_frames = bvh.motion.frames.len();
+    if num_frames == 0 {
+        return Err(MocapError::Usage("--timewarp needs a clip with frames".into()));
+    }
+    if frame_time.is_nan() || frame_time <= 0.0 {
+        return Err(MocapError::Usage(format!("--timewarp needs a positive frame time, not {}", frame_time)));
+    }
+    let clip_duration = (num_frames - 1) as f64 * frame_time;
+    let last_input_time = curve.points.last().unwrap().1;
+    // Allow for the frame time having been rounded when it was written (input times past the last
+    // frame play the last frame)
+    if last_input_time > clip_duration + frame_time * 0.01 {
+        return Err(MocapError::Usage(format!("the --timewarp curve reaches input time {}, past the end of the clip ({} s)", last_input_time, clip_duration)));
+    }
+
+    let rotations = rotation_channels(&bvh.hierarchy.root);
+    let num_output_frames = (curve.duration() / frame_time + 1e-9).floor() + 1.0;
+    if num_output_frames.is_nan() || num_output_frames > u32::MAX as f64 {
+        return Err(MocapError::Overflow(format!("the --timewarp curve makes {:e} frames, more than a clip can have", num_output_frames)));
+    }
+    let num_output_frames = num_output_frames as usize;
+    let frames = (0..num_output_frames).map(|index| {
+        let position = (curve.input_time(index as f64 * frame_time) / frame_time).min((num_frames - 1) as f64);
+        resample::sample(&bvh.motion.frames, &rotations, position, Interpolation::Linear)
+    }).collect::<Vec<_>>();
+
+    // A marker's time can come out a rounding error past either end of the curve, as can the last
+    // input time (see above)
+    let (first_input_time, slack) = (curve.points[0].1, frame_time * 0.01);
+    for marker in std::mem::take(markers) {
+        let input_time = marker.0 as f64 * frame_time;
+        let input_time = match input_time {
+            _ if input_time < first_input_time && input_time >= first_input_time - slack => first_input_time,
+            _ if input_time > last_input_time && input_time <= last_input_time + slack => last_input_time,
+            _ => input_time,
+        };
+        match curve.output_time(input_time) {
+            Some(output_time) => markers.push((((output_time / frame_time).round() as u32).min(num_output_frames as u32 - 1), marker.1)),
+            None => log::warning(format!("dropping marker {} at frame {}, which the time warp doesn't play", marker.1, marker.0)),
+        }
+    }
+
+    bvh.motion.frames = frames;
+    bvh.motion.num_frames = num_output_frames as u32;
+    Ok(vec![(KEY.into(), curve.spec())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_util;
+
+    // HIERARCHY at 10 frames a second, every channel moving linearly: value = frame * rate
+    fn linear_clip(num_frames: usize) -> bvh::Bvh {
+        let mut ret = test_util::parse(&test_util::clip_text(num_frames, |frame, channel| frame as f64 * rate(channel)));
+        ret.motion.frame_time = 0.1;
+        ret
+    }
+
+    fn rate(channel: usize) -> f64 {
+        (channel + 1) as f64 * 0.1
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn identity_warp_changes_nothing() {
+        let mut bvh = linear_clip(30);
+        let original = bvh.motion.frames.clone();
+        let mut markers = vec![(0, "start".to_string()), (17, "middle".into()), (29, "end".into())];
+        let curve = Curve::parse("0=0,2.9=2.9").unwrap();
+        assert_eq!(apply(&mut bvh, &mut markers, &curve).unwrap(), vec![(KEY.to_string(), "0=0,2.9=2.9".to_string())]);
+
+        assert_eq!(bvh.motion.num_frames, 30);
+        assert_eq!(bvh.motion.frames.len(), 30);
+        for (frame, original) in bvh.motion.frames.iter().zip(original.iter()) {
+            for (value, original) in frame.iter().zip(original.iter()) {
+                assert_close(*value, *original);
+            }
+        }
+        assert_eq!(markers, vec![(0, "start".to_string()), (17, "middle".into()), (29, "end".into())]);
+    }
+
+    #[test]
+    fn piecewise_linear_warp_matches_sampling_by_hand() {
+        let mut bvh = linear_clip(40);
+        let mut markers = vec![(17, "kick".to_string()), (35, "past the curve".into())];
+        let curve = Curve::parse("0=0,1.0=0.4,2.0=3.0").unwrap();
+        apply(&mut bvh, &mut markers, &curve).unwrap();
+
+        // Lasts 2 s at the same frame time
+        assert_eq!(bvh.motion.num_frames, 21);
+        assert_eq!(bvh.motion.frames.len(), 21);
+        // Output frame 5 (0.5 s) plays 0.2 s, input frame 2; output frame 15 (1.5 s) plays
+        // 0.4 + 2.6 * 0.5 = 1.7 s, input frame 17; output frame 12 (1.2 s) plays 0.92 s, between
+        // input frames 9 and 10
+        for &(output_frame, input_frame) in [(0, 0.0), (5, 2.0), (10, 4.0), (12, 9.2), (15, 17.0), (20, 30.0)].iter() {
+            for (channel, value) in bvh.motion.frames[output_frame].iter().enumerate() {
+                assert_close(*value, input_frame * rate(channel));
+            }
+        }
+        // The marker at input frame 17 is played at output frame 15; input frame 35 (3.5 s) is never played
+        assert_eq!(markers, vec![(15, "kick".to_string())]);
+    }
+
+    #[test]
+    fn rotations_are_interpolated_the_short_way_round() {
+        let mut bvh = linear_clip(3);
+        // Hips' Zrotation from 170 to -170: halfway is 180, not 0
+        bvh.motion.frames[0][3] = 170.0;
+        bvh.motion.frames[1][3] = -170.0;
+        bvh.motion.frames[2][3] = -170.0;
+        apply(&mut bvh, &mut Vec::new(), &Curve::parse("0=0,0.2=0.05").unwrap()).unwrap();
+        assert_close(bvh.motion.frames[1][3].abs(), 175.0);
+        assert_close(bvh.motion.frames[2][3].abs(), 180.0);
+    }
+
+    #[test]
+    fn refuses_non_monotonic_curves() {
+        for &(spec, error) in [
+            ("0=0,1=2,2=1", "input times must not decrease"),
+            ("0=0,1=1,1=2", "output times must increase"),
+            ("0=0,2=1,1=2", "output times must increase"),
+            ("0=0", "at least two points"),
+            ("0=0,1=-1", "invalid time"),
+        ].iter() {
+            match Curve::parse(spec) {
+                Err(message) => assert!(message.contains(error), "{}: {}", spec, message),
+                Ok(curve) => panic!("{} parsed as {:?}", spec, curve),
+            }
+        }
+        // Equal input times hold a pose
+        assert!(Curve::parse("0=0,1=1,2=1").is_ok());
+    }
+
+    #[test]
+    fn refuses_a_curve_past_the_end_of_the_clip() {
+        let mut bvh = linear_clip(10);
+        match apply(&mut bvh, &mut Vec::new(), &Curve::parse("0=0,1=2").unwrap()) {
+            Err(MocapError::Usage(message)) => assert!(message.contains("past the end of the clip"), "{}", message),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use bvh;
+
+use error::MocapError;
+use manifest;
+use markers::Marker;
+use resample::{self, Interpolation};
+use {rotation_channels, Mocap};
+
+// Variable frame timing, for sensor captures whose sampling rate jitters. BVH assumes frames are
+// frame_time apart, so such a capture's times come separately, with --timestamps, as a sidecar
+// file of one time per frame in seconds (see `read_file`). The frames are kept as captured and
+// their timestamps stored with them in the .raw file (see raw.rs), so they're exact through the
+// round trip: `mocap decode --save-timestamps` writes the captured frames with the timestamps
+// file beside them again.
+//
+// A BVH file can't hold them otherwise, so the conversion's BVH output, `mocap unpack` and `mocap
+// decode` without --save-timestamps resample the clip to uniform timing: frames `frame time`
+// apart from the first timestamp to the last (up to the last whole frame), each sampled from the
+// captured frames around its time as --fps does (with --interpolation), and markers moved to the
+// frame nearest their time. The uniform frame time is the median of the intervals between
+// timestamps, the nominal sampling rate, which jitter either way and the odd dropped frame don't
+// shift the way they shift the mean. It's also the clip's frame_time, for the passes that assume
+// one such as --smooth, so --rational-fps can snap it to an exact rate.
+//
+// Timestamps have to increase, and there has to be one per frame. The passes that retime a clip
+// (--fps, --timewarp, --loop-trim, --override-frame-time) can't be combined with them; --max-frames
+// drops the timestamps of the frames it drops, and concatenation appends them, a clip without any
+// counting as timed frame_time apart from 0.
+
+// Reads a timestamps file: one time in seconds per line, blank lines and lines starting with #
+// ignored.
+pub fn read_file(path: &Path) -> Result<Vec<f64>, MocapError> {
+    let contents = fs::read_to_string(path)?;
+    let mut ret = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let time = line.parse::<f64>().ok().filter(|time| time.is_finite())
+            .ok_or_else(|| MocapError::InvalidTimestamps(format!("{}:{}: expected a time in seconds, got {}", path.display(), index + 1, line)))?;
+        if ret.last().is_some_and(|previous| time <= *previous) {
+            return Err(MocapError::InvalidTimestamps(format!("{}:{}: {} doesn't follow {}", path.display(), index + 1, time, ret[ret.len() - 1])));
+        }
+        ret.push(time);
+    }
+    Ok(ret)
+}
+
+// Writes `timestamps` as a timestamps file, which --timestamps (and `read_file`) reads back.
+pub fn write_file(timestamps: &[f64], path: &Path) -> Result<(), MocapError> {
+    let mut w = io::BufWriter::new(manifest::create(path)?);
+    writeln!(w, "# seconds")?;
+    for time in timestamps.iter() {
+        writeln!(w, "{}", time)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+// Whether `timestamps` are usable: finite and increasing.
+pub fn is_valid(timestamps: &[f64]) -> bool {
+    timestamps.iter().all(|time| time.is_finite()) && timestamps.windows(2).all(|pair| pair[0] < pair[1])
+}
+
+// The uniform frame time for `timestamps`: the median interval between them. None for fewer than
+// two.
+pub fn frame_time(timestamps: &[f64]) -> Option<f64> {
+    let mut intervals = timestamps.windows(2).map(|pair| pair[1] - pair[0]).collect::<Vec<_>>();
+    if intervals.is_empty() {
+        return None;
+    }
+    intervals.sort_by(f64::total_cmp);
+    let middle = intervals.len() / 2;
+    Some(if intervals.len() % 2 == 0 { (intervals[middle - 1] + intervals[middle]) / 2.0 } else { intervals[middle] })
+}
+
+// Resamples `bvh`, whose frames are at `timestamps` (one per frame), and `markers` to uniform
+// timing at its frame time.
+pub fn resample(bvh: &mut bvh::Bvh, timestamps: &[f64], markers: &mut [Marker], interpolation: Interpolation) {
+    let frame_time = bvh.motion.frame_time;
+    let frames = &bvh.motion.frames;
+    if frames.len() < 2 || frame_time.is_nan() || frame_time <= 0.0 {
+        return;
+    }
+    let start = timestamps[0];
+    let num_output_frames = ((timestamps[timestamps.len() - 1] - start) / frame_time + 1e-9).floor() as usize + 1;
+    // Where `time` falls among the captured frames, as a fractional frame index
+    let position = |time: f64| {
+        let index = timestamps.partition_point(|timestamp| *timestamp <= time).clamp(1, timestamps.len() - 1) - 1;
+        (index as f64 + (time - timestamps[index]) / (timestamps[index + 1] - timestamps[index])).clamp(0.0, (timestamps.len() - 1) as f64)
+    };
+
+    let rotations = rotation_channels(&bvh.hierarchy.root);
+    let output = (0..num_output_frames).map(|index| resample::sample(frames, &rotations, position(start + index as f64 * frame_time), interpolation)).collect::<Vec<_>>();
+    for marker in markers.iter_mut() {
+        marker.0 = (((timestamps[marker.0 as usize] - start) / frame_time).round() as u32).min(num_output_frames as u32 - 1);
+    }
+    bvh.motion.frames = output;
+    bvh.motion.num_frames = num_output_frames as u32;
+}
+
+// The timestamps of `mocap` with `other`'s frames appended, `other`'s starting a frame time after
+// `mocap`'s last; none if neither clip has any.
+pub fn appended(mocap: &Mocap, other: &Mocap) -> Vec<f64> {
+    if mocap.timestamps.is_empty() && other.timestamps.is_empty() {
+        return Vec::new();
+    }
+    let frame_time = mocap.frame_time as f64;
+    let timestamps = |mocap: &Mocap| if mocap.timestamps.is_empty() {
+        (0..mocap.num_frames).map(|frame| frame as f64 * frame_time).collect()
+    } else {
+        mocap.timestamps.clone()
+    };
+    let (mut ret, other) = (timestamps(mocap), timestamps(other));
+    let offset = ret.last().map_or(0.0, |last| last + frame_time) - other.first().cloned().unwrap_or(0.0);
+    ret.extend(other.into_iter().map(|time| time + offset));
+    ret
+}
@@ -0,0 +1,226 @@
+use bvh;
+
+use ground;
+use posematch::{self, Weights};
+use rotation_channels;
+
+// Transition points between two clips of the same skeleton (`mocap transitions`): pairs of a frame
+// of the first clip and a frame of the second where cutting from one to the other looks natural.
+// Every pair (every `stride`th frame of each clip) is scored by the channel pose distance (see
+// posematch.rs) plus the same distance between the two frames' velocities (the change to the next
+// frame), so the motion also carries on in the same direction. Candidates close to a better one
+// are left out, so the list isn't the same spot several times over.
+//
+// The suggested blend is shorter for fast motion, which would visibly smear, and longer for slow
+// motion: the time the pose takes to move BLEND_DISTANCE at the pair's average speed, between
+// MIN_BLEND_TIME and MAX_BLEND_TIME. `stitch` joins the clips at a candidate, crossfading over the
+// blend.
+
+// In pose distance units (roughly degrees)
+const BLEND_DISTANCE: f64 = 30.0;
+const MIN_BLEND_TIME: f64 = 0.1;
+const MAX_BLEND_TIME: f64 = 0.5;
+
+// Candidates within this many frames (in both clips) of a better one are left out
+const MIN_SEPARATION: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub frame_a: usize,
+    pub frame_b: usize,
+    pub score: f64,
+    pub pose_distance: f64,
+    pub velocity_mismatch: f64,
+    pub blend_frames: usize,
+}
+
+// The best `count` candidates for cutting from `a` to `b`, best first. Both need at least two
+// frames.
+pub fn find(a: &bvh::Bvh, b: &bvh::Bvh, stride: usize, count: usize) -> Vec<Candidate> {
+    let weights = Weights::new(&a.hierarchy.root);
+    let rotations = rotation_channels(&a.hierarchy.root);
+    let (velocities_a, velocities_b) = (velocities(&a.motion.frames, &rotations), velocities(&b.motion.frames, &rotations));
+    let zero = vec![0.0; rotations.len()];
+    let frame_time = a.motion.frame_time;
+
+    let mut candidates = Vec::new();
+    for frame_a in (0..velocities_a.len()).step_by(stride) {
+        for frame_b in (0..velocities_b.len()).step_by(stride) {
+            let pose_distance = posematch::pose_distance(&a.motion.frames[frame_a], &b.motion.frames[frame_b], &weights);
+            let velocity_mismatch = posematch::pose_distance(&velocities_a[frame_a], &velocities_b[frame_b], &weights);
+            candidates.push((frame_a, frame_b, pose_distance, velocity_mismatch));
+        }
+    }
+    candidates.sort_by(|x, y| (x.2 + x.3).total_cmp(&(y.2 + y.3)));
+
+    let mut ret: Vec<Candidate> = Vec::new();
+    for (frame_a, frame_b, pose_distance, velocity_mismatch) in candidates {
+        if ret.len() == count {
+            break;
+        }
+        if ret.iter().any(|better| better.frame_a.abs_diff(frame_a) < MIN_SEPARATION && better.frame_b.abs_diff(frame_b) < MIN_SEPARATION) {
+            continue;
+        }
+        // Per second, averaged over the two clips
+        let speed = (posematch::pose_distance(&velocities_a[frame_a], &zero, &weights) + posematch::pose_distance(&velocities_b[frame_b], &zero, &weights)) / 2.0 / frame_time;
+        let blend_time = if speed > 0.0 { (BLEND_DISTANCE / speed).clamp(MIN_BLEND_TIME, MAX_BLEND_TIME) } else { MAX_BLEND_TIME };
+        ret.push(Candidate {
+            frame_a: frame_a,
+            frame_b: frame_b,
+            score: pose_distance + velocity_mismatch,
+            pose_distance: pose_distance,
+            velocity_mismatch: velocity_mismatch,
+            blend_frames: ((blend_time / frame_time).round() as usize).max(1),
+        });
+    }
+    ret
+}
+
+// `a` up to the candidate's frame, then `b` from its frame on, crossfading from one to the other
+// over the blend (rotations the short way round). `b`'s root is moved to continue from where `a`'s
+// is, except along the up axis; its heading isn't changed.
+pub fn stitch(a: bvh::Bvh, b: &bvh::Bvh, candidate: &Candidate) -> bvh::Bvh {
+    let rotations = rotation_channels(&a.hierarchy.root);
+    let up_axis = ground::detect_up_axis(&a);
+    let mut root_offset = vec![0.0; rotations.len()];
+    for (index, channel) in a.hierarchy.root.channels.iter().enumerate() {
+        let axis = match *channel {
+            bvh::Channel::XPosition => 0,
+            bvh::Channel::YPosition => 1,
+            bvh::Channel::ZPosition => 2,
+            _ => continue,
+        };
+        if axis != up_axis {
+            root_offset[index] = a.motion.frames[candidate.frame_a][index] - b.motion.frames[candidate.frame_b][index];
+        }
+    }
+    let b_frame = |index: usize| b.motion.frames[index].iter().zip(root_offset.iter()).map(|(value, offset)| value + offset).collect::<Vec<_>>();
+
+    let mut frames = a.motion.frames[..candidate.frame_a].to_vec();
+    let blend_frames = candidate.blend_frames.min(b.motion.frames.len() - candidate.frame_b);
+    for step in 0..blend_frames {
+        // Smoothstep from a to b, leaving out the end points (which are the two clips unblended)
+        let t = (step + 1) as f64 / (blend_frames + 1) as f64;
+        let t = t * t * (3.0 - 2.0 * t);
+        let from = &a.motion.frames[(candidate.frame_a + step).min(a.motion.frames.len() - 1)];
+        let to = b_frame(candidate.frame_b + step);
+        frames.push(from.iter().zip(to.iter()).zip(rotations.iter()).map(|((from, to), rotation)| {
+            let difference = if *rotation { to - from - 360.0 * ((to - from + 180.0) / 360.0).floor() } else { to - from };
+            from + difference * t
+        }).collect());
+    }
+    frames.extend((candidate.frame_b + blend_frames..b.motion.frames.len()).map(b_frame));
+
+    bvh::Bvh {
+        hierarchy: a.hierarchy,
+        motion: bvh::Motion {
+            num_frames: frames.len() as u32,
+            frame_time: a.motion.frame_time,
+            frames: frames,
+        },
+    }
+}
+
+// Each frame's change to the next one, rotations the short way round; one fewer than the frames.
+fn velocities(frames: &[Vec<f64>], rotations: &[bool]) -> Vec<Vec<f64>> {
+    frames.windows(2).map(|pair| pair[0].iter().zip(pair[1].iter()).zip(rotations.iter()).map(|((from, to), rotation)| {
+        if *rotation { to - from - 360.0 * ((to - from + 180.0) / 360.0).floor() } else { to - from }
+    }).collect()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_util::{self, sine};
+
+    const SEGMENT: usize = 20;
+
+    // `num_frames` frames of other motion with the shared segment at `start`
+    fn with_segment(num_frames: usize, start: usize, other: fn(usize, usize) -> f64) -> bvh::Bvh {
+        test_util::parse(&test_util::clip_text(num_frames, |frame, channel| {
+            if frame >= start && frame < start + SEGMENT { sine(frame - start, channel) } else { other(frame, channel) }
+        }))
+    }
+
+    fn clips() -> (bvh::Bvh, bvh::Bvh) {
+        (with_segment(60, 30, |frame, channel| sine(frame * 3 + 7, channel) * 0.5 + 5.0),
+         with_segment(45, 5, |frame, channel| sine(frame * 2 + 50, channel) * 1.5 - 5.0))
+    }
+
+    #[test]
+    fn finds_a_shared_segment_first() {
+        let (a, b) = clips();
+        let candidates = find(&a, &b, 1, 5);
+        assert_eq!(candidates.len(), 5);
+        let best = &candidates[0];
+        // Any frame of the segment but the last, whose velocity leads out of it
+        assert_eq!(best.frame_a - best.frame_b, 30 - 5);
+        assert!(best.frame_b >= 5 && best.frame_b < 5 + SEGMENT - 1, "{:?}", best);
+        assert!(best.score < 1e-9 && best.pose_distance < 1e-9 && best.velocity_mismatch < 1e-9, "{:?}", best);
+        assert!((MIN_BLEND_TIME..=MAX_BLEND_TIME + 1e-9).contains(&(best.blend_frames as f64 * a.motion.frame_time)));
+
+        // Ranked, and never two at the same spot
+        for pair in candidates.windows(2) {
+            assert!(pair[0].score <= pair[1].score);
+        }
+        for (index, candidate) in candidates.iter().enumerate() {
+            assert!((candidate.score - candidate.pose_distance - candidate.velocity_mismatch).abs() < 1e-9);
+            for other in candidates[..index].iter() {
+                assert!(other.frame_a.abs_diff(candidate.frame_a) >= MIN_SEPARATION || other.frame_b.abs_diff(candidate.frame_b) >= MIN_SEPARATION);
+            }
+        }
+
+        // Striding over every other frame no pair lines up exactly, the offset between the clips
+        // being odd, but the best is still within the segment
+        let strided = &find(&a, &b, 2, 1)[0];
+        assert!(strided.frame_a >= 30 && strided.frame_a < 30 + SEGMENT && strided.frame_b >= 5 && strided.frame_b < 5 + SEGMENT, "{:?}", strided);
+        assert_eq!((strided.frame_a % 2, strided.frame_b % 2), (0, 0));
+    }
+
+    #[test]
+    fn stitching_at_a_shared_segment_is_seamless() {
+        let (a, b) = clips();
+        let best = find(&a, &b, 1, 1).remove(0);
+        let expected = a.motion.frames[..best.frame_a].iter().chain(b.motion.frames[best.frame_b..].iter()).cloned().collect::<Vec<_>>();
+        let frame_time = a.motion.frame_time;
+        let stitched = stitch(a, &b, &best);
+        assert_eq!(stitched.motion.num_frames as usize, expected.len());
+        assert_eq!(stitched.motion.frame_time, frame_time);
+        for (index, (frame, expected)) in stitched.motion.frames.iter().zip(expected.iter()).enumerate() {
+            for (value, expected) in frame.iter().zip(expected.iter()) {
+                assert!((value - expected).abs() < 1e-9, "frame {}: {} vs {}", index, value, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn stitching_moves_the_second_clip_to_continue_from_the_first() {
+        // The same clip, walking along X and Z, cut back to its start: the root carries on from
+        // where the first left off rather than jumping back, and its height (Y, the up axis) is kept
+        let walk = || test_util::parse(&test_util::clip_text(30, |frame, channel| match channel {
+            0 => frame as f64 * 2.0,
+            1 => 90.0 + sine(frame, channel) * 0.1,
+            2 => frame as f64 * -0.5,
+            _ => sine(frame, channel),
+        }));
+        let candidate = Candidate {
+            frame_a: 20,
+            frame_b: 0,
+            score: 0.0,
+            pose_distance: 0.0,
+            velocity_mismatch: 0.0,
+            blend_frames: 4,
+        };
+        let (a, b) = (walk(), walk());
+        let stitched = stitch(a, &b, &candidate);
+        assert_eq!(stitched.motion.frames.len(), 20 + 30);
+        // Past the blend, the second clip's frames moved 40 along X and -10 along Z
+        for frame in 20 + 4..50 {
+            let (value, original) = (&stitched.motion.frames[frame], &b.motion.frames[frame - 20]);
+            assert!((value[0] - (original[0] + 40.0)).abs() < 1e-9 && (value[2] - (original[2] - 10.0)).abs() < 1e-9);
+            assert_eq!(value[1], original[1]);
+            assert_eq!(&value[3..], &original[3..]);
+        }
+    }
+}
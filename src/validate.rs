@@ -0,0 +1,238 @@
+use bvh;
+
+use error::MocapError;
+use selector;
+use timing;
+use {max_level, num_levels, Joint, JointChildren, Mocap};
+
+impl Mocap {
+    // Checks the invariants the rest of the code relies on, reporting every violation rather than
+    // just the first. This is mostly useful for catching bugs in code that builds or transforms a
+    // `Mocap` directly; `build_mocap` and `raw::read` only produce valid ones. Joints own their
+    // children, so the hierarchy can't contain cycles and no check is needed for that.
+    pub fn validate(&self) -> Result<(), MocapError> {
+        let mut violations = Vec::new();
+
+        if num_levels(self.channel_quantization_bits).is_none() {
+            violations.push(format!("channel quantization bits {} not in [1, 8]", self.channel_quantization_bits));
+        }
+        if !self.frame_time.is_finite() || self.frame_time <= 0.0 {
+            violations.push(format!("frame time {} is not a positive number", self.frame_time));
+        }
+
+        validate_joint(&self.root, "", self.num_frames, self.channel_quantization_bits, &mut violations);
+
+        if self.markers.windows(2).any(|pair| pair[0].0 > pair[1].0) {
+            violations.push("markers are not sorted by frame".into());
+        }
+        for (frame, name) in self.markers.iter() {
+            if *frame >= self.num_frames {
+                violations.push(format!("marker {} at frame {} is past the last frame", name, frame));
+            }
+        }
+        if !self.timestamps.is_empty() && self.timestamps.len() != self.num_frames as usize {
+            violations.push(format!("{} timestamps for {} frames", self.timestamps.len(), self.num_frames));
+        }
+        if !timing::is_valid(&self.timestamps) {
+            violations.push("timestamps are not finite and increasing".into());
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(MocapError::InvalidMocap(violations))
+        }
+    }
+
+    // Checks that every leaf is the same kind as in `source`, the hierarchy this was built from: an
+    // end site where it has one and child joints where it has them, as many as it has. `build_bvh`
+    // writes the hierarchy back as it finds it, so a pass that turned an end site into a joint
+    // without channels (or the other way round) would otherwise go unnoticed until the output is
+    // found to have a different skeleton.
+    pub fn validate_leaves(&self, source: &bvh::Joint) -> Result<(), MocapError> {
+        let mut violations = Vec::new();
+        compare_leaves(&self.root, source, "", &mut violations);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(MocapError::InvalidMocap(violations))
+        }
+    }
+}
+
+fn validate_joint(joint: &Joint, parent_path: &str, num_frames: u32, channel_quantization_bits: u8, violations: &mut Vec<String>) {
+    let name = selector::escape(&joint.name);
+    let path = if parent_path.is_empty() { name } else { format!("{}/{}", parent_path, name) };
+
+    if !is_finite(&joint.offset) {
+        violations.push(format!("{}: offset {:?} is not finite", path, joint.offset));
+    }
+
+    for channel in joint.channels.iter() {
+        let location = format!("{} {}", path, channel.type_.name());
+        match channel.values {
+            Some(ref values) => {
+                if values.len() != num_frames as usize || !channel.deltas.is_empty() {
+                    violations.push(format!("{}: lossless with {} values and {} deltas but {} frames", location, values.len(), channel.deltas.len(), num_frames));
+                }
+                if values.iter().any(|value| !value.is_finite()) {
+                    violations.push(format!("{}: lossless values are not all finite", location));
+                }
+            }
+            None => {
+                if channel.deltas.len() != num_frames as usize {
+                    violations.push(format!("{}: {} deltas but {} frames", location, channel.deltas.len(), num_frames));
+                }
+            }
+        }
+        if !channel.reference.is_finite() || !channel.value_range_min.is_finite() {
+            violations.push(format!("{}: reference {} / range min {} is not finite", location, channel.reference, channel.value_range_min));
+        }
+        if !channel.value_range.is_finite() || channel.value_range < 0.0 {
+            violations.push(format!("{}: range {} is not a finite, non-negative number", location, channel.value_range));
+        }
+        if let Some((min, max)) = channel.clamp {
+            if !min.is_finite() || !max.is_finite() || min > max {
+                violations.push(format!("{}: clamp bounds [{}, {}] are not finite and ordered", location, min, max));
+            }
+        }
+        if let Some(bits) = channel.bits {
+            if num_levels(bits).is_none() {
+                violations.push(format!("{}: channel quantization bits {} not in [1, 8]", location, bits));
+            }
+        }
+        if let Some(anchor_level) = channel.anchor_level {
+            let bits = channel.bits(channel_quantization_bits);
+            if channel.type_.is_translation() {
+                violations.push(format!("{}: translation channels can't be anchored", location));
+            }
+            if anchor_level > max_level(bits) {
+                violations.push(format!("{}: anchor level {} is out of range for {} bits", location, anchor_level, bits));
+            }
+        }
+    }
+
+    match joint.children {
+        JointChildren::Joints(ref joints) => {
+            if joints.is_empty() {
+                violations.push(format!("{}: neither child joints nor an end site", path));
+            }
+            for child in joints.iter() {
+                validate_joint(child, &path, num_frames, channel_quantization_bits, violations);
+            }
+        }
+        JointChildren::EndSite(ref offset) => {
+            if !is_finite(offset) {
+                violations.push(format!("{}: end site offset {:?} is not finite", path, offset));
+            }
+        }
+    }
+}
+
+fn compare_leaves(joint: &Joint, source: &bvh::Joint, parent_path: &str, violations: &mut Vec<String>) {
+    let name = selector::escape(&joint.name);
+    let path = if parent_path.is_empty() { name } else { format!("{}/{}", parent_path, name) };
+
+    match (&joint.children, &source.children) {
+        (JointChildren::Joints(joints), bvh::JointChildren::Joints(source)) if joints.len() == source.len() => {
+            for (joint, source) in joints.iter().zip(source.iter()) {
+                compare_leaves(joint, source, &path, violations);
+            }
+        }
+        (JointChildren::EndSite(_), bvh::JointChildren::EndSite(_)) => (),
+        (children, source) => {
+            let describe = |num_joints: Option<usize>| match num_joints {
+                Some(num_joints) => format!("{} child joint{}", num_joints, if num_joints == 1 { "" } else { "s" }),
+                None => "an end site".to_string(),
+            };
+            let joints = match *children {
+                JointChildren::Joints(ref joints) => Some(joints.len()),
+                JointChildren::EndSite(_) => None,
+            };
+            let source = match *source {
+                bvh::JointChildren::Joints(ref joints) => Some(joints.len()),
+                bvh::JointChildren::EndSite(_) => None,
+            };
+            violations.push(format!("{}: {}, but the source has {}", path, describe(joints), describe(source)));
+        }
+    }
+}
+
+fn is_finite(offset: &(f32, f32, f32)) -> bool {
+    offset.0.is_finite() && offset.1.is_finite() && offset.2.is_finite()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conversion::ConversionSettings;
+    use test_util;
+    use build_mocap;
+
+    fn joints(joint: &mut Joint) -> &mut Vec<Joint> {
+        match joint.children {
+            JointChildren::Joints(ref mut joints) => joints,
+            JointChildren::EndSite(_) => panic!("{} has an end site", joint.name),
+        }
+    }
+
+    fn violations(result: Result<(), MocapError>) -> Vec<String> {
+        match result {
+            Err(MocapError::InvalidMocap(violations)) => violations,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn leaves_match_the_source_after_building() {
+        let bvh = test_util::sine_clip(3);
+        let mocap = build_mocap(&bvh, &ConversionSettings::default().settings());
+        assert!(mocap.validate().is_ok());
+        assert!(mocap.validate_leaves(&bvh.hierarchy.root).is_ok());
+    }
+
+    #[test]
+    fn reports_a_joint_where_the_source_has_an_end_site() {
+        let bvh = test_util::sine_clip(3);
+        let mut mocap = build_mocap(&bvh, &ConversionSettings::default().settings());
+        // Head's end site turned into a joint without channels
+        let head = &mut joints(&mut joints(&mut mocap.root)[0])[0];
+        let end_site = Joint {
+            name: "HeadEnd".into(),
+            original_name: None,
+            offset: (0.0, 5.0, 0.0),
+            channels: Vec::new(),
+            children: JointChildren::EndSite((0.0, 0.0, 0.0)),
+        };
+        head.children = JointChildren::Joints(vec![end_site]);
+        // Which is a valid `Mocap`, just not the source's skeleton
+        assert!(mocap.validate().is_ok());
+        assert_eq!(violations(mocap.validate_leaves(&bvh.hierarchy.root)), vec!["Hips/Spine/Head: 1 child joint, but the source has an end site".to_string()]);
+    }
+
+    #[test]
+    fn reports_an_end_site_where_the_source_has_joints() {
+        let bvh = test_util::sine_clip(3);
+        let mut mocap = build_mocap(&bvh, &ConversionSettings::default().settings());
+        joints(&mut mocap.root)[0].children = JointChildren::EndSite((0.0, 10.0, 0.0));
+        joints(&mut mocap.root).pop();
+        assert_eq!(violations(mocap.validate_leaves(&bvh.hierarchy.root)), vec![
+            "Hips: 1 child joint, but the source has 2 child joints".to_string(),
+        ]);
+
+        let mut mocap = build_mocap(&bvh, &ConversionSettings::default().settings());
+        joints(&mut mocap.root)[0].children = JointChildren::EndSite((0.0, 10.0, 0.0));
+        assert_eq!(violations(mocap.validate_leaves(&bvh.hierarchy.root)), vec![
+            "Hips/Spine: an end site, but the source has 1 child joint".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn a_joint_needs_children_or_an_end_site() {
+        let bvh = test_util::sine_clip(3);
+        let mut mocap = build_mocap(&bvh, &ConversionSettings::default().settings());
+        joints(&mut mocap.root)[1].children = JointChildren::Joints(Vec::new());
+        assert_eq!(violations(mocap.validate()), vec!["Hips/LeftLeg: neither child joints nor an end site".to_string()]);
+        assert_eq!(violations(mocap.validate_leaves(&bvh.hierarchy.root)), vec!["Hips/LeftLeg: 0 child joints, but the source has an end site".to_string()]);
+    }
+}
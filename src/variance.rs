@@ -0,0 +1,70 @@
+use std::io::{self, Write};
+
+use json;
+use {decode_channel, Mocap};
+
+// Per-channel statistics of the decoded motion, for level-of-detail decisions at runtime: a player
+// streaming a cheaper LOD can drop the channels that barely move without analysing the clip
+// again. With --channel-variance every channel's variance (of its dequantized values, in degrees
+// squared or units squared) is recorded in the metadata, in flat channel order separated by
+// spaces; --stats-json writes it with the rest of the statistics below as JSON:
+//
+//   [
+//     { "flat_index": 0, "joint_name": "Hips", "channel_type": "TranslationX",
+//       "min": -1.5, "max": 2.25, "mean": 0.5, "variance": 0.75 },
+//     ...
+//   ]
+//
+// Rotations are taken as they decode, so a channel wrapping between 180 and -180 degrees reports
+// the variance of that jump rather than of the small motion around it.
+
+// Metadata key holding the variances
+pub const KEY: &str = "channel_variance";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelStats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub variance: f64, // Population variance, over every frame
+}
+
+// Every channel's statistics in flat channel order. A clip without frames has them all 0.
+pub fn channel_stats(mocap: &Mocap) -> Vec<ChannelStats> {
+    let bits = mocap.channel_quantization_bits;
+    mocap.channels().into_iter().map(|channel| {
+        let mut values = Vec::with_capacity(mocap.num_frames as usize);
+        decode_channel(channel, bits, |_, value| values.push(value));
+        if values.is_empty() {
+            return ChannelStats { min: 0.0, max: 0.0, mean: 0.0, variance: 0.0 };
+        }
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        ChannelStats {
+            min: values.iter().cloned().fold(f64::INFINITY, f64::min),
+            max: values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+            mean: mean,
+            variance: values.iter().map(|value| (value - mean) * (value - mean)).sum::<f64>() / values.len() as f64,
+        }
+    }).collect()
+}
+
+pub fn metadata(stats: &[ChannelStats]) -> (String, String) {
+    (KEY.into(), stats.iter().map(|stats| format!("{}", stats.variance)).collect::<Vec<_>>().join(" "))
+}
+
+pub fn write_json<W: Write>(mocap: &Mocap, stats: &[ChannelStats], w: &mut W) -> io::Result<()> {
+    writeln!(w, "[")?;
+    for (index, (descriptor, channel_stats)) in mocap.channel_map().into_iter().zip(stats.iter()).enumerate() {
+        let separator = if index + 1 < stats.len() { "," } else { "" };
+        writeln!(w, "  {{ \"flat_index\": {}, \"joint_name\": \"{}\", \"channel_type\": \"{}\", \"min\": {}, \"max\": {}, \"mean\": {}, \"variance\": {} }}{}",
+            descriptor.flat_index,
+            json::escape(&descriptor.joint_name),
+            descriptor.channel_type.name(),
+            channel_stats.min,
+            channel_stats.max,
+            channel_stats.mean,
+            channel_stats.variance,
+            separator)?;
+    }
+    writeln!(w, "]")
+}
@@ -0,0 +1,388 @@
+use container::{self, Container};
+use drift;
+use error::MocapError;
+use raw;
+use selector;
+use view::{ChannelData, MocapView};
+use {max_level, num_levels, Joint, JointChildren, Mocap};
+
+// `mocap verify`: decodes every channel of every clip in a .raw file or container and checks what
+// reading alone doesn't, collecting every problem found rather than stopping at the first:
+//
+//   - that the file parses: magic, version, block layout and lengths (see raw.rs), seek index
+//     structure (seek.rs) and container reference poses (container.rs);
+//   - every invariant `Mocap::validate` checks;
+//   - that every level a channel's deltas reach, starting from its initial level, is within the
+//     clip's bit depth, which also keeps every reconstructed value within the channel's declared
+//     range; that lossless values are within the channel's clamp bounds;
+//   - that every seek index entry's levels are the levels the blocks before it decode to;
+//   - that a container clip's known attributes are valid;
+//   - with --max-delta-run, that no channel's chain of deltas runs longer than that between
+//     absolute levels (see drift.rs).
+//
+// Neither format has checksums or a string table (strings are stored inline), so there's nothing
+// to check there; a flipped bit in a delta is only caught if it takes a level off the grid.
+//
+// A finding's location is a joint path and channel type as in `Mocap::validate`, a seek index
+// entry, an attribute, or empty for the file as a whole.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub clip: String,
+    pub location: String,
+    pub frame: Option<u32>,
+    pub message: String,
+}
+
+// Every problem with the file in `data`, none if it's sound. `name` is the clip name findings in a
+// .raw file are reported under.
+pub fn verify(data: &[u8], name: &str, max_delta_run: Option<usize>, max_depth: usize) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    if data.starts_with(raw::MAGIC) {
+        match raw::read(data, max_depth) {
+            Ok(mocap) => {
+                verify_clip(&mocap, name, &mut findings);
+                // The blocks and index parse if `raw::read` succeeded
+                if let Ok(view) = MocapView::parse(data, max_depth) {
+                    verify_seek_table(&view, &mocap, name, &mut findings);
+                }
+            }
+            Err(e) => findings.push(file_finding(name, e)),
+        }
+    } else {
+        match container::read(data, max_depth) {
+            Ok(container) => verify_container(&container, &mut findings),
+            Err(e) => findings.push(file_finding("", e)),
+        }
+    }
+    if let Some(max_delta_run) = max_delta_run {
+        verify_delta_runs(data, name, max_delta_run, max_depth, &mut findings);
+    }
+    findings
+}
+
+fn file_finding(clip: &str, e: MocapError) -> Finding {
+    Finding {
+        clip: clip.into(),
+        location: String::new(),
+        frame: None,
+        message: e.to_string(),
+    }
+}
+
+fn verify_container(container: &Container, findings: &mut Vec<Finding>) {
+    for clip in container.clips.iter() {
+        // An alias's data is its original's, verified with that
+        if clip.alias.is_none() {
+            verify_clip(&clip.mocap, &clip.name, findings);
+        }
+        for (key, value) in clip.attributes.iter() {
+            if !container::is_valid_attribute(key, value, clip.mocap.num_frames) {
+                findings.push(Finding {
+                    clip: clip.name.clone(),
+                    location: format!("attribute {}", key),
+                    frame: None,
+                    message: format!("invalid value {}", value),
+                });
+            }
+        }
+    }
+}
+
+fn verify_clip(mocap: &Mocap, clip: &str, findings: &mut Vec<Finding>) {
+    let finding = |location: String, frame: Option<u32>, message: String| Finding {
+        clip: clip.into(),
+        location: location,
+        frame: frame,
+        message: message,
+    };
+
+    if let Err(MocapError::InvalidMocap(violations)) = mocap.validate() {
+        for violation in violations {
+            findings.push(match violation.split_once(": ") {
+                Some((location, message)) => finding(location.into(), None, message.into()),
+                None => finding(String::new(), None, violation),
+            });
+        }
+    }
+    if num_levels(mocap.channel_quantization_bits).is_none() {
+        // Already reported, and there's no grid to check levels against
+        return;
+    }
+
+    for (channel, location) in mocap.channels().into_iter().zip(channel_locations(mocap)) {
+        if let Some(ref values) = channel.values {
+            if let Some((min, max)) = channel.clamp {
+                let outside = values.iter().enumerate().filter(|(_, value)| **value < min || **value > max).collect::<Vec<_>>();
+                if let Some(&(frame, value)) = outside.first() {
+                    findings.push(finding(location, Some(frame as u32), format!("lossless value {} outside the clamp bounds [{}, {}]{}", value, min, max, more(outside.len()))));
+                }
+            }
+            continue;
+        }
+
+        let bits = channel.bits(mocap.channel_quantization_bits);
+        if num_levels(bits).is_none() {
+            continue;
+        }
+        let max_level = max_level(bits);
+        if channel.initial_level > max_level {
+            findings.push(finding(location.clone(), None, format!("initial level {} past the {} bit grid", channel.initial_level, bits)));
+        }
+        let mut level = channel.initial_level;
+        let mut off_grid = Vec::new();
+        for (frame, delta) in channel.deltas.iter().enumerate() {
+            level = (level as i8).wrapping_add(*delta) as u8;
+            if level > max_level {
+                off_grid.push((frame, level));
+            }
+        }
+        if let Some(&(frame, level)) = off_grid.first() {
+            findings.push(finding(location, Some(frame as u32), format!("level {} past the {} bit grid{}", level, bits, more(off_grid.len()))));
+        }
+    }
+}
+
+// Every seek index entry's levels against the levels the channels' deltas decode to just before
+// its block. Those are the deltas of `mocap`, the clip read without the index: the view's channels
+// decode each block from its entry's levels, so a wrong entry would also throw off the next one.
+fn verify_seek_table(view: &MocapView, mocap: &Mocap, clip: &str, findings: &mut Vec<Finding>) {
+    let entries = match view.seek_table() {
+        Some(entries) => entries,
+        None => return,
+    };
+    let locations = channel_locations(mocap);
+    // Predicted channels come restored, so their stored levels aren't checked
+    let block_channels = view.channels().into_iter().zip(mocap.channels()).zip(locations).filter_map(|((view, channel), location)| view.block_index().map(|index| (index, channel, location)));
+    for (index, channel, location) in block_channels {
+        let mut level = channel.initial_level;
+        let mut frame = 0;
+        let mut entry_levels = Vec::with_capacity(entries.len());
+        channel.for_each_delta(|delta| {
+            if entry_levels.len() < entries.len() && entries[entry_levels.len()].start_frame == frame {
+                entry_levels.push(level);
+            }
+            level = (level as i8).wrapping_add(delta) as u8;
+            frame += 1;
+        });
+        for ((entry_index, entry), level) in entries.iter().enumerate().zip(entry_levels) {
+            if entry.levels[index] != level {
+                findings.push(Finding {
+                    clip: clip.into(),
+                    location: format!("seek index entry {}", entry_index),
+                    frame: Some(entry.start_frame),
+                    message: format!("{} starts at level {}, but the blocks before it decode to {}", location, entry.levels[index], level),
+                });
+            }
+        }
+    }
+}
+
+fn verify_delta_runs(data: &[u8], name: &str, max_delta_run: usize, max_depth: usize, findings: &mut Vec<Finding>) {
+    // A file that doesn't read is already reported
+    let clips = match drift::read(data, max_depth) {
+        Ok(clips) => clips,
+        Err(_) => return,
+    };
+    for clip in clips.iter() {
+        let count = clip.chains.iter().filter(|chain| chain.longest_run as usize > max_delta_run).count();
+        if count > 0 {
+            findings.push(Finding {
+                clip: if clip.name.is_empty() { name.into() } else { clip.name.clone() },
+                location: String::new(),
+                frame: None,
+                message: format!("the deltas of {} channel{} run up to {} frames between absolute levels, past --max-delta-run {}{}", count, if count == 1 { "" } else { "s" }, clip.longest_run(), max_delta_run, if clip.anchored { "" } else { " (there's no seek index)" }),
+            });
+        }
+    }
+}
+
+fn more(count: usize) -> String {
+    if count > 1 {
+        format!(" (and {} more frames)", count - 1)
+    } else {
+        String::new()
+    }
+}
+
+// Every channel's location, `<joint path> <channel type>`, in flat channel order.
+fn channel_locations(mocap: &Mocap) -> Vec<String> {
+    let mut ret = Vec::new();
+    push_locations(&mocap.root, "", &mut ret);
+    ret
+}
+
+fn push_locations(joint: &Joint, parent_path: &str, locations: &mut Vec<String>) {
+    let name = selector::escape(&joint.name);
+    let path = if parent_path.is_empty() { name } else { format!("{}/{}", parent_path, name) };
+    locations.extend(joint.channels.iter().map(|channel| format!("{} {}", path, channel.type_.name())));
+    if let JointChildren::Joints(ref joints) = joint.children {
+        for child in joints.iter() {
+            push_locations(child, &path, locations);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitpack;
+    use container::Clip;
+    use conversion::ConversionSettingsBuilder;
+    use depth::DEFAULT_MAX_DEPTH;
+    use periodic::Limits;
+    use test_util;
+    use build_mocap;
+
+    const NUM_FRAMES: usize = 40;
+
+    fn clip(bits: u8) -> Mocap {
+        build_mocap(&test_util::sine_clip(NUM_FRAMES), &ConversionSettingsBuilder::default().channel_quantization_bits(bits).build().unwrap().settings())
+    }
+
+    fn raw(mocap: &Mocap) -> Vec<u8> {
+        let mut ret = Vec::new();
+        raw::write(mocap, Limits::default(), &mut ret).unwrap();
+        ret
+    }
+
+    fn container_clip(name: &str, mocap: Mocap, attributes: Vec<(String, String)>) -> Clip {
+        Clip {
+            name: name.into(),
+            reference_pose: None,
+            attributes: attributes,
+            thumbnail: None,
+            alias: None,
+            mocap: mocap,
+        }
+    }
+
+    fn packed(clips: Vec<Clip>) -> Vec<u8> {
+        let container = Container {
+            reference_poses: Vec::new(),
+            clips: clips,
+        };
+        let mut ret = Vec::new();
+        container::write(&container, Limits::default(), &mut ret).unwrap();
+        ret
+    }
+
+    fn locations(findings: &[Finding]) -> Vec<(String, String, Option<u32>)> {
+        findings.iter().map(|finding| (finding.clip.clone(), finding.location.clone(), finding.frame)).collect()
+    }
+
+    #[test]
+    fn sound_files_have_no_findings() {
+        assert_eq!(verify(&raw(&clip(8)), "walk", None, DEFAULT_MAX_DEPTH), Vec::new());
+        let mut data = Vec::new();
+        raw::write_indexed(&clip(5), 8, bitpack::Layout::Packed, Limits::default(), &mut data).unwrap();
+        assert_eq!(verify(&data, "walk", Some(8), DEFAULT_MAX_DEPTH), Vec::new());
+        let attributes = vec![("loop".to_string(), "true".to_string()), ("sync_end".to_string(), "39".to_string())];
+        assert_eq!(verify(&packed(vec![container_clip("walk", clip(8), attributes), container_clip("run", clip(4), Vec::new())]), "", None, DEFAULT_MAX_DEPTH), Vec::new());
+    }
+
+    #[test]
+    fn reports_files_that_dont_parse() {
+        let data = raw(&clip(8));
+        let findings = verify(&data[..data.len() - 3], "walk", None, DEFAULT_MAX_DEPTH);
+        assert_eq!(locations(&findings), vec![("walk".to_string(), String::new(), None)]);
+
+        let data = packed(vec![container_clip("walk", clip(8), Vec::new())]);
+        let findings = verify(&data[..data.len() / 2], "ignored", None, DEFAULT_MAX_DEPTH);
+        assert_eq!(locations(&findings), vec![(String::new(), String::new(), None)]);
+        assert!(!findings[0].message.is_empty());
+    }
+
+    #[test]
+    fn reports_invariant_violations_by_channel() {
+        let mut mocap = clip(8);
+        mocap.root.channels[3].clamp = Some((1.0, -1.0));
+        mocap.root.channels[4].deltas.pop();
+        let mut findings = Vec::new();
+        verify_clip(&mocap, "walk", &mut findings);
+        assert_eq!(locations(&findings), vec![
+            ("walk".to_string(), "Hips RotationZ".to_string(), None),
+            ("walk".to_string(), "Hips RotationX".to_string(), None),
+        ]);
+        assert_eq!(findings[0].message, "clamp bounds [1, -1] are not finite and ordered");
+        assert_eq!(findings[1].message, format!("{} deltas but {} frames", NUM_FRAMES - 1, NUM_FRAMES));
+    }
+
+    #[test]
+    fn reports_levels_past_the_bit_grid() {
+        let mut mocap = clip(4);
+        let spine = match mocap.root.children {
+            JointChildren::Joints(ref mut joints) => &mut joints[0],
+            JointChildren::EndSite(_) => unreachable!(),
+        };
+        // Level 15 but for frames 10 and 11
+        spine.channels[0].initial_level = 15;
+        for (frame, delta) in spine.channels[0].deltas.iter_mut().enumerate() {
+            *delta = match frame { 10 => 1, 12 => -1, _ => 0 };
+        }
+        // Back on the grid with the first delta
+        spine.channels[1].initial_level = 16;
+        spine.channels[1].deltas = vec![-1; NUM_FRAMES];
+        spine.channels[1].deltas[1..].iter_mut().for_each(|delta| *delta = 0);
+        let mut findings = Vec::new();
+        verify_clip(&mocap, "walk", &mut findings);
+        assert_eq!(locations(&findings), vec![
+            ("walk".to_string(), "Hips/Spine RotationZ".to_string(), Some(10)),
+            ("walk".to_string(), "Hips/Spine RotationX".to_string(), None),
+        ]);
+        assert_eq!(findings[0].message, "level 16 past the 4 bit grid (and 1 more frames)");
+        assert_eq!(findings[1].message, "initial level 16 past the 4 bit grid");
+    }
+
+    #[test]
+    fn reports_lossless_values_outside_the_clamp_bounds() {
+        let mut mocap = clip(8);
+        let values = (0..NUM_FRAMES).map(|frame| frame as f64).collect::<Vec<_>>();
+        mocap.root.channels[0].values = Some(values);
+        mocap.root.channels[0].deltas = Vec::new();
+        mocap.root.channels[0].clamp = Some((0.0, 37.0));
+        let findings = verify(&packed(vec![container_clip("walk", mocap, Vec::new())]), "", None, DEFAULT_MAX_DEPTH);
+        assert_eq!(locations(&findings), vec![("walk".to_string(), "Hips TranslationX".to_string(), Some(38))]);
+        assert_eq!(findings[0].message, "lossless value 38 outside the clamp bounds [0, 37] (and 1 more frames)");
+    }
+
+    #[test]
+    fn reports_seek_index_levels_the_blocks_dont_decode_to() {
+        let mut data = Vec::new();
+        raw::write_indexed(&clip(8), 8, bitpack::Layout::Packed, Limits::default(), &mut data).unwrap();
+        let view = MocapView::parse(&data, DEFAULT_MAX_DEPTH).unwrap();
+        let entry = &view.seek_table().unwrap()[2];
+        let num_levels = entry.levels.len();
+        // The index ends with its offset; before that, the last three entries of 16 bytes and the
+        // levels, and the third entry's levels start 16 bytes into it
+        let position = data.len() - 8 - 3 * (16 + num_levels) + 16;
+        let level = entry.levels[0];
+        data[position] = level ^ 1;
+
+        let findings = verify(&data, "walk", None, DEFAULT_MAX_DEPTH);
+        assert_eq!(locations(&findings), vec![("walk".to_string(), "seek index entry 2".to_string(), Some(16))]);
+        assert_eq!(findings[0].message, format!("Hips TranslationX starts at level {}, but the blocks before it decode to {}", level ^ 1, level));
+    }
+
+    #[test]
+    fn reports_invalid_attributes() {
+        let attributes = vec![("speed".to_string(), "-1".to_string()), ("sync_start".to_string(), "40".to_string()), ("custom".to_string(), "anything".to_string())];
+        let findings = verify(&packed(vec![container_clip("walk", clip(8), attributes)]), "", None, DEFAULT_MAX_DEPTH);
+        assert_eq!(locations(&findings), vec![
+            ("walk".to_string(), "attribute speed".to_string(), None),
+            ("walk".to_string(), "attribute sync_start".to_string(), None),
+        ]);
+        assert_eq!(findings[0].message, "invalid value -1");
+    }
+
+    #[test]
+    fn reports_delta_runs_past_the_limit() {
+        let findings = verify(&raw(&clip(8)), "walk", Some(10), DEFAULT_MAX_DEPTH);
+        assert_eq!(locations(&findings), vec![("walk".to_string(), String::new(), None)]);
+        assert!(findings[0].message.ends_with("past --max-delta-run 10 (there's no seek index)"), "{}", findings[0].message);
+
+        let mut data = Vec::new();
+        raw::write_indexed(&clip(8), 16, bitpack::Layout::Packed, Limits::default(), &mut data).unwrap();
+        let findings = verify(&data, "walk", Some(10), DEFAULT_MAX_DEPTH);
+        assert!(findings[0].message.ends_with("up to 16 frames between absolute levels, past --max-delta-run 10"), "{}", findings[0].message);
+    }
+}
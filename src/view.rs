@@ -0,0 +1,346 @@
+use bvh;
+
+use bitpack;
+use error::MocapError;
+use prediction;
+use raw::{self, Reader};
+use seek;
+use {build_bvh_joint, decode_channel, decode_channels_parallel, Channel, Mocap};
+
+// A .raw file read in place: the header and skeleton are parsed up front, but the delta payloads
+// stay in the caller's buffer (which can be a memory-mapped file) and are decoded straight from
+// there. `parse` checks every block against the buffer's bounds before handing out slices, so a
+// truncated or corrupt file is an error rather than a panic later. Deltas are unpacked a byte at
+// a time (see bitpack.rs) and multi-byte header fields are copied out with `from_le_bytes`, so the
+// buffer needn't be aligned. Channels predicted from a partner (see prediction.rs) are restored up
+// front, and from then on held like periodic ones.
+#[derive(Debug)]
+pub struct MocapView<'a> {
+    header: Mocap, // The clip without deltas
+    layout: bitpack::Layout,
+    blocks: Vec<Block<'a>>,
+    seek_table: Option<Vec<seek::Entry>>,
+    block_indices: Vec<Option<usize>>, // Per channel, where it's stored among the channels in the blocks
+}
+
+#[derive(Debug, Clone)]
+struct Block<'a> {
+    start_frame: usize,
+    num_frames: usize,
+    offsets: Vec<usize>, // Where each channel's deltas start, and past the last one's end
+    deltas: &'a [u8], // Packed or as bit planes, channel-major
+}
+
+impl<'a> Block<'a> {
+    // Unpacks the first `count` deltas of the channel at `index` among those in the block.
+    fn unpack<F: FnMut(i8)>(&self, index: usize, count: usize, bits: u8, layout: bitpack::Layout, level: &mut u8, f: F) {
+        bitpack::unpack(&self.deltas[self.offsets[index]..self.offsets[index + 1]], self.num_frames, count, bits, layout, level, f);
+    }
+}
+
+// One channel of a view: its quantization parameters and its deltas, one slice per block, or
+// for a periodic channel (see periodic.rs), one in a sparse track (see raw.rs) or a predicted one
+// the deltas `parse` expanded. A lossless channel's
+// values are in its `Channel`. With a seek index each block's deltas are decoded from the entry's
+// level rather than from where the block before left off, which is the same for a sound file, but
+// keeps a corrupt delta from carrying past its block (see drift.rs).
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelView<'a> {
+    channel: &'a Channel,
+    index: Option<usize>, // Among the channels stored in the blocks
+    bits: u8, // The channel's bit depth
+    layout: bitpack::Layout,
+    blocks: &'a [Block<'a>],
+    anchors: Option<&'a [seek::Entry]>, // Per block, from the seek index
+}
+
+// What decoding a channel needs, implemented by owned `Channel`s and borrowed `ChannelView`s.
+pub trait ChannelData {
+    // The quantization parameters; any deltas it holds are ignored in favor of `for_each_delta`.
+    fn channel(&self) -> &Channel;
+    fn for_each_delta<F: FnMut(i8)>(&self, f: F);
+}
+
+impl ChannelData for Channel {
+    fn channel(&self) -> &Channel {
+        self
+    }
+
+    fn for_each_delta<F: FnMut(i8)>(&self, mut f: F) {
+        for delta in self.deltas.iter() {
+            f(*delta);
+        }
+    }
+}
+
+impl<'a> ChannelView<'a> {
+    // Where the channel is among those read from the blocks; None if its deltas are expanded or
+    // it's lossless.
+    pub fn block_index(&self) -> Option<usize> {
+        self.index
+    }
+}
+
+impl<'a> ChannelData for ChannelView<'a> {
+    fn channel(&self) -> &Channel {
+        self.channel
+    }
+
+    fn for_each_delta<F: FnMut(i8)>(&self, f: F) {
+        match self.index {
+            Some(index) => {
+                let mut f = f;
+                let mut level = self.channel.initial_level;
+                for (block_index, block) in self.blocks.iter().enumerate() {
+                    let mut anchored = self.anchors.map_or(level, |entries| entries[block_index].levels[index]);
+                    // Moves the first delta to the anchored level
+                    let mut shift = (anchored as i8).wrapping_sub(level as i8);
+                    block.unpack(index, block.num_frames, self.bits, self.layout, &mut anchored, |delta| {
+                        f(delta.wrapping_add(shift));
+                        shift = 0;
+                    });
+                    level = anchored;
+                }
+            }
+            None => self.channel.for_each_delta(f),
+        }
+    }
+}
+
+impl<'a> MocapView<'a> {
+    // Fails on joints deeper than `max_depth` (see depth.rs).
+    pub fn parse(data: &'a [u8], max_depth: usize) -> Result<MocapView<'a>, MocapError> {
+        let mut reader = Reader::with_max_depth(data, max_depth);
+        raw::read_magic(&mut reader)?;
+        let (header, layout) = raw::read_clip_header(&mut reader)?;
+        let mut bits = Vec::new();
+        let block_indices = header.channels().into_iter().map(|channel| if raw::is_in_blocks(channel) {
+            bits.push(channel.bits(header.channel_quantization_bits));
+            Some(bits.len() - 1)
+        } else {
+            None
+        }).collect::<Vec<_>>();
+
+        let mut blocks = Vec::new();
+        let mut block_positions = Vec::new();
+        let mut remaining = header.num_frames;
+        while remaining > 0 {
+            let offset = reader.position();
+            let block_frames = raw::read_block_frames(&mut reader, remaining)?;
+            let mut offsets = vec![0];
+            for bits in bits.iter() {
+                let end = offsets.last().unwrap() + bitpack::packed_len(block_frames as usize, *bits);
+                offsets.push(end);
+            }
+            let len = *offsets.last().unwrap();
+            blocks.push(Block {
+                start_frame: (header.num_frames - remaining) as usize,
+                num_frames: block_frames as usize,
+                offsets: offsets,
+                deltas: reader.bytes(len)?,
+            });
+            block_positions.push((header.num_frames - remaining, offset as u64, (reader.position() - offset) as u32));
+            remaining -= block_frames;
+        }
+        let seek_table = if reader.remaining() > 0 {
+            Some(seek::read(&mut reader, &block_positions, &bits)?)
+        } else {
+            None
+        };
+        reader.finish()?;
+
+        let mut ret = MocapView {
+            header: header,
+            layout: layout,
+            blocks: blocks,
+            seek_table: seek_table,
+            block_indices: block_indices,
+        };
+        ret.restore_predicted()?;
+        Ok(ret)
+    }
+
+    // Expands the predicted channels' deltas into the header, leaving them out of the blocks.
+    fn restore_predicted(&mut self) -> Result<(), MocapError> {
+        let restored = {
+            let channels = self.channels();
+            let predictions = prediction::read(&self.header.metadata, &self.header.channels())?;
+            prediction::restore(&predictions, self.header.channel_quantization_bits, |index| prediction::levels(&channels[index]))
+        };
+        let mut channels = self.header.channels_mut();
+        for (index, levels) in restored {
+            channels[index].deltas = prediction::deltas(&levels, channels[index].initial_level);
+        }
+        self.header.metadata.retain(|entry| entry.0 != prediction::KEY);
+        Ok(())
+    }
+
+    // The file's seek index (see seek.rs), if it has one: an entry per block.
+    pub fn seek_table(&self) -> Option<&[seek::Entry]> {
+        self.seek_table.as_deref()
+    }
+
+    // Every channel's value at `frame`, in flat channel order. With a seek index this unpacks only
+    // the frame's block, up to the frame; without one, every block before it too.
+    pub fn decode_frame_at(&self, frame: usize) -> Result<Vec<f64>, MocapError> {
+        if frame >= self.header.num_frames as usize {
+            return Err(MocapError::Usage(format!("there's no frame {} in a clip of {} frames", frame, self.header.num_frames)));
+        }
+        let bits = self.header.channel_quantization_bits;
+        let (first_block, levels) = match self.seek_table {
+            Some(ref entries) => {
+                let index = entries.partition_point(|entry| entry.start_frame as usize <= frame) - 1;
+                (index, entries[index].levels.clone())
+            }
+            None => (0, self.header.channels().into_iter().zip(self.block_indices.iter()).filter(|(_, index)| index.is_some()).map(|(channel, _)| channel.initial_level).collect()),
+        };
+
+        Ok(self.channels().into_iter().map(|view| {
+            let channel = view.channel;
+            if let Some(ref values) = channel.values {
+                return values[frame];
+            }
+            let level = match view.index {
+                Some(index) => {
+                    let mut level = levels[index];
+                    for block in self.blocks[first_block..].iter().take_while(|block| block.start_frame <= frame) {
+                        let count = (frame + 1 - block.start_frame).min(block.num_frames);
+                        block.unpack(index, count, channel.bits(bits), self.layout, &mut level, |_| ());
+                    }
+                    level
+                }
+                // Periodic, sparse or predicted, with its deltas expanded
+                None => channel.deltas[..=frame].iter().fold(channel.initial_level, |level, delta| (level as i8).wrapping_add(*delta) as u8),
+            };
+            let value = channel.value_of(level, bits);
+            match channel.clamp {
+                Some((min, max)) => value.clamp(min, max),
+                None => value,
+            }
+        }).collect())
+    }
+
+    // Everything but the deltas: the skeleton, channel parameters, metadata and markers.
+    pub fn header(&self) -> &Mocap {
+        &self.header
+    }
+
+    // In flat channel order, as `Mocap::channels`.
+    pub fn channels(&self) -> Vec<ChannelView<'_>> {
+        self.header.channels().into_iter().zip(self.block_indices.iter()).map(|(channel, index)| ChannelView {
+            channel: channel,
+            index: index.filter(|_| raw::is_in_blocks(channel)),
+            bits: channel.bits(self.header.channel_quantization_bits),
+            layout: self.layout,
+            blocks: &self.blocks,
+            anchors: self.seek_table.as_deref(),
+        }).collect()
+    }
+
+    pub fn reconstruct_frames(&self, frames: &mut Vec<Vec<f64>>) {
+        frames.resize(self.header.num_frames as usize, Vec::new());
+        for frame in frames.iter_mut() {
+            frame.clear();
+        }
+
+        for channel in self.channels() {
+            decode_channel(&channel, self.header.channel_quantization_bits, |index, value| frames[index].push(value));
+        }
+    }
+
+    // Decodes on `threads` threads (see `decode_channels_parallel`) if more than one.
+    pub fn to_bvh(&self, threads: usize) -> bvh::Bvh {
+        let mut frames = Vec::new();
+        if threads > 1 {
+            decode_channels_parallel(&self.channels(), self.header.num_frames as usize, self.header.channel_quantization_bits, threads, &mut frames);
+        } else {
+            self.reconstruct_frames(&mut frames);
+        }
+
+        bvh::Bvh {
+            hierarchy: bvh::Hierarchy {
+                root: build_bvh_joint(&self.header.root),
+            },
+            motion: bvh::Motion {
+                num_frames: self.header.num_frames,
+                frame_time: self.header.frame_time as _,
+                frames: frames,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use conversion::ConversionSettings;
+    use depth::DEFAULT_MAX_DEPTH;
+    use periodic::Limits;
+    use test_util;
+    use {build_bvh, build_mocap};
+
+    const NUM_FRAMES: usize = 45;
+
+    type WriteRaw = fn(&Mocap, &mut Vec<u8>) -> io::Result<()>;
+
+    const WRITES: [(&str, WriteRaw); 5] = [
+        ("packed", |mocap, w| raw::write(mocap, Limits::default(), w)),
+        ("bit planes", |mocap, w| raw::write_bit_planes(mocap, Limits::default(), w)),
+        ("sparse", |mocap, w| raw::write_sparse(mocap, Limits::default(), w)),
+        ("indexed", |mocap, w| raw::write_indexed(mocap, 8, bitpack::Layout::Packed, Limits::default(), w)),
+        ("indexed bit planes", |mocap, w| raw::write_indexed(mocap, 8, bitpack::Layout::BitPlanes, Limits::default(), w)),
+    ];
+
+    fn clip() -> Mocap {
+        // Constant and periodic channels as well, which aren't stored in the blocks
+        let mut bvh = test_util::sine_clip(NUM_FRAMES);
+        for (index, frame) in bvh.motion.frames.iter_mut().enumerate() {
+            frame[1] = 4.0;
+            frame[2] = [0.0, 5.0, 10.0][index % 3];
+        }
+        build_mocap(&bvh, &ConversionSettings::default().settings())
+    }
+
+    #[test]
+    fn view_decodes_as_the_owned_clip_does() {
+        let mocap = clip();
+        for (name, write) in WRITES.iter() {
+            let mut data = Vec::new();
+            write(&mocap, &mut data).unwrap();
+            let expected = build_bvh(&raw::read(&data, DEFAULT_MAX_DEPTH).unwrap()).motion.frames;
+            assert_eq!(expected, build_bvh(&mocap).motion.frames, "{}", name);
+
+            let view = MocapView::parse(&data, DEFAULT_MAX_DEPTH).unwrap();
+            let mut frames = Vec::new();
+            view.reconstruct_frames(&mut frames);
+            assert_eq!(frames, expected, "{}", name);
+            assert_eq!(view.to_bvh(4).motion.frames, expected, "{}", name);
+            for (index, frame) in expected.iter().enumerate() {
+                assert_eq!(&view.decode_frame_at(index).unwrap(), frame, "{} frame {}", name, index);
+            }
+            assert!(view.decode_frame_at(NUM_FRAMES).is_err());
+        }
+    }
+
+    #[test]
+    fn refuses_every_truncation() {
+        let mocap = clip();
+        for (name, write) in WRITES.iter() {
+            let mut data = Vec::new();
+            write(&mocap, &mut data).unwrap();
+            let expected = build_bvh(&mocap).motion.frames;
+            for len in 0..data.len() {
+                match MocapView::parse(&data[..len], DEFAULT_MAX_DEPTH) {
+                    Err(_) => (),
+                    // Cut just before the seek index, a sound file without one
+                    Ok(view) => {
+                        assert!(view.seek_table().is_none() && MocapView::parse(&data, DEFAULT_MAX_DEPTH).unwrap().seek_table().is_some(), "{} cut to {} of {} bytes", name, len, data.len());
+                        assert_eq!(view.to_bvh(1).motion.frames, expected);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::io::{self, Write};
+
+use bvh;
+
+use concat;
+use error::MocapError;
+use periodic;
+use raw::{self, Reader};
+use {build_bvh, build_mocap, reconstruct_frames, Mocap, Settings};
+
+// Experimental vector-quantized encoding: rather than quantizing each channel separately, every
+// frame is replaced by the nearest of a small codebook of representative frames, and only its
+// index is stored. For repetitive motion (looping idles, cycles) a few dozen entries can stand in
+// for thousands of frames. All values are little-endian.
+//
+//   magic           b"MCVQ"
+//   version         u8, FORMAT_VERSION
+//   codebook        the codebook entries as a clip with one frame per entry, encoded as in a .raw
+//                   file following its version (see raw.rs); its metadata is the source clip's, it
+//                   has no markers
+//   num_frames      u32
+//   index size      u8, 1 or 2 bytes
+//   indices         one codebook index per frame
+pub const MAGIC: &[u8; 4] = b"MCVQ";
+pub const FORMAT_VERSION: u8 = 1;
+
+pub const MAX_CODEBOOK_SIZE: usize = 0x10000;
+
+// Stop refining the codebook after this many passes even if assignments are still changing
+const MAX_ITERATIONS: usize = 32;
+
+#[derive(Debug)]
+pub struct Vq {
+    pub codebook: Mocap,
+    pub indices: Vec<u16>,
+}
+
+// Builds a codebook of at most `codebook_size` entries for `frames` (the source frames of
+// `mocap`) with k-means, and quantizes the codebook itself with `settings`.
+pub fn encode(mocap: &Mocap, frames: &[Vec<f64>], codebook_size: usize, settings: &Settings) -> Vq {
+    let (centroids, assignments) = k_means(frames, codebook_size);
+
+    let mut codebook_bvh = build_bvh(mocap);
+    codebook_bvh.motion.num_frames = centroids.len() as u32;
+    codebook_bvh.motion.frames = centroids;
+    let mut codebook = build_mocap(&codebook_bvh, settings);
+    concat::copy_names(&mocap.root, &mut codebook.root);
+    for (channel, original) in codebook.channels_mut().into_iter().zip(mocap.channels()) {
+        channel.clamp = original.clamp;
+    }
+    codebook.metadata = mocap.metadata.clone();
+
+    Vq {
+        codebook: codebook,
+        indices: assignments.into_iter().map(|index| index as u16).collect(),
+    }
+}
+
+// The decoded clip, with the codebook frames looked up by index.
+pub fn decode(vq: &Vq) -> bvh::Bvh {
+    let mut entries = Vec::new();
+    reconstruct_frames(&vq.codebook, &mut entries);
+
+    let mut ret = build_bvh(&vq.codebook);
+    ret.motion.num_frames = vq.indices.len() as u32;
+    ret.motion.frames = vq.indices.iter().map(|index| entries[*index as usize].clone()).collect();
+    ret
+}
+
+// Farthest-point initialization (deterministic, and spreads the entries over the whole motion),
+// followed by Lloyd iterations. Distances are plain squared distances between channel values.
+fn k_means(frames: &[Vec<f64>], k: usize) -> (Vec<Vec<f64>>, Vec<usize>) {
+    let k = k.min(frames.len());
+    if k == 0 {
+        return (Vec::new(), Vec::new());
+    }
+
+    let mut centroids = vec![frames[0].clone()];
+    let mut distances: Vec<f64> = frames.iter().map(|frame| distance_squared(frame, &frames[0])).collect();
+    while centroids.len() < k {
+        let (farthest, farthest_distance) = distances.iter().cloned().enumerate().fold((0, 0.0), |max, (index, distance)| if distance > max.1 { (index, distance) } else { max });
+        if farthest_distance == 0.0 {
+            // Fewer distinct frames than entries
+            break;
+        }
+        centroids.push(frames[farthest].clone());
+        for (distance, frame) in distances.iter_mut().zip(frames.iter()) {
+            *distance = distance.min(distance_squared(frame, &frames[farthest]));
+        }
+    }
+
+    let mut assignments = vec![0; frames.len()];
+    for iteration in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for (assignment, frame) in assignments.iter_mut().zip(frames.iter()) {
+            let nearest = nearest(&centroids, frame);
+            if nearest != *assignment {
+                *assignment = nearest;
+                changed = true;
+            }
+        }
+        if iteration > 0 && !changed {
+            break;
+        }
+
+        for (index, centroid) in centroids.iter_mut().enumerate() {
+            let members: Vec<&Vec<f64>> = frames.iter().zip(assignments.iter()).filter(|(_, assignment)| **assignment == index).map(|(frame, _)| frame).collect();
+            // An entry nothing is assigned to keeps its value
+            if !members.is_empty() {
+                for (channel_index, value) in centroid.iter_mut().enumerate() {
+                    *value = members.iter().map(|frame| frame[channel_index]).sum::<f64>() / (members.len() as f64);
+                }
+            }
+        }
+    }
+
+    (centroids, assignments)
+}
+
+fn nearest(centroids: &[Vec<f64>], frame: &[f64]) -> usize {
+    let mut ret = 0;
+    let mut min_distance = f64::INFINITY;
+    for (index, centroid) in centroids.iter().enumerate() {
+        let distance = distance_squared(centroid, frame);
+        if distance < min_distance {
+            ret = index;
+            min_distance = distance;
+        }
+    }
+    ret
+}
+
+fn distance_squared(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+pub fn write<W: Write>(vq: &Vq, limits: periodic::Limits, w: &mut W) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&[FORMAT_VERSION])?;
+    raw::write_clip(&vq.codebook, limits, w)?;
+
+    w.write_all(&(vq.indices.len() as u32).to_le_bytes())?;
+    if vq.codebook.num_frames as usize <= 0x100 {
+        w.write_all(&[1])?;
+        for index in vq.indices.iter() {
+            w.write_all(&[*index as u8])?;
+        }
+    } else {
+        w.write_all(&[2])?;
+        for index in vq.indices.iter() {
+            w.write_all(&index.to_le_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn read(data: &[u8], max_depth: usize) -> Result<Vq, MocapError> {
+    let mut reader = Reader::with_max_depth(data, max_depth);
+
+    if reader.bytes(4)? != MAGIC {
+        return Err(MocapError::InvalidRaw("not a mocap VQ file".into()));
+    }
+    let version = reader.u8()?;
+    if version != FORMAT_VERSION {
+        return Err(MocapError::InvalidRaw(format!("unsupported VQ format version {}", version)));
+    }
+    let codebook = raw::read_clip(&mut reader)?;
+
+    let num_frames = reader.u32()?;
+    let index_size = reader.u8()?;
+    let mut indices = Vec::new();
+    for _ in 0..num_frames {
+        let index = match index_size {
+            1 => reader.u8()? as u16,
+            2 => reader.u16()?,
+            _ => return Err(MocapError::InvalidRaw(format!("invalid VQ index size {}", index_size))),
+        };
+        if index as u32 >= codebook.num_frames {
+            return Err(MocapError::InvalidRaw(format!("VQ index {} out of range for a codebook of {} entries", index, codebook.num_frames)));
+        }
+        indices.push(index);
+    }
+    reader.finish()?;
+
+    Ok(Vq {
+        codebook: codebook,
+        indices: indices,
+    })
+}
@@ -0,0 +1,256 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+use bvh;
+
+use bitpack;
+use concat;
+use error::MocapError;
+use raw;
+use seek;
+use {build_bvh_joint, build_mocap, count_bvh_channels, max_level, Joint, Mocap, RotationAnchor, Settings, TranslationReference};
+
+// Writes a .raw file incrementally, for captures too long to hold in memory or still in progress.
+// Since the global range of each channel isn't known up front, the ranges are declared when the
+// writer is created (from a calibration clip, or known physical bounds) and samples outside them
+// are clamped and counted. Frames are quantized as they're pushed and written out in blocks of
+// `block_frames`; `finish` fills in the frame count, and the seek index if asked for with
+// `enable_seek_index`.
+//
+// Quantization is exactly what `build_mocap` does for a clip whose channels span the declared
+// ranges, so streaming a clip with its own ranges produces the same file as converting it
+// (except that the writer stores no metadata or markers).
+pub const DEFAULT_BLOCK_FRAMES: usize = 256;
+
+pub struct MocapWriter<W: Write + Seek> {
+    w: W,
+    header: Mocap, // The skeleton and quantization parameters, without deltas
+    ranges: Vec<(f64, f64)>, // Per channel: (min, max) relative to the reference, as `build_mocap` quantizes with them
+    levels: Vec<u8>, // Per channel: the previous level
+    block_levels: Vec<u8>, // Per channel: the level before the first frame in `block`
+    seek_index: Option<Vec<seek::Entry>>,
+    block: Vec<Vec<i8>>, // Per channel: the deltas not written out yet
+    block_frames: usize,
+    pending_frames: usize, // Frames in `block`
+    num_frames: u32,
+    num_clamped: Vec<u64>, // Per channel
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriterStats {
+    pub num_frames: u32,
+    pub num_clamped: Vec<u64>, // Per channel: samples outside the declared ranges
+}
+
+impl<W: Write + Seek> MocapWriter<W> {
+    // `skeleton`'s channel parameters and deltas are ignored. `ranges` are the (min, max) values
+    // each channel is expected to take, in flat channel order. `TranslationReference::Mean` isn't
+    // supported, since the mean isn't known up front, and neither are rotation anchors.
+    pub fn new(mut w: W, skeleton: &Joint, frame_time: f64, settings: &Settings, ranges: &[(f64, f64)], block_frames: usize) -> Result<MocapWriter<W>, MocapError> {
+        if settings.translation_reference == TranslationReference::Mean {
+            return Err(MocapError::Usage("streaming can't store translations relative to their mean".into()));
+        }
+        if settings.rotation_anchor != RotationAnchor::None {
+            return Err(MocapError::Usage("streaming can't anchor rotation channels".into()));
+        }
+        if ranges.iter().any(|&(min, max)| !min.is_finite() || !max.is_finite() || min > max) {
+            return Err(MocapError::Usage("channel ranges must be finite and ordered".into()));
+        }
+
+        // Quantizing a two-frame clip spanning the ranges gives the same per-channel parameters
+        // a converted clip with those ranges would have.
+        let bounds = bvh::Bvh {
+            hierarchy: bvh::Hierarchy {
+                root: build_bvh_joint(skeleton),
+            },
+            motion: bvh::Motion {
+                num_frames: 2,
+                frame_time: frame_time,
+                frames: vec![ranges.iter().map(|range| range.0).collect(), ranges.iter().map(|range| range.1).collect()],
+            },
+        };
+        let num_channels = count_bvh_channels(&bounds.hierarchy.root);
+        if ranges.len() != num_channels {
+            return Err(MocapError::Usage(format!("expected {} channel ranges, got {}", num_channels, ranges.len())));
+        }
+        let mut header = build_mocap(&bounds, settings);
+        concat::copy_names(skeleton, &mut header.root);
+        header.num_frames = 0;
+        for channel in header.channels_mut() {
+            channel.deltas.clear();
+        }
+        let ranges = header.channels().into_iter().zip(ranges.iter()).map(|(channel, &(min, max))| (min - channel.reference, max - channel.reference)).collect();
+
+        w.write_all(raw::MAGIC)?;
+        w.write_all(&[raw::FORMAT_VERSION])?;
+        raw::write_clip_header(&header, &mut w)?;
+
+        Ok(MocapWriter {
+            w: w,
+            header: header,
+            ranges: ranges,
+            levels: vec![0; num_channels],
+            block_levels: vec![0; num_channels],
+            seek_index: None,
+            block: vec![Vec::with_capacity(block_frames); num_channels],
+            block_frames: block_frames.max(1),
+            pending_frames: 0,
+            num_frames: 0,
+            num_clamped: vec![0; num_channels],
+        })
+    }
+
+    // Writes a seek index (see seek.rs) after the last block.
+    pub fn enable_seek_index(&mut self) {
+        self.seek_index = Some(Vec::new());
+    }
+
+    pub fn push_frame(&mut self, frame: &[f64]) -> Result<(), MocapError> {
+        if frame.len() != self.levels.len() {
+            return Err(MocapError::Usage(format!("expected {} channel values, got {}", self.levels.len(), frame.len())));
+        }
+        let num_frames = self.num_frames.checked_add(1).ok_or_else(|| MocapError::Overflow(format!("a clip can't have more than {} frames", u32::MAX)))?;
+
+        let max_level = max_level(self.header.channel_quantization_bits) as f64;
+        for (index, channel) in self.header.channels().into_iter().enumerate() {
+            let (min, max) = self.ranges[index];
+            let range = max - min;
+            let mut value = frame[index] - channel.reference;
+            // Against the bounds themselves, as min + range can round below max
+            if value < min || value > max {
+                self.num_clamped[index] += 1;
+                value = value.clamp(min, max);
+            }
+            let level = if range > 0.0 { (((value - min) / range) * max_level) as u8 } else { 0 };
+
+            self.block[index].push((level as i8).wrapping_sub(self.levels[index] as i8));
+            self.levels[index] = level;
+        }
+        self.num_frames = num_frames;
+        self.pending_frames += 1;
+
+        if self.pending_frames >= self.block_frames {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        if self.pending_frames == 0 {
+            return Ok(());
+        }
+        let mut packed = (self.pending_frames as u32).to_le_bytes().to_vec();
+        for deltas in self.block.iter_mut() {
+            bitpack::pack(deltas, self.header.channel_quantization_bits, bitpack::Layout::Packed, &mut packed);
+            deltas.clear();
+        }
+        if let Some(ref mut entries) = self.seek_index {
+            entries.push(seek::Entry {
+                start_frame: self.num_frames - self.pending_frames as u32,
+                offset: self.w.stream_position()?,
+                len: packed.len() as u32,
+                levels: self.block_levels.clone(),
+            });
+        }
+        self.w.write_all(&packed)?;
+        self.block_levels.copy_from_slice(&self.levels);
+        self.pending_frames = 0;
+        Ok(())
+    }
+
+    // Writes out the last block and the frame count.
+    pub fn finish(mut self) -> Result<(W, WriterStats), MocapError> {
+        self.flush_block()?;
+        if let Some(ref entries) = self.seek_index {
+            let index_offset = self.w.stream_position()?;
+            seek::write(entries, index_offset, &mut self.w)?;
+        }
+        self.w.seek(SeekFrom::Start(raw::NUM_FRAMES_OFFSET))?;
+        self.w.write_all(&self.num_frames.to_le_bytes())?;
+        self.w.seek(SeekFrom::End(0))?;
+        self.w.flush()?;
+
+        let stats = WriterStats {
+            num_frames: self.num_frames,
+            num_clamped: self.num_clamped,
+        };
+        Ok((self.w, stats))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use conversion::ConversionSettings;
+    use depth::DEFAULT_MAX_DEPTH;
+    use test_util;
+    use view::MocapView;
+    use build_bvh;
+
+    const NUM_FRAMES: usize = 50;
+
+    // Each channel's (min, max) over the clip
+    fn clip_ranges(bvh: &bvh::Bvh) -> Vec<(f64, f64)> {
+        (0..test_util::NUM_CHANNELS).map(|channel| {
+            let values = bvh.motion.frames.iter().map(|frame| frame[channel]);
+            (values.clone().fold(f64::INFINITY, f64::min), values.fold(f64::NEG_INFINITY, f64::max))
+        }).collect()
+    }
+
+    fn stream(bvh: &bvh::Bvh, ranges: &[(f64, f64)], seek_index: bool) -> (Vec<u8>, WriterStats) {
+        let settings = ConversionSettings::default().settings();
+        let skeleton = build_mocap(bvh, &settings).root;
+        let mut writer = MocapWriter::new(Cursor::new(Vec::new()), &skeleton, bvh.motion.frame_time, &settings, ranges, 16).unwrap();
+        if seek_index {
+            writer.enable_seek_index();
+        }
+        for frame in bvh.motion.frames.iter() {
+            writer.push_frame(frame).unwrap();
+        }
+        let (w, stats) = writer.finish().unwrap();
+        (w.into_inner(), stats)
+    }
+
+    #[test]
+    fn streaming_matches_converting_with_the_same_ranges() {
+        let bvh = test_util::sine_clip(NUM_FRAMES);
+        let converted = build_mocap(&bvh, &ConversionSettings::default().settings());
+        for seek_index in [false, true].iter() {
+            let (data, stats) = stream(&bvh, &clip_ranges(&bvh), *seek_index);
+            assert_eq!(stats, WriterStats { num_frames: NUM_FRAMES as u32, num_clamped: vec![0; test_util::NUM_CHANNELS] });
+            assert_eq!(MocapView::parse(&data, DEFAULT_MAX_DEPTH).unwrap().seek_table().is_some(), *seek_index);
+
+            let streamed = raw::read(&data, DEFAULT_MAX_DEPTH).unwrap();
+            assert_eq!(streamed.num_frames, converted.num_frames);
+            for (streamed, converted) in streamed.channels().into_iter().zip(converted.channels()) {
+                assert_eq!((streamed.value_range_min, streamed.value_range, streamed.initial_level), (converted.value_range_min, converted.value_range, converted.initial_level));
+                assert_eq!(streamed.deltas, converted.deltas);
+            }
+            assert_eq!(build_bvh(&streamed).motion.frames, build_bvh(&converted).motion.frames);
+        }
+    }
+
+    #[test]
+    fn samples_outside_the_ranges_are_clamped_and_counted() {
+        let bvh = test_util::sine_clip(NUM_FRAMES);
+        let mut ranges = clip_ranges(&bvh);
+        ranges[0] = (-1.0, 1.0);
+        let (data, stats) = stream(&bvh, &ranges, false);
+        assert_eq!(stats.num_clamped[0] as usize, bvh.motion.frames.iter().filter(|frame| frame[0].abs() > 1.0).count());
+        assert!(stats.num_clamped[1..].iter().all(|num_clamped| *num_clamped == 0));
+        for frame in build_bvh(&raw::read(&data, DEFAULT_MAX_DEPTH).unwrap()).motion.frames.iter() {
+            assert!(frame[0] >= -1.0 - 1e-6 && frame[0] <= 1.0 + 1e-6, "{}", frame[0]);
+        }
+    }
+
+    #[test]
+    fn refuses_the_wrong_number_of_values() {
+        let bvh = test_util::sine_clip(2);
+        let settings = ConversionSettings::default().settings();
+        let skeleton = build_mocap(&bvh, &settings).root;
+        assert!(matches!(MocapWriter::new(Cursor::new(Vec::new()), &skeleton, 0.03, &settings, &clip_ranges(&bvh)[1..], 16), Err(MocapError::Usage(_))));
+        let mut writer = MocapWriter::new(Cursor::new(Vec::new()), &skeleton, 0.03, &settings, &clip_ranges(&bvh), 16).unwrap();
+        assert!(matches!(writer.push_frame(&[0.0; 3]), Err(MocapError::Usage(_))));
+    }
+}
@@ -0,0 +1,77 @@
+// A conversion interrupted with Ctrl-C stops and leaves none of its outputs behind (see
+// cancel.rs and manifest.rs).
+
+#![cfg(unix)]
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const HIERARCHY: &str = "HIERARCHY
+ROOT Hips
+{
+\tOFFSET 0 0 0
+\tCHANNELS 6 Xposition Yposition Zposition Zrotation Xrotation Yrotation
+\tJOINT Spine
+\t{
+\t\tOFFSET 0 10 0
+\t\tCHANNELS 3 Zrotation Xrotation Yrotation
+\t\tEnd Site
+\t\t{
+\t\t\tOFFSET 0 5 0
+\t\t}
+\t}
+}
+";
+
+fn clip() -> String {
+    let num_frames = 200;
+    let mut ret = format!("{}MOTION\nFrames: {}\nFrame Time: 0.033333\n", HIERARCHY, num_frames);
+    for frame in 0..num_frames {
+        let values = (0..9).map(|channel| format!("{}", (frame as f64 * 0.15 + channel as f64).sin() * (10.0 + channel as f64 * 2.0))).collect::<Vec<_>>();
+        writeln!(ret, "{}", values.join(" ")).unwrap();
+    }
+    ret
+}
+
+fn file_names(dir: &PathBuf) -> Vec<String> {
+    let mut ret = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect::<Vec<_>>();
+    ret.sort();
+    ret
+}
+
+#[test]
+fn interrupted_conversion_leaves_no_outputs() {
+    let dir = env::temp_dir().join(format!("mocap-cancel-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("in.bvh"), clip()).unwrap();
+
+    // Waiting for the interrupt once it's started writing its outputs, which go to temporary files
+    // until it's done (see manifest.rs)
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mocap"))
+        .args(["in.bvh", "out.bvh", "out.csv", "out.raw"])
+        .env("MOCAP_TEST_PAUSE_AFTER_STAGING", "1")
+        .current_dir(&dir)
+        .spawn()
+        .unwrap();
+
+    let start = Instant::now();
+    while !file_names(&dir).iter().any(|name| name.ends_with(".tmp")) {
+        assert!(child.try_wait().unwrap().is_none(), "the conversion finished without staging an output");
+        assert!(start.elapsed() < Duration::from_secs(60), "the conversion never started writing");
+        thread::sleep(Duration::from_millis(1));
+    }
+    let status = Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap();
+    assert!(status.success());
+
+    // As for a process ended by SIGINT
+    assert_eq!(child.wait().unwrap().code(), Some(130));
+    assert_eq!(file_names(&dir), vec!["in.bvh".to_string()]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}